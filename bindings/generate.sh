@@ -0,0 +1,36 @@
+#!/bin/bash
+# Generate the Kotlin and Swift sources from the `bindings` feature
+#
+# Usage: bindings/generate.sh [cargo build options, e.g. --release --target aarch64-linux-android]
+#
+# Builds librvpnse with the feature, then runs uniffi-bindgen on it. The
+# generated files are not checked in: Kotlin goes to bindings/kotlin/generated,
+# Swift to bindings/swift/Sources.
+set -euo pipefail
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+cd "$root"
+
+cargo build --features bindings --bin uniffi-bindgen
+
+# The shared library cargo builds, for whichever target and profile
+library="$(cargo build --features bindings --lib "$@" --message-format=json \
+    | grep -o '"filenames":\[[^]]*\]' | grep -o '[^"]*librvpnse\.\(so\|dylib\)' | head -n 1)"
+if [ -z "$library" ]; then
+    echo "librvpnse not found in the cargo output" >&2
+    exit 1
+fi
+bindgen="$root/target/debug/uniffi-bindgen"
+
+rm -rf bindings/kotlin/generated
+"$bindgen" generate --library "$library" --language kotlin --no-format --out-dir bindings/kotlin/generated
+
+swift_out="$(mktemp -d)"
+"$bindgen" generate --library "$library" --language swift --no-format --out-dir "$swift_out"
+mkdir -p bindings/swift/Sources/RVPNSE bindings/swift/Sources/RVPNSEFFI
+mv "$swift_out/RVPNSE.swift" bindings/swift/Sources/RVPNSE/
+mv "$swift_out/RVPNSEFFI.h" bindings/swift/Sources/RVPNSEFFI/
+mv "$swift_out/RVPNSEFFI.modulemap" bindings/swift/Sources/RVPNSEFFI/module.modulemap
+rm -rf "$swift_out"
+
+echo "Generated bindings/kotlin/generated and bindings/swift/Sources from $library"
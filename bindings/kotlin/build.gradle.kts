@@ -0,0 +1,28 @@
+// Kotlin API generated by UniFFI into generated/ (bindings/generate.sh),
+// loading librvpnse at run time through JNA. Android apps depend on
+// "net.java.dev.jna:jna:<version>@aar" instead and ship librvpnse.so for
+// each ABI under jniLibs/.
+plugins {
+    kotlin("jvm") version "1.9.24"
+    `java-library`
+}
+
+group = "dev.rvpnse"
+version = "0.1.0"
+
+repositories {
+    mavenCentral()
+}
+
+dependencies {
+    api("net.java.dev.jna:jna:5.14.0")
+    // For the suspending connect and authenticate
+    api("org.jetbrains.kotlinx:kotlinx-coroutines-core:1.8.1")
+}
+
+kotlin {
+    jvmToolchain(11)
+    sourceSets.main {
+        kotlin.srcDir("generated")
+    }
+}
@@ -0,0 +1,22 @@
+// swift-tools-version:5.7
+import PackageDescription
+
+// Sources/ is generated by UniFFI (bindings/generate.sh). Links against
+// librvpnse built with `--features bindings`; point the linker at it with
+// `-Xlinker -L<dir>` or the Xcode library search paths.
+let package = Package(
+    name: "RVPNSE",
+    platforms: [.iOS(.v14), .macOS(.v11)],
+    products: [
+        .library(name: "RVPNSE", targets: ["RVPNSE"]),
+    ],
+    targets: [
+        .systemLibrary(name: "RVPNSEFFI", path: "Sources/RVPNSEFFI"),
+        .target(
+            name: "RVPNSE",
+            dependencies: ["RVPNSEFFI"],
+            path: "Sources/RVPNSE",
+            linkerSettings: [.linkedLibrary("rvpnse")]
+        ),
+    ]
+)
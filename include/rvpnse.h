@@ -5,6 +5,7 @@
  * into applications written in other languages (Swift, Kotlin, C#, etc.).
  * 
  * Usage:
+ * 0. Optionally set up shared state with vpnse_init() (vpnse_shutdown() at unload)
  * 1. Parse and validate configuration with vpnse_parse_config()
  * 2. Create client instance with vpnse_client_new()
  * 3. Connect to server with vpnse_client_connect()
@@ -24,6 +25,15 @@
 extern "C" {
 #endif
 
+/**
+ * Version of the C ABI this header describes
+ *
+ * Bumped when a function is removed, a signature or struct layout changes or
+ * an error code is renumbered; new functions keep the version. Compare it
+ * with vpnse_abi_version() before using the library.
+ */
+#define VPNSE_ABI_VERSION 1
+
 /**
  * Error codes returned by Rust VPNSE functions
  */
@@ -39,9 +49,54 @@ typedef enum {
     VPNSE_CONNECTION_LIMIT_REACHED = 8,
     VPNSE_RATE_LIMIT_EXCEEDED = 9,
     VPNSE_RETRY_LIMIT_EXCEEDED = 10,
+    VPNSE_CANCELLED = 11,
+    VPNSE_IN_PROGRESS = 12,
     VPNSE_INTERNAL_ERROR = 99
 } vpnse_error_t;
 
+/**
+ * Detailed error kinds reported by vpnse_last_error_code()
+ *
+ * The values are stable: new kinds get new numbers and none is reused.
+ */
+typedef enum {
+    VPNSE_ERR_OK = 0,
+    VPNSE_ERR_INVALID_CONFIG = 1,
+    VPNSE_ERR_INVALID_PARAMETER = 2,
+
+    VPNSE_ERR_NETWORK = 10,
+    VPNSE_ERR_CONNECTION_FAILED = 11,
+    VPNSE_ERR_TIMEOUT = 12,
+    VPNSE_ERR_TLS = 13,
+    VPNSE_ERR_PROXY = 14,
+    VPNSE_ERR_DNS = 15,
+
+    VPNSE_ERR_AUTH_FAILED = 20,
+    VPNSE_ERR_ACCESS_DENIED = 21,
+    VPNSE_ERR_PASSWORD_EXPIRED = 22,
+    VPNSE_ERR_AUTH_METHOD_NOT_SUPPORTED = 23,
+    VPNSE_ERR_LOCKED_OUT = 24,
+
+    VPNSE_ERR_HUB_NOT_FOUND = 30,
+    VPNSE_ERR_SERVER_FULL = 31,
+    VPNSE_ERR_PROTOCOL_MISMATCH = 32,
+    VPNSE_ERR_SESSION_REMOVED = 33,
+    VPNSE_ERR_DISCONNECTED_BY_SERVER = 34,
+    VPNSE_ERR_SERVER_ERROR = 35,
+
+    /** Creating the TUN device or changing routes needs root, CAP_NET_ADMIN or an administrator */
+    VPNSE_ERR_PERMISSION_DENIED = 40,
+    VPNSE_ERR_TUN = 41,
+    VPNSE_ERR_ROUTING_FAILED = 42,
+    VPNSE_ERR_DNS_CONFIG_FAILED = 43,
+
+    VPNSE_ERR_CANCELLED = 50,
+    VPNSE_ERR_INVALID_STATE = 51,
+    VPNSE_ERR_UNSUPPORTED = 52,
+
+    VPNSE_ERR_INTERNAL = 99
+} vpnse_error_code_t;
+
 /**
  * Connection status values
  */
@@ -53,9 +108,95 @@ typedef enum {
 
 /**
  * Opaque VPN client handle
+ *
+ * A handle, not a pointer: it may be used from any thread, calls on one
+ * client from several threads run one at a time, and calls with a freed
+ * handle fail with VPNSE_INVALID_PARAMETER instead of touching freed memory.
+ * Calls on a client from inside one of its own callbacks that would have to
+ * wait for the call running the callback fail with VPNSE_IN_PROGRESS.
  */
 typedef struct vpnse_client vpnse_client_t;
 
+/**
+ * Options for vpnse_init()
+ */
+typedef struct {
+    /** Built-in logger level: 0 leaves logging to the host, 1 = error ... 5 = trace */
+    int log_level;
+    /** Worker threads of the shared runtime; 0 for one per CPU */
+    uint32_t worker_threads;
+    /** Non-zero to log panics before the previous panic hook runs */
+    int install_panic_hook;
+} vpnse_init_options_t;
+
+/**
+ * Set up the library's process-wide state
+ *
+ * Creates the shared runtime that blocking calls, async calls and packet
+ * forwarding run on, installs the TLS crypto provider and, as requested, a
+ * logger and a panic hook. Calls nest: only the first sets anything up and
+ * each must be matched by vpnse_shutdown(). Without it, calls run on a
+ * runtime the library starts on first use and keeps for the process's life.
+ *
+ * @param options Options, or NULL for the defaults (no logger, panic hook)
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_init(const vpnse_init_options_t* options);
+
+/**
+ * Release what vpnse_init() set up
+ *
+ * The last of nested calls cancels pending async calls and waits until their
+ * callbacks have returned (except the one it is called from, if any), stops
+ * the shared runtime and joins its threads, and restores the previous panic
+ * hook. Disconnect and free clients first. Afterwards the
+ * library may be unloaded or initialised again.
+ *
+ * @return VPNSE_SUCCESS on success, VPNSE_INVALID_PARAMETER if not initialised
+ */
+int vpnse_shutdown(void);
+
+/**
+ * Callback receiving the library's log records
+ *
+ * @param level 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace
+ * @param target Module that logged the record, e.g. "rvpnse::tunnel"
+ * @param message Log message
+ * @param fields JSON object of structured fields, e.g. {"interface":"tun0"}
+ * @param user_data Pointer passed to vpnse_set_log_callback()
+ *
+ * Runs on whichever thread logged; the strings are only valid during the call.
+ */
+typedef void (*vpnse_log_cb)(int level, const char* target, const char* message, const char* fields,
+                             void* user_data);
+
+/**
+ * Send the library's log records to a callback instead of stderr
+ *
+ * Replaces the logger of vpnse_init() and applies to all clients. Fails if
+ * the host already installed a logger of its own in this process.
+ *
+ * @param callback Log callback, or NULL to stop logging
+ * @param level Most verbose level to deliver: 1 = error ... 5 = trace
+ * @param user_data Opaque pointer passed back to every callback invocation
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_set_log_callback(vpnse_log_cb callback, int level, void* user_data);
+
+/**
+ * Undo system changes left behind by crashed processes
+ *
+ * Replays the change journals of processes that exited without tearing their
+ * tunnel down: their routes, DNS settings, firewall rules and kill switch are
+ * removed. Journals of running processes are left alone. Call it once at
+ * startup, before connecting.
+ *
+ * @param state_dir Journal directory (tunnel.state_dir), or NULL for the default
+ * @param restored Receives the number of journals replayed (nullable)
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_cleanup_stale_state(const char* state_dir, uint32_t* restored);
+
 /**
  * Parse and validate a SoftEther VPN configuration
  * 
@@ -70,7 +211,7 @@ int vpnse_parse_config(const char* config_str, char* error_msg, size_t error_msg
  * Create a new VPN client instance
  * 
  * @param config_str TOML configuration string (null-terminated)
- * @return Opaque pointer to VPN client on success, NULL on failure
+ * @return Opaque client handle on success, NULL on failure
  */
 vpnse_client_t* vpnse_client_new(const char* config_str);
 
@@ -84,6 +225,94 @@ vpnse_client_t* vpnse_client_new(const char* config_str);
  */
 int vpnse_client_connect(vpnse_client_t* client, const char* server, uint16_t port);
 
+/**
+ * Callback receiving the result of vpnse_client_connect_async() or
+ * vpnse_client_authenticate_async()
+ *
+ * @param result VPNSE_SUCCESS, VPNSE_CANCELLED or another error code
+ * @param user_data Pointer passed to the call that started the operation
+ */
+typedef void (*vpnse_connect_cb)(int result, void* user_data);
+
+/**
+ * Start connecting to a SoftEther VPN server without blocking
+ *
+ * Returns immediately. The handshake runs on a library-owned worker thread
+ * and the callback is invoked exactly once on that thread when the connect
+ * succeeds, fails or is cancelled. Until then other calls on the client wait
+ * for the connect, except vpnse_client_connect_cancel(); freeing the handle
+ * releases the client once the connect is over. The callback may call back
+ * into the library for this client (including vpnse_client_free()) and
+ * should return quickly; marshal to your UI thread yourself.
+ *
+ * @param client VPN client instance
+ * @param server Server hostname or IP address (null-terminated)
+ * @param port Server port number
+ * @param callback Completion callback (required)
+ * @param user_data Opaque pointer passed back to the callback
+ * @return VPNSE_SUCCESS if started, VPNSE_IN_PROGRESS if an async call is
+ *         already pending, other error codes on failure (the callback is not invoked)
+ */
+int vpnse_client_connect_async(vpnse_client_t* client, const char* server, uint16_t port,
+                               vpnse_connect_cb callback, void* user_data);
+
+/**
+ * Cancel a pending vpnse_client_connect_async() or vpnse_client_authenticate_async()
+ *
+ * The completion callback still runs exactly once, with VPNSE_CANCELLED
+ * unless the call finished first.
+ *
+ * @param client VPN client instance
+ * @return VPNSE_SUCCESS if a pending call was signalled, VPNSE_INVALID_PARAMETER if none is pending
+ */
+int vpnse_client_connect_cancel(vpnse_client_t* client);
+
+/**
+ * Connect to the best node of the configured cluster
+ *
+ * Blocks like vpnse_client_connect(). The first call probes every node in
+ * [clustering] cluster_nodes and picks the fastest; later calls follow
+ * load_balancing_strategy. Clears a pin set by
+ * vpnse_client_connect_cluster_node().
+ *
+ * @param client VPN client instance with clustering enabled
+ * @return VPNSE_SUCCESS on success, VPNSE_INVALID_CONFIG if clustering is
+ *         not enabled, other error codes on failure
+ */
+int vpnse_client_connect_cluster_auto(vpnse_client_t* client);
+
+/**
+ * Connect to one node of the configured cluster and pin it
+ *
+ * Blocks like vpnse_client_connect(). The node is tried even if earlier
+ * failures blacklisted it, and failover does not move away from it until
+ * vpnse_client_connect_cluster_auto() is called.
+ *
+ * @param client VPN client instance with clustering enabled
+ * @param index Position of the node in cluster_nodes (the "index" field of
+ *              vpnse_client_cluster_nodes())
+ * @return VPNSE_SUCCESS on success, VPNSE_INVALID_PARAMETER if there is no
+ *         such node, other error codes on failure
+ */
+int vpnse_client_connect_cluster_node(vpnse_client_t* client, uint32_t index);
+
+/**
+ * Get the health of every cluster node, for a server picker
+ *
+ * Fills the buffer with a JSON array in cluster_nodes order, each entry with
+ * "index", "address", "healthy", "response_time_ms" (null until a probe
+ * reached the node), "active_connections", "penalty" (decayed failure
+ * score), "blacklisted_for_ms" (null unless blacklisted), "last_failure"
+ * (auth_failed, connection_reset, unreachable or null) and "pinned". The
+ * array is empty when clustering is not enabled.
+ *
+ * @param client VPN client instance
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (256 bytes per node is sufficient)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_client_cluster_nodes(const vpnse_client_t* client, char* buffer, size_t buffer_len);
+
 /**
  * Authenticate with SoftEther VPN server
  * 
@@ -94,6 +323,39 @@ int vpnse_client_connect(vpnse_client_t* client, const char* server, uint16_t po
  */
 int vpnse_client_authenticate(vpnse_client_t* client, const char* username, const char* password);
 
+/**
+ * Log in without blocking
+ *
+ * The login counterpart of vpnse_client_connect_async(), with the same
+ * threading and callback rules. vpnse_client_connect_cancel() cancels it and
+ * drops the connection, whose login exchange was cut short.
+ *
+ * @param client Connected VPN client instance
+ * @param username Username for authentication (null-terminated)
+ * @param password Password for authentication (null-terminated)
+ * @param callback Completion callback (required)
+ * @param user_data Opaque pointer passed back to the callback
+ * @return VPNSE_SUCCESS if started, VPNSE_IN_PROGRESS if an async call is
+ *         already pending, other error codes on failure (the callback is not invoked)
+ */
+int vpnse_client_authenticate_async(vpnse_client_t* client, const char* username, const char* password,
+                                    vpnse_connect_cb callback, void* user_data);
+
+/**
+ * Change the configured user's password on the server
+ *
+ * Use when vpnse_client_authenticate() failed and vpnse_last_error_code()
+ * returns VPNSE_ERR_PASSWORD_EXPIRED. The client must be connected but need
+ * not be logged in; later logins use the new password.
+ *
+ * @param client Connected VPN client instance
+ * @param old_password Current password (null-terminated)
+ * @param new_password Password to set, non-empty (null-terminated)
+ * @return VPNSE_SUCCESS on success, VPNSE_AUTHENTICATION_FAILED if the old
+ *         password is wrong, another error code on failure
+ */
+int vpnse_client_change_password(vpnse_client_t* client, const char* old_password, const char* new_password);
+
 /**
  * Disconnect from VPN server
  * 
@@ -104,7 +366,10 @@ int vpnse_client_disconnect(vpnse_client_t* client);
 
 /**
  * Free VPN client instance
- * 
+ *
+ * Calls already running on the client finish first. Freeing NULL or an
+ * already freed handle does nothing.
+ *
  * @param client VPN client instance to free
  */
 void vpnse_client_free(vpnse_client_t* client);
@@ -116,6 +381,208 @@ void vpnse_client_free(vpnse_client_t* client);
  */
 const char* vpnse_version(void);
 
+/**
+ * ABI version the library was built with
+ *
+ * @return The library's VPNSE_ABI_VERSION; a host built against a header with
+ *         a different value must not use the library
+ */
+uint32_t vpnse_abi_version(void);
+
+/**
+ * Detailed error code of the last failed call on this thread
+ *
+ * vpnse_error_t only tells the broad category; this tells e.g. a missing hub
+ * from rejected credentials. It is updated when a call fails inside the
+ * library, not by parameter checks returning VPNSE_INVALID_PARAMETER. A
+ * vpnse_client_connect_async() callback sees its own connect's error.
+ *
+ * @return A vpnse_error_code_t value, VPNSE_ERR_OK if no call on this thread has failed yet
+ */
+int vpnse_last_error_code(void);
+
+/**
+ * Describe a detailed error code
+ *
+ * @param code Value returned by vpnse_last_error_code()
+ * @return English description (caller must not free), or NULL for an unknown code
+ */
+const char* vpnse_error_message(int code);
+
+/**
+ * Describe compiled features and runtime platform capabilities
+ *
+ * Fills the buffer with a JSON object listing the TLS backend, crypto
+ * provider, transport and tunnel backends of this build, plus detected
+ * platform capabilities (TUN and netlink availability, privileges).
+ *
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (1024 bytes is sufficient)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_capabilities(char* buffer, size_t buffer_len);
+
+/**
+ * List the host's network interfaces, candidate uplinks first
+ *
+ * Fills the buffer with a JSON array of interfaces, each with "name",
+ * "index", "kind" (ethernet, wireless, cellular, loopback, tunnel, virtual,
+ * other), "addresses", "is_up", and the "gateway" and "metric" of its
+ * default route (null when it has none).
+ *
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (4096 bytes fits a typical host)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_list_interfaces(char* buffer, size_t buffer_len);
+
+/**
+ * Download the VPN Gate server list (built with the "vpngate" feature)
+ *
+ * Fills the buffer with a JSON array of servers, each with "host_name",
+ * "ip", "port", "vpngate_score", "ping_ms" (null when unmeasured),
+ * "speed_bps", "country_long", "country_short", "sessions", "uptime_ms",
+ * "total_users", "operator" and "message".
+ *
+ * @param url List URL, or NULL for the public VPN Gate API
+ * @param timeout_ms Download timeout in milliseconds
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (the public list needs about 256 KB)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_vpngate_fetch_servers(const char* url, uint32_t timeout_ms, char* buffer, size_t buffer_len);
+
+/**
+ * Pick a VPN Gate server and build the configuration to connect to it
+ * (built with the "vpngate" feature)
+ *
+ * Servers matching the criteria, a JSON object such as
+ * {"countries":["JP"],"max_ping_ms":100,"min_speed_bps":10000000,"max_sessions":50}
+ * with every field optional, are ranked by speed, ping and load. The TOML
+ * configuration written for the chosen one keeps base_config's settings
+ * apart from the server, hub and account, and can go to vpnse_client_new().
+ *
+ * @param servers_json Server list from vpnse_vpngate_fetch_servers()
+ * @param criteria_json Selection criteria, or NULL to accept every server
+ * @param base_config TOML configuration to start from
+ * @param rank 0 for the best server, 1 for the runner-up, ...
+ * @param buffer Buffer receiving the NUL-terminated TOML configuration
+ * @param buffer_len Size of the buffer
+ * @return VPNSE_SUCCESS on success, VPNSE_INVALID_CONFIG if fewer than
+ *         rank + 1 servers match
+ */
+int vpnse_vpngate_select(const char* servers_json, const char* criteria_json, const char* base_config,
+                         uint32_t rank, char* buffer, size_t buffer_len);
+
+/**
+ * Choose the uplink interface that carries the next connection
+ *
+ * Sockets to the server (HTTPS and UDP acceleration) are bound to the
+ * interface and the route to the server is pinned to it, so the session
+ * stays on e.g. LTE even when Wi-Fi has the lower metric.
+ *
+ * @param client VPN client instance
+ * @param interface_name Name from vpnse_list_interfaces(), or NULL for the OS default
+ * @return VPNSE_SUCCESS on success, VPNSE_NETWORK_ERROR if the interface is
+ *         missing, down or has no IPv4 address
+ */
+int vpnse_client_set_underlay_interface(vpnse_client_t* client, const char* interface_name);
+
+/**
+ * Check the privileges needed to establish a tunnel
+ *
+ * Fills the buffer with a JSON object listing each missing privilege
+ * (TUN device, CAP_NET_ADMIN, passwordless sudo, administrator rights,
+ * TUN driver, system tools) together with a remediation hint, e.g.
+ * {"os":"linux","missing":[{"privilege":"net_admin","detail":"...",
+ * "remediation":"grant CAP_NET_ADMIN ... or run as root"}]}.
+ * An empty "missing" array means the tunnel can be set up.
+ *
+ * @param client VPN client instance
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (4096 bytes is sufficient)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_check_privileges(const vpnse_client_t* client, char* buffer, size_t buffer_len);
+
+/**
+ * Collect a redacted support snapshot ("doctor" report)
+ *
+ * Fills the buffer with a JSON document describing where the configuration
+ * came from, the effective settings with credentials redacted, recent state
+ * transitions and events, the routing table and resolver configuration,
+ * counters and environment capabilities. Attach it to support tickets.
+ * Times are {"unix_ms": ..., "monotonic_ms": ...} pairs; compute durations
+ * from monotonic_ms, which is unaffected by wall-clock changes.
+ *
+ * @param client VPN client instance
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (the routing table can make the
+ *                   report large; 64 KiB is a reasonable size)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_client_doctor(const vpnse_client_t* client, char* buffer, size_t buffer_len);
+
+/**
+ * Probe a server without connecting
+ *
+ * Connects to the server over TCP, runs a TLS handshake under the client's
+ * certificate policy and sends the watermark, without logging in, and fills
+ * the buffer with what it found: {"address", "tcp_connect_ms",
+ * "tls_handshake_ms", "tls_version", "cipher_suite", "certificate_sha256",
+ * "watermark_ms", "server": {"product", "version", "build", ...}, "error"}.
+ * A server that accepted TCP but failed a later step is still reported,
+ * with that step's fields null and the reason in "error". Meant for server
+ * pickers; blocks for up to the configured connect timeout and leaves the
+ * client's own connection alone. Proxies are not used.
+ *
+ * @param client VPN client instance
+ * @param server Server hostname or IP address
+ * @param port Server port
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (1024 bytes is sufficient for most servers)
+ * @return VPNSE_SUCCESS on success, an error code if the server could not be
+ *         reached, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_probe_server(const vpnse_client_t* client, const char* server, uint16_t port, char* buffer, size_t buffer_len);
+
+/**
+ * Get throughput and packet rates over a recent window
+ *
+ * Fills the buffer with a JSON object with the interval covered ("since"
+ * and "until" as {"unix_ms", "monotonic_ms"} pairs, "interval_secs" taken
+ * from the monotonic clock) and the transmit/receive rates (tx_mbps, rx_mbps, tx_pps, rx_pps), errors per
+ * second and connection drops, or the literal "null" until an earlier sample
+ * inside the window exists. Each call records a sample; poll it periodically
+ * (e.g. once per second) for dashboards.
+ *
+ * @param client VPN client instance
+ * @param window_ms How far back to compare, in milliseconds
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (512 bytes is sufficient)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_client_rates(const vpnse_client_t* client, uint32_t window_ms, char* buffer, size_t buffer_len);
+
+/**
+ * Get traffic and session counters
+ *
+ * Fills the buffer with a JSON object with the bytes and packets sent and
+ * received, "uncompressed_bytes" and "compressed_bytes" of data payloads
+ * (0 unless the session compresses), "uptime_ms" of the current session (null while not connected),
+ * "reconnects" (sessions re-established after a loss), "rtt_ms", the
+ * round trip to the hub gateway measured by the last keepalive (null until
+ * one was answered), and for a UDP path "nat_keepalive_interval_ms" and
+ * "nat_timeout_ms", the NAT mapping timeout detected by its keepalive (null
+ * without a UDP path or until detected).
+ *
+ * @param client VPN client instance
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (512 bytes is sufficient)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_client_get_stats(const vpnse_client_t* client, char* buffer, size_t buffer_len);
+
 /**
  * Get connection status
  * 
@@ -124,6 +591,385 @@ const char* vpnse_version(void);
  */
 int vpnse_client_status(const vpnse_client_t* client);
 
+/**
+ * Check that the hub's virtual gateway answers through the tunnel
+ *
+ * Sends an ICMP echo to the gateway, so it works on hubs without internet
+ * access and contacts no third party.
+ *
+ * @param client VPN client instance with an established tunnel
+ * @param timeout_ms How long to wait for the reply
+ * @param rtt_ms Receives the round-trip time in milliseconds (may be NULL)
+ * @return VPNSE_SUCCESS if the gateway answered, error code on failure
+ */
+int vpnse_check_connection(const vpnse_client_t* client, uint32_t timeout_ms, uint32_t* rtt_ms);
+
+/**
+ * Callback used to review planned system changes
+ *
+ * @param category Change category (0 = route or interface address, 1 = DNS, 2 = kernel parameter, 3 = firewall)
+ * @param description Human readable description of the change (valid only during the call)
+ * @param user_data Pointer passed to vpnse_client_set_change_planner()
+ * @return Non-zero to approve the change, 0 to veto it
+ */
+typedef int (*vpnse_change_review_cb)(int category, const char* description, void* user_data);
+
+/**
+ * Register a callback that can veto routing, DNS, kernel and firewall changes
+ * before they are applied. The callback runs on the thread calling
+ * vpnse_client_establish_tunnel(). A change whose description contains a
+ * NUL byte cannot be shown to the callback and is vetoed.
+ *
+ * @param client VPN client instance
+ * @param callback Review callback, or NULL to remove a previously set one
+ * @param user_data Opaque pointer passed back to the callback
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_set_change_planner(vpnse_client_t* client, vpnse_change_review_cb callback, void* user_data);
+
+/**
+ * Callback receiving authentication failure events
+ *
+ * @param reason 0 = invalid credentials, 1 = network, 2 = timeout, 3 = protocol, 4 = locally locked out
+ * @param attempt Consecutive failed attempts for this profile
+ * @param retry_after_secs Seconds until the next attempt is allowed (0 if not locked out)
+ * @param user_data Pointer passed to vpnse_client_set_auth_failure_callback()
+ */
+typedef void (*vpnse_auth_failure_cb)(int reason, uint32_t attempt, uint64_t retry_after_secs, void* user_data);
+
+/**
+ * Register a callback for authentication failure events, e.g. to show a
+ * lockout countdown instead of retrying immediately.
+ *
+ * @param client VPN client instance
+ * @param callback Event callback, or NULL to remove a previously set one
+ * @param user_data Opaque pointer passed back to the callback
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_set_auth_failure_callback(vpnse_client_t* client, vpnse_auth_failure_cb callback, void* user_data);
+
+/**
+ * Callback supplying a one-time password
+ *
+ * Called on a worker thread when the hub asks for a second factor; it may
+ * block, e.g. while a dialog is open.
+ *
+ * @param username Account logging in
+ * @param hub Virtual hub asking for the code
+ * @param message Prompt from the server, or NULL
+ * @param attempt 1 for the first code of a login, higher after a rejected one
+ * @param otp Buffer receiving the NUL-terminated code
+ * @param otp_len Size of otp in bytes
+ * @param user_data Pointer passed to vpnse_client_set_otp_callback()
+ * @return 0 with the code written to otp, non-zero to cancel the login
+ */
+typedef int (*vpnse_otp_cb)(const char* username, const char* hub, const char* message, uint32_t attempt,
+                            char* otp, size_t otp_len, void* user_data);
+
+/**
+ * Register a callback supplying one-time passwords to hubs with a second
+ * factor. Without one, logins to such hubs fail; the callback must answer
+ * within auth.otp_timeout_secs.
+ *
+ * @param client VPN client instance
+ * @param callback OTP callback, or NULL to remove a previously set one
+ * @param user_data Opaque pointer passed back to the callback
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_set_otp_callback(vpnse_client_t* client, vpnse_otp_cb callback, void* user_data);
+
+/**
+ * Limit tunnel traffic, e.g. on a metered connection
+ *
+ * Packets are delayed, not dropped. Applies from the next packet, also while
+ * tunneling, and stays in force across reconnects.
+ *
+ * @param client VPN client instance
+ * @param upload_bps Upload limit in bits per second, 0 for unlimited
+ * @param download_bps Download limit in bits per second, 0 for unlimited
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_set_rate_limit(vpnse_client_t* client, uint64_t upload_bps, uint64_t download_bps);
+
+/**
+ * Replace the tunnel's DNS servers without reconnecting
+ *
+ * Addresses are packed back to back, 16 bytes each in network order; IPv4
+ * servers are written IPv4-mapped (::ffff:a.b.c.d). Takes effect immediately
+ * while tunneling, otherwise at the next tunnel establishment.
+ *
+ * @param client VPN client instance
+ * @param addresses count * 16 bytes of addresses (may be NULL when count is 0)
+ * @param count Number of addresses; 0 restores the default servers
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_set_dns_servers(vpnse_client_t* client, const uint8_t* addresses, size_t count);
+
+/**
+ * Turn the kill switch on or off
+ *
+ * While on, the firewall drops all traffic except through the tunnel and to
+ * the VPN server, from the moment the tunnel is up (immediately if it
+ * already is) until vpnse_client_disconnect(). The rules stay while the
+ * session is lost or reconnecting.
+ *
+ * @param client VPN client instance
+ * @param enable Non-zero to turn the kill switch on, 0 to turn it off
+ * @return VPNSE_SUCCESS on success, error code if the firewall rules could
+ *         not be installed or removed
+ */
+int vpnse_client_enable_killswitch(vpnse_client_t* client, int enable);
+
+/**
+ * Establish the VPN tunnel (routing layer)
+ *
+ * Creates a TUN interface and routes traffic through the VPN.
+ *
+ * @param client VPN client instance (must be authenticated)
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_establish_tunnel(vpnse_client_t* client);
+
+/**
+ * Same as vpnse_client_establish_tunnel()
+ */
+int vpnse_tunnel_establish(vpnse_client_t* client);
+
+/**
+ * Close the VPN tunnel
+ *
+ * @param client VPN client instance
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_tunnel_close(vpnse_client_t* client);
+
+/**
+ * Get the tunnel interface name, e.g. "tun0"
+ *
+ * @param client VPN client instance
+ * @param interface_buffer Buffer receiving the NUL-terminated name
+ * @param buffer_len Size of the buffer
+ * @return VPNSE_SUCCESS on success, 1 if no tunnel is established, error code on failure
+ */
+int vpnse_get_tunnel_interface(vpnse_client_t* client, char* interface_buffer, size_t buffer_len);
+
+/**
+ * Get the tunnel's local IP address
+ *
+ * @param client VPN client instance
+ * @param ip_buffer Buffer receiving the NUL-terminated address
+ * @param buffer_len Size of the buffer
+ * @return VPNSE_SUCCESS on success, 1 if no tunnel is established, error code on failure
+ */
+int vpnse_get_tunnel_local_ip(vpnse_client_t* client, char* ip_buffer, size_t buffer_len);
+
+/**
+ * Get the tunnel's remote IP address (gateway)
+ *
+ * @param client VPN client instance
+ * @param ip_buffer Buffer receiving the NUL-terminated address
+ * @param buffer_len Size of the buffer
+ * @return VPNSE_SUCCESS on success, 1 if no tunnel is established, error code on failure
+ */
+int vpnse_get_tunnel_remote_ip(vpnse_client_t* client, char* ip_buffer, size_t buffer_len);
+
+/**
+ * Get the tunnel subnet, e.g. "10.0.0.2/24"
+ *
+ * @param client VPN client instance
+ * @param subnet_buffer Buffer receiving the NUL-terminated subnet
+ * @param buffer_len Size of the buffer
+ * @return VPNSE_SUCCESS on success, 1 if no tunnel is established, error code on failure
+ */
+int vpnse_get_tunnel_subnet(vpnse_client_t* client, char* subnet_buffer, size_t buffer_len);
+
+/**
+ * Get the current public IP address, to check that traffic leaves through the VPN
+ *
+ * Queries external services, so it only works with public_ip.enabled set in
+ * the configuration; vpnse_check_connection() is the default check.
+ *
+ * @param client VPN client instance
+ * @param ip_buffer Buffer receiving the NUL-terminated address
+ * @param buffer_len Size of the buffer
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_get_public_ip(vpnse_client_t* client, char* ip_buffer, size_t buffer_len);
+
+/**
+ * Get the interface configuration for a VPN interface the app creates
+ *
+ * Fills the buffer with a JSON object with the tunnel "address",
+ * "prefix_len", "gateway", "mtu", "dns_servers", "dns_domain", "routes"
+ * (CIDR strings; empty means route everything) and "ipv6_address", for
+ * VpnService.Builder or NEPacketTunnelNetworkSettings. Call after
+ * vpnse_client_authenticate().
+ *
+ * @param client VPN client instance
+ * @param buffer Buffer receiving the NUL-terminated JSON string
+ * @param buffer_len Size of the buffer (1024 bytes is sufficient)
+ * @return VPNSE_SUCCESS on success, VPNSE_BUFFER_TOO_SMALL if the buffer is too small
+ */
+int vpnse_client_tunnel_settings(vpnse_client_t* client, char* buffer, size_t buffer_len);
+
+/**
+ * Use a VPN interface created by the app, by file descriptor (Android)
+ *
+ * Starts forwarding between the session and the interface without touching
+ * routes, DNS or the firewall, which the app configured itself. The
+ * descriptor is duplicated; the app keeps and closes its own.
+ *
+ * @param client VPN client instance (must be authenticated)
+ * @param fd Descriptor of the interface, e.g. ParcelFileDescriptor.getFd()
+ * @return VPNSE_SUCCESS on success, VPNSE_TUNNEL_ERROR on platforms without descriptors
+ */
+int vpnse_client_attach_tun_fd(vpnse_client_t* client, int fd);
+
+/**
+ * Exchange tunnel packets with the app instead of an interface (iOS)
+ *
+ * Starts forwarding through a packet queue: feed packets read from
+ * NEPacketTunnelFlow to vpnse_client_write_inbound_packet() and write those
+ * returned by vpnse_client_read_outbound_packet() back to it.
+ *
+ * @param client VPN client instance (must be authenticated)
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_attach_packet_flow(vpnse_client_t* client);
+
+/**
+ * Send one IP packet from the host's network stack through the tunnel
+ *
+ * Never blocks; the packet is dropped with VPNSE_NETWORK_ERROR when the
+ * queue is full.
+ *
+ * @param client VPN client instance with an attached packet flow
+ * @param packet The IP packet
+ * @param packet_len Length of the packet in bytes
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_write_inbound_packet(const vpnse_client_t* client, const uint8_t* packet, size_t packet_len);
+
+/**
+ * Receive the next IP packet from the tunnel for the host's network stack
+ *
+ * @param client VPN client instance with an attached packet flow
+ * @param buffer Buffer receiving the packet (at least the tunnel MTU)
+ * @param buffer_len Size of the buffer; longer packets are truncated
+ * @param timeout_ms How long to wait for a packet
+ * @return Packet length, 0 if none arrived in time, or a negated error code
+ */
+int vpnse_client_read_outbound_packet(const vpnse_client_t* client, uint8_t* buffer, size_t buffer_len,
+                                      uint32_t timeout_ms);
+
+/**
+ * DNS update event callback
+ *
+ * @param addresses count packed 16-byte addresses (valid only during the call)
+ * @param count Number of DNS servers now configured
+ * @param applied 1 if the platform resolver was reconfigured, 0 if pending until the tunnel is up
+ * @param user_data Pointer passed to vpnse_client_set_dns_updated_callback()
+ */
+typedef void (*vpnse_dns_updated_cb)(const uint8_t* addresses, size_t count, int applied, void* user_data);
+
+/**
+ * Register a callback for DNS update events
+ *
+ * @param client VPN client instance
+ * @param callback Event callback, or NULL to remove a previously set one
+ * @param user_data Opaque pointer passed back to the callback
+ * @return VPNSE_SUCCESS on success, error code on failure
+ */
+int vpnse_client_set_dns_updated_callback(vpnse_client_t* client, vpnse_dns_updated_cb callback, void* user_data);
+
+/*
+ * Event callbacks
+ *
+ * Push notifications instead of polling vpnse_client_status(). Callbacks run
+ * on the thread that caused the event (a runtime worker thread for the async
+ * calls); stats callbacks run on a runtime timer thread. They must return
+ * quickly and must not free the client. Pass NULL to remove a callback.
+ */
+
+/**
+ * Connection status change callback
+ *
+ * @param from Previous status (codes as returned by vpnse_client_status())
+ * @param to New status
+ * @param user_data Pointer passed at registration
+ */
+typedef void (*vpnse_state_change_cb)(int from, int to, void* user_data);
+
+/**
+ * Error callback
+ *
+ * @param code Error code (vpnse_error_t)
+ * @param context What failed: "connect", "authenticate", "tunnel", "forwarding", "session" or "dns"
+ * @param message Error description (valid only during the call)
+ * @param user_data Pointer passed at registration
+ */
+typedef void (*vpnse_error_cb)(int code, const char* context, const char* message, void* user_data);
+
+/**
+ * Reconnection / cluster failover progress callback
+ *
+ * @param phase 0 = attempting, 1 = succeeded, 2 = failed
+ * @param attempt Consecutive attempts so far, starting at 1
+ * @param retry_after_ms Delay before another attempt is possible (0 if unknown)
+ * @param user_data Pointer passed at registration
+ */
+typedef void (*vpnse_reconnect_cb)(int phase, uint32_t attempt, uint64_t retry_after_ms, void* user_data);
+
+/**
+ * Traffic counters callback, called periodically while tunneling
+ */
+typedef void (*vpnse_stats_cb)(uint64_t bytes_sent, uint64_t bytes_received,
+                               uint64_t packets_sent, uint64_t packets_received, void* user_data);
+
+int vpnse_client_set_state_change_callback(vpnse_client_t* client, vpnse_state_change_cb callback, void* user_data);
+int vpnse_client_set_error_callback(vpnse_client_t* client, vpnse_error_cb callback, void* user_data);
+int vpnse_client_set_reconnect_callback(vpnse_client_t* client, vpnse_reconnect_cb callback, void* user_data);
+
+/**
+ * Register a stats callback
+ *
+ * @param interval_ms Time between callbacks (at least 100 ms); applies from the next tunnel establishment
+ */
+int vpnse_client_set_stats_callback(vpnse_client_t* client, vpnse_stats_cb callback, uint32_t interval_ms, void* user_data);
+
+/**
+ * TLS to the server implemented by the host application
+ *
+ * connect() gets the connected TCP socket in blocking mode (a file descriptor,
+ * or a SOCKET on Windows) and the server name, runs the handshake and returns
+ * the session, or NULL on failure. read() and write() block and return the
+ * bytes transferred, 0 at the end of the stream or a negative value on error;
+ * they are called from two threads at once. peer_certificate() (may be NULL)
+ * copies the server's DER certificate into buffer and returns its full
+ * length, or a negative value if unknown. close() releases the session; the
+ * library closes the socket afterwards.
+ */
+typedef struct {
+    void* (*connect)(int64_t socket, const char* server_name, void* user_data);
+    intptr_t (*read)(void* session, uint8_t* buffer, size_t len, void* user_data);
+    intptr_t (*write)(void* session, const uint8_t* data, size_t len, void* user_data);
+    intptr_t (*peer_certificate)(void* session, uint8_t* buffer, size_t len, void* user_data);
+    void (*close)(void* session, void* user_data);
+} vpnse_tls_callbacks_t;
+
+/**
+ * Use the host application's TLS for the connections to the server
+ *
+ * Chain verification is up to the callbacks; tls.pinned_certificates are
+ * still checked against peer_certificate(). Applies from the next connect.
+ *
+ * @param client VPN client instance
+ * @param callbacks Provider callbacks (copied), or NULL for the built-in TLS
+ * @param user_data Opaque pointer passed back to every callback
+ * @return VPNSE_SUCCESS on success, VPNSE_INVALID_PARAMETER if a required callback is missing
+ */
+int vpnse_client_set_tls_provider(vpnse_client_t* client, const vpnse_tls_callbacks_t* callbacks, void* user_data);
+
 #ifdef __cplusplus
 }
 #endif
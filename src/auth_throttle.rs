@@ -0,0 +1,262 @@
+//! Authentication Failure Throttling
+//!
+//! Tracks consecutive authentication failures per profile and enforces a
+//! local lockout after repeated credential rejections, independent of the
+//! network retry limits in [`ConnectionTracker`](crate::client::ConnectionTracker).
+//! Every failure is reported as a structured [`AuthFailure`] so front ends can
+//! show a countdown instead of letting the user hammer the server.
+
+use crate::config::AuthConfig;
+use crate::error::VpnError;
+use crate::timestamp::Timestamp;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Why an authentication attempt failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailureReason {
+    /// The server rejected the credentials
+    InvalidCredentials,
+    /// The server could not be reached or dropped the connection
+    Network,
+    /// The attempt timed out
+    Timeout,
+    /// Unexpected protocol response
+    Protocol,
+    /// Refused locally because the profile is locked out
+    LockedOut,
+}
+
+impl AuthFailureReason {
+    /// Classify an error returned by the authentication path
+    pub fn from_error(error: &VpnError) -> Self {
+        match error {
+            VpnError::Authentication(_) => AuthFailureReason::InvalidCredentials,
+            VpnError::Network(_) | VpnError::Connection(_) | VpnError::Io(_) | VpnError::Tls(_) => {
+                AuthFailureReason::Network
+            }
+            VpnError::Timeout(_) => AuthFailureReason::Timeout,
+            _ => AuthFailureReason::Protocol,
+        }
+    }
+
+    /// Only rejected credentials count towards the lockout; transport errors
+    /// are already handled by the connection retry limits
+    fn counts_towards_lockout(self) -> bool {
+        self == AuthFailureReason::InvalidCredentials
+    }
+}
+
+impl fmt::Display for AuthFailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AuthFailureReason::InvalidCredentials => "invalid_credentials",
+            AuthFailureReason::Network => "network",
+            AuthFailureReason::Timeout => "timeout",
+            AuthFailureReason::Protocol => "protocol",
+            AuthFailureReason::LockedOut => "locked_out",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Structured authentication failure event
+#[derive(Debug, Clone)]
+pub struct AuthFailure {
+    /// Profile the attempt was made for (`user@server:port/hub`)
+    pub profile: String,
+    pub reason: AuthFailureReason,
+    /// Consecutive failures for this profile, including this one
+    pub attempt: u32,
+    /// When the failure was recorded
+    pub at: Timestamp,
+    /// Earliest time the next attempt will be allowed, if locked out
+    pub next_allowed: Option<Timestamp>,
+}
+
+impl AuthFailure {
+    /// Time left until the next attempt is allowed (zero if not locked out)
+    pub fn retry_after(&self) -> Duration {
+        // Measured on the monotonic clock, so a wall-clock change cannot stretch the lockout
+        self.next_allowed
+            .map(|t| t.duration_since(&Timestamp::now()))
+            .unwrap_or_default()
+    }
+}
+
+/// Callback receiving authentication failure events
+pub type AuthFailureHandler = std::sync::Arc<dyn Fn(&AuthFailure) + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct FailureStreak {
+    /// Consecutive failures of any kind
+    attempts: u32,
+    /// Consecutive credential rejections
+    rejections: u32,
+    locked_until: Option<Timestamp>,
+}
+
+/// Per-profile failure streaks and lockout policy
+#[derive(Debug)]
+pub struct AuthThrottle {
+    max_failures: u32,
+    lockout: Duration,
+    max_lockout: Duration,
+    streaks: Mutex<HashMap<String, FailureStreak>>,
+}
+
+impl AuthThrottle {
+    /// Create a throttle using the lockout policy from the auth configuration
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            max_failures: config.max_auth_failures,
+            lockout: Duration::from_secs(u64::from(config.lockout_secs)),
+            max_lockout: Duration::from_secs(u64::from(config.max_lockout_secs)),
+            streaks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Check whether `profile` may attempt to authenticate now
+    ///
+    /// Returns the lockout event as the error when it may not.
+    pub fn check(&self, profile: &str) -> std::result::Result<(), AuthFailure> {
+        let streaks = self.streaks.lock().unwrap();
+        let streak = match streaks.get(profile) {
+            Some(streak) => streak,
+            None => return Ok(()),
+        };
+        let now = Timestamp::now();
+        match streak.locked_until {
+            Some(until) if until.instant > now.instant => Err(AuthFailure {
+                profile: profile.to_string(),
+                reason: AuthFailureReason::LockedOut,
+                attempt: streak.attempts,
+                at: now,
+                next_allowed: Some(until),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record a failed attempt and return the resulting event
+    pub fn record_failure(&self, profile: &str, reason: AuthFailureReason) -> AuthFailure {
+        let now = Timestamp::now();
+        let mut streaks = self.streaks.lock().unwrap();
+        let streak = streaks.entry(profile.to_string()).or_default();
+        streak.attempts = streak.attempts.saturating_add(1);
+
+        if reason.counts_towards_lockout() {
+            streak.rejections = streak.rejections.saturating_add(1);
+            if self.max_failures > 0 && streak.rejections >= self.max_failures {
+                let lockout = self.lockout_for(streak.rejections);
+                streak.locked_until = Some(now + lockout);
+            }
+        }
+
+        AuthFailure {
+            profile: profile.to_string(),
+            reason,
+            attempt: streak.attempts,
+            at: now,
+            next_allowed: streak.locked_until,
+        }
+    }
+
+    /// Clear the failure streak after a successful authentication
+    pub fn record_success(&self, profile: &str) {
+        self.streaks.lock().unwrap().remove(profile);
+    }
+
+    /// Exponential lockout: `lockout * 2^(rejections - max_failures)`, capped
+    fn lockout_for(&self, rejections: u32) -> Duration {
+        let exponent = rejections.saturating_sub(self.max_failures).min(16);
+        self.lockout
+            .saturating_mul(1u32 << exponent)
+            .min(self.max_lockout)
+    }
+}
+
+/// Error returned to the caller for a locked out profile
+pub fn lockout_error(failure: &AuthFailure) -> VpnError {
+    VpnError::RateLimitExceeded(format!(
+        "Authentication for {} locked after {} failed attempts. Wait {} seconds.",
+        failure.profile,
+        failure.attempt,
+        failure.retry_after().as_secs()
+    ))
+}
+
+/// Emit the event to the log in a structured, grep-friendly form
+pub fn log_failure(failure: &AuthFailure) {
+    log::warn!(
+        "auth_failure profile={} reason={} attempt={} retry_after_secs={}",
+        failure.profile,
+        failure.reason,
+        failure.attempt,
+        failure.retry_after().as_secs()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn throttle(max_failures: u32) -> AuthThrottle {
+        let mut config = crate::config::Config::default_test().auth;
+        config.max_auth_failures = max_failures;
+        config.lockout_secs = 30;
+        config.max_lockout_secs = 90;
+        AuthThrottle::new(&config)
+    }
+
+    #[test]
+    fn test_lockout_after_repeated_rejections() {
+        let throttle = throttle(2);
+        let profile = "user@vpn:443/VPN";
+
+        let first = throttle.record_failure(profile, AuthFailureReason::InvalidCredentials);
+        assert_eq!(first.attempt, 1);
+        assert!(first.next_allowed.is_none());
+        assert!(throttle.check(profile).is_ok());
+
+        let second = throttle.record_failure(profile, AuthFailureReason::InvalidCredentials);
+        assert_eq!(second.attempt, 2);
+        assert!(second.retry_after() > Duration::from_secs(25));
+
+        let locked = throttle.check(profile).unwrap_err();
+        assert_eq!(locked.reason, AuthFailureReason::LockedOut);
+        assert_eq!(locked.attempt, 2);
+
+        // Backoff doubles but is capped at max_lockout_secs
+        throttle.record_failure(profile, AuthFailureReason::InvalidCredentials);
+        let capped = throttle.record_failure(profile, AuthFailureReason::InvalidCredentials);
+        assert!(capped.retry_after() <= Duration::from_secs(90));
+        assert!(capped.retry_after() > Duration::from_secs(85));
+
+        throttle.record_success(profile);
+        assert!(throttle.check(profile).is_ok());
+    }
+
+    #[test]
+    fn test_network_failures_do_not_lock_out() {
+        let throttle = throttle(1);
+        let failure = throttle.record_failure("p", AuthFailureReason::Network);
+        assert_eq!(failure.attempt, 1);
+        assert!(failure.next_allowed.is_none());
+        assert!(throttle.check("p").is_ok());
+    }
+
+    #[test]
+    fn test_reason_classification() {
+        assert_eq!(
+            AuthFailureReason::from_error(&VpnError::Authentication("bad password".into())),
+            AuthFailureReason::InvalidCredentials
+        );
+        assert_eq!(
+            AuthFailureReason::from_error(&VpnError::Timeout("slow".into())),
+            AuthFailureReason::Timeout
+        );
+    }
+}
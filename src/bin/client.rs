@@ -5,7 +5,8 @@
 
 use rvpnse::{
     client::{VpnClient, ConnectionStatus},
-    config::{Config, ServerConfig, AuthConfig, AuthMethod, NetworkConfig, ConnectionLimitsConfig, LoggingConfig, ClusteringConfig},
+    config::{Config, ServerConfig, AuthConfig, AuthMethod, NetworkConfig, ConnectionLimitsConfig, LoggingConfig, ClusteringConfig, RoutingConfig, IdentityConfig, TunnelOptionsConfig, PublicIpConfig, DiagnosticsConfig, HealthConfig, ReconnectConfig, TlsOptionsConfig, NatTraversalConfig, Transport},
+    diagnostics::{DEFAULT_DNS_PROBE_TIMEOUT, DEFAULT_PING_TIMEOUT},
     error::{Result, VpnError},
 };
 use std::env;
@@ -26,6 +27,9 @@ async fn main() -> Result<()> {
 
     // Parse command line arguments
     let args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "doctor" {
+        return run_doctor(args.get(2).map(String::as_str).unwrap_or("config.toml"));
+    }
     let config_path = if args.len() > 2 && args[1] == "--config" {
         &args[2]
     } else if args.len() > 1 && !args[1].starts_with("--") {
@@ -45,8 +49,14 @@ async fn main() -> Result<()> {
 
     // Create VPN client
     let mut client = VpnClient::new(config.clone())?;
+    client.set_config_origin(config_path);
     info!("VPN client initialized");
 
+    let privileges = client.check_privileges();
+    for missing in &privileges.missing {
+        warn!("Missing privilege: {} - {}", missing.detail, missing.remediation);
+    }
+
     // Setup signal handlers for graceful shutdown
     let shutdown_signal = setup_shutdown_handler();
 
@@ -117,6 +127,24 @@ async fn main() -> Result<()> {
     // Display connection information
     display_connection_info(&client, &config).await;
 
+    // Verify DNS through the tunnel (bounded, never blocks startup for long)
+    match client.run_dns_diagnostics(DEFAULT_DNS_PROBE_TIMEOUT).await {
+        Ok(report) => report.log_summary(),
+        Err(e) => warn!("DNS diagnostics unavailable: {}", e),
+    }
+
+    // Verify the hub answers; the external public IP check is opt-in
+    match client.check_hub_connectivity(DEFAULT_PING_TIMEOUT).await {
+        Ok(rtt) => println!("   ✅ Hub gateway answered in {:?}", rtt),
+        Err(e) => warn!("Hub connectivity check failed: {}", e),
+    }
+    if config.public_ip.enabled {
+        match client.get_current_public_ip().await {
+            Ok(ip) => println!("   🌐 Public IP: {}", ip),
+            Err(e) => warn!("Public IP lookup failed: {}", e),
+        }
+    }
+
     // Setup signal handlers for graceful shutdown
     let (shutdown_tx, mut shutdown_rx) = tokio::sync::oneshot::channel::<()>();
     tokio::spawn(async move {
@@ -136,15 +164,23 @@ async fn main() -> Result<()> {
             }
             _ = keepalive_interval.tick() => {
                 // Check connection status and send keepalive
-                let status = client.status();
-                if status == ConnectionStatus::Tunneling || status == ConnectionStatus::Connected {
-                    debug!("Sending keepalive...");
-                    if let Err(e) = client.send_keepalive().await {
-                        warn!("Keepalive failed: {}", e);
+                let alive = match client.check_session() {
+                    Ok(()) => {
+                        debug!("Sending keepalive...");
+                        client.send_keepalive().await
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = alive {
+                    warn!("Connection lost ({}), status: {:?}", e, client.status());
+                    if !config.reconnect.enabled {
+                        break;
                     }
-                } else {
-                    warn!("Connection lost, status: {:?}", status);
-                    // You could implement reconnection logic here
+                    if let Err(e) = client.reconnect().await {
+                        error!("Reconnection failed: {}", e);
+                        break;
+                    }
+                    info!("Connection restored");
                 }
             }
         }
@@ -189,6 +225,18 @@ async fn load_config(config_path: &str) -> Result<Config> {
     }
 }
 
+/// Print a redacted support snapshot as JSON and exit
+///
+/// Unlike a normal run this never creates a default config file, so the
+/// report reflects exactly what is on disk.
+fn run_doctor(config_path: &str) -> Result<()> {
+    let config = Config::from_file(config_path)?;
+    let mut client = VpnClient::new(config)?;
+    client.set_config_origin(config_path);
+    println!("{}", client.doctor().to_json());
+    Ok(())
+}
+
 /// Create a default configuration
 fn create_default_config() -> Config {
     Config {
@@ -201,19 +249,36 @@ fn create_default_config() -> Config {
             verify_certificate: true,
             timeout: 30,
             keepalive_interval: 60,
+            idle_timeout: None,
+            dead_peer_timeout: None,
+            max_connections: 1,
+            transports: vec![Transport::Tcp],
+            use_compression: false,
+            encrypt_data: false,
         },
         connection_limits: ConnectionLimitsConfig::default(),
         auth: AuthConfig {
             method: AuthMethod::Password,
             username: Some("vpnuser".to_string()),
             password: Some("vpnpass".to_string()),
-            client_cert: None,
-            client_key: None,
             ca_cert: None,
+            max_auth_failures: 3,
+            lockout_secs: 30,
+            max_lockout_secs: 900,
+            otp_timeout_secs: 120,
         },
         network: NetworkConfig::default(),
         logging: LoggingConfig::default(),
         clustering: ClusteringConfig::default(),
+        routing: RoutingConfig::default(),
+        identity: IdentityConfig::default(),
+        tunnel: TunnelOptionsConfig::default(),
+        public_ip: PublicIpConfig::default(),
+        diagnostics: DiagnosticsConfig::default(),
+        health: HealthConfig::default(),
+        reconnect: ReconnectConfig::default(),
+        tls: TlsOptionsConfig::default(),
+        nat_traversal: NatTraversalConfig::default(),
     }
 }
 
@@ -374,6 +439,7 @@ fn print_usage() {
     println!();
     println!("USAGE:");
     println!("    rvpnse-client [CONFIG_FILE]");
+    println!("    rvpnse-client doctor [CONFIG_FILE]");
     println!();
     println!("ARGS:");
     println!("    CONFIG_FILE    Path to configuration file (default: config.toml)");
@@ -385,6 +451,9 @@ fn print_usage() {
     println!("    # Use custom config");
     println!("    sudo rvpnse-client /etc/rvpnse/client.toml");
     println!();
+    println!("    # Print a redacted state dump for support tickets");
+    println!("    rvpnse-client doctor /etc/rvpnse/client.toml");
+    println!();
     println!("CONFIG FORMAT:");
     println!("    The configuration file should be in TOML format.");
     println!("    A default config will be created if none exists.");
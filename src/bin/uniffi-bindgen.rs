@@ -0,0 +1,7 @@
+//! Writes the Kotlin and Swift sources for the `bindings` feature
+//!
+//! `cargo run --features bindings --bin uniffi-bindgen -- generate --library <librvpnse> --language kotlin --out-dir <dir>`
+
+fn main() {
+    uniffi::uniffi_bindgen_main()
+}
@@ -0,0 +1,389 @@
+//! Kotlin and Swift bindings, generated with UniFFI
+//!
+//! Built with the `bindings` feature. The types here wrap [`crate::Config`]
+//! and [`crate::VpnClient`] for apps that would otherwise call the C API in
+//! `include/rvpnse.h` through hand-written wrappers: `uniffi-bindgen` reads
+//! them from the built library and writes the Kotlin and Swift sources (see
+//! `bindings/README.md`).
+//!
+//! Connects and logins run on the [`blocking`](crate::blocking) runtime and
+//! are awaited from Kotlin coroutines or Swift tasks; cancelling the caller
+//! cancels the call and drops the half-open connection, like
+//! `vpnse_client_connect_cancel`. Calls on one client are serialised, so a
+//! call made while a connect is in progress waits for it.
+
+use crate::client::events::{self, ReconnectPhase};
+use crate::client::{ClientStats, ConnectionStatus};
+use crate::error::{ErrorCode, VpnError};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::{Mutex, Notify};
+
+/// Error raised in Kotlin and Swift
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+pub enum VpnseError {
+    /// `code` is the stable kind, `message` the description of this failure
+    #[error("{message}")]
+    Failed { code: ErrorCode, message: String },
+}
+
+impl From<VpnError> for VpnseError {
+    fn from(error: VpnError) -> Self {
+        VpnseError::Failed { code: error.code(), message: error.to_string() }
+    }
+}
+
+type Result<T> = std::result::Result<T, VpnseError>;
+
+/// A parsed and validated client configuration
+#[derive(uniffi::Object)]
+pub struct Config(crate::Config);
+
+#[uniffi::export]
+impl Config {
+    /// Parse a TOML configuration
+    #[uniffi::constructor]
+    pub fn from_toml(toml: String) -> Result<Arc<Self>> {
+        let config: crate::Config = toml.parse()?;
+        config.validate()?;
+        Ok(Arc::new(Self(config)))
+    }
+
+    /// Read a TOML configuration file
+    #[uniffi::constructor]
+    pub fn from_file(path: String) -> Result<Arc<Self>> {
+        let config = crate::Config::from_file(path)?;
+        config.validate()?;
+        Ok(Arc::new(Self(config)))
+    }
+
+    /// The configuration as TOML
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(self.0.to_toml()?)
+    }
+
+    /// Server address from `[server]`
+    pub fn server_address(&self) -> String {
+        self.0.server.address.clone()
+    }
+
+    /// Server port from `[server]`
+    pub fn server_port(&self) -> u16 {
+        self.0.server.port
+    }
+}
+
+/// A failure while connecting or running the tunnel
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ErrorEvent {
+    /// What was being done: `connect`, `authenticate`, `tunnel`, `forwarding`, `session` or `dns`
+    pub context: String,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+/// A step of re-establishing a lost or failed-over connection
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct ReconnectEvent {
+    pub phase: ReconnectPhase,
+    /// Consecutive attempts so far, starting at 1
+    pub attempt: u32,
+    /// Server being tried (`address:port`), if known
+    pub endpoint: Option<String>,
+    /// Delay before the next attempt is allowed (only for `Failed`)
+    pub retry_in: Option<Duration>,
+}
+
+/// Traffic counters sent while tunneling
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct TrafficCounters {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+}
+
+/// Interface configuration for the VPN interface the app creates
+#[derive(Debug, Clone, uniffi::Record)]
+pub struct TunnelSettings {
+    pub address: String,
+    pub prefix_len: u8,
+    pub gateway: String,
+    pub mtu: u16,
+    /// Resolvers in order of preference, IPv4 and IPv6
+    pub dns_servers: Vec<String>,
+    pub dns_domain: Option<String>,
+    /// Networks pushed by the server (`10.1.0.0/16`); empty means route everything
+    pub routes: Vec<String>,
+    /// IPv6 address as `address/prefix_len`, on dual-stack tunnels
+    pub ipv6_address: Option<String>,
+}
+
+/// Receives a client's events, implemented in Kotlin or Swift
+///
+/// Methods run on the thread that caused the event (stats on a timer task)
+/// while the client is busy with it, so they should return quickly and not
+/// call back into the client.
+#[uniffi::export(with_foreign)]
+pub trait VpnEventListener: Send + Sync {
+    fn on_state_changed(&self, from: ConnectionStatus, to: ConnectionStatus);
+    fn on_error(&self, event: ErrorEvent);
+    fn on_reconnect(&self, event: ReconnectEvent);
+    fn on_stats(&self, counters: TrafficCounters);
+}
+
+/// Work a connect or login does on the runtime
+enum ClientCall {
+    Connect { server: String, port: u16 },
+    Authenticate { username: String, password: zeroize::Zeroizing<String> },
+}
+
+impl ClientCall {
+    async fn run(&self, client: &mut crate::VpnClient) -> crate::Result<()> {
+        match self {
+            Self::Connect { server, port } => client.connect_async(server, *port).await,
+            Self::Authenticate { username, password } => client.authenticate(username, password).await,
+        }
+    }
+}
+
+/// Signals the call when the awaiting Kotlin or Swift task goes away
+struct CancelOnDrop(Arc<Notify>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.notify_one();
+    }
+}
+
+/// SoftEther VPN client
+#[derive(uniffi::Object)]
+pub struct VpnClient {
+    inner: Arc<Mutex<crate::VpnClient>>,
+    runtime: Arc<Runtime>,
+}
+
+#[uniffi::export]
+impl VpnClient {
+    /// Create a client for `config`
+    #[uniffi::constructor]
+    pub fn new(config: Arc<Config>) -> Result<Arc<Self>> {
+        let client = crate::VpnClient::new(config.0.clone())?;
+        Ok(Arc::new(Self { inner: Arc::new(Mutex::new(client)), runtime: crate::blocking::runtime()? }))
+    }
+
+    /// Connect to the server and run the handshake
+    pub async fn connect(&self, server: String, port: u16) -> Result<()> {
+        self.run(ClientCall::Connect { server, port }).await
+    }
+
+    /// Log in on the connected server
+    pub async fn authenticate(&self, username: String, password: String) -> Result<()> {
+        let password = zeroize::Zeroizing::new(password);
+        self.run(ClientCall::Authenticate { username, password }).await
+    }
+
+    /// Create the TUN interface and route traffic through it (desktop)
+    pub fn establish_tunnel(&self) -> Result<()> {
+        let _entered = self.runtime.enter();
+        Ok(self.inner.blocking_lock().establish_tunnel()?)
+    }
+
+    /// Interface configuration for `VpnService.Builder` or `NEPacketTunnelNetworkSettings`, once logged in
+    pub fn tunnel_settings(&self) -> Result<TunnelSettings> {
+        let settings = self.inner.blocking_lock().host_tunnel_settings()?;
+        Ok(TunnelSettings {
+            address: settings.address.to_string(),
+            prefix_len: settings.prefix_len,
+            gateway: settings.gateway.to_string(),
+            mtu: settings.mtu,
+            dns_servers: settings.dns_servers.iter().map(ToString::to_string).collect(),
+            dns_domain: settings.dns_domain,
+            routes: settings.routes,
+            ipv6_address: settings.ipv6_address,
+        })
+    }
+
+    /// Forward packets through the VPN interface behind `fd`, which is duplicated
+    #[cfg(unix)]
+    pub fn attach_tun_fd(&self, fd: i32) -> Result<()> {
+        let _entered = self.runtime.enter();
+        Ok(self.inner.blocking_lock().attach_tun_fd(fd)?)
+    }
+
+    /// Disconnect, tearing down the tunnel
+    pub fn disconnect(&self) -> Result<()> {
+        let _entered = self.runtime.enter();
+        Ok(self.inner.blocking_lock().disconnect()?)
+    }
+
+    pub fn status(&self) -> ConnectionStatus {
+        self.inner.blocking_lock().status()
+    }
+
+    /// Traffic counters, session uptime, reconnect count and the last measured RTT
+    pub fn stats(&self) -> ClientStats {
+        self.inner.blocking_lock().stats()
+    }
+
+    /// Send events to `listener`, stats every `stats_interval` while tunneling; `None` stops them
+    pub fn set_listener(&self, listener: Option<Arc<dyn VpnEventListener>>, stats_interval: Duration) {
+        let mut client = self.inner.blocking_lock();
+        let events = client.events();
+        let Some(listener) = listener else {
+            events.on_state_change(None);
+            events.on_error(None);
+            events.on_reconnect(None);
+            events.on_stats(None, stats_interval);
+            return;
+        };
+
+        let target = listener.clone();
+        events.on_state_change(Some(Arc::new(move |change: &events::StateChange| {
+            target.on_state_changed(change.from, change.to);
+        })));
+        let target = listener.clone();
+        events.on_error(Some(Arc::new(move |event: &events::ErrorEvent<'_>| {
+            target.on_error(ErrorEvent {
+                context: event.context.to_string(),
+                code: event.error.code(),
+                message: event.error.to_string(),
+            });
+        })));
+        let target = listener.clone();
+        events.on_reconnect(Some(Arc::new(move |event: &events::ReconnectEvent| {
+            target.on_reconnect(ReconnectEvent {
+                phase: event.phase,
+                attempt: event.attempt,
+                endpoint: event.endpoint.map(|endpoint| endpoint.to_string()),
+                retry_in: event.retry_in,
+            });
+        })));
+        events.on_stats(
+            Some(Arc::new(move |snapshot: &crate::PerformanceSnapshot| {
+                listener.on_stats(TrafficCounters {
+                    bytes_sent: snapshot.bytes_sent,
+                    bytes_received: snapshot.bytes_received,
+                    packets_sent: snapshot.packets_sent,
+                    packets_received: snapshot.packets_received,
+                });
+            })),
+            stats_interval,
+        );
+    }
+}
+
+impl VpnClient {
+    /// Run `call` on the runtime, cancelling it if the awaiting task goes away
+    async fn run(&self, call: ClientCall) -> Result<()> {
+        let inner = self.inner.clone();
+        let cancel = Arc::new(Notify::new());
+        let _cancel_on_drop = CancelOnDrop(cancel.clone());
+        let task = self.runtime.spawn(async move {
+            let mut client = inner.lock().await;
+            tokio::select! {
+                result = call.run(&mut client) => result,
+                _ = cancel.notified() => {
+                    // Reset the half-open connection state
+                    let _ = client.disconnect();
+                    Err(VpnError::Cancelled("Call cancelled".to_string()))
+                }
+            }
+        });
+        match task.await {
+            Ok(result) => Ok(result?),
+            Err(err) => Err(VpnError::Other(format!("Call failed: {err}")).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn test_config() -> Arc<Config> {
+        Arc::new(Config(crate::Config::default_test()))
+    }
+
+    struct Recorder(std::sync::Mutex<mpsc::Sender<(ConnectionStatus, ConnectionStatus)>>);
+
+    impl VpnEventListener for Recorder {
+        fn on_state_changed(&self, from: ConnectionStatus, to: ConnectionStatus) {
+            let _ = self.0.lock().unwrap().send((from, to));
+        }
+        fn on_error(&self, _event: ErrorEvent) {}
+        fn on_reconnect(&self, _event: ReconnectEvent) {}
+        fn on_stats(&self, _counters: TrafficCounters) {}
+    }
+
+    #[test]
+    fn test_config_round_trip_and_errors() {
+        let config = test_config();
+        let parsed = Config::from_toml(config.to_toml().unwrap()).unwrap();
+        assert_eq!(parsed.server_address(), config.server_address());
+        assert_eq!(parsed.server_port(), config.server_port());
+
+        let Err(VpnseError::Failed { code, .. }) = Config::from_toml("[server".to_string()) else {
+            panic!("invalid TOML parsed");
+        };
+        assert_eq!(code, ErrorCode::InvalidConfig);
+    }
+
+    #[test]
+    fn test_client_reports_failed_connect() {
+        // Nothing listens on this port once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let client = VpnClient::new(test_config()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        client.set_listener(Some(Arc::new(Recorder(std::sync::Mutex::new(tx)))), Duration::from_secs(1));
+
+        let result = futures::executor::block_on(client.connect("127.0.0.1".to_string(), port));
+        assert!(matches!(result, Err(VpnseError::Failed { .. })));
+        assert_eq!(client.status(), ConnectionStatus::Disconnected);
+        assert_eq!(client.stats().bytes_sent, 0);
+        let states: Vec<_> = rx.try_iter().collect();
+        assert_eq!(
+            states,
+            [
+                (ConnectionStatus::Disconnected, ConnectionStatus::Connecting),
+                (ConnectionStatus::Connecting, ConnectionStatus::Disconnected)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dropping_the_caller_cancels_connect() {
+        // Accepts the TCP connection but never answers the TLS handshake
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _accepted = std::thread::spawn(move || listener.accept());
+
+        let client = VpnClient::new(test_config()).unwrap();
+        futures::executor::block_on(async {
+            let connect = client.connect("127.0.0.1".to_string(), port);
+            let timeout = futures_timer(Duration::from_millis(200));
+            futures::pin_mut!(connect, timeout);
+            assert!(matches!(futures::future::select(connect, timeout).await, futures::future::Either::Right(_)));
+        });
+
+        // The cancelled connect releases the client once it has reset it
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while client.inner.try_lock().is_err() && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(client.inner.try_lock().is_ok());
+        assert_eq!(client.status(), ConnectionStatus::Disconnected);
+    }
+
+    /// Resolves after `duration`, without a Tokio runtime
+    async fn futures_timer(duration: Duration) {
+        let (tx, rx) = futures::channel::oneshot::channel::<()>();
+        std::thread::spawn(move || {
+            std::thread::sleep(duration);
+            let _ = tx.send(());
+        });
+        let _ = rx.await;
+    }
+}
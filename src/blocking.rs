@@ -0,0 +1,176 @@
+//! Blocking facade over the async client
+//!
+//! Synchronous callers, the C FFI among them, run the async client on one
+//! runtime shared by the whole process instead of a runtime per call. Tasks
+//! a call leaves behind, such as packet forwarding after the tunnel is up,
+//! keep running once it returns, and a blocking call made from inside an
+//! async context fails with [`VpnError::InvalidState`] rather than panicking.
+//!
+//! ```no_run
+//! use rvpnse::{blocking, Config};
+//!
+//! # fn main() -> rvpnse::Result<()> {
+//! let config = Config::from_file("vpn.toml")?;
+//! let (server, port) = (config.server.address.clone(), config.server.port);
+//! let mut client = blocking::VpnClient::new(config)?;
+//! client.connect(&server, port)?;
+//! client.authenticate("user", "pass")?;
+//! client.establish_tunnel()?;
+//! client.disconnect()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::config::Config;
+use crate::diagnostics::DnsDiagnostics;
+use crate::error::{Result, VpnError};
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+
+static RUNTIME: OnceLock<Arc<Runtime>> = OnceLock::new();
+
+/// The runtime blocking calls run on, started on first use
+pub fn runtime() -> Result<Arc<Runtime>> {
+    if let Some(runtime) = RUNTIME.get() {
+        return Ok(runtime.clone());
+    }
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("vpnse-blocking")
+        .build()
+        .map_err(|e| VpnError::Connection(format!("Failed to create runtime: {e}")))?;
+    // A racing caller may have won; its runtime is used and this one dropped
+    Ok(RUNTIME.get_or_init(|| Arc::new(runtime)).clone())
+}
+
+/// Run `future` to completion on the shared runtime
+///
+/// # Errors
+/// Fails when called from inside a Tokio runtime, where blocking would stall
+/// or panic; await the async API there instead.
+pub fn block_on<F: Future>(future: F) -> Result<F::Output> {
+    outside_runtime()?;
+    Ok(runtime()?.block_on(future))
+}
+
+/// [`crate::VpnClient`] with blocking calls, run on the shared runtime
+///
+/// Calls not covered here go through [`client`](Self::client) and
+/// [`client_mut`](Self::client_mut).
+pub struct VpnClient {
+    inner: crate::VpnClient,
+    runtime: Arc<Runtime>,
+}
+
+impl VpnClient {
+    /// Create a client for `config`
+    pub fn new(config: Config) -> Result<Self> {
+        Self::from_client(crate::VpnClient::new(config)?)
+    }
+
+    /// Drive an existing client
+    pub fn from_client(inner: crate::VpnClient) -> Result<Self> {
+        Ok(Self { inner, runtime: runtime()? })
+    }
+
+    /// See [`crate::VpnClient::connect_async`]
+    pub fn connect(&mut self, server: &str, port: u16) -> Result<()> {
+        block_on_with(&self.runtime, self.inner.connect_async(server, port))
+    }
+
+    /// See [`crate::VpnClient::connect_to_cluster`]
+    pub fn connect_to_cluster(&mut self) -> Result<()> {
+        block_on_with(&self.runtime, self.inner.connect_to_cluster())
+    }
+
+    /// See [`crate::VpnClient::authenticate`]
+    pub fn authenticate(&mut self, username: &str, password: &str) -> Result<()> {
+        block_on_with(&self.runtime, self.inner.authenticate(username, password))
+    }
+
+    /// See [`crate::VpnClient::change_password`]
+    pub fn change_password(&mut self, old_password: &str, new_password: &str) -> Result<()> {
+        block_on_with(&self.runtime, self.inner.change_password(old_password, new_password))
+    }
+
+    /// See [`crate::VpnClient::establish_tunnel`]; packet forwarding runs on
+    /// the shared runtime
+    pub fn establish_tunnel(&mut self) -> Result<()> {
+        let _entered = self.runtime.enter();
+        self.inner.establish_tunnel()
+    }
+
+    /// See [`crate::VpnClient::check_hub_connectivity`]
+    pub fn check_hub_connectivity(&self, deadline: Duration) -> Result<Duration> {
+        block_on_with(&self.runtime, self.inner.check_hub_connectivity(deadline))
+    }
+
+    /// See [`crate::VpnClient::run_dns_diagnostics`]
+    pub fn run_dns_diagnostics(&self, deadline: Duration) -> Result<DnsDiagnostics> {
+        block_on_with(&self.runtime, self.inner.run_dns_diagnostics(deadline))
+    }
+
+    /// See [`crate::VpnClient::disconnect`]
+    pub fn disconnect(&mut self) -> Result<()> {
+        let _entered = self.runtime.enter();
+        self.inner.disconnect()
+    }
+
+    /// The async client
+    pub fn client(&self) -> &crate::VpnClient {
+        &self.inner
+    }
+
+    /// The async client, for calls without a blocking form
+    pub fn client_mut(&mut self) -> &mut crate::VpnClient {
+        &mut self.inner
+    }
+
+    /// Give up the facade, keeping the client
+    pub fn into_inner(self) -> crate::VpnClient {
+        self.inner
+    }
+}
+
+fn block_on_with<T>(runtime: &Runtime, future: impl Future<Output = Result<T>>) -> Result<T> {
+    outside_runtime()?;
+    runtime.block_on(future)
+}
+
+fn outside_runtime() -> Result<()> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(_) => Err(VpnError::InvalidState("Blocking call made from an async context; use the async API".to_string())),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ConnectionStatus;
+
+    #[test]
+    fn test_blocking_calls_share_a_runtime() {
+        assert!(Arc::ptr_eq(&runtime().unwrap(), &runtime().unwrap()));
+        assert_eq!(block_on(async { tokio::spawn(async { 7 }).await.unwrap() }).unwrap(), 7);
+
+        // Tasks outlive the call that spawned them
+        let (tx, rx) = std::sync::mpsc::channel();
+        block_on(async move {
+            tokio::spawn(async move { tx.send(()).unwrap() });
+        })
+        .unwrap();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refused_inside_a_runtime() {
+        assert!(matches!(block_on(async {}), Err(VpnError::InvalidState(_))));
+
+        let mut client = VpnClient::new(Config::default_test()).unwrap();
+        assert!(matches!(client.connect("127.0.0.1", 1), Err(VpnError::InvalidState(_))));
+        assert_eq!(client.client().status(), ConnectionStatus::Disconnected);
+    }
+}
@@ -0,0 +1,165 @@
+//! Runtime Feature Introspection
+//!
+//! Describes what this build of the library was compiled with and what the
+//! host platform actually offers, so applications can adapt their UI (e.g.
+//! hide full-tunnel mode when no TUN device is available).
+
+use serde::Serialize;
+
+/// Compiled features and detected platform capabilities
+#[derive(Debug, Clone, Serialize)]
+pub struct Capabilities {
+    /// Library version
+    pub version: &'static str,
+    /// TLS implementation
+    pub tls_backend: &'static str,
+    /// Crypto provider backing TLS (`ring`, `aws-lc-rs` or `none`)
+    pub crypto_provider: &'static str,
+    /// Whether the crypto provider runs in FIPS mode
+    pub fips: bool,
+    /// Whether UDP based transports (R-UDP / QUIC) are available
+    pub udp_transport: bool,
+    /// Whether QUIC is available
+    pub quic: bool,
+    /// Whether PAC proxy scripts can be evaluated
+    pub pac: bool,
+    /// Whether the VPN Gate server list can be fetched
+    pub vpngate: bool,
+    /// Tunnel backends compiled into this build
+    pub tunnel_backends: Vec<&'static str>,
+    /// Capabilities detected on the running system
+    pub platform: PlatformCapabilities,
+}
+
+/// Capabilities detected at runtime
+#[derive(Debug, Clone, Serialize)]
+pub struct PlatformCapabilities {
+    /// Operating system name
+    pub os: &'static str,
+    /// A TUN device can be opened by this process
+    pub tun_available: bool,
+    /// Netlink sockets can be created (Linux only)
+    pub netlink_available: bool,
+    /// The process runs with administrative privileges
+    pub privileged: bool,
+}
+
+impl Capabilities {
+    /// Serialize to a JSON object
+    pub fn to_json(&self) -> String {
+        // Serialization of plain strings and booleans cannot fail
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+    }
+}
+
+/// Collect compiled and runtime capabilities
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        version: crate::VERSION,
+        tls_backend: "rustls",
+        crypto_provider: crypto_provider(),
+        fips: false,
+        udp_transport: false,
+        quic: false,
+        pac: cfg!(feature = "pac"),
+        vpngate: cfg!(feature = "vpngate"),
+        tunnel_backends: tunnel_backends(),
+        platform: PlatformCapabilities {
+            os: std::env::consts::OS,
+            tun_available: tun_available(),
+            netlink_available: netlink_available(),
+            privileged: is_privileged(),
+        },
+    }
+}
+
+fn crypto_provider() -> &'static str {
+    if cfg!(feature = "ring-crypto") {
+        "ring"
+    } else if cfg!(feature = "aws-lc-crypto") {
+        "aws-lc-rs"
+    } else {
+        "none"
+    }
+}
+
+fn tunnel_backends() -> Vec<&'static str> {
+    let mut backends = Vec::new();
+    if cfg!(target_os = "linux") {
+        backends.push("linux-tun");
+    }
+    if cfg!(target_os = "macos") {
+        backends.push("macos-utun");
+    }
+    if cfg!(target_os = "windows") {
+        backends.push("windows-tap");
+    }
+    backends
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn tun_available() -> bool {
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/net/tun")
+        .is_ok()
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn tun_available() -> bool {
+    // utun is part of the kernel, but creating one requires root
+    is_privileged()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub(crate) fn tun_available() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn netlink_available() -> bool {
+    // SAFETY: plain socket creation; the descriptor is closed right away
+    unsafe {
+        let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE);
+        if fd < 0 {
+            return false;
+        }
+        libc::close(fd);
+        true
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn netlink_available() -> bool {
+    false
+}
+
+#[cfg(unix)]
+pub(crate) fn is_privileged() -> bool {
+    // SAFETY: geteuid has no preconditions
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_privileged() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capabilities_json() {
+        let caps = capabilities();
+        assert_eq!(caps.version, crate::VERSION);
+        assert_eq!(caps.platform.os, std::env::consts::OS);
+
+        let json = caps.to_json();
+        assert!(json.starts_with('{'));
+        assert!(json.contains("\"tls_backend\":\"rustls\""));
+        assert!(json.contains("\"platform\":{"));
+        assert!(json.contains("\"tun_available\":"));
+    }
+}
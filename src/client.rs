@@ -3,17 +3,64 @@
 //! This module provides the main VpnClient struct that handles `SoftEther` SSL-VPN
 //! protocol communication and tunnel management.
 
-use crate::config::Config;
-use crate::error::{Result, VpnError};
-use crate::protocol::{AuthClient, ProtocolHandler};
+use crate::auth_throttle::{self, AuthFailure, AuthFailureHandler, AuthFailureReason, AuthThrottle};
+use crate::client_optimized::{self, PerformanceConfig, PerformanceRates, PerformanceSnapshot, PerformanceStats, SnapshotHistory};
+use crate::config::{AuthMethod, Config, NetworkConfig, RouteMode, SessionTimeouts, Transport};
+use crate::crypto::tls::{CertificatePolicy, TlsProvider, TlsRelay, TlsTarget};
+use crate::diagnostics::{self, DnsDiagnostics};
+use crate::doctor::{DiagnosticLog, DoctorReport};
+use crate::error::{ErrorCode, Result, VpnError};
+use crate::privileges::{self, PrivilegeReport};
+use crate::resolver;
+use crate::protocol::{rpc, AuthClient, LoginMethod, OtpProvider, ProtocolHandler, ServerInfo};
 use crate::protocol::binary::BinaryProtocolClient;
+use crate::protocol::cert_auth::ClientCertificate;
+use crate::protocol::credentials::CredentialCache;
+use crate::protocol::resumption::TicketStore;
+use crate::protocol::bonding::{self, BondHealth, LinkStatus};
+use crate::protocol::nat_traversal::{NatTraversal, NatTraversalRelay};
+use crate::protocol::proxy::{ProxyRelay, UpstreamProxy};
+use crate::protocol::heartbeat::Heartbeat;
 use crate::protocol::session::SessionManager;
-use crate::tunnel::{TunnelConfig, TunnelManager};
+use crate::protocol::udp_accel::{self, DataPath, UdpAccelGrant, UdpAccelOffer, UdpAccelSession};
+use crate::tunnel::{
+    dhcp, host_device, l2, ChangeJournal, DhcpClient, DhcpLease, FramingParams, HostTunnelSettings, KillSwitch,
+    KillSwitchPolicy, MacAddr, NeighborStats, PacketFlow, RateLimiter, SystemChangePlanner, SystemOps, TunReader,
+    TunWriter, TunnelConfig, TunnelHttpBinding, TunnelManager, WorkerPool,
+};
+use crate::tunnel::workers::{ShardedReceiver, ShardedSender};
+use crate::underlay::UnderlayBinding;
+use bytes::Bytes;
+use serde::Serialize;
 use std::collections::HashMap;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+pub mod events;
+pub mod failover;
+pub mod health;
+pub mod probe;
+pub mod reconnect;
+pub mod state;
+pub mod stats;
+
+pub use events::{ClientEvents, DnsUpdated, ReconnectEvent, ReconnectPhase};
+pub use failover::{FailoverEvent, FailoverPhase};
+pub use reconnect::DisconnectedReason;
+use reconnect::IdleWatch;
+pub use state::{ConnectionState, Transition};
+pub use health::{HealthEvent, HealthReport, HealthState};
+use health::HealthMonitor;
+pub use probe::ServerProbe;
+pub use stats::ClientStats;
+use stats::SessionClock;
+
+/// Traffic snapshots kept for [`VpnClient::rates_over`]
+const STATS_HISTORY_LEN: usize = 120;
 
 /// Cluster node information
 #[derive(Debug, Clone)]
@@ -24,8 +71,120 @@ pub struct ClusterNode {
     pub active_connections: u32,
     pub last_health_check: Instant,
     pub response_time: Duration,
+    /// Decaying failure penalty and blacklist state
+    pub penalty: NodePenalty,
 }
 
+/// Why a connection attempt to a cluster node failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeFailure {
+    /// The node rejected our credentials
+    AuthFailed,
+    /// The node accepted the connection and then dropped it
+    ConnectionReset,
+    /// The node could not be resolved or connected to
+    Unreachable,
+}
+
+impl NodeFailure {
+    /// Classify an error returned while connecting to a node
+    pub fn from_error(error: &VpnError) -> Self {
+        match error {
+            VpnError::Authentication(_) => NodeFailure::AuthFailed,
+            VpnError::Connection(_)
+            | VpnError::Io(_)
+            | VpnError::Tls(_)
+            | VpnError::Protocol(_)
+            | VpnError::ServerRefused { .. } => NodeFailure::ConnectionReset,
+            _ => NodeFailure::Unreachable,
+        }
+    }
+}
+
+impl std::fmt::Display for NodeFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            NodeFailure::AuthFailed => "auth_failed",
+            NodeFailure::ConnectionReset => "connection_reset",
+            NodeFailure::Unreachable => "unreachable",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Failure penalty of a cluster node
+///
+/// Each failure adds one to `score`, which halves every `penalty_half_life`
+/// seconds. A failure blacklists the node for `base * 2^(score - 1)` seconds,
+/// capped at `blacklist_max_penalty`, so a node that keeps failing is retried
+/// ever less often while one that recovers is forgiven over time.
+#[derive(Debug, Clone, Default)]
+pub struct NodePenalty {
+    score: f64,
+    /// When `score` was last updated
+    updated: Option<Instant>,
+    blacklisted_until: Option<Instant>,
+    last_failure: Option<NodeFailure>,
+}
+
+impl NodePenalty {
+    /// Failure score at `now`, after decay
+    fn score_at(&self, now: Instant, half_life: Duration) -> f64 {
+        match self.updated {
+            Some(updated) => {
+                let elapsed = now.saturating_duration_since(updated).as_secs_f64();
+                self.score * 0.5f64.powf(elapsed / half_life.as_secs_f64())
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Time left on the blacklist at `now`, if any
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        self.blacklisted_until
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+}
+
+/// Point-in-time view of a cluster node, as returned by
+/// [`VpnClient::get_cluster_status`]
+///
+/// Serializes with durations in milliseconds, for server pickers.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClusterNodeStatus {
+    /// Position in `cluster_nodes`, as passed to [`VpnClient::connect_to_cluster_member`]
+    pub index: usize,
+    pub address: String,
+    pub healthy: bool,
+    /// TCP connect time measured by the last probe, if it succeeded
+    #[serde(rename = "response_time_ms", serialize_with = "serialize_millis")]
+    pub response_time: Option<Duration>,
+    pub active_connections: u32,
+    /// Decayed failure score
+    pub penalty: f64,
+    /// Time left until the node is tried again, if blacklisted
+    #[serde(rename = "blacklisted_for_ms", serialize_with = "serialize_millis")]
+    pub blacklisted_for: Option<Duration>,
+    pub last_failure: Option<NodeFailure>,
+    /// Chosen explicitly; failover does not move away from it
+    pub pinned: bool,
+}
+
+fn serialize_millis<D: Copy + Into<Option<Duration>>, S: serde::Serializer>(
+    duration: &D,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    (*duration).into().map(|d| d.as_millis() as u64).serialize(serializer)
+}
+
+/// Outcome of probing the node at an index: its endpoint and connect time, if it answered
+type ProbeResult = (usize, Option<(SocketAddr, Duration)>);
+
+/// How long the startup probe waits for a faster node once a healthy one has answered
+const PROBE_GRACE: Duration = Duration::from_millis(100);
+
 /// Cluster manager for handling multiple VPN endpoints
 #[derive(Debug)]
 pub struct ClusterManager {
@@ -33,19 +192,27 @@ pub struct ClusterManager {
     current_node_index: usize,
     total_connections: u32,
     config: crate::config::ClusteringConfig,
-    last_failover: Instant,
+    /// When failover last moved to another node
+    last_failover: Option<Instant>,
+    /// Whether the startup probe has populated the health table
+    warmed_up: bool,
+    /// Node the user chose, which failover does not move away from
+    pinned: Option<usize>,
+    /// Results of startup probes still running in the background
+    probe_results: Option<tokio::sync::mpsc::UnboundedReceiver<ProbeResult>>,
 }
 
 impl ClusterManager {
     pub fn new(config: crate::config::ClusteringConfig) -> Self {
-        let nodes = config.cluster_nodes.iter().map(|addr| {
+        let nodes = config.cluster_nodes.iter().map(|node| {
             ClusterNode {
-                address: addr.clone(),
+                address: node.address.clone(),
                 endpoint: None,
                 is_healthy: true,
                 active_connections: 0,
                 last_health_check: Instant::now(),
                 response_time: Duration::from_millis(0),
+                penalty: NodePenalty::default(),
             }
         }).collect();
 
@@ -54,32 +221,42 @@ impl ClusterManager {
             current_node_index: 0,
             total_connections: 0,
             config,
-            last_failover: Instant::now(),
+            last_failover: None,
+            warmed_up: false,
+            pinned: None,
+            probe_results: None,
         }
     }
 
     /// Get the next available node based on load balancing strategy
     pub fn get_next_node(&mut self) -> Option<&mut ClusterNode> {
+        let index = self.next_node_index()?;
+        Some(&mut self.nodes[index])
+    }
+
+    /// Index of the next available node, skipping blacklisted ones
+    fn next_node_index(&mut self) -> Option<usize> {
+        self.apply_probe_results();
         if self.nodes.is_empty() {
             return None;
         }
 
+        let now = Instant::now();
         match self.config.load_balancing_strategy {
-            crate::config::LoadBalancingStrategy::RoundRobin => {
-                let current_index = self.current_node_index;
-                self.current_node_index = (self.current_node_index + 1) % self.nodes.len();
-                Some(&mut self.nodes[current_index])
-            },
             crate::config::LoadBalancingStrategy::LeastConnections => {
-                self.nodes.iter_mut()
-                    .filter(|n| n.is_healthy)
-                    .min_by_key(|n| n.active_connections)
+                self.nodes.iter()
+                    .enumerate()
+                    .filter(|(_, n)| n.is_healthy && n.penalty.remaining(now).is_none())
+                    .min_by_key(|(_, n)| n.active_connections)
+                    .map(|(i, _)| i)
             },
             crate::config::LoadBalancingStrategy::Random => {
                 use rand::Rng;
                 let healthy_indices: Vec<_> = self.nodes.iter()
                     .enumerate()
-                    .filter_map(|(i, n)| if n.is_healthy { Some(i) } else { None })
+                    .filter_map(|(i, n)| {
+                        if n.is_healthy && n.penalty.remaining(now).is_none() { Some(i) } else { None }
+                    })
                     .collect();
                 
                 if healthy_indices.is_empty() {
@@ -88,14 +265,121 @@ impl ClusterManager {
                 
                 let mut rng = rand::thread_rng();
                 let idx = rng.gen_range(0..healthy_indices.len());
-                let node_index = healthy_indices[idx];
-                Some(&mut self.nodes[node_index])
+                Some(healthy_indices[idx])
             },
             _ => {
-                // Default to round-robin for other strategies
-                let current_index = self.current_node_index;
-                self.current_node_index = (self.current_node_index + 1) % self.nodes.len();
-                Some(&mut self.nodes[current_index])
+                // Round-robin, also the default for other strategies
+                for _ in 0..self.nodes.len() {
+                    let current_index = self.current_node_index;
+                    self.current_node_index = (self.current_node_index + 1) % self.nodes.len();
+                    if self.nodes[current_index].penalty.remaining(now).is_none() {
+                        return Some(current_index);
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Record a failed connection attempt to the node at `index`
+    ///
+    /// Returns how long the node is blacklisted for.
+    pub fn record_failure(&mut self, index: usize, failure: NodeFailure) -> Duration {
+        let half_life = Duration::from_secs(u64::from(self.config.penalty_half_life.max(1)));
+        let base = f64::from(self.config.blacklist_base_penalty);
+        let max = f64::from(self.config.blacklist_max_penalty);
+        let now = Instant::now();
+
+        let penalty = &mut self.nodes[index].penalty;
+        penalty.score = penalty.score_at(now, half_life) + 1.0;
+        penalty.updated = Some(now);
+        penalty.last_failure = Some(failure);
+
+        let duration = Duration::from_secs_f64((base * 2f64.powf(penalty.score - 1.0)).min(max));
+        penalty.blacklisted_until = Some(now + duration);
+
+        log::warn!(
+            "Cluster node {} failed ({}), blacklisted for {}s",
+            self.nodes[index].address,
+            failure,
+            duration.as_secs()
+        );
+        duration
+    }
+
+    /// Record a successful connection to the node at `index`
+    ///
+    /// Lifts any blacklist; the failure score keeps decaying so a flapping
+    /// node still escalates.
+    pub fn record_success(&mut self, index: usize) {
+        if let Some(node) = self.nodes.get_mut(index) {
+            node.penalty.blacklisted_until = None;
+        }
+    }
+
+    /// Whether the node at `index` is currently blacklisted
+    pub fn is_blacklisted(&self, index: usize) -> bool {
+        self.nodes
+            .get(index)
+            .is_some_and(|n| n.penalty.remaining(Instant::now()).is_some())
+    }
+
+    /// Time until the first blacklisted node becomes eligible again
+    pub fn next_retry(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.nodes.iter().filter_map(|n| n.penalty.remaining(now)).min()
+    }
+
+    /// Status of every node, including penalty and blacklist state
+    pub fn status(&mut self) -> Vec<ClusterNodeStatus> {
+        self.apply_probe_results();
+        let now = Instant::now();
+        let half_life = Duration::from_secs(u64::from(self.config.penalty_half_life.max(1)));
+        self.nodes.iter().enumerate().map(|(index, node)| ClusterNodeStatus {
+            index,
+            address: node.address.clone(),
+            healthy: node.is_healthy,
+            response_time: Some(node.response_time).filter(|rtt| node.is_healthy && !rtt.is_zero()),
+            active_connections: node.active_connections,
+            penalty: node.penalty.score_at(now, half_life),
+            blacklisted_for: node.penalty.remaining(now),
+            last_failure: node.penalty.last_failure,
+            pinned: self.pinned == Some(index),
+        }).collect()
+    }
+
+    /// Pin the node at `index` (or nothing), so failover stays off other nodes
+    pub fn pin(&mut self, index: Option<usize>) {
+        self.pinned = index.filter(|&i| i < self.nodes.len());
+        if let Some(index) = self.pinned {
+            self.current_node_index = index;
+        }
+    }
+
+    /// Index of the pinned node, if any
+    pub fn pinned(&self) -> Option<usize> {
+        self.pinned
+    }
+
+    /// Endpoint of the node at `index`, resolving it if no probe has yet
+    ///
+    /// A node that does not resolve is marked unhealthy and penalised.
+    fn resolve_node(&mut self, index: usize) -> Result<SocketAddr> {
+        let node = &mut self.nodes[index];
+        if let Some(endpoint) = node.endpoint {
+            return Ok(endpoint);
+        }
+        match node.address.to_socket_addrs().map(|mut addrs| addrs.next()) {
+            Ok(Some(addr)) => {
+                node.endpoint = Some(addr);
+                Ok(addr)
+            }
+            Ok(None) => Err(VpnError::Connection("No available cluster nodes".to_string())),
+            Err(e) => {
+                node.is_healthy = false;
+                let error = VpnError::Connection(format!("Failed to resolve cluster node {}: {}", node.address, e));
+                self.record_failure(index, NodeFailure::Unreachable);
+                Err(error)
             }
         }
     }
@@ -124,6 +408,7 @@ impl ClusterManager {
 
     /// Perform health check on cluster nodes
     pub async fn health_check(&mut self) -> Result<()> {
+        self.apply_probe_results();
         for node in &mut self.nodes {
             if node.last_health_check.elapsed() > Duration::from_secs(self.config.health_check_interval as u64) {
                 // Simple health check - try to resolve the address
@@ -145,18 +430,141 @@ impl ClusterManager {
         Ok(())
     }
 
+    /// Whether the node health table has been populated by a startup probe
+    pub fn is_warm(&self) -> bool {
+        self.warmed_up
+    }
+
+    /// Probe all nodes concurrently and return the index of the fastest healthy one
+    ///
+    /// At most `probe_concurrency` probes are in flight at once and each is
+    /// bounded by `probe_timeout`. Returns once a healthy node has answered
+    /// and [`PROBE_GRACE`] has passed without a faster one, or once every
+    /// probe is over. Probes still running go on in the background and
+    /// update each node's health, endpoint and response time as they finish,
+    /// so later load balancing starts with a warm table.
+    pub async fn probe_all_nodes(&mut self) -> Option<usize> {
+        use futures::stream::{self, StreamExt};
+
+        let timeout = Duration::from_secs(self.config.probe_timeout as u64);
+        let concurrency = self.config.probe_concurrency as usize;
+        let addresses: Vec<String> = self.nodes.iter().map(|node| node.address.clone()).collect();
+
+        let (sender, mut results) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut probes = stream::iter(addresses.into_iter().enumerate())
+                .map(|(i, address)| async move { (i, probe_node(&address, timeout).await) })
+                .buffer_unordered(concurrency);
+            while let Some(result) = probes.next().await {
+                if sender.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut fastest: Option<(usize, Duration)> = None;
+        let mut grace_deadline = None;
+        loop {
+            let result = match grace_deadline {
+                None => results.recv().await,
+                Some(deadline) => match tokio::time::timeout_at(deadline, results.recv()).await {
+                    Ok(result) => result,
+                    Err(_) => break,
+                },
+            };
+            let Some((i, result)) = result else {
+                break;
+            };
+            if let Some(rtt) = self.apply_probe_result(i, result) {
+                if fastest.is_none_or(|(_, best)| rtt < best) {
+                    fastest = Some((i, rtt));
+                }
+                grace_deadline.get_or_insert_with(|| tokio::time::Instant::now() + PROBE_GRACE);
+            }
+        }
+        self.probe_results = Some(results);
+
+        log::info!(
+            "Probing {} cluster nodes, {} healthy so far",
+            self.nodes.len(),
+            self.nodes.iter().filter(|n| n.is_healthy).count()
+        );
+
+        self.warmed_up = true;
+
+        fastest.map(|(i, _)| {
+            self.current_node_index = i;
+            i
+        })
+    }
+
+    /// Record the outcome of probing the node at `index`
+    ///
+    /// Returns the response time of a node that answered and is not blacklisted.
+    fn apply_probe_result(&mut self, index: usize, result: Option<(SocketAddr, Duration)>) -> Option<Duration> {
+        let node = &mut self.nodes[index];
+        node.last_health_check = Instant::now();
+        let Some((endpoint, rtt)) = result else {
+            node.is_healthy = false;
+            return None;
+        };
+        node.endpoint = Some(endpoint);
+        node.is_healthy = true;
+        node.response_time = rtt;
+        node.penalty.remaining(Instant::now()).is_none().then_some(rtt)
+    }
+
+    /// Record the results background probes have delivered since the last call
+    fn apply_probe_results(&mut self) {
+        use tokio::sync::mpsc::error::TryRecvError;
+
+        let Some(mut results) = self.probe_results.take() else {
+            return;
+        };
+        loop {
+            match results.try_recv() {
+                Ok((index, result)) => {
+                    self.apply_probe_result(index, result);
+                }
+                Err(TryRecvError::Empty) => {
+                    self.probe_results = Some(results);
+                    return;
+                }
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+    }
+
     /// Handle failover to next healthy node
+    ///
+    /// Never moves away from a pinned node, nor within `failover_timeout` of the last failover.
     pub fn failover(&mut self) -> Option<&ClusterNode> {
-        if self.last_failover.elapsed() < Duration::from_secs(self.config.failover_timeout as u64) {
-            return None; // Too soon for another failover
+        if self.pinned.is_some() || self.failover_cooldown().is_some() {
+            return None;
         }
+        self.next_available_node()
+    }
+
+    /// Time left before [`failover`](Self::failover) moves to another node again
+    pub fn failover_cooldown(&self) -> Option<Duration> {
+        let since = self.last_failover?.elapsed();
+        Duration::from_secs(u64::from(self.config.failover_timeout))
+            .checked_sub(since)
+            .filter(|left| !left.is_zero())
+    }
 
-        // Find next healthy node
+    /// Move to the next healthy node that is not blacklisted, regardless of pinning and cooldown
+    ///
+    /// Lets a failover already under way go on to the following node when
+    /// the one it picked fails too.
+    pub fn next_available_node(&mut self) -> Option<&ClusterNode> {
+        self.apply_probe_results();
+        let now = Instant::now();
         for _ in 0..self.nodes.len() {
             self.current_node_index = (self.current_node_index + 1) % self.nodes.len();
             let node = &self.nodes[self.current_node_index];
-            if node.is_healthy {
-                self.last_failover = Instant::now();
+            if node.is_healthy && node.penalty.remaining(now).is_none() {
+                self.last_failover = Some(now);
                 return Some(node);
             }
         }
@@ -165,8 +573,20 @@ impl ClusterManager {
     }
 }
 
+/// Resolve a node and time a TCP connect to it, giving up after `timeout`
+async fn probe_node(address: &str, timeout: Duration) -> Option<(SocketAddr, Duration)> {
+    let probe = async {
+        let start = Instant::now();
+        let endpoint = tokio::net::lookup_host(address).await.ok()?.next()?;
+        tokio::net::TcpStream::connect(endpoint).await.ok()?;
+        Some((endpoint, start.elapsed()))
+    };
+    tokio::time::timeout(timeout, probe).await.ok().flatten()
+}
+
 /// Connection status enumeration
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "bindings", derive(uniffi::Enum))]
 pub enum ConnectionStatus {
     Disconnected,
     Connecting,
@@ -174,6 +594,7 @@ pub enum ConnectionStatus {
     Tunneling, // Full tunnel established
 }
 
+
 /// `SoftEther` VPN Client with full tunnel support
 ///
 /// This client handles both `SoftEther` SSL-VPN protocol communication
@@ -190,14 +611,142 @@ pub struct VpnClient {
     protocol_handler: Option<ProtocolHandler>,
     session_manager: Option<SessionManager>,
     tunnel_manager: Option<TunnelManager>,
-    status: ConnectionStatus,
+    state: ConnectionState,
     server_endpoint: Option<SocketAddr>,
+
+    /// Connect, keepalive and idle timing for the current server
+    timeouts: SessionTimeouts,
     
     /// Cluster manager for SSL-VPN RPC farm support
     cluster_manager: Option<ClusterManager>,
 
     /// Global connection tracker (shared across all clients if needed)
     connection_tracker: Arc<ConnectionTracker>,
+
+    /// Host hook that reviews route/DNS/firewall changes before they are applied
+    change_planner: Option<Arc<dyn SystemChangePlanner>>,
+
+    /// Per-profile authentication failure streaks and lockout
+    auth_throttle: Arc<AuthThrottle>,
+
+    /// Receives structured authentication failure events
+    auth_failure_handler: Option<AuthFailureHandler>,
+
+    /// Supplies one-time passwords to hubs asking for a second factor
+    otp_provider: Option<Arc<dyn OtpProvider>>,
+
+    /// DNS servers set at runtime, handed to the tunnel manager when it is created
+    dns_servers: Option<Vec<IpAddr>>,
+
+    /// State, error, reconnect, stats and DNS event handlers
+    events: ClientEvents,
+
+    /// Consecutive cluster failovers, reset once one succeeds
+    failover_attempts: u32,
+
+    /// Where the configuration came from (file path, FFI, ...), for support dumps
+    config_origin: String,
+
+    /// Recent state transitions and events, for support dumps
+    diagnostics: Mutex<DiagnosticLog>,
+
+    /// Data path counters
+    traffic: Arc<PerformanceStats>,
+
+    /// Upload/download limits the packet pump keeps to, kept across reconnects
+    shaper: Arc<RateLimiter>,
+
+    /// Data path tuning
+    performance: PerformanceConfig,
+
+    /// Samples throughput into `traffic` while tunneling
+    performance_monitor: Option<JoinHandle<()>>,
+
+    /// Recent counter snapshots, for rate computation
+    stats_history: Mutex<SnapshotHistory>,
+
+    /// Start of the current session, for uptime
+    session_clock: SessionClock,
+
+    /// Loss and RTT of the health checks through the tunnel
+    health: HealthMonitor,
+
+    /// Sessions re-established by [`reconnect`](Self::reconnect)
+    reconnects: u32,
+
+    /// Round trip to the server measured by the last keepalive
+    rtt: Mutex<Option<Duration>>,
+
+    /// UDP acceleration path, when negotiated with the server
+    udp_accel: Option<Arc<UdpAccelSession>>,
+
+    /// Frames received over the UDP acceleration path
+    udp_frames: Option<mpsc::Receiver<Bytes>>,
+
+    /// Task sending UDP keepalives and receiving frames
+    udp_pump: Option<JoinHandle<()>>,
+
+    /// Uplink the session is bound to, resolved from `network.underlay_interface` on connect
+    underlay: Option<UnderlayBinding>,
+
+    /// Proxy the data connections go through, chosen on connect
+    proxy: Option<UpstreamProxy>,
+
+    /// Loopback relay for the HTTP client when it cannot use `proxy` itself
+    proxy_relay: Option<ProxyRelay>,
+
+    /// Loopback relay carrying every connection over NAT-T, when that transport is used
+    nat_relay: Option<NatTraversalRelay>,
+
+    /// Transport of the current connection, from `server.transports`
+    transport: Option<Transport>,
+
+    /// Version, hubs and capabilities the server reported before login
+    server_info: Option<ServerInfo>,
+
+    /// TLS stack supplied by the application, rustls when unset
+    tls_provider: Option<Arc<dyn TlsProvider>>,
+
+    /// Loopback relay carrying the HTTP requests over `tls_provider`
+    tls_relay: Option<TlsRelay>,
+
+    /// Binary data session opened by tunneling mode, handed to the packet pump
+    binary_session: Option<BinaryProtocolClient>,
+
+    /// Further data connections granted by the server (`server.max_connections`),
+    /// bonded with `binary_session` when forwarding starts
+    bonded_sessions: Vec<BinaryProtocolClient>,
+
+    /// Per-connection health of the bonded data session, while forwarding
+    bond: Option<BondHealth>,
+
+    /// Keepalive state of the data session, which outlives its hand-over to the pump
+    heartbeat: Option<Arc<Heartbeat>>,
+
+    /// DHCP client that leased the address over the data session (`tunnel.dhcp`),
+    /// until the tunnel manager takes it over
+    dhcp: Option<DhcpClient>,
+
+    /// Password hashes of successful logins, reused by reconnects; wiped on disconnect
+    credentials: CredentialCache,
+
+    /// Tickets resuming the session without a login (`reconnect.resume_session`); dropped on disconnect
+    session_tickets: Option<TicketStore>,
+
+    /// Why the last session ended, until the next connection succeeds
+    disconnect_reason: Option<DisconnectedReason>,
+
+    /// Whether the kill switch should be engaged while tunneling
+    kill_switch_enabled: bool,
+
+    /// Firewall rules blocking non-VPN traffic, kept across session loss and reconnects
+    kill_switch: KillSwitch,
+
+    /// Undo record of the system changes made by the tunnel and kill switch
+    change_journal: Option<ChangeJournal>,
+
+    /// Packets exchanged with the host app, when it owns the tunnel device
+    packet_flow: Option<Arc<PacketFlow>>,
 }
 
 impl VpnClient {
@@ -211,6 +760,13 @@ impl VpnClient {
         } else {
             None
         };
+        let auth_throttle = Arc::new(AuthThrottle::new(&config.auth));
+        let kill_switch_enabled = config.tunnel.kill_switch;
+        let change_journal = ChangeJournal::from_options(&config.tunnel);
+        let session_tickets = TicketStore::from_config(&config);
+        let shaper = Arc::new(RateLimiter::new(config.network.upload_limit_bps, config.network.download_limit_bps));
+        let timeouts = config.session_timeouts(&format!("{}:{}", config.server.address, config.server.port));
+        let health = HealthMonitor::new(&config.health);
 
         Ok(VpnClient {
             config,
@@ -218,10 +774,52 @@ impl VpnClient {
             protocol_handler: None,
             session_manager: None,
             tunnel_manager: None,
-            status: ConnectionStatus::Disconnected,
+            state: ConnectionState::default(),
             server_endpoint: None,
+            timeouts,
             cluster_manager,
             connection_tracker: Arc::new(ConnectionTracker::new()),
+            change_planner: None,
+            auth_throttle,
+            auth_failure_handler: None,
+            otp_provider: None,
+            dns_servers: None,
+            events: ClientEvents::default(),
+            failover_attempts: 0,
+            config_origin: "programmatic".to_string(),
+            diagnostics: Mutex::new(DiagnosticLog::default()),
+            traffic: Arc::new(PerformanceStats::new()),
+            shaper,
+            performance: PerformanceConfig::default(),
+            performance_monitor: None,
+            stats_history: Mutex::new(SnapshotHistory::new(STATS_HISTORY_LEN)),
+            session_clock: SessionClock::default(),
+            health,
+            reconnects: 0,
+            rtt: Mutex::new(None),
+            udp_accel: None,
+            udp_frames: None,
+            udp_pump: None,
+            underlay: None,
+            proxy: None,
+            proxy_relay: None,
+            nat_relay: None,
+            transport: None,
+            server_info: None,
+            tls_provider: None,
+            tls_relay: None,
+            binary_session: None,
+            bonded_sessions: Vec::new(),
+            bond: None,
+            heartbeat: None,
+            dhcp: None,
+            credentials: CredentialCache::new(),
+            session_tickets,
+            disconnect_reason: None,
+            kill_switch_enabled,
+            kill_switch: KillSwitch::new(Arc::new(SystemOps::default())).with_journal(change_journal.clone()),
+            change_journal,
+            packet_flow: None,
         })
     }
 
@@ -236,6 +834,13 @@ impl VpnClient {
         } else {
             None
         };
+        let auth_throttle = Arc::new(AuthThrottle::new(&config.auth));
+        let kill_switch_enabled = config.tunnel.kill_switch;
+        let change_journal = ChangeJournal::from_options(&config.tunnel);
+        let session_tickets = TicketStore::from_config(&config);
+        let shaper = Arc::new(RateLimiter::new(config.network.upload_limit_bps, config.network.download_limit_bps));
+        let timeouts = config.session_timeouts(&format!("{}:{}", config.server.address, config.server.port));
+        let health = HealthMonitor::new(&config.health);
 
         Ok(VpnClient {
             config,
@@ -243,10 +848,52 @@ impl VpnClient {
             protocol_handler: None,
             session_manager: None,
             tunnel_manager: None,
-            status: ConnectionStatus::Disconnected,
+            state: ConnectionState::default(),
             server_endpoint: None,
+            timeouts,
             cluster_manager,
             connection_tracker: tracker,
+            change_planner: None,
+            auth_throttle,
+            auth_failure_handler: None,
+            otp_provider: None,
+            dns_servers: None,
+            events: ClientEvents::default(),
+            failover_attempts: 0,
+            config_origin: "programmatic".to_string(),
+            diagnostics: Mutex::new(DiagnosticLog::default()),
+            traffic: Arc::new(PerformanceStats::new()),
+            shaper,
+            performance: PerformanceConfig::default(),
+            performance_monitor: None,
+            stats_history: Mutex::new(SnapshotHistory::new(STATS_HISTORY_LEN)),
+            session_clock: SessionClock::default(),
+            health,
+            reconnects: 0,
+            rtt: Mutex::new(None),
+            udp_accel: None,
+            udp_frames: None,
+            udp_pump: None,
+            underlay: None,
+            proxy: None,
+            proxy_relay: None,
+            nat_relay: None,
+            transport: None,
+            server_info: None,
+            tls_provider: None,
+            tls_relay: None,
+            binary_session: None,
+            bonded_sessions: Vec::new(),
+            bond: None,
+            heartbeat: None,
+            dhcp: None,
+            credentials: CredentialCache::new(),
+            session_tickets,
+            disconnect_reason: None,
+            kill_switch_enabled,
+            kill_switch: KillSwitch::new(Arc::new(SystemOps::default())).with_journal(change_journal.clone()),
+            change_journal,
+            packet_flow: None,
         })
     }
 
@@ -258,12 +905,17 @@ impl VpnClient {
     ///
     /// This does NOT handle platform networking (TUN/TAP, routing, DNS).
     /// Your application must handle those separately.
+    ///
+    /// Timeouts and keepalive come from [`Config::session_timeouts`] for
+    /// `server:port`.
     pub async fn connect_async(&mut self, server: &str, port: u16) -> Result<()> {
-        if self.status != ConnectionStatus::Disconnected {
-            return Err(VpnError::Connection(
-                "Already connected or connecting".to_string(),
-            ));
-        }
+        let timeouts = self.config.session_timeouts(&format!("{server}:{port}"));
+        self.connect_session(server, port, timeouts).await
+    }
+
+    /// Connect to `server:port`, keeping `timeouts` for the session
+    async fn connect_session(&mut self, server: &str, port: u16, timeouts: SessionTimeouts) -> Result<()> {
+        self.state.check(Transition::Connect)?;
 
         // Create endpoint identifier for retry tracking
         let endpoint_key = format!("{server}:{port}");
@@ -274,11 +926,16 @@ impl VpnClient {
         self.connection_tracker
             .can_retry(&endpoint_key, &self.config.connection_limits)?;
 
-        self.status = ConnectionStatus::Connecting;
-
         // Resolve server address
-        let server_addr = Self::resolve_server_address(server, port)?;
+        let mark = self.socket_mark();
+        let server_addr = Self::resolve_server_address(server, port, &self.config.network, mark, &timeouts).await?;
+
+        // After a failover the server differs from the one the kill switch lets through
+        self.retarget_kill_switch(server_addr.ip())?;
+
+        self.transition(Transition::Connect)?;
         self.server_endpoint = Some(server_addr);
+        self.timeouts = timeouts;
 
         // Attempt connection with proper SoftEther protocol
         let result = self.attempt_connection_async(server_addr, &endpoint_key).await;
@@ -286,12 +943,14 @@ impl VpnClient {
         match result {
             Ok(_) => {
                 self.connection_tracker.record_connection();
-                self.status = ConnectionStatus::Connected;
-                Ok(())
+                self.disconnect_reason = None;
+                self.transition(Transition::Establish)
             }
             Err(e) => {
                 self.connection_tracker.record_retry(&endpoint_key);
-                self.status = ConnectionStatus::Disconnected;
+                self.record_event(format!("Connection to {endpoint_key} failed: {e}"));
+                self.events.error("connect", &e);
+                self.transition(Transition::Fail)?;
                 Err(e)
             }
         }
@@ -301,31 +960,171 @@ impl VpnClient {
     async fn attempt_connection_async(&mut self, server_addr: SocketAddr, endpoint_key: &str) -> Result<()> {
         // Add delay if this is a retry attempt
         if self.config.connection_limits.retry_delay > 0 {
-            let retry_attempts = self.connection_tracker.retry_attempts.lock().unwrap();
-            if let Some((count, _)) = retry_attempts.get(endpoint_key) {
-                if *count > 0 {
-                    tokio::time::sleep(Duration::from_secs(
-                        self.config.connection_limits.retry_delay as u64,
-                    )).await;
+            let retrying = self
+                .connection_tracker
+                .retry_attempts
+                .lock()
+                .unwrap()
+                .get(endpoint_key)
+                .is_some_and(|(count, _)| *count > 0);
+            if retrying {
+                tokio::time::sleep(Duration::from_secs(
+                    self.config.connection_limits.retry_delay as u64,
+                )).await;
+            }
+        }
+
+        // Fall back to the next transport only when this one could not reach the server
+        let transports = self.config.server.transports.clone();
+        for (i, &transport) in transports.iter().enumerate() {
+            let attempt = tokio::time::timeout(self.timeouts.connect, self.open_connection(server_addr, transport))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(VpnError::Timeout(format!(
+                        "Connecting to {endpoint_key} timed out after {}s",
+                        self.timeouts.connect.as_secs()
+                    )))
+                });
+            match attempt {
+                Err(e) if i + 1 < transports.len() && is_unreachable(&e) => {
+                    log::warn!(transport:% = transport, next:% = transports[i + 1]; "Server unreachable: {}", e);
+                    self.record_event(format!("{transport} connection to {endpoint_key} failed: {e}"));
+                }
+                result => {
+                    self.transport = result.is_ok().then_some(transport);
+                    return result;
+                }
+            }
+        }
+        Err(VpnError::Config("server.transports cannot be empty".to_string()))
+    }
+
+    /// Handshake with the server over `transport` and prepare the auth client
+    async fn open_connection(&mut self, server_addr: SocketAddr, transport: Transport) -> Result<()> {
+        // Bind to the chosen uplink; resolved per attempt since addresses change on roaming
+        self.underlay = match self.config.network.underlay_interface {
+            Some(ref name) => Some(UnderlayBinding::resolve(name)?),
+            None => None,
+        };
+        if let Some(ref underlay) = self.underlay {
+            log::info!("Binding session to uplink {} ({})", underlay.interface, underlay.address);
+        }
+
+        // Pick the underlay proxy (static, PAC or WPAD-discovered); NAT-T brings its own
+        let target_host = self.config.server.hostname.as_deref().unwrap_or(&self.config.server.address);
+        self.nat_relay = None;
+        let proxy_url = match transport {
+            Transport::NatT => Some(self.use_nat_traversal(target_host.to_string(), server_addr.port()).await?),
+            Transport::Tcp => {
+                let target_url = format!("https://{}:{}/", target_host, server_addr.port());
+                match crate::proxy::resolve_proxy(&self.config.network, &target_url).await {
+                    Some(proxy_url) => Some(self.use_proxy(&proxy_url).await?),
+                    None => {
+                        self.proxy = None;
+                        self.proxy_relay = None;
+                        None
+                    }
                 }
             }
+        };
+
+        let identity = crate::protocol::ClientIdentity::from_config(&self.config.identity)?;
+
+        let certificates = CertificatePolicy::from_config(&self.config)?;
+
+        // The HTTP client only speaks rustls; any other TLS stack is reached through a relay
+        self.tls_relay = None;
+        let (proxy_url, base_url) = match self.tls_provider {
+            Some(ref provider) => {
+                log::info!("Using TLS provider {}", provider.name());
+                let target = TlsTarget {
+                    server: server_addr,
+                    server_name: self.tls_server_name().unwrap_or_else(|| server_addr.ip().to_string()),
+                    certificates: certificates.clone(),
+                    proxy: self.proxy.clone(),
+                    socket_mark: self.socket_mark(),
+                };
+                let relay = TlsRelay::start(provider.clone(), target).await?;
+                let base_url = relay.url();
+                self.tls_relay = Some(relay);
+                (None, Some(base_url))
+            }
+            None => (proxy_url, None),
+        };
+
+        // The HTTP client cannot mark its sockets, so under policy routing its
+        // direct connections are bound to the current uplink instead
+        let http_underlay = match self.underlay {
+            Some(ref underlay) => Some(underlay.clone()),
+            None if self.socket_mark().is_some() && proxy_url.is_none() && base_url.is_none() => {
+                crate::underlay::default_uplink()
+            }
+            None => None,
+        };
+        if self.socket_mark().is_some() && self.proxy.is_some() && transport == Transport::Tcp {
+            log::warn!("Policy routing does not mark proxied connections; the proxy has to stay reachable outside the tunnel");
         }
 
         // Initialize protocol handler
-        let mut protocol_handler = ProtocolHandler::new(server_addr, self.config.server.verify_certificate)?;
+        let mut protocol_handler = ProtocolHandler::with_underlay(
+            server_addr,
+            &certificates,
+            proxy_url.as_deref(),
+            http_underlay.as_ref(),
+        )?;
+        protocol_handler.set_identity(&identity);
+        if let Some(ref base_url) = base_url {
+            protocol_handler.set_base_url(base_url);
+        }
         
         // Step 1: HTTP watermark handshake
         protocol_handler.establish_session().await?;
+
+        // Step 2: what the server tells before login; a hub it does not list would be refused anyway
+        let server_info = self.server_info.insert(rpc::query_server_info(&protocol_handler).await);
+        server_info.check_hub(&self.config.server.hub)?;
         
         // Initialize auth client
-        let auth_client = AuthClient::new(
-            format!("{}:{}", self.config.server.address, self.config.server.port),
-            self.config.server.hostname.clone(),
+        let mut auth_client = AuthClient::new(
+            server_addr.to_string(),
+            self.tls_server_name(),
             self.config.server.hub.clone(),
             self.config.auth.username.clone().unwrap_or_default(),
             self.config.auth.password.clone().unwrap_or_default(),
             self.config.server.verify_certificate,
         )?;
+        auth_client.set_proxy(proxy_url)?;
+        auth_client.set_underlay(http_underlay)?;
+        auth_client.set_certificate_policy(certificates)?;
+        if let Some(ref base_url) = base_url {
+            auth_client.set_base_url(base_url);
+        }
+        auth_client.set_identity(identity);
+        auth_client.set_max_connections(self.config.server.max_connections);
+        auth_client.set_compression(self.config.server.use_compression);
+        match self.config.auth.method {
+            AuthMethod::Password => {
+                auth_client.set_login_method(LoginMethod::Password);
+                let cached = self.credentials.get(
+                    auth_client.server_address(),
+                    auth_client.hub_name(),
+                    auth_client.username(),
+                );
+                if let Some(hash) = cached {
+                    log::debug!("Logging in with the cached password hash");
+                    auth_client.set_password_hash(hash);
+                }
+            }
+            AuthMethod::External => auth_client.set_login_method(LoginMethod::External),
+            AuthMethod::Anonymous => auth_client.set_login_method(LoginMethod::Anonymous),
+            AuthMethod::Certificate { ref cert_path, ref key_path } => {
+                auth_client.set_client_certificate(Some(ClientCertificate::load(cert_path, key_path)?));
+            }
+        }
+        auth_client.set_otp_provider(
+            self.otp_provider.clone(),
+            Duration::from_secs(u64::from(self.config.auth.otp_timeout_secs)),
+        );
         
         self.protocol_handler = Some(protocol_handler);
         self.auth_client = Some(auth_client);
@@ -333,11 +1132,123 @@ impl VpnClient {
         Ok(())
     }
 
-    /// Parse server address - expects IP:port format
-    fn resolve_server_address(server: &str, port: u16) -> Result<SocketAddr> {
-        // Parse IP address directly - no DNS resolution needed
-        format!("{server}:{port}").parse::<SocketAddr>()
-            .map_err(|e| VpnError::Config(format!("Invalid server address '{server}:{port}': {e}")))
+    /// Route every connection through `proxy_url` and return the URL the HTTP client uses
+    ///
+    /// The HTTP client handles plain HTTP proxies with Basic credentials
+    /// itself; for SOCKS5 and NTLM it goes through a loopback relay. Proxies
+    /// that cannot tunnel the data connections (HTTPS) are left to the HTTP
+    /// client alone.
+    async fn use_proxy(&mut self, proxy_url: &str) -> Result<String> {
+        self.proxy_relay = None;
+        let proxy = match UpstreamProxy::from_url(proxy_url, self.config.network.proxy_auth.as_ref()) {
+            Ok(proxy) => proxy,
+            Err(e) => {
+                log::warn!("Data connections go direct: {}", e);
+                self.proxy = None;
+                return Ok(proxy_url.to_string());
+            }
+        };
+        log::info!("Connecting through proxy {}", proxy.address());
+        let client_url = match proxy.client_url() {
+            Some(url) => url,
+            None => {
+                let relay = ProxyRelay::start(proxy.clone()).await?;
+                let url = relay.url();
+                self.proxy_relay = Some(relay);
+                url
+            }
+        };
+        self.proxy = Some(proxy);
+        Ok(client_url)
+    }
+
+    /// Carry every connection over NAT-T to the server registered as `host`
+    ///
+    /// The streams are offered by a loopback relay, which the HTTP client and
+    /// the data connections use as their proxy; `network.proxy_url` is ignored.
+    async fn use_nat_traversal(&mut self, host: String, port: u16) -> Result<String> {
+        self.proxy_relay = None;
+        log::info!(server = host.as_str(); "Connecting through NAT traversal");
+        let nat_traversal =
+            NatTraversal::new(&self.config.nat_traversal, &host, port)
+                .with_underlay(self.underlay.clone())
+                .with_socket_mark(self.socket_mark());
+        let relay = NatTraversalRelay::start(nat_traversal).await?;
+        let url = relay.url();
+        self.proxy = Some(UpstreamProxy::from_url(&url, None)?);
+        self.nat_relay = Some(relay);
+        Ok(url)
+    }
+
+    /// Resolve `server` and pick the address to connect to
+    ///
+    /// Several addresses are raced Happy Eyeballs style. Through a proxy the
+    /// race would only test the local network, so the first address is used.
+    async fn resolve_server_address(
+        server: &str,
+        port: u16,
+        network: &NetworkConfig,
+        mark: Option<u32>,
+        timeouts: &SessionTimeouts,
+    ) -> Result<SocketAddr> {
+        let addresses = resolver::resolve(server, port, network).await?;
+        if addresses.len() == 1 || network.proxy_url.is_some() || network.pac_url.is_some() {
+            return Ok(addresses[0]);
+        }
+        let race = resolver::race(&addresses, resolver::CONNECTION_ATTEMPT_DELAY, mark);
+        match tokio::time::timeout(timeouts.connect, race).await {
+            Ok(Ok(address)) => Ok(address),
+            Ok(Err(e)) => {
+                log::warn!("None of the addresses of {} answered ({}), trying {}", server, e, addresses[0]);
+                Ok(addresses[0])
+            }
+            Err(_) => {
+                log::warn!("None of the addresses of {} answered in time, trying {}", server, addresses[0]);
+                Ok(addresses[0])
+            }
+        }
+    }
+
+    /// Name presented to the server in TLS: `server.hostname`, else a hostname given as `server.address`
+    fn tls_server_name(&self) -> Option<String> {
+        let server = &self.config.server;
+        let named = server.address.parse::<std::net::IpAddr>().is_err();
+        server.hostname.clone().or_else(|| named.then(|| server.address.clone()))
+    }
+
+    /// Change the configured user's password on the server
+    ///
+    /// Call after [`connect_async`](Self::connect_async), typically when
+    /// [`authenticate`](Self::authenticate) failed with
+    /// [`ErrorCode::PasswordExpired`]; no login is needed. A wrong old
+    /// password counts towards the login lockout. On success the new
+    /// password replaces `auth.password` for later logins and reconnects.
+    pub async fn change_password(&mut self, old_password: &str, new_password: &str) -> Result<()> {
+        if new_password.is_empty() {
+            return Err(VpnError::Config("The new password cannot be empty".to_string()));
+        }
+        let profile = self.auth_profile("");
+        if let Err(failure) = self.auth_throttle.check(&profile) {
+            self.report_auth_failure(&failure);
+            return Err(auth_throttle::lockout_error(&failure));
+        }
+        let auth_client = self
+            .auth_client
+            .as_mut()
+            .ok_or_else(|| VpnError::Connection("Not connected".to_string()))?;
+
+        if let Err(e) = auth_client.change_password(old_password, new_password).await {
+            if let VpnError::Authentication(_) = e {
+                let failure = self.auth_throttle.record_failure(&profile, AuthFailureReason::InvalidCredentials);
+                self.report_auth_failure(&failure);
+            }
+            self.events.error("change_password", &e);
+            return Err(e);
+        }
+        self.credentials.forget(auth_client.server_address(), auth_client.hub_name());
+        self.config.auth.password = Some(new_password.to_string());
+        self.record_event("Password changed".to_string());
+        Ok(())
     }
 
     /// Authenticate with SoftEther VPN server using proper SSL-VPN protocol
@@ -349,13 +1260,58 @@ impl VpnClient {
     /// 4. SSL-VPN handshake completion
     /// 5. DHCP IP assignment request
     pub async fn authenticate(&mut self, username: &str, password: &str) -> Result<()> {
+        self.state.check(Transition::Login)?;
+        let profile = self.auth_profile(username);
+        if let Err(failure) = self.auth_throttle.check(&profile) {
+            self.report_auth_failure(&failure);
+            return Err(auth_throttle::lockout_error(&failure));
+        }
+
+        let socket_mark = self.socket_mark();
         let auth_client = self
             .auth_client
             .as_mut()
             .ok_or_else(|| VpnError::Connection("Not connected".to_string()))?;
 
+        if self.config.network.udp_acceleration {
+            let offer = UdpAccelOffer::bind_on(self.underlay.as_ref()).await;
+            match offer.and_then(|offer| offer.with_socket_mark(socket_mark)) {
+                Ok(offer) => auth_client.set_udp_accel_offer(Some(offer)),
+                Err(e) => log::warn!("UDP acceleration not offered: {}", e),
+            }
+        }
+
+        if let Some(tickets) = self.session_tickets.as_mut() {
+            let user = if username.is_empty() { auth_client.username() } else { username };
+            auth_client.set_session_ticket(tickets.get(auth_client.server_address(), auth_client.hub_name(), user));
+        }
+
         // Perform authentication using PACK binary protocol
-        auth_client.authenticate(username, password).await?;
+        if let Err(e) = auth_client.authenticate(username, password).await {
+            let reason = AuthFailureReason::from_error(&e);
+            if reason == AuthFailureReason::InvalidCredentials {
+                self.credentials.forget(auth_client.server_address(), auth_client.hub_name());
+            }
+            if let Some(tickets) = self.session_tickets.as_mut() {
+                tickets.forget(auth_client.server_address(), auth_client.hub_name());
+            }
+            let failure = self.auth_throttle.record_failure(&profile, reason);
+            self.report_auth_failure(&failure);
+            self.events.error("authenticate", &e);
+            return Err(e);
+        }
+        self.auth_throttle.record_success(&profile);
+        if let Some(hash) = auth_client.cacheable_password_hash() {
+            self.credentials
+                .insert(auth_client.server_address(), auth_client.hub_name(), auth_client.username(), hash);
+        }
+        let resumed = auth_client.resumed();
+        if let Some(tickets) = self.session_tickets.as_mut() {
+            match auth_client.session_ticket() {
+                Some(ticket) => tickets.insert(ticket),
+                None => tickets.forget(auth_client.server_address(), auth_client.hub_name()),
+            }
+        }
         log::info!("✅ PACK authentication successful");
 
         // Analyze binary session data for IP configuration
@@ -389,17 +1345,23 @@ impl VpnClient {
             log::warn!("⚠️ No PACK data available from authentication");
         }
 
+        if resumed {
+            self.record_event("Session resumed without a full login".to_string());
+        }
+        self.start_udp_acceleration().await;
+
         // **EXPERIMENTAL**: After successful authentication, we may already have everything needed
         // Let's skip the SSL-VPN handshake and DHCP requests for now and see if we can proceed
         // to tunneling mode directly. The authentication success indicates the server accepts us.
         
-        // CRITICAL FIX: Set connection status to Connected after successful authentication
-        self.status = ConnectionStatus::Connected;
+        self.transition(Transition::Login)?;
         log::info!("🔄 Authentication complete - proceeding to tunneling mode...");
         log::info!("📝 Note: Using fallback IPs until DHCP implementation is fixed");
 
         // Initialize session manager after successful authentication
-        let session_manager = SessionManager::new(&self.config)?;
+        let mut session_manager = SessionManager::new(&self.timeouts);
+        session_manager.start_session()?;
+        session_manager.set_server_timeout(self.auth_client.as_ref().and_then(AuthClient::session_timeout));
         self.session_manager = Some(session_manager);
 
         // **CRITICAL SoftEther Architecture**: 
@@ -428,14 +1390,320 @@ impl VpnClient {
         Ok(())
     }
 
+    /// Complete the UDP acceleration offered in the login, if the server accepted it
+    async fn start_udp_acceleration(&mut self) {
+        let Some(auth_client) = self.auth_client.as_mut() else {
+            return;
+        };
+        let Some(offer) = auth_client.take_udp_accel_offer() else {
+            return;
+        };
+        let grant = auth_client.get_server_endpoint().and_then(|endpoint| {
+            auth_client
+                .get_pack_data()
+                .and_then(|pack| UdpAccelGrant::from_pack(pack, endpoint.ip()))
+        });
+        let Some(grant) = grant else {
+            log::info!("Server did not accept UDP acceleration, tunnel data stays on TLS");
+            return;
+        };
+
+        let server_addr = grant.server_addr;
+        match offer.accept(grant).await {
+            Ok(session) => {
+                let session = Arc::new(session);
+                let (frames, pump) = udp_accel::spawn_pump(session.clone());
+                self.udp_accel = Some(session);
+                self.udp_frames = Some(frames);
+                self.udp_pump = Some(pump);
+                self.record_event(format!("UDP acceleration negotiated with {server_addr}"));
+            }
+            Err(e) => log::warn!("UDP acceleration unavailable, tunnel data stays on TLS: {}", e),
+        }
+    }
+
+    fn stop_udp_acceleration(&mut self) {
+        if let Some(pump) = self.udp_pump.take() {
+            pump.abort();
+        }
+        self.udp_frames = None;
+        self.udp_accel = None;
+    }
+
+    /// Transport currently carrying tunnel data
+    ///
+    /// [`DataPath::Udp`] while a negotiated UDP acceleration path is alive,
+    /// otherwise [`DataPath::Tls`].
+    pub fn data_path(&self) -> DataPath {
+        match self.udp_accel {
+            Some(ref session) if session.is_usable() => DataPath::Udp,
+            _ => DataPath::Tls,
+        }
+    }
+
+    /// Limit tunnel traffic to `upload_bps` and `download_bps` bits per second (0 = unlimited)
+    ///
+    /// Applies from the next packet, also while tunneling, and stays in force
+    /// across reconnects.
+    pub fn set_rate_limit(&mut self, upload_bps: u64, download_bps: u64) {
+        self.shaper.set(upload_bps, download_bps);
+        self.config.network.upload_limit_bps = upload_bps;
+        self.config.network.download_limit_bps = download_bps;
+        log::info!(upload_bps, download_bps; "Tunnel rate limit changed");
+    }
+
+    /// Current `(upload, download)` limits in bits per second, 0 for unlimited
+    pub fn rate_limit(&self) -> (u64, u64) {
+        self.shaper.limits()
+    }
+
+    /// Tune the data path; takes effect when packet forwarding next starts
+    ///
+    /// With `packet_workers` above 1, tunnel packets are compressed and
+    /// encrypted on that many worker tasks, sharded by flow. With
+    /// `enable_packet_batching`, packets ready together go out in one write
+    /// of up to `packet_batch_size` packets. Bonded data connections are
+    /// opened up to `max_connections` at a time.
+    pub fn set_performance_config(&mut self, performance: PerformanceConfig) {
+        self.performance = performance;
+    }
+
+    /// Data path tuning in use
+    pub fn performance_config(&self) -> &PerformanceConfig {
+        &self.performance
+    }
+
+    /// Register a handler for structured authentication failure events
+    ///
+    /// The handler is called for every failed attempt and for attempts refused
+    /// locally while the profile is locked out, so a UI can show a countdown.
+    pub fn set_auth_failure_handler(&mut self, handler: Option<AuthFailureHandler>) {
+        self.auth_failure_handler = handler;
+    }
+
+    /// Register the source of one-time passwords for hubs with a second factor
+    ///
+    /// Asked when the login is answered with a challenge; declining or not
+    /// answering within `auth.otp_timeout_secs` fails the connection. Takes
+    /// effect from the next connection.
+    pub fn set_otp_provider(&mut self, provider: Option<Arc<dyn OtpProvider>>) {
+        self.otp_provider = provider;
+    }
+
+    /// Replace the tunnel's DNS servers without reconnecting
+    ///
+    /// Takes effect immediately while tunneling (subject to the change
+    /// planner), otherwise when the tunnel is next established. An empty list
+    /// restores the default servers. Emits a [`DnsUpdated`] event on success.
+    pub fn set_dns_servers(&mut self, servers: Vec<IpAddr>) -> Result<()> {
+        let requested = Some(servers.clone()).filter(|servers| !servers.is_empty());
+        let (servers, applied) = match self.tunnel_manager {
+            Some(ref mut tunnel_manager) => {
+                let applied = tunnel_manager.is_established();
+                match tunnel_manager.set_dns_servers(servers.clone()) {
+                    Ok(effective) => (effective, applied),
+                    Err(e) => {
+                        self.record_event(format!("DNS update failed: {e}"));
+                        self.events.error("dns", &e);
+                        return Err(e);
+                    }
+                }
+            }
+            None => (servers.clone(), false),
+        };
+        self.dns_servers = requested;
+
+        let list = servers.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+        self.record_event(if list.is_empty() {
+            "DNS servers reset to defaults".to_string()
+        } else {
+            format!("DNS servers updated: {list}")
+        });
+        self.events.dns_updated(&DnsUpdated { servers, applied });
+        Ok(())
+    }
+
+    /// Block all traffic outside the tunnel while connected
+    ///
+    /// Engages immediately when tunneling, otherwise with the next tunnel.
+    /// Once engaged, the firewall rules stay when the session drops, so
+    /// nothing leaks while it is down or reconnecting; they are removed by
+    /// [`disconnect`](Self::disconnect) or by turning the kill switch off.
+    /// See [`crate::tunnel::killswitch`] for the rules each platform gets.
+    pub fn enable_kill_switch(&mut self, enabled: bool) -> Result<()> {
+        self.kill_switch_enabled = enabled;
+        if !enabled {
+            self.kill_switch.release()?;
+            self.record_event("Kill switch released".to_string());
+            return Ok(());
+        }
+        if self.is_tunnel_established() {
+            self.engage_kill_switch()?;
+        }
+        Ok(())
+    }
+
+    /// Whether the kill switch rules are installed
+    pub fn is_kill_switch_engaged(&self) -> bool {
+        self.kill_switch.is_engaged()
+    }
+
+    /// ARP/NDP counters, when the hub bridges Ethernet frames (`tunnel.layer2`)
+    pub fn neighbor_stats(&self) -> Option<NeighborStats> {
+        self.tunnel_manager.as_ref().and_then(TunnelManager::neighbor_stats)
+    }
+
+    /// Current DHCP lease of the tunnel address (`tunnel.dhcp`)
+    pub fn dhcp_lease(&self) -> Option<DhcpLease> {
+        self.tunnel_manager
+            .as_ref()
+            .and_then(TunnelManager::dhcp_lease)
+            .or_else(|| self.dhcp.as_ref()?.lease().cloned())
+    }
+
+    /// Install the kill switch for the current tunnel interface and server
+    fn engage_kill_switch(&mut self) -> Result<()> {
+        let tunnel_interface = self
+            .tunnel_manager
+            .as_ref()
+            .and_then(TunnelManager::get_interface_info)
+            .map(|(name, ..)| name);
+        let (Some(tunnel_interface), Some(endpoint)) = (tunnel_interface, self.server_endpoint) else {
+            return Err(VpnError::InvalidState("Kill switch needs an established tunnel".into()));
+        };
+
+        let policy = KillSwitchPolicy { tunnel_interface, server: endpoint.ip() };
+        if let Err(e) = self.kill_switch.engage(policy) {
+            self.record_event(format!("Kill switch could not be engaged: {e}"));
+            self.events.error("killswitch", &e);
+            return Err(e);
+        }
+        self.record_event(format!("Kill switch engaged, only {} may reach the network", endpoint.ip()));
+        Ok(())
+    }
+
+    /// Let an engaged kill switch through to `server` instead of the previous server
+    ///
+    /// The rules are replaced in one step, so a session moving to another
+    /// cluster node never opens a gap.
+    fn retarget_kill_switch(&mut self, server: IpAddr) -> Result<()> {
+        let Some(policy) = self.kill_switch.policy().filter(|policy| policy.server != server).cloned() else {
+            return Ok(());
+        };
+        if let Err(e) = self.kill_switch.engage(KillSwitchPolicy { server, ..policy }) {
+            self.record_event(format!("Kill switch could not be moved to {server}: {e}"));
+            self.events.error("killswitch", &e);
+            return Err(e);
+        }
+        self.record_event(format!("Kill switch moved, only {server} may reach the network"));
+        Ok(())
+    }
+
+    /// Where HTTP requests through the tunnel should leave from
+    ///
+    /// Fails with [`VpnError::InvalidState`] unless the tunnel is up.
+    pub fn tunnel_http_binding(&self) -> Result<TunnelHttpBinding> {
+        self.tunnel_manager
+            .as_ref()
+            .ok_or_else(|| VpnError::InvalidState("Tunnel is not established".into()))?
+            .http_binding()
+    }
+
+    /// HTTP client for APIs behind the VPN
+    ///
+    /// Requests are sent from the tunnel address and interface and names are
+    /// resolved through the tunnel's DNS servers, so they reach the VPN even
+    /// when the system routing table has not caught up or sends the
+    /// destination elsewhere. Use [`tunnel_http_binding`](Self::tunnel_http_binding)
+    /// to customize the client further.
+    pub fn tunnel_http_client(&self) -> Result<reqwest::Client> {
+        self.tunnel_http_binding()?.client()
+    }
+
+    /// Event handlers, for push notifications instead of polling [`status`](Self::status)
+    ///
+    /// ```
+    /// use rvpnse::client::events::{ErrorEvent, StateChange};
+    /// use rvpnse::{Config, ConnectionStatus, VpnClient};
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> rvpnse::Result<()> {
+    /// let mut config = Config::default_test();
+    /// config.server.port = 1; // nothing listens here
+    /// let mut client = VpnClient::new(config)?;
+    ///
+    /// let seen = Arc::new(Mutex::new(Vec::new()));
+    /// let states = seen.clone();
+    /// client.events().on_state_change(Some(Arc::new(move |change: &StateChange| {
+    ///     states.lock().unwrap().push(change.to);
+    /// })));
+    /// client.events().on_error(Some(Arc::new(|event: &ErrorEvent<'_>| {
+    ///     assert_eq!(event.context, "connect");
+    /// })));
+    ///
+    /// assert!(client.connect_async("127.0.0.1", 1).await.is_err());
+    /// assert_eq!(
+    ///     *seen.lock().unwrap(),
+    ///     [ConnectionStatus::Connecting, ConnectionStatus::Disconnected]
+    /// );
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn events(&mut self) -> &mut ClientEvents {
+        &mut self.events
+    }
+
+    /// Profile key used for failure tracking (`user@server:port/hub`)
+    fn auth_profile(&self, username: &str) -> String {
+        let username = if username.is_empty() {
+            self.config.auth.username.as_deref().unwrap_or_default()
+        } else {
+            username
+        };
+        format!(
+            "{}@{}:{}/{}",
+            username, self.config.server.address, self.config.server.port, self.config.server.hub
+        )
+    }
+
+    fn report_auth_failure(&self, failure: &AuthFailure) {
+        auth_throttle::log_failure(failure);
+        self.record_event(format!(
+            "Authentication failed for {}: {} (attempt {})",
+            failure.profile, failure.reason, failure.attempt
+        ));
+        if let Some(ref handler) = self.auth_failure_handler {
+            handler(failure);
+        }
+    }
+
     /// Disconnect from VPN server
     ///
+    /// Also releases the kill switch, if engaged, wipes the cached
+    /// password hashes and drops the session tickets.
+    ///
     /// # Errors
     /// Returns an error if tunnel teardown fails
     pub fn disconnect(&mut self) -> Result<()> {
+        self.credentials.clear();
+        if let Some(tickets) = self.session_tickets.as_mut() {
+            tickets.clear();
+        }
+        self.disconnect_reason = Some(DisconnectedReason::Local);
+        let closed = self.close_session();
+        let released = self.kill_switch.release();
+        if let Err(ref e) = released {
+            self.record_event(format!("Kill switch release failed: {e}"));
+            self.events.error("killswitch", e);
+        }
+        closed.and(released)
+    }
+
+    /// Tear down the tunnel and drop the session, leaving the kill switch in place
+    pub(crate) fn close_session(&mut self) -> Result<()> {
         // Record disconnection for connection tracking
-        if self.status == ConnectionStatus::Connected || self.status == ConnectionStatus::Tunneling
-        {
+        if matches!(self.status(), ConnectionStatus::Connected | ConnectionStatus::Tunneling) {
             self.connection_tracker.record_disconnection();
         }
 
@@ -444,11 +1712,25 @@ impl VpnClient {
             tunnel_manager.teardown_tunnel()?;
         }
 
+        self.stop_udp_acceleration();
+
+        self.binary_session = None;
+        self.proxy_relay = None;
+        self.nat_relay = None;
+        self.transport = None;
+        self.server_info = None;
+        self.tls_relay = None;
+        self.bonded_sessions.clear();
+        self.bond = None;
+        self.heartbeat = None;
+        self.dhcp = None;
+        self.packet_flow = None;
+        self.health.reset();
         self.tunnel_manager = None;
         self.session_manager = None;
         self.protocol_handler = None;
         self.auth_client = None;
-        self.status = ConnectionStatus::Disconnected;
+        self.transition(Transition::Close)?;
         self.server_endpoint = None;
         Ok(())
     }
@@ -457,18 +1739,138 @@ impl VpnClient {
     pub fn teardown_tunnel(&mut self) -> Result<()> {
         if let Some(ref mut tunnel_manager) = self.tunnel_manager {
             tunnel_manager.teardown_tunnel()?;
-            self.status = ConnectionStatus::Connected; // Back to just connected state
+            self.transition(Transition::CloseTunnel)?;
+        }
+        Ok(())
+    }
+
+    /// Get current connection status
+    #[must_use]
+    pub fn status(&self) -> ConnectionStatus {
+        self.state.status()
+    }
+
+    /// Why the client is disconnected, if a session ended since the last connect
+    pub fn disconnect_reason(&self) -> Option<&DisconnectedReason> {
+        self.disconnect_reason.as_ref()
+    }
+
+    /// Apply `transition` to the connection state, reporting the change if the status changed
+    fn transition(&mut self, transition: Transition) -> Result<()> {
+        let Some((previous, status)) = self.state.apply(transition)? else {
+            return Ok(());
+        };
+        self.diagnostics.lock().unwrap().record_transition(previous, status);
+        self.session_clock.observe(status, Instant::now());
+        if status == ConnectionStatus::Tunneling {
+            self.events.start_stats(self.traffic.clone());
+            self.start_performance_monitor();
+        } else {
+            self.events.stop_stats();
+            if let Some(monitor) = self.performance_monitor.take() {
+                monitor.abort();
+            }
+            self.traffic.active_connections.store(0, Ordering::Relaxed);
+        }
+        self.events.state_changed(previous, status);
+        Ok(())
+    }
+
+    fn record_event(&self, message: String) {
+        self.diagnostics.lock().unwrap().record_event(message);
+    }
+
+    /// Record where the configuration was loaded from (shown in [`doctor`](Self::doctor))
+    pub fn set_config_origin(&mut self, origin: impl Into<String>) {
+        self.config_origin = origin.into();
+    }
+
+    /// Collect a redacted snapshot of configuration, state and environment for support
+    pub fn doctor(&self) -> DoctorReport {
+        let mut report = DoctorReport::new(&self.config, &self.config_origin);
+        report.status = format!("{:?}", self.status());
+        report.server_endpoint = self.server_endpoint.map(|addr| addr.to_string());
+        report.server_info = self.server_info.clone();
+        if let Some(ref tunnel_manager) = self.tunnel_manager {
+            report.assigned_ip = tunnel_manager.get_config().map(|c| c.local_ip.to_string());
+            if let Some(plan) = tunnel_manager.applied_plan() {
+                report.network.applied_changes = plan.approved().map(ToString::to_string).collect();
+            }
+            report.network.custom_routes =
+                tunnel_manager.installed_custom_routes().iter().map(ToString::to_string).collect();
+        }
+
+        let diagnostics = self.diagnostics.lock().unwrap();
+        report.state_history = diagnostics.transitions();
+        report.recent_events = diagnostics.events();
+        report.counters.events_recorded = diagnostics.events_recorded();
+        report.counters.active_connections = self.connection_tracker.active_connections();
+        report.counters.recent_connection_attempts = self.connection_tracker.recent_attempts();
+        report
+    }
+
+    /// Check whether this process has the privileges needed to set up the tunnel
+    ///
+    /// Returns every missing privilege with a remediation hint; run it at
+    /// startup so users learn what to fix before the connection attempt.
+    pub fn check_privileges(&self) -> PrivilegeReport {
+        privileges::check_privileges()
+    }
+
+    /// Current traffic counters; each call is also kept for [`rates_over`](Self::rates_over)
+    pub fn traffic_snapshot(&self) -> PerformanceSnapshot {
+        let snapshot = self.traffic.snapshot();
+        self.stats_history.lock().unwrap().push(snapshot.clone());
+        snapshot
+    }
+
+    /// Throughput and packet rates over roughly the last `window`
+    ///
+    /// Takes a fresh snapshot and compares it with the oldest one recorded
+    /// inside the window. Returns `None` until an earlier snapshot exists,
+    /// so poll this (or [`stats`](Self::stats)) periodically.
+    pub fn rates_over(&self, window: Duration) -> Option<PerformanceRates> {
+        self.traffic_snapshot();
+        self.stats_history.lock().unwrap().rates_over(window)
+    }
+
+    /// Choose the uplink interface for the next connection (`None` for the OS default)
+    ///
+    /// The interface must exist, be up and have an IPv4 address; see
+    /// [`list_interfaces`](crate::underlay::list_interfaces) for the candidates.
+    pub fn set_underlay_interface(&mut self, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            UnderlayBinding::resolve(name)?;
         }
+        self.config.network.underlay_interface = name.map(str::to_string);
         Ok(())
     }
 
-    /// Get current connection status
-    #[must_use]
-    pub fn status(&self) -> ConnectionStatus {
-        self.status
+    /// Uplink the current session is bound to, if one was chosen
+    pub fn underlay(&self) -> Option<&UnderlayBinding> {
+        self.underlay.as_ref()
+    }
+
+    /// Firewall mark on the session's own sockets, under policy routing (Linux only)
+    fn socket_mark(&self) -> Option<u32> {
+        let policy = cfg!(target_os = "linux") && self.config.routing.mode == RouteMode::Policy;
+        policy.then_some(self.config.routing.socket_mark)
+    }
+
+    /// Transport that reached the server, while connected
+    pub fn transport(&self) -> Option<Transport> {
+        self.transport
+    }
+
+    /// What the server reported about itself when connecting
+    ///
+    /// Its product and version, and the virtual hubs and capabilities if the
+    /// server lists them. Kept until disconnect, and also after a connection
+    /// failed because the configured hub is not among those listed.
+    pub fn server_info(&self) -> Option<&ServerInfo> {
+        self.server_info.as_ref()
     }
 
-    /// Get server endpoint (if connected)
     pub fn server_endpoint(&self) -> Option<SocketAddr> {
         self.server_endpoint
     }
@@ -476,7 +1878,7 @@ impl VpnClient {
     /// Send keepalive packet (protocol level)
     pub async fn send_keepalive(&mut self) -> Result<()> {
         // In tunneling mode, use binary keepalive instead of HTTP
-        if self.status == ConnectionStatus::Tunneling {
+        if self.status() == ConnectionStatus::Tunneling {
             log::debug!("Sending binary VPN keepalive");
             return self.send_binary_keepalive().await;
         }
@@ -489,16 +1891,41 @@ impl VpnClient {
 
         auth_client.send_keepalive().await?;
 
-        // Also use session manager if available
+        // The server answered, which refreshes the session
         if let Some(ref mut session_manager) = self.session_manager {
-            session_manager.send_keepalive()?;
+            let now = Instant::now();
+            session_manager.keepalive_sent(now)?;
+            session_manager.record_activity(now);
         }
 
         Ok(())
     }
 
+    /// Time left before the server's session timeout runs out
+    ///
+    /// `None` when not logged in or when the server announced no timeout.
+    /// Keepalives and traffic from the server start it over.
+    pub fn session_remaining(&self) -> Option<Duration> {
+        self.session_manager.as_ref().and_then(SessionManager::session_remaining)
+    }
+
     /// Send packet data using PACK binary format
+    ///
+    /// Goes over the UDP acceleration path while it is alive and falls back to
+    /// the TLS session otherwise.
     pub async fn send_packet_data(&mut self, packet_data: &[u8]) -> Result<()> {
+        if let Some(ref session) = self.udp_accel {
+            if session.is_usable() {
+                match session.send(packet_data).await {
+                    Ok(()) => {
+                        self.traffic.update_traffic(packet_data.len() as u64, 0, 1, 0);
+                        return Ok(());
+                    }
+                    Err(e) => log::warn!("{}; sending through TLS", e),
+                }
+            }
+        }
+
         let protocol_handler = self
             .protocol_handler
             .as_ref()
@@ -511,6 +1938,7 @@ impl VpnClient {
         // Create data PACK and send via HTTPS
         let data_pack = protocol_handler.create_data_pack(packet_data);
         let _response = protocol_handler.send_pack(&data_pack).await?;
+        self.traffic.update_traffic(packet_data.len() as u64, 0, 1, 0);
 
         Ok(())
     }
@@ -535,7 +1963,7 @@ impl VpnClient {
 
     /// Check if client is ready for packet forwarding
     pub fn is_ready_for_packets(&self) -> bool {
-        self.status == ConnectionStatus::Connected && self.session_manager.is_some()
+        self.status() == ConnectionStatus::Connected && self.session_manager.is_some()
     }
 
     /// Establish VPN tunnel (create TUN interface and configure routing)
@@ -543,60 +1971,140 @@ impl VpnClient {
     /// This creates a real TUN interface and configures system routing
     /// to send all traffic through the VPN tunnel.
     pub fn establish_tunnel(&mut self) -> Result<()> {
-        // FIRST LINE OF FUNCTION - NO CONDITIONS
-        println!("🚨🚨🚨 ESTABLISH_TUNNEL START - NO CONDITIONS 🚨🚨🚨");
-        eprintln!("🚨🚨🚨 ESTABLISH_TUNNEL START - NO CONDITIONS 🚨🚨🚨");
-        log::error!("🚨🚨🚨 ESTABLISH_TUNNEL START - NO CONDITIONS 🚨🚨🚨");
-        
-        println!("🚨 ESTABLISH_TUNNEL FUNCTION ENTERED!");
-        eprintln!("🚨 ESTABLISH_TUNNEL FUNCTION ENTERED!");
-        log::error!("🚨 ESTABLISH_TUNNEL FUNCTION ENTERED!");
-        
-        log::info!("🚀 establish_tunnel() called - current status: {:?}", self.status);
-        println!("🚀 establish_tunnel() called - current status: {:?}", self.status);
-        
-        if self.status != ConnectionStatus::Connected {
-            log::error!("❌ Status check failed: expected Connected, got {:?}", self.status);
-            println!("❌ Status check failed: expected Connected, got {:?}", self.status);
-            return Err(VpnError::Connection("Must be connected first".to_string()));
-        }
+        log::debug!("establish_tunnel() called, status {:?}", self.status());
+
+        self.state.check(Transition::OpenTunnel)?;
 
         if self.session_manager.is_none() {
-            log::error!("❌ Session manager check failed: session_manager is None");
-            println!("❌ Session manager check failed: session_manager is None");
+            log::error!("Cannot establish tunnel: no authenticated session");
             return Err(VpnError::Connection(
                 "Must be authenticated first".to_string(),
             ));
         }
         
-        log::info!("✅ All pre-checks passed, proceeding with tunnel establishment");
-        println!("✅ All pre-checks passed, proceeding with tunnel establishment");
+        self.prepare_tunnel_manager()?;
+
+        // Establish the actual tunnel with routing
+        if let Some(ref mut tunnel_manager) = self.tunnel_manager {
+            if let Err(e) = tunnel_manager.establish_tunnel() {
+                self.record_event(format!("Tunnel establishment failed: {e}"));
+                self.events.error("tunnel", &e);
+                return Err(e);
+            }
+            self.transition(Transition::OpenTunnel)?;
+            log::info!("All traffic is now routed through the VPN tunnel");
+        }
+
+        if self.kill_switch_enabled {
+            self.engage_kill_switch()?;
+        }
+
+        self.start_packet_forwarding();
+
+        Ok(())
+    }
+
+    /// Interface configuration for a VPN interface the host app creates
+    ///
+    /// Mobile apps, which cannot let the library create the interface, set up
+    /// `VpnService.Builder` (Android) or `NEPacketTunnelNetworkSettings` (iOS)
+    /// from this once authenticated, then hand the interface over with
+    /// [`attach_tun_fd`](Self::attach_tun_fd) or
+    /// [`attach_packet_flow`](Self::attach_packet_flow).
+    pub fn host_tunnel_settings(&mut self) -> Result<HostTunnelSettings> {
+        self.check_ready_for_tunnel()?;
+        self.prepare_tunnel_manager()?;
+        self.tunnel_manager
+            .as_ref()
+            .map(TunnelManager::host_settings)
+            .ok_or_else(|| VpnError::Connection("Tunnel manager not available".to_string()))
+    }
+
+    /// Forward packets through the VPN interface behind `fd` (Android `VpnService`)
+    ///
+    /// The descriptor must carry bare IP packets. It is duplicated, so the app
+    /// keeps its own. Nothing on the system is changed; routes, DNS and
+    /// lockdown are configured by the app.
+    #[cfg(unix)]
+    pub fn attach_tun_fd(&mut self, fd: std::os::unix::io::RawFd) -> Result<()> {
+        let (reader, writer) = host_device::from_fd(fd)?;
+        self.attach_host_device(reader, writer)
+    }
+
+    /// Exchange packets with the app's own packet I/O (iOS `NEPacketTunnelFlow`)
+    ///
+    /// The app passes what it reads from its packet flow to
+    /// [`PacketFlow::write_inbound`] and writes what
+    /// [`PacketFlow::read_outbound`] returns back to it. The flow closes with
+    /// the session.
+    pub fn attach_packet_flow(&mut self) -> Result<Arc<PacketFlow>> {
+        let (flow, reader, writer) = host_device::packet_flow();
+        self.attach_host_device(reader, writer)?;
+        let flow = Arc::new(flow);
+        self.packet_flow = Some(flow.clone());
+        Ok(flow)
+    }
+
+    /// Packet flow attached with [`attach_packet_flow`](Self::attach_packet_flow), while the session lasts
+    pub fn packet_flow(&self) -> Option<Arc<PacketFlow>> {
+        self.packet_flow.clone()
+    }
+
+    fn attach_host_device(&mut self, reader: TunReader, writer: TunWriter) -> Result<()> {
+        self.check_ready_for_tunnel()?;
+        self.prepare_tunnel_manager()?;
+        if let Some(ref mut tunnel_manager) = self.tunnel_manager {
+            if let Err(e) = tunnel_manager.attach_host_device(reader, writer) {
+                self.record_event(format!("Tunnel establishment failed: {e}"));
+                self.events.error("tunnel", &e);
+                return Err(e);
+            }
+        }
+        self.transition(Transition::OpenTunnel)?;
+        self.record_event("Tunneling through a device of the host app".to_string());
+        self.start_packet_forwarding();
+        Ok(())
+    }
+
+    fn check_ready_for_tunnel(&self) -> Result<()> {
+        self.state.check(Transition::OpenTunnel)?;
+        if self.session_manager.is_none() {
+            return Err(VpnError::Connection("Must be authenticated first".to_string()));
+        }
+        Ok(())
+    }
 
+    /// Create the tunnel manager from the lease and `[tunnel]`, unless it exists
+    fn prepare_tunnel_manager(&mut self) -> Result<()> {
         // Get IP configuration from authentication response
         log::info!("🔍 establish_tunnel() starting - checking for stored IP config...");
-        let tunnel_config = if let Some(auth_client) = &self.auth_client {
+        let mut tunnel_config = if let Some(auth_client) = &self.auth_client {
             log::info!("✅ Auth client exists, checking for IP config...");
+            let lease = auth_client.lease_options();
             if let Some(ip_config) = auth_client.get_ip_config() {
-                println!("✅ Using server-assigned IP configuration from auth response!");
-                println!("🎯 Source: {}", ip_config.source);
-                println!("🎯 Assigned IP: {}", ip_config.local_ip);
-                println!("🎯 Gateway IP: {}", ip_config.gateway_ip);
-                println!("🎯 Netmask: {}", ip_config.netmask);
-                
+                log::info!(
+                    source = ip_config.source.as_str(),
+                    local_ip = ip_config.local_ip.as_str(),
+                    gateway = ip_config.gateway_ip.as_str(),
+                    netmask = ip_config.netmask.as_str();
+                    "Using server-assigned IP configuration"
+                );
+
+
                 // Convert string IPs to Ipv4Addr
                 let local_ip = ip_config.local_ip.parse::<std::net::Ipv4Addr>()
                     .unwrap_or_else(|e| {
-                        println!("⚠️ Failed to parse local IP '{}': {}, using fallback", ip_config.local_ip, e);
+                        log::warn!("Failed to parse local IP '{}': {}, using fallback", ip_config.local_ip, e);
                         std::net::Ipv4Addr::new(10, 224, 51, 132)
                     });
                 let gateway_ip = ip_config.gateway_ip.parse::<std::net::Ipv4Addr>()
                     .unwrap_or_else(|e| {
-                        println!("⚠️ Failed to parse gateway IP '{}': {}, using fallback", ip_config.gateway_ip, e);
+                        log::warn!("Failed to parse gateway IP '{}': {}, using fallback", ip_config.gateway_ip, e);
                         std::net::Ipv4Addr::new(10, 224, 51, 1)
                     });
                 let netmask = ip_config.netmask.parse::<std::net::Ipv4Addr>()
                     .unwrap_or_else(|e| {
-                        println!("⚠️ Failed to parse netmask '{}': {}, using fallback", ip_config.netmask, e);
+                        log::warn!("Failed to parse netmask '{}': {}, using fallback", ip_config.netmask, e);
                         std::net::Ipv4Addr::new(255, 255, 255, 0)
                     });
                 
@@ -610,11 +2118,20 @@ impl VpnClient {
                         std::net::Ipv4Addr::new(8, 8, 8, 8),
                         std::net::Ipv4Addr::new(8, 8, 4, 4),
                     ],
+                    pushed_routes: auth_client.pushed_routes(),
+                    wins_servers: lease.wins_servers,
+                    dns_domain: lease.dns_domain,
+                    ipv6: lease.ipv6,
+                    bypass_cgroups: Vec::new(),
+                    only_cgroups: Vec::new(),
+                    mode: Default::default(),
+                    windows_driver: Default::default(),
+                    auto_mtu: false,
+                    queues: 1,
+                    offload: false,
                 }
             } else {
-                log::warn!("⚠️ No IP config found in auth response, using fallback");
-                println!("⚠️ No IP config found in auth response, using fallback");
-                println!("🔧 This means the binary session data parsing needs improvement");
+                log::warn!("No IP config found in auth response, using fallback");
                 TunnelConfig {
                     interface_name: "vpnse0".to_string(),
                     local_ip: std::net::Ipv4Addr::new(10, 224, 51, 132),
@@ -625,41 +2142,214 @@ impl VpnClient {
                         std::net::Ipv4Addr::new(8, 8, 8, 8),
                         std::net::Ipv4Addr::new(8, 8, 4, 4),
                     ],
+                    pushed_routes: auth_client.pushed_routes(),
+                    wins_servers: lease.wins_servers,
+                    dns_domain: lease.dns_domain,
+                    ipv6: lease.ipv6,
+                    bypass_cgroups: Vec::new(),
+                    only_cgroups: Vec::new(),
+                    mode: Default::default(),
+                    windows_driver: Default::default(),
+                    auto_mtu: false,
+                    queues: 1,
+                    offload: false,
                 }
             }
         } else {
-            log::warn!("⚠️ No auth client available, using fallback");
-            println!("⚠️ No auth client available, using fallback");
+            log::warn!("No auth client available, using fallback");
             TunnelConfig::default()
         };
+        // Per-app routing is local policy, not part of the lease
+        tunnel_config.bypass_cgroups = self.config.tunnel.bypass_cgroups.clone();
+        tunnel_config.only_cgroups = self.config.tunnel.only_cgroups.clone();
+        tunnel_config.mode = self.config.tunnel.mode;
+        tunnel_config.windows_driver = self.config.tunnel.windows_driver;
+        tunnel_config.auto_mtu = self.config.tunnel.auto_mtu;
+        tunnel_config.queues = self.config.tunnel.queues.unwrap_or(1);
+        tunnel_config.offload = self.config.tunnel.offload;
+        if let Some(lease) = self.dhcp.as_ref().and_then(DhcpClient::lease) {
+            lease.apply_to(&mut tunnel_config);
+        }
 
-        // Create tunnel manager if not exists
         if self.tunnel_manager.is_none() {
-            let tunnel_manager = TunnelManager::new(tunnel_config);
+            let mut tunnel_manager = TunnelManager::new(tunnel_config, self.server_endpoint());
+            tunnel_manager.set_change_planner(self.change_planner.clone());
+            tunnel_manager.set_routing_config(self.config.routing.clone());
+            tunnel_manager.set_tunnel_options(&self.config.tunnel);
+            tunnel_manager.set_public_ip_config(self.config.public_ip.clone());
+            tunnel_manager.set_underlay(self.underlay.clone());
+            if self.config.tunnel.layer2 {
+                tunnel_manager.set_mac_address(self.link_mac()?);
+            }
+            if let Some(dhcp) = self.dhcp.take() {
+                tunnel_manager.set_dhcp_client(dhcp);
+            }
+            // Frame with what authentication negotiated, or at least the data session's id
+            let framing = self
+                .auth_client
+                .as_ref()
+                .and_then(AuthClient::framing_params)
+                .or_else(|| self.binary_session.as_ref()?.session_id().map(FramingParams::new));
+            match framing {
+                Some(params) => tunnel_manager.set_framing(params),
+                None => log::warn!("No framing parameters negotiated, tunnel packets are not framed"),
+            }
+            if let Some(ref servers) = self.dns_servers {
+                tunnel_manager.set_dns_servers(servers.clone())?;
+            }
+            let ops = if self.config.tunnel.in_memory_only {
+                SystemOps::in_memory_only()
+            } else {
+                SystemOps::default()
+            };
+            let ops = ops.with_strict(self.config.tunnel.is_strict()).with_strict_dns(self.config.tunnel.strict_dns);
+            tunnel_manager.set_platform_ops(Arc::new(ops));
+            tunnel_manager.set_change_journal(self.change_journal.clone());
             self.tunnel_manager = Some(tunnel_manager);
         }
+        Ok(())
+    }
+
+    /// Sample throughput every `stats_interval` while tunneling; without a
+    /// Tokio runtime there is nothing to run it on
+    fn start_performance_monitor(&mut self) {
+        if let Some(monitor) = self.performance_monitor.take() {
+            monitor.abort();
+        }
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let monitor = client_optimized::monitor_performance(self.traffic.clone(), self.performance.clone());
+        self.performance_monitor = Some(handle.spawn(monitor));
+    }
 
-        // Establish the actual tunnel with routing
+    /// Pump packets between the TUN device and the binary data session
+    ///
+    /// Without a data session or Tokio runtime the tunnel stays up but
+    /// carries no traffic; the reason is recorded for support dumps.
+    fn start_packet_forwarding(&mut self) {
+        let Some(session) = self.binary_session.take() else {
+            log::warn!("No binary data session, tunnel traffic will not be forwarded");
+            return;
+        };
+        let bonded = std::mem::take(&mut self.bonded_sessions);
+        let started = tokio::runtime::Handle::try_current()
+            .map_err(|_| VpnError::Connection("Packet forwarding needs a Tokio runtime".to_string()))
+            .and_then(|handle| {
+                let tunnel_manager = self
+                    .tunnel_manager
+                    .as_mut()
+                    .ok_or_else(|| VpnError::Connection("Tunnel not established".to_string()))?;
+                if bonded.is_empty() {
+                    let (mut sink, source) = session.into_split()?;
+                    if self.performance.enable_packet_batching {
+                        sink = sink.with_batching(self.performance.packet_batch_size);
+                    }
+                    self.traffic.active_connections.store(1, Ordering::Relaxed);
+                    let workers = self.performance.packet_workers;
+                    if workers > 1 {
+                        log::info!(workers; "Processing tunnel packets on worker tasks");
+                        let pool = WorkerPool::spawn(&handle, workers);
+                        let (sink, source) =
+                            (ShardedSender::new(&handle, sink, pool.clone()), ShardedReceiver::new(&handle, source, pool));
+                        return tunnel_manager.start_packet_routing_loop(&handle, sink, source, self.traffic.clone(), self.shaper.clone());
+                    }
+                    return tunnel_manager.start_packet_routing_loop(&handle, sink, source, self.traffic.clone(), self.shaper.clone());
+                }
+                let connections = std::iter::once(session)
+                    .chain(bonded)
+                    .map(BinaryProtocolClient::into_split)
+                    .collect::<Result<Vec<_>>>()?;
+                let (sink, source, health) = bonding::bond(connections)?;
+                log::info!("Striping tunnel traffic across {} data connections", health.live_connections());
+                self.traffic.active_connections.store(health.live_connections() as u64, Ordering::Relaxed);
+                tunnel_manager.start_packet_routing_loop(&handle, sink, source, self.traffic.clone(), self.shaper.clone())?;
+                self.bond = Some(health);
+                Ok(())
+            });
+        if let Err(e) = started {
+            log::warn!("Packet forwarding not started: {}", e);
+            self.record_event(format!("Packet forwarding not started: {e}"));
+            self.events.error("forwarding", &e);
+        }
+    }
+
+    /// Register a planner that can veto or modify system changes
+    ///
+    /// The planner is consulted during [`establish_tunnel`](Self::establish_tunnel)
+    /// before any route, DNS, kernel or firewall change is made.
+    ///
+    /// ```
+    /// use rvpnse::tunnel::{ChangeCategory, ChangePlan};
+    /// use rvpnse::{Config, VpnClient};
+    /// use std::sync::Arc;
+    ///
+    /// let mut client = VpnClient::new(Config::default_test())?;
+    /// // Leave the host's resolver configuration alone
+    /// client.set_change_planner(Arc::new(|plan: &mut ChangePlan| {
+    ///     plan.veto_category(ChangeCategory::Dns, "DNS is managed by the host");
+    /// }));
+    /// # Ok::<(), rvpnse::VpnError>(())
+    /// ```
+    pub fn set_change_planner(&mut self, planner: Arc<dyn SystemChangePlanner>) {
         if let Some(ref mut tunnel_manager) = self.tunnel_manager {
-            tunnel_manager.establish_tunnel()?;
-            self.status = ConnectionStatus::Tunneling;
-            println!("✅ VPN tunnel established successfully - all traffic now routed through VPN");
+            tunnel_manager.set_change_planner(Some(planner.clone()));
         }
+        self.change_planner = Some(planner);
+    }
 
-        Ok(())
+    /// Remove a previously registered change planner
+    pub fn clear_change_planner(&mut self) {
+        if let Some(ref mut tunnel_manager) = self.tunnel_manager {
+            tunnel_manager.set_change_planner(None);
+        }
+        self.change_planner = None;
+    }
+
+    /// Use `provider` for TLS to the server instead of rustls (`None` goes back to rustls)
+    ///
+    /// Applies from the next connect. The provider verifies the chain as
+    /// `server.verify_certificate` and `[tls]` ask; `tls.pinned_certificates`
+    /// are checked against the certificate it reports.
+    pub fn set_tls_provider(&mut self, provider: Option<Arc<dyn TlsProvider>>) {
+        self.tls_provider = provider;
     }
 
     /// Check if tunnel is established
     pub fn is_tunnel_established(&self) -> bool {
-        self.status == ConnectionStatus::Tunneling
+        self.status() == ConnectionStatus::Tunneling
             && self
                 .tunnel_manager
                 .as_ref()
                 .is_some_and(|tm| tm.is_established())
     }
 
+    /// Check that the hub answers through the tunnel
+    ///
+    /// Pings the virtual gateway, so it works in closed networks and contacts
+    /// nothing outside the VPN. Returns the round-trip time.
+    pub async fn check_hub_connectivity(&self, deadline: Duration) -> Result<Duration> {
+        let tunnel_config = self
+            .tunnel_manager
+            .as_ref()
+            .and_then(|tm| tm.get_config())
+            .ok_or_else(|| VpnError::Connection("Tunnel not established".to_string()))?;
+
+        let rtt = diagnostics::ping_gateway(tunnel_config.remote_ip, deadline).await?;
+        self.record_rtt(Some(rtt));
+        Ok(rtt)
+    }
+
     /// Get current public IP (for testing if traffic is routed through VPN)
+    ///
+    /// Contacts third-party services and fails unless `public_ip.enabled` is
+    /// set; prefer [`Self::check_hub_connectivity`].
     pub async fn get_current_public_ip(&self) -> Result<String> {
+        if !self.config.diagnostics.external_probes {
+            return Err(VpnError::Config(
+                "Public IP lookup is an external probe (diagnostics.external_probes = false)".to_string(),
+            ));
+        }
         if let Some(ref tunnel_manager) = self.tunnel_manager {
             tunnel_manager.get_current_public_ip().await
         } else {
@@ -669,17 +2359,54 @@ impl VpnClient {
         }
     }
 
+    /// Verify DNS resolution through the tunnel
+    ///
+    /// Probes the system resolver plus the VPN gateway and configured DNS
+    /// servers concurrently; each probe gives up after `deadline`. With
+    /// `diagnostics.external_probes` off, only the VPN's servers are asked,
+    /// for `diagnostics.internal_probe_name` or the pushed DNS domain.
+    pub async fn run_dns_diagnostics(&self, deadline: Duration) -> Result<DnsDiagnostics> {
+        let tunnel_config = self
+            .tunnel_manager
+            .as_ref()
+            .and_then(|tm| tm.get_config())
+            .ok_or_else(|| VpnError::Connection("Tunnel not established".to_string()))?;
+
+        let mut servers = vec![tunnel_config.remote_ip];
+        servers.extend(tunnel_config.dns_servers.iter().copied());
+        servers.dedup();
+
+        if self.config.diagnostics.external_probes {
+            return Ok(diagnostics::run_dns_diagnostics(&servers, diagnostics::DEFAULT_DNS_PROBE_NAME, deadline).await);
+        }
+
+        let name = self
+            .config
+            .diagnostics
+            .internal_probe_name
+            .clone()
+            .or_else(|| tunnel_config.dns_domain.clone())
+            .ok_or_else(|| {
+                VpnError::Config(
+                    "No internal name to probe: set diagnostics.internal_probe_name or enable external_probes"
+                        .to_string(),
+                )
+            })?;
+        Ok(diagnostics::run_internal_dns_diagnostics(&servers, &name, deadline).await)
+    }
+
     /// Get VPN session information
     pub fn get_session_info(&self) -> Option<VpnSessionInfo> {
         if let Some(ref auth_client) = self.auth_client {
+            let tunnel_config = self.tunnel_manager.as_ref().and_then(|tm| tm.get_config());
             Some(VpnSessionInfo {
                 session_id: auth_client.session_id().cloned(),
                 server_endpoint: self.server_endpoint(),
                 is_authenticated: auth_client.is_authenticated(),
                 connection_status: self.status(),
                 // In a real implementation, this would come from the VPN server
-                assigned_ip: if self.status == ConnectionStatus::Connected
-                    || self.status == ConnectionStatus::Tunneling
+                assigned_ip: if self.status() == ConnectionStatus::Connected
+                    || self.status() == ConnectionStatus::Tunneling
                 {
                     Some("192.168.100.10".to_string()) // Simulated VPN-assigned IP
                 } else {
@@ -687,6 +2414,11 @@ impl VpnClient {
                 },
                 // VPN server's public IP that clients see
                 vpn_server_ip: self.server_endpoint().map(|addr| addr.ip().to_string()),
+                dns_domain: tunnel_config.as_ref().and_then(|c| c.dns_domain.clone()),
+                wins_servers: tunnel_config.map(|c| c.wins_servers).unwrap_or_default(),
+                mtu: self.tunnel_manager.as_ref().map(|tm| tm.mtu()),
+                mss_clamp: self.tunnel_manager.as_ref().and_then(|tm| tm.mss_clamp()),
+                discovered_mtu: self.tunnel_manager.as_ref().and_then(|tm| tm.discovered_mtu()),
             })
         } else {
             None
@@ -717,14 +2449,53 @@ impl VpnClient {
         log::debug!("Creating binary protocol client for endpoint: {:?}", server_endpoint);
         
         // Initialize binary protocol client for high-performance VPN transmission
-        let binary_client = BinaryProtocolClient::new(server_endpoint);
+        let mut binary_client = BinaryProtocolClient::new(server_endpoint)
+            .with_proxy(self.proxy.clone())
+            .with_socket_mark(self.socket_mark());
+        if let Some(framing) = auth_client.framing_params() {
+            binary_client = binary_client.with_session_id(framing.session_id);
+        }
+        if self.config.server.encrypt_data {
+            match auth_client.session_keys() {
+                Some(keys) => binary_client = binary_client.with_session_keys(&keys)?,
+                None => log::warn!("No session keys from the login, data packets will not be encrypted"),
+            }
+        }
+        if auth_client.compression_negotiated() {
+            log::info!("Server agreed to compression, data packets will be zlib-compressed");
+            binary_client = binary_client.with_compression(self.traffic.clone());
+        } else if self.config.server.use_compression {
+            log::info!("Server declined compression, data packets will be sent as they are");
+        }
         
-        // TODO: Transfer session state from PACK auth to binary protocol
+        // TODO: Transfer the rest of the session state from PACK auth to binary protocol
         // This includes:
-        // - Session ID
-        // - Encryption keys  
         // - Connection parameters
         // - VPN configuration
+        let username = self.config.auth.username.clone().unwrap_or_default();
+        let password = self.config.auth.password.clone().unwrap_or_default();
+        let hub = self.config.server.hub.clone();
+        let opened = tokio::time::timeout(self.timeouts.connect, async {
+            binary_client.connect().await?;
+            binary_client.authenticate(&username, &password, &hub).await
+        })
+        .await
+        .unwrap_or_else(|_| Err(VpnError::Timeout("Binary data session setup timed out".to_string())));
+        match opened {
+            Ok(_) => {
+                if self.config.tunnel.dhcp {
+                    self.acquire_dhcp_lease(&mut binary_client).await;
+                }
+                self.open_bonded_connections(&binary_client, server_endpoint).await;
+                self.heartbeat = Some(binary_client.heartbeat());
+                self.binary_session = Some(binary_client);
+            }
+            Err(e) => {
+                log::warn!("Binary data session unavailable, the tunnel will not carry traffic: {}", e);
+                self.record_event(format!("Binary data session failed: {e}"));
+                self.events.error("session", &e);
+            }
+        }
         
         log::info!("✅ Tunneling mode started - ready for binary VPN packet transmission");
         
@@ -740,10 +2511,113 @@ impl VpnClient {
         Ok(())
     }
 
+    /// Join the data session with the further connections the server granted
+    ///
+    /// Each one gets keys of its own and shares the session's keepalive state.
+    /// A connection that cannot be opened is left out and the session runs on
+    /// the others. Up to `max_connections` of the performance config are
+    /// opened at the same time.
+    async fn open_bonded_connections(&mut self, primary: &BinaryProtocolClient, server_endpoint: SocketAddr) {
+        let Some(auth_client) = self.auth_client.as_ref() else { return };
+        let granted = auth_client.granted_connections();
+        let keys = self.config.server.encrypt_data.then(|| auth_client.session_keys()).flatten();
+        let Some(session_id) = primary.session_id() else { return };
+        let compressed = primary.is_compressed();
+        if granted > 1 {
+            log::info!("Server granted {} data connections, opening {} more", granted, granted - 1);
+        }
+        let username = self.config.auth.username.clone().unwrap_or_default();
+        let password = self.config.auth.password.clone().unwrap_or_default();
+        let permits = tokio::sync::Semaphore::new(self.performance.max_connections.max(1));
+        let (proxy, traffic, hub, connect_timeout) =
+            (&self.proxy, &self.traffic, &self.config.server.hub, self.timeouts.connect);
+        let socket_mark = self.socket_mark();
+        let opening = (1..granted).map(|index| {
+            let (permits, keys, username, password) = (&permits, &keys, &username, &password);
+            async move {
+                // Never closed, so a permit always comes
+                let _permit = permits.acquire().await;
+                let opened = async {
+                    let mut client = BinaryProtocolClient::new(server_endpoint)
+                        .with_proxy(proxy.clone())
+                        .with_socket_mark(socket_mark)
+                        .with_session_id(session_id)
+                        .with_connection_index(index)
+                        .with_heartbeat(primary.heartbeat());
+                    if let Some(keys) = keys {
+                        client = client.with_session_keys(&keys.for_connection(index)?)?;
+                    }
+                    if compressed {
+                        client = client.with_compression(traffic.clone());
+                    }
+                    client.connect().await?;
+                    client.authenticate(username, password, hub).await?;
+                    Ok(client)
+                };
+                tokio::time::timeout(connect_timeout, opened)
+                    .await
+                    .unwrap_or_else(|_| Err(VpnError::Timeout("Data connection setup timed out".to_string())))
+            }
+        });
+        let opened = futures::future::join_all(opening).await;
+        for (index, opened) in (1..granted).zip(opened) {
+            match opened {
+                Ok(client) => self.bonded_sessions.push(client),
+                Err(e) => {
+                    log::warn!("Data connection {} unavailable, continuing with fewer: {}", index, e);
+                    self.record_event(format!("Data connection {index} failed: {e}"));
+                }
+            }
+        }
+    }
+
+    /// Per-connection health of the data session, when it is striped across several
+    ///
+    /// Empty with a single data connection or before forwarding starts.
+    pub fn bond_health(&self) -> Vec<LinkStatus> {
+        self.bond.as_ref().map(BondHealth::links).unwrap_or_default()
+    }
+
+    /// MAC address on bridged hubs: `tunnel.mac_address`, or derived from the
+    /// profile so that it stays the same across reconnects
+    fn link_mac(&self) -> Result<MacAddr> {
+        match self.config.tunnel.mac_address {
+            Some(ref mac) => l2::parse_mac(mac),
+            None => Ok(l2::stable_mac(&self.auth_profile(""))),
+        }
+    }
+
+    /// Lease the tunnel address from the bridged segment before the tunnel is set up
+    ///
+    /// Failure is not fatal: the tunnel then uses the addresses from the login.
+    async fn acquire_dhcp_lease(&mut self, session: &mut BinaryProtocolClient) {
+        let mac = match self.link_mac() {
+            Ok(mac) => mac,
+            Err(e) => {
+                log::warn!("No DHCP lease, using the addresses from the login: {}", e);
+                return;
+            }
+        };
+        let mut client = DhcpClient::new(mac);
+        match dhcp::acquire(&mut client, session, self.timeouts.connect).await {
+            Ok(lease) => {
+                self.record_event(format!("DHCP lease of {} from {}", lease.address, lease.server));
+                self.dhcp = Some(client);
+            }
+            Err(e) => {
+                log::warn!("No DHCP lease, using the addresses from the login: {}", e);
+                self.record_event(format!("DHCP failed: {e}"));
+                self.events.error("dhcp", &e);
+            }
+        }
+    }
+
     /// Start binary protocol keep-alive loop for VPN session maintenance
     /// 
     /// This replaces the HTTP-based keep-alive with binary protocol keep-alive
-    /// for high-performance VPN operation
+    /// for high-performance VPN operation. A lost session is re-established
+    /// with [`reconnect`](Self::reconnect) when `reconnect.enabled` is set;
+    /// the loop only returns when that fails or reconnection is disabled.
     pub async fn start_binary_keepalive_loop(&mut self) -> Result<()> {
         log::info!("🔄 Starting binary protocol keep-alive loop...");
         
@@ -751,19 +2625,42 @@ impl VpnClient {
         let protocol_handler = self.protocol_handler.as_ref()
             .ok_or_else(|| VpnError::Connection("Protocol handler not available".to_string()))?;
         
-        // Start keep-alive and packet processing loop
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        // Start keep-alive and packet processing loop, often enough to refresh
+        // the session before the server's timeout
+        let period = self
+            .session_manager
+            .as_ref()
+            .map_or(self.timeouts.keepalive_interval, SessionManager::keepalive_interval);
+        let mut interval = tokio::time::interval(period);
+        let mut idle = IdleWatch::new(self.timeouts.idle);
+        let health_checks = self.config.health.enabled;
+        let mut health_interval =
+            tokio::time::interval(Duration::from_secs(u64::from(self.config.health.interval_secs.max(1))));
         
         loop {
             tokio::select! {
                 _ = interval.tick() => {
+                    self.traffic_snapshot();
+
                     // Send binary keep-alive packet
-                    if let Err(e) = self.send_binary_keepalive().await {
+                    let checked = self.check_session().and_then(|()| self.check_idle(&mut idle));
+                    let alive = match checked.and_then(|()| self.check_session_expiry()) {
+                        Ok(()) => self.send_binary_keepalive().await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = alive {
                         log::error!("Keep-alive failed: {}", e);
-                        break;
+                        self.recover_session(e).await?;
+                        idle.reset(Instant::now());
+                        continue;
                     }
                     log::debug!("Binary keep-alive sent");
                 }
+
+                // Check that the tunnel passes traffic, not just that the server answers
+                _ = health_interval.tick(), if health_checks && self.status() == ConnectionStatus::Tunneling => {
+                    self.run_health_check().await?;
+                }
                 
                 // Handle incoming VPN packets
                 packet_result = self.receive_vpn_packet() => {
@@ -775,47 +2672,65 @@ impl VpnClient {
                         }
                         Err(e) => {
                             log::error!("Failed to receive VPN packet: {}", e);
-                            break;
+                            self.recover_session(e).await?;
                         }
                     }
                 }
             }
         }
-        
-        log::info!("✅ Binary keep-alive loop started");
-        Ok(())
     }
     
-    /// Send binary keep-alive packet using VPN protocol
+    /// Send a session keepalive and check that the server still answers
+    ///
+    /// While forwarding, the probe goes out through the packet pump, whose
+    /// inbound side matches the echo and takes the RTT; before that it is
+    /// sent on the idle data session. Fails with [`VpnError::Timeout`] once
+    /// the server has answered nothing for the dead-peer timeout.
     async fn send_binary_keepalive(&mut self) -> Result<()> {
-        // CRITICAL FIX: When in tunneling mode, we should NOT use HTTP keepalive
-        // Instead we should use UDP or raw socket keepalive on the TUN interface
-        
-        // Create binary keep-alive packet (SoftEther PING)
-        let keepalive_packet = vec![
-            0x01, 0x00, 0x00, 0x08, // Packet length (8 bytes)
-            0x50, 0x49, 0x4E, 0x47, // "PING" magic bytes
-        ];
-        
-        // TEMPORARY WORKAROUND: Don't actually send via HTTP protocol which causes 403
-        // Instead, if we have a tunnel manager, send an ICMP ping to the VPN gateway
-        if let Some(ref mut tunnel_manager) = self.tunnel_manager {
-            if let Some(config) = tunnel_manager.get_config() {
-                // Log instead of sending actual HTTP request
-                log::info!("Binary keepalive: pinging gateway {}", config.remote_ip);
-                
-                // No need to actually ping here - the tunnel interface will maintain connectivity
-                return Ok(());
+        let now = Instant::now();
+        let forwarding = self.tunnel_manager.as_ref().is_some_and(TunnelManager::is_forwarding);
+        if let Some(heartbeat) = self.heartbeat.clone() {
+            match heartbeat.take_rtt() {
+                Some(rtt) => self.record_rtt(Some(rtt)),
+                None if heartbeat.silent_for(now).is_some() => self.record_rtt(None),
+                None => {}
+            }
+            // Echoes are only read once the pump owns the session
+            if let Some(limit) = self.timeouts.dead_peer.filter(|_| forwarding) {
+                heartbeat.check_alive(limit, now)?;
             }
         }
-        
-        // If no tunnel manager, log a warning but don't actually try HTTP which would cause 403
-        log::warn!("Binary keepalive attempted but tunnel not available");
+
+        if !self.tunnel_manager.as_ref().is_some_and(TunnelManager::send_keepalive) {
+            match self.binary_session {
+                Some(ref mut session) => session.send_keepalive().await?,
+                None => {
+                    log::warn!("Binary keepalive attempted but no data session available");
+                    return Ok(());
+                }
+            }
+        }
+        if let Some(ref mut session_manager) = self.session_manager {
+            session_manager.keepalive_sent(now)?;
+        }
         Ok(())
     }
     
     /// Receive VPN packet from server
     async fn receive_vpn_packet(&mut self) -> Result<Vec<u8>> {
+        if let Some(ref mut frames) = self.udp_frames {
+            let frame = tokio::select! {
+                frame = frames.recv() => Some(frame),
+                _ = tokio::time::sleep(Duration::from_millis(100)) => None,
+            };
+            match frame {
+                Some(Some(frame)) => return Ok(frame.to_vec()),
+                // The UDP path failed; everything goes through TLS from now on
+                Some(None) => self.stop_udp_acceleration(),
+                None => return Ok(vec![]),
+            }
+        }
+
         // TODO: Implement actual packet reception from binary protocol
         // For now, return empty to avoid infinite loop
         tokio::time::sleep(Duration::from_millis(100)).await;
@@ -827,6 +2742,7 @@ impl VpnClient {
         if packet.is_empty() {
             return Ok(());
         }
+        self.traffic.update_traffic(0, packet.len() as u64, 0, 1);
         
         // TODO: Route packet through tunnel interface
         // This should:
@@ -837,11 +2753,14 @@ impl VpnClient {
         Ok(())
     }
 
-    /// Synchronous connect method for FFI compatibility
+    /// Blocking [`connect_async`](Self::connect_async), run on the shared
+    /// runtime of [`crate::blocking`]
+    ///
+    /// # Errors
+    /// Fails with [`VpnError::InvalidState`] when called from async code,
+    /// which should await `connect_async` instead.
     pub fn connect(&mut self, server: &str, port: u16) -> Result<()> {
-        let rt = tokio::runtime::Runtime::new()
-            .map_err(|e| VpnError::Connection(format!("Failed to create runtime: {}", e)))?;
-        rt.block_on(self.connect_async(server, port))
+        crate::blocking::block_on(self.connect_async(server, port))?
     }
 
     /// Update peer count for clustering
@@ -887,83 +2806,107 @@ impl VpnClient {
         Ok(())
     }
 
-    /// Get cluster node status information
-    pub fn get_cluster_status(&self) -> Option<Vec<(String, bool, u32)>> {
-        if let Some(ref cluster_manager) = self.cluster_manager {
-            Some(cluster_manager.nodes.iter().map(|node| {
-                (node.address.clone(), node.is_healthy, node.active_connections)
-            }).collect())
-        } else {
-            None
-        }
+    /// Get cluster node status information, including blacklist state
+    pub fn get_cluster_status(&mut self) -> Option<Vec<ClusterNodeStatus>> {
+        self.cluster_manager.as_mut().map(ClusterManager::status)
     }
 
     /// Connect to next available cluster node
+    ///
+    /// On the first connect every node is probed and the fastest one wins;
+    /// later connects follow the load-balancing strategy. Clears any pin. A
+    /// failed attempt penalises the node so it is skipped until its blacklist
+    /// expires.
     pub async fn connect_to_cluster(&mut self) -> Result<()> {
-        if !self.config.clustering.enabled {
-            return Err(VpnError::Configuration(
-                "Clustering is not enabled".to_string(),
-            ));
-        }
+        let cluster_manager = self.cluster_manager_mut()?;
+        cluster_manager.pin(None);
 
-        if let Some(ref mut cluster_manager) = self.cluster_manager {
-            if let Some(node) = cluster_manager.get_next_node() {
-                if let Some(endpoint) = node.endpoint {
-                    self.server_endpoint = Some(endpoint);
-                    node.active_connections += 1;
-                    cluster_manager.update_peer_count(cluster_manager.get_peer_count() + 1);
-                    
-                    // Use the endpoint to connect
-                    return self.connect_async(&endpoint.ip().to_string(), endpoint.port()).await;
-                } else {
-                    // Try to resolve the address
-                    match node.address.to_socket_addrs() {
-                        Ok(mut addrs) => {
-                            if let Some(addr) = addrs.next() {
-                                node.endpoint = Some(addr);
-                                self.server_endpoint = Some(addr);
-                                node.active_connections += 1;
-                                cluster_manager.update_peer_count(cluster_manager.get_peer_count() + 1);
-                                
-                                return self.connect_async(&addr.ip().to_string(), addr.port()).await;
-                            }
-                        },
-                        Err(e) => {
-                            node.is_healthy = false;
-                            return Err(VpnError::Connection(
-                                format!("Failed to resolve cluster node {}: {}", node.address, e)
-                            ));
-                        }
-                    }
-                }
-            }
+        // On first connect probe every node in parallel and start with the fastest
+        let fastest = if cluster_manager.is_warm() {
+            None
+        } else {
+            cluster_manager.probe_all_nodes().await
+        };
+        let Some(index) = fastest.or_else(|| cluster_manager.next_node_index()) else {
+            return Err(no_cluster_node_error("No available cluster nodes", cluster_manager));
+        };
+
+        self.connect_to_cluster_index(index).await
+    }
+
+    /// Connect to the cluster node at `index` and pin it
+    ///
+    /// The node is tried even if blacklisted, since the user chose it, and
+    /// failover does not move away from it until
+    /// [`connect_to_cluster`](Self::connect_to_cluster) is used again.
+    pub async fn connect_to_cluster_member(&mut self, index: usize) -> Result<()> {
+        let cluster_manager = self.cluster_manager_mut()?;
+        if index >= cluster_manager.get_nodes_count() {
+            return Err(VpnError::Configuration(format!(
+                "No cluster node {index}, the cluster has {}",
+                cluster_manager.get_nodes_count()
+            )));
         }
+        cluster_manager.pin(Some(index));
+        log::info!("Connecting to pinned cluster node {}", cluster_manager.nodes[index].address);
 
-        Err(VpnError::Connection(
-            "No available cluster nodes".to_string(),
-        ))
+        self.connect_to_cluster_index(index).await
     }
 
-    /// Handle failover to next healthy cluster node
-    pub async fn handle_cluster_failover(&mut self) -> Result<()> {
-        if !self.config.clustering.enabled || !self.config.clustering.enable_failover {
+    fn cluster_manager_mut(&mut self) -> Result<&mut ClusterManager> {
+        if !self.config.clustering.enabled {
             return Err(VpnError::Configuration(
-                "Clustering failover is not enabled".to_string(),
+                "Clustering is not enabled".to_string(),
             ));
         }
+        self.cluster_manager
+            .as_mut()
+            .ok_or_else(|| VpnError::Connection("No available cluster nodes".to_string()))
+    }
+
+    /// Resolve the node at `index`, count the connection and connect to it
+    async fn connect_to_cluster_index(&mut self, index: usize) -> Result<()> {
+        let cluster_manager = self.cluster_manager_mut()?;
+        let endpoint = cluster_manager.resolve_node(index)?;
+        cluster_manager.nodes[index].active_connections += 1;
+        cluster_manager.update_peer_count(cluster_manager.get_peer_count() + 1);
+        self.server_endpoint = Some(endpoint);
+
+        self.connect_to_cluster_node(index, endpoint).await
+    }
 
+    /// Connect to the cluster node at `index` and record the outcome against it
+    async fn connect_to_cluster_node(&mut self, index: usize, endpoint: SocketAddr) -> Result<()> {
+        let timeouts = match self.cluster_manager {
+            Some(ref cluster_manager) => self.config.session_timeouts(&cluster_manager.nodes[index].address),
+            None => self.config.session_timeouts(&endpoint.to_string()),
+        };
+        let result = self.connect_session(&endpoint.ip().to_string(), endpoint.port(), timeouts).await;
         if let Some(ref mut cluster_manager) = self.cluster_manager {
-            if let Some(node) = cluster_manager.failover() {
-                if let Some(endpoint) = node.endpoint {
-                    self.server_endpoint = Some(endpoint);
-                    return self.connect_async(&endpoint.ip().to_string(), endpoint.port()).await;
+            match &result {
+                Ok(()) => cluster_manager.record_success(index),
+                Err(e) => {
+                    cluster_manager.record_failure(index, NodeFailure::from_error(e));
                 }
             }
         }
+        result
+    }
+}
 
-        Err(VpnError::Connection(
-            "No healthy nodes available for failover".to_string(),
-        ))
+/// Whether `error` means the server could not be reached at all, so another transport may do better
+fn is_unreachable(error: &VpnError) -> bool {
+    matches!(error.code(), ErrorCode::Network | ErrorCode::ConnectionFailed | ErrorCode::Timeout)
+}
+
+/// Error for when no cluster node can be tried, noting when a blacklisted one frees up
+fn no_cluster_node_error(message: &str, cluster_manager: &ClusterManager) -> VpnError {
+    match cluster_manager.next_retry() {
+        Some(retry) => VpnError::Connection(format!(
+            "{message}: all remaining nodes are blacklisted, next retry in {}s",
+            retry.as_secs().max(1)
+        )),
+        None => VpnError::Connection(message.to_string()),
     }
 }
 
@@ -976,6 +2919,16 @@ pub struct VpnSessionInfo {
     pub connection_status: ConnectionStatus,
     pub assigned_ip: Option<String>,
     pub vpn_server_ip: Option<String>,
+    /// Connection-specific DNS domain pushed by the server
+    pub dns_domain: Option<String>,
+    /// WINS servers pushed by the server
+    pub wins_servers: Vec<std::net::Ipv4Addr>,
+    /// Effective tunnel MTU (server-provided or `[tunnel] mtu`)
+    pub mtu: Option<u16>,
+    /// Effective TCP MSS clamp
+    pub mss_clamp: Option<u16>,
+    /// Tunnel MTU fitted to the probed path MTU (`[tunnel] auto_mtu`)
+    pub discovered_mtu: Option<u16>,
 }
 
 impl Drop for VpnClient {
@@ -1078,6 +3031,18 @@ impl ConnectionTracker {
         Ok(())
     }
 
+    /// Connections currently counted against the concurrency limit
+    pub fn active_connections(&self) -> u32 {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    /// Connection attempts within the last minute
+    pub fn recent_attempts(&self) -> u32 {
+        let one_minute_ago = Instant::now() - Duration::from_secs(60);
+        let attempts = self.connection_attempts.lock().unwrap();
+        attempts.iter().filter(|&&t| t > one_minute_ago).count() as u32
+    }
+
     /// Record a connection attempt
     fn record_connection(&self) {
         self.active_connections.fetch_add(1, Ordering::Relaxed);
@@ -1121,7 +3086,244 @@ mod tests {
 
         // Note: Actual connection would require a real server
         // This just tests the state machine
-        client.status = ConnectionStatus::Connecting;
+        client.transition(Transition::Connect).unwrap();
         assert_eq!(client.status(), ConnectionStatus::Connecting);
+        assert!(client.transition(Transition::OpenTunnel).is_err());
+        assert_eq!(client.status(), ConnectionStatus::Connecting);
+    }
+
+    #[test]
+    fn test_status_checks_come_from_state_machine() {
+        let mut client = VpnClient::new(Config::default_test()).unwrap();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = changes.clone();
+        client.events().on_state_change(Some(Arc::new(move |change: &events::StateChange| {
+            seen.lock().unwrap().push((change.from, change.to));
+        })));
+
+        let err = client.establish_tunnel().unwrap_err();
+        assert_eq!(err.to_string(), "Connection failed: Cannot open the tunnel while Disconnected");
+        assert!(client.attach_packet_flow().is_err());
+
+        client.transition(Transition::Connect).unwrap();
+        client.transition(Transition::Establish).unwrap();
+        // A repeated login keeps the status and reports nothing
+        client.transition(Transition::Login).unwrap();
+        // Connected but never logged in: the session check still applies
+        let err = client.establish_tunnel().unwrap_err();
+        assert_eq!(err.to_string(), "Connection failed: Must be authenticated first");
+        client.disconnect().unwrap();
+
+        assert_eq!(
+            *changes.lock().unwrap(),
+            [
+                (ConnectionStatus::Disconnected, ConnectionStatus::Connecting),
+                (ConnectionStatus::Connecting, ConnectionStatus::Connected),
+                (ConnectionStatus::Connected, ConnectionStatus::Disconnected),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disconnect_wipes_cached_credentials() {
+        let mut client = VpnClient::new(Config::default_test()).unwrap();
+        let hash = crate::protocol::PasswordHash::new("alice", "secret");
+        client.credentials.insert("127.0.0.1:443", "VPN", "alice", hash);
+
+        // Losing the session keeps the hash for the reconnect
+        client.close_session().unwrap();
+        assert_eq!(client.credentials.len(), 1);
+        client.disconnect().unwrap();
+        assert!(client.credentials.is_empty());
+    }
+
+    #[test]
+    fn test_session_tickets_dropped_on_disconnect() {
+        let mut config = Config::default_test();
+        assert!(VpnClient::new(config.clone()).unwrap().session_tickets.is_none());
+        config.reconnect.resume_session = true;
+        config.tunnel.in_memory_only = true;
+        let mut client = VpnClient::new(config).unwrap();
+
+        let mut welcome = crate::protocol::Pack::new();
+        welcome.add_str("session_name", "SID-ALICE-1");
+        welcome.add_data("session_key", vec![7; 20]);
+        let ticket = crate::protocol::SessionTicket::from_login("127.0.0.1:443", "VPN", "alice", &welcome).unwrap();
+        let tickets = client.session_tickets.as_mut().unwrap();
+        tickets.insert(ticket.clone());
+        assert_eq!(tickets.get("127.0.0.1:443", "VPN", "alice"), Some(ticket));
+
+        // A lost session can still be resumed, a closed one cannot
+        client.close_session().unwrap();
+        assert_eq!(client.session_tickets.as_ref().unwrap().len(), 1);
+        client.disconnect().unwrap();
+        assert!(client.session_tickets.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_doctor_report() {
+        let mut client = VpnClient::new(Config::default_test()).unwrap();
+        client.set_config_origin("/etc/rvpnse/test.toml");
+        client.transition(Transition::Connect).unwrap();
+        client.record_event("Connection to 127.0.0.1:443 failed: refused".to_string());
+        client.transition(Transition::Fail).unwrap();
+
+        let report = client.doctor();
+        assert_eq!(report.config_origin, "/etc/rvpnse/test.toml");
+        assert_eq!(report.status, "Disconnected");
+        assert_eq!(report.state_history.len(), 2);
+        assert_eq!(report.state_history[0].to, "Connecting");
+        assert_eq!(report.recent_events.len(), 1);
+        assert_eq!(report.effective_config["auth"]["password"], "<redacted>");
+    }
+
+    #[tokio::test]
+    async fn test_transport_fallback_to_nat_t() {
+        // Nothing accepts TCP, and the NAT-T server does not know the server
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let nat_t = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default_test();
+        config.server.transports = vec![Transport::Tcp, Transport::NatT];
+        config.nat_traversal.servers = vec!["127.0.0.1".to_string()];
+        config.nat_traversal.port = nat_t.local_addr().unwrap().port();
+        config.nat_traversal.punch_timeout_secs = 1;
+        let nat_t = tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            let (len, client) = nat_t.recv_from(&mut buf).await.unwrap();
+            let request = crate::protocol::nat_traversal::ConnectRequest::decode(&buf[..len]).unwrap();
+            nat_t.send_to(&request.reply(Err("host not registered")), client).await.unwrap();
+            request
+        });
+
+        let mut client = VpnClient::new(config).unwrap();
+        assert!(client.connect_async("127.0.0.1", closed.port()).await.is_err());
+        assert_eq!(client.transport(), None);
+        let request = nat_t.await.unwrap();
+        assert_eq!(request.dest_host, "localhost");
+        let events = client.doctor().recent_events;
+        assert!(events.iter().any(|event| event.message.starts_with("tcp connection to 127.0.0.1")), "{events:?}");
+    }
+
+    #[tokio::test]
+    async fn test_parallel_cluster_probe() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+
+        // Grab a free port and close it again so connects are refused
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let config = crate::config::ClusteringConfig {
+            enabled: true,
+            cluster_nodes: vec![closed.to_string().into(), live.to_string().into()],
+            probe_concurrency: 2,
+            probe_timeout: 2,
+            ..Default::default()
+        };
+        let mut manager = ClusterManager::new(config);
+        assert!(!manager.is_warm());
+
+        let fastest = manager.probe_all_nodes().await;
+        assert_eq!(fastest, Some(1));
+        assert!(manager.is_warm());
+        assert!(manager.nodes[1].is_healthy);
+        assert_eq!(manager.nodes[1].endpoint, Some(live));
+
+        // The refused node is marked down once its probe is in
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while manager.status()[0].healthy {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cluster_probe_does_not_wait_for_slow_nodes() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = listener.local_addr().unwrap();
+
+        // Nothing answers on TEST-NET, so that probe runs until the timeout
+        let config = crate::config::ClusteringConfig {
+            enabled: true,
+            cluster_nodes: vec!["192.0.2.1:443".into(), live.to_string().into()],
+            probe_concurrency: 2,
+            probe_timeout: 5,
+            ..Default::default()
+        };
+        let mut manager = ClusterManager::new(config);
+
+        let start = Instant::now();
+        assert_eq!(manager.probe_all_nodes().await, Some(1));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(manager.is_warm());
+    }
+
+    #[test]
+    fn test_cluster_node_blacklist_decay() {
+        let config = crate::config::ClusteringConfig {
+            enabled: true,
+            cluster_nodes: vec!["10.0.0.1:443".into(), "10.0.0.2:443".into()],
+            blacklist_base_penalty: 30,
+            blacklist_max_penalty: 100,
+            penalty_half_life: 300,
+            ..Default::default()
+        };
+        let mut manager = ClusterManager::new(config);
+
+        // Penalties double per failure up to the cap
+        assert_eq!(manager.record_failure(0, NodeFailure::AuthFailed), Duration::from_secs(30));
+        let second = manager.record_failure(0, NodeFailure::ConnectionReset);
+        assert!(second > Duration::from_secs(59) && second <= Duration::from_secs(60));
+        assert_eq!(manager.record_failure(0, NodeFailure::ConnectionReset), Duration::from_secs(100));
+        assert!(manager.is_blacklisted(0));
+        assert!(!manager.is_blacklisted(1));
+
+        // Round-robin skips the blacklisted node
+        assert_eq!(manager.get_next_node().unwrap().address, "10.0.0.2:443");
+        assert_eq!(manager.get_next_node().unwrap().address, "10.0.0.2:443");
+
+        let status = manager.status();
+        assert_eq!(status[0].last_failure, Some(NodeFailure::ConnectionReset));
+        assert!(status[0].blacklisted_for.is_some());
+        assert!(status[1].blacklisted_for.is_none());
+
+        // A score last updated two half-lives ago has decayed to a quarter
+        let half_life = Duration::from_secs(300);
+        manager.nodes[0].penalty.updated = Some(Instant::now() - 2 * half_life);
+        let decayed = manager.nodes[0].penalty.score_at(Instant::now(), half_life);
+        assert!((decayed - 0.75).abs() < 0.01);
+
+        // With every node blacklisted nothing is offered until one expires
+        manager.record_failure(1, NodeFailure::Unreachable);
+        assert!(manager.get_next_node().is_none());
+        assert!(manager.next_retry().unwrap() <= Duration::from_secs(30));
+
+        manager.record_success(0);
+        assert!(!manager.is_blacklisted(0));
+        assert_eq!(manager.get_next_node().unwrap().address, "10.0.0.1:443");
+    }
+
+    #[test]
+    fn test_pinned_cluster_node_blocks_failover() {
+        let config = crate::config::ClusteringConfig {
+            enabled: true,
+            cluster_nodes: vec!["10.0.0.1:443".into(), "10.0.0.2:443".into()],
+            failover_timeout: 0,
+            ..Default::default()
+        };
+        let mut manager = ClusterManager::new(config);
+
+        manager.pin(Some(1));
+        assert_eq!(manager.pinned(), Some(1));
+        assert!(manager.failover().is_none());
+        let status = manager.status();
+        assert!(!status[0].pinned && status[1].pinned);
+        assert_eq!(status[1].index, 1);
+
+        // Unknown nodes cannot be pinned; unpinning restores failover
+        manager.pin(Some(2));
+        assert_eq!(manager.pinned(), None);
+        assert!(manager.failover().is_some());
     }
 }
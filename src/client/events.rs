@@ -0,0 +1,264 @@
+//! Client events
+//!
+//! Push notifications for embedders that would otherwise poll
+//! [`VpnClient::status`](super::VpnClient::status): state transitions,
+//! errors, reconnection and cluster failover progress, periodic traffic
+//! counters, DNS updates and changes of tunnel health.
+//! Handlers run synchronously on the thread that caused the event (for async
+//! FFI calls, a runtime worker), except stats which come from a timer task,
+//! so they should return quickly.
+
+use super::failover::FailoverEvent;
+use super::health::HealthEvent;
+use super::ConnectionStatus;
+use crate::client_optimized::{PerformanceSnapshot, PerformanceStats};
+use crate::error::VpnError;
+use crate::timestamp::Timestamp;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Shortest interval accepted for stats events
+pub const MIN_STATS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The connection status changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateChange {
+    pub from: ConnectionStatus,
+    pub to: ConnectionStatus,
+    pub at: Timestamp,
+}
+
+/// A failure while connecting or running the tunnel
+#[derive(Debug)]
+pub struct ErrorEvent<'a> {
+    /// What was being done: `connect`, `authenticate`, `tunnel`, `forwarding`, `session` or `dns`
+    pub context: &'static str,
+    pub error: &'a VpnError,
+}
+
+/// Progress of re-establishing a lost or failed-over connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "bindings", derive(uniffi::Enum))]
+pub enum ReconnectPhase {
+    /// A new connection attempt is starting
+    Attempting,
+    /// The connection is back
+    Succeeded,
+    /// The attempt failed; `retry_in` says when another one is possible
+    Failed,
+}
+
+/// A reconnection step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectEvent {
+    pub phase: ReconnectPhase,
+    /// Consecutive attempts so far, starting at 1
+    pub attempt: u32,
+    /// Server being tried, if known
+    pub endpoint: Option<SocketAddr>,
+    /// Delay before the next attempt is allowed (only for `Failed`)
+    pub retry_in: Option<Duration>,
+}
+
+/// The tunnel's DNS servers were changed with [`VpnClient::set_dns_servers`](super::VpnClient::set_dns_servers)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsUpdated {
+    /// Servers now configured for the tunnel
+    pub servers: Vec<IpAddr>,
+    /// Whether the platform resolver was reconfigured (false before the tunnel is up)
+    pub applied: bool,
+}
+
+/// Callback receiving [`StateChange`] events
+pub type StateChangeHandler = Arc<dyn Fn(&StateChange) + Send + Sync>;
+
+/// Callback receiving [`ErrorEvent`]s
+pub type ErrorHandler = Arc<dyn Fn(&ErrorEvent<'_>) + Send + Sync>;
+
+/// Callback receiving [`ReconnectEvent`]s
+pub type ReconnectHandler = Arc<dyn Fn(&ReconnectEvent) + Send + Sync>;
+
+/// Callback receiving traffic counters while tunneling
+pub type StatsHandler = Arc<dyn Fn(&PerformanceSnapshot) + Send + Sync>;
+
+/// Callback receiving [`DnsUpdated`] events
+pub type DnsUpdateHandler = Arc<dyn Fn(&DnsUpdated) + Send + Sync>;
+
+/// Callback receiving [`HealthEvent`]s
+pub type HealthHandler = Arc<dyn Fn(&HealthEvent) + Send + Sync>;
+
+/// Callback receiving [`FailoverEvent`]s
+pub type FailoverHandler = Arc<dyn Fn(&FailoverEvent) + Send + Sync>;
+
+/// Registered event handlers of a client
+///
+/// Obtained with [`VpnClient::events`](super::VpnClient::events). Passing
+/// `None` to a registration method removes the handler.
+#[derive(Default)]
+pub struct ClientEvents {
+    state_change: Option<StateChangeHandler>,
+    error: Option<ErrorHandler>,
+    reconnect: Option<ReconnectHandler>,
+    stats: Option<(StatsHandler, Duration)>,
+    dns_update: Option<DnsUpdateHandler>,
+    health: Option<HealthHandler>,
+    failover: Option<FailoverHandler>,
+    // Timer emitting stats while tunneling
+    stats_task: Option<JoinHandle<()>>,
+}
+
+impl ClientEvents {
+    /// Called on every status transition
+    pub fn on_state_change(&mut self, handler: Option<StateChangeHandler>) {
+        self.state_change = handler;
+    }
+
+    /// Called when connecting, authenticating, tunnel setup or forwarding fails
+    pub fn on_error(&mut self, handler: Option<ErrorHandler>) {
+        self.error = handler;
+    }
+
+    /// Called at each step of a reconnection or cluster failover
+    pub fn on_reconnect(&mut self, handler: Option<ReconnectHandler>) {
+        self.reconnect = handler;
+    }
+
+    /// Called every `interval` (at least [`MIN_STATS_INTERVAL`]) while tunneling
+    ///
+    /// Takes effect from the next time the tunnel comes up.
+    pub fn on_stats(&mut self, handler: Option<StatsHandler>, interval: Duration) {
+        self.stats = handler.map(|handler| (handler, interval.max(MIN_STATS_INTERVAL)));
+    }
+
+    /// Called after the DNS servers were changed
+    pub fn on_dns_update(&mut self, handler: Option<DnsUpdateHandler>) {
+        self.dns_update = handler;
+    }
+
+    /// Called when a health check finds the tunnel healthy, degraded or down after it was otherwise
+    pub fn on_health(&mut self, handler: Option<HealthHandler>) {
+        self.health = handler;
+    }
+
+    /// Called at each step of moving the session to another cluster node
+    pub fn on_failover(&mut self, handler: Option<FailoverHandler>) {
+        self.failover = handler;
+    }
+
+    pub(crate) fn state_changed(&self, from: ConnectionStatus, to: ConnectionStatus) {
+        if let Some(ref handler) = self.state_change {
+            handler(&StateChange { from, to, at: Timestamp::now() });
+        }
+    }
+
+    pub(crate) fn error(&self, context: &'static str, error: &VpnError) {
+        if let Some(ref handler) = self.error {
+            handler(&ErrorEvent { context, error });
+        }
+    }
+
+    pub(crate) fn reconnect(&self, event: ReconnectEvent) {
+        if let Some(ref handler) = self.reconnect {
+            handler(&event);
+        }
+    }
+
+    pub(crate) fn dns_updated(&self, event: &DnsUpdated) {
+        if let Some(ref handler) = self.dns_update {
+            handler(event);
+        }
+    }
+
+    pub(crate) fn health_changed(&self, event: &HealthEvent) {
+        if let Some(ref handler) = self.health {
+            handler(event);
+        }
+    }
+
+    pub(crate) fn failover(&self, event: &FailoverEvent) {
+        if let Some(ref handler) = self.failover {
+            handler(event);
+        }
+    }
+
+    /// Start emitting stats from `traffic`, if a handler and a Tokio runtime are available
+    pub(crate) fn start_stats(&mut self, traffic: Arc<PerformanceStats>) {
+        self.stop_stats();
+        let Some((ref handler, interval)) = self.stats else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            log::warn!("Stats events need a Tokio runtime; none will be sent");
+            return;
+        };
+        let handler = handler.clone();
+        self.stats_task = Some(runtime.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                handler(&traffic.snapshot());
+            }
+        }));
+    }
+
+    pub(crate) fn stop_stats(&mut self) {
+        if let Some(task) = self.stats_task.take() {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for ClientEvents {
+    fn drop(&mut self) {
+        self.stop_stats();
+    }
+}
+
+impl std::fmt::Debug for ClientEvents {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientEvents")
+            .field("state_change", &self.state_change.is_some())
+            .field("error", &self.error.is_some())
+            .field("reconnect", &self.reconnect.is_some())
+            .field("stats", &self.stats.as_ref().map(|(_, interval)| interval))
+            .field("dns_update", &self.dns_update.is_some())
+            .field("health", &self.health.is_some())
+            .field("failover", &self.failover.is_some())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn test_stats_events_while_running() {
+        let mut events = ClientEvents::default();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let sink = received.clone();
+        events.on_stats(
+            Some(Arc::new(move |snapshot: &PerformanceSnapshot| {
+                sink.lock().unwrap().push(snapshot.bytes_sent);
+            })),
+            Duration::ZERO,
+        );
+
+        let traffic = Arc::new(PerformanceStats::new());
+        traffic.update_traffic(1200, 0, 1, 0);
+        events.start_stats(traffic);
+        tokio::time::sleep(MIN_STATS_INTERVAL * 3).await;
+        events.stop_stats();
+
+        let count = received.lock().unwrap().len();
+        assert!(count >= 1, "no stats events received");
+        assert!(received.lock().unwrap().iter().all(|&bytes| bytes == 1200));
+        tokio::time::sleep(MIN_STATS_INTERVAL * 2).await;
+        assert_eq!(received.lock().unwrap().len(), count);
+    }
+}
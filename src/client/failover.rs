@@ -0,0 +1,274 @@
+//! Cluster failover
+//!
+//! When the session to a cluster node is lost, [`ClusterManager::failover`]
+//! only says which node to try next. [`VpnClient::handle_cluster_failover`]
+//! and the automatic recovery move the whole session there: the node that
+//! failed is penalised, the session is rebuilt on the next healthy node, the
+//! client logs in again and, if a tunnel was up, the tunnel is brought back
+//! with that node's lease and routes. Nodes are tried one after another
+//! without the `[reconnect]` backoff until one takes the session or none is
+//! left. An engaged kill switch follows the session from node to node, so
+//! nothing leaks while it moves.
+//!
+//! Each step is reported as a [`FailoverEvent`]; attempts on a node are also
+//! reported as [`ReconnectEvent`]s.
+
+use super::reconnect::is_retryable;
+use super::{no_cluster_node_error, ClusterManager, ConnectionStatus, NodeFailure, VpnClient};
+use super::{ReconnectEvent, ReconnectPhase};
+use crate::error::{Result, VpnError};
+use std::net::SocketAddr;
+
+/// Step of a cluster failover
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailoverPhase {
+    /// The session is leaving `from`; `error` says why, when it was lost
+    Started,
+    /// Connecting to the node `to`
+    Connecting,
+    /// Logged in on `to`
+    Authenticated,
+    /// The session, and the tunnel if there was one, run on `to`
+    Completed,
+    /// The node `to` could not take the session; the next one is tried
+    NodeFailed,
+    /// No node took the session
+    Abandoned,
+}
+
+/// Progress of a cluster failover
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailoverEvent {
+    pub phase: FailoverPhase,
+    /// Node the session was on
+    pub from: Option<SocketAddr>,
+    /// Node being moved to
+    pub to: Option<SocketAddr>,
+    /// What went wrong, for `Started`, `NodeFailed` and `Abandoned`
+    pub error: Option<String>,
+}
+
+impl VpnClient {
+    /// Move the session to the next healthy cluster node
+    ///
+    /// Works whether or not the current session is still up: it is closed
+    /// first. Fails with [`VpnError::Configuration`] unless clustering
+    /// failover is enabled, and without trying anything when a node is pinned,
+    /// within `clustering.failover_timeout` of the last failover, or when no
+    /// node is available.
+    pub async fn handle_cluster_failover(&mut self) -> Result<()> {
+        self.fail_over(None).await
+    }
+
+    /// Whether a lost session would be moved to another cluster node rather than reconnected
+    pub(crate) fn can_fail_over(&self) -> bool {
+        self.config.clustering.enabled
+            && self.config.clustering.enable_failover
+            && self.cluster_manager.as_ref().is_some_and(|cluster_manager| {
+                cluster_manager.pinned().is_none() && cluster_manager.get_nodes_count() > 1
+            })
+    }
+
+    /// Fail over after `cause` took down the session, or on request when `None`
+    pub(crate) async fn fail_over(&mut self, cause: Option<&VpnError>) -> Result<()> {
+        if !self.config.clustering.enabled || !self.config.clustering.enable_failover {
+            return Err(VpnError::Configuration("Clustering failover is not enabled".to_string()));
+        }
+        let from = self.server_endpoint;
+        let with_tunnel = self.status() == ConnectionStatus::Tunneling;
+        let Some(ref mut cluster_manager) = self.cluster_manager else {
+            return Err(VpnError::Connection("No available cluster nodes".to_string()));
+        };
+        if let Some(pinned) = cluster_manager.pinned() {
+            return Err(VpnError::Connection(format!(
+                "Pinned to cluster node {}, not failing over",
+                cluster_manager.nodes[pinned].address
+            )));
+        }
+        if let Some(left) = cluster_manager.failover_cooldown() {
+            return Err(VpnError::Connection(format!(
+                "Failed over less than clustering.failover_timeout ago, next failover in {}s",
+                left.as_secs().max(1)
+            )));
+        }
+
+        if let Some(cause) = cause {
+            let current = cluster_manager.current_node_index;
+            cluster_manager.record_failure(current, NodeFailure::from_error(cause));
+        }
+        self.record_event(match from {
+            Some(from) => format!("Failing over from cluster node {from}"),
+            None => "Failing over to the next cluster node".to_string(),
+        });
+        let mut event = FailoverEvent {
+            phase: FailoverPhase::Started,
+            from,
+            to: None,
+            error: cause.map(ToString::to_string),
+        };
+        self.events.failover(&event);
+        if self.status() != ConnectionStatus::Disconnected {
+            self.release_session();
+        }
+
+        let nodes = self.cluster_manager.as_ref().map_or(0, ClusterManager::get_nodes_count);
+        for _ in 0..nodes {
+            let Some(ref mut cluster_manager) = self.cluster_manager else {
+                break;
+            };
+            if cluster_manager.next_available_node().is_none() {
+                break;
+            }
+            let index = cluster_manager.current_node_index;
+            let error = match cluster_manager.resolve_node(index) {
+                Ok(endpoint) => match self.move_session(index, endpoint, with_tunnel, &mut event).await {
+                    Ok(()) => return Ok(()),
+                    Err(e) => e,
+                },
+                Err(e) => e,
+            };
+            if !is_retryable(&error) {
+                self.abandon_failover(event, &error);
+                return Err(error);
+            }
+        }
+
+        let message = "No healthy nodes available for failover";
+        let error = match self.cluster_manager {
+            Some(ref cluster_manager) => no_cluster_node_error(message, cluster_manager),
+            None => VpnError::Connection(message.to_string()),
+        };
+        self.abandon_failover(event, &error);
+        Err(error)
+    }
+
+    /// Rebuild the session on the node at `index`, releasing it again on failure
+    async fn move_session(
+        &mut self,
+        index: usize,
+        endpoint: SocketAddr,
+        with_tunnel: bool,
+        event: &mut FailoverEvent,
+    ) -> Result<()> {
+        self.failover_attempts += 1;
+        let mut attempt = ReconnectEvent {
+            phase: ReconnectPhase::Attempting,
+            attempt: self.failover_attempts,
+            endpoint: Some(endpoint),
+            retry_in: None,
+        };
+        self.events.reconnect(attempt);
+        *event = FailoverEvent { phase: FailoverPhase::Connecting, to: Some(endpoint), error: None, ..event.clone() };
+        self.events.failover(event);
+
+        match self.join_cluster_node(index, endpoint, with_tunnel, event).await {
+            Ok(()) => {
+                self.failover_attempts = 0;
+                self.reconnects += 1;
+                self.record_event(format!("Failed over to cluster node {endpoint}"));
+                self.events.reconnect(ReconnectEvent { phase: ReconnectPhase::Succeeded, ..attempt });
+                event.phase = FailoverPhase::Completed;
+                self.events.failover(event);
+                Ok(())
+            }
+            Err(e) => {
+                self.record_event(format!("Failover to cluster node {endpoint} failed: {e}"));
+                self.release_session();
+                attempt.phase = ReconnectPhase::Failed;
+                attempt.retry_in = self.cluster_manager.as_ref().and_then(ClusterManager::next_retry);
+                self.events.reconnect(attempt);
+                event.phase = FailoverPhase::NodeFailed;
+                event.error = Some(e.to_string());
+                self.events.failover(event);
+                Err(e)
+            }
+        }
+    }
+
+    /// Connect, log in and, with `with_tunnel`, bring the tunnel up on the node at `index`
+    async fn join_cluster_node(
+        &mut self,
+        index: usize,
+        endpoint: SocketAddr,
+        with_tunnel: bool,
+        event: &mut FailoverEvent,
+    ) -> Result<()> {
+        // Records the outcome of the connection against the node
+        self.connect_to_cluster_node(index, endpoint).await?;
+
+        // The login keeps the configured password, or the hash cached by the last login
+        let username = self.config.auth.username.clone().unwrap_or_default();
+        let result = match self.authenticate(&username, "").await {
+            Ok(()) => {
+                event.phase = FailoverPhase::Authenticated;
+                self.events.failover(event);
+                if with_tunnel {
+                    self.establish_tunnel()
+                } else {
+                    Ok(())
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if let (Err(e), Some(cluster_manager)) = (&result, self.cluster_manager.as_mut()) {
+            cluster_manager.record_failure(index, NodeFailure::from_error(e));
+        }
+        result
+    }
+
+    fn abandon_failover(&mut self, event: FailoverEvent, error: &VpnError) {
+        self.record_event(format!("Cluster failover abandoned: {error}"));
+        self.events.failover(&FailoverEvent { phase: FailoverPhase::Abandoned, error: Some(error.to_string()), ..event });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClusterNodeConfig, Config};
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn test_failover_walks_the_nodes() {
+        // Ports nothing listens on refuse at once
+        let mut closed = Vec::new();
+        for _ in 0..2 {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            closed.push(listener.local_addr().unwrap());
+        }
+        let mut config = Config::default_test();
+        config.connection_limits.retry_delay = 0;
+        config.clustering.enabled = true;
+        config.clustering.cluster_nodes = closed.iter().map(|addr| ClusterNodeConfig::from(addr.to_string())).collect();
+        let mut client = VpnClient::new(config).unwrap();
+        assert!(client.can_fail_over());
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        client.events().on_failover(Some(Arc::new(move |event: &FailoverEvent| {
+            sink.lock().unwrap().push((event.phase, event.to));
+        })));
+
+        let result = client.handle_cluster_failover().await;
+        assert!(matches!(result, Err(VpnError::Connection(_))), "{result:?}");
+        assert_eq!(client.status(), ConnectionStatus::Disconnected);
+        let events = events.lock().unwrap();
+        assert_eq!(events.first(), Some(&(FailoverPhase::Started, None)));
+        assert_eq!(events.last().map(|(phase, _)| *phase), Some(FailoverPhase::Abandoned));
+        // Both nodes were tried once, and both are now blacklisted
+        let failed: Vec<_> = events.iter().filter(|(phase, _)| *phase == FailoverPhase::NodeFailed).collect();
+        assert_eq!(failed.len(), 2);
+        assert!(closed.iter().all(|addr| events.contains(&(FailoverPhase::Connecting, Some(*addr)))));
+        assert!(client.cluster_manager.as_ref().unwrap().next_retry().is_some());
+
+        // A failover that moved less than failover_timeout ago is not repeated
+        assert!(client.cluster_manager.as_ref().unwrap().failover_cooldown().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_failover_needs_clustering() {
+        let mut client = VpnClient::new(Config::default_test()).unwrap();
+        assert!(!client.can_fail_over());
+        assert!(matches!(client.handle_cluster_failover().await, Err(VpnError::Configuration(_))));
+    }
+}
@@ -0,0 +1,352 @@
+//! Tunnel health checks
+//!
+//! Keepalives only show that the server still answers on the session. The
+//! tunnel behind it can pass nothing while they keep succeeding, for example
+//! when the hub's virtual NAT stalls or a route is taken over. With
+//! `[health]` enabled, the keepalive loop sends a few probes through the
+//! tunnel every `interval_secs`: echoes to the hub gateway, DNS queries to
+//! the tunnel's DNS server, or HTTP requests. [`HealthMonitor`] turns the
+//! answers into packet loss and RTT over the last checks. Changes of
+//! [`HealthState`] are reported as [`HealthEvent`]s. When the tunnel goes
+//! down, `health.action` decides whether to reconnect or fail over.
+
+use super::VpnClient;
+use crate::config::{HealthAction, HealthConfig, HealthProbe};
+use crate::diagnostics;
+use crate::error::{Result, VpnError};
+use crate::timestamp::Timestamp;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// How well the tunnel passes traffic
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HealthState {
+    /// Nothing checked yet, or everything within limits
+    #[default]
+    Healthy,
+    /// Loss or RTT above `health.max_loss` or `health.max_rtt_ms`
+    Degraded,
+    /// `health.failed_checks` checks in a row got no answer
+    Down,
+}
+
+/// Outcome of one check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthCheck {
+    pub sent: u32,
+    pub answered: u32,
+    /// Average round trip of the answered probes
+    pub rtt: Option<Duration>,
+}
+
+/// Tunnel health over the recent checks, as returned by [`VpnClient::check_health`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthReport {
+    pub state: HealthState,
+    /// Fraction of probes lost, between 0.0 and 1.0
+    pub loss: f64,
+    /// Average round trip of the answered probes
+    pub rtt: Option<Duration>,
+    /// Checks in a row without any answer
+    pub failed_checks: u32,
+    pub at: Timestamp,
+}
+
+/// The tunnel's [`HealthState`] changed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HealthEvent {
+    pub from: HealthState,
+    pub report: HealthReport,
+}
+
+/// Loss and RTT over a window of checks, and the state they amount to
+#[derive(Debug, Clone)]
+pub struct HealthMonitor {
+    checks: VecDeque<HealthCheck>,
+    window: usize,
+    max_loss: f64,
+    max_rtt: Option<Duration>,
+    down_after: u32,
+    failed_checks: u32,
+    state: HealthState,
+}
+
+impl HealthMonitor {
+    pub fn new(config: &HealthConfig) -> Self {
+        Self {
+            checks: VecDeque::new(),
+            window: config.window.max(1) as usize,
+            max_loss: config.max_loss,
+            max_rtt: config.max_rtt_ms.map(|ms| Duration::from_millis(u64::from(ms))),
+            down_after: config.failed_checks.max(1),
+            failed_checks: 0,
+            state: HealthState::Healthy,
+        }
+    }
+
+    pub fn state(&self) -> HealthState {
+        self.state
+    }
+
+    /// Add a check; returns the event when the state changed
+    pub fn record(&mut self, check: HealthCheck) -> Option<HealthEvent> {
+        if self.checks.len() == self.window {
+            self.checks.pop_front();
+        }
+        self.checks.push_back(check);
+        self.failed_checks = if check.answered == 0 { self.failed_checks + 1 } else { 0 };
+
+        let report = self.report();
+        let state = if self.failed_checks >= self.down_after {
+            HealthState::Down
+        } else if report.loss > self.max_loss || self.max_rtt.zip(report.rtt).is_some_and(|(max, rtt)| rtt > max) {
+            HealthState::Degraded
+        } else {
+            HealthState::Healthy
+        };
+        let from = std::mem::replace(&mut self.state, state);
+        (from != state).then_some(HealthEvent { from, report: HealthReport { state, ..report } })
+    }
+
+    /// Health over the checks in the window
+    pub fn report(&self) -> HealthReport {
+        let sent: u32 = self.checks.iter().map(|check| check.sent).sum();
+        let answered: u32 = self.checks.iter().map(|check| check.answered).sum();
+        // Each check's average RTT weighs by the answers it stands for
+        let timed: Vec<_> = self.checks.iter().filter_map(|check| Some((check.rtt?, check.answered))).collect();
+        let weight: u32 = timed.iter().map(|(_, answered)| answered).sum();
+        let rtt_total: Duration = timed.iter().map(|(rtt, answered)| *rtt * *answered).sum();
+        HealthReport {
+            state: self.state,
+            loss: if sent == 0 { 0.0 } else { f64::from(sent - answered) / f64::from(sent) },
+            rtt: (weight > 0).then(|| rtt_total / weight),
+            failed_checks: self.failed_checks,
+            at: Timestamp::now(),
+        }
+    }
+
+    /// Forget the checks, for a new session
+    pub fn reset(&mut self) {
+        self.checks.clear();
+        self.failed_checks = 0;
+        self.state = HealthState::Healthy;
+    }
+}
+
+impl VpnClient {
+    /// Probe the tunnel once as `[health]` says and update its health
+    ///
+    /// Works whether or not `health.enabled` is set; the keepalive loop only
+    /// calls it periodically when it is. A probe that times out counts as
+    /// lost. Fails when the tunnel is not up or probing is impossible, for
+    /// example without permission to send ICMP.
+    pub async fn check_health(&mut self) -> Result<HealthReport> {
+        let target = self.probe_target()?;
+        let deadline = Duration::from_millis(u64::from(self.config.health.timeout_ms));
+        let check = probe_tunnel(&target, self.config.health.probes, deadline).await?;
+        match self.health.record(check) {
+            Some(event) => {
+                let report = event.report;
+                log::warn!(
+                    loss = report.loss, rtt_ms:? = report.rtt.map(|rtt| rtt.as_millis());
+                    "Tunnel health {:?} -> {:?}", event.from, report.state
+                );
+                self.record_event(format!("Tunnel health {:?} -> {:?}", event.from, report.state));
+                self.events.health_changed(&event);
+                Ok(report)
+            }
+            None => Ok(self.health.report()),
+        }
+    }
+
+    /// Tunnel health over the recent checks
+    pub fn health(&self) -> HealthReport {
+        self.health.report()
+    }
+
+    /// Check health and act on a tunnel that just went down, for the keepalive loop
+    pub(crate) async fn run_health_check(&mut self) -> Result<()> {
+        let was = self.health.state();
+        let report = match self.check_health().await {
+            Ok(report) => report,
+            Err(e) => {
+                log::warn!("Health check failed: {}", e);
+                return Ok(());
+            }
+        };
+        if report.state != HealthState::Down || was == HealthState::Down {
+            return Ok(());
+        }
+        let cause = VpnError::Timeout(format!(
+            "Tunnel passes no traffic: {} health checks in a row went unanswered",
+            report.failed_checks
+        ));
+        match self.config.health.action {
+            HealthAction::Notify => Ok(()),
+            HealthAction::Reconnect => self.recover(cause, false).await,
+            HealthAction::Failover => {
+                let fail_over = self.can_fail_over();
+                if !fail_over {
+                    log::warn!("Cluster failover is not available; reconnecting to the same server");
+                }
+                self.recover(cause, fail_over).await
+            }
+        }
+    }
+
+    /// What `[health]` probes in the current tunnel
+    fn probe_target(&self) -> Result<ProbeTarget> {
+        let tunnel_config = self
+            .tunnel_manager
+            .as_ref()
+            .and_then(|tm| tm.get_config())
+            .ok_or_else(|| VpnError::Connection("Tunnel not established".to_string()))?;
+        let health = &self.config.health;
+        Ok(match health.probe {
+            HealthProbe::Icmp => ProbeTarget::Icmp(match health.target {
+                Some(ref ip) => ip
+                    .parse()
+                    .map_err(|e| VpnError::Config(format!("Invalid health.target '{ip}': {e}")))?,
+                None => tunnel_config.remote_ip,
+            }),
+            HealthProbe::Dns => ProbeTarget::Dns {
+                server: tunnel_config.dns_servers.first().copied().unwrap_or(tunnel_config.remote_ip),
+                name: health
+                    .target
+                    .clone()
+                    .or_else(|| self.config.diagnostics.internal_probe_name.clone())
+                    .or(tunnel_config.dns_domain)
+                    .ok_or_else(|| VpnError::Config("No name to probe: set health.target for dns probes".into()))?,
+            },
+            HealthProbe::Http => ProbeTarget::Http(health.target.clone().unwrap_or_default()),
+        })
+    }
+}
+
+/// Where a health check sends its probes
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ProbeTarget {
+    Icmp(Ipv4Addr),
+    Dns { server: Ipv4Addr, name: String },
+    Http(String),
+}
+
+/// Send `probes` probes to `target` one after the other
+///
+/// A probe that times out or cannot get through counts as lost; other
+/// errors mean probing itself is impossible and end the check.
+async fn probe_tunnel(target: &ProbeTarget, probes: u32, deadline: Duration) -> Result<HealthCheck> {
+    let mut check = HealthCheck { sent: 0, answered: 0, rtt: None };
+    let mut total = Duration::ZERO;
+    for _ in 0..probes {
+        let start = Instant::now();
+        let answer = match target {
+            ProbeTarget::Icmp(ip) => diagnostics::ping_gateway(*ip, deadline).await.map(drop),
+            ProbeTarget::Dns { server, name } => match diagnostics::query_dns_server(*server, name, deadline).await {
+                // Any answer shows the query made it through
+                Err(VpnError::Dns(_)) => Ok(()),
+                result => result.map(drop),
+            },
+            ProbeTarget::Http(url) => http_probe(url, deadline).await,
+        };
+        check.sent += 1;
+        match answer {
+            Ok(()) => {
+                check.answered += 1;
+                total += start.elapsed();
+            }
+            Err(VpnError::Timeout(_) | VpnError::Io(_) | VpnError::Network(_)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    check.rtt = (check.answered > 0).then(|| total / check.answered);
+    Ok(check)
+}
+
+/// Fetch `url`; any HTTP response counts as an answer
+async fn http_probe(url: &str, deadline: Duration) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(deadline)
+        .build()
+        .map_err(|e| VpnError::Network(format!("Failed to create HTTP client: {e}")))?;
+    match client.head(url).send().await {
+        Ok(_) => Ok(()),
+        Err(e) if e.is_timeout() => Err(VpnError::Timeout(format!("{url} did not answer within {deadline:?}"))),
+        Err(e) => Err(VpnError::Network(format!("Health probe to {url} failed: {e}"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor() -> HealthMonitor {
+        HealthMonitor::new(&HealthConfig {
+            window: 3,
+            max_loss: 0.2,
+            max_rtt_ms: Some(200),
+            failed_checks: 2,
+            ..Default::default()
+        })
+    }
+
+    fn check(answered: u32, rtt_ms: u64) -> HealthCheck {
+        HealthCheck { sent: 3, answered, rtt: (answered > 0).then(|| Duration::from_millis(rtt_ms)) }
+    }
+
+    #[test]
+    fn test_loss_and_rtt_over_the_window() {
+        let mut health = monitor();
+        assert_eq!(health.record(check(3, 40)), None);
+        assert_eq!(health.report().loss, 0.0);
+        assert_eq!(health.report().rtt, Some(Duration::from_millis(40)));
+
+        // One in three lost over two checks is above 20 %
+        let event = health.record(check(1, 100)).unwrap();
+        assert_eq!((event.from, event.report.state), (HealthState::Healthy, HealthState::Degraded));
+        assert!((event.report.loss - 2.0 / 6.0).abs() < 1e-9);
+        assert_eq!(event.report.rtt, Some(Duration::from_millis(55)));
+
+        // The lossy check leaves the window
+        health.record(check(3, 40));
+        health.record(check(3, 40));
+        assert_eq!(health.record(check(3, 40)).unwrap().report.state, HealthState::Healthy);
+
+        // Slow but complete answers degrade too
+        assert_eq!(health.record(check(3, 900)).unwrap().report.state, HealthState::Degraded);
+    }
+
+    #[test]
+    fn test_down_after_unanswered_checks() {
+        let mut health = monitor();
+        health.record(check(3, 40));
+        assert_eq!(health.record(check(0, 0)).unwrap().report.state, HealthState::Degraded);
+        let event = health.record(check(0, 0)).unwrap();
+        assert_eq!((event.from, event.report.state), (HealthState::Degraded, HealthState::Down));
+        assert_eq!(event.report.failed_checks, 2);
+        assert_eq!(health.record(check(0, 0)), None);
+
+        // One answer ends the outage
+        assert_eq!(health.record(check(3, 40)).unwrap().report.state, HealthState::Degraded);
+        health.reset();
+        assert_eq!(health.state(), HealthState::Healthy);
+        assert_eq!(health.report().rtt, None);
+    }
+
+    #[tokio::test]
+    async fn test_probes_count_lost_and_answered() {
+        // Nothing listens on a closed port: every request fails to get through
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let target = ProbeTarget::Http(format!("http://{closed}/"));
+        let check = probe_tunnel(&target, 2, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(check, HealthCheck { sent: 2, answered: 0, rtt: None });
+    }
+
+    #[tokio::test]
+    async fn test_check_health_needs_a_tunnel() {
+        let mut client = VpnClient::new(crate::config::Config::default_test()).unwrap();
+        assert!(matches!(client.check_health().await, Err(VpnError::Connection(_))));
+        assert_eq!(client.health().state, HealthState::Healthy);
+    }
+}
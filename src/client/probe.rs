@@ -0,0 +1,184 @@
+//! Server probe
+//!
+//! [`VpnClient::probe_server`] tells a server picker what it needs to know
+//! before connecting: whether a server answers, how quickly, with which TLS
+//! and which SoftEther build. The probe goes as far as the watermark
+//! handshake, whose answer is the server's hello, and never logs in.
+//!
+//! Connections are made directly, without `network.proxy_url` or a PAC
+//! file: through a proxy the timings would describe the proxy instead.
+
+use super::{serialize_millis, VpnClient};
+use crate::crypto::tls::{certificate_fingerprint, CertificatePolicy};
+use crate::error::{Result, VpnError};
+use crate::protocol::{ProtocolHandler, ServerInfo};
+use crate::resolver;
+use crate::underlay::{self, UnderlayBinding};
+use rustls::pki_types::ServerName;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// What a server showed of itself, as returned by [`VpnClient::probe_server`]
+///
+/// Serializes with durations in milliseconds. A server that accepted the TCP
+/// connection but failed a later step is still reported, with that step's
+/// fields `None` and the reason in `error`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerProbe {
+    /// Address probed, the first to connect when the name has several
+    pub address: SocketAddr,
+    /// TCP connect time, about one round trip
+    #[serde(rename = "tcp_connect_ms", serialize_with = "serialize_millis")]
+    pub tcp_connect: Duration,
+    #[serde(rename = "tls_handshake_ms", serialize_with = "serialize_millis")]
+    pub tls_handshake: Option<Duration>,
+    /// TLS version negotiated, e.g. `TLSv1_3`
+    pub tls_version: Option<String>,
+    pub cipher_suite: Option<String>,
+    /// SHA-256 fingerprint of the server certificate, as pinned in `tls.pinned_certificates`
+    pub certificate_sha256: Option<String>,
+    /// Time from the watermark request to the server's hello
+    #[serde(rename = "watermark_ms", serialize_with = "serialize_millis")]
+    pub watermark: Option<Duration>,
+    /// Product and version from the hello
+    pub server: Option<ServerInfo>,
+    /// Why the probe stopped short of the hello
+    pub error: Option<String>,
+}
+
+impl ServerProbe {
+    /// Serialize to a JSON object
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+    }
+
+    /// Whether the server answered the watermark like a SoftEther server
+    pub fn is_softether(&self) -> bool {
+        self.server.is_some()
+    }
+}
+
+impl VpnClient {
+    /// Probe `server:port` without connecting the client
+    ///
+    /// Measures a TCP connect, a TLS handshake under the configured
+    /// certificate policy and a watermark exchange, each on its own
+    /// connection, all within `server.timeout`. Fails only when the server
+    /// cannot be resolved or reached over TCP. The client's state is left as
+    /// it is, so a connected client can probe other servers.
+    pub async fn probe_server(&self, server: &str, port: u16) -> Result<ServerProbe> {
+        let deadline = self.config.session_timeouts(&format!("{server}:{port}")).connect;
+        tokio::time::timeout(deadline, self.run_probe(server, port)).await.unwrap_or_else(|_| {
+            Err(VpnError::Timeout(format!("Probing {server}:{port} timed out after {}s", deadline.as_secs())))
+        })
+    }
+
+    async fn run_probe(&self, server: &str, port: u16) -> Result<ServerProbe> {
+        let addresses = resolver::resolve(server, port, &self.config.network).await?;
+        let mark = self.socket_mark();
+        let address = match addresses.len() {
+            1 => addresses[0],
+            _ => resolver::race(&addresses, resolver::CONNECTION_ATTEMPT_DELAY, mark).await?,
+        };
+
+        let started = Instant::now();
+        let stream = underlay::connect_tcp(address, mark).await?;
+        let mut probe = ServerProbe {
+            address,
+            tcp_connect: started.elapsed(),
+            tls_handshake: None,
+            tls_version: None,
+            cipher_suite: None,
+            certificate_sha256: None,
+            watermark: None,
+            server: None,
+            error: None,
+        };
+
+        let certificates = CertificatePolicy::from_config(&self.config)?;
+        if let Err(e) = tls_handshake(&mut probe, stream, server, &certificates).await {
+            probe.error = Some(e.to_string());
+            return Ok(probe);
+        }
+
+        let underlay = match self.config.network.underlay_interface {
+            Some(ref name) => Some(UnderlayBinding::resolve(name)?),
+            None => None,
+        };
+        let mut handler = ProtocolHandler::with_underlay(address, &certificates, None, underlay.as_ref())?;
+        handler.set_identity(&crate::protocol::ClientIdentity::from_config(&self.config.identity)?);
+        let started = Instant::now();
+        match handler.establish_session().await {
+            Ok(()) => {
+                probe.watermark = Some(started.elapsed());
+                probe.server = handler.server_hello().map(ServerInfo::from_hello);
+            }
+            Err(e) => probe.error = Some(e.to_string()),
+        }
+        log::debug!("Probed {}:{}: {:?}", server, port, probe);
+        Ok(probe)
+    }
+}
+
+/// Run a TLS handshake over `stream` and note what was negotiated in `probe`
+async fn tls_handshake(
+    probe: &mut ServerProbe,
+    stream: tokio::net::TcpStream,
+    server: &str,
+    certificates: &CertificatePolicy,
+) -> Result<()> {
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(certificates.client_config()?));
+    let name = match server.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => ServerName::IpAddress(ip.into()),
+        Err(_) => ServerName::try_from(server.to_string())
+            .map_err(|e| VpnError::Config(format!("Invalid server name '{server}': {e}")))?,
+    };
+    let started = Instant::now();
+    let stream = connector
+        .connect(name, stream)
+        .await
+        .map_err(|e| VpnError::Tls(format!("TLS handshake failed: {e}")))?;
+    probe.tls_handshake = Some(started.elapsed());
+    let session = stream.get_ref().1;
+    probe.tls_version = session.protocol_version().map(|version| format!("{version:?}"));
+    probe.cipher_suite = session.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite()));
+    probe.certificate_sha256 = session
+        .peer_certificates()
+        .and_then(|chain| chain.first())
+        .map(|certificate| hex::encode(certificate_fingerprint(certificate)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use tokio::io::AsyncWriteExt;
+
+    #[tokio::test]
+    async fn test_probe_reports_how_far_it_got() {
+        let client = VpnClient::new(Config::default_test()).unwrap();
+
+        // Nothing listening: the server is unreachable
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        assert!(client.probe_server("127.0.0.1", closed.port()).await.is_err());
+
+        // Something listening that does not speak TLS
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").await;
+        });
+        let probe = client.probe_server("127.0.0.1", port).await.unwrap();
+        assert_eq!(probe.address.port(), port);
+        assert!(probe.tls_handshake.is_none() && !probe.is_softether());
+        assert!(probe.error.as_deref().is_some_and(|e| e.contains("TLS")), "{probe:?}");
+
+        let json: serde_json::Value = serde_json::from_str(&probe.to_json()).unwrap();
+        assert!(json["tcp_connect_ms"].is_u64());
+        assert!(json["server"].is_null());
+    }
+}
@@ -0,0 +1,514 @@
+//! Automatic reconnection
+//!
+//! When the session drops (failed keepalive, reset data connection) the
+//! client tears down what is left of it, then reconnects, re-authenticates and
+//! rebuilds the tunnel, waiting longer after each failed attempt as set in
+//! `[reconnect]`. Progress is reported through
+//! [`ClientEvents::on_reconnect`](super::ClientEvents::on_reconnect).
+//!
+//! A session the server ended itself is torn down at once. Causes that would
+//! only be refused again (the session was removed, the user or hub is gone)
+//! are not retried; [`VpnClient::disconnect_reason`] says what happened.
+
+use super::{ConnectionStatus, ReconnectEvent, ReconnectPhase, VpnClient};
+use crate::config::ReconnectConfig;
+use crate::error::{Result, VpnError};
+use crate::protocol::auth::disconnect_is_permanent;
+use crate::protocol::session::SessionManager;
+use crate::tunnel::TunnelManager;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+/// Exponential backoff with jitter between reconnection attempts
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    factor: f64,
+    jitter: f64,
+    max_attempts: u32,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: &ReconnectConfig) -> Self {
+        Self {
+            initial: Duration::from_millis(config.initial_delay_ms),
+            max: Duration::from_secs(u64::from(config.max_delay_secs)),
+            factor: config.backoff_factor,
+            jitter: config.jitter,
+            max_attempts: config.max_attempts,
+            attempt: 0,
+        }
+    }
+
+    /// Attempts started so far
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Start the next attempt and return how long to wait before it
+    ///
+    /// `None` once `max_attempts` have been made.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.max_attempts > 0 && self.attempt >= self.max_attempts {
+            return None;
+        }
+        self.attempt += 1;
+        Some(self.delay(self.attempt, rand::random::<f64>() * 2.0 - 1.0))
+    }
+
+    /// Delay before `attempt`, with `spread` in `-1.0..=1.0` scaling the jitter
+    fn delay(&self, attempt: u32, spread: f64) -> Duration {
+        let exponent = i32::try_from(attempt.saturating_sub(1)).unwrap_or(i32::MAX);
+        let base = (self.initial.as_secs_f64() * self.factor.powi(exponent)).min(self.max.as_secs_f64());
+        Duration::from_secs_f64((base * (1.0 + self.jitter * spread)).max(0.0))
+    }
+}
+
+/// Notices a session the server has stopped sending on
+///
+/// Follows the inbound packet counter; once it has not moved for longer than
+/// the idle timeout the session is treated as lost.
+#[derive(Debug, Clone)]
+pub struct IdleWatch {
+    limit: Option<Duration>,
+    received: u64,
+    since: Instant,
+}
+
+impl IdleWatch {
+    /// Watch with `limit` (`None` never reports idle)
+    pub fn new(limit: Option<Duration>) -> Self {
+        Self { limit, received: 0, since: Instant::now() }
+    }
+
+    /// Start the quiet period over at `now`
+    pub fn reset(&mut self, now: Instant) {
+        self.since = now;
+    }
+
+    /// Record the inbound packet counter
+    ///
+    /// Returns how long nothing has arrived once that exceeds the limit.
+    pub fn observe(&mut self, received: u64, now: Instant) -> Option<Duration> {
+        if received != self.received {
+            self.received = received;
+            self.since = now;
+            return None;
+        }
+        let quiet = now.saturating_duration_since(self.since);
+        self.limit.filter(|limit| quiet > *limit).map(|_| quiet)
+    }
+}
+
+/// Why a session ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectedReason {
+    /// [`VpnClient::disconnect`] was called
+    Local,
+    /// The server ended the session, e.g. an administrator removed it or the hub stopped
+    ServerRequested { code: u32, message: String },
+    /// The session was lost and could not be re-established
+    SessionLost(String),
+}
+
+impl DisconnectedReason {
+    fn from_cause(cause: &VpnError) -> Self {
+        match cause {
+            VpnError::ServerDisconnected { code, message } => {
+                DisconnectedReason::ServerRequested { code: *code, message: message.clone() }
+            }
+            other => DisconnectedReason::SessionLost(other.to_string()),
+        }
+    }
+}
+
+/// Whether reconnecting could get past `error`
+///
+/// Rejected credentials and configuration problems do not fix themselves,
+/// and retrying them would only count against the authentication lockout.
+/// The same goes for a server that removed the session or revoked access,
+/// and for a login the user cancelled.
+pub fn is_retryable(error: &VpnError) -> bool {
+    match error {
+        VpnError::ServerDisconnected { code, .. } | VpnError::ServerRefused { code, .. } => {
+            !disconnect_is_permanent(*code)
+        }
+        _ => !matches!(
+            error,
+            VpnError::Authentication(_)
+                | VpnError::Config(_)
+                | VpnError::Configuration(_)
+                | VpnError::Permission(_)
+                | VpnError::CapabilityUnavailable(_)
+                | VpnError::Cancelled(_)
+        ),
+    }
+}
+
+/// Feed what was heard from the server into the session's expiry clock
+///
+/// `received` is the inbound data packet counter, `None` while nothing is
+/// forwarded and the session counts as alive. `heard` is when the last frame
+/// of any kind arrived: the data counter skips keepalives, and an idle tunnel
+/// hears nothing else.
+fn track_server_activity(session: &mut SessionManager, received: Option<u64>, heard: Option<Instant>, now: Instant) {
+    match received {
+        Some(received) => session.observe_received(received, now),
+        None => session.record_activity(now),
+    }
+    if let Some(heard) = heard {
+        session.record_activity(heard);
+    }
+}
+
+impl VpnClient {
+    /// Check that the session is still alive
+    ///
+    /// Fails when the client is no longer connected or when packet forwarding
+    /// stopped because the data connection went away, with
+    /// [`VpnError::ServerDisconnected`] if the server said why.
+    pub fn check_session(&self) -> Result<()> {
+        match self.status() {
+            ConnectionStatus::Disconnected | ConnectionStatus::Connecting => {
+                Err(VpnError::Connection("Session lost".to_string()))
+            }
+            ConnectionStatus::Connected => Ok(()),
+            ConnectionStatus::Tunneling => {
+                let Some(tunnel_manager) = self.tunnel_manager.as_ref() else {
+                    return Ok(());
+                };
+                if let Some(notice) = tunnel_manager.disconnect_notice() {
+                    Err(notice.into())
+                } else if tunnel_manager.forwarding_stopped() {
+                    Err(VpnError::Connection("Data connection closed by the server".to_string()))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Fail when packets are being forwarded but none arrived within the idle timeout
+    pub(crate) fn check_idle(&self, watch: &mut IdleWatch) -> Result<()> {
+        let now = Instant::now();
+        let forwarding = self.tunnel_manager.as_ref().is_some_and(TunnelManager::is_forwarding);
+        if !forwarding {
+            watch.reset(now);
+            return Ok(());
+        }
+        match watch.observe(self.traffic.packets_received.load(Ordering::Relaxed), now) {
+            Some(quiet) => Err(VpnError::Timeout(format!(
+                "Nothing received from the server for {}s",
+                quiet.as_secs()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Fail when the server's session timeout ran out without a word from it
+    ///
+    /// Packets received and keepalives echoed count as activity; until
+    /// packets are being forwarded nothing can be observed and the session is
+    /// taken as alive. The resulting [`VpnError::Timeout`] goes to
+    /// [`recover_session`](Self::recover_session) like any other loss.
+    pub(crate) fn check_session_expiry(&mut self) -> Result<()> {
+        let forwarding = self.tunnel_manager.as_ref().is_some_and(TunnelManager::is_forwarding);
+        let received = forwarding.then(|| self.traffic.packets_received.load(Ordering::Relaxed));
+        let heard = self.heartbeat.as_ref().and_then(|heartbeat| heartbeat.last_heard());
+        let Some(session_manager) = self.session_manager.as_mut() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        track_server_activity(session_manager, received, heard, now);
+        session_manager.check_expiry(now)
+    }
+
+    /// Re-establish a lost session
+    ///
+    /// Tears down the remains of the previous session, then reconnects to the
+    /// same server with the same timeouts, re-authenticates with the configured
+    /// credentials and, if a tunnel was up, rebuilds it. Attempts are spaced by the `[reconnect]`
+    /// backoff; each is reported as a [`ReconnectEvent`]. Gives up on errors
+    /// that retrying cannot fix and after `max_attempts`. Works regardless of
+    /// `reconnect.enabled`, which only controls automatic reconnection.
+    pub async fn reconnect(&mut self) -> Result<()> {
+        let endpoint = self.server_endpoint;
+        let with_tunnel = self.status() == ConnectionStatus::Tunneling;
+        self.release_session();
+        self.reconnect_to(endpoint, with_tunnel).await
+    }
+
+    /// Reconnect to `endpoint` with backoff once the session is released
+    async fn reconnect_to(&mut self, endpoint: Option<SocketAddr>, with_tunnel: bool) -> Result<()> {
+        let mut backoff = Backoff::new(&self.config.reconnect);
+        let mut delay = backoff.next_delay();
+        while let Some(wait) = delay {
+            tokio::time::sleep(wait).await;
+            let mut event = ReconnectEvent {
+                phase: ReconnectPhase::Attempting,
+                attempt: backoff.attempt(),
+                endpoint,
+                retry_in: None,
+            };
+            self.events.reconnect(event);
+
+            let error = match self.reconnect_once(endpoint, with_tunnel).await {
+                Ok(()) => {
+                    self.reconnects += 1;
+                    self.record_event(format!("Reconnected after {} attempt(s)", event.attempt));
+                    self.events.reconnect(ReconnectEvent { phase: ReconnectPhase::Succeeded, ..event });
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            self.record_event(format!("Reconnect attempt {} failed: {error}", event.attempt));
+            self.release_session();
+
+            delay = if is_retryable(&error) { backoff.next_delay() } else { None };
+            event.phase = ReconnectPhase::Failed;
+            event.retry_in = delay;
+            self.events.reconnect(event);
+            if !is_retryable(&error) {
+                return Err(error);
+            }
+        }
+
+        Err(VpnError::RetryLimitExceeded(format!(
+            "Could not reconnect after {} attempts",
+            backoff.attempt()
+        )))
+    }
+
+    /// Handle a session loss detected while running
+    ///
+    /// Fails over to another cluster node when clustering failover is
+    /// enabled, reconnects to the same server otherwise; see [`recover`](Self::recover).
+    pub(crate) async fn recover_session(&mut self, cause: VpnError) -> Result<()> {
+        let fail_over = self.can_fail_over();
+        self.recover(cause, fail_over).await
+    }
+
+    /// Re-establish the session after `cause`, moving to another cluster node with `fail_over`
+    ///
+    /// Recovers if `reconnect.enabled` and `cause` is retryable, otherwise
+    /// fails with `cause`. A session the server ended is then released right
+    /// away; the kill switch stays engaged until [`disconnect`](VpnClient::disconnect).
+    /// When no cluster node takes the session, the client keeps reconnecting
+    /// to the node it was on with the `[reconnect]` backoff.
+    pub(crate) async fn recover(&mut self, cause: VpnError, fail_over: bool) -> Result<()> {
+        self.record_event(format!("Session lost: {cause}"));
+        self.events.error("session", &cause);
+        let reason = DisconnectedReason::from_cause(&cause);
+        let result = if self.config.reconnect.enabled && is_retryable(&cause) {
+            if fail_over {
+                self.fail_over_or_reconnect(&cause).await
+            } else {
+                self.reconnect().await
+            }
+        } else {
+            if matches!(cause, VpnError::ServerDisconnected { .. }) {
+                self.release_session();
+            }
+            Err(cause)
+        };
+        if result.is_err() {
+            self.disconnect_reason = Some(reason);
+        }
+        result
+    }
+
+    async fn fail_over_or_reconnect(&mut self, cause: &VpnError) -> Result<()> {
+        let endpoint = self.server_endpoint;
+        let with_tunnel = self.status() == ConnectionStatus::Tunneling;
+        match self.fail_over(Some(cause)).await {
+            Err(e) if is_retryable(&e) => {
+                log::warn!("Cluster failover failed, reconnecting to the same node: {}", e);
+                self.release_session();
+                self.reconnect_to(endpoint, with_tunnel).await
+            }
+            result => result,
+        }
+    }
+
+    /// One reconnection attempt: connect, authenticate and optionally rebuild the tunnel
+    async fn reconnect_once(&mut self, endpoint: Option<SocketAddr>, with_tunnel: bool) -> Result<()> {
+        let timeouts = self.timeouts;
+        match endpoint {
+            Some(endpoint) => {
+                self.connect_session(&endpoint.ip().to_string(), endpoint.port(), timeouts).await?;
+            }
+            None => {
+                let server = self.config.server.address.clone();
+                self.connect_session(&server, self.config.server.port, timeouts).await?;
+            }
+        }
+
+        // The login keeps the configured password, or the hash cached by the last login
+        let username = self.config.auth.username.clone().unwrap_or_default();
+        self.authenticate(&username, "").await?;
+
+        if with_tunnel {
+            self.establish_tunnel()?;
+        }
+        Ok(())
+    }
+
+    /// Drop the session and tunnel, even when teardown reports an error
+    ///
+    /// The kill switch stays engaged, so nothing leaks until the session is back.
+    pub(super) fn release_session(&mut self) {
+        if let Err(e) = self.close_session() {
+            log::warn!("Teardown of the lost session failed: {}", e);
+            self.record_event(format!("Teardown of the lost session failed: {e}"));
+            // Leave the tunnel manager to its drop-time cleanup and clear the rest
+            self.tunnel_manager = None;
+            let _ = self.close_session();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::sync::{Arc, Mutex};
+
+    fn backoff(max_attempts: u32) -> Backoff {
+        Backoff::new(&ReconnectConfig {
+            max_attempts,
+            initial_delay_ms: 500,
+            max_delay_secs: 5,
+            backoff_factor: 2.0,
+            jitter: 0.2,
+            ..ReconnectConfig::default()
+        })
+    }
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let unlimited = backoff(0);
+        assert_eq!(unlimited.delay(1, 0.0), Duration::from_millis(500));
+        assert_eq!(unlimited.delay(2, 0.0), Duration::from_secs(1));
+        assert_eq!(unlimited.delay(4, 0.0), Duration::from_secs(4));
+        assert_eq!(unlimited.delay(5, 0.0), Duration::from_secs(5));
+        assert_eq!(unlimited.delay(100, 0.0), Duration::from_secs(5));
+        assert_eq!(unlimited.delay(1, -1.0), Duration::from_millis(400));
+        assert_eq!(unlimited.delay(1, 1.0), Duration::from_millis(600));
+
+        let mut limited = backoff(2);
+        assert!(limited.next_delay().is_some_and(|d| d <= Duration::from_millis(600)));
+        assert!(limited.next_delay().is_some());
+        assert_eq!(limited.next_delay(), None);
+        assert_eq!(limited.attempt(), 2);
+    }
+
+    #[test]
+    fn test_keepalive_echoes_keep_idle_tunnel_alive() {
+        use crate::config::SessionTimeouts;
+        use crate::protocol::heartbeat::Heartbeat;
+
+        let timeouts = SessionTimeouts {
+            connect: Duration::from_secs(30),
+            keepalive_interval: Duration::from_secs(10),
+            idle: None,
+            dead_peer: None,
+        };
+        let mut session = SessionManager::new(&timeouts);
+        session.start_session().unwrap();
+        session.set_server_timeout(Some(Duration::from_secs(20)));
+        let heartbeat = Heartbeat::new();
+        let start = Instant::now();
+
+        // Forwarding, no data for a minute, the server answering every keepalive
+        for tick in 0..=6 {
+            let now = start + Duration::from_secs(10 * tick);
+            let probe = heartbeat.probe(7, tick as u32, now);
+            heartbeat.received(&probe, now + Duration::from_millis(30));
+            track_server_activity(&mut session, Some(5), heartbeat.last_heard(), now + Duration::from_secs(1));
+            assert!(session.check_expiry(now + Duration::from_secs(1)).is_ok(), "expired at {}s", 10 * tick);
+        }
+
+        // Once the echoes stop, the session runs out
+        let late = start + Duration::from_secs(85);
+        track_server_activity(&mut session, Some(5), heartbeat.last_heard(), late);
+        assert!(matches!(session.check_expiry(late), Err(VpnError::Timeout(_))));
+    }
+
+    #[test]
+    fn test_idle_watch() {
+        let start = Instant::now();
+        let mut watch = IdleWatch::new(Some(Duration::from_secs(10)));
+        watch.reset(start);
+        assert_eq!(watch.observe(0, start + Duration::from_secs(10)), None);
+        assert_eq!(watch.observe(3, start + Duration::from_secs(15)), None);
+        assert_eq!(watch.observe(3, start + Duration::from_secs(24)), None);
+        assert_eq!(watch.observe(3, start + Duration::from_secs(26)), Some(Duration::from_secs(11)));
+
+        watch.reset(start + Duration::from_secs(30));
+        assert_eq!(watch.observe(3, start + Duration::from_secs(35)), None);
+
+        let mut disabled = IdleWatch::new(None);
+        assert_eq!(disabled.observe(0, start + Duration::from_secs(3600)), None);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_reports_each_attempt() {
+        let mut config = Config::default_test();
+        config.server.port = 1;
+        config.connection_limits.retry_delay = 0;
+        config.reconnect.max_attempts = 2;
+        config.reconnect.initial_delay_ms = 1;
+        let mut client = VpnClient::new(config).unwrap();
+
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let sink = phases.clone();
+        client.events().on_reconnect(Some(Arc::new(move |event: &ReconnectEvent| {
+            sink.lock().unwrap().push((event.phase, event.attempt, event.retry_in.is_some()));
+        })));
+
+        let result = client.reconnect().await;
+        assert!(matches!(result, Err(VpnError::RetryLimitExceeded(_))), "{result:?}");
+        assert_eq!(client.status(), ConnectionStatus::Disconnected);
+        assert_eq!(
+            *phases.lock().unwrap(),
+            vec![
+                (ReconnectPhase::Attempting, 1, false),
+                (ReconnectPhase::Failed, 1, true),
+                (ReconnectPhase::Attempting, 2, false),
+                (ReconnectPhase::Failed, 2, false),
+            ]
+        );
+        assert!(!is_retryable(&VpnError::Authentication("bad password".into())));
+        assert!(!is_retryable(&VpnError::Cancelled("One-time password entry declined".into())));
+    }
+
+    #[tokio::test]
+    async fn test_permanent_server_disconnect_is_not_retried() {
+        let mut config = Config::default_test();
+        config.reconnect.enabled = true;
+        let mut client = VpnClient::new(config).unwrap();
+
+        let attempts = Arc::new(Mutex::new(0));
+        let sink = attempts.clone();
+        client.events().on_reconnect(Some(Arc::new(move |_: &ReconnectEvent| *sink.lock().unwrap() += 1)));
+
+        let removed = VpnError::ServerDisconnected { code: 11, message: "Removed by administrator".into() };
+        assert!(!is_retryable(&removed));
+        let result = client.recover_session(removed).await;
+        assert!(matches!(result, Err(VpnError::ServerDisconnected { code: 11, .. })), "{result:?}");
+        assert_eq!(*attempts.lock().unwrap(), 0);
+        assert_eq!(client.status(), ConnectionStatus::Disconnected);
+        assert_eq!(
+            client.disconnect_reason(),
+            Some(&DisconnectedReason::ServerRequested { code: 11, message: "Removed by administrator".into() })
+        );
+
+        // A stopping hub may come back
+        assert!(is_retryable(&VpnError::ServerDisconnected { code: 10, message: "Hub stopping".into() }));
+        client.disconnect().unwrap();
+        assert_eq!(client.disconnect_reason(), Some(&DisconnectedReason::Local));
+    }
+}
@@ -0,0 +1,188 @@
+//! Connection state machine
+//!
+//! [`VpnClient`](super::VpnClient) changes its [`ConnectionStatus`] only by
+//! applying a [`Transition`] to its [`ConnectionState`]. Which transitions a
+//! status allows is decided in one place, [`Transition::target`]; any other
+//! is refused, so the client never ends up tunneling without a session or
+//! connecting twice.
+//!
+//! ```text
+//! Disconnected --Connect--> Connecting --Establish--> Connected --OpenTunnel--> Tunneling
+//!              <---Fail----            Login, CloseTunnel: stay  <--CloseTunnel--
+//! Close: any status --> Disconnected
+//! ```
+//!
+//! Refusals are [`VpnError::Connection`] errors, like the status checks the
+//! state machine replaced, so FFI callers keep getting
+//! `VPNSE_CONNECTION_FAILED` for calls made in the wrong status.
+
+use super::ConnectionStatus;
+use crate::error::{Result, VpnError};
+
+/// Something that happened to the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// A connect attempt starts
+    Connect,
+    /// The server accepted the connect attempt
+    Establish,
+    /// The connect attempt failed
+    Fail,
+    /// A login on the connection succeeded
+    Login,
+    /// Packets flow through a tunnel device
+    OpenTunnel,
+    /// The tunnel device is gone; the session stays
+    CloseTunnel,
+    /// The session is over, whatever the status was
+    Close,
+}
+
+impl Transition {
+    /// Status this transition leads to from `from`, `None` if it is not allowed there
+    #[must_use]
+    pub fn target(self, from: ConnectionStatus) -> Option<ConnectionStatus> {
+        use ConnectionStatus::{Connected, Connecting, Disconnected, Tunneling};
+        match (from, self) {
+            (Disconnected, Transition::Connect) => Some(Connecting),
+            (Connecting, Transition::Establish) => Some(Connected),
+            (Connecting, Transition::Fail) => Some(Disconnected),
+            (Connected, Transition::Login) => Some(Connected),
+            (Connected, Transition::OpenTunnel) => Some(Tunneling),
+            // Closing a tunnel that never opened leaves the session as it is
+            (Connected | Tunneling, Transition::CloseTunnel) => Some(Connected),
+            (_, Transition::Close) => Some(Disconnected),
+            _ => None,
+        }
+    }
+
+    fn action(self) -> &'static str {
+        match self {
+            Transition::Connect => "connect",
+            Transition::Establish => "complete a connection",
+            Transition::Fail => "abandon a connect attempt",
+            Transition::Login => "log in",
+            Transition::OpenTunnel => "open the tunnel",
+            Transition::CloseTunnel => "close the tunnel",
+            Transition::Close => "close the session",
+        }
+    }
+}
+
+/// Status of a client, changed only through [`apply`](Self::apply)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionState {
+    status: ConnectionStatus,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        Self { status: ConnectionStatus::Disconnected }
+    }
+}
+
+impl ConnectionState {
+    #[must_use]
+    pub fn status(&self) -> ConnectionStatus {
+        self.status
+    }
+
+    /// Fail unless `transition` is allowed now, without applying it
+    ///
+    /// For guarding work that ends in `transition`, before doing it.
+    pub fn check(&self, transition: Transition) -> Result<ConnectionStatus> {
+        transition.target(self.status).ok_or_else(|| {
+            VpnError::Connection(format!("Cannot {} while {:?}", transition.action(), self.status))
+        })
+    }
+
+    /// Apply `transition`
+    ///
+    /// Returns the previous and the new status when the status changed, and
+    /// `None` for transitions that keep it, which report no event.
+    pub fn apply(&mut self, transition: Transition) -> Result<Option<(ConnectionStatus, ConnectionStatus)>> {
+        let target = self.check(transition)?;
+        if target == self.status {
+            return Ok(None);
+        }
+        let previous = std::mem::replace(&mut self.status, target);
+        Ok(Some((previous, target)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ConnectionStatus::{Connected, Connecting, Disconnected, Tunneling};
+
+    const ALL: [Transition; 7] = [
+        Transition::Connect,
+        Transition::Establish,
+        Transition::Fail,
+        Transition::Login,
+        Transition::OpenTunnel,
+        Transition::CloseTunnel,
+        Transition::Close,
+    ];
+
+    fn run(transitions: &[Transition]) -> Result<ConnectionState> {
+        let mut state = ConnectionState::default();
+        for &transition in transitions {
+            state.apply(transition)?;
+        }
+        Ok(state)
+    }
+
+    #[test]
+    fn test_session_lifecycle() {
+        let mut state = ConnectionState::default();
+        assert_eq!(state.apply(Transition::Connect).unwrap(), Some((Disconnected, Connecting)));
+        assert_eq!(state.apply(Transition::Establish).unwrap(), Some((Connecting, Connected)));
+        // Logging in and closing an unopened tunnel keep the status and report nothing
+        assert_eq!(state.apply(Transition::Login).unwrap(), None);
+        assert_eq!(state.apply(Transition::CloseTunnel).unwrap(), None);
+        assert_eq!(state.apply(Transition::OpenTunnel).unwrap(), Some((Connected, Tunneling)));
+        assert_eq!(state.apply(Transition::CloseTunnel).unwrap(), Some((Tunneling, Connected)));
+        assert_eq!(state.apply(Transition::Close).unwrap(), Some((Connected, Disconnected)));
+        assert_eq!(state.apply(Transition::Close).unwrap(), None);
+
+        let failed = run(&[Transition::Connect, Transition::Fail]).unwrap();
+        assert_eq!(failed.status(), Disconnected);
+    }
+
+    #[test]
+    fn test_illegal_transitions_are_refused() {
+        // No tunnel without a connection, no second connect
+        for transitions in [
+            &[Transition::OpenTunnel][..],
+            &[Transition::Login],
+            &[Transition::Connect, Transition::OpenTunnel],
+            &[Transition::Connect, Transition::Connect],
+            &[Transition::Connect, Transition::Establish, Transition::Connect],
+            &[Transition::Connect, Transition::Establish, Transition::Fail],
+            &[Transition::Connect, Transition::Establish, Transition::OpenTunnel, Transition::Login],
+        ] {
+            let err = run(transitions).unwrap_err();
+            assert!(matches!(err, VpnError::Connection(_)), "{transitions:?}: {err}");
+        }
+
+        let state = run(&[Transition::Connect]).unwrap();
+        let err = state.check(Transition::OpenTunnel).unwrap_err();
+        assert_eq!(err.to_string(), "Connection failed: Cannot open the tunnel while Connecting");
+        // A refused transition leaves the status alone
+        let mut state = state;
+        assert!(state.apply(Transition::Login).is_err());
+        assert_eq!(state.status(), Connecting);
+    }
+
+    #[test]
+    fn test_close_is_allowed_everywhere() {
+        for from in [Disconnected, Connecting, Connected, Tunneling] {
+            assert_eq!(Transition::Close.target(from), Some(Disconnected));
+        }
+        // Only a connect attempt leaves Disconnected
+        let leaving: Vec<_> =
+            ALL.into_iter().filter(|t| t.target(Disconnected).is_some_and(|to| to != Disconnected)).collect();
+        assert_eq!(leaving, [Transition::Connect]);
+    }
+}
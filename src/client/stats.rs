@@ -0,0 +1,153 @@
+//! Connection statistics
+//!
+//! [`VpnClient::stats`] combines the data path counters kept by the packet
+//! pump with what the client knows about the session itself: how long it has
+//! been up, how often it had to be re-established and the round-trip time to
+//! the server measured by the last keepalive echo. A UDP data path adds what
+//! its NAT keepalive has found out about the NAT in between.
+
+use super::{serialize_millis, ConnectionStatus, VpnClient};
+use crate::protocol::nat_keepalive::NatKeepaliveStats;
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Traffic and session counters, as returned by [`VpnClient::stats`]
+///
+/// Serializes with durations in milliseconds (`uptime_ms`, `rtt_ms`,
+/// `nat_keepalive_interval_ms`, `nat_timeout_ms`).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "bindings", derive(uniffi::Record))]
+pub struct ClientStats {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Data payload bytes before compression, when the session compresses
+    pub uncompressed_bytes: u64,
+    /// The same payloads as sent and received compressed
+    pub compressed_bytes: u64,
+    /// Time since the current session was established, if connected
+    #[serde(rename = "uptime_ms", serialize_with = "serialize_millis")]
+    pub uptime: Option<Duration>,
+    /// Sessions re-established after a loss over the client's lifetime
+    pub reconnects: u32,
+    /// Round trip of the last keepalive the server echoed, or of the last hub connectivity check
+    #[serde(rename = "rtt_ms", serialize_with = "serialize_millis")]
+    pub rtt: Option<Duration>,
+    /// Idle time before the UDP path (acceleration or NAT-T) sends a NAT keepalive, if there is one
+    #[serde(rename = "nat_keepalive_interval_ms", serialize_with = "serialize_millis")]
+    pub nat_keepalive_interval: Option<Duration>,
+    /// NAT mapping timeout the keepalive detected on the UDP path
+    #[serde(rename = "nat_timeout_ms", serialize_with = "serialize_millis")]
+    pub nat_timeout: Option<Duration>,
+}
+
+impl ClientStats {
+    /// Serialize to a JSON object
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+    }
+}
+
+/// When the current session came up; cleared with the session
+#[derive(Debug, Default)]
+pub(crate) struct SessionClock {
+    started: Option<Instant>,
+}
+
+impl SessionClock {
+    /// Follow a status change: start on connect, stop on disconnect
+    pub(crate) fn observe(&mut self, status: ConnectionStatus, now: Instant) {
+        match status {
+            ConnectionStatus::Connected | ConnectionStatus::Tunneling => {
+                self.started.get_or_insert(now);
+            }
+            ConnectionStatus::Disconnected | ConnectionStatus::Connecting => self.started = None,
+        }
+    }
+
+    pub(crate) fn uptime(&self, now: Instant) -> Option<Duration> {
+        self.started.map(|started| now.saturating_duration_since(started))
+    }
+}
+
+impl VpnClient {
+    /// Traffic counters, session uptime, reconnect count and the last measured RTT
+    ///
+    /// The traffic part is also kept for [`rates_over`](Self::rates_over),
+    /// like [`traffic_snapshot`](Self::traffic_snapshot).
+    pub fn stats(&self) -> ClientStats {
+        let traffic = self.traffic_snapshot();
+        let nat_keepalive = self.nat_keepalive_stats();
+        ClientStats {
+            bytes_sent: traffic.bytes_sent,
+            bytes_received: traffic.bytes_received,
+            packets_sent: traffic.packets_sent,
+            packets_received: traffic.packets_received,
+            uncompressed_bytes: traffic.uncompressed_bytes,
+            compressed_bytes: traffic.compressed_bytes,
+            uptime: self.session_clock.uptime(Instant::now()),
+            reconnects: self.reconnects,
+            rtt: *self.rtt.lock().unwrap(),
+            nat_keepalive_interval: nat_keepalive.as_ref().map(|stats| stats.current_interval),
+            nat_timeout: nat_keepalive.and_then(|stats| stats.detected_nat_timeout),
+        }
+    }
+
+    /// NAT keepalive of the UDP path: the UDP acceleration path, else the NAT-T streams
+    ///
+    /// `None` while the session runs over TCP only.
+    pub fn nat_keepalive_stats(&self) -> Option<NatKeepaliveStats> {
+        match (&self.udp_accel, &self.nat_relay) {
+            (Some(session), _) => Some(session.nat_keepalive_stats()),
+            (None, Some(relay)) => Some(relay.nat_keepalive_stats()),
+            (None, None) => None,
+        }
+    }
+
+    /// Remember the outcome of a gateway ping; `None` when it went unanswered
+    pub(crate) fn record_rtt(&self, rtt: Option<Duration>) {
+        if let Some(rtt) = rtt {
+            self.traffic.record_latency(u64::try_from(rtt.as_millis()).unwrap_or(u64::MAX));
+        }
+        *self.rtt.lock().unwrap() = rtt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_session_clock_spans_connected_states() {
+        let start = Instant::now();
+        let mut clock = SessionClock::default();
+        assert_eq!(clock.uptime(start), None);
+
+        clock.observe(ConnectionStatus::Connected, start);
+        clock.observe(ConnectionStatus::Tunneling, start + Duration::from_secs(2));
+        assert_eq!(clock.uptime(start + Duration::from_secs(5)), Some(Duration::from_secs(5)));
+
+        clock.observe(ConnectionStatus::Disconnected, start + Duration::from_secs(6));
+        assert_eq!(clock.uptime(start + Duration::from_secs(7)), None);
+    }
+
+    #[test]
+    fn test_stats_of_new_client() {
+        let client = VpnClient::new(Config::default_test()).unwrap();
+        client.traffic.update_traffic(1200, 300, 2, 1);
+        client.record_rtt(Some(Duration::from_millis(42)));
+
+        let stats = client.stats();
+        assert_eq!((stats.bytes_sent, stats.packets_received), (1200, 1));
+        assert_eq!((stats.uptime, stats.reconnects), (None, 0));
+
+        let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+        assert_eq!(json["rtt_ms"], 42);
+        assert!(json["uptime_ms"].is_null());
+        // No UDP path, so no NAT keepalive
+        assert_eq!(client.nat_keepalive_stats(), None);
+        assert!(json["nat_keepalive_interval_ms"].is_null());
+    }
+}
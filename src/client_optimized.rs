@@ -1,42 +1,36 @@
-//! High-Performance VPN Client with Optimizations
-//! 
-//! This module provides an optimized VPN client implementation with:
-//! - Async packet processing with batching
-//! - Connection pooling and load balancing
-//! - Adaptive performance tuning
-//! - Real-time monitoring and statistics
-
-use crate::error::{Result, VpnError};
-use crate::config::VpnConfig;
-// Note: Binary protocol removed - using HTTP Watermark + PACK instead
-// use crate::protocol::binary::BinaryProtocolClient;
-use crate::tunnel::real_tun::RealTunInterface;
-use bytes::Bytes;
+//! Data path tuning and performance statistics
+//!
+//! [`PerformanceConfig`] tunes how [`VpnClient`](crate::VpnClient) moves
+//! tunnel packets: how many bonded data connections it opens at once, how
+//! many packets go out in one write, and how many worker tasks seal them.
+//! [`PerformanceStats`] holds the live counters of the data path, which a
+//! monitor task samples into throughput and latency while tunneling.
+
+use crate::timestamp::Timestamp;
+use serde::Serialize;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::net::SocketAddr;
-use tokio::sync::{RwLock, mpsc, Semaphore};
-use tokio::time::{Duration, Instant, interval};
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant, MissedTickBehavior};
 use std::sync::atomic::{AtomicU64, AtomicBool, Ordering};
 
 /// Performance configuration
 #[derive(Debug, Clone)]
 pub struct PerformanceConfig {
-    /// Maximum concurrent connections
+    /// Bonded data connections opened at the same time when the server
+    /// grants several
     pub max_connections: usize,
-    /// Packet batch size for bulk processing
+    /// Most packets written to the data session in one go
     pub packet_batch_size: usize,
-    /// Buffer sizes
-    pub send_buffer_size: usize,
-    pub receive_buffer_size: usize,
-    /// Timeout settings
-    pub connection_timeout: Duration,
-    pub keepalive_interval: Duration,
-    /// Performance tuning
-    pub enable_compression: bool,
+    /// Hold back packets the pump has ready to write them together, up to
+    /// `packet_batch_size`; a lone packet still goes out straight away
     pub enable_packet_batching: bool,
-    pub adaptive_mtu: bool,
-    /// Monitoring
+    /// Worker tasks compressing and encrypting tunnel packets in parallel;
+    /// 1 keeps it on the packet pump's own tasks
+    pub packet_workers: usize,
+    /// How often throughput is sampled into the statistics while tunneling
     pub stats_interval: Duration,
+    /// Log throughput and latency at every sample
     pub enable_detailed_stats: bool,
 }
 
@@ -45,15 +39,10 @@ impl Default for PerformanceConfig {
         Self {
             max_connections: 10,
             packet_batch_size: 32,
-            send_buffer_size: 65536,
-            receive_buffer_size: 65536,
-            connection_timeout: Duration::from_secs(30),
-            keepalive_interval: Duration::from_secs(30),
-            enable_compression: true,
             enable_packet_batching: true,
-            adaptive_mtu: true,
+            packet_workers: 1,
             stats_interval: Duration::from_secs(10),
-            enable_detailed_stats: true,
+            enable_detailed_stats: false,
         }
     }
 }
@@ -66,6 +55,11 @@ pub struct PerformanceStats {
     pub bytes_received: AtomicU64,
     pub packets_sent: AtomicU64,
     pub packets_received: AtomicU64,
+    /// Data payload bytes before compression, both directions, while the
+    /// session compresses
+    pub uncompressed_bytes: AtomicU64,
+    /// The same payloads as compressed on the wire
+    pub compressed_bytes: AtomicU64,
     
     // Performance metrics
     pub avg_latency_ms: AtomicU64,
@@ -95,6 +89,8 @@ impl Default for PerformanceStats {
             bytes_received: AtomicU64::new(0),
             packets_sent: AtomicU64::new(0),
             packets_received: AtomicU64::new(0),
+            uncompressed_bytes: AtomicU64::new(0),
+            compressed_bytes: AtomicU64::new(0),
             avg_latency_ms: AtomicU64::new(0),
             throughput_mbps: AtomicU64::new(0),
             packet_loss_percent: AtomicU64::new(0),
@@ -129,24 +125,26 @@ impl PerformanceStats {
         self.packets_received.fetch_add(packets_received, Ordering::Relaxed);
     }
 
+    /// Count a payload of `uncompressed` bytes that took `compressed` bytes on the wire
+    pub fn update_compression(&self, uncompressed: u64, compressed: u64) {
+        self.uncompressed_bytes.fetch_add(uncompressed, Ordering::Relaxed);
+        self.compressed_bytes.fetch_add(compressed, Ordering::Relaxed);
+    }
+
     /// Update performance metrics
     pub fn update_performance(&self, latency_ms: u64, throughput_mbps: u64) {
-        // Use exponential moving average for smoother metrics
-        let current_latency = self.avg_latency_ms.load(Ordering::Relaxed);
-        let new_latency = if current_latency == 0 {
-            latency_ms
-        } else {
-            (current_latency * 7 + latency_ms) / 8 // 87.5% weight to history
-        };
-        self.avg_latency_ms.store(new_latency, Ordering::Relaxed);
-        
-        let current_throughput = self.throughput_mbps.load(Ordering::Relaxed);
-        let new_throughput = if current_throughput == 0 {
-            throughput_mbps
-        } else {
-            (current_throughput * 7 + throughput_mbps) / 8
-        };
-        self.throughput_mbps.store(new_throughput, Ordering::Relaxed);
+        self.record_latency(latency_ms);
+        self.record_throughput(throughput_mbps);
+    }
+
+    /// Average a measured round trip into `avg_latency_ms`
+    pub fn record_latency(&self, latency_ms: u64) {
+        moving_average(&self.avg_latency_ms, latency_ms);
+    }
+
+    /// Average a sampled throughput into `throughput_mbps`
+    pub fn record_throughput(&self, throughput_mbps: u64) {
+        moving_average(&self.throughput_mbps, throughput_mbps);
     }
 
     /// Get current statistics as a snapshot
@@ -156,6 +154,8 @@ impl PerformanceStats {
             bytes_received: self.bytes_received.load(Ordering::Relaxed),
             packets_sent: self.packets_sent.load(Ordering::Relaxed),
             packets_received: self.packets_received.load(Ordering::Relaxed),
+            uncompressed_bytes: self.uncompressed_bytes.load(Ordering::Relaxed),
+            compressed_bytes: self.compressed_bytes.load(Ordering::Relaxed),
             avg_latency_ms: self.avg_latency_ms.load(Ordering::Relaxed),
             throughput_mbps: self.throughput_mbps.load(Ordering::Relaxed),
             packet_loss_percent: self.packet_loss_percent.load(Ordering::Relaxed),
@@ -166,18 +166,20 @@ impl PerformanceStats {
             protocol_errors: self.protocol_errors.load(Ordering::Relaxed),
             network_errors: self.network_errors.load(Ordering::Relaxed),
             tunnel_errors: self.tunnel_errors.load(Ordering::Relaxed),
-            timestamp: Instant::now(),
+            timestamp: Timestamp::now(),
         }
     }
 }
 
 /// Performance statistics snapshot
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct PerformanceSnapshot {
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub packets_sent: u64,
     pub packets_received: u64,
+    pub uncompressed_bytes: u64,
+    pub compressed_bytes: u64,
     pub avg_latency_ms: u64,
     pub throughput_mbps: u64,
     pub packet_loss_percent: u64,
@@ -188,365 +190,149 @@ pub struct PerformanceSnapshot {
     pub protocol_errors: u64,
     pub network_errors: u64,
     pub tunnel_errors: u64,
-    pub timestamp: Instant,
+    pub timestamp: Timestamp,
 }
 
-/// Packet batch for optimized processing
-#[derive(Debug)]
-struct PacketBatch {
-    packets: Vec<Bytes>,
-    total_size: usize,
-    created_at: Instant,
-}
-
-impl PacketBatch {
-    fn new() -> Self {
-        Self {
-            packets: Vec::new(),
-            total_size: 0,
-            created_at: Instant::now(),
+impl PerformanceSnapshot {
+    /// Per-second rates between `earlier` and this snapshot
+    ///
+    /// Counters that went backwards (stats were reset) count as zero. The
+    /// interval is measured on the monotonic clock.
+    pub fn delta(&self, earlier: &PerformanceSnapshot) -> PerformanceRates {
+        let interval = self.timestamp.duration_since(&earlier.timestamp);
+        let secs = interval.as_secs_f64();
+        let per_sec = |now: u64, then: u64| {
+            if secs > 0.0 {
+                now.saturating_sub(then) as f64 / secs
+            } else {
+                0.0
+            }
+        };
+        let errors = |s: &PerformanceSnapshot| s.protocol_errors + s.network_errors + s.tunnel_errors;
+
+        PerformanceRates {
+            since: earlier.timestamp,
+            until: self.timestamp,
+            interval_secs: secs,
+            tx_mbps: per_sec(self.bytes_sent, earlier.bytes_sent) * 8.0 / 1_000_000.0,
+            rx_mbps: per_sec(self.bytes_received, earlier.bytes_received) * 8.0 / 1_000_000.0,
+            tx_pps: per_sec(self.packets_sent, earlier.packets_sent),
+            rx_pps: per_sec(self.packets_received, earlier.packets_received),
+            errors_per_sec: per_sec(errors(self), errors(earlier)),
+            connection_drops: self.connection_drops.saturating_sub(earlier.connection_drops),
         }
     }
+}
 
-    fn add_packet(&mut self, packet: Bytes) -> bool {
-        self.total_size += packet.len();
-        self.packets.push(packet);
-        
-        // Return true if batch should be flushed
-        self.packets.len() >= 32 || self.total_size >= 65536 || 
-        self.created_at.elapsed() > Duration::from_millis(10)
-    }
-
-    fn is_empty(&self) -> bool {
-        self.packets.is_empty()
-    }
-
-    fn len(&self) -> usize {
-        self.packets.len()
-    }
-
-    fn total_size(&self) -> usize {
-        self.total_size
-    }
+/// Rates derived from two [`PerformanceSnapshot`]s
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PerformanceRates {
+    /// Earlier snapshot
+    pub since: Timestamp,
+    /// Later snapshot
+    pub until: Timestamp,
+    /// Time between the two snapshots
+    pub interval_secs: f64,
+    pub tx_mbps: f64,
+    pub rx_mbps: f64,
+    pub tx_pps: f64,
+    pub rx_pps: f64,
+    /// Protocol, network and tunnel errors per second
+    pub errors_per_sec: f64,
+    /// Connection drops during the interval
+    pub connection_drops: u64,
+}
 
-    fn drain(&mut self) -> Vec<Bytes> {
-        let packets = std::mem::take(&mut self.packets);
-        self.total_size = 0;
-        self.created_at = Instant::now();
-        packets
+impl PerformanceRates {
+    /// Serialize to a JSON object
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
     }
 }
 
-/// High-performance optimized VPN client
-pub struct OptimizedVpnClient {
-    config: VpnConfig,
-    perf_config: PerformanceConfig,
-    stats: Arc<PerformanceStats>,
-    // Note: Binary protocol removed - using HTTP Watermark + PACK instead
-    // protocol_client: Option<BinaryProtocolClient>,
-    tun_interface: Option<RealTunInterface>,
-    
-    // Async channels for packet processing
-    outbound_tx: Option<mpsc::Sender<Bytes>>,
-    inbound_tx: Option<mpsc::Sender<Bytes>>,
-    
-    // Connection management
-    connection_semaphore: Arc<Semaphore>,
-    is_running: Arc<AtomicBool>,
-    
-    // Performance optimization
-    packet_batches: Arc<RwLock<PacketBatch>>,
-    adaptive_mtu: Arc<AtomicU64>,
+/// Bounded ring of recent snapshots for computing rates over a window
+#[derive(Debug)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<PerformanceSnapshot>,
+    capacity: usize,
 }
 
-impl OptimizedVpnClient {
-    /// Create new optimized VPN client
-    pub fn new(config: VpnConfig, perf_config: Option<PerformanceConfig>) -> Self {
-        let perf_config = perf_config.unwrap_or_default();
-        let connection_semaphore = Arc::new(Semaphore::new(perf_config.max_connections));
-        
+impl SnapshotHistory {
+    /// Keep at most `capacity` snapshots (at least two)
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(2);
         Self {
-            config,
-            perf_config,
-            stats: Arc::new(PerformanceStats::new()),
-            tun_interface: None,
-            outbound_tx: None,
-            inbound_tx: None,
-            connection_semaphore,
-            is_running: Arc::new(AtomicBool::new(false)),
-            packet_batches: Arc::new(RwLock::new(PacketBatch::new())),
-            adaptive_mtu: Arc::new(AtomicU64::new(1500)),
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
         }
     }
 
-    /// Connect to VPN server with optimizations
-    pub async fn connect(&mut self) -> Result<()> {
-        log::info!("Connecting to VPN with performance optimizations");
-        
-        // Acquire connection permit
-        let _permit = self.connection_semaphore.acquire().await
-            .map_err(|_| VpnError::Connection("Connection limit reached".to_string()))?;
-        
-        // Connect using binary protocol
-        let server_addr: SocketAddr = format!("{}:{}", self.config.server.address, self.config.server.port)
-            .parse()
-            .map_err(|e| VpnError::Config(format!("Invalid server address: {}", e)))?;
-        
-        // Note: Binary protocol removed - need to implement HTTP Watermark + PACK protocol
-        // TODO: Replace with proper SoftEther SSL-VPN implementation
-        return Err(VpnError::Network("Binary protocol no longer supported - use VpnClient instead".to_string()));
-    }
-
-    /// Start packet processing tasks
-    async fn start_packet_processors(
-        &self,
-        mut outbound_rx: mpsc::Receiver<Bytes>,
-        mut inbound_rx: mpsc::Receiver<Bytes>,
-    ) -> Result<()> {
-        let stats = Arc::clone(&self.stats);
-        let is_running = Arc::clone(&self.is_running);
-        let _packet_batches = Arc::clone(&self.packet_batches);
-        let enable_batching = self.perf_config.enable_packet_batching;
-        
-        // Outbound packet processor (TUN -> Server)
-        tokio::spawn(async move {
-            let mut batch = PacketBatch::new();
-            let mut batch_timer = interval(Duration::from_millis(5));
-            
-            while is_running.load(Ordering::Relaxed) {
-                tokio::select! {
-                    packet = outbound_rx.recv() => {
-                        if let Some(packet) = packet {
-                            if enable_batching {
-                                if batch.add_packet(packet) {
-                                    // Process batch
-                                    let packets = batch.drain();
-                                    Self::process_outbound_batch(&stats, packets).await;
-                                }
-                            } else {
-                                // Process individual packet
-                                Self::process_outbound_packet(&stats, packet).await;
-                            }
-                        }
-                    }
-                    _ = batch_timer.tick() => {
-                        if !batch.is_empty() {
-                            // Flush pending batch
-                            let packets = batch.drain();
-                            Self::process_outbound_batch(&stats, packets).await;
-                        }
-                    }
-                }
-            }
-        });
-
-        // Inbound packet processor (Server -> TUN)
-        let stats_clone = Arc::clone(&self.stats);
-        let is_running_clone = Arc::clone(&self.is_running);
-        
-        tokio::spawn(async move {
-            while is_running_clone.load(Ordering::Relaxed) {
-                if let Some(packet) = inbound_rx.recv().await {
-                    Self::process_inbound_packet(&stats_clone, packet).await;
-                }
-            }
-        });
-
-        Ok(())
-    }
-
-    /// Process outbound packet batch
-    async fn process_outbound_batch(stats: &PerformanceStats, packets: Vec<Bytes>) {
-        let start_time = Instant::now();
-        let mut total_bytes = 0;
-        let packet_count = packets.len();
-        
-        for packet in packets {
-            total_bytes += packet.len();
-            // Send packet to VPN server
-            // In real implementation, this would use the protocol client
-        }
-        
-        let processing_time = start_time.elapsed();
-        stats.update_traffic(total_bytes as u64, 0, packet_count as u64, 0);
-        
-        if processing_time > Duration::from_millis(100) {
-            log::warn!("Slow outbound batch processing: {:?} for {} packets", processing_time, packet_count);
+    /// Record a snapshot, evicting the oldest when full
+    pub fn push(&mut self, snapshot: PerformanceSnapshot) {
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
         }
+        self.snapshots.push_back(snapshot);
     }
 
-    /// Process individual outbound packet
-    async fn process_outbound_packet(stats: &PerformanceStats, packet: Bytes) {
-        let start_time = Instant::now();
-        
-        // Send packet to VPN server
-        // In real implementation, this would use the protocol client
-        
-        let processing_time = start_time.elapsed();
-        stats.update_traffic(packet.len() as u64, 0, 1, 0);
-        
-        if processing_time > Duration::from_millis(10) {
-            log::warn!("Slow outbound packet processing: {:?}", processing_time);
+    /// Rates from the oldest snapshot inside `window` to the latest one
+    ///
+    /// `None` until two snapshots at different times fall inside the window.
+    pub fn rates_over(&self, window: Duration) -> Option<PerformanceRates> {
+        let latest = self.snapshots.back()?;
+        let earliest = self
+            .snapshots
+            .iter()
+            .find(|s| latest.timestamp.duration_since(&s.timestamp) <= window)?;
+        if earliest.timestamp.instant >= latest.timestamp.instant {
+            return None;
         }
+        Some(latest.delta(earliest))
     }
 
-    /// Process inbound packet
-    async fn process_inbound_packet(stats: &PerformanceStats, packet: Bytes) {
-        let start_time = Instant::now();
-        
-        // Send packet to TUN interface
-        // In real implementation, this would use the TUN interface
-        
-        let processing_time = start_time.elapsed();
-        stats.update_traffic(0, packet.len() as u64, 0, 1);
-        
-        if processing_time > Duration::from_millis(10) {
-            log::warn!("Slow inbound packet processing: {:?}", processing_time);
-        }
-    }
-
-    /// Start performance monitoring task
-    async fn start_performance_monitor(&self) -> Result<()> {
-        let stats = Arc::clone(&self.stats);
-        let is_running = Arc::clone(&self.is_running);
-        let interval_duration = self.perf_config.stats_interval;
-        let detailed_stats = self.perf_config.enable_detailed_stats;
-        
-        tokio::spawn(async move {
-            let mut interval = interval(interval_duration);
-            let mut last_snapshot = stats.snapshot();
-            
-            while is_running.load(Ordering::Relaxed) {
-                interval.tick().await;
-                
-                let current_snapshot = stats.snapshot();
-                
-                // Calculate throughput
-                let time_diff = current_snapshot.timestamp.duration_since(last_snapshot.timestamp);
-                let bytes_diff = current_snapshot.bytes_sent + current_snapshot.bytes_received -
-                                last_snapshot.bytes_sent - last_snapshot.bytes_received;
-                
-                if time_diff.as_secs() > 0 {
-                    let throughput_mbps = (bytes_diff * 8) / (time_diff.as_secs() * 1_000_000);
-                    stats.update_performance(0, throughput_mbps); // Latency updated elsewhere
-                }
-                
-                if detailed_stats {
-                    log::info!("Performance: {}MB/s, {}ms latency, {} active connections",
-                        current_snapshot.throughput_mbps,
-                        current_snapshot.avg_latency_ms,
-                        current_snapshot.active_connections);
-                }
-                
-                last_snapshot = current_snapshot;
-            }
-        });
-
-        Ok(())
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
     }
 
-    /// Start keepalive task
-    async fn start_keepalive_task(&self) -> Result<()> {
-        let is_running = Arc::clone(&self.is_running);
-        let keepalive_interval = self.perf_config.keepalive_interval;
-        
-        tokio::spawn(async move {
-            let mut interval = interval(keepalive_interval);
-            
-            while is_running.load(Ordering::Relaxed) {
-                interval.tick().await;
-                
-                // Send keepalive
-                // In real implementation, this would use the protocol client
-                log::debug!("Sending optimized keepalive");
-            }
-        });
-
-        Ok(())
-    }
-
-    /// Send packet through optimized pipeline
-    pub async fn send_packet(&self, packet: Bytes) -> Result<()> {
-        if let Some(ref tx) = self.outbound_tx {
-            tx.send(packet).await
-                .map_err(|_| VpnError::Connection("Outbound channel closed".to_string()))?;
-        } else {
-            return Err(VpnError::Connection("Not connected".to_string()));
-        }
-        Ok(())
-    }
-
-    /// Get current performance statistics
-    pub fn get_stats(&self) -> PerformanceSnapshot {
-        self.stats.snapshot()
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
     }
+}
 
-    /// Optimize connection based on current performance
-    pub async fn optimize_performance(&mut self) -> Result<()> {
-        let stats = self.stats.snapshot();
-        
-        // Adaptive MTU adjustment
-        if self.perf_config.adaptive_mtu {
-            let current_mtu = self.adaptive_mtu.load(Ordering::Relaxed);
-            let new_mtu = if stats.packet_loss_percent > 5 {
-                // High packet loss - reduce MTU
-                std::cmp::max(current_mtu - 100, 1280)
-            } else if stats.avg_latency_ms < 50 && stats.throughput_mbps > 100 {
-                // Good performance - try larger MTU
-                std::cmp::min(current_mtu + 100, 9000)
-            } else {
-                current_mtu
-            };
-            
-            if new_mtu != current_mtu {
-                self.adaptive_mtu.store(new_mtu, Ordering::Relaxed);
-                log::info!("Adaptive MTU adjusted to: {}", new_mtu);
-            }
-        }
-        
-        // Log performance recommendations
-        if stats.avg_latency_ms > 200 {
-            log::warn!("High latency detected ({}ms). Consider server optimization.", stats.avg_latency_ms);
-        }
-        
-        if stats.throughput_mbps < 10 {
-            log::warn!("Low throughput detected ({}MB/s). Check network conditions.", stats.throughput_mbps);
-        }
-        
-        if stats.cpu_usage_percent > 80 {
-            log::warn!("High CPU usage ({}%). Consider reducing packet batch size.", stats.cpu_usage_percent);
-        }
-        
-        Ok(())
-    }
+/// Exponential moving average, 87.5% weight to history
+fn moving_average(average: &AtomicU64, sample: u64) {
+    let current = average.load(Ordering::Relaxed);
+    let next = if current == 0 {
+        sample
+    } else {
+        current.saturating_mul(7).saturating_add(sample) / 8
+    };
+    average.store(next, Ordering::Relaxed);
+}
 
-    /// Disconnect with cleanup
-    pub async fn disconnect(&mut self) -> Result<()> {
-        log::info!("Disconnecting optimized VPN client");
-        
-        self.is_running.store(false, Ordering::Relaxed);
-        self.stats.is_monitoring.store(false, Ordering::Relaxed);
-        
-        // Close channels
-        self.outbound_tx = None;
-        self.inbound_tx = None;
-        
-        // Note: Binary protocol client removed
-        // Protocol client cleanup no longer needed
-        
-        // Close TUN interface
-        if let Some(mut tun) = self.tun_interface.take() {
-            tun.destroy_interface().await?;
+/// Sample throughput into `stats` every `config.stats_interval` until aborted
+///
+/// Each sample is the megabits per second sent and received since the one
+/// before, averaged into `throughput_mbps`.
+pub(crate) async fn monitor_performance(stats: Arc<PerformanceStats>, config: PerformanceConfig) {
+    let mut ticker = tokio::time::interval(config.stats_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick completes immediately
+    ticker.tick().await;
+    let mut last = stats.snapshot();
+    loop {
+        ticker.tick().await;
+        let current = stats.snapshot();
+        let rates = current.delta(&last);
+        stats.record_throughput((rates.tx_mbps + rates.rx_mbps).round() as u64);
+        if config.enable_detailed_stats {
+            log::info!(
+                tx_mbps = rates.tx_mbps, rx_mbps = rates.rx_mbps, tx_pps = rates.tx_pps, rx_pps = rates.rx_pps,
+                latency_ms = current.avg_latency_ms, connections = current.active_connections;
+                "Tunnel performance"
+            );
         }
-        
-        log::info!("Optimized VPN client disconnected");
-        Ok(())
-    }
-
-    /// Check if client is connected
-    pub fn is_connected(&self) -> bool {
-        // Note: Binary protocol client removed, using is_running status only
-        self.is_running.load(Ordering::Relaxed)
+        last = current;
     }
 }
 
@@ -554,26 +340,6 @@ impl OptimizedVpnClient {
 mod tests {
     use super::*;
 
-    #[test]
-    fn test_packet_batch() {
-        let mut batch = PacketBatch::new();
-        assert!(batch.is_empty());
-        
-        let small_packet = Bytes::from(vec![0u8; 100]);
-        assert!(!batch.add_packet(small_packet)); // Should not trigger flush
-        assert_eq!(batch.len(), 1);
-        
-        // Add many packets to trigger batch size flush
-        for _ in 0..35 {
-            let packet = Bytes::from(vec![0u8; 100]);
-            if batch.add_packet(packet) {
-                break; // Batch flushed
-            }
-        }
-        
-        assert!(batch.len() >= 32); // Should have triggered batch flush
-    }
-
     #[test]
     fn test_performance_stats() {
         let stats = PerformanceStats::new();
@@ -589,35 +355,61 @@ mod tests {
         let snapshot = stats.snapshot();
         assert_eq!(snapshot.bytes_sent, 1000);
         assert_eq!(snapshot.avg_latency_ms, 50);
+
+        // Later samples move the average an eighth of the way
+        stats.record_latency(90);
+        assert_eq!(stats.avg_latency_ms.load(Ordering::Relaxed), 55);
+    }
+
+    #[test]
+    fn test_snapshot_delta_and_history() {
+        let stats = PerformanceStats::new();
+        let start = stats.snapshot();
+        let mut history = SnapshotHistory::new(3);
+        history.push(start.clone());
+        assert_eq!(history.rates_over(Duration::from_secs(10)), None);
+
+        // 2.5 MB and 2000 packets each way over two seconds
+        stats.update_traffic(2_500_000, 2_500_000, 2000, 2000);
+        let mut later = stats.snapshot();
+        later.timestamp = start.timestamp + Duration::from_secs(2);
+        let rates = later.delta(&start);
+        assert_eq!(rates.interval_secs, 2.0);
+        assert_eq!(rates.tx_mbps, 10.0);
+        assert_eq!(rates.rx_pps, 1000.0);
+        assert_eq!(rates.errors_per_sec, 0.0);
+
+        history.push(later.clone());
+        assert_eq!(history.rates_over(Duration::from_secs(10)), Some(rates));
+        // The start snapshot is outside a one-second window
+        assert_eq!(history.rates_over(Duration::from_secs(1)), None);
+
+        // Oldest snapshot is evicted once the ring is full
+        for offset in [3, 4] {
+            let mut next = later.clone();
+            next.timestamp = start.timestamp + Duration::from_secs(offset);
+            history.push(next);
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.rates_over(Duration::from_secs(10)).unwrap().interval_secs, 2.0);
     }
 
     #[tokio::test]
-    async fn test_optimized_client_creation() {
-        let config = VpnConfig {
-            server: crate::config::ServerConfig {
-                hostname: "test.example.com".to_string(),
-                port: 443,
-                hub: "VPN".to_string(),
-                use_ssl: true,
-                verify_certificate: true,
-                timeout: 30,
-                keepalive_interval: 60,
-            },
-            auth: crate::config::AuthConfig {
-                method: crate::config::AuthMethod::Password,
-                username: Some("testuser".to_string()),
-                password: Some("testpass".to_string()),
-                client_cert: None,
-                client_key: None,
-                ca_cert: None,
-            },
-            connection_limits: Default::default(),
-            network: Default::default(),
-            logging: Default::default(),
+    async fn test_monitor_samples_throughput() {
+        let stats = Arc::new(PerformanceStats::new());
+        let config = PerformanceConfig { stats_interval: Duration::from_millis(20), ..Default::default() };
+        let monitor = tokio::spawn(monitor_performance(stats.clone(), config));
+        // Let the monitor take its first snapshot
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        // A megabyte within one interval shows up as throughput
+        stats.update_traffic(1_000_000, 0, 1000, 0);
+        let sampled = async {
+            while stats.throughput_mbps.load(Ordering::Relaxed) == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
         };
-        
-        let client = OptimizedVpnClient::new(config, None);
-        assert!(!client.is_connected());
-        assert_eq!(client.perf_config.max_connections, 10);
+        tokio::time::timeout(Duration::from_secs(2), sampled).await.unwrap();
+        monitor.abort();
     }
 }
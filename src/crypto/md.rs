@@ -0,0 +1,128 @@
+//! MD4, MD5 and HMAC-MD5, the digests NTLM proxy authentication is built on
+//!
+//! Neither crypto backend ships them. They are only used to answer a proxy's
+//! NTLM challenge, never to protect VPN traffic.
+
+/// Digest length in bytes, for both MD4 and MD5
+pub const MD_LEN: usize = 16;
+
+const INITIAL_STATE: [u32; 4] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476];
+
+/// MD4 of `data`
+pub fn md4(data: &[u8]) -> [u8; MD_LEN] {
+    digest(data, md4_compress)
+}
+
+/// MD5 of `data`
+pub fn md5(data: &[u8]) -> [u8; MD_LEN] {
+    digest(data, md5_compress)
+}
+
+/// HMAC-MD5 of `data` under `key`
+pub fn hmac_md5(key: &[u8], data: &[u8]) -> [u8; MD_LEN] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..MD_LEN].copy_from_slice(&md5(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner: Vec<u8> = block.iter().map(|b| b ^ 0x36).chain(data.iter().copied()).collect();
+    let outer: Vec<u8> = block.iter().map(|b| b ^ 0x5c).chain(md5(&inner)).collect();
+    md5(&outer)
+}
+
+/// Pad like MD4 and MD5 do (little-endian length) and run `compress` over the blocks
+fn digest(data: &[u8], compress: fn(&mut [u32; 4], &[u32; 16])) -> [u8; MD_LEN] {
+    let bit_len = (data.len() as u64).wrapping_mul(8);
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&bit_len.to_le_bytes());
+
+    let mut state = INITIAL_STATE;
+    for block in message.chunks_exact(64) {
+        let mut words = [0u32; 16];
+        for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        compress(&mut state, &words);
+    }
+
+    let mut digest = [0u8; MD_LEN];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+fn md4_compress(state: &mut [u32; 4], x: &[u32; 16]) {
+    let [mut a, mut b, mut c, mut d] = *state;
+    for round in 0..3 {
+        for step in 0..16 {
+            let (f, k, shift) = match round {
+                0 => ((b & c) | (!b & d), step, [3, 7, 11, 19][step % 4]),
+                1 => ((b & c) | (b & d) | (c & d), (step % 4) * 4 + step / 4, [3, 5, 9, 13][step % 4]),
+                _ => (b ^ c ^ d, [0, 8, 4, 12, 2, 10, 6, 14, 1, 9, 5, 13, 3, 11, 7, 15][step], [3, 9, 11, 15][step % 4]),
+            };
+            let constant = [0, 0x5A82_7999, 0x6ED9_EBA1][round];
+            let rotated = a.wrapping_add(f).wrapping_add(x[k]).wrapping_add(constant).rotate_left(shift);
+            (a, b, c, d) = (d, rotated, b, c);
+        }
+    }
+    for (value, add) in state.iter_mut().zip([a, b, c, d]) {
+        *value = value.wrapping_add(add);
+    }
+}
+
+const MD5_SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+fn md5_compress(state: &mut [u32; 4], x: &[u32; 16]) {
+    let [mut a, mut b, mut c, mut d] = *state;
+    for i in 0..64 {
+        let (f, k) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        // floor(|sin(i + 1)| * 2^32), as the RFC tabulates it
+        let constant = ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32;
+        let shift = MD5_SHIFTS[(i / 16) * 4 + i % 4];
+        let rotated = a.wrapping_add(f).wrapping_add(constant).wrapping_add(x[k]).rotate_left(shift);
+        (a, b, c, d) = (d, b.wrapping_add(rotated), b, c);
+    }
+    for (value, add) in state.iter_mut().zip([a, b, c, d]) {
+        *value = value.wrapping_add(add);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_digest_vectors() {
+        // RFC 1320 and RFC 1321
+        assert_eq!(hex::encode(md4(b"")), "31d6cfe0d16ae931b73c59d7e0c089c0");
+        assert_eq!(hex::encode(md4(b"abc")), "a448017aaf21d8525fc10ae87aa6729d");
+        assert_eq!(
+            hex::encode(md4(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")),
+            "e33b4ddc9c38f2199c3e7b164fcc0536"
+        );
+        assert_eq!(hex::encode(md5(b"")), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex::encode(md5(b"abc")), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            hex::encode(md5(b"12345678901234567890123456789012345678901234567890123456789012345678901234567890")),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+
+        // RFC 2202, including a key longer than a block
+        assert_eq!(hex::encode(hmac_md5(b"Jefe", b"what do ya want for nothing?")), "750c783e6ab0b503eaa86e310a5db738");
+        assert_eq!(
+            hex::encode(hmac_md5(&[0xaa; 80], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+            "6b1ab7fe4bd7bf8f0b62e6ce61b9d0cd"
+        );
+    }
+}
@@ -5,21 +5,34 @@ use crate::error::Result;
 #[cfg(all(feature = "ring-crypto", not(feature = "aws-lc-crypto")))]
 use ring::rand::SecureRandom;
 #[cfg(all(feature = "ring-crypto", not(feature = "aws-lc-crypto")))]
-use ring::{aead, digest, pbkdf2, rand};
+use ring::{aead, digest, hkdf, pbkdf2, rand};
 
 #[cfg(all(feature = "aws-lc-crypto", not(feature = "ring-crypto")))]
 use aws_lc_rs::rand::SecureRandom;
 #[cfg(all(feature = "aws-lc-crypto", not(feature = "ring-crypto")))]
-use aws_lc_rs::{aead, digest, pbkdf2, rand};
+use aws_lc_rs::{aead, digest, hkdf, pbkdf2, rand};
 
 // If both features are enabled, prefer ring (for CI --all-features)
 #[cfg(all(feature = "ring-crypto", feature = "aws-lc-crypto"))]
 use ring::rand::SecureRandom;
 #[cfg(all(feature = "ring-crypto", feature = "aws-lc-crypto"))]
-use ring::{aead, digest, pbkdf2, rand};
+use ring::{aead, digest, hkdf, pbkdf2, rand};
 
+pub mod md;
+pub mod session;
+pub mod sha0;
 pub mod tls;
 
+pub use session::{DataCipher, DataKey, SessionKeys};
+pub use sha0::sha0;
+
+/// SHA-256 of `data`
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    hash
+}
+
 /// Cryptographic engine for VPN operations
 pub struct CryptoEngine {
     rng: rand::SystemRandom,
@@ -0,0 +1,254 @@
+//! Data path encryption keyed from the login exchange
+//!
+//! This is an rVPNSE extension, enabled with `server.encrypt_data`: SoftEther
+//! servers send data payloads as they are inside TLS and cannot read packets
+//! sealed this way.
+//!
+//! Both ends feed the server random from the hello, the SoftEther password
+//! hash and the session key from the login response into HKDF-SHA256 and
+//! get one AES-256-GCM key per direction. Nonces are per-direction packet
+//! counters, never sent: the stream is ordered, so a dropped, replayed or
+//! reordered packet fails authentication. Each further connection of a
+//! bonded session gets keys of its own, so no two streams share a nonce.
+//!
+//! To seal or open packets in parallel, nonces are reserved from the
+//! [`DataCipher`] in stream order and used with its shared [`DataKey`].
+
+use super::{aead, hkdf};
+use crate::error::{Result, VpnError};
+use std::sync::Arc;
+use zeroize::Zeroizing;
+
+/// Key length for AES-256-GCM
+pub const KEY_LEN: usize = 32;
+
+const CLIENT_TO_SERVER: &[u8] = b"rvpnse data client->server";
+const SERVER_TO_CLIENT: &[u8] = b"rvpnse data server->client";
+const CONNECTION: &[u8] = b"rvpnse data connection";
+
+/// Keys for both directions of one session, wiped on drop
+#[derive(Clone)]
+pub struct SessionKeys {
+    client_to_server: Zeroizing<[u8; KEY_LEN]>,
+    server_to_client: Zeroizing<[u8; KEY_LEN]>,
+}
+
+impl SessionKeys {
+    /// Derive the keys from the server `random` and the shared `secrets`
+    ///
+    /// Fails without a random or without at least one non-empty secret,
+    /// since the keys would then be predictable.
+    pub fn derive(random: &[u8], secrets: &[&[u8]]) -> Result<Self> {
+        if random.is_empty() {
+            return Err(VpnError::Crypto("No server random to derive session keys from".into()));
+        }
+        if secrets.iter().all(|secret| secret.is_empty()) {
+            return Err(VpnError::Crypto("No shared secret to derive session keys from".into()));
+        }
+        let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, random).extract(&secrets.concat());
+        Ok(Self {
+            client_to_server: expand(&prk, CLIENT_TO_SERVER)?,
+            server_to_client: expand(&prk, SERVER_TO_CLIENT)?,
+        })
+    }
+
+    /// Keys for data connection `index` of the session; 0 is the first, using these keys
+    pub fn for_connection(&self, index: u32) -> Result<Self> {
+        if index == 0 {
+            return Ok(self.clone());
+        }
+        let salt = hkdf::Salt::new(hkdf::HKDF_SHA256, &index.to_be_bytes());
+        let connection = |key: &[u8; KEY_LEN], direction: &[u8]| {
+            expand(&salt.extract(key), &[CONNECTION, direction].concat())
+        };
+        Ok(Self {
+            client_to_server: connection(&self.client_to_server, CLIENT_TO_SERVER)?,
+            server_to_client: connection(&self.server_to_client, SERVER_TO_CLIENT)?,
+        })
+    }
+
+    /// Ciphers for the client end: outbound, then inbound
+    pub fn client_ciphers(&self) -> Result<(DataCipher, DataCipher)> {
+        Ok((DataCipher::new(&self.client_to_server)?, DataCipher::new(&self.server_to_client)?))
+    }
+
+    /// Ciphers for the server end: outbound, then inbound
+    pub fn server_ciphers(&self) -> Result<(DataCipher, DataCipher)> {
+        Ok((DataCipher::new(&self.server_to_client)?, DataCipher::new(&self.client_to_server)?))
+    }
+}
+
+impl std::fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionKeys").finish_non_exhaustive()
+    }
+}
+
+fn expand(prk: &hkdf::Prk, info: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>> {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    prk.expand(&[info], &aead::AES_256_GCM)
+        .and_then(|okm| okm.fill(key.as_mut()))
+        .map_err(|_| VpnError::Crypto("Session key derivation failed".into()))?;
+    Ok(key)
+}
+
+/// AES-256-GCM for one direction of the data path
+pub struct DataCipher {
+    key: DataKey,
+    counter: u64,
+}
+
+impl DataCipher {
+    fn new(key: &[u8; KEY_LEN]) -> Result<Self> {
+        let key = aead::UnboundKey::new(&aead::AES_256_GCM, key)
+            .map_err(|_| VpnError::Crypto("Invalid session key".into()))?;
+        Ok(Self { key: DataKey(Arc::new(aead::LessSafeKey::new(key))), counter: 0 })
+    }
+
+    /// Reserve the nonce of the next packet in this direction, for [`DataKey`]
+    pub fn reserve(&mut self) -> Result<u64> {
+        let nonce = self.counter;
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| VpnError::Crypto("Session nonces exhausted".into()))?;
+        Ok(nonce)
+    }
+
+    /// The key, shared, for packets whose nonces were reserved here
+    pub fn key(&self) -> DataKey {
+        self.key.clone()
+    }
+
+    /// Encrypt the next packet, authenticating `aad` along with it
+    pub fn seal(&mut self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.reserve()?;
+        self.key.seal_at(nonce, aad, data)
+    }
+
+    /// Decrypt the next packet, which must have been sealed with the same `aad`
+    pub fn open(&mut self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.reserve()?;
+        self.key.open_at(nonce, aad, data)
+    }
+}
+
+/// Key of a [`DataCipher`], sealing and opening at nonces reserved from it
+///
+/// Each nonce must be used once: reserve it from the cipher first.
+#[derive(Clone)]
+pub struct DataKey(Arc<aead::LessSafeKey>);
+
+impl DataKey {
+    fn nonce(counter: u64) -> aead::Nonce {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        aead::Nonce::assume_unique_for_key(nonce)
+    }
+
+    /// Encrypt the packet with reserved `nonce`, authenticating `aad` along with it
+    pub fn seal_at(&self, nonce: u64, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let mut in_out = data.to_vec();
+        self.0
+            .seal_in_place_append_tag(Self::nonce(nonce), aead::Aad::from(aad), &mut in_out)
+            .map_err(|_| VpnError::Crypto("Packet encryption failed".into()))?;
+        Ok(in_out)
+    }
+
+    /// Decrypt the packet with reserved `nonce`, which must have been sealed with the same `aad`
+    pub fn open_at(&self, nonce: u64, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let mut in_out = data.to_vec();
+        let len = self
+            .0
+            .open_in_place(Self::nonce(nonce), aead::Aad::from(aad), &mut in_out)
+            .map_err(|_| VpnError::Crypto("Packet failed authentication".into()))?
+            .len();
+        in_out.truncate(len);
+        Ok(in_out)
+    }
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataKey").finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Debug for DataCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataCipher").field("counter", &self.counter).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `a` and `b` seal and open each other's packets in both directions
+    fn same_keys(a: &SessionKeys, b: &SessionKeys) -> bool {
+        let (mut a_out, mut a_in) = a.client_ciphers().unwrap();
+        let (mut b_out, mut b_in) = b.server_ciphers().unwrap();
+        let outbound = a_out.seal(b"hdr", b"ping").unwrap();
+        let inbound = b_out.seal(b"hdr", b"pong").unwrap();
+        b_in.open(b"hdr", &outbound).is_ok() && a_in.open(b"hdr", &inbound).is_ok()
+    }
+
+    #[test]
+    fn test_session_ciphers_pair_up() {
+        let keys = SessionKeys::derive(b"server random", &[b"password hash", b""]).unwrap();
+        let (mut client_out, mut client_in) = keys.client_ciphers().unwrap();
+        let (mut server_out, mut server_in) = keys.server_ciphers().unwrap();
+
+        let first = client_out.seal(b"hdr", b"ping").unwrap();
+        let second = client_out.seal(b"hdr", b"ping").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(server_in.open(b"hdr", &first).unwrap(), b"ping");
+        assert_eq!(server_in.open(b"hdr", &second).unwrap(), b"ping");
+
+        // Directions use different keys
+        let reply = server_out.seal(b"hdr", b"pong").unwrap();
+        assert!(server_in.open(b"hdr", &reply).is_err());
+        assert_eq!(client_in.open(b"hdr", &reply).unwrap(), b"pong");
+
+        // Replays, tampered headers and other secrets are all rejected
+        assert!(server_in.open(b"hdr", &first).is_err());
+        let (mut sealer, _) = keys.client_ciphers().unwrap();
+        let (_, mut opener) = keys.server_ciphers().unwrap();
+        let sealed = sealer.seal(b"hdr", b"data").unwrap();
+        assert!(opener.open(b"HDR", &sealed).is_err());
+        let other = SessionKeys::derive(b"server random", &[b"other hash"]).unwrap();
+        assert!(same_keys(&keys, &keys));
+        assert!(!same_keys(&other, &keys));
+
+        // Bonded connections get distinct keys, the first keeps the session's
+        assert!(same_keys(&keys.for_connection(0).unwrap(), &keys));
+        let second = keys.for_connection(1).unwrap();
+        assert!(!same_keys(&second, &keys));
+        assert!(!same_keys(&second, &keys.for_connection(2).unwrap()));
+        assert!(same_keys(&second, &keys.for_connection(1).unwrap()));
+
+        assert!(SessionKeys::derive(b"", &[b"secret"]).is_err());
+        assert!(SessionKeys::derive(b"random", &[b""]).is_err());
+    }
+
+    #[test]
+    fn test_reserved_nonces() {
+        let keys = SessionKeys::derive(b"server random", &[b"password hash"]).unwrap();
+        let (mut sealer, _) = keys.client_ciphers().unwrap();
+        let (_, mut opener) = keys.server_ciphers().unwrap();
+
+        // Sealed out of order, opened in stream order
+        let (first, second) = (sealer.reserve().unwrap(), sealer.reserve().unwrap());
+        let key = sealer.key();
+        let later = key.seal_at(second, b"hdr", b"second").unwrap();
+        let earlier = key.seal_at(first, b"hdr", b"first").unwrap();
+        assert_eq!(opener.open(b"hdr", &earlier).unwrap(), b"first");
+        assert_eq!(opener.open(b"hdr", &later).unwrap(), b"second");
+
+        // The cipher carries on after the reserved nonces
+        let third = sealer.seal(b"hdr", b"third").unwrap();
+        let nonce = opener.reserve().unwrap();
+        assert_eq!(opener.key().open_at(nonce, b"hdr", &third).unwrap(), b"third");
+        assert!(opener.key().open_at(nonce, b"hdr", &earlier).is_err());
+    }
+}
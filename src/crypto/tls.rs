@@ -1,11 +1,35 @@
 //! TLS/SSL handling for secure connections
-
-use crate::error::Result;
-use rustls::pki_types::ServerName;
-use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+//!
+//! [`CertificatePolicy`] decides which server certificates are accepted:
+//! chain verification per `server.verify_certificate`, extra CAs and
+//! fingerprint pins from `[tls]`. Every HTTP client that talks to the server
+//! takes its TLS configuration from it.
+//!
+//! The TLS stack itself is rustls unless the application supplies a
+//! [`TlsProvider`]: its own library, the platform's ([`NativeTlsProvider`]
+//! with the `native-tls` feature) or one implemented over FFI callbacks.
+//! HTTP requests then reach the server through a [`TlsRelay`].
+
+use crate::config::Config;
+use crate::error::{Result, VpnError};
+#[cfg(feature = "native")]
+use crate::protocol::proxy::UpstreamProxy;
+#[cfg(feature = "native")]
+use futures::future::BoxFuture;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, SignatureScheme, StreamOwned};
 use std::io::{Read, Write};
+#[cfg(feature = "native")]
+use std::net::{Ipv4Addr, SocketAddr};
 use std::net::TcpStream;
 use std::sync::Arc;
+#[cfg(feature = "native")]
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(feature = "native")]
+use tokio::task::JoinHandle;
 
 /// Custom certificate verifier that accepts all certificates (for VPN Gate testing)
 #[derive(Debug)]
@@ -61,64 +85,435 @@ impl rustls::client::danger::ServerCertVerifier for AcceptAllVerifier {
     }
 }
 
-/// TLS configuration for VPN connections
-pub struct TlsConfig {
-    client_config: Arc<ClientConfig>,
+/// SHA-256 fingerprint of a DER certificate
+pub type Fingerprint = [u8; 32];
+
+/// Which server certificates a connection accepts
+#[derive(Debug, Clone)]
+pub struct CertificatePolicy {
+    /// Check the chain against the built-in roots and `extra_roots`
+    verify_chain: bool,
+    extra_roots: Vec<CertificateDer<'static>>,
+    /// Accepted leaf certificates; empty accepts any that passes the rest
+    pins: Vec<Fingerprint>,
+    /// Name the certificate must carry, instead of the address connected to
+    server_name: Option<String>,
 }
 
-impl TlsConfig {
-    /// Create a new TLS configuration
-    pub fn new(verify_certificate: bool) -> Result<Self> {
-        // Install crypto provider based on feature flags
-        // Prioritize ring if both features are enabled (for CI --all-features)
-        #[cfg(all(feature = "ring-crypto", not(feature = "aws-lc-crypto")))]
-        {
-            rustls::crypto::ring::default_provider()
-                .install_default()
-                .map_err(|_| {
-                    crate::error::VpnError::Network("Failed to install ring crypto provider".into())
-                })?;
-        }
+impl CertificatePolicy {
+    /// Verify the chain against the built-in roots, or accept any certificate
+    pub fn new(verify_certificate: bool) -> Self {
+        Self { verify_chain: verify_certificate, extra_roots: Vec::new(), pins: Vec::new(), server_name: None }
+    }
 
-        #[cfg(all(feature = "aws-lc-crypto", not(feature = "ring-crypto")))]
-        {
-            rustls::crypto::aws_lc_rs::default_provider()
-                .install_default()
-                .map_err(|_| {
-                    crate::error::VpnError::Network(
-                        "Failed to install aws-lc-rs crypto provider".into(),
-                    )
-                })?;
+    /// Policy for the server in `config`
+    ///
+    /// CA bundles are read here, so a missing file fails before connecting.
+    /// The certificate name is checked against `server.hostname` when set,
+    /// since the server is reached by address.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let mut policy = Self::new(config.server.verify_certificate);
+        policy.server_name = config.server.hostname.clone();
+        for path in config.auth.ca_cert.iter().chain(&config.tls.ca_file) {
+            let pem = std::fs::read(path)
+                .map_err(|e| VpnError::Config(format!("Cannot read CA file '{path}': {e}")))?;
+            policy.extra_roots.extend(parse_certificates(&pem)?);
         }
+        if let Some(ref pem) = config.tls.ca_pem {
+            policy.extra_roots.extend(parse_certificates(pem.as_bytes())?);
+        }
+        policy.pins = config.tls.pinned_certificates.iter().map(|pin| parse_fingerprint(pin)).collect::<Result<_>>()?;
+        Ok(policy)
+    }
+
+    /// Accept only certificates with one of these fingerprints
+    pub fn with_pins(mut self, pins: Vec<Fingerprint>) -> Self {
+        self.pins = pins;
+        self
+    }
 
-        // If both features are enabled, prefer ring (for CI --all-features)
-        #[cfg(all(feature = "ring-crypto", feature = "aws-lc-crypto"))]
-        {
-            rustls::crypto::ring::default_provider()
-                .install_default()
-                .map_err(|_| {
-                    crate::error::VpnError::Network("Failed to install ring crypto provider".into())
-                })?;
+    /// Trust the CA certificates in `pem` besides the built-in roots
+    pub fn with_ca_pem(mut self, pem: &[u8]) -> Result<Self> {
+        self.extra_roots.extend(parse_certificates(pem)?);
+        Ok(self)
+    }
+
+    /// Refuse `certificate` (DER) unless it matches a pin, when there are any
+    pub fn check_pins(&self, certificate: Option<&[u8]>) -> Result<()> {
+        if self.pins.is_empty() {
+            return Ok(());
         }
+        match certificate.map(certificate_fingerprint) {
+            Some(fingerprint) if self.pins.contains(&fingerprint) => Ok(()),
+            Some(fingerprint) => {
+                Err(VpnError::Network(format!("Server certificate {} is not pinned", hex::encode(fingerprint))))
+            }
+            None => Err(VpnError::Network("TLS provider did not expose the pinned server certificate".into())),
+        }
+    }
 
-        let client_config = if verify_certificate {
-            // Use standard certificate verification
-            let mut root_store = RootCertStore::empty();
-            root_store.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    /// Whether every certificate is accepted
+    pub fn accepts_any(&self) -> bool {
+        !self.verify_chain && self.pins.is_empty()
+    }
+
+    /// rustls configuration enforcing the policy
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        install_crypto_provider()?;
+        let verifier: Arc<dyn ServerCertVerifier> = if self.accepts_any() {
+            // For VPN Gate testing and self-signed servers nobody pinned
+            Arc::new(AcceptAllVerifier)
+        } else {
+            Arc::new(self.verifier()?)
+        };
+        Ok(ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth())
+    }
+
+    /// Make the HTTP client built by `builder` enforce the policy
+    #[cfg(feature = "native")]
+    pub fn apply_to(&self, builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder> {
+        Ok(builder.use_preconfigured_tls(self.client_config()?))
+    }
 
-            ClientConfig::builder()
-                .with_root_certificates(root_store)
-                .with_no_client_auth()
+    fn verifier(&self) -> Result<PolicyVerifier> {
+        let provider = rustls::crypto::CryptoProvider::get_default()
+            .cloned()
+            .ok_or_else(|| VpnError::Network("No rustls crypto provider installed".into()))?;
+        let chain = if self.verify_chain {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            for root in &self.extra_roots {
+                roots
+                    .add(root.clone())
+                    .map_err(|e| VpnError::Config(format!("Invalid CA certificate: {e}")))?;
+            }
+            let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .map_err(|e| VpnError::Config(format!("Cannot verify certificates: {e}")))?;
+            Some(verifier)
         } else {
-            // Use custom verifier that accepts all certificates (for VPN Gate testing)
-            ClientConfig::builder()
-                .dangerous()
-                .with_custom_certificate_verifier(Arc::new(AcceptAllVerifier))
-                .with_no_client_auth()
+            None
+        };
+        let server_name = match self.server_name {
+            Some(ref name) => Some(
+                ServerName::try_from(name.clone())
+                    .map_err(|e| VpnError::Config(format!("Invalid server hostname '{name}': {e}")))?,
+            ),
+            None => None,
         };
+        Ok(PolicyVerifier {
+            chain,
+            pins: self.pins.clone(),
+            server_name,
+            algorithms: provider.signature_verification_algorithms,
+        })
+    }
+}
+
+/// Parse a fingerprint written as hex, with or without colons
+pub fn parse_fingerprint(text: &str) -> Result<Fingerprint> {
+    let digits: String = text.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect();
+    hex::decode(&digits)
+        .ok()
+        .and_then(|bytes| Fingerprint::try_from(bytes).ok())
+        .ok_or_else(|| VpnError::Config(format!("Invalid SHA-256 certificate fingerprint '{text}'")))
+}
+
+/// Fingerprint of a DER certificate, as pinned in `tls.pinned_certificates`
+pub fn certificate_fingerprint(certificate: &[u8]) -> Fingerprint {
+    super::sha256(certificate)
+}
+
+fn parse_certificates(pem: &[u8]) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| VpnError::Config(format!("Invalid CA certificate: {e}")))?;
+    if certificates.is_empty() {
+        return Err(VpnError::Config("No certificate found in CA bundle".into()));
+    }
+    Ok(certificates)
+}
+
+/// Verifier for every policy but accepting anything
+#[derive(Debug)]
+struct PolicyVerifier {
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<Fingerprint>,
+    server_name: Option<ServerName<'static>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PolicyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = certificate_fingerprint(end_entity);
+        if !self.pins.is_empty() && !self.pins.contains(&fingerprint) {
+            return Err(rustls::Error::General(format!(
+                "server certificate {} is not pinned",
+                hex::encode(fingerprint)
+            )));
+        }
+        match self.chain {
+            Some(ref chain) => {
+                let name = self.server_name.as_ref().unwrap_or(server_name);
+                chain.verify_server_cert(end_entity, intermediates, name, ocsp_response, now)
+            }
+            None => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// TLS session to the server as a [`TlsProvider`] hands it back
+///
+/// Application data goes through `AsyncRead` and `AsyncWrite`.
+#[cfg(feature = "native")]
+pub trait TlsStream: AsyncRead + AsyncWrite + Send + Unpin {
+    /// DER certificate the server presented, if the provider exposes it
+    fn peer_certificate(&self) -> Option<Vec<u8>>;
+}
+
+/// TLS stack used for the connections to the server
+#[cfg(feature = "native")]
+pub trait TlsProvider: Send + Sync {
+    /// Name for logs
+    fn name(&self) -> &str;
+
+    /// Run the handshake with `server_name` over `stream`
+    ///
+    /// Chain verification and extra CAs follow `certificates`. Pins are
+    /// checked by the caller against [`TlsStream::peer_certificate`].
+    fn connect<'a>(
+        &'a self,
+        stream: tokio::net::TcpStream,
+        server_name: &'a str,
+        certificates: &'a CertificatePolicy,
+    ) -> BoxFuture<'a, Result<Box<dyn TlsStream>>>;
+}
+
+/// TLS through rustls, like the built-in HTTP client
+#[cfg(feature = "native")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RustlsProvider;
+
+#[cfg(feature = "native")]
+impl TlsProvider for RustlsProvider {
+    fn name(&self) -> &str {
+        "rustls"
+    }
+
+    fn connect<'a>(
+        &'a self,
+        stream: tokio::net::TcpStream,
+        server_name: &'a str,
+        certificates: &'a CertificatePolicy,
+    ) -> BoxFuture<'a, Result<Box<dyn TlsStream>>> {
+        Box::pin(async move {
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(certificates.client_config()?));
+            let name = ServerName::try_from(server_name.to_string())
+                .map_err(|e| VpnError::Config(format!("Invalid server name '{server_name}': {e}")))?;
+            let stream = connector
+                .connect(name, stream)
+                .await
+                .map_err(|e| VpnError::Network(format!("TLS handshake failed: {e}")))?;
+            Ok(Box::new(stream) as Box<dyn TlsStream>)
+        })
+    }
+}
 
+#[cfg(feature = "native")]
+impl TlsStream for tokio_rustls::client::TlsStream<tokio::net::TcpStream> {
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.get_ref().1.peer_certificates()?.first().map(|certificate| certificate.to_vec())
+    }
+}
+
+/// TLS through the platform library: OpenSSL, Secure Transport or SChannel
+///
+/// Trusts the system's roots rather than the built-in ones.
+#[cfg(feature = "native-tls")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NativeTlsProvider;
+
+#[cfg(feature = "native-tls")]
+impl TlsProvider for NativeTlsProvider {
+    fn name(&self) -> &str {
+        "native-tls"
+    }
+
+    fn connect<'a>(
+        &'a self,
+        stream: tokio::net::TcpStream,
+        server_name: &'a str,
+        certificates: &'a CertificatePolicy,
+    ) -> BoxFuture<'a, Result<Box<dyn TlsStream>>> {
+        Box::pin(async move {
+            let mut builder = native_tls::TlsConnector::builder();
+            builder.danger_accept_invalid_certs(!certificates.verify_chain);
+            for root in &certificates.extra_roots {
+                let root = native_tls::Certificate::from_der(root)
+                    .map_err(|e| VpnError::Config(format!("Invalid CA certificate: {e}")))?;
+                builder.add_root_certificate(root);
+            }
+            let connector = builder
+                .build()
+                .map_err(|e| VpnError::Network(format!("Cannot set up native TLS: {e}")))?;
+            let name = certificates.server_name.as_deref().unwrap_or(server_name);
+            let stream = tokio_native_tls::TlsConnector::from(connector)
+                .connect(name, stream)
+                .await
+                .map_err(|e| VpnError::Network(format!("TLS handshake failed: {e}")))?;
+            Ok(Box::new(stream) as Box<dyn TlsStream>)
+        })
+    }
+}
+
+#[cfg(feature = "native-tls")]
+impl TlsStream for tokio_native_tls::TlsStream<tokio::net::TcpStream> {
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.get_ref().peer_certificate().ok()??.to_der().ok()
+    }
+}
+
+/// Where a [`TlsRelay`] sends its connections
+#[cfg(feature = "native")]
+pub struct TlsTarget {
+    /// Server address
+    pub server: SocketAddr,
+    /// Name for SNI and certificate checks
+    pub server_name: String,
+    pub certificates: CertificatePolicy,
+    /// Proxy the TCP connections go through
+    pub proxy: Option<UpstreamProxy>,
+    /// Firewall mark of direct connections, under policy routing
+    pub socket_mark: Option<u32>,
+}
+
+/// Plain HTTP endpoint on loopback whose connections reach the server over a [`TlsProvider`]
+///
+/// The HTTP client can only speak TLS through rustls; pointed at
+/// [`url`](Self::url) instead of the server, its requests leave through the
+/// provider, one TLS session per connection. Stops accepting connections
+/// when dropped.
+#[cfg(feature = "native")]
+pub struct TlsRelay {
+    addr: SocketAddr,
+    accept_task: JoinHandle<()>,
+}
+
+#[cfg(feature = "native")]
+impl TlsRelay {
+    /// Listen on an ephemeral loopback port
+    pub async fn start(provider: Arc<dyn TlsProvider>, target: TlsTarget) -> Result<Self> {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let target = Arc::new(target);
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (provider, target) = (provider.clone(), target.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = relay(stream, provider.as_ref(), &target).await {
+                        log::warn!("TLS relay to {} over {} failed: {}", target.server, provider.name(), e);
+                    }
+                });
+            }
+        });
+        Ok(Self { addr, accept_task })
+    }
+
+    /// Base URL for the HTTP client, replacing `https://server`
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+}
+
+#[cfg(feature = "native")]
+impl Drop for TlsRelay {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// Carry one HTTP client connection over a TLS session to the server
+#[cfg(feature = "native")]
+async fn relay(mut client: tokio::net::TcpStream, provider: &dyn TlsProvider, target: &TlsTarget) -> Result<()> {
+    let stream = match target.proxy {
+        Some(ref proxy) => proxy.connect(&target.server.to_string()).await?,
+        None => crate::underlay::connect_tcp(target.server, target.socket_mark).await?,
+    };
+    let mut tls = provider.connect(stream, &target.server_name, &target.certificates).await?;
+    target.certificates.check_pins(tls.peer_certificate().as_deref())?;
+    tokio::io::copy_bidirectional(&mut client, &mut tls).await?;
+    Ok(())
+}
+
+/// Install this build's rustls crypto provider as the process default
+///
+/// Does nothing if a provider is already installed, by an earlier call or by
+/// the host application, so it is safe to call before every handshake.
+pub fn install_crypto_provider() -> Result<()> {
+    if rustls::crypto::CryptoProvider::get_default().is_some() {
+        return Ok(());
+    }
+
+    // Prioritize ring if both features are enabled (for CI --all-features)
+    #[cfg(feature = "ring-crypto")]
+    let (provider, name) = (rustls::crypto::ring::default_provider(), "ring");
+    #[cfg(all(feature = "aws-lc-crypto", not(feature = "ring-crypto")))]
+    let (provider, name) = (rustls::crypto::aws_lc_rs::default_provider(), "aws-lc-rs");
+
+    // Losing a race against another thread installing one is fine too
+    if provider.install_default().is_err() && rustls::crypto::CryptoProvider::get_default().is_none() {
+        return Err(crate::error::VpnError::Network(format!("Failed to install {name} crypto provider")));
+    }
+    Ok(())
+}
+
+/// TLS configuration for VPN connections
+pub struct TlsConfig {
+    client_config: Arc<ClientConfig>,
+}
+
+impl TlsConfig {
+    /// Create a new TLS configuration
+    pub fn new(verify_certificate: bool) -> Result<Self> {
+        Self::with_policy(&CertificatePolicy::new(verify_certificate))
+    }
+
+    /// Create a TLS configuration accepting the certificates `policy` allows
+    pub fn with_policy(policy: &CertificatePolicy) -> Result<Self> {
         Ok(Self {
-            client_config: Arc::new(client_config),
+            client_config: Arc::new(policy.client_config()?),
         })
     }
 
@@ -228,3 +623,130 @@ impl TlsConnection {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT_PEM: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/cert_auth/client.crt"));
+    #[cfg(feature = "native")]
+    const KEY_PEM: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/cert_auth/client.key"));
+    const CERT_SHA256: &str = "CB:7F:C9:72:26:E0:3F:85:6B:35:7C:91:01:6F:B6:09:01:59:E6:71:5D:83:70:AB:21:B0:F8:DE:B9:8C:5C:25";
+
+    fn verify(policy: &CertificatePolicy) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        install_crypto_provider().unwrap();
+        let certificate = parse_certificates(CERT_PEM).unwrap().remove(0);
+        let name = ServerName::try_from("vpn.example.com").unwrap();
+        policy.verifier().unwrap().verify_server_cert(&certificate, &[], &name, &[], UnixTime::now())
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let pin = parse_fingerprint(CERT_SHA256).unwrap();
+        assert_eq!(parse_fingerprint(&CERT_SHA256.replace(':', "").to_lowercase()).unwrap(), pin);
+        let certificate = parse_certificates(CERT_PEM).unwrap().remove(0);
+        assert_eq!(certificate_fingerprint(&certificate), pin);
+
+        assert!(parse_fingerprint("cb:7f").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_pinned_certificate() {
+        let pin = parse_fingerprint(CERT_SHA256).unwrap();
+        let pinned = CertificatePolicy::new(false).with_pins(vec![pin]);
+        assert!(!pinned.accepts_any());
+        assert!(verify(&pinned).is_ok());
+
+        let other = CertificatePolicy::new(false).with_pins(vec![[0; 32]]);
+        assert!(verify(&other).unwrap_err().to_string().contains("not pinned"));
+
+        // Pinning does not skip the chain when it is verified: the test
+        // certificate is self-signed and not issued for the server name
+        let chained = CertificatePolicy::new(true).with_pins(vec![pin]);
+        assert!(verify(&chained).is_err());
+        assert!(CertificatePolicy::new(true).client_config().is_ok());
+    }
+
+    #[test]
+    fn test_policy_from_config() {
+        let mut config = Config::default_test();
+        assert!(CertificatePolicy::from_config(&config).unwrap().accepts_any());
+
+        config.tls.pinned_certificates = vec![CERT_SHA256.to_string()];
+        config.tls.ca_pem = Some(String::from_utf8(CERT_PEM.to_vec()).unwrap());
+        let policy = CertificatePolicy::from_config(&config).unwrap();
+        assert_eq!((policy.pins.len(), policy.extra_roots.len()), (1, 1));
+
+        config.tls.ca_pem = Some("not a certificate".to_string());
+        assert!(CertificatePolicy::from_config(&config).is_err());
+        config.tls.ca_pem = None;
+        config.tls.ca_file = Some("/nonexistent/ca.pem".to_string());
+        assert!(CertificatePolicy::from_config(&config).is_err());
+    }
+
+    /// TLS server presenting the fixture certificate and echoing what it reads
+    #[cfg(feature = "native")]
+    async fn echo_server() -> SocketAddr {
+        install_crypto_provider().unwrap();
+        let key = rustls_pemfile::private_key(&mut &KEY_PEM[..]).unwrap().unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(parse_certificates(CERT_PEM).unwrap(), key)
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        let (mut reader, mut writer) = tokio::io::split(stream);
+                        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    /// Send `ping` through `relay` and return what comes back
+    #[cfg(feature = "native")]
+    async fn ping(relay: &TlsRelay) -> Vec<u8> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let addr = relay.url().trim_start_matches("http://").parse::<SocketAddr>().unwrap();
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut answer = Vec::new();
+        let _ = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            let mut buffer = [0u8; 4];
+            if stream.read_exact(&mut buffer).await.is_ok() {
+                answer.extend_from_slice(&buffer);
+            }
+        })
+        .await;
+        answer
+    }
+
+    #[cfg(feature = "native")]
+    #[tokio::test]
+    async fn test_relay_over_rustls_provider() {
+        let server = echo_server().await;
+        let target = |pin| TlsTarget {
+            server,
+            server_name: "vpn.example.com".to_string(),
+            certificates: CertificatePolicy::new(false).with_pins(vec![pin]),
+            proxy: None,
+            socket_mark: None,
+        };
+
+        let pinned = parse_fingerprint(CERT_SHA256).unwrap();
+        let relay = TlsRelay::start(Arc::new(RustlsProvider), target(pinned)).await.unwrap();
+        assert_eq!(ping(&relay).await, b"ping");
+
+        // Another certificate is expected: the connection is dropped before any data
+        let relay = TlsRelay::start(Arc::new(RustlsProvider), target([0; 32])).await.unwrap();
+        assert!(ping(&relay).await.is_empty());
+    }
+}
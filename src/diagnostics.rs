@@ -0,0 +1,518 @@
+//! Diagnostics Module
+//!
+//! Post-connect checks that tell the user whether the tunnel is actually
+//! usable. Everything in here is native async code bounded by an explicit
+//! deadline - no external `host`/`dig`/`ping` binaries - so a broken network
+//! produces a failed probe instead of a stalled connect path.
+
+use crate::error::{Result, VpnError};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// Default deadline for a single DNS probe
+pub const DEFAULT_DNS_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Default name resolved by the DNS probes
+pub const DEFAULT_DNS_PROBE_NAME: &str = "google.com";
+
+/// Default deadline for the hub gateway ping
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// ICMP echo reply type
+const ICMP_ECHO_REPLY: u8 = 0;
+/// ICMP echo request type
+const ICMP_ECHO_REQUEST: u8 = 8;
+/// ICMP header length (type, code, checksum, identifier, sequence)
+const ICMP_HEADER_LEN: usize = 8;
+
+/// DNS record type A
+pub(crate) const DNS_TYPE_A: u16 = 1;
+/// DNS record type AAAA
+pub(crate) const DNS_TYPE_AAAA: u16 = 28;
+/// DNS class IN
+const DNS_CLASS_IN: u16 = 1;
+/// Maximum size of a plain UDP DNS response
+const DNS_MAX_UDP_RESPONSE: usize = 512;
+
+/// How a DNS probe was performed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsProbeMethod {
+    /// Lookup through the operating system resolver (honours resolv.conf / resolved)
+    SystemResolver,
+    /// Direct UDP query against a specific DNS server
+    DirectQuery,
+}
+
+/// Result of a single DNS probe
+#[derive(Debug, Clone)]
+pub struct DnsProbe {
+    pub method: DnsProbeMethod,
+    /// Server queried directly, `None` for the system resolver
+    pub server: Option<Ipv4Addr>,
+    pub addresses: Vec<IpAddr>,
+    pub elapsed: Duration,
+    pub error: Option<String>,
+}
+
+impl DnsProbe {
+    /// Whether the probe returned at least one address
+    pub fn is_success(&self) -> bool {
+        self.error.is_none() && !self.addresses.is_empty()
+    }
+}
+
+/// Aggregated DNS diagnostics report
+#[derive(Debug, Clone)]
+pub struct DnsDiagnostics {
+    pub query_name: String,
+    pub probes: Vec<DnsProbe>,
+    /// Whether `/etc/nsswitch.conf` lists `dns` for hosts (Linux only)
+    pub nsswitch_has_dns: Option<bool>,
+}
+
+impl DnsDiagnostics {
+    /// DNS is considered working if any probe succeeded
+    pub fn is_healthy(&self) -> bool {
+        self.probes.iter().any(DnsProbe::is_success)
+    }
+
+    /// Log a human readable summary of the report
+    pub fn log_summary(&self) {
+        log::info!("DNS diagnostics for {}", self.query_name);
+        for probe in &self.probes {
+            let target = match probe.server {
+                Some(server) => format!("server {server}"),
+                None => "system resolver".to_string(),
+            };
+            if probe.is_success() {
+                log::info!("{} resolved in {:?}", target, probe.elapsed);
+            } else {
+                log::warn!(
+                    "{} failed after {:?}: {}",
+                    target,
+                    probe.elapsed,
+                    probe.error.as_deref().unwrap_or("no addresses returned")
+                );
+            }
+        }
+        if self.nsswitch_has_dns == Some(false) {
+            log::warn!("'dns' not found in the /etc/nsswitch.conf hosts line; add it for proper DNS resolution");
+        }
+        if self.is_healthy() {
+            log::info!("DNS resolution working through at least one method");
+        } else {
+            log::warn!("DNS resolution failed with all methods");
+        }
+    }
+}
+
+/// Run all DNS probes concurrently, each bounded by `deadline`
+///
+/// The system resolver is probed once and every server in `dns_servers` is
+/// queried directly, so the total wall time is roughly one `deadline`.
+pub async fn run_dns_diagnostics(
+    dns_servers: &[Ipv4Addr],
+    query_name: &str,
+    deadline: Duration,
+) -> DnsDiagnostics {
+    let system_probe = async {
+        let start = Instant::now();
+        let result = resolve_with_system(query_name, deadline).await;
+        probe_from_result(DnsProbeMethod::SystemResolver, None, start, result)
+    };
+
+    let (system, direct) =
+        futures::future::join(system_probe, direct_probes(dns_servers, query_name, deadline)).await;
+
+    let mut probes = Vec::with_capacity(direct.len() + 1);
+    probes.push(system);
+    probes.extend(direct);
+
+    DnsDiagnostics {
+        query_name: query_name.to_string(),
+        probes,
+        nsswitch_has_dns: nsswitch_has_dns(),
+    }
+}
+
+/// Query each of `dns_servers` directly for `query_name`, bounded by `deadline`
+///
+/// The internal-only variant of [`run_dns_diagnostics`]: the system resolver
+/// is skipped because it may forward the query outside the tunnel, so use a
+/// name that only the VPN's DNS servers know.
+pub async fn run_internal_dns_diagnostics(
+    dns_servers: &[Ipv4Addr],
+    query_name: &str,
+    deadline: Duration,
+) -> DnsDiagnostics {
+    DnsDiagnostics {
+        query_name: query_name.to_string(),
+        probes: direct_probes(dns_servers, query_name, deadline).await,
+        nsswitch_has_dns: nsswitch_has_dns(),
+    }
+}
+
+async fn direct_probes(dns_servers: &[Ipv4Addr], query_name: &str, deadline: Duration) -> Vec<DnsProbe> {
+    futures::future::join_all(dns_servers.iter().map(|&server| async move {
+        let start = Instant::now();
+        let result = query_dns_server(server, query_name, deadline)
+            .await
+            .map(|addrs| addrs.into_iter().map(IpAddr::V4).collect());
+        probe_from_result(DnsProbeMethod::DirectQuery, Some(server), start, result)
+    }))
+    .await
+}
+
+fn probe_from_result(
+    method: DnsProbeMethod,
+    server: Option<Ipv4Addr>,
+    start: Instant,
+    result: Result<Vec<IpAddr>>,
+) -> DnsProbe {
+    let elapsed = start.elapsed();
+    match result {
+        Ok(addresses) => DnsProbe { method, server, addresses, elapsed, error: None },
+        Err(e) => DnsProbe { method, server, addresses: Vec::new(), elapsed, error: Some(e.to_string()) },
+    }
+}
+
+/// Resolve `name` through the system resolver within `deadline`
+pub async fn resolve_with_system(name: &str, deadline: Duration) -> Result<Vec<IpAddr>> {
+    let lookup = tokio::net::lookup_host((name, 0));
+    match tokio::time::timeout(deadline, lookup).await {
+        Ok(Ok(addrs)) => Ok(addrs.map(|addr| addr.ip()).collect()),
+        Ok(Err(e)) => Err(VpnError::Dns(format!("Failed to resolve {name}: {e}"))),
+        Err(_) => Err(VpnError::Timeout(format!(
+            "Resolving {name} took longer than {deadline:?}"
+        ))),
+    }
+}
+
+/// Send an A query for `name` straight to `server` and wait at most `deadline`
+pub async fn query_dns_server(
+    server: Ipv4Addr,
+    name: &str,
+    deadline: Duration,
+) -> Result<Vec<Ipv4Addr>> {
+    query_dns_server_from(SocketAddr::new(IpAddr::V4(server), 53), Ipv4Addr::UNSPECIFIED, name, deadline).await
+}
+
+/// Like [`query_dns_server`], sending the query from the local address `source`
+pub(crate) async fn query_dns_server_from(
+    server: SocketAddr,
+    source: Ipv4Addr,
+    name: &str,
+    deadline: Duration,
+) -> Result<Vec<Ipv4Addr>> {
+    let query_id: u16 = rand::random();
+    let query = build_dns_query(query_id, name, DNS_TYPE_A)?;
+
+    let exchange = async {
+        let socket = UdpSocket::bind((source, 0)).await?;
+        socket.connect(server).await?;
+        socket.send(&query).await?;
+
+        let mut buf = [0u8; DNS_MAX_UDP_RESPONSE];
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            // Ignore stray datagrams that do not belong to our query
+            if len >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == query_id {
+                return parse_dns_response(query_id, &buf[..len]).map(|answers| ipv4_only(&answers));
+            }
+        }
+    };
+
+    match tokio::time::timeout(deadline, exchange).await {
+        Ok(result) => result,
+        Err(_) => Err(VpnError::Timeout(format!(
+            "DNS server {} did not answer within {deadline:?}",
+            server.ip()
+        ))),
+    }
+}
+
+/// Encode a recursive query for the `qtype` records of `name`
+pub(crate) fn build_dns_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&0x0100u16.to_be_bytes()); // standard query, recursion desired
+    query.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    query.extend_from_slice(&[0, 0, 0, 0, 0, 0]); // ANCOUNT, NSCOUNT, ARCOUNT
+
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(VpnError::Dns(format!("Invalid DNS name: {name}")));
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Extract A and AAAA records from a DNS response
+pub(crate) fn parse_dns_response(id: u16, data: &[u8]) -> Result<Vec<IpAddr>> {
+    let truncated = || VpnError::Dns("Truncated DNS response".to_string());
+
+    if data.len() < 12 {
+        return Err(truncated());
+    }
+    if u16::from_be_bytes([data[0], data[1]]) != id {
+        return Err(VpnError::Dns("DNS response ID mismatch".to_string()));
+    }
+    let rcode = data[3] & 0x0f;
+    if rcode != 0 {
+        return Err(VpnError::Dns(format!("DNS server returned rcode {rcode}")));
+    }
+
+    let qdcount = u16::from_be_bytes([data[4], data[5]]);
+    let ancount = u16::from_be_bytes([data[6], data[7]]);
+    let mut offset = 12;
+
+    for _ in 0..qdcount {
+        offset = skip_dns_name(data, offset).ok_or_else(truncated)?;
+        offset = offset.checked_add(4).filter(|&o| o <= data.len()).ok_or_else(truncated)?;
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..ancount {
+        offset = skip_dns_name(data, offset).ok_or_else(truncated)?;
+        let header = data.get(offset..offset + 10).ok_or_else(truncated)?;
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        offset += 10;
+        let rdata = data.get(offset..offset + rdlength).ok_or_else(truncated)?;
+        match (rtype, <[u8; 4]>::try_from(rdata), <[u8; 16]>::try_from(rdata)) {
+            (DNS_TYPE_A, Ok(v4), _) => addresses.push(IpAddr::from(v4)),
+            (DNS_TYPE_AAAA, _, Ok(v6)) => addresses.push(IpAddr::from(v6)),
+            _ => {}
+        }
+        offset += rdlength;
+    }
+
+    Ok(addresses)
+}
+
+fn ipv4_only(addresses: &[IpAddr]) -> Vec<Ipv4Addr> {
+    addresses
+        .iter()
+        .filter_map(|addr| match addr {
+            IpAddr::V4(v4) => Some(*v4),
+            IpAddr::V6(_) => None,
+        })
+        .collect()
+}
+
+/// Skip over a (possibly compressed) DNS name, returning the offset after it
+fn skip_dns_name(data: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            l if l & 0xc0 == 0xc0 => {
+                data.get(offset + 1)?;
+                return Some(offset + 2);
+            }
+            l => offset += 1 + l as usize,
+        }
+    }
+}
+
+/// Ping the hub's virtual gateway and return the round-trip time
+///
+/// This is SoftEther's "check connection": the echo never leaves the VPN, so
+/// it works on hubs without internet egress and tells no third party that a
+/// tunnel came up. Uses an unprivileged ICMP socket where the kernel allows
+/// it and a raw socket otherwise.
+pub async fn ping_gateway(gateway: Ipv4Addr, deadline: Duration) -> Result<Duration> {
+    #[cfg(unix)]
+    {
+        let socket = open_icmp_socket()?;
+        socket.connect(SocketAddr::new(IpAddr::V4(gateway), 0)).await?;
+
+        let sequence: u16 = rand::random();
+        let token: [u8; 8] = rand::random();
+        let request = build_echo_request(rand::random(), sequence, &token);
+
+        let start = Instant::now();
+        let exchange = async {
+            socket.send(&request).await?;
+            let mut buf = [0u8; 1500];
+            loop {
+                let len = socket.recv(&mut buf).await?;
+                if is_echo_reply(&buf[..len], sequence, &token) {
+                    return Ok(start.elapsed());
+                }
+            }
+        };
+
+        match tokio::time::timeout(deadline, exchange).await {
+            Ok(result) => result,
+            Err(_) => Err(VpnError::Timeout(format!(
+                "Gateway {gateway} did not answer within {deadline:?}"
+            ))),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (gateway, deadline);
+        Err(VpnError::Platform(
+            "Gateway ping is not supported on this platform".to_string(),
+        ))
+    }
+}
+
+/// Open an ICMP socket, preferring the unprivileged datagram kind
+#[cfg(unix)]
+fn open_icmp_socket() -> Result<UdpSocket> {
+    use std::os::unix::io::FromRawFd;
+
+    for kind in [libc::SOCK_DGRAM, libc::SOCK_RAW] {
+        // SAFETY: plain socket(2) call; ownership of a valid fd moves into the std socket
+        let fd = unsafe { libc::socket(libc::AF_INET, kind, libc::IPPROTO_ICMP) };
+        if fd >= 0 {
+            let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+            socket.set_nonblocking(true)?;
+            return Ok(UdpSocket::from_std(socket)?);
+        }
+    }
+
+    Err(VpnError::Permission(
+        "Cannot open an ICMP socket (requires root or net.ipv4.ping_group_range)".to_string(),
+    ))
+}
+
+/// Encode an ICMP echo request carrying `payload`
+fn build_echo_request(identifier: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(ICMP_HEADER_LEN + payload.len());
+    packet.extend_from_slice(&[ICMP_ECHO_REQUEST, 0, 0, 0]);
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&sequence.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    let checksum = internet_checksum(&packet);
+    packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    packet
+}
+
+/// Whether `data` is the echo reply to our request
+///
+/// The identifier is not compared: unprivileged Linux sockets rewrite it.
+/// Raw sockets (and datagram sockets on macOS) deliver the IPv4 header too.
+fn is_echo_reply(data: &[u8], sequence: u16, payload: &[u8]) -> bool {
+    let icmp = match data.first() {
+        Some(&b) if b >> 4 == 4 => data.get(usize::from(b & 0x0f) * 4..).unwrap_or_default(),
+        _ => data,
+    };
+
+    icmp.len() >= ICMP_HEADER_LEN
+        && icmp[0] == ICMP_ECHO_REPLY
+        && icmp[6..8] == sequence.to_be_bytes()
+        && &icmp[ICMP_HEADER_LEN..] == payload
+}
+
+/// RFC 1071 ones' complement checksum
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Check whether the hosts line in nsswitch.conf includes DNS
+fn nsswitch_has_dns() -> Option<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        let content = std::fs::read_to_string("/etc/nsswitch.conf").ok()?;
+        content
+            .lines()
+            .find(|line| line.trim_start().starts_with("hosts:"))
+            .map(|line| line.contains("dns"))
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_dns_query() {
+        let query = build_dns_query(0x1234, "example.com", DNS_TYPE_A).unwrap();
+        assert_eq!(&query[..2], &[0x12, 0x34]);
+        assert_eq!(&query[12..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+        assert!(build_dns_query(1, "bad..name", DNS_TYPE_A).is_err());
+    }
+
+    #[test]
+    fn test_parse_dns_response() {
+        let mut response = build_dns_query(0xbeef, "example.com", DNS_TYPE_A).unwrap();
+        response[2] = 0x81; // QR + RD
+        response[3] = 0x80; // RA, rcode 0
+        response[7] = 1; // ANCOUNT = 1
+        // Answer: pointer to question name, type A, class IN, TTL, 4 byte address
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 93, 184, 216, 34]);
+
+        let addrs = parse_dns_response(0xbeef, &response).unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([93, 184, 216, 34])]);
+
+        // AAAA answers come out as IPv6 addresses
+        response[7] = 2;
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 28, 0, 1, 0, 0, 0, 60, 0, 16]);
+        response.extend_from_slice(&[0x26, 0x06, 0x28, 0, 0x02, 0x20, 0, 1, 0x02, 0x48, 0x18, 0x93, 0x25, 0xc8, 0x19, 0x46]);
+        let addrs = parse_dns_response(0xbeef, &response).unwrap();
+        assert_eq!(addrs[1], "2606:2800:220:1:248:1893:25c8:1946".parse::<IpAddr>().unwrap());
+
+        assert!(parse_dns_response(0xdead, &response).is_err());
+        assert!(parse_dns_response(0xbeef, &response[..response.len() - 2]).is_err());
+    }
+
+    #[test]
+    fn test_echo_request_and_reply() {
+        let token = *b"rvpnse!!";
+        let request = build_echo_request(0x4242, 7, &token);
+        assert_eq!(request[0], ICMP_ECHO_REQUEST);
+        assert_eq!(internet_checksum(&request), 0);
+
+        let mut reply = request.clone();
+        reply[0] = ICMP_ECHO_REPLY;
+        assert!(is_echo_reply(&reply, 7, &token));
+        assert!(!is_echo_reply(&reply, 8, &token));
+        assert!(!is_echo_reply(&request, 7, &token));
+
+        // Same reply behind a 20 byte IPv4 header, as raw sockets deliver it
+        let mut with_header = vec![0x45; 1];
+        with_header.extend_from_slice(&[0; 19]);
+        with_header.extend_from_slice(&reply);
+        assert!(is_echo_reply(&with_header, 7, &token));
+    }
+
+    #[tokio::test]
+    async fn test_probe_respects_deadline() {
+        // TEST-NET-1 is never routed, so the probe must give up on its own
+        let deadline = Duration::from_millis(200);
+        let start = Instant::now();
+        let result = query_dns_server(Ipv4Addr::new(192, 0, 2, 1), "example.com", deadline).await;
+        assert!(result.is_err());
+        assert!(start.elapsed() < deadline + Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_internal_diagnostics_skip_system_resolver() {
+        let servers = [Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)];
+        let report = run_internal_dns_diagnostics(&servers, "intranet.corp", Duration::from_millis(100)).await;
+        assert_eq!(report.probes.len(), 2);
+        assert!(report.probes.iter().all(|p| p.method == DnsProbeMethod::DirectQuery));
+        assert!(!report.is_healthy());
+    }
+}
@@ -33,6 +33,14 @@ pub enum VpnError {
     #[error("Protocol error: {0}")]
     Protocol(String),
 
+    /// The server ended the session, with SoftEther error `code`
+    #[error("Disconnected by server (error {code}): {message}")]
+    ServerDisconnected { code: u32, message: String },
+
+    /// The server refused the login with SoftEther error `code`
+    #[error("Refused by server (error {code}): {message}")]
+    ServerRefused { code: u32, message: String },
+
     /// Cryptographic errors
     #[error("Cryptographic error: {0}")]
     Crypto(String),
@@ -53,10 +61,18 @@ pub enum VpnError {
     #[error("DNS error: {0}")]
     Dns(String),
 
+    /// Proxy discovery and PAC evaluation errors
+    #[error("Proxy error: {0}")]
+    Proxy(String),
+
     /// Permission/privilege errors
     #[error("Permission error: {0}")]
     Permission(String),
 
+    /// A feature cannot be provided under the active constraints
+    #[error("Capability unavailable: {0}")]
+    CapabilityUnavailable(String),
+
     /// Connection limit errors
     #[error("Connection limit reached: {0}")]
     ConnectionLimitReached(String),
@@ -81,6 +97,10 @@ pub enum VpnError {
     #[error("Operation timed out: {0}")]
     Timeout(String),
 
+    /// The user or application declined to continue
+    #[error("Cancelled: {0}")]
+    Cancelled(String),
+
     /// Invalid state errors
     #[error("Invalid state: {0}")]
     InvalidState(String),
@@ -90,6 +110,193 @@ pub enum VpnError {
     Other(String),
 }
 
+/// Stable, machine-readable kind of a [`VpnError`]
+///
+/// The numbers are part of the C API: applications branch on them, so a
+/// value is never reused or renumbered. New kinds get new numbers.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "bindings", derive(uniffi::Enum))]
+pub enum ErrorCode {
+    Ok = 0,
+    InvalidConfig = 1,
+    InvalidParameter = 2,
+
+    Network = 10,
+    ConnectionFailed = 11,
+    Timeout = 12,
+    Tls = 13,
+    Proxy = 14,
+    Dns = 15,
+
+    AuthFailed = 20,
+    AccessDenied = 21,
+    PasswordExpired = 22,
+    AuthMethodNotSupported = 23,
+    LockedOut = 24,
+
+    HubNotFound = 30,
+    ServerFull = 31,
+    ProtocolMismatch = 32,
+    SessionRemoved = 33,
+    DisconnectedByServer = 34,
+    ServerError = 35,
+
+    /// Creating the TUN device or changing routes needs root, `CAP_NET_ADMIN` or an administrator
+    PermissionDenied = 40,
+    TunError = 41,
+    RoutingFailed = 42,
+    DnsConfigFailed = 43,
+
+    Cancelled = 50,
+    InvalidState = 51,
+    Unsupported = 52,
+
+    Internal = 99,
+}
+
+impl ErrorCode {
+    /// Every code, in numeric order
+    pub const ALL: [ErrorCode; 28] = [
+        ErrorCode::Ok,
+        ErrorCode::InvalidConfig,
+        ErrorCode::InvalidParameter,
+        ErrorCode::Network,
+        ErrorCode::ConnectionFailed,
+        ErrorCode::Timeout,
+        ErrorCode::Tls,
+        ErrorCode::Proxy,
+        ErrorCode::Dns,
+        ErrorCode::AuthFailed,
+        ErrorCode::AccessDenied,
+        ErrorCode::PasswordExpired,
+        ErrorCode::AuthMethodNotSupported,
+        ErrorCode::LockedOut,
+        ErrorCode::HubNotFound,
+        ErrorCode::ServerFull,
+        ErrorCode::ProtocolMismatch,
+        ErrorCode::SessionRemoved,
+        ErrorCode::DisconnectedByServer,
+        ErrorCode::ServerError,
+        ErrorCode::PermissionDenied,
+        ErrorCode::TunError,
+        ErrorCode::RoutingFailed,
+        ErrorCode::DnsConfigFailed,
+        ErrorCode::Cancelled,
+        ErrorCode::InvalidState,
+        ErrorCode::Unsupported,
+        ErrorCode::Internal,
+    ];
+
+    /// The code with numeric value `value`, if there is one
+    pub fn from_i32(value: i32) -> Option<Self> {
+        Self::ALL.into_iter().find(|code| *code as i32 == value)
+    }
+
+    /// Classify a SoftEther server error code
+    ///
+    /// Codes without a dedicated kind become [`ErrorCode::ServerError`].
+    pub fn from_server(code: u32) -> Self {
+        match code {
+            0 => ErrorCode::Ok,
+            1 => ErrorCode::ConnectionFailed,
+            // ERR_SERVER_IS_NOT_VPN, ERR_PROTOCOL_ERROR, ERR_INVALID_PROTOCOL
+            2 | 4 | 14 => ErrorCode::ProtocolMismatch,
+            // ERR_DISCONNECTED, ERR_HUB_STOPPING, ERR_SESSION_TIMEOUT
+            3 | 10 | 13 => ErrorCode::DisconnectedByServer,
+            6 => ErrorCode::Cancelled,
+            7 => ErrorCode::AuthMethodNotSupported,
+            8 => ErrorCode::HubNotFound,
+            9 => ErrorCode::AuthFailed,
+            11 => ErrorCode::SessionRemoved,
+            12 => ErrorCode::AccessDenied,
+            // ERR_TOO_MANY_CONNECTION, ERR_HUB_IS_BUSY, ERR_TOO_MANY_USER_SESSION
+            15 | 16 | 20 => ErrorCode::ServerFull,
+            // ERR_PROXY_CONNECT_FAILED, ERR_PROXY_ERROR, ERR_PROXY_AUTH_FAILED
+            17..=19 => ErrorCode::Proxy,
+            // ERR_MSCHAP2_PASSWORD_NEED_RESET
+            118 => ErrorCode::PasswordExpired,
+            _ => ErrorCode::ServerError,
+        }
+    }
+
+    /// Fixed English description, independent of the error's details
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::Ok => "No error",
+            ErrorCode::InvalidConfig => "Invalid configuration",
+            ErrorCode::InvalidParameter => "Invalid parameter",
+            ErrorCode::Network => "Network error",
+            ErrorCode::ConnectionFailed => "Connection failed",
+            ErrorCode::Timeout => "Operation timed out",
+            ErrorCode::Tls => "TLS error",
+            ErrorCode::Proxy => "Proxy error",
+            ErrorCode::Dns => "Name resolution failed",
+            ErrorCode::AuthFailed => "Authentication failed",
+            ErrorCode::AccessDenied => "Access denied by hub policy",
+            ErrorCode::PasswordExpired => "Password expired",
+            ErrorCode::AuthMethodNotSupported => "Authentication method not allowed by the hub",
+            ErrorCode::LockedOut => "Too many failed logins, temporarily locked out",
+            ErrorCode::HubNotFound => "Virtual hub not found",
+            ErrorCode::ServerFull => "Server has too many connections",
+            ErrorCode::ProtocolMismatch => "Server does not speak a compatible protocol",
+            ErrorCode::SessionRemoved => "Session removed by an administrator",
+            ErrorCode::DisconnectedByServer => "Disconnected by server",
+            ErrorCode::ServerError => "Server error",
+            ErrorCode::PermissionDenied => "Insufficient privileges (root or administrator required)",
+            ErrorCode::TunError => "Tunnel interface error",
+            ErrorCode::RoutingFailed => "Routing configuration failed",
+            ErrorCode::DnsConfigFailed => "DNS configuration failed",
+            ErrorCode::Cancelled => "Operation cancelled",
+            ErrorCode::InvalidState => "Invalid state",
+            ErrorCode::Unsupported => "Not supported on this platform",
+            ErrorCode::Internal => "Internal error",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl VpnError {
+    /// Stable kind of this error, for callers that branch on it
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            VpnError::Config(_) | VpnError::Configuration(_) => ErrorCode::InvalidConfig,
+            VpnError::Network(_) => ErrorCode::Network,
+            VpnError::Connection(_) => ErrorCode::ConnectionFailed,
+            VpnError::PacketError(_) | VpnError::Protocol(_) => ErrorCode::ProtocolMismatch,
+            VpnError::Authentication(_) => ErrorCode::AuthFailed,
+            VpnError::ServerDisconnected { code, .. } => match ErrorCode::from_server(*code) {
+                ErrorCode::ServerError | ErrorCode::Ok => ErrorCode::DisconnectedByServer,
+                known => known,
+            },
+            VpnError::ServerRefused { code, .. } => ErrorCode::from_server(*code),
+            VpnError::Crypto(_) | VpnError::Tls(_) => ErrorCode::Tls,
+            VpnError::Platform(_) | VpnError::CapabilityUnavailable(_) => ErrorCode::Unsupported,
+            VpnError::TunTap(_) => ErrorCode::TunError,
+            VpnError::Routing(_) => ErrorCode::RoutingFailed,
+            VpnError::Dns(_) => ErrorCode::DnsConfigFailed,
+            VpnError::Proxy(_) => ErrorCode::Proxy,
+            VpnError::Permission(_) => ErrorCode::PermissionDenied,
+            VpnError::ConnectionLimitReached(_) => ErrorCode::ServerFull,
+            VpnError::RateLimitExceeded(_) | VpnError::RetryLimitExceeded(_) => ErrorCode::LockedOut,
+            VpnError::Io(e) => match e.kind() {
+                std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+                std::io::ErrorKind::TimedOut => ErrorCode::Timeout,
+                _ => ErrorCode::Network,
+            },
+            VpnError::Timeout(_) => ErrorCode::Timeout,
+            VpnError::Cancelled(_) => ErrorCode::Cancelled,
+            VpnError::InvalidState(_) => ErrorCode::InvalidState,
+            VpnError::Other(_) => ErrorCode::Internal,
+        }
+    }
+}
+
 /// Result type alias for VPN operations
 pub type Result<T> = std::result::Result<T, VpnError>;
 
@@ -144,4 +351,27 @@ mod tests {
         assert!(vpn_result.is_err());
         assert!(vpn_result.unwrap_err().to_string().contains("test context"));
     }
+
+    #[test]
+    fn test_error_codes() {
+        for (index, code) in ErrorCode::ALL.into_iter().enumerate() {
+            assert_eq!(ErrorCode::from_i32(code as i32), Some(code));
+            assert!(ErrorCode::ALL[..index].iter().all(|earlier| (*earlier as i32) < code as i32));
+        }
+        assert_eq!(ErrorCode::from_i32(3), None);
+
+        let refused = |code| VpnError::ServerRefused { code, message: String::new() };
+        assert_eq!(refused(8).code(), ErrorCode::HubNotFound);
+        assert_eq!(refused(14).code(), ErrorCode::ProtocolMismatch);
+        assert_eq!(refused(16).code(), ErrorCode::ServerFull);
+        assert_eq!(refused(1000).code(), ErrorCode::ServerError);
+        let removed = VpnError::ServerDisconnected { code: 11, message: String::new() };
+        assert_eq!(removed.code(), ErrorCode::SessionRemoved);
+        let unknown = VpnError::ServerDisconnected { code: 1000, message: String::new() };
+        assert_eq!(unknown.code(), ErrorCode::DisconnectedByServer);
+
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        assert_eq!(VpnError::from(denied).code(), ErrorCode::PermissionDenied);
+        assert_eq!(VpnError::Authentication(String::new()).code(), ErrorCode::AuthFailed);
+    }
 }
@@ -5,12 +5,47 @@
 
 #![allow(clippy::missing_safety_doc)]
 
+use std::cell::Cell;
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_int};
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+use std::os::raw::{c_char, c_int, c_void};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::ptr;
+use std::time::Duration;
 
+use futures::future::BoxFuture;
+use tokio::sync::Notify;
+use zeroize::Zeroizing;
+
+use crate::auth_throttle::{AuthFailure, AuthFailureReason};
+use crate::client::events::{
+    DnsUpdateHandler, ErrorEvent, ErrorHandler, ReconnectHandler, StateChange, StateChangeHandler,
+    StatsHandler,
+};
+use crate::client::{DnsUpdated, ReconnectEvent, ReconnectPhase};
+use crate::client_optimized::PerformanceSnapshot;
+use crate::crypto::tls::{CertificatePolicy, TlsProvider, TlsStream};
+use crate::ConnectionStatus;
+use crate::tunnel::{journal, ChangeCategory, ChangePlan, SystemChangePlanner, SystemOps};
+use crate::error::ErrorCode;
+use crate::protocol::{OtpChallenge, OtpProvider};
+use crate::logging::{self, LogRecord, LogSink, StderrSink};
 use crate::{Config, VpnClient, VpnError};
 
+mod handles;
+pub use handles::VpnseClient;
+
+#[cfg(test)]
+mod abi_golden;
+
+/// Version of the C ABI, `VPNSE_ABI_VERSION` in `rvpnse.h`
+///
+/// Bumped when an export is removed, a signature or `#[repr(C)]` layout
+/// changes or an error code is renumbered. New exports keep the version.
+pub const VPNSE_ABI_VERSION: u32 = 1;
+
 /// Error codes returned by C FFI functions
 #[repr(C)]
 pub enum VPNSEError {
@@ -22,23 +57,343 @@ pub enum VPNSEError {
     InvalidParameter = 5,
     TunnelError = 6,
     BufferTooSmall = 7,
+    Cancelled = 11,
+    InProgress = 12,
     InternalError = 99,
 }
 
 impl From<VpnError> for VPNSEError {
     fn from(error: VpnError) -> Self {
+        VPNSEError::from(&error)
+    }
+}
+
+impl From<&VpnError> for VPNSEError {
+    fn from(error: &VpnError) -> Self {
         match error {
-            VpnError::Config(_) => VPNSEError::InvalidConfig,
-            VpnError::Connection(_) => VPNSEError::ConnectionFailed,
+            VpnError::Config(_) | VpnError::Configuration(_) => VPNSEError::InvalidConfig,
+            VpnError::ServerRefused { .. } => match error.code() {
+                ErrorCode::HubNotFound | ErrorCode::AuthMethodNotSupported => VPNSEError::InvalidConfig,
+                ErrorCode::AccessDenied => VPNSEError::AuthenticationFailed,
+                _ => VPNSEError::ConnectionFailed,
+            },
+            VpnError::Connection(_) | VpnError::ServerDisconnected { .. } => VPNSEError::ConnectionFailed,
             VpnError::Authentication(_) => VPNSEError::AuthenticationFailed,
             VpnError::Network(_) => VPNSEError::NetworkError,
             VpnError::TunTap(_) => VPNSEError::TunnelError,
             VpnError::Routing(_) => VPNSEError::TunnelError,
+            VpnError::Dns(_) => VPNSEError::TunnelError,
+            VpnError::Cancelled(_) => VPNSEError::Cancelled,
             _ => VPNSEError::InternalError,
         }
     }
 }
 
+thread_local! {
+    /// Detailed kind of the last error an FFI call on this thread returned
+    static LAST_ERROR: Cell<ErrorCode> = const { Cell::new(ErrorCode::Ok) };
+}
+
+/// Remember `error` for `vpnse_last_error_code` and return its `VPNSEError`
+fn record_error(error: &VpnError) -> c_int {
+    LAST_ERROR.with(|last| last.set(error.code()));
+    VPNSEError::from(error) as c_int
+}
+
+/// Options for `vpnse_init`
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VpnseInitOptions {
+    /// Log level of the built-in logger: 0 leaves logging to the host,
+    /// 1 = error, 2 = warn, 3 = info, 4 = debug, 5 = trace
+    pub log_level: c_int,
+    /// Worker threads of the shared runtime; 0 for one per CPU
+    pub worker_threads: u32,
+    /// Non-zero to log panics before the previous panic hook runs
+    pub install_panic_hook: c_int,
+}
+
+impl Default for VpnseInitOptions {
+    fn default() -> Self {
+        Self { log_level: 0, worker_threads: 0, install_panic_hook: 1 }
+    }
+}
+
+type PanicHook = Box<dyn Fn(&std::panic::PanicHookInfo<'_>) + Sync + Send + 'static>;
+
+/// Process-wide state set up by `vpnse_init`
+struct Library {
+    /// Balanced `vpnse_init` calls still to be matched by `vpnse_shutdown`
+    users: usize,
+    runtime: Arc<tokio::runtime::Runtime>,
+    /// Hook ours chains to, put back itself on shutdown: a wrapper would
+    /// point into this library after it is unloaded
+    previous_hook: Option<Arc<Mutex<Option<PanicHook>>>>,
+}
+
+/// How long `vpnse_shutdown` waits for async calls and runtime tasks
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+static LIBRARY: Mutex<Option<Library>> = Mutex::new(None);
+
+/// Runtime set up by `vpnse_init`, if any
+fn shared_runtime() -> Option<Arc<tokio::runtime::Runtime>> {
+    let library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    library.as_ref().map(|library| library.runtime.clone())
+}
+
+/// Runtime for a blocking FFI call: the one from `vpnse_init`, otherwise the
+/// library's [`blocking`](crate::blocking) runtime
+fn runtime() -> Result<Arc<tokio::runtime::Runtime>, VpnError> {
+    match shared_runtime() {
+        Some(runtime) => Ok(runtime),
+        None => crate::blocking::runtime(),
+    }
+}
+
+fn log_level(level: c_int) -> Option<log::LevelFilter> {
+    match level {
+        1 => Some(log::LevelFilter::Error),
+        2 => Some(log::LevelFilter::Warn),
+        3 => Some(log::LevelFilter::Info),
+        4 => Some(log::LevelFilter::Debug),
+        5 => Some(log::LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// Set up the library's process-wide state
+///
+/// Creates the shared runtime that blocking calls, async calls and packet
+/// forwarding run on, installs the TLS crypto provider and, as requested, a
+/// logger and a panic hook that logs panics. Calls nest: only the first one
+/// sets anything up (later options are ignored) and every call must be
+/// matched by `vpnse_shutdown`. Without it, calls run on a runtime the
+/// library starts on first use and keeps for the life of the process.
+///
+/// The built-in logger writes to stderr until `vpnse_set_log_callback`
+/// redirects it. A logger can only be installed once per process; if the
+/// host or an earlier load installed one, only its level is changed.
+///
+/// # Parameters
+/// - `options`: Options, or NULL for the defaults (no logger, panic hook)
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_init(options: *const VpnseInitOptions) -> c_int {
+    let options = options.as_ref().copied().unwrap_or_default();
+    let mut library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(library) = library.as_mut() {
+        library.users += 1;
+        return VPNSEError::Success as c_int;
+    }
+
+    if let Err(err) = crate::crypto::tls::install_crypto_provider() {
+        return record_error(&err);
+    }
+
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all().thread_name("vpnse-worker");
+    if options.worker_threads > 0 {
+        builder.worker_threads(options.worker_threads as usize);
+    }
+    let runtime = match builder.build() {
+        Ok(runtime) => Arc::new(runtime),
+        Err(_) => return VPNSEError::InternalError as c_int,
+    };
+
+    if let Some(level) = log_level(options.log_level) {
+        if logging::set_sink(Some(Arc::new(StderrSink)), level).is_err() {
+            log::set_max_level(level);
+        }
+    }
+
+    let previous_hook = (options.install_panic_hook != 0).then(|| {
+        let previous = Arc::new(Mutex::new(Some(std::panic::take_hook())));
+        let chained = previous.clone();
+        std::panic::set_hook(Box::new(move |info| {
+            log::error!("Panic in rVPNSE: {info}");
+            if let Some(hook) = chained.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+                hook(info);
+            }
+        }));
+        previous
+    });
+
+    *library = Some(Library { users: 1, runtime, previous_hook });
+    VPNSEError::Success as c_int
+}
+
+/// Release what `vpnse_init` set up
+///
+/// The last of nested calls cancels pending async calls and waits until
+/// their callbacks have returned (except the one it is called from, if
+/// any), stops the shared runtime with every task on it (packet
+/// forwarding of tunnels still up included), joins its threads and puts the
+/// previous panic hook back. Disconnect and free clients first. Afterwards
+/// the library can be unloaded, or initialised again.
+///
+/// # Returns
+/// - 0 on success
+/// - `InvalidParameter` if the library is not initialised
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_shutdown() -> c_int {
+    let library = {
+        let mut library = LIBRARY.lock().unwrap_or_else(|e| e.into_inner());
+        match library.as_mut() {
+            None => return VPNSEError::InvalidParameter as c_int,
+            Some(state) if state.users > 1 => {
+                state.users -= 1;
+                return VPNSEError::Success as c_int;
+            }
+            Some(_) => library.take().unwrap(),
+        }
+    };
+
+    // Async call workers hold the runtime until their callbacks have run.
+    // A callback may start another call, or be the caller of this function,
+    // whose own worker cannot be joined.
+    let current = std::thread::current().id();
+    loop {
+        for cancel in PENDING_CALLS.lock().unwrap_or_else(|e| e.into_inner()).values() {
+            cancel.notify_one();
+        }
+        let workers: Vec<_> = std::mem::take(&mut *ASYNC_WORKERS.lock().unwrap_or_else(|e| e.into_inner()))
+            .into_iter()
+            .filter(|worker| worker.thread().id() != current)
+            .collect();
+        if workers.is_empty() {
+            break;
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+    }
+
+    if let Some(previous) = library.previous_hook {
+        let hook = previous.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(hook) = hook {
+            std::panic::set_hook(hook);
+        }
+    }
+
+    match Arc::try_unwrap(library.runtime) {
+        Ok(runtime) => runtime.shutdown_timeout(SHUTDOWN_TIMEOUT),
+        // A blocking call still running on another thread drops the last reference
+        Err(_) => log::warn!("vpnse_shutdown called while other calls are in progress"),
+    }
+    VPNSEError::Success as c_int
+}
+
+/// Callback receiving the library's log records
+///
+/// `level` is 1 = error ... 5 = trace. `fields` is a JSON object of the
+/// record's structured fields, such as `{"interface":"tun0","mtu":"1400"}`.
+/// Runs on whichever thread logged; the strings are only valid during the call.
+pub type VpnseLogCallback = Option<
+    unsafe extern "C" fn(
+        level: c_int,
+        target: *const c_char,
+        message: *const c_char,
+        fields: *const c_char,
+        user_data: *mut c_void,
+    ),
+>;
+
+struct FfiLogSink {
+    callback: unsafe extern "C" fn(c_int, *const c_char, *const c_char, *const c_char, *mut c_void),
+    user_data: usize,
+}
+
+impl LogSink for FfiLogSink {
+    fn log(&self, record: &LogRecord<'_>) {
+        let fields: serde_json::Map<String, serde_json::Value> =
+            record.fields.iter().map(|(key, value)| (key.clone(), value.clone().into())).collect();
+        let c_string = |text: &str| CString::new(text.replace('\0', " ")).unwrap_or_default();
+        let (target, message) = (c_string(record.target), c_string(record.message));
+        let fields = c_string(&serde_json::Value::Object(fields).to_string());
+        unsafe {
+            (self.callback)(
+                record.level as c_int,
+                target.as_ptr(),
+                message.as_ptr(),
+                fields.as_ptr(),
+                self.user_data as *mut c_void,
+            )
+        };
+    }
+}
+
+/// Send the library's log records to a callback instead of stderr
+///
+/// Replaces the logger of `vpnse_init` and takes effect for all clients.
+/// Fails if the host already installed a logger of its own in this process.
+///
+/// # Parameters
+/// - `callback`: Log callback, or NULL to stop logging
+/// - `level`: Most verbose level to deliver: 1 = error ... 5 = trace
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_set_log_callback(
+    callback: VpnseLogCallback,
+    level: c_int,
+    user_data: *mut c_void,
+) -> c_int {
+    let sink = match (callback, log_level(level)) {
+        (Some(callback), Some(_)) => {
+            Some(Arc::new(FfiLogSink { callback, user_data: user_data as usize }) as Arc<dyn LogSink>)
+        }
+        (Some(_), None) => return VPNSEError::InvalidParameter as c_int,
+        (None, _) => None,
+    };
+    match logging::set_sink(sink, log_level(level).unwrap_or(log::LevelFilter::Off)) {
+        Ok(()) => VPNSEError::Success as c_int,
+        Err(err) => record_error(&err),
+    }
+}
+
+/// Undo system changes left behind by crashed processes
+///
+/// Replays the change journals of processes that exited without tearing
+/// their tunnel down: their routes, DNS settings, firewall rules and kill
+/// switch are removed. Journals of running processes are left alone. Call
+/// it once at startup, before connecting.
+///
+/// # Parameters
+/// - `state_dir`: Journal directory (`tunnel.state_dir`), or NULL for the default
+/// - `restored`: Receives the number of journals replayed (nullable)
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_cleanup_stale_state(state_dir: *const c_char, restored: *mut u32) -> c_int {
+    let dir = if state_dir.is_null() {
+        journal::default_state_dir()
+    } else {
+        match CStr::from_ptr(state_dir).to_str() {
+            Ok(s) => PathBuf::from(s),
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        }
+    };
+
+    match journal::cleanup_stale_state(&dir, &SystemOps::default()) {
+        Ok(count) => {
+            if !restored.is_null() {
+                *restored = u32::try_from(count).unwrap_or(u32::MAX);
+            }
+            VPNSEError::Success as c_int
+        }
+        Err(err) => record_error(&err),
+    }
+}
+
 /// Parse and validate a SoftEther VPN configuration
 ///
 /// # Parameters
@@ -80,7 +435,7 @@ pub unsafe extern "C" fn vpnse_parse_config(
                 );
                 *error_msg.add(copy_len) = 0; // Null terminate
             }
-            VPNSEError::from(err) as c_int
+            record_error(&err)
         }
     }
 }
@@ -90,11 +445,14 @@ pub unsafe extern "C" fn vpnse_parse_config(
 /// # Parameters
 /// - `config_str`: TOML configuration string
 ///
+/// The handle may be used from any thread; calls on one client from several
+/// threads run one at a time.
+///
 /// # Returns
-/// - Opaque pointer to VPN client on success
+/// - Opaque client handle on success
 /// - NULL on failure
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_client_new(config_str: *const c_char) -> *mut VpnClient {
+pub unsafe extern "C" fn vpnse_client_new(config_str: *const c_char) -> *mut VpnseClient {
     if config_str.is_null() {
         return ptr::null_mut();
     }
@@ -110,7 +468,10 @@ pub unsafe extern "C" fn vpnse_client_new(config_str: *const c_char) -> *mut Vpn
     };
 
     match VpnClient::new(config) {
-        Ok(client) => Box::into_raw(Box::new(client)),
+        Ok(mut client) => {
+            client.set_config_origin("ffi (inline TOML)");
+            handles::insert(client)
+        }
         Err(_) => ptr::null_mut(),
     }
 }
@@ -127,427 +488,3009 @@ pub unsafe extern "C" fn vpnse_client_new(config_str: *const c_char) -> *mut Vpn
 /// - Error code on failure
 #[no_mangle]
 pub unsafe extern "C" fn vpnse_client_connect(
-    client: *mut VpnClient,
+    client: *mut VpnseClient,
     server: *const c_char,
     port: u16,
 ) -> c_int {
-    if client.is_null() || server.is_null() {
+    if server.is_null() {
         return VPNSEError::InvalidParameter as c_int;
     }
 
-    let client = &mut *client;
-    let server_str = match CStr::from_ptr(server).to_str() {
-        Ok(s) => s,
-        Err(_) => return VPNSEError::InvalidParameter as c_int,
-    };
+    handles::with_client(client, |client| {
+        let server_str = match CStr::from_ptr(server).to_str() {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        };
 
-    match client.connect(server_str, port) {
-        Ok(_) => VPNSEError::Success as c_int,
-        Err(err) => VPNSEError::from(err) as c_int,
-    }
+        block_on_connect(client.connect_async(server_str, port))
+    })
+    .unwrap_or_else(|e| e as c_int)
 }
 
-/// Authenticate with SoftEther VPN server
+/// Callback receiving the result of `vpnse_client_connect_async` or
+/// `vpnse_client_authenticate_async`
+///
+/// `result` is 0 on success or an error code (`Cancelled` when the
+/// operation was cancelled).
+pub type VpnseConnectCallback = Option<unsafe extern "C" fn(result: c_int, user_data: *mut c_void)>;
+
+lazy_static::lazy_static! {
+    /// Cancellation signals of pending async calls, keyed by client handle
+    static ref PENDING_CALLS: Mutex<HashMap<usize, Arc<Notify>>> = Mutex::new(HashMap::new());
+    /// Worker threads of async calls, joined by `vpnse_shutdown`
+    static ref ASYNC_WORKERS: Mutex<Vec<std::thread::JoinHandle<()>>> = Mutex::new(Vec::new());
+}
+
+/// Start connecting to a SoftEther VPN server without blocking
+///
+/// Returns immediately; the handshake runs on a worker thread owned by the
+/// library and `callback` is invoked exactly once on that thread when it
+/// completes, fails or is cancelled. Until the callback has been invoked,
+/// other calls on the client wait for the connect, except
+/// `vpnse_client_connect_cancel`; freeing the handle releases the client once
+/// the connect is over. The callback may call back into the library for this
+/// client (including `vpnse_client_free`) and should return quickly.
 ///
 /// # Parameters
 /// - `client`: VPN client instance
-/// - `username`: Username for authentication
-/// - `password`: Password for authentication
+/// - `server`: Server hostname or IP address
+/// - `port`: Server port number
+/// - `callback`: Completion callback (required)
+/// - `user_data`: Opaque pointer passed back to the callback
 ///
 /// # Returns
-/// - 0 on success
-/// - Error code on failure
+/// - 0 if the connect was started (the result is delivered to the callback)
+/// - `InProgress` if an async call is already pending for this client
+/// - Error code on failure (the callback is not invoked)
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_client_authenticate(
-    client: *mut VpnClient,
-    username: *const c_char,
-    password: *const c_char,
+pub unsafe extern "C" fn vpnse_client_connect_async(
+    client: *mut VpnseClient,
+    server: *const c_char,
+    port: u16,
+    callback: VpnseConnectCallback,
+    user_data: *mut c_void,
 ) -> c_int {
-    if client.is_null() || username.is_null() || password.is_null() {
+    if server.is_null() {
         return VPNSEError::InvalidParameter as c_int;
     }
-
-    let client = &mut *client;
-    let username_str = match CStr::from_ptr(username).to_str() {
-        Ok(s) => s,
-        Err(_) => return VPNSEError::InvalidParameter as c_int,
-    };
-    let password_str = match CStr::from_ptr(password).to_str() {
-        Ok(s) => s,
+    let server = match CStr::from_ptr(server).to_str() {
+        Ok(s) => s.to_string(),
         Err(_) => return VPNSEError::InvalidParameter as c_int,
     };
-
-    match tokio::runtime::Runtime::new()
-        .unwrap()
-        .block_on(client.authenticate(username_str, password_str))
-    {
-        Ok(_) => VPNSEError::Success as c_int,
-        Err(err) => VPNSEError::from(err) as c_int,
-    }
+    spawn_async_call(client, AsyncCall::Connect { server, port }, callback, user_data)
 }
 
-/// Disconnect from VPN server
+/// Log in without blocking
+///
+/// The login counterpart of `vpnse_client_connect_async`, with the same
+/// threading and callback rules; `vpnse_client_connect_cancel` cancels it and
+/// drops the connection, whose login exchange was cut short.
 ///
 /// # Parameters
-/// - `client`: VPN client instance
+/// - `client`: Connected VPN client instance
+/// - `username`: Username for authentication
+/// - `password`: Password for authentication
+/// - `callback`: Completion callback (required)
+/// - `user_data`: Opaque pointer passed back to the callback
 ///
 /// # Returns
-/// - 0 on success
-/// - Error code on failure
+/// - 0 if the login was started (the result is delivered to the callback)
+/// - `InProgress` if an async call is already pending for this client
+/// - Error code on failure (the callback is not invoked)
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_client_disconnect(client: *mut VpnClient) -> c_int {
-    if client.is_null() {
+pub unsafe extern "C" fn vpnse_client_authenticate_async(
+    client: *mut VpnseClient,
+    username: *const c_char,
+    password: *const c_char,
+    callback: VpnseConnectCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    if username.is_null() || password.is_null() {
         return VPNSEError::InvalidParameter as c_int;
     }
+    let (Ok(username), Ok(password)) = (CStr::from_ptr(username).to_str(), CStr::from_ptr(password).to_str()) else {
+        return VPNSEError::InvalidParameter as c_int;
+    };
+    let call = AsyncCall::Authenticate {
+        username: username.to_string(),
+        password: Zeroizing::new(password.to_string()),
+    };
+    spawn_async_call(client, call, callback, user_data)
+}
 
-    let client = &mut *client;
-    match client.disconnect() {
-        Ok(_) => VPNSEError::Success as c_int,
-        Err(err) => VPNSEError::from(err) as c_int,
-    }
+/// Work an async FFI call does on its worker thread
+enum AsyncCall {
+    Connect { server: String, port: u16 },
+    Authenticate { username: String, password: Zeroizing<String> },
 }
 
-/// Free VPN client instance
-///
-/// # Parameters
-/// - `client`: VPN client instance to free
-#[no_mangle]
-pub unsafe extern "C" fn vpnse_client_free(client: *mut VpnClient) {
-    if !client.is_null() {
-        unsafe {
-            let _ = Box::from_raw(client);
+impl AsyncCall {
+    fn thread_name(&self) -> &'static str {
+        match self {
+            Self::Connect { .. } => "vpnse-connect",
+            Self::Authenticate { .. } => "vpnse-auth",
         }
     }
-}
 
-/// Get library version
-///
-/// # Returns
-/// - Version string (caller must not free)
-#[no_mangle]
-pub unsafe extern "C" fn vpnse_version() -> *const c_char {
-    static VERSION_CSTR: &[u8] = concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes();
-    VERSION_CSTR.as_ptr() as *const c_char
+    async fn run(&self, client: &mut VpnClient) -> crate::Result<()> {
+        match self {
+            Self::Connect { server, port } => client.connect_async(server, *port).await,
+            Self::Authenticate { username, password } => client.authenticate(username, password).await,
+        }
+    }
 }
 
-/// Get connection status
-///
-/// # Parameters
-/// - `client`: VPN client instance
-///
-/// # Returns
-/// - 0: Disconnected
-/// - 1: Connecting
-/// - 2: Connected (Protocol only)
-/// - 3: Tunnel established
-/// - -1: Error or invalid client
-#[no_mangle]
-pub unsafe extern "C" fn vpnse_client_status(client: *const VpnClient) -> c_int {
-    if client.is_null() {
-        return -1;
+/// Run `call` on a worker thread and report its result to `callback`
+unsafe fn spawn_async_call(
+    client: *mut VpnseClient,
+    call: AsyncCall,
+    callback: VpnseConnectCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let Some(callback) = callback else {
+        return VPNSEError::InvalidParameter as c_int;
+    };
+    let Some(shared) = handles::get(client) else {
+        return VPNSEError::InvalidParameter as c_int;
+    };
+
+    let client_addr = client as usize;
+    let user_data = user_data as usize;
+    let cancel = Arc::new(Notify::new());
+    {
+        let mut pending = PENDING_CALLS.lock().unwrap_or_else(|e| e.into_inner());
+        if pending.contains_key(&client_addr) {
+            return VPNSEError::InProgress as c_int;
+        }
+        pending.insert(client_addr, cancel.clone());
     }
 
-    let client = &*client;
-    match client.status() {
-        crate::ConnectionStatus::Disconnected => 0,
-        crate::ConnectionStatus::Connecting => 1,
-        crate::ConnectionStatus::Connected => 2,
-        crate::ConnectionStatus::Tunneling => 3,
+    // Held until the handle is recorded, so that shutdown sees every worker
+    let mut workers = ASYNC_WORKERS.lock().unwrap_or_else(|e| e.into_inner());
+    workers.retain(|worker| !worker.is_finished());
+    let worker = std::thread::Builder::new()
+        .name(call.thread_name().to_string())
+        .spawn(move || {
+            // Other calls on the client wait until this one is over
+            let code = handles::lock(client_addr, &shared, |client| {
+                let outcome = runtime().map(|rt| {
+                    rt.block_on(async {
+                        tokio::select! {
+                            result = call.run(client) => Some(result),
+                            _ = cancel.notified() => None,
+                        }
+                    })
+                });
+                match outcome {
+                    Ok(Some(Ok(()))) => VPNSEError::Success as c_int,
+                    Ok(Some(Err(err))) | Err(err) => record_error(&err),
+                    Ok(None) => {
+                        // Reset the half-open connection state
+                        let _ = client.disconnect();
+                        VPNSEError::Cancelled as c_int
+                    }
+                }
+            })
+            .unwrap_or_else(|e| e as c_int);
+            // A handle freed meanwhile takes its client with it here
+            drop(shared);
+
+            PENDING_CALLS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&client_addr);
+            unsafe { callback(code, user_data as *mut c_void) };
+        });
+
+    match worker {
+        Ok(worker) => {
+            workers.push(worker);
+            VPNSEError::Success as c_int
+        }
+        Err(_) => {
+            PENDING_CALLS
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&client_addr);
+            VPNSEError::InternalError as c_int
+        }
     }
 }
 
-/// Establish VPN tunnel (routing layer)
+/// Cancel a pending `vpnse_client_connect_async` or
+/// `vpnse_client_authenticate_async`
 ///
-/// This function attempts to create a TUN interface and configure routing
-/// to route traffic through the VPN tunnel.
+/// Cancellation is asynchronous: the completion callback still runs exactly
+/// once, with `Cancelled` unless the call finished first.
 ///
 /// # Parameters
-/// - `client`: VPN client instance (must be authenticated)
+/// - `client`: VPN client instance
 ///
 /// # Returns
-/// - 0 on success
-/// - Error code on failure
+/// - 0 if a pending call was signalled
+/// - `InvalidParameter` if no call is pending for this client
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_client_establish_tunnel(client: *mut VpnClient) -> c_int {
-    if client.is_null() {
-        return VPNSEError::InvalidParameter as c_int;
-    }
-
-    let client = &mut *client;
-    match client.establish_tunnel() {
-        Ok(_) => VPNSEError::Success as c_int,
-        Err(err) => VPNSEError::from(err) as c_int,
+pub unsafe extern "C" fn vpnse_client_connect_cancel(client: *mut VpnseClient) -> c_int {
+    let pending = PENDING_CALLS.lock().unwrap_or_else(|e| e.into_inner());
+    match pending.get(&(client as usize)) {
+        Some(cancel) => {
+            cancel.notify_one();
+            VPNSEError::Success as c_int
+        }
+        None => VPNSEError::InvalidParameter as c_int,
     }
 }
 
-/// Establish a VPN tunnel
+/// Connect to the best node of the configured cluster
+///
+/// Blocks like `vpnse_client_connect`. The first call probes every node and
+/// picks the fastest; later calls follow `load_balancing_strategy`. Clears a
+/// pin set by `vpnse_client_connect_cluster_node`.
 ///
 /// # Parameters
-/// - `client`: VPN client instance
+/// - `client`: VPN client instance with `[clustering]` enabled
 ///
 /// # Returns
 /// - 0 on success
-/// - Error code on failure
+/// - Error code on failure (`InvalidConfig` if clustering is not enabled)
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_tunnel_establish(client: *mut VpnClient) -> c_int {
-    if client.is_null() {
-        return VPNSEError::InvalidParameter as c_int;
-    }
-
-    let client = &mut *client;
-    match client.establish_tunnel() {
-        Ok(_) => VPNSEError::Success as c_int,
-        Err(err) => VPNSEError::from(err) as c_int,
-    }
+pub unsafe extern "C" fn vpnse_client_connect_cluster_auto(client: *mut VpnseClient) -> c_int {
+    handles::with_client(client, |client| {
+        block_on_connect(client.connect_to_cluster())
+    })
+    .unwrap_or_else(|e| e as c_int)
 }
 
-/// Close the VPN tunnel
+/// Connect to one node of the configured cluster and pin it
+///
+/// Blocks like `vpnse_client_connect`. The node is tried even if it is
+/// blacklisted after earlier failures, and failover does not move away from
+/// it until `vpnse_client_connect_cluster_auto` is called.
 ///
 /// # Parameters
-/// - `client`: VPN client instance
+/// - `client`: VPN client instance with `[clustering]` enabled
+/// - `index`: Position of the node in `cluster_nodes` (the `index` field of
+///   `vpnse_client_cluster_nodes`)
 ///
 /// # Returns
 /// - 0 on success
-/// - Error code on failure
+/// - Error code on failure (`InvalidParameter` if there is no such node)
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_tunnel_close(client: *mut VpnClient) -> c_int {
-    if client.is_null() {
-        return VPNSEError::InvalidParameter as c_int;
-    }
-
-    let client = &mut *client;
-    match client.teardown_tunnel() {
-        Ok(_) => VPNSEError::Success as c_int,
-        Err(err) => VPNSEError::from(err) as c_int,
-    }
+pub unsafe extern "C" fn vpnse_client_connect_cluster_node(client: *mut VpnseClient, index: u32) -> c_int {
+    handles::with_client(client, |client| {
+        let index = index as usize;
+        if client.get_cluster_status().is_some_and(|nodes| index >= nodes.len()) {
+            return VPNSEError::InvalidParameter as c_int;
+        }
+        block_on_connect(client.connect_to_cluster_member(index))
+    })
+    .unwrap_or_else(|e| e as c_int)
 }
 
-/// Get current public IP address (for testing if traffic is routed through VPN)
+/// Get the health of every cluster node, for a server picker
+///
+/// Writes a NUL-terminated JSON array in `cluster_nodes` order such as
+/// `[{"index":0,"address":"vpn1.example.com:443","healthy":true,"response_time_ms":23,"active_connections":1,"penalty":0.0,"blacklisted_for_ms":null,"last_failure":null,"pinned":false},...]`.
+/// `response_time_ms` is null until a probe reached the node; `last_failure`
+/// is `auth_failed`, `connection_reset` or `unreachable`. Empty when
+/// clustering is not enabled.
 ///
 /// # Parameters
 /// - `client`: VPN client instance
-/// - `ip_buffer`: Buffer to store the IP address string
+/// - `buffer`: Buffer to store the JSON string
 /// - `buffer_len`: Size of the buffer
 ///
 /// # Returns
 /// - 0 on success
-/// - Error code on failure
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_get_public_ip(
-    client: *mut VpnClient,
-    ip_buffer: *mut c_char,
+pub unsafe extern "C" fn vpnse_client_cluster_nodes(
+    client: *const VpnseClient,
+    buffer: *mut c_char,
     buffer_len: usize,
 ) -> c_int {
-    if client.is_null() || ip_buffer.is_null() || buffer_len == 0 {
+    if buffer.is_null() || buffer_len == 0 {
         return VPNSEError::InvalidParameter as c_int;
     }
 
-    let client = &mut *client;
-    match tokio::runtime::Runtime::new()
-        .unwrap()
-        .block_on(client.get_current_public_ip())
-    {
-        Ok(ip) => {
-            let ip_cstr = match CString::new(ip) {
-                Ok(s) => s,
-                Err(_) => return VPNSEError::InvalidParameter as c_int,
-            };
+    handles::with_client(client, |client| {
+        let nodes = client.get_cluster_status().unwrap_or_default();
+        let json = match CString::new(serde_json::to_string(&nodes).unwrap_or_else(|_| "[]".to_string())) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InternalError as c_int,
+        };
 
-            let ip_bytes = ip_cstr.as_bytes_with_nul();
-            if ip_bytes.len() > buffer_len {
-                return VPNSEError::BufferTooSmall as c_int;
-            }
+        let json_bytes = json.as_bytes_with_nul();
+        if json_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
 
-            unsafe {
-                ptr::copy_nonoverlapping(
-                    ip_bytes.as_ptr() as *const c_char,
-                    ip_buffer,
-                    ip_bytes.len(),
-                );
-            }
-
-            VPNSEError::Success as c_int
+        unsafe {
+            ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
         }
-        Err(err) => VPNSEError::from(err) as c_int,
+
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Run a connect on the shared runtime, or a fresh one as `VpnClient::connect` does
+fn block_on_connect(connect: impl std::future::Future<Output = crate::Result<()>>) -> c_int {
+    let result = runtime().and_then(|rt| rt.block_on(connect));
+    match result {
+        Ok(()) => VPNSEError::Success as c_int,
+        Err(err) => record_error(&err),
     }
 }
 
-/// Get tunnel interface name
+/// Authenticate with SoftEther VPN server
 ///
 /// # Parameters
 /// - `client`: VPN client instance
-/// - `interface_buffer`: Buffer to store the interface name
-/// - `buffer_len`: Size of the buffer
+/// - `username`: Username for authentication
+/// - `password`: Password for authentication
 ///
 /// # Returns
-/// - 0 on success, 1 if no tunnel established
+/// - 0 on success
 /// - Error code on failure
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_get_tunnel_interface(
-    client: *mut VpnClient,
-    interface_buffer: *mut c_char,
-    buffer_len: usize,
+pub unsafe extern "C" fn vpnse_client_authenticate(
+    client: *mut VpnseClient,
+    username: *const c_char,
+    password: *const c_char,
 ) -> c_int {
-    if client.is_null() || interface_buffer.is_null() || buffer_len == 0 {
+    if username.is_null() || password.is_null() {
         return VPNSEError::InvalidParameter as c_int;
     }
 
-    if let Some((interface_name, _, _, _)) = crate::tunnel::get_tunnel_interface() {
-        let interface_cstr = match CString::new(interface_name) {
+    handles::with_client(client, |client| {
+        let username_str = match CStr::from_ptr(username).to_str() {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        };
+        let password_str = match CStr::from_ptr(password).to_str() {
             Ok(s) => s,
             Err(_) => return VPNSEError::InvalidParameter as c_int,
         };
 
-        let interface_bytes = interface_cstr.as_bytes_with_nul();
-        if interface_bytes.len() > buffer_len {
-            return VPNSEError::BufferTooSmall as c_int;
+        match runtime().and_then(|rt| rt.block_on(client.authenticate(username_str, password_str))) {
+            Ok(_) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
         }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
 
-        unsafe {
-            ptr::copy_nonoverlapping(
-                interface_bytes.as_ptr() as *const c_char,
-                interface_buffer,
-                interface_bytes.len(),
-            );
+/// Change the configured user's password on the server
+///
+/// For when `vpnse_client_authenticate` failed and [`vpnse_last_error_code`]
+/// reports `PasswordExpired`; needs a connection but no login. Later logins
+/// use the new password.
+///
+/// # Parameters
+/// - `client`: VPN client instance, connected
+/// - `old_password`: Current password
+/// - `new_password`: Password to set, non-empty
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`AuthenticationFailed` if the old password is wrong)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_change_password(
+    client: *mut VpnseClient,
+    old_password: *const c_char,
+    new_password: *const c_char,
+) -> c_int {
+    if old_password.is_null() || new_password.is_null() {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    handles::with_client(client, |client| {
+        let old_password = match CStr::from_ptr(old_password).to_str() {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        };
+        let new_password = match CStr::from_ptr(new_password).to_str() {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        };
+
+        match runtime().and_then(|rt| rt.block_on(client.change_password(old_password, new_password))) {
+            Ok(()) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
         }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
 
-        VPNSEError::Success as c_int
-    } else {
-        1 // No tunnel established
+/// Disconnect from VPN server
+///
+/// # Parameters
+/// - `client`: VPN client instance
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_disconnect(client: *mut VpnseClient) -> c_int {
+    handles::with_client(client, |client| {
+        match client.disconnect() {
+            Ok(_) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
+        }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Free VPN client instance
+///
+/// Calls already running on the client finish first; null and already freed
+/// handles are ignored.
+///
+/// # Parameters
+/// - `client`: VPN client instance to free
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_free(client: *mut VpnseClient) {
+    drop(handles::remove(client));
+}
+
+/// Get library version
+///
+/// # Returns
+/// - Version string (caller must not free)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_version() -> *const c_char {
+    static VERSION_CSTR: &[u8] = concat!(env!("CARGO_PKG_VERSION"), "\0").as_bytes();
+    VERSION_CSTR.as_ptr() as *const c_char
+}
+
+/// ABI version the library was built with
+///
+/// Hosts compare it with the `VPNSE_ABI_VERSION` of the header they were
+/// compiled against and refuse to run on a mismatch.
+///
+/// # Returns
+/// - The library's `VPNSE_ABI_VERSION`
+#[no_mangle]
+pub extern "C" fn vpnse_abi_version() -> u32 {
+    VPNSE_ABI_VERSION
+}
+
+/// Detailed error code of the last failed call on this thread
+///
+/// `VPNSEError` only tells the broad category; this tells e.g. a missing hub
+/// from rejected credentials. It is updated when a call fails inside the
+/// library, not by parameter checks returning `InvalidParameter`, and a
+/// `vpnse_client_connect_async` callback sees its own connect's error.
+///
+/// # Returns
+/// - A `vpnse_error_code_t` value, 0 if no call on this thread has failed yet
+#[no_mangle]
+pub extern "C" fn vpnse_last_error_code() -> c_int {
+    LAST_ERROR.with(Cell::get) as c_int
+}
+
+/// Describe a detailed error code
+///
+/// # Parameters
+/// - `code`: Value returned by `vpnse_last_error_code`
+///
+/// # Returns
+/// - English description (caller must not free), or NULL for an unknown code
+#[no_mangle]
+pub extern "C" fn vpnse_error_message(code: c_int) -> *const c_char {
+    static MESSAGES: OnceLock<Vec<(ErrorCode, CString)>> = OnceLock::new();
+    let messages = MESSAGES.get_or_init(|| {
+        ErrorCode::ALL
+            .into_iter()
+            .map(|code| (code, CString::new(code.description()).unwrap_or_default()))
+            .collect()
+    });
+    ErrorCode::from_i32(code)
+        .and_then(|code| messages.iter().find(|(known, _)| *known == code))
+        .map_or(ptr::null(), |(_, message)| message.as_ptr())
+}
+
+/// Describe compiled features and runtime platform capabilities
+///
+/// Writes a NUL-terminated JSON object such as
+/// `{"version":"0.1.0","tls_backend":"rustls",...,"platform":{"tun_available":true,...}}`.
+///
+/// # Parameters
+/// - `buffer`: Buffer to store the JSON string
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_capabilities(buffer: *mut c_char, buffer_len: usize) -> c_int {
+    if buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    let json = match CString::new(crate::capabilities::capabilities().to_json()) {
+        Ok(s) => s,
+        Err(_) => return VPNSEError::InternalError as c_int,
+    };
+
+    let json_bytes = json.as_bytes_with_nul();
+    if json_bytes.len() > buffer_len {
+        return VPNSEError::BufferTooSmall as c_int;
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
+    }
+
+    VPNSEError::Success as c_int
+}
+
+/// List the host's network interfaces, candidate uplinks first
+///
+/// Writes a NUL-terminated JSON array such as
+/// `[{"name":"wlan0","index":3,"kind":"wireless","addresses":["192.168.1.20"],"is_up":true,"gateway":"192.168.1.1","metric":600},...]`.
+/// Pass a `name` to [`vpnse_client_set_underlay_interface`] to pin the
+/// session to that uplink.
+///
+/// # Parameters
+/// - `buffer`: Buffer to store the JSON string
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_list_interfaces(buffer: *mut c_char, buffer_len: usize) -> c_int {
+    if buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    let interfaces = crate::underlay::list_interfaces();
+    let json = match CString::new(serde_json::to_string(&interfaces).unwrap_or_else(|_| "[]".to_string())) {
+        Ok(s) => s,
+        Err(_) => return VPNSEError::InternalError as c_int,
+    };
+
+    let json_bytes = json.as_bytes_with_nul();
+    if json_bytes.len() > buffer_len {
+        return VPNSEError::BufferTooSmall as c_int;
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
     }
+
+    VPNSEError::Success as c_int
 }
 
-/// Get tunnel local IP address
+/// Download the VPN Gate server list
+///
+/// Writes a NUL-terminated JSON array of servers such as
+/// `[{"host_name":"public-vpn-123","ip":"219.100.37.10","port":443,"ping_ms":12,"speed_bps":98765432,"country_short":"JP",...},...]`,
+/// to show to the user or pass to [`vpnse_vpngate_select`]. The public list
+/// is well over 100 KB. Only built with the `vpngate` feature.
 ///
 /// # Parameters
-/// - `client`: VPN client instance
-/// - `ip_buffer`: Buffer to store the local IP address
+/// - `url`: List URL, or NULL for the public VPN Gate API
+/// - `timeout_ms`: Download timeout in milliseconds
+/// - `buffer`: Buffer to store the JSON string
 /// - `buffer_len`: Size of the buffer
 ///
 /// # Returns
-/// - 0 on success, 1 if no tunnel established
-/// - Error code on failure
+/// - 0 on success
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
+#[cfg(feature = "vpngate")]
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_get_tunnel_local_ip(
-    client: *mut VpnClient,
-    ip_buffer: *mut c_char,
+pub unsafe extern "C" fn vpnse_vpngate_fetch_servers(
+    url: *const c_char,
+    timeout_ms: u32,
+    buffer: *mut c_char,
     buffer_len: usize,
 ) -> c_int {
-    if client.is_null() || ip_buffer.is_null() || buffer_len == 0 {
+    if buffer.is_null() || buffer_len == 0 || timeout_ms == 0 {
         return VPNSEError::InvalidParameter as c_int;
     }
-
-    if let Some((_, local_ip, _, _)) = crate::tunnel::get_tunnel_interface() {
-        let ip_cstr = match CString::new(local_ip) {
+    let url = if url.is_null() {
+        crate::vpngate::VPNGATE_API_URL
+    } else {
+        match CStr::from_ptr(url).to_str() {
             Ok(s) => s,
             Err(_) => return VPNSEError::InvalidParameter as c_int,
-        };
-
-        let ip_bytes = ip_cstr.as_bytes_with_nul();
-        if ip_bytes.len() > buffer_len {
-            return VPNSEError::BufferTooSmall as c_int;
         }
+    };
 
-        unsafe {
-            ptr::copy_nonoverlapping(
-                ip_bytes.as_ptr() as *const c_char,
-                ip_buffer,
-                ip_bytes.len(),
-            );
-        }
+    let timeout = Duration::from_millis(u64::from(timeout_ms));
+    let servers = match runtime().and_then(|rt| rt.block_on(crate::vpngate::fetch_server_list(url, timeout))) {
+        Ok(servers) => servers,
+        Err(err) => return record_error(&err),
+    };
+    let json = match serde_json::to_string(&servers).ok().and_then(|json| CString::new(json).ok()) {
+        Some(s) => s,
+        None => return VPNSEError::InternalError as c_int,
+    };
 
-        VPNSEError::Success as c_int
+    let json_bytes = json.as_bytes_with_nul();
+    if json_bytes.len() > buffer_len {
+        return VPNSEError::BufferTooSmall as c_int;
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
+    }
+
+    VPNSEError::Success as c_int
+}
+
+/// Pick a VPN Gate server and build the configuration to connect to it
+///
+/// Filters the servers from [`vpnse_vpngate_fetch_servers`] by `criteria`,
+/// e.g. `{"countries":["JP","KR"],"max_ping_ms":100,"min_speed_bps":10000000,"max_sessions":50}`
+/// (every field optional), ranks the rest by speed, ping and load and writes
+/// the NUL-terminated TOML configuration for the `rank`-th best, ready for
+/// [`vpnse_client_new`]. Only built with the `vpngate` feature.
+///
+/// # Parameters
+/// - `servers_json`: Server list as written by `vpnse_vpngate_fetch_servers`
+/// - `criteria_json`: Selection criteria, or NULL to accept every server
+/// - `base_config`: TOML configuration whose non-server settings are kept
+/// - `rank`: 0 for the best server, 1 for the runner-up, ...
+/// - `buffer`: Buffer to store the TOML configuration
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`InvalidConfig` if fewer than `rank + 1` servers match)
+#[cfg(feature = "vpngate")]
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_vpngate_select(
+    servers_json: *const c_char,
+    criteria_json: *const c_char,
+    base_config: *const c_char,
+    rank: u32,
+    buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if servers_json.is_null() || base_config.is_null() || buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+    let servers: Vec<crate::vpngate::VpnGateServer> =
+        match CStr::from_ptr(servers_json).to_str().ok().and_then(|json| serde_json::from_str(json).ok()) {
+            Some(servers) => servers,
+            None => return VPNSEError::InvalidParameter as c_int,
+        };
+    let criteria: crate::vpngate::SelectionCriteria = if criteria_json.is_null() {
+        Default::default()
     } else {
-        1 // No tunnel established
+        match CStr::from_ptr(criteria_json).to_str().ok().and_then(|json| serde_json::from_str(json).ok()) {
+            Some(criteria) => criteria,
+            None => return VPNSEError::InvalidParameter as c_int,
+        }
+    };
+    let base: Config = match CStr::from_ptr(base_config).to_str() {
+        Ok(toml) => match toml.parse() {
+            Ok(config) => config,
+            Err(err) => return record_error(&err),
+        },
+        Err(_) => return VPNSEError::InvalidParameter as c_int,
+    };
+
+    let selected = crate::vpngate::select_servers(&servers, &criteria);
+    let toml = selected
+        .get(rank as usize)
+        .ok_or_else(|| VpnError::Config(format!("Only {} VPN Gate servers match the criteria", selected.len())))
+        .and_then(|server| server.to_config(&base))
+        .and_then(|config| config.to_toml());
+    let toml = match toml {
+        Ok(toml) => match CString::new(toml) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InternalError as c_int,
+        },
+        Err(err) => return record_error(&err),
+    };
+
+    let toml_bytes = toml.as_bytes_with_nul();
+    if toml_bytes.len() > buffer_len {
+        return VPNSEError::BufferTooSmall as c_int;
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(toml_bytes.as_ptr() as *const c_char, buffer, toml_bytes.len());
     }
+
+    VPNSEError::Success as c_int
 }
 
-/// Get tunnel remote IP address (gateway)
+/// Choose the uplink interface that carries the next connection
 ///
 /// # Parameters
 /// - `client`: VPN client instance
-/// - `ip_buffer`: Buffer to store the remote IP address
+/// - `interface_name`: Interface name from [`vpnse_list_interfaces`], or null for the OS default
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`NetworkError` if the interface is missing, down or has no IPv4 address)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_underlay_interface(
+    client: *mut VpnseClient,
+    interface_name: *const c_char,
+) -> c_int {
+    handles::with_client(client, |client| {
+        let name = if interface_name.is_null() {
+            None
+        } else {
+            match CStr::from_ptr(interface_name).to_str() {
+                Ok(s) => Some(s),
+                Err(_) => return VPNSEError::InvalidParameter as c_int,
+            }
+        };
+
+        match client.set_underlay_interface(name) {
+            Ok(()) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
+        }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Check the privileges needed to establish a tunnel
+///
+/// Writes a NUL-terminated JSON object such as
+/// `{"os":"linux","missing":[{"privilege":"net_admin","detail":"...","remediation":"..."}]}`.
+/// An empty `missing` array means the tunnel can be set up. Meant for
+/// onboarding flows that want to guide the user before connecting.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `buffer`: Buffer to store the JSON string
 /// - `buffer_len`: Size of the buffer
 ///
 /// # Returns
-/// - 0 on success, 1 if no tunnel established
-/// - Error code on failure
+/// - 0 on success
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_get_tunnel_remote_ip(
-    client: *mut VpnClient,
-    ip_buffer: *mut c_char,
+pub unsafe extern "C" fn vpnse_check_privileges(
+    client: *const VpnseClient,
+    buffer: *mut c_char,
     buffer_len: usize,
 ) -> c_int {
-    if client.is_null() || ip_buffer.is_null() || buffer_len == 0 {
+    if buffer.is_null() || buffer_len == 0 {
         return VPNSEError::InvalidParameter as c_int;
     }
 
-    if let Some((_, _, remote_ip, _)) = crate::tunnel::get_tunnel_interface() {
-        let ip_cstr = match CString::new(remote_ip) {
+    handles::with_client(client, |client| {
+        let json = match CString::new(client.check_privileges().to_json()) {
             Ok(s) => s,
-            Err(_) => return VPNSEError::InvalidParameter as c_int,
+            Err(_) => return VPNSEError::InternalError as c_int,
         };
 
-        let ip_bytes = ip_cstr.as_bytes_with_nul();
-        if ip_bytes.len() > buffer_len {
+        let json_bytes = json.as_bytes_with_nul();
+        if json_bytes.len() > buffer_len {
             return VPNSEError::BufferTooSmall as c_int;
         }
 
         unsafe {
-            ptr::copy_nonoverlapping(
-                ip_bytes.as_ptr() as *const c_char,
-                ip_buffer,
-                ip_bytes.len(),
-            );
+            ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
         }
 
         VPNSEError::Success as c_int
-    } else {
-        1 // No tunnel established
-    }
+    })
+    .unwrap_or_else(|e| e as c_int)
 }
 
-/// Get tunnel subnet information
+/// Collect a redacted support snapshot ("doctor" report)
+///
+/// Writes a NUL-terminated JSON document with the configuration origin,
+/// effective settings (credentials redacted), recent state transitions and
+/// events, routing/DNS snapshot, counters and environment facts.
 ///
 /// # Parameters
 /// - `client`: VPN client instance
-/// - `subnet_buffer`: Buffer to store the subnet (e.g., "10.0.0.2/24")
+/// - `buffer`: Buffer to store the JSON string
 /// - `buffer_len`: Size of the buffer
 ///
 /// # Returns
-/// - 0 on success, 1 if no tunnel established
-/// - Error code on failure
+/// - 0 on success
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
 #[no_mangle]
-pub unsafe extern "C" fn vpnse_get_tunnel_subnet(
-    client: *mut VpnClient,
-    subnet_buffer: *mut c_char,
+pub unsafe extern "C" fn vpnse_client_doctor(
+    client: *const VpnseClient,
+    buffer: *mut c_char,
     buffer_len: usize,
 ) -> c_int {
-    if client.is_null() || subnet_buffer.is_null() || buffer_len == 0 {
+    if buffer.is_null() || buffer_len == 0 {
         return VPNSEError::InvalidParameter as c_int;
     }
 
-    if let Some((_, _, _, subnet)) = crate::tunnel::get_tunnel_interface() {
-        let subnet_cstr = match CString::new(subnet) {
+    handles::with_client(client, |client| {
+        let json = match CString::new(client.doctor().to_json()) {
             Ok(s) => s,
-            Err(_) => return VPNSEError::InvalidParameter as c_int,
+            Err(_) => return VPNSEError::InternalError as c_int,
         };
 
-        let subnet_bytes = subnet_cstr.as_bytes_with_nul();
-        if subnet_bytes.len() > buffer_len {
+        let json_bytes = json.as_bytes_with_nul();
+        if json_bytes.len() > buffer_len {
             return VPNSEError::BufferTooSmall as c_int;
         }
 
         unsafe {
-            ptr::copy_nonoverlapping(
-                subnet_bytes.as_ptr() as *const c_char,
-                subnet_buffer,
-                subnet_bytes.len(),
-            );
+            ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
         }
 
         VPNSEError::Success as c_int
-    } else {
-        1 // No tunnel established
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Probe a server without connecting: latency, TLS details and version
+///
+/// Writes a NUL-terminated JSON object such as
+/// `{"address":"203.0.113.7:443","tcp_connect_ms":21,"tls_handshake_ms":48,"tls_version":"TLSv1_3",
+/// "cipher_suite":"TLS13_AES_256_GCM_SHA384","certificate_sha256":"..","watermark_ms":63,
+/// "server":{"product":"SoftEther VPN Server (64 bit)","version":438,..},"error":null}`.
+/// A server that accepted TCP but failed TLS or the watermark is still
+/// reported, with `error` set. The client's own connection is not touched.
+///
+/// # Parameters
+/// - `client`: VPN client instance, for its certificate policy and timeouts
+/// - `server`: Server hostname or IP address
+/// - `port`: Server port number
+/// - `buffer`: Buffer to store the JSON string
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (the server could not be reached, or `BufferTooSmall`)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_probe_server(
+    client: *const VpnseClient,
+    server: *const c_char,
+    port: u16,
+    buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if server.is_null() || buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+    let Ok(server) = CStr::from_ptr(server).to_str() else {
+        return VPNSEError::InvalidParameter as c_int;
+    };
+
+    handles::with_client(client, |client| {
+        let probe = match runtime().and_then(|rt| rt.block_on(client.probe_server(server, port))) {
+            Ok(probe) => probe,
+            Err(err) => return record_error(&err),
+        };
+        let json = match CString::new(probe.to_json()) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InternalError as c_int,
+        };
+
+        let json_bytes = json.as_bytes_with_nul();
+        if json_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
+        }
+
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Get throughput and packet rates over a recent window
+///
+/// Writes a NUL-terminated JSON object such as
+/// `{"since":{"unix_ms":..,"monotonic_ms":..},"until":{..},"interval_secs":5.0,"tx_mbps":12.4,...}`,
+/// or `null` until the client has an earlier sample inside the window. Every
+/// call records a sample, so polling this once per second is enough.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `window_ms`: How far back to compare, in milliseconds
+/// - `buffer`: Buffer to store the JSON string
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_rates(
+    client: *const VpnseClient,
+    window_ms: u32,
+    buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    handles::with_client(client, |client| {
+        let rates = client.rates_over(Duration::from_millis(u64::from(window_ms)));
+        let json = match CString::new(rates.map_or_else(|| "null".to_string(), |r| r.to_json())) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InternalError as c_int,
+        };
+
+        let json_bytes = json.as_bytes_with_nul();
+        if json_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
+        }
+
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Get traffic and session counters
+///
+/// Writes a NUL-terminated JSON object such as
+/// `{"bytes_sent":..,"bytes_received":..,"packets_sent":..,"packets_received":..,"uncompressed_bytes":..,
+/// "compressed_bytes":..,"uptime_ms":..,"reconnects":0,"rtt_ms":..,"nat_keepalive_interval_ms":..,
+/// "nat_timeout_ms":..}`.
+/// `uptime_ms` is `null` while not connected and `rtt_ms` until a keepalive
+/// got an answer from the hub gateway. The compression counters stay 0
+/// unless the session compresses data packets. The NAT keepalive fields are
+/// `null` without a UDP path, `nat_timeout_ms` until a NAT timeout was detected.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `buffer`: Buffer to store the JSON string
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_get_stats(
+    client: *const VpnseClient,
+    buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    handles::with_client(client, |client| {
+        let json = match CString::new(client.stats().to_json()) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InternalError as c_int,
+        };
+
+        let json_bytes = json.as_bytes_with_nul();
+        if json_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
+        }
+
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Get connection status
+///
+/// # Parameters
+/// - `client`: VPN client instance
+///
+/// # Returns
+/// - 0: Disconnected
+/// - 1: Connecting
+/// - 2: Connected (Protocol only)
+/// - 3: Tunnel established
+/// - -1: Error or invalid client
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_status(client: *const VpnseClient) -> c_int {
+    handles::with_client(client, |client| {
+        status_code(client.status())
+    })
+    .unwrap_or(-1)
+}
+
+fn status_code(status: ConnectionStatus) -> c_int {
+    match status {
+        ConnectionStatus::Disconnected => 0,
+        ConnectionStatus::Connecting => 1,
+        ConnectionStatus::Connected => 2,
+        ConnectionStatus::Tunneling => 3,
+    }
+}
+
+/// Establish VPN tunnel (routing layer)
+///
+/// This function attempts to create a TUN interface and configure routing
+/// to route traffic through the VPN tunnel.
+///
+/// # Parameters
+/// - `client`: VPN client instance (must be authenticated)
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_establish_tunnel(client: *mut VpnseClient) -> c_int {
+    handles::with_client(client, |client| {
+        // Packet forwarding runs on the library's runtime
+        let runtime = runtime().ok();
+        let _entered = runtime.as_ref().map(|runtime| runtime.enter());
+        match client.establish_tunnel() {
+            Ok(_) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
+        }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Get the interface configuration for a VPN interface the host app creates
+///
+/// Writes a NUL-terminated JSON object such as
+/// `{"address":"10.0.0.2","prefix_len":24,"gateway":"10.0.0.1","mtu":1500,"dns_servers":["10.0.0.1"],
+/// "dns_domain":null,"routes":["10.1.0.0/16"],"ipv6_address":null}`, from which mobile apps set up
+/// `VpnService.Builder` or `NEPacketTunnelNetworkSettings` before calling
+/// `vpnse_client_attach_tun_fd` or `vpnse_client_attach_packet_flow`. An
+/// empty `routes` list means routing everything through the tunnel.
+///
+/// # Parameters
+/// - `client`: VPN client instance (must be authenticated)
+/// - `buffer`: Buffer to store the JSON string
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`BufferTooSmall` if the JSON does not fit)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_tunnel_settings(
+    client: *mut VpnseClient,
+    buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    handles::with_client(client, |client| {
+        let settings = match client.host_tunnel_settings() {
+            Ok(settings) => settings,
+            Err(err) => return record_error(&err),
+        };
+        let json = match CString::new(settings.to_json()) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InternalError as c_int,
+        };
+
+        let json_bytes = json.as_bytes_with_nul();
+        if json_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(json_bytes.as_ptr() as *const c_char, buffer, json_bytes.len());
+        }
+
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Forward packets through a VPN interface the host app created (Android)
+///
+/// `fd` is the interface's descriptor (`ParcelFileDescriptor.getFd()`) and
+/// must carry bare IP packets. The library works on a duplicate, so the app
+/// keeps ownership of `fd`. No routes, DNS or firewall rules are changed.
+///
+/// # Parameters
+/// - `client`: VPN client instance (must be authenticated)
+/// - `fd`: File descriptor of the VPN interface
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`TunnelError` on platforms without descriptors)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_attach_tun_fd(client: *mut VpnseClient, fd: c_int) -> c_int {
+    if fd < 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    #[cfg(unix)]
+    {
+        // Packet forwarding runs on the library's runtime
+        let runtime = runtime().ok();
+        let _entered = runtime.as_ref().map(|runtime| runtime.enter());
+        handles::with_client(client, |client| match client.attach_tun_fd(fd) {
+            Ok(()) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
+        })
+        .unwrap_or_else(|e| e as c_int)
+    }
+    #[cfg(not(unix))]
+    {
+        match handles::get(client) {
+            Some(_) => VPNSEError::TunnelError as c_int,
+            None => VPNSEError::InvalidParameter as c_int,
+        }
+    }
+}
+
+/// Exchange tunnel packets with the host app directly (iOS)
+///
+/// Afterwards the app passes every packet read from its packet flow to
+/// `vpnse_client_write_inbound_packet` and writes what
+/// `vpnse_client_read_outbound_packet` returns back to it. No routes, DNS or
+/// firewall rules are changed. The flow closes with the session.
+///
+/// # Parameters
+/// - `client`: VPN client instance (must be authenticated)
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_attach_packet_flow(client: *mut VpnseClient) -> c_int {
+    handles::with_client(client, |client| {
+        let runtime = runtime().ok();
+        let _entered = runtime.as_ref().map(|runtime| runtime.enter());
+        match client.attach_packet_flow() {
+            Ok(_) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
+        }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Hand a packet from the host's network stack to the tunnel
+///
+/// Never blocks. When the queue towards the server is full the packet is
+/// dropped and `NetworkError` returned; the app can carry on.
+///
+/// # Parameters
+/// - `client`: VPN client instance with an attached packet flow
+/// - `packet`: IP packet
+/// - `packet_len`: Length of the packet
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`ConnectionFailed` once the flow is closed)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_write_inbound_packet(
+    client: *const VpnseClient,
+    packet: *const u8,
+    packet_len: usize,
+) -> c_int {
+    if packet.is_null() || packet_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    // The flow is used outside the client's lock, so packets keep moving
+    // while other calls run
+    let flow = match handles::with_client(client, |client| client.packet_flow()) {
+        Ok(Some(flow)) => flow,
+        Ok(None) => return VPNSEError::ConnectionFailed as c_int,
+        Err(e) => return e as c_int,
+    };
+    match flow.write_inbound(std::slice::from_raw_parts(packet, packet_len)) {
+        Ok(()) => VPNSEError::Success as c_int,
+        Err(err) => record_error(&err),
+    }
+}
+
+/// Take the next packet from the tunnel for the host's network stack
+///
+/// Waits up to `timeout_ms` for one. A packet longer than the buffer is
+/// truncated; a buffer of the tunnel MTU always suffices.
+///
+/// # Parameters
+/// - `client`: VPN client instance with an attached packet flow
+/// - `buffer`: Buffer receiving the IP packet
+/// - `buffer_len`: Size of the buffer
+/// - `timeout_ms`: How long to wait (0 returns at once)
+///
+/// # Returns
+/// - Length of the packet written to `buffer`
+/// - 0 if none arrived in time
+/// - A negated error code on failure (`-ConnectionFailed` once the flow is closed)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_read_outbound_packet(
+    client: *const VpnseClient,
+    buffer: *mut u8,
+    buffer_len: usize,
+    timeout_ms: u32,
+) -> c_int {
+    if buffer.is_null() || buffer_len == 0 {
+        return -(VPNSEError::InvalidParameter as c_int);
+    }
+
+    let flow = match handles::with_client(client, |client| client.packet_flow()) {
+        Ok(Some(flow)) => flow,
+        Ok(None) => return -(VPNSEError::ConnectionFailed as c_int),
+        Err(e) => return -(e as c_int),
+    };
+    let buffer = std::slice::from_raw_parts_mut(buffer, buffer_len.min(c_int::MAX as usize));
+    match flow.read_outbound(buffer, Duration::from_millis(u64::from(timeout_ms))) {
+        Ok(Some(len)) => len as c_int,
+        Ok(None) => 0,
+        Err(err) => -(record_error(&err)),
+    }
+}
+
+/// Establish a VPN tunnel
+///
+/// # Parameters
+/// - `client`: VPN client instance
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_tunnel_establish(client: *mut VpnseClient) -> c_int {
+    handles::with_client(client, |client| {
+        // Packet forwarding runs on the library's runtime
+        let runtime = runtime().ok();
+        let _entered = runtime.as_ref().map(|runtime| runtime.enter());
+        match client.establish_tunnel() {
+            Ok(_) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
+        }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Close the VPN tunnel
+///
+/// # Parameters
+/// - `client`: VPN client instance
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_tunnel_close(client: *mut VpnseClient) -> c_int {
+    handles::with_client(client, |client| {
+        match client.teardown_tunnel() {
+            Ok(_) => VPNSEError::Success as c_int,
+            Err(err) => record_error(&err),
+        }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Check that the hub's virtual gateway answers through the tunnel
+///
+/// Sends an ICMP echo to the gateway; nothing leaves the VPN.
+///
+/// # Parameters
+/// - `client`: VPN client instance with an established tunnel
+/// - `timeout_ms`: How long to wait for the reply
+/// - `rtt_ms`: Receives the round-trip time in milliseconds (nullable)
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_check_connection(
+    client: *const VpnseClient,
+    timeout_ms: u32,
+    rtt_ms: *mut u32,
+) -> c_int {
+    if timeout_ms == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    handles::with_client(client, |client| {
+        let runtime = match runtime() {
+            Ok(runtime) => runtime,
+            Err(_) => return VPNSEError::InternalError as c_int,
+        };
+
+        match runtime.block_on(client.check_hub_connectivity(Duration::from_millis(u64::from(timeout_ms)))) {
+            Ok(rtt) => {
+                if !rtt_ms.is_null() {
+                    *rtt_ms = u32::try_from(rtt.as_millis()).unwrap_or(u32::MAX);
+                }
+                VPNSEError::Success as c_int
+            }
+            Err(err) => record_error(&err),
+        }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Get current public IP address (for testing if traffic is routed through VPN)
+///
+/// Queries external services, so it only works when `public_ip.enabled` is
+/// set in the configuration; `vpnse_check_connection` is the default check.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `ip_buffer`: Buffer to store the IP address string
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_get_public_ip(
+    client: *mut VpnseClient,
+    ip_buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if ip_buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    handles::with_client(client, |client| {
+        match runtime().and_then(|rt| rt.block_on(client.get_current_public_ip())) {
+            Ok(ip) => {
+                let ip_cstr = match CString::new(ip) {
+                    Ok(s) => s,
+                    Err(_) => return VPNSEError::InvalidParameter as c_int,
+                };
+
+                let ip_bytes = ip_cstr.as_bytes_with_nul();
+                if ip_bytes.len() > buffer_len {
+                    return VPNSEError::BufferTooSmall as c_int;
+                }
+
+                unsafe {
+                    ptr::copy_nonoverlapping(
+                        ip_bytes.as_ptr() as *const c_char,
+                        ip_buffer,
+                        ip_bytes.len(),
+                    );
+                }
+
+                VPNSEError::Success as c_int
+            }
+            Err(err) => record_error(&err),
+        }
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Get tunnel interface name
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `interface_buffer`: Buffer to store the interface name
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success, 1 if no tunnel established
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_get_tunnel_interface(
+    client: *mut VpnseClient,
+    interface_buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if handles::get(client).is_none() || interface_buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    if let Some((interface_name, _, _, _)) = crate::tunnel::get_tunnel_interface() {
+        let interface_cstr = match CString::new(interface_name) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        };
+
+        let interface_bytes = interface_cstr.as_bytes_with_nul();
+        if interface_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                interface_bytes.as_ptr() as *const c_char,
+                interface_buffer,
+                interface_bytes.len(),
+            );
+        }
+
+        VPNSEError::Success as c_int
+    } else {
+        1 // No tunnel established
+    }
+}
+
+/// Get tunnel local IP address
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `ip_buffer`: Buffer to store the local IP address
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success, 1 if no tunnel established
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_get_tunnel_local_ip(
+    client: *mut VpnseClient,
+    ip_buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if handles::get(client).is_none() || ip_buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    if let Some((_, local_ip, _, _)) = crate::tunnel::get_tunnel_interface() {
+        let ip_cstr = match CString::new(local_ip) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        };
+
+        let ip_bytes = ip_cstr.as_bytes_with_nul();
+        if ip_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ip_bytes.as_ptr() as *const c_char,
+                ip_buffer,
+                ip_bytes.len(),
+            );
+        }
+
+        VPNSEError::Success as c_int
+    } else {
+        1 // No tunnel established
+    }
+}
+
+/// Get tunnel remote IP address (gateway)
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `ip_buffer`: Buffer to store the remote IP address
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success, 1 if no tunnel established
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_get_tunnel_remote_ip(
+    client: *mut VpnseClient,
+    ip_buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if handles::get(client).is_none() || ip_buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    if let Some((_, _, remote_ip, _)) = crate::tunnel::get_tunnel_interface() {
+        let ip_cstr = match CString::new(remote_ip) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        };
+
+        let ip_bytes = ip_cstr.as_bytes_with_nul();
+        if ip_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                ip_bytes.as_ptr() as *const c_char,
+                ip_buffer,
+                ip_bytes.len(),
+            );
+        }
+
+        VPNSEError::Success as c_int
+    } else {
+        1 // No tunnel established
+    }
+}
+
+/// Get tunnel subnet information
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `subnet_buffer`: Buffer to store the subnet (e.g., "10.0.0.2/24")
+/// - `buffer_len`: Size of the buffer
+///
+/// # Returns
+/// - 0 on success, 1 if no tunnel established
+/// - Error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_get_tunnel_subnet(
+    client: *mut VpnseClient,
+    subnet_buffer: *mut c_char,
+    buffer_len: usize,
+) -> c_int {
+    if handles::get(client).is_none() || subnet_buffer.is_null() || buffer_len == 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+
+    if let Some((_, _, _, subnet)) = crate::tunnel::get_tunnel_interface() {
+        let subnet_cstr = match CString::new(subnet) {
+            Ok(s) => s,
+            Err(_) => return VPNSEError::InvalidParameter as c_int,
+        };
+
+        let subnet_bytes = subnet_cstr.as_bytes_with_nul();
+        if subnet_bytes.len() > buffer_len {
+            return VPNSEError::BufferTooSmall as c_int;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                subnet_bytes.as_ptr() as *const c_char,
+                subnet_buffer,
+                subnet_bytes.len(),
+            );
+        }
+
+        VPNSEError::Success as c_int
+    } else {
+        1 // No tunnel established
+    }
+}
+
+/// Callback used to review planned system changes
+///
+/// Called once per planned change with its category (0 = route, 1 = DNS,
+/// 2 = kernel parameter, 3 = firewall) and a human readable description.
+/// Return non-zero to approve the change or 0 to veto it. A change whose
+/// description contains a NUL byte is vetoed without calling back.
+pub type VpnseChangeReviewCallback = Option<
+    unsafe extern "C" fn(category: c_int, description: *const c_char, user_data: *mut c_void) -> c_int,
+>;
+
+/// Adapts a C review callback to [`SystemChangePlanner`]
+struct FfiChangePlanner {
+    callback: unsafe extern "C" fn(c_int, *const c_char, *mut c_void) -> c_int,
+    user_data: usize,
+}
+
+// The host promises the callback and user data may be used from the thread
+// that establishes the tunnel.
+unsafe impl Send for FfiChangePlanner {}
+unsafe impl Sync for FfiChangePlanner {}
+
+impl SystemChangePlanner for FfiChangePlanner {
+    fn review(&self, plan: &mut ChangePlan) {
+        let mut vetoed = Vec::new();
+        for (index, item) in plan.items().iter().enumerate() {
+            let category = match item.change.category() {
+                ChangeCategory::Route => 0,
+                ChangeCategory::Dns => 1,
+                ChangeCategory::Kernel => 2,
+                ChangeCategory::Firewall => 3,
+            };
+            // The host cannot review what it cannot be shown
+            let Ok(description) = CString::new(item.change.to_string()) else {
+                vetoed.push((index, "description contains a NUL byte, not shown to the host application"));
+                continue;
+            };
+            let approved = unsafe {
+                (self.callback)(category, description.as_ptr(), self.user_data as *mut c_void)
+            };
+            if approved == 0 {
+                vetoed.push((index, "vetoed by host application"));
+            }
+        }
+        for (index, reason) in vetoed {
+            plan.veto(index, reason);
+        }
+    }
+}
+
+/// Register a callback that can veto system changes before they are applied
+///
+/// The callback runs synchronously on the thread that calls
+/// `vpnse_client_establish_tunnel` while the tunnel is being configured.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callback`: Review callback, or NULL to remove a previously set one
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_change_planner(
+    client: *mut VpnseClient,
+    callback: VpnseChangeReviewCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    handles::with_client(client, |client| {
+        match callback {
+            Some(callback) => client.set_change_planner(Arc::new(FfiChangePlanner {
+                callback,
+                user_data: user_data as usize,
+            })),
+            None => client.clear_change_planner(),
+        }
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Callback receiving authentication failure events
+///
+/// `reason` is 0 = invalid credentials, 1 = network, 2 = timeout,
+/// 3 = protocol, 4 = locally locked out. `retry_after_secs` is the time until
+/// the next attempt is allowed (0 when not locked out).
+pub type VpnseAuthFailureCallback = Option<
+    unsafe extern "C" fn(reason: c_int, attempt: u32, retry_after_secs: u64, user_data: *mut c_void),
+>;
+
+/// Register a callback for authentication failure events
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callback`: Event callback, or NULL to remove a previously set one
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_auth_failure_callback(
+    client: *mut VpnseClient,
+    callback: VpnseAuthFailureCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    handles::with_client(client, |client| {
+        let handler = callback.map(|callback| {
+            let user_data = user_data as usize;
+            Arc::new(move |failure: &AuthFailure| {
+                let reason = match failure.reason {
+                    AuthFailureReason::InvalidCredentials => 0,
+                    AuthFailureReason::Network => 1,
+                    AuthFailureReason::Timeout => 2,
+                    AuthFailureReason::Protocol => 3,
+                    AuthFailureReason::LockedOut => 4,
+                };
+                unsafe {
+                    callback(
+                        reason,
+                        failure.attempt,
+                        failure.retry_after().as_secs(),
+                        user_data as *mut c_void,
+                    )
+                };
+            }) as crate::auth_throttle::AuthFailureHandler
+        });
+        client.set_auth_failure_handler(handler);
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Callback supplying a one-time password
+///
+/// `message` is the server's prompt or NULL. Writes the NUL-terminated code
+/// into `otp` (`otp_len` bytes) and returns 0, or returns non-zero to cancel
+/// the login. Runs on a worker thread and may block, e.g. on a dialog.
+pub type VpnseOtpCallback = Option<
+    unsafe extern "C" fn(
+        username: *const c_char,
+        hub: *const c_char,
+        message: *const c_char,
+        attempt: u32,
+        otp: *mut c_char,
+        otp_len: usize,
+        user_data: *mut c_void,
+    ) -> c_int,
+>;
+
+/// Room for the code handed to [`VpnseOtpCallback`]
+const FFI_OTP_LEN: usize = 256;
+
+/// Adapts [`VpnseOtpCallback`] to [`OtpProvider`]
+#[derive(Clone, Copy)]
+struct FfiOtpProvider {
+    callback: VpnseOtpCallback,
+    user_data: usize,
+}
+
+impl FfiOtpProvider {
+    /// Ask the host for a code, blocking the calling thread
+    fn ask(&self, challenge: &OtpChallenge) -> Option<String> {
+        let callback = self.callback?;
+        let username = CString::new(challenge.username.as_str()).ok()?;
+        let hub = CString::new(challenge.hub.as_str()).ok()?;
+        let message = challenge.message.as_deref().map(CString::new).transpose().ok()?;
+        let mut otp = vec![0u8; FFI_OTP_LEN];
+        let code = unsafe {
+            callback(
+                username.as_ptr(),
+                hub.as_ptr(),
+                message.as_ref().map_or(ptr::null(), |message| message.as_ptr()),
+                challenge.attempt,
+                otp.as_mut_ptr() as *mut c_char,
+                otp.len(),
+                self.user_data as *mut c_void,
+            )
+        };
+        if code != 0 {
+            return None;
+        }
+        let otp = CStr::from_bytes_until_nul(&otp).ok()?;
+        otp.to_str().ok().map(str::to_string)
+    }
+}
+
+impl OtpProvider for FfiOtpProvider {
+    fn one_time_password(&self, challenge: OtpChallenge) -> BoxFuture<'static, Option<String>> {
+        let provider = *self;
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || provider.ask(&challenge)).await.ok().flatten()
+        })
+    }
+}
+
+/// Register a callback supplying one-time passwords to hubs with a second factor
+///
+/// Without one, logins to such hubs fail. The callback must answer within
+/// `auth.otp_timeout_secs`.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callback`: OTP callback, or NULL to remove a previously set one
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_otp_callback(
+    client: *mut VpnseClient,
+    callback: VpnseOtpCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    handles::with_client(client, |client| {
+        let provider = callback.is_some().then(|| {
+            Arc::new(FfiOtpProvider { callback, user_data: user_data as usize }) as Arc<dyn OtpProvider>
+        });
+        client.set_otp_provider(provider);
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Limit tunnel traffic, e.g. on a metered connection
+///
+/// Applies from the next packet, also while tunneling, and stays in force
+/// across reconnects.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `upload_bps`: Upload limit in bits per second, 0 for unlimited
+/// - `download_bps`: Download limit in bits per second, 0 for unlimited
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_rate_limit(client: *mut VpnseClient, upload_bps: u64, download_bps: u64) -> c_int {
+    handles::with_client(client, |client| {
+        client.set_rate_limit(upload_bps, download_bps);
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Size of one address in the DNS server arrays
+const DNS_ADDRESS_LEN: usize = 16;
+
+/// Replace the tunnel's DNS servers without reconnecting
+///
+/// Addresses are packed back to back as 16 bytes each in network order;
+/// IPv4 servers are written IPv4-mapped (`::ffff:a.b.c.d`). Takes effect
+/// immediately while tunneling, otherwise at the next tunnel establishment.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `addresses`: `count * 16` bytes of addresses (may be NULL when `count` is 0)
+/// - `count`: Number of addresses; 0 restores the default servers
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (`TunnelError` if the update was vetoed or could not be applied)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_dns_servers(
+    client: *mut VpnseClient,
+    addresses: *const u8,
+    count: usize,
+) -> c_int {
+    if addresses.is_null() && count > 0 {
+        return VPNSEError::InvalidParameter as c_int;
+    }
+    let Some(len) = count.checked_mul(DNS_ADDRESS_LEN) else {
+        return VPNSEError::InvalidParameter as c_int;
+    };
+
+    let servers = if count == 0 {
+        Vec::new()
+    } else {
+        std::slice::from_raw_parts(addresses, len)
+            .chunks_exact(DNS_ADDRESS_LEN)
+            .map(|chunk| {
+                let octets: [u8; DNS_ADDRESS_LEN] = chunk.try_into().unwrap();
+                Ipv6Addr::from(octets).to_canonical()
+            })
+            .collect()
+    };
+
+    handles::with_client(client, |client| match client.set_dns_servers(servers) {
+        Ok(()) => VPNSEError::Success as c_int,
+        Err(err) => record_error(&err),
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Turn the kill switch on or off
+///
+/// While on, the firewall drops all traffic except through the tunnel and to
+/// the VPN server, from the moment the tunnel is up (immediately if it
+/// already is) until [`vpnse_client_disconnect`]. The rules stay while the
+/// session is lost or reconnecting.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `enable`: Non-zero to turn the kill switch on, 0 to turn it off (removing its rules)
+///
+/// # Returns
+/// - 0 on success
+/// - Error code on failure (the firewall rules could not be installed or removed)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_enable_killswitch(client: *mut VpnseClient, enable: c_int) -> c_int {
+    handles::with_client(client, |client| match client.enable_kill_switch(enable != 0) {
+        Ok(()) => VPNSEError::Success as c_int,
+        Err(err) => record_error(&err),
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Callback receiving DNS update events
+///
+/// `addresses` holds `count` packed 16-byte addresses in the same format as
+/// [`vpnse_client_set_dns_servers`] and is only valid during the call.
+/// `applied` is 1 if the platform resolver was reconfigured, 0 if the servers
+/// wait for the next tunnel establishment.
+pub type VpnseDnsUpdatedCallback = Option<
+    unsafe extern "C" fn(addresses: *const u8, count: usize, applied: c_int, user_data: *mut c_void),
+>;
+
+/// Register a callback for DNS update events
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callback`: Event callback, or NULL to remove a previously set one
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_dns_updated_callback(
+    client: *mut VpnseClient,
+    callback: VpnseDnsUpdatedCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    handles::with_client(client, |client| {
+        let handler = callback.map(|callback| {
+            let user_data = user_data as usize;
+            Arc::new(move |event: &DnsUpdated| {
+                let packed: Vec<u8> = event
+                    .servers
+                    .iter()
+                    .flat_map(|server| match server {
+                        IpAddr::V4(v4) => v4.to_ipv6_mapped().octets(),
+                        IpAddr::V6(v6) => v6.octets(),
+                    })
+                    .collect();
+                unsafe {
+                    callback(
+                        packed.as_ptr(),
+                        event.servers.len(),
+                        c_int::from(event.applied),
+                        user_data as *mut c_void,
+                    )
+                };
+            }) as DnsUpdateHandler
+        });
+        client.events().on_dns_update(handler);
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Callback receiving connection status changes
+///
+/// `from` and `to` use the codes of [`vpnse_client_status`]. Like the other
+/// event callbacks it runs on the thread that caused the change (a runtime
+/// worker for async calls) and must return quickly; it must not free the
+/// client.
+pub type VpnseStateChangeCallback =
+    Option<unsafe extern "C" fn(from: c_int, to: c_int, user_data: *mut c_void)>;
+
+/// Callback receiving errors
+///
+/// `code` is a [`VPNSEError`] value. `context` names what failed (`connect`,
+/// `authenticate`, `tunnel`, `forwarding`, `session`, `dns`); both strings are
+/// only valid during the call.
+pub type VpnseErrorCallback = Option<
+    unsafe extern "C" fn(code: c_int, context: *const c_char, message: *const c_char, user_data: *mut c_void),
+>;
+
+/// Callback receiving reconnection progress
+///
+/// `phase` is 0 = attempting, 1 = succeeded, 2 = failed. `retry_after_ms` is
+/// the delay before another attempt is possible (0 if unknown or not failed).
+pub type VpnseReconnectCallback =
+    Option<unsafe extern "C" fn(phase: c_int, attempt: u32, retry_after_ms: u64, user_data: *mut c_void)>;
+
+/// Callback receiving traffic counters while tunneling
+///
+/// Called from a runtime timer thread, not the thread that registered it.
+pub type VpnseStatsCallback = Option<
+    unsafe extern "C" fn(
+        bytes_sent: u64,
+        bytes_received: u64,
+        packets_sent: u64,
+        packets_received: u64,
+        user_data: *mut c_void,
+    ),
+>;
+
+/// Register a callback for connection status changes
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callback`: Event callback, or NULL to remove a previously set one
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_state_change_callback(
+    client: *mut VpnseClient,
+    callback: VpnseStateChangeCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let handler = callback.map(|callback| {
+        let user_data = user_data as usize;
+        Arc::new(move |change: &StateChange| unsafe {
+            callback(status_code(change.from), status_code(change.to), user_data as *mut c_void)
+        }) as StateChangeHandler
+    });
+    handles::with_client(client, |client| {
+        client.events().on_state_change(handler);
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Register a callback for errors while connecting or tunneling
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callback`: Event callback, or NULL to remove a previously set one
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_error_callback(
+    client: *mut VpnseClient,
+    callback: VpnseErrorCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let handler = callback.map(|callback| {
+        let user_data = user_data as usize;
+        Arc::new(move |event: &ErrorEvent<'_>| {
+            let context = CString::new(event.context).unwrap_or_default();
+            let message = CString::new(event.error.to_string().replace('\0', " ")).unwrap_or_default();
+            unsafe {
+                callback(
+                    VPNSEError::from(event.error) as c_int,
+                    context.as_ptr(),
+                    message.as_ptr(),
+                    user_data as *mut c_void,
+                )
+            };
+        }) as ErrorHandler
+    });
+    handles::with_client(client, |client| {
+        client.events().on_error(handler);
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Register a callback for reconnection and cluster failover progress
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callback`: Event callback, or NULL to remove a previously set one
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_reconnect_callback(
+    client: *mut VpnseClient,
+    callback: VpnseReconnectCallback,
+    user_data: *mut c_void,
+) -> c_int {
+    let handler = callback.map(|callback| {
+        let user_data = user_data as usize;
+        Arc::new(move |event: &ReconnectEvent| {
+            let phase = match event.phase {
+                ReconnectPhase::Attempting => 0,
+                ReconnectPhase::Succeeded => 1,
+                ReconnectPhase::Failed => 2,
+            };
+            let retry_after_ms = event.retry_in.map_or(0, |delay| delay.as_millis() as u64);
+            unsafe { callback(phase, event.attempt, retry_after_ms, user_data as *mut c_void) };
+        }) as ReconnectHandler
+    });
+    handles::with_client(client, |client| {
+        client.events().on_reconnect(handler);
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// Register a callback receiving traffic counters every `interval_ms` while tunneling
+///
+/// Intervals below 100 ms are raised to 100 ms. Takes effect from the next
+/// time the tunnel comes up.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callback`: Event callback, or NULL to remove a previously set one
+/// - `interval_ms`: Time between two callbacks
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_stats_callback(
+    client: *mut VpnseClient,
+    callback: VpnseStatsCallback,
+    interval_ms: u32,
+    user_data: *mut c_void,
+) -> c_int {
+    let handler = callback.map(|callback| {
+        let user_data = user_data as usize;
+        Arc::new(move |snapshot: &PerformanceSnapshot| unsafe {
+            callback(
+                snapshot.bytes_sent,
+                snapshot.bytes_received,
+                snapshot.packets_sent,
+                snapshot.packets_received,
+                user_data as *mut c_void,
+            )
+        }) as StatsHandler
+    });
+    handles::with_client(client, |client| {
+        client.events().on_stats(handler, Duration::from_millis(u64::from(interval_ms)));
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+/// TLS implemented by the host application, see `vpnse_client_set_tls_provider`
+///
+/// `connect` gets the connected TCP socket in blocking mode (a file
+/// descriptor, or a `SOCKET` on Windows) and the server name, runs the
+/// handshake on it and returns the session, or NULL on failure. The other
+/// callbacks get that session back. `read` and `write` block and return the
+/// bytes transferred, 0 at the end of the stream or a negative value on
+/// error; they are called from two threads at once. `peer_certificate`
+/// (optional) copies the server's DER certificate into `buffer` and returns
+/// its full length, or a negative value if unknown. `close` releases the
+/// session; the library closes the socket afterwards.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct VpnseTlsCallbacks {
+    pub connect: Option<unsafe extern "C" fn(socket: i64, server_name: *const c_char, user_data: *mut c_void) -> *mut c_void>,
+    pub read: Option<unsafe extern "C" fn(session: *mut c_void, buffer: *mut u8, len: usize, user_data: *mut c_void) -> isize>,
+    pub write: Option<unsafe extern "C" fn(session: *mut c_void, data: *const u8, len: usize, user_data: *mut c_void) -> isize>,
+    pub peer_certificate:
+        Option<unsafe extern "C" fn(session: *mut c_void, buffer: *mut u8, len: usize, user_data: *mut c_void) -> isize>,
+    pub close: Option<unsafe extern "C" fn(session: *mut c_void, user_data: *mut c_void)>,
+}
+
+/// Bytes moved per blocking `read`/`write` call
+const FFI_TLS_CHUNK: usize = 16 * 1024;
+
+/// Adapts [`VpnseTlsCallbacks`] to [`TlsProvider`]
+struct FfiTlsProvider {
+    callbacks: VpnseTlsCallbacks,
+    user_data: usize,
+}
+
+// The host promises the callbacks and user data may be used from any thread.
+unsafe impl Send for FfiTlsProvider {}
+unsafe impl Sync for FfiTlsProvider {}
+
+impl TlsProvider for FfiTlsProvider {
+    fn name(&self) -> &str {
+        "host application"
+    }
+
+    fn connect<'a>(
+        &'a self,
+        stream: tokio::net::TcpStream,
+        server_name: &'a str,
+        _certificates: &'a CertificatePolicy,
+    ) -> BoxFuture<'a, crate::error::Result<Box<dyn TlsStream>>> {
+        Box::pin(async move {
+            let socket = stream.into_std()?;
+            socket.set_nonblocking(false)?;
+            let name = CString::new(server_name)
+                .map_err(|_| VpnError::Config(format!("Invalid server name '{server_name}'")))?;
+            let (callbacks, user_data) = (self.callbacks, self.user_data);
+            let session = tokio::task::spawn_blocking(move || {
+                let connect = callbacks.connect?;
+                let session = unsafe { connect(raw_socket(&socket), name.as_ptr(), user_data as *mut c_void) };
+                (!session.is_null()).then(|| FfiTlsSession { callbacks, user_data, session: session as usize, socket })
+            })
+            .await
+            .map_err(|e| VpnError::Network(format!("TLS provider failed: {e}")))?
+            .ok_or_else(|| VpnError::Network("TLS handshake in the host application failed".into()))?;
+            Ok(Box::new(FfiTlsStream::start(Arc::new(session))) as Box<dyn TlsStream>)
+        })
+    }
+}
+
+#[cfg(unix)]
+fn raw_socket(socket: &std::net::TcpStream) -> i64 {
+    use std::os::unix::io::AsRawFd;
+    i64::from(socket.as_raw_fd())
+}
+
+#[cfg(windows)]
+fn raw_socket(socket: &std::net::TcpStream) -> i64 {
+    use std::os::windows::io::AsRawSocket;
+    socket.as_raw_socket() as i64
+}
+
+/// Session returned by the host's `connect`, closed when the last user lets go
+struct FfiTlsSession {
+    callbacks: VpnseTlsCallbacks,
+    user_data: usize,
+    session: usize,
+    socket: std::net::TcpStream,
+}
+
+impl FfiTlsSession {
+    fn read(&self, buffer: &mut [u8]) -> isize {
+        match self.callbacks.read {
+            Some(read) => unsafe {
+                read(self.session as *mut c_void, buffer.as_mut_ptr(), buffer.len(), self.user_data as *mut c_void)
+            },
+            None => -1,
+        }
+    }
+
+    fn write_all(&self, mut data: &[u8]) -> bool {
+        let Some(write) = self.callbacks.write else { return false };
+        while !data.is_empty() {
+            let written = unsafe {
+                write(self.session as *mut c_void, data.as_ptr(), data.len(), self.user_data as *mut c_void)
+            };
+            if written <= 0 {
+                return false;
+            }
+            data = &data[(written as usize).min(data.len())..];
+        }
+        true
+    }
+
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        let peer_certificate = self.callbacks.peer_certificate?;
+        let mut certificate = vec![0u8; 4096];
+        loop {
+            let len = unsafe {
+                peer_certificate(
+                    self.session as *mut c_void,
+                    certificate.as_mut_ptr(),
+                    certificate.len(),
+                    self.user_data as *mut c_void,
+                )
+            };
+            let len = usize::try_from(len).ok()?;
+            if len <= certificate.len() {
+                certificate.truncate(len);
+                return Some(certificate);
+            }
+            certificate.resize(len, 0);
+        }
+    }
+}
+
+impl Drop for FfiTlsSession {
+    fn drop(&mut self) {
+        if let Some(close) = self.callbacks.close {
+            unsafe { close(self.session as *mut c_void, self.user_data as *mut c_void) };
+        }
+    }
+}
+
+/// Async side of a host TLS session, pumped by two blocking threads
+struct FfiTlsStream {
+    inner: tokio::io::DuplexStream,
+    certificate: Option<Vec<u8>>,
+    session: Arc<FfiTlsSession>,
+}
+
+impl FfiTlsStream {
+    fn start(session: Arc<FfiTlsSession>) -> Self {
+        let certificate = session.peer_certificate();
+        let (inner, pumped) = tokio::io::duplex(FFI_TLS_CHUNK * 4);
+        let (mut from_client, mut to_client) = tokio::io::split(pumped);
+        let handle = tokio::runtime::Handle::current();
+
+        let reader = session.clone();
+        let reader_handle = handle.clone();
+        tokio::task::spawn_blocking(move || {
+            use tokio::io::AsyncWriteExt;
+            let mut buffer = vec![0u8; FFI_TLS_CHUNK];
+            loop {
+                let len = reader.read(&mut buffer);
+                if len <= 0 {
+                    break;
+                }
+                let data = &buffer[..(len as usize).min(buffer.len())];
+                if reader_handle.block_on(to_client.write_all(data)).is_err() {
+                    break;
+                }
+            }
+            let _ = reader_handle.block_on(to_client.shutdown());
+        });
+
+        let writer = session.clone();
+        tokio::task::spawn_blocking(move || {
+            use tokio::io::AsyncReadExt;
+            let mut buffer = vec![0u8; FFI_TLS_CHUNK];
+            while let Ok(len @ 1..) = handle.block_on(from_client.read(&mut buffer)) {
+                if !writer.write_all(&buffer[..len]) {
+                    break;
+                }
+            }
+            // There is no callback for close_notify; the server sees the end of the stream
+            let _ = writer.socket.shutdown(std::net::Shutdown::Write);
+        });
+
+        Self { inner, certificate, session }
+    }
+}
+
+impl Drop for FfiTlsStream {
+    fn drop(&mut self) {
+        // Wakes the thread blocked in the host's read so the session can close
+        let _ = self.session.socket.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+impl tokio::io::AsyncRead for FfiTlsStream {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for FfiTlsStream {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl TlsStream for FfiTlsStream {
+    fn peer_certificate(&self) -> Option<Vec<u8>> {
+        self.certificate.clone()
+    }
+}
+
+/// Let the host application provide TLS to the server
+///
+/// The handshake, encryption and chain verification happen in the
+/// callbacks; `tls.pinned_certificates` are still checked against what
+/// `peer_certificate` reports. Applies from the next connect.
+///
+/// # Parameters
+/// - `client`: VPN client instance
+/// - `callbacks`: Provider callbacks (copied), or NULL to go back to the built-in TLS
+/// - `user_data`: Opaque pointer passed back to every callback invocation
+///
+/// # Returns
+/// - 0 on success, error code on failure (`connect`, `read`, `write` and
+///   `close` are required)
+#[no_mangle]
+pub unsafe extern "C" fn vpnse_client_set_tls_provider(
+    client: *mut VpnseClient,
+    callbacks: *const VpnseTlsCallbacks,
+    user_data: *mut c_void,
+) -> c_int {
+    handles::with_client(client, |client| {
+        match callbacks.as_ref() {
+            Some(callbacks) => {
+                if callbacks.connect.is_none()
+                    || callbacks.read.is_none()
+                    || callbacks.write.is_none()
+                    || callbacks.close.is_none()
+                {
+                    return VPNSEError::InvalidParameter as c_int;
+                }
+                client.set_tls_provider(Some(Arc::new(FfiTlsProvider {
+                    callbacks: *callbacks,
+                    user_data: user_data as usize,
+                })));
+            }
+            None => client.set_tls_provider(None),
+        }
+        VPNSEError::Success as c_int
+    })
+    .unwrap_or_else(|e| e as c_int)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::SystemChange;
+    use std::sync::mpsc;
+
+    unsafe extern "C" fn send_result(result: c_int, user_data: *mut c_void) {
+        // The test may drop its sender as soon as the result arrives, while
+        // `send` is still returning; a clone keeps the channel alive
+        let sender = (*(user_data as *const mpsc::Sender<c_int>)).clone();
+        let _ = sender.send(result);
+    }
+
+    fn new_client() -> *mut VpnseClient {
+        handles::insert(VpnClient::new(Config::default_test()).unwrap())
+    }
+
+    /// Held by tests with async calls in flight, which `vpnse_shutdown` would cancel
+    static GLOBAL_STATE: Mutex<()> = Mutex::new(());
+
+    fn lock_global_state() -> std::sync::MutexGuard<'static, ()> {
+        GLOBAL_STATE.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    #[test]
+    fn test_probe_server_needs_a_reachable_server() {
+        let client = new_client();
+        let mut buffer = [0 as c_char; 1024];
+        let code = unsafe { vpnse_probe_server(client, ptr::null(), 443, buffer.as_mut_ptr(), buffer.len()) };
+        assert_eq!(code, VPNSEError::InvalidParameter as c_int);
+
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server = CString::new("127.0.0.1").unwrap();
+        let port = closed.port();
+        let code = unsafe { vpnse_probe_server(client, server.as_ptr(), port, buffer.as_mut_ptr(), buffer.len()) };
+        assert_ne!(code, VPNSEError::Success as c_int);
+
+        unsafe { vpnse_client_free(client) };
+    }
+
+    #[test]
+    fn test_connect_async_reports_failure_through_callback() {
+        let _global = lock_global_state();
+        let client = new_client();
+        let (tx, rx) = mpsc::channel::<c_int>();
+        let server = CString::new("not an address").unwrap();
+
+        let code = unsafe {
+            vpnse_client_connect_async(
+                client,
+                server.as_ptr(),
+                443,
+                Some(send_result),
+                &tx as *const _ as *mut c_void,
+            )
+        };
+        assert_eq!(code, VPNSEError::Success as c_int);
+        let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(result, VPNSEError::InvalidConfig as c_int);
+        assert_eq!(unsafe { vpnse_client_connect_cancel(client) }, VPNSEError::InvalidParameter as c_int);
+
+        unsafe { vpnse_client_free(client) };
+    }
+
+    #[test]
+    fn test_authenticate_async_reports_failure_through_callback() {
+        let _global = lock_global_state();
+        let client = new_client();
+        let (tx, rx) = mpsc::channel::<c_int>();
+        let username = CString::new("user").unwrap();
+        let password = CString::new("pass").unwrap();
+        let (username, password) = (username.as_ptr(), password.as_ptr());
+        let user_data = &tx as *const _ as *mut c_void;
+
+        unsafe {
+            assert_eq!(
+                vpnse_client_authenticate_async(client, username, ptr::null(), Some(send_result), user_data),
+                VPNSEError::InvalidParameter as c_int
+            );
+            assert_eq!(
+                vpnse_client_authenticate_async(client, username, password, None, user_data),
+                VPNSEError::InvalidParameter as c_int
+            );
+            // Not connected
+            assert_eq!(
+                vpnse_client_authenticate_async(client, username, password, Some(send_result), user_data),
+                VPNSEError::Success as c_int
+            );
+        }
+        let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(result, VPNSEError::ConnectionFailed as c_int);
+        assert!(rx.try_recv().is_err());
+
+        unsafe { vpnse_client_free(client) };
+    }
+
+    #[test]
+    fn test_cluster_node_pinning() {
+        // Nothing listens on this port once the listener is dropped
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut config = Config::default_test();
+        config.clustering.enabled = true;
+        config.clustering.cluster_nodes = vec!["127.0.0.1:1".into(), closed.to_string().into()];
+        let client = handles::insert(VpnClient::new(config).unwrap());
+        let mut buffer = vec![0 as c_char; 2048];
+
+        unsafe {
+            assert_eq!(vpnse_client_connect_cluster_node(client, 2), VPNSEError::InvalidParameter as c_int);
+            assert_ne!(vpnse_client_connect_cluster_node(client, 1), VPNSEError::Success as c_int);
+            assert_eq!(
+                vpnse_client_cluster_nodes(client, buffer.as_mut_ptr(), buffer.len()),
+                VPNSEError::Success as c_int
+            );
+            assert_eq!(vpnse_client_cluster_nodes(client, buffer.as_mut_ptr(), 8), VPNSEError::BufferTooSmall as c_int);
+        }
+
+        let json = unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap();
+        let nodes: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(nodes.as_array().unwrap().len(), 2);
+        assert_eq!(nodes[1]["address"], closed.to_string());
+        assert_eq!(nodes[1]["pinned"], true);
+        assert_eq!(nodes[0]["pinned"], false);
+        assert!(nodes[1]["last_failure"].is_string());
+        assert!(nodes[1]["blacklisted_for_ms"].as_u64().unwrap() > 0);
+
+        unsafe { vpnse_client_free(client) };
+
+        // Without clustering there is nothing to pick from
+        let client = new_client();
+        unsafe {
+            assert_eq!(vpnse_client_connect_cluster_auto(client), VPNSEError::InvalidConfig as c_int);
+            assert_eq!(
+                vpnse_client_cluster_nodes(client, buffer.as_mut_ptr(), buffer.len()),
+                VPNSEError::Success as c_int
+            );
+            assert_eq!(CStr::from_ptr(buffer.as_ptr()).to_str().unwrap(), "[]");
+            vpnse_client_free(client);
+        }
+    }
+
+    #[test]
+    fn test_connect_async_can_be_cancelled() {
+        let _global = lock_global_state();
+        // Accepts the TCP connection but never answers the TLS handshake
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _accepted = std::thread::spawn(move || listener.accept());
+
+        let client = new_client();
+        let (tx, rx) = mpsc::channel::<c_int>();
+        let server = CString::new("127.0.0.1").unwrap();
+        let user_data = &tx as *const _ as *mut c_void;
+
+        unsafe {
+            assert_eq!(
+                vpnse_client_connect_async(client, server.as_ptr(), port, Some(send_result), user_data),
+                VPNSEError::Success as c_int
+            );
+            assert_eq!(
+                vpnse_client_connect_async(client, server.as_ptr(), port, Some(send_result), user_data),
+                VPNSEError::InProgress as c_int
+            );
+            let user = CString::new("user").unwrap();
+            assert_eq!(
+                vpnse_client_authenticate_async(client, user.as_ptr(), user.as_ptr(), Some(send_result), user_data),
+                VPNSEError::InProgress as c_int
+            );
+            assert_eq!(vpnse_client_connect_cancel(client), VPNSEError::Success as c_int);
+        }
+
+        let result = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(result, VPNSEError::Cancelled as c_int);
+        assert_eq!(unsafe { vpnse_client_status(client) }, 0);
+
+        unsafe { vpnse_client_free(client) };
+    }
+
+    unsafe extern "C" fn record_dns(addresses: *const u8, count: usize, applied: c_int, user_data: *mut c_void) {
+        let sender = &*(user_data as *const mpsc::Sender<(Vec<u8>, c_int)>);
+        let bytes = std::slice::from_raw_parts(addresses, count * DNS_ADDRESS_LEN).to_vec();
+        let _ = sender.send((bytes, applied));
+    }
+
+    #[test]
+    fn test_set_rate_limit() {
+        let client = new_client();
+        unsafe {
+            assert_eq!(vpnse_client_set_rate_limit(ptr::null_mut(), 1, 1), VPNSEError::InvalidParameter as c_int);
+            assert_eq!(vpnse_client_set_rate_limit(client, 2_000_000, 0), VPNSEError::Success as c_int);
+            assert_eq!(handles::with_client(client, |client| client.rate_limit()).ok(), Some((2_000_000, 0)));
+            vpnse_client_free(client);
+        }
+    }
+
+    #[test]
+    fn test_set_dns_servers_round_trip() {
+        let client = new_client();
+        let (tx, rx) = mpsc::channel::<(Vec<u8>, c_int)>();
+        let user_data = &tx as *const _ as *mut c_void;
+
+        let mut packed = Vec::new();
+        packed.extend(std::net::Ipv4Addr::new(9, 9, 9, 9).to_ipv6_mapped().octets());
+        packed.extend("2620:fe::fe".parse::<Ipv6Addr>().unwrap().octets());
+
+        unsafe {
+            assert_eq!(
+                vpnse_client_set_dns_updated_callback(client, Some(record_dns), user_data),
+                VPNSEError::Success as c_int
+            );
+            assert_eq!(vpnse_client_set_dns_servers(client, packed.as_ptr(), 2), VPNSEError::Success as c_int);
+            assert_eq!(
+                vpnse_client_set_dns_servers(client, ptr::null(), 1),
+                VPNSEError::InvalidParameter as c_int
+            );
+        }
+
+        // Not tunneling yet: remembered for the next establishment
+        assert_eq!(rx.try_recv().unwrap(), (packed, 0));
+        assert!(rx.try_recv().is_err());
+
+        unsafe { vpnse_client_free(client) };
+    }
+
+    #[test]
+    fn test_get_stats_json() {
+        let client = new_client();
+        let mut buffer = vec![0 as c_char; 512];
+        unsafe {
+            assert_eq!(
+                vpnse_client_get_stats(client, buffer.as_mut_ptr(), buffer.len()),
+                VPNSEError::Success as c_int
+            );
+            assert_eq!(vpnse_client_get_stats(client, buffer.as_mut_ptr(), 8), VPNSEError::BufferTooSmall as c_int);
+        }
+
+        let json = unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap();
+        let stats: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(stats["bytes_sent"], 0);
+        assert_eq!(stats["reconnects"], 0);
+        assert!(stats["uptime_ms"].is_null());
+
+        unsafe { vpnse_client_free(client) };
+    }
+
+    #[test]
+    fn test_change_planner_vetoes_undescribable_changes() {
+        unsafe extern "C" fn approve_all(_: c_int, _description: *const c_char, user_data: *mut c_void) -> c_int {
+            *(user_data as *mut u32) += 1;
+            1
+        }
+
+        let mut calls = 0u32;
+        let planner = FfiChangePlanner { callback: approve_all, user_data: &mut calls as *mut u32 as usize };
+        let mut plan = ChangePlan::new();
+        plan.push(SystemChange::SetSysctl { key: "net.ipv4.ip_forward".to_string(), value: "1".to_string() });
+        plan.push(SystemChange::SetSysctl { key: "net.ipv4\0.ip_forward".to_string(), value: "1".to_string() });
+        planner.review(&mut plan);
+
+        // Only the change the host was shown can be approved
+        assert_eq!(calls, 1);
+        let vetoed: Vec<_> = plan.vetoed().map(|(change, _)| change.clone()).collect();
+        assert_eq!(vetoed, [plan.items()[1].change.clone()]);
+    }
+
+    #[test]
+    fn test_last_error_code() {
+        assert_eq!(vpnse_last_error_code(), ErrorCode::Ok as c_int);
+        let config = CString::new("[server").unwrap();
+        let result = unsafe { vpnse_parse_config(config.as_ptr(), ptr::null_mut(), 0) };
+        assert_eq!(result, VPNSEError::InvalidConfig as c_int);
+        assert_eq!(vpnse_last_error_code(), ErrorCode::InvalidConfig as c_int);
+
+        let message = unsafe { CStr::from_ptr(vpnse_error_message(vpnse_last_error_code())) };
+        assert_eq!(message.to_str().unwrap(), "Invalid configuration");
+        assert!(vpnse_error_message(3).is_null());
+    }
+
+    #[test]
+    fn test_cleanup_stale_state() {
+        let dir = tempfile::tempdir().unwrap();
+        // This process is alive, so its journal is not replayed
+        let journal = crate::tunnel::ChangeJournal::new(dir.path());
+        journal.record(crate::tunnel::JournalScope::KillSwitch, crate::tunnel::UndoAction::DisableKillSwitch).unwrap();
+        let path = CString::new(dir.path().to_str().unwrap()).unwrap();
+        let mut restored = u32::MAX;
+        assert_eq!(unsafe { vpnse_cleanup_stale_state(path.as_ptr(), &mut restored) }, VPNSEError::Success as c_int);
+        assert_eq!(restored, 0);
+        assert!(journal.path().exists());
+    }
+
+    #[cfg(feature = "vpngate")]
+    #[test]
+    fn test_vpngate_select() {
+        let csv = "*vpn_servers\n\
+                   a,203.0.113.1,1,80,50000000,Japan,JP,10,1,1,1,2weeks,Op,,\n\
+                   b,203.0.113.2,1,20,50000000,Korea Republic of,KR,10,1,1,1,2weeks,Op,,\n*";
+        let servers = crate::vpngate::parse_server_list(csv).unwrap();
+        let servers = CString::new(serde_json::to_string(&servers).unwrap()).unwrap();
+        let base = CString::new(Config::default_test().to_toml().unwrap()).unwrap();
+        let select = |criteria: Option<&str>, rank: u32| {
+            let criteria = criteria.map(|c| CString::new(c).unwrap());
+            let criteria = criteria.as_ref().map_or(ptr::null(), |c| c.as_ptr());
+            let mut buffer = vec![0 as c_char; 8192];
+            let code = unsafe {
+                vpnse_vpngate_select(servers.as_ptr(), criteria, base.as_ptr(), rank, buffer.as_mut_ptr(), buffer.len())
+            };
+            let config = unsafe { CStr::from_ptr(buffer.as_ptr()) }.to_str().unwrap().parse::<Config>().ok();
+            (code, config.map(|c| c.server.address))
+        };
+
+        assert_eq!(select(None, 0), (VPNSEError::Success as c_int, Some("203.0.113.2".to_string())));
+        assert_eq!(select(None, 1).1.as_deref(), Some("203.0.113.1"));
+        assert_eq!(select(Some(r#"{"countries":["jp"]}"#), 0).1.as_deref(), Some("203.0.113.1"));
+        assert_eq!(select(Some(r#"{"countries":["jp"]}"#), 1).0, VPNSEError::InvalidConfig as c_int);
+        assert_eq!(select(Some("not json"), 0).0, VPNSEError::InvalidParameter as c_int);
+    }
+
+    #[test]
+    fn test_change_password_needs_connection() {
+        let client = handles::insert(VpnClient::new(Config::default_test()).unwrap());
+        let (old, new) = (CString::new("old").unwrap(), CString::new("new").unwrap());
+        unsafe {
+            assert_eq!(
+                vpnse_client_change_password(client, old.as_ptr(), ptr::null()),
+                VPNSEError::InvalidParameter as c_int
+            );
+            assert_eq!(
+                vpnse_client_change_password(client, old.as_ptr(), new.as_ptr()),
+                VPNSEError::ConnectionFailed as c_int
+            );
+            vpnse_client_free(client);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_otp_callback() {
+        unsafe extern "C" fn answer(
+            _username: *const c_char,
+            hub: *const c_char,
+            message: *const c_char,
+            attempt: u32,
+            otp: *mut c_char,
+            otp_len: usize,
+            _user_data: *mut c_void,
+        ) -> c_int {
+            // Cancel the second attempt
+            if attempt > 1 || !message.is_null() {
+                return 1;
+            }
+            let code = format!("{}-123456\0", unsafe { CStr::from_ptr(hub) }.to_str().unwrap());
+            assert!(code.len() <= otp_len);
+            unsafe { ptr::copy_nonoverlapping(code.as_ptr() as *const c_char, otp, code.len()) };
+            0
+        }
+
+        let provider = FfiOtpProvider { callback: Some(answer), user_data: 0 };
+        let mut challenge = OtpChallenge { username: "alice".into(), hub: "VPN".into(), message: None, attempt: 1 };
+        assert_eq!(provider.one_time_password(challenge.clone()).await.as_deref(), Some("VPN-123456"));
+        challenge.attempt = 2;
+        assert_eq!(provider.one_time_password(challenge).await, None);
+
+        let client = handles::insert(VpnClient::new(Config::default_test()).unwrap());
+        unsafe {
+            assert_eq!(
+                vpnse_client_set_otp_callback(ptr::null_mut(), Some(answer), ptr::null_mut()),
+                VPNSEError::InvalidParameter as c_int
+            );
+            assert_eq!(vpnse_client_set_otp_callback(client, Some(answer), ptr::null_mut()), VPNSEError::Success as c_int);
+            assert_eq!(vpnse_client_set_otp_callback(client, None, ptr::null_mut()), VPNSEError::Success as c_int);
+            vpnse_client_free(client);
+        }
+    }
+
+    #[test]
+    fn test_log_callback() {
+        type Records = Mutex<Vec<(c_int, String, String)>>;
+        unsafe extern "C" fn collect(
+            level: c_int,
+            _target: *const c_char,
+            message: *const c_char,
+            fields: *const c_char,
+            user_data: *mut c_void,
+        ) {
+            let text = |s: *const c_char| unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned();
+            let records = unsafe { &*(user_data as *const Records) };
+            records.lock().unwrap().push((level, text(message), text(fields)));
+        }
+
+        let _lock = crate::logging::TEST_SINK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let records = Records::default();
+        let user_data = &records as *const Records as *mut c_void;
+        unsafe {
+            assert_eq!(vpnse_set_log_callback(Some(collect), 0, user_data), VPNSEError::InvalidParameter as c_int);
+            assert_eq!(vpnse_set_log_callback(Some(collect), 3, user_data), VPNSEError::Success as c_int);
+            log::warn!(interface = "tun0"; "Route check failed");
+            assert_eq!(vpnse_set_log_callback(None, 0, ptr::null_mut()), VPNSEError::Success as c_int);
+        }
+        let expected = (2, "Route check failed".to_string(), r#"{"interface":"tun0"}"#.to_string());
+        assert!(records.lock().unwrap().contains(&expected));
+    }
+
+    #[test]
+    fn test_packet_flow_needs_session() {
+        let client = new_client();
+        let mut buffer = [0u8; 1500];
+        unsafe {
+            assert_eq!(vpnse_client_attach_packet_flow(client), VPNSEError::ConnectionFailed as c_int);
+            assert_eq!(vpnse_client_attach_tun_fd(client, -1), VPNSEError::InvalidParameter as c_int);
+            assert_eq!(
+                vpnse_client_write_inbound_packet(client, buffer.as_ptr(), 20),
+                VPNSEError::ConnectionFailed as c_int
+            );
+            assert_eq!(
+                vpnse_client_read_outbound_packet(client, buffer.as_mut_ptr(), buffer.len(), 0),
+                -(VPNSEError::ConnectionFailed as c_int)
+            );
+            vpnse_client_free(client);
+        }
+    }
+
+    #[test]
+    fn test_enable_killswitch_waits_for_tunnel() {
+        let client = new_client();
+        unsafe {
+            assert_eq!(vpnse_client_enable_killswitch(ptr::null_mut(), 1), VPNSEError::InvalidParameter as c_int);
+            // Not tunneling: nothing is installed until the tunnel is up
+            assert_eq!(vpnse_client_enable_killswitch(client, 1), VPNSEError::Success as c_int);
+            assert_eq!(handles::with_client(client, |client| client.is_kill_switch_engaged()).ok(), Some(false));
+            assert_eq!(vpnse_client_enable_killswitch(client, 0), VPNSEError::Success as c_int);
+            vpnse_client_free(client);
+        }
+    }
+
+    #[derive(Default)]
+    struct RecordedEvents {
+        states: Vec<(c_int, c_int)>,
+        errors: Vec<(c_int, String)>,
+    }
+
+    unsafe extern "C" fn record_state(from: c_int, to: c_int, user_data: *mut c_void) {
+        let events = &*(user_data as *const Mutex<RecordedEvents>);
+        events.lock().unwrap().states.push((from, to));
+    }
+
+    unsafe extern "C" fn record_error(code: c_int, context: *const c_char, _message: *const c_char, user_data: *mut c_void) {
+        let events = &*(user_data as *const Mutex<RecordedEvents>);
+        let context = CStr::from_ptr(context).to_string_lossy().into_owned();
+        events.lock().unwrap().errors.push((code, context));
+    }
+
+    #[test]
+    fn test_event_callbacks_report_failed_connect() {
+        let _global = lock_global_state();
+        // Nothing listens on this port once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+
+        let client = new_client();
+        let events = Mutex::new(RecordedEvents::default());
+        let user_data = &events as *const _ as *mut c_void;
+        let (tx, rx) = mpsc::channel::<c_int>();
+        let server = CString::new("127.0.0.1").unwrap();
+
+        unsafe {
+            assert_eq!(vpnse_client_set_state_change_callback(client, Some(record_state), user_data), 0);
+            assert_eq!(vpnse_client_set_error_callback(client, Some(record_error), user_data), 0);
+            assert_eq!(
+                vpnse_client_connect_async(client, server.as_ptr(), port, Some(send_result), &tx as *const _ as *mut c_void),
+                VPNSEError::Success as c_int
+            );
+        }
+        let result = rx.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_ne!(result, VPNSEError::Success as c_int);
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.states, vec![(0, 1), (1, 0)]);
+        assert_eq!(events.errors, vec![(result, "connect".to_string())]);
+        drop(events);
+
+        unsafe { vpnse_client_free(client) };
+    }
+
+    #[test]
+    fn test_init_and_shutdown_nest_and_repeat() {
+        let _global = lock_global_state();
+        let options = VpnseInitOptions { worker_threads: 2, ..Default::default() };
+
+        unsafe {
+            assert_eq!(vpnse_shutdown(), VPNSEError::InvalidParameter as c_int);
+            for _ in 0..2 {
+                assert_eq!(vpnse_init(&options), VPNSEError::Success as c_int);
+                assert_eq!(vpnse_init(ptr::null()), VPNSEError::Success as c_int);
+                assert!(rustls::crypto::CryptoProvider::get_default().is_some());
+                let shared = shared_runtime().unwrap();
+                assert!(Arc::ptr_eq(&runtime().unwrap(), &shared));
+                assert_eq!(shared.block_on(async { tokio::task::spawn(async { 7 }).await.unwrap() }), 7);
+                drop(shared);
+
+                // Only the outermost shutdown releases anything
+                assert_eq!(vpnse_shutdown(), VPNSEError::Success as c_int);
+                assert!(shared_runtime().is_some());
+                assert_eq!(vpnse_shutdown(), VPNSEError::Success as c_int);
+                assert!(shared_runtime().is_none());
+            }
+            assert_eq!(vpnse_shutdown(), VPNSEError::InvalidParameter as c_int);
+        }
+    }
+
+    /// Callback that takes its time, recording when it started and finished
+    struct SlowCallback {
+        started: Mutex<mpsc::Sender<()>>,
+        finished: std::sync::atomic::AtomicBool,
+    }
+
+    unsafe extern "C" fn slow_callback(_result: c_int, user_data: *mut c_void) {
+        let state = &*(user_data as *const SlowCallback);
+        let _ = state.started.lock().unwrap().send(());
+        std::thread::sleep(Duration::from_millis(300));
+        state.finished.store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_running_callbacks() {
+        let _global = lock_global_state();
+        let client = new_client();
+        let (tx, rx) = mpsc::channel();
+        let state = SlowCallback { started: Mutex::new(tx), finished: Default::default() };
+        let server = CString::new("not an address").unwrap();
+
+        unsafe {
+            assert_eq!(vpnse_init(ptr::null()), VPNSEError::Success as c_int);
+            let user_data = &state as *const _ as *mut c_void;
+            assert_eq!(
+                vpnse_client_connect_async(client, server.as_ptr(), 443, Some(slow_callback), user_data),
+                VPNSEError::Success as c_int
+            );
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+            assert_eq!(vpnse_shutdown(), VPNSEError::Success as c_int);
+            assert!(state.finished.load(std::sync::atomic::Ordering::SeqCst));
+            vpnse_client_free(client);
+        }
+    }
+
+    /// Host "TLS" that passes bytes through unchanged, on the socket it is given
+    #[cfg(unix)]
+    mod passthrough {
+        use super::*;
+        use std::io::{Read, Write};
+        use std::mem::ManuallyDrop;
+        use std::os::unix::io::FromRawFd;
+
+        fn socket(session: *mut c_void) -> ManuallyDrop<std::net::TcpStream> {
+            ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(*(session as *const i32)) })
+        }
+
+        pub unsafe extern "C" fn connect(socket: i64, _name: *const c_char, _user_data: *mut c_void) -> *mut c_void {
+            Box::into_raw(Box::new(socket as i32)) as *mut c_void
+        }
+
+        pub unsafe extern "C" fn read(session: *mut c_void, buffer: *mut u8, len: usize, _: *mut c_void) -> isize {
+            let buffer = std::slice::from_raw_parts_mut(buffer, len);
+            socket(session).read(buffer).map_or(-1, |n| n as isize)
+        }
+
+        pub unsafe extern "C" fn write(session: *mut c_void, data: *const u8, len: usize, _: *mut c_void) -> isize {
+            let data = std::slice::from_raw_parts(data, len);
+            socket(session).write(data).map_or(-1, |n| n as isize)
+        }
+
+        pub unsafe extern "C" fn close(session: *mut c_void, user_data: *mut c_void) {
+            drop(Box::from_raw(session as *mut i32));
+            (*(user_data as *const std::sync::atomic::AtomicBool)).store(true, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tls_provider_callbacks() {
+        use crate::crypto::tls::{TlsRelay, TlsTarget};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let client = new_client();
+        let closed = Box::leak(Box::new(AtomicBool::new(false)));
+        let mut callbacks = VpnseTlsCallbacks {
+            connect: Some(passthrough::connect),
+            read: Some(passthrough::read),
+            write: Some(passthrough::write),
+            peer_certificate: None,
+            close: None,
+        };
+        let user_data = closed as *const AtomicBool as *mut c_void;
+        unsafe {
+            assert_eq!(vpnse_client_set_tls_provider(client, &callbacks, user_data), VPNSEError::InvalidParameter as c_int);
+            callbacks.close = Some(passthrough::close);
+            assert_eq!(vpnse_client_set_tls_provider(client, &callbacks, user_data), VPNSEError::Success as c_int);
+            assert_eq!(vpnse_client_set_tls_provider(client, ptr::null(), user_data), VPNSEError::Success as c_int);
+            vpnse_client_free(client);
+        }
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            // Plain echo server standing in for the TLS server
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let server = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+
+            let provider = Arc::new(FfiTlsProvider { callbacks, user_data: user_data as usize });
+            let target = TlsTarget {
+                server,
+                server_name: "vpn.example.com".to_string(),
+                certificates: CertificatePolicy::new(false),
+                proxy: None,
+                socket_mark: None,
+            };
+            let relay = TlsRelay::start(provider, target).await.unwrap();
+            let addr = relay.url().trim_start_matches("http://").parse::<std::net::SocketAddr>().unwrap();
+            let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut answer = [0u8; 4];
+            tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut answer)).await.unwrap().unwrap();
+            assert_eq!(&answer, b"ping");
+
+            // Hanging up ends the session in the host
+            drop(stream);
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while !closed.load(Ordering::SeqCst) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        });
     }
 }
@@ -0,0 +1,331 @@
+//! C ABI golden tests
+//!
+//! `tests/fixtures/abi/v<N>.txt` freezes the exports of ABI version N: every
+//! `#[no_mangle]` function, callback type, `#[repr(C)]` struct and error code
+//! as written in `ffi.rs`, with parameter and field names left out. An entry
+//! that disappears or changes breaks hosts built against that version, so
+//! the test asks for a [`VPNSE_ABI_VERSION`] bump and a new snapshot file.
+//! New exports are compatible and are appended to the current snapshot.
+//!
+//! `include/rvpnse.h` is written by hand for its documentation; the header
+//! tests keep its declarations and constants in step with the exports.
+//! Where `cbindgen` is installed, the header's function, callback and struct
+//! declarations must also match what cbindgen generates from `cbindgen.toml`,
+//! types and order alike; CI sets `RVPNSE_REQUIRE_CBINDGEN` so a missing
+//! cbindgen fails instead of skipping.
+
+use super::VPNSE_ABI_VERSION;
+use crate::error::ErrorCode;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::Command;
+
+const SOURCE: &str = include_str!("../ffi.rs");
+const HEADER: &str = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/include/rvpnse.h"));
+
+fn snapshot_path(version: u32) -> String {
+    format!("{}/tests/fixtures/abi/v{version}.txt", env!("CARGO_MANIFEST_DIR"))
+}
+
+/// `ffi.rs` without comments, on one line with single spaces
+fn flattened_source() -> String {
+    let code: Vec<&str> = SOURCE
+        .lines()
+        .map(|line| match line.find("//") {
+            Some(comment) if !line[..comment].contains('"') => &line[..comment],
+            _ => line,
+        })
+        .collect();
+    code.join(" ").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Text from `open` up to and including its matching `close`
+fn balanced(text: &str, open: char, close: char) -> &str {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return &text[..=i];
+            }
+        }
+    }
+    text
+}
+
+/// Drop parameter and field names and `pub`, which are not part of the ABI
+fn strip_names(signature: &str) -> String {
+    let names = Regex::new(r"\b(pub )?[a-z_][a-z0-9_]*: ").unwrap();
+    let spacing = Regex::new(r"\( | \)|,\)|, \)|, \}|< |, >").unwrap();
+    let stripped = names.replace_all(signature, "");
+    spacing
+        .replace_all(&stripped, |caps: &regex::Captures| match &caps[0] {
+            "( " => "(",
+            ", }" => " }",
+            "< " => "<",
+            ", >" => ">",
+            _ => ")",
+        })
+        .into_owned()
+}
+
+/// Exported items of `ffi.rs`, keyed by name
+fn exports() -> BTreeMap<String, String> {
+    let source = flattened_source();
+    let mut exports = BTreeMap::new();
+
+    let function = Regex::new(r#"#\[no_mangle\] pub (?:unsafe )?extern "C" fn (\w+)"#).unwrap();
+    for caps in function.captures_iter(&source) {
+        let rest = &source[caps.get(0).unwrap().end()..];
+        let params = balanced(rest, '(', ')');
+        let ret = rest[params.len()..].split('{').next().unwrap().trim();
+        let signature = format!("fn {}{} {}", &caps[1], params, ret);
+        exports.insert(caps[1].to_string(), strip_names(signature.trim()));
+    }
+
+    let alias = Regex::new(r"pub type (Vpnse\w+) = ([^;]+);").unwrap();
+    for caps in alias.captures_iter(&source) {
+        exports.insert(caps[1].to_string(), strip_names(&format!("type {} = {}", &caps[1], &caps[2])));
+    }
+
+    let structure = Regex::new(r"#\[repr\(C\)\] (?:#\[derive\([^)]*\)\] )?pub struct (\w+) ").unwrap();
+    for caps in structure.captures_iter(&source) {
+        let body = balanced(&source[caps.get(0).unwrap().end()..], '{', '}');
+        exports.insert(caps[1].to_string(), strip_names(&format!("struct {} {}", &caps[1], body)));
+    }
+
+    let errors = Regex::new(r"pub enum VPNSEError \{([^}]*)\}").unwrap();
+    let body = errors.captures(&source).expect("VPNSEError not found")[1].to_string();
+    for variant in body.split(',').map(str::trim).filter(|v| !v.is_empty()) {
+        let name = variant.split(" = ").next().unwrap();
+        exports.insert(format!("VPNSEError::{name}"), format!("const VPNSEError::{variant}"));
+    }
+    exports
+}
+
+/// Snapshot entries keyed by name, as [`exports`] renders them
+fn snapshot(version: u32) -> BTreeMap<String, String> {
+    let path = snapshot_path(version);
+    let text = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{path}: {e}"));
+    let name = Regex::new(r"^(?:fn|type|struct|const) ([\w:]+)").unwrap();
+    text.lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let caps = name.captures(line).unwrap_or_else(|| panic!("{path}: bad entry {line}"));
+            (caps[1].to_string(), line.to_string())
+        })
+        .collect()
+}
+
+/// Names of the functions `rvpnse.h` declares
+fn header_functions() -> BTreeSet<String> {
+    let declaration = Regex::new(r"(?m)^[a-z][\w\s\*]*\b(vpnse_\w+)\(").unwrap();
+    declaration
+        .captures_iter(HEADER)
+        .filter(|caps| !caps[0].starts_with("typedef"))
+        .map(|caps| caps[1].to_string())
+        .collect()
+}
+
+/// Values of the header enum closed by `} <name>;`
+fn header_enum(name: &str) -> BTreeSet<i32> {
+    let end = HEADER.find(&format!("}} {name};")).unwrap_or_else(|| panic!("{name} not in header"));
+    let start = HEADER[..end].rfind("typedef enum {").unwrap();
+    let value = Regex::new(r"= (-?\d+)").unwrap();
+    value
+        .captures_iter(&HEADER[start..end])
+        .map(|caps| caps[1].parse().unwrap())
+        .collect()
+}
+
+/// Declaration with parameter and field names dropped, tokens separated by single spaces
+fn c_types(declaration: &str) -> String {
+    let token = Regex::new(r"[A-Za-z_]\w*|\S").unwrap();
+    let tokens: Vec<&str> = token.find_iter(declaration).map(|m| m.as_str()).collect();
+    let is_name = |t: &str| t.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_');
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|&(i, t)| {
+            // A name follows a type (an identifier or `*`) and ends a parameter or field
+            let ends = tokens.get(i + 1).is_some_and(|next| [",", ")", ";"].contains(next));
+            let typed = i > 0 && (is_name(tokens[i - 1]) || tokens[i - 1] == "*");
+            !(is_name(t) && ends && typed && !["void", "const"].contains(t))
+        })
+        .map(|(_, t)| *t)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Functions, callback typedefs and structs a C header declares, keyed by name
+///
+/// Enums are left out: cbindgen does not prefix their constants, and the
+/// values are checked against the exports above.
+fn c_declarations(header: &str) -> BTreeMap<String, String> {
+    let comment = Regex::new(r"(?s)/\*.*?\*/|//[^\n]*").unwrap();
+    let code: String = comment
+        .replace_all(header, "")
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace("extern \"C\" {", "");
+
+    // Top-level statements; the `}` closing `extern "C"` is dropped
+    let mut statements = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    let mut text = String::new();
+    for c in code.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' if depth == 0 => continue,
+            '}' => depth -= 1,
+            ';' if depth == 0 => {
+                statements.push(text[start..].trim().to_string());
+                start = text.len() + 1;
+            }
+            _ => {}
+        }
+        text.push(c);
+    }
+
+    let callback = Regex::new(r"^typedef .*?\(\s*\*\s*(\w+)\s*\)\s*\(").unwrap();
+    let structure = Regex::new(r"^typedef struct\s*\w*\s*(\{.*\})\s*(\w+)$").unwrap();
+    let function = Regex::new(r"^[^(]*?\b(\w+)\s*\(").unwrap();
+    let mut declarations = BTreeMap::new();
+    for statement in statements.iter().filter(|s| !s.is_empty()) {
+        if let Some(caps) = structure.captures(statement) {
+            declarations.insert(caps[2].to_string(), format!("struct {}", c_types(&caps[1])));
+        } else if statement.starts_with("typedef enum") || !statement.contains('(') {
+            continue;
+        } else if let Some(caps) = callback.captures(statement) {
+            declarations.insert(caps[1].to_string(), c_types(statement));
+        } else if let Some(caps) = function.captures(statement).filter(|_| !statement.starts_with("typedef")) {
+            // The function's own name goes like a parameter's
+            let declaration = statement.replacen(&format!("{}(", &caps[1]), "(", 1);
+            let declaration = declaration.replacen(&format!("{} (", &caps[1]), "(", 1);
+            declarations.insert(caps[1].to_string(), c_types(&declaration));
+        }
+    }
+    declarations
+}
+
+/// Header cbindgen generates from `cbindgen.toml`, `None` without cbindgen
+fn cbindgen_header() -> Option<String> {
+    let dir = env!("CARGO_MANIFEST_DIR");
+    let output = tempfile::tempdir().unwrap();
+    let generated = output.path().join("rvpnse.h");
+    let status = Command::new("cbindgen")
+        .current_dir(dir)
+        .args(["--config", "cbindgen.toml", "--crate", "rvpnse", "--quiet", "--output"])
+        .arg(&generated)
+        .status();
+    match status {
+        Ok(status) => {
+            assert!(status.success(), "cbindgen failed: {status}");
+            Some(std::fs::read_to_string(&generated).unwrap())
+        }
+        Err(e) if std::env::var_os("RVPNSE_REQUIRE_CBINDGEN").is_some() => panic!("cbindgen not runnable: {e}"),
+        Err(_) => None,
+    }
+}
+
+#[test]
+fn test_exports_match_abi_snapshot() {
+    let exports = exports();
+    let frozen = snapshot(VPNSE_ABI_VERSION);
+
+    let broken: Vec<_> = frozen.iter().filter(|(name, entry)| exports.get(*name) != Some(*entry)).collect();
+    assert!(
+        broken.is_empty(),
+        "exports of ABI v{VPNSE_ABI_VERSION} removed or changed, bump VPNSE_ABI_VERSION and add a snapshot: {broken:#?}"
+    );
+
+    let added: Vec<_> = exports.iter().filter(|(name, _)| !frozen.contains_key(*name)).map(|(_, e)| e).collect();
+    assert!(added.is_empty(), "new exports missing from {}: {added:#?}", snapshot_path(VPNSE_ABI_VERSION));
+    assert!(exports.contains_key("vpnse_abi_version"));
+}
+
+#[test]
+fn test_header_declares_every_export() {
+    let exported: BTreeSet<String> =
+        exports().into_iter().filter(|(_, entry)| entry.starts_with("fn ")).map(|(name, _)| name).collect();
+    let declared = header_functions();
+    assert_eq!(
+        exported.difference(&declared).collect::<Vec<_>>(),
+        Vec::<&String>::new(),
+        "exported but not declared in rvpnse.h"
+    );
+    assert_eq!(
+        declared.difference(&exported).collect::<Vec<_>>(),
+        Vec::<&String>::new(),
+        "declared in rvpnse.h but not exported"
+    );
+}
+
+#[test]
+fn test_header_constants_match() {
+    let define = Regex::new(r"#define VPNSE_ABI_VERSION (\d+)").unwrap();
+    let version: u32 = define.captures(HEADER).expect("VPNSE_ABI_VERSION not defined")[1].parse().unwrap();
+    assert_eq!(version, VPNSE_ABI_VERSION);
+
+    // The header keeps a few codes the library no longer returns
+    let results = header_enum("vpnse_error_t");
+    for entry in exports().values().filter(|entry| entry.starts_with("const VPNSEError::")) {
+        let value: i32 = entry.rsplit(" = ").next().unwrap().parse().unwrap();
+        assert!(results.contains(&value), "{entry} missing from vpnse_error_t");
+    }
+
+    let codes: BTreeSet<i32> = ErrorCode::ALL.iter().map(|&code| code as i32).collect();
+    assert_eq!(header_enum("vpnse_error_code_t"), codes);
+}
+
+#[test]
+fn test_c_declarations_ignore_names_and_layout() {
+    let shipped = "/** Doc */\n#ifdef __cplusplus\nextern \"C\" {\n#endif\n\
+        typedef struct vpnse_client vpnse_client_t;\n\
+        typedef enum {\n    VPNSE_SUCCESS = 0\n} vpnse_error_t;\n\
+        typedef struct {\n    int log_level; /* level */\n\
+        void* (*connect)(int64_t socket, void* user_data);\n} opts_t;\n\
+        typedef void (*vpnse_log_cb)(int level, const char* message,\n                             void* user_data);\n\
+        vpnse_client_t* vpnse_client_new(const char* config_str);\n\
+        int vpnse_shutdown(void);\n#ifdef __cplusplus\n}\n#endif\n";
+    let generated = "typedef struct opts_t {\n  int log_level;\n  void *(*connect)(int64_t, void *data);\n} opts_t;\n\
+        typedef void (*vpnse_log_cb)(int lvl, const char *msg, void *ud);\n\
+        vpnse_client_t *vpnse_client_new(const char *config);\n\
+        int vpnse_shutdown(void);\n";
+    let declarations = c_declarations(shipped);
+    assert_eq!(declarations, c_declarations(generated));
+    assert_eq!(declarations["vpnse_client_new"], "vpnse_client_t * ( const char * )");
+    assert_eq!(declarations["opts_t"], "struct { int ; void * ( * ) ( int64_t , void * ) ; }");
+
+    // A changed type is a difference
+    let changed = generated.replace("const char *config", "char *config");
+    assert_ne!(c_declarations(&changed), declarations);
+
+    // Every function of the shipped header is picked up
+    let shipped = c_declarations(HEADER);
+    assert!(header_functions().iter().all(|name| shipped.contains_key(name)));
+    assert!(shipped.contains_key("vpnse_tls_callbacks_t") && shipped.contains_key("vpnse_otp_cb"));
+}
+
+#[test]
+fn test_header_matches_cbindgen() {
+    let Some(generated) = cbindgen_header() else {
+        eprintln!("cbindgen not installed, rvpnse.h not compared with its output");
+        return;
+    };
+    let generated = c_declarations(&generated);
+    let shipped = c_declarations(HEADER);
+    let differing: Vec<_> = generated
+        .keys()
+        .chain(shipped.keys())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .filter(|name| generated.get(*name) != shipped.get(*name))
+        .map(|name| (name, shipped.get(name), generated.get(name)))
+        .collect();
+    assert!(differing.is_empty(), "rvpnse.h (left) differs from cbindgen (right): {differing:#?}");
+}
@@ -0,0 +1,181 @@
+//! Client handles given out over the FFI
+//!
+//! Hosts hold an opaque handle rather than a pointer to the client. The
+//! client sits in a registry slot as `Arc<Mutex<VpnClient>>`, so calls from
+//! different threads take turns on it and a call already under way keeps it
+//! alive through a concurrent `vpnse_client_free`. Handles carry the slot's
+//! generation: a freed handle, or one whose slot went to a newer client, is
+//! refused instead of reaching memory it no longer owns.
+
+use super::VPNSEError;
+use crate::VpnClient;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+
+/// Opaque client handle, `vpnse_client_t` in C; never dereferenced
+pub struct VpnseClient {
+    _private: [u8; 0],
+}
+
+type SharedClient = Arc<Mutex<VpnClient>>;
+
+/// Low half of a handle is the slot index plus one, high half the generation
+const INDEX_BITS: u32 = usize::BITS / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+
+struct Slot {
+    generation: usize,
+    client: Option<SharedClient>,
+}
+
+struct Registry {
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+}
+
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry { slots: Vec::new(), free: Vec::new() });
+
+thread_local! {
+    /// Handles whose client this thread has locked, to refuse calls from its
+    /// callbacks instead of deadlocking
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+fn registry() -> std::sync::MutexGuard<'static, Registry> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn decode(handle: usize) -> Option<(usize, usize)> {
+    let index = (handle & INDEX_MASK).checked_sub(1)?;
+    Some((index, handle >> INDEX_BITS))
+}
+
+/// Register `client`; null once the registry is full
+pub(super) fn insert(client: VpnClient) -> *mut VpnseClient {
+    let client = Some(Arc::new(Mutex::new(client)));
+    let mut registry = registry();
+    let index = match registry.free.pop() {
+        Some(index) => {
+            registry.slots[index].client = client;
+            index
+        }
+        None if registry.slots.len() < INDEX_MASK => {
+            registry.slots.push(Slot { generation: 0, client });
+            registry.slots.len() - 1
+        }
+        None => return std::ptr::null_mut(),
+    };
+    let generation = registry.slots[index].generation;
+    ((generation << INDEX_BITS) | (index + 1)) as *mut VpnseClient
+}
+
+/// Client behind `handle`, unless it is null, freed or never issued
+pub(super) fn get(handle: *const VpnseClient) -> Option<SharedClient> {
+    let (index, generation) = decode(handle as usize)?;
+    let registry = registry();
+    let slot = registry.slots.get(index).filter(|slot| slot.generation == generation)?;
+    slot.client.clone()
+}
+
+/// Unregister `handle`, returning its client; `None` for a double free
+///
+/// The slot's generation moves on, so the handle stays invalid after the
+/// slot is reused.
+pub(super) fn remove(handle: *const VpnseClient) -> Option<SharedClient> {
+    let (index, generation) = decode(handle as usize)?;
+    let mut registry = registry();
+    let slot = registry.slots.get_mut(index).filter(|slot| slot.generation == generation)?;
+    let client = slot.client.take()?;
+    slot.generation = (slot.generation + 1) & INDEX_MASK;
+    registry.free.push(index);
+    Some(client)
+}
+
+/// Run `f` on the client behind `handle`, waiting for calls on other threads
+///
+/// # Errors
+/// `InvalidParameter` for a null, freed or unknown handle, `InProgress` when
+/// this thread is already inside a call on the client, i.e. from a callback.
+pub(super) fn with_client<T>(handle: *const VpnseClient, f: impl FnOnce(&mut VpnClient) -> T) -> Result<T, VPNSEError> {
+    let client = get(handle).ok_or(VPNSEError::InvalidParameter)?;
+    lock(handle as usize, &client, f)
+}
+
+/// [`with_client`] for a client already taken from the registry
+pub(super) fn lock<T>(key: usize, client: &SharedClient, f: impl FnOnce(&mut VpnClient) -> T) -> Result<T, VPNSEError> {
+    if HELD.with(|held| held.borrow().contains(&key)) {
+        return Err(VPNSEError::InProgress);
+    }
+    let mut client = client.lock().unwrap_or_else(|e| e.into_inner());
+    HELD.with(|held| held.borrow_mut().push(key));
+    let _held = Held(key);
+    Ok(f(&mut client))
+}
+
+/// Takes a handle off [`HELD`] however the call ends
+struct Held(usize);
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        HELD.with(|held| held.borrow_mut().retain(|&key| key != self.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn client() -> VpnClient {
+        VpnClient::new(Config::default_test()).unwrap()
+    }
+
+    #[test]
+    fn test_stale_handles_are_refused() {
+        let first = insert(client());
+        assert!(!first.is_null());
+        assert!(with_client(first, |_| ()).is_ok());
+        assert!(remove(first).is_some());
+        // Double free
+        assert!(remove(first).is_none());
+
+        // The slot goes to the next client under a new generation
+        let second = insert(client());
+        assert_ne!(first, second);
+        assert!(matches!(with_client(first, |_| ()), Err(VPNSEError::InvalidParameter)));
+        assert!(with_client(second, |_| ()).is_ok());
+        assert!(get(std::ptr::null()).is_none());
+        assert!(remove(second).is_some());
+    }
+
+    #[test]
+    fn test_calls_from_callbacks_are_refused() {
+        let handle = insert(client());
+        let nested = with_client(handle, |_| with_client(handle, |_| ()));
+        assert!(matches!(nested, Ok(Err(VPNSEError::InProgress))));
+        // Released once the outer call returns
+        assert!(with_client(handle, |_| ()).is_ok());
+        remove(handle);
+    }
+
+    #[test]
+    fn test_concurrent_calls_take_turns() {
+        let handle = insert(client()) as usize;
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        assert!(with_client(handle as *const VpnseClient, |client| client.set_rate_limit(1, 1)).is_ok());
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // A call in flight keeps the client alive through a free
+        let client = get(handle as *const VpnseClient).unwrap();
+        assert!(remove(handle as *const VpnseClient).is_some());
+        assert!(lock(handle, &client, |client| client.rate_limit()).is_ok());
+    }
+}
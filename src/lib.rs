@@ -19,27 +19,116 @@
 //! - Platform-specific DNS configuration
 //! - Platform-specific permissions/privileges
 //!
+//! ## Quick Start
+//! A session goes through configuration, connection, authentication and
+//! tunnel setup, and ends with a disconnect:
+//!
+//! ```no_run
+//! use rvpnse::client::events::StateChange;
+//! use rvpnse::{Config, ConnectionStatus, VpnClient};
+//! use std::sync::Arc;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> rvpnse::Result<()> {
+//! let config = Config::from_file("vpn.toml")?;
+//! let username = config.auth.username.clone().unwrap_or_default();
+//! let password = config.auth.password.clone().unwrap_or_default();
+//! let (server, port) = (config.server.address.clone(), config.server.port);
+//!
+//! let mut client = VpnClient::new(config)?;
+//! client.events().on_state_change(Some(Arc::new(|change: &StateChange| {
+//!     println!("{:?} -> {:?}", change.from, change.to);
+//! })));
+//!
+//! client.connect_async(&server, port).await?;
+//! client.authenticate(&username, &password).await?;
+//! client.establish_tunnel()?;
+//! assert_eq!(client.status(), ConnectionStatus::Tunneling);
+//!
+//! // Fails once the session is lost; `reconnect` brings it back
+//! client.check_session()?;
+//!
+//! client.disconnect()?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The binary data channel can be tried without a server through the
+//! in-process [`protocol::mock::MockServer`].
+//!
 //! ## Integration Examples
 //! See the `examples/` directory for integration patterns and the
 //! documentation in `docs/integration/` for platform-specific guides.
 
+#[cfg(feature = "no_std_core")]
+extern crate alloc;
+
+#[cfg(feature = "native")]
+pub mod auth_throttle;
+#[cfg(feature = "native")]
+pub mod blocking;
+pub mod capabilities;
+#[cfg(feature = "native")]
 pub mod client;
+#[cfg(feature = "native")]
 pub mod client_optimized;
 pub mod config;
 pub mod crypto;
+#[cfg(feature = "native")]
+pub mod diagnostics;
+#[cfg(feature = "native")]
+pub mod doctor;
 pub mod error;
+pub mod logging;
+pub mod net_util;
+#[cfg(feature = "native")]
+pub mod privileges;
 pub mod protocol;
+#[cfg(feature = "native")]
+pub mod proxy;
+#[cfg(feature = "native")]
+pub mod resolver;
+#[cfg(feature = "native")]
+pub mod timestamp;
+#[cfg(feature = "native")]
 pub mod tunnel;
+/// Protocol-only builds keep the Ethernet helpers the configuration is checked with
+#[cfg(not(feature = "native"))]
+#[allow(dead_code)] // helpers for the packet path, which is native only
+pub mod tunnel {
+    pub mod l2;
+    pub mod packet_framing;
+}
+#[cfg(feature = "native")]
+pub mod underlay;
+#[cfg(feature = "vpngate")]
+pub mod vpngate;
 
 // Re-export core types for static library interface
-pub use client::{ConnectionStatus, VpnClient};
-pub use client_optimized::{OptimizedVpnClient, PerformanceConfig, PerformanceSnapshot};
+pub use capabilities::{capabilities, Capabilities};
+#[cfg(feature = "native")]
+pub use client::{ClientStats, ConnectionStatus, ServerProbe, VpnClient};
+#[cfg(feature = "native")]
+pub use client_optimized::{
+    PerformanceConfig, PerformanceRates, PerformanceSnapshot, PerformanceStats, SnapshotHistory,
+};
 pub use config::Config;
-pub use error::{Result, VpnError};
+pub use error::{ErrorCode, Result, VpnError};
+#[cfg(feature = "native")]
+pub use timestamp::Timestamp;
+#[cfg(feature = "native")]
+pub use underlay::{UnderlayBinding, UnderlayInterface};
 
 /// Library version information
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 pub const NAME: &str = env!("CARGO_PKG_NAME");
 
 /// C FFI Interface for cross-platform integration
+#[cfg(feature = "native")]
 pub mod ffi;
+
+/// Kotlin and Swift bindings generated with UniFFI
+#[cfg(feature = "bindings")]
+pub mod bindings;
+#[cfg(feature = "bindings")]
+uniffi::setup_scaffolding!();
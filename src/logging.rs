@@ -0,0 +1,153 @@
+//! Pluggable destination for the library's log output
+//!
+//! The library reports progress through the [`log`] facade: a message plus
+//! structured fields such as `interface` or `server`. Rust hosts can install
+//! any `log` logger to receive it. Hosts that would rather take the records
+//! directly, like the C API's log callback, register a [`LogSink`] with
+//! [`set_sink`] instead.
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Result, VpnError};
+
+pub use log::{Level, LevelFilter};
+
+/// One record handed to a [`LogSink`]
+#[derive(Debug, Clone, Copy)]
+pub struct LogRecord<'a> {
+    pub level: Level,
+    /// Module that logged it, e.g. `rvpnse::tunnel`
+    pub target: &'a str,
+    pub message: &'a str,
+    /// Structured fields as key and formatted value, in logging order
+    pub fields: &'a [(String, String)],
+}
+
+/// Receiver of the library's log records
+///
+/// Called on whatever thread logged, so it must not block for long.
+pub trait LogSink: Send + Sync {
+    fn log(&self, record: &LogRecord<'_>);
+}
+
+/// Writes records to stderr as `LEVEL target: message key=value ...`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StderrSink;
+
+impl LogSink for StderrSink {
+    fn log(&self, record: &LogRecord<'_>) {
+        let mut line = format!("{:<5} {}: {}", record.level, record.target, record.message);
+        for (key, value) in record.fields {
+            let _ = write!(line, " {key}={value}");
+        }
+        let _ = writeln!(std::io::stderr().lock(), "{line}");
+    }
+}
+
+struct State {
+    /// Whether [`Dispatcher`] is the process's `log` logger
+    installed: bool,
+    sink: Option<Arc<dyn LogSink>>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State { installed: false, sink: None });
+
+/// `log` logger forwarding this crate's records to the registered sink
+struct Dispatcher;
+
+static DISPATCHER: Dispatcher = Dispatcher;
+
+fn current_sink() -> Option<Arc<dyn LogSink>> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner()).sink.clone()
+}
+
+impl log::Log for Dispatcher {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        // Dependencies (hyper, rustls, ...) log through the same facade
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let Some(sink) = current_sink() else { return };
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        let message = record.args().to_string();
+        sink.log(&LogRecord { level: record.level(), target: record.target(), message: &message, fields: &fields.0 });
+    }
+
+    fn flush(&self) {}
+}
+
+struct Fields(Vec<(String, String)>);
+
+impl<'kvs> log::kv::VisitSource<'kvs> for Fields {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> std::result::Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+/// Send the library's records up to `level` to `sink`, or stop with `None`
+///
+/// The first call makes this module the process's `log` logger, which fails
+/// if the host installed another one; later calls only swap the sink.
+pub fn set_sink(sink: Option<Arc<dyn LogSink>>, level: LevelFilter) -> Result<()> {
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    if !state.installed {
+        log::set_logger(&DISPATCHER)
+            .map_err(|_| VpnError::InvalidState("Another logger is already installed".to_string()))?;
+        state.installed = true;
+    }
+    log::set_max_level(if sink.is_some() { level } else { LevelFilter::Off });
+    state.sink = sink;
+    Ok(())
+}
+
+/// Held by tests that register a sink, as there is only one per process
+#[cfg(test)]
+pub(crate) static TEST_SINK_LOCK: Mutex<()> = Mutex::new(());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Entry = (Level, String, Vec<(String, String)>);
+
+    #[derive(Default)]
+    struct Collect(Mutex<Vec<Entry>>);
+
+    impl LogSink for Collect {
+        fn log(&self, record: &LogRecord<'_>) {
+            let entry = (record.level, record.message.to_string(), record.fields.to_vec());
+            self.0.lock().unwrap().push(entry);
+        }
+    }
+
+    #[test]
+    fn test_sink_receives_structured_records() {
+        let _lock = TEST_SINK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let sink = Arc::new(Collect::default());
+        set_sink(Some(sink.clone()), LevelFilter::Info).unwrap();
+        log::info!(interface = "tun0", mtu = 1400; "TUN interface created");
+        log::debug!("Below the level");
+        log::info!(target: "hyper::proto", "Not ours");
+        set_sink(None, LevelFilter::Info).unwrap();
+        log::info!("After the sink was removed");
+
+        // Other tests log concurrently through the same sink
+        let records = sink.0.lock().unwrap();
+        let fields = vec![("interface".to_string(), "tun0".to_string()), ("mtu".to_string(), "1400".to_string())];
+        assert!(records.contains(&(Level::Info, "TUN interface created".to_string(), fields)));
+        let dropped = ["Below the level", "Not ours", "After the sink was removed"];
+        assert!(!records.iter().any(|(_, message, _)| dropped.contains(&message.as_str())));
+    }
+}
@@ -0,0 +1,163 @@
+//! Prefix math for tunnel addressing, routes and DHCP leases
+//!
+//! Everything that derives a subnet from an address and mask, compares
+//! prefixes or checks an address against a DHCP pool goes through here, on
+//! top of [`ipnet`], instead of masking integers or cutting strings at each
+//! call site.
+
+use ipnet::{IpNet, Ipv4Net};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Prefix length of a contiguous netmask (`255.255.255.0` → 24)
+///
+/// Non-contiguous masks such as `255.0.255.0` have no prefix length.
+pub fn prefix_len(netmask: Ipv4Addr) -> Option<u8> {
+    ipnet::ipv4_mask_to_prefix(netmask).ok()
+}
+
+/// Network `address` belongs to, with host bits cleared
+///
+/// `None` if `prefix_len` is too long for the address family.
+pub fn subnet(address: IpAddr, prefix_len: u8) -> Option<IpNet> {
+    IpNet::new(address, prefix_len).ok().map(|net| net.trunc())
+}
+
+/// IPv4 network of `address` under `netmask`, `None` for a non-contiguous mask
+pub fn ipv4_subnet(address: Ipv4Addr, netmask: Ipv4Addr) -> Option<Ipv4Net> {
+    Ipv4Net::new(address, prefix_len(netmask)?).ok().map(|net| net.trunc())
+}
+
+/// Parse `addr/len`, or a bare address as a host prefix
+///
+/// The address keeps its host bits; see [`IpNet::trunc`].
+pub fn parse_network(text: &str) -> Option<IpNet> {
+    let text = text.trim();
+    text.parse::<IpNet>().ok().or_else(|| text.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Host route (/32 or /128) to `ip`, in CIDR notation
+///
+/// Text that is not an address is returned unchanged.
+pub fn host_cidr(ip: &str) -> String {
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => IpNet::from(ip).to_string(),
+        Err(_) => ip.to_string(),
+    }
+}
+
+/// Whether two prefixes share any address
+///
+/// Prefixes are either nested or disjoint, so this is containment in one
+/// direction or the other. Different families never overlap.
+pub fn overlaps(a: &IpNet, b: &IpNet) -> bool {
+    a.contains(&b.network()) || b.contains(&a.network())
+}
+
+/// Whether a DHCP server may hand `address` out of `pool`
+///
+/// Inside the pool and neither its network nor its broadcast address; /31
+/// and /32 pools have no such reserved addresses (RFC 3021).
+pub fn is_assignable(address: Ipv4Addr, pool: &Ipv4Net) -> bool {
+    pool.contains(&address)
+        && (pool.prefix_len() >= 31 || (address != pool.network() && address != pool.broadcast()))
+}
+
+/// First usable host of the network `address` belongs to, the conventional gateway
+pub fn first_host(address: Ipv4Addr, prefix_len: u8) -> Option<Ipv4Addr> {
+    let net = Ipv4Net::new(address, prefix_len).ok()?;
+    net.hosts().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn ipv4() -> impl Strategy<Value = Ipv4Addr> {
+        any::<u32>().prop_map(Ipv4Addr::from)
+    }
+
+    fn ipv4_net() -> impl Strategy<Value = Ipv4Net> {
+        (ipv4(), 0u8..=32).prop_map(|(address, len)| Ipv4Net::new(address, len).unwrap().trunc())
+    }
+
+    #[test]
+    fn test_known_values() {
+        assert_eq!(prefix_len(Ipv4Addr::new(255, 255, 255, 0)), Some(24));
+        assert_eq!(prefix_len(Ipv4Addr::new(255, 0, 255, 0)), None);
+        assert_eq!(ipv4_subnet(Ipv4Addr::new(10, 21, 255, 7), Ipv4Addr::new(255, 255, 255, 0)).unwrap().to_string(), "10.21.255.0/24");
+        assert_eq!(subnet("fd00:21::5".parse().unwrap(), 64).unwrap().to_string(), "fd00:21::/64");
+        assert_eq!(subnet("10.0.0.1".parse().unwrap(), 33), None);
+        assert_eq!(parse_network("203.0.113.10").unwrap().to_string(), "203.0.113.10/32");
+        assert_eq!(parse_network("10.0.0.0/33"), None);
+        assert_eq!(host_cidr("2001:db8::1"), "2001:db8::1/128");
+        assert_eq!(first_host(Ipv4Addr::new(192, 168, 4, 77), 24), Some(Ipv4Addr::new(192, 168, 4, 1)));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_subnet_contains_address(address in ipv4(), len in 0u8..=32) {
+            let net = subnet(IpAddr::V4(address), len).unwrap();
+            prop_assert!(net.contains(&IpAddr::V4(address)));
+            prop_assert_eq!(net.prefix_len(), len);
+            // Deriving again from any member gives the same network
+            prop_assert_eq!(subnet(net.network(), len), Some(net));
+            prop_assert_eq!(subnet(net.broadcast(), len), Some(net));
+        }
+
+        #[test]
+        fn prop_netmask_round_trip(address in ipv4(), len in 0u8..=32) {
+            let netmask = Ipv4Net::new(address, len).unwrap().netmask();
+            prop_assert_eq!(prefix_len(netmask), Some(len));
+            prop_assert_eq!(ipv4_subnet(address, netmask).map(IpNet::V4), subnet(IpAddr::V4(address), len));
+        }
+
+        #[test]
+        fn prop_non_contiguous_mask_rejected(mask in any::<u32>()) {
+            let contiguous = mask.leading_ones() + mask.trailing_zeros() == 32;
+            prop_assert_eq!(prefix_len(Ipv4Addr::from(mask)).is_some(), contiguous);
+        }
+
+        #[test]
+        fn prop_parse_network_round_trip(net in ipv4_net()) {
+            prop_assert_eq!(parse_network(&net.to_string()), Some(IpNet::V4(net)));
+        }
+
+        #[test]
+        fn prop_overlap_matches_shared_address(a in ipv4_net(), b in ipv4_net(), probe in ipv4()) {
+            let (a, b) = (IpNet::V4(a), IpNet::V4(b));
+            prop_assert_eq!(overlaps(&a, &b), overlaps(&b, &a));
+            prop_assert!(overlaps(&a, &a));
+            // Any address in both prefixes is evidence of an overlap
+            if a.contains(&IpAddr::V4(probe)) && b.contains(&IpAddr::V4(probe)) {
+                prop_assert!(overlaps(&a, &b));
+            }
+            // Disjoint prefixes have no common network address
+            if !overlaps(&a, &b) {
+                prop_assert!(!a.contains(&b.network()) && !b.contains(&a.network()));
+            }
+        }
+
+        #[test]
+        fn prop_assignable_addresses(pool in ipv4_net(), address in ipv4()) {
+            let assignable = is_assignable(address, &pool);
+            if assignable {
+                prop_assert!(pool.contains(&address));
+            }
+            if !pool.contains(&address) {
+                prop_assert!(!assignable);
+            }
+            if pool.prefix_len() < 31 {
+                prop_assert!(!is_assignable(pool.network(), &pool));
+                prop_assert!(!is_assignable(pool.broadcast(), &pool));
+            } else {
+                prop_assert!(is_assignable(pool.network(), &pool));
+            }
+            // The conventional gateway is assignable
+            if let Some(gateway) = first_host(address, pool.prefix_len()) {
+                let own_pool = Ipv4Net::new(address, pool.prefix_len()).unwrap().trunc();
+                prop_assert!(is_assignable(gateway, &own_pool));
+            }
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Startup Privilege Self-Check
+//!
+//! Establishing a tunnel needs a TUN device, the right to configure network
+//! interfaces and a way to run route/DNS/firewall commands (root, or `sudo`
+//! without a password prompt). Checking all of that up front lets the CLI and
+//! onboarding UIs tell the user exactly what to fix instead of failing halfway
+//! through tunnel setup.
+
+use crate::tunnel::Platform;
+use serde::Serialize;
+
+/// Linux capability bit for CAP_NET_ADMIN
+#[cfg(target_os = "linux")]
+const CAP_NET_ADMIN: u32 = 12;
+
+/// Kind of privilege or prerequisite that is missing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Privilege {
+    /// A TUN device cannot be opened
+    TunDevice,
+    /// Interfaces cannot be configured (Linux CAP_NET_ADMIN)
+    NetAdmin,
+    /// Route, DNS and firewall commands cannot be run through `sudo`
+    PrivilegeHelper,
+    /// The process is not elevated (macOS root / Windows administrator)
+    Administrator,
+    /// The TUN driver is not installed (Windows wintun)
+    Driver,
+    /// A system tool the tunnel relies on is not installed
+    Tool,
+}
+
+/// One missing privilege with a hint on how to fix it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MissingPrivilege {
+    pub privilege: Privilege,
+    /// What was found lacking
+    pub detail: String,
+    /// What the user can do about it
+    pub remediation: String,
+}
+
+/// Result of [`check_privileges`]
+#[derive(Debug, Clone, Serialize)]
+pub struct PrivilegeReport {
+    /// Operating system name
+    pub os: &'static str,
+    /// Missing privileges, empty if the tunnel can be set up
+    pub missing: Vec<MissingPrivilege>,
+}
+
+impl PrivilegeReport {
+    /// Whether nothing is missing
+    pub fn is_sufficient(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Serialize to a JSON object
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+    }
+}
+
+/// What the host offers, gathered once per check
+#[derive(Debug, Clone, Default)]
+struct HostFacts {
+    /// Running as root / elevated administrator
+    elevated: bool,
+    /// CAP_NET_ADMIN in the effective set (Linux)
+    net_admin: bool,
+    /// A TUN device can be opened (Linux) or the wintun driver is present (Windows)
+    tun_available: bool,
+    /// `sudo -n` works without prompting (Unix, only probed when not elevated)
+    sudo_available: bool,
+    /// Required tools not found in PATH
+    missing_tools: Vec<&'static str>,
+}
+
+/// Check whether this process can establish a tunnel on this host
+pub fn check_privileges() -> PrivilegeReport {
+    let platform = Platform::current();
+    PrivilegeReport {
+        os: std::env::consts::OS,
+        missing: evaluate(platform, &host_facts(platform)),
+    }
+}
+
+/// Turn host facts into the list of missing privileges for `platform`
+fn evaluate(platform: Platform, facts: &HostFacts) -> Vec<MissingPrivilege> {
+    let mut missing = Vec::new();
+    let mut push = |privilege, detail: &str, remediation: &str| {
+        missing.push(MissingPrivilege {
+            privilege,
+            detail: detail.to_string(),
+            remediation: remediation.to_string(),
+        })
+    };
+
+    match platform {
+        Platform::Linux => {
+            if !facts.tun_available {
+                push(
+                    Privilege::TunDevice,
+                    "/dev/net/tun cannot be opened",
+                    "load the tun module (modprobe tun) and make sure /dev/net/tun is readable and writable",
+                );
+            }
+            if !facts.elevated && !facts.net_admin {
+                push(
+                    Privilege::NetAdmin,
+                    "CAP_NET_ADMIN is not in the effective capability set",
+                    "grant CAP_NET_ADMIN (sudo setcap cap_net_admin+ep <client binary>) or run as root",
+                );
+            }
+            if !facts.elevated && !facts.sudo_available {
+                push(
+                    Privilege::PrivilegeHelper,
+                    "route, DNS and firewall changes run through sudo, which asks for a password",
+                    "allow passwordless sudo for ip, iptables and busctl (or resolvconf), or run as root",
+                );
+            }
+        }
+        Platform::MacOs => {
+            if !facts.elevated && !facts.sudo_available {
+                push(
+                    Privilege::Administrator,
+                    "not running as root and sudo asks for a password",
+                    "run as root or allow passwordless sudo for route, ifconfig and networksetup",
+                );
+            }
+        }
+        Platform::Windows => {
+            if !facts.elevated {
+                push(
+                    Privilege::Administrator,
+                    "the process is not elevated",
+                    "start the client from an elevated prompt (Run as administrator)",
+                );
+            }
+            if !facts.tun_available {
+                push(
+                    Privilege::Driver,
+                    "wintun.dll was not found",
+                    "download wintun from https://www.wintun.net and place wintun.dll next to the executable",
+                );
+            }
+        }
+        Platform::Other => {}
+    }
+
+    for tool in &facts.missing_tools {
+        push(
+            Privilege::Tool,
+            &format!("`{tool}` was not found in PATH"),
+            &format!("install {}", tool_package(tool)),
+        );
+    }
+
+    missing
+}
+
+/// Package that usually provides `tool`
+fn tool_package(tool: &str) -> &str {
+    match tool {
+        "ip" => "iproute2",
+        other => other,
+    }
+}
+
+/// Tools the platform operations shell out to
+fn required_tools(platform: Platform) -> &'static [&'static str] {
+    match platform {
+        Platform::Linux => &["ip", "iptables"],
+        Platform::MacOs => &["route", "ifconfig", "networksetup"],
+        Platform::Windows => &["netsh"],
+        Platform::Other => &[],
+    }
+}
+
+fn host_facts(platform: Platform) -> HostFacts {
+    let elevated = is_elevated();
+    HostFacts {
+        elevated,
+        net_admin: has_net_admin(),
+        tun_available: tun_available(),
+        sudo_available: !elevated && sudo_available(),
+        missing_tools: required_tools(platform)
+            .iter()
+            .copied()
+            .filter(|tool| !in_path(tool))
+            .collect(),
+    }
+}
+
+/// Whether `tool` is an executable file somewhere in PATH
+fn in_path(tool: &str) -> bool {
+    let Some(path) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&path).any(|dir| {
+        let candidate = dir.join(tool);
+        candidate.is_file() || candidate.with_extension("exe").is_file()
+    })
+}
+
+#[cfg(unix)]
+fn is_elevated() -> bool {
+    crate::capabilities::is_privileged()
+}
+
+#[cfg(windows)]
+fn is_elevated() -> bool {
+    // `net session` only succeeds in an elevated process
+    std::process::Command::new("net")
+        .arg("session")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_elevated() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn has_net_admin() -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            let caps = status.lines().find_map(|line| line.strip_prefix("CapEff:"))?;
+            u64::from_str_radix(caps.trim(), 16).ok()
+        })
+        .is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn has_net_admin() -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn tun_available() -> bool {
+    crate::capabilities::tun_available()
+}
+
+#[cfg(windows)]
+fn tun_available() -> bool {
+    let next_to_exe = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("wintun.dll")))
+        .is_some_and(|dll| dll.is_file());
+    let system = std::env::var_os("SystemRoot")
+        .map(|root| std::path::Path::new(&root).join("System32").join("wintun.dll"))
+        .is_some_and(|dll| dll.is_file());
+    next_to_exe || system
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn tun_available() -> bool {
+    // utun is part of the macOS kernel; opening it is covered by the root check
+    true
+}
+
+#[cfg(unix)]
+fn sudo_available() -> bool {
+    std::process::Command::new("sudo")
+        .args(["-n", "true"])
+        .stdin(std::process::Stdio::null())
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn sudo_available() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(missing: &[MissingPrivilege]) -> Vec<Privilege> {
+        missing.iter().map(|m| m.privilege).collect()
+    }
+
+    #[test]
+    fn test_linux_unprivileged_user() {
+        let facts = HostFacts {
+            tun_available: true,
+            missing_tools: vec!["ip"],
+            ..HostFacts::default()
+        };
+        let missing = evaluate(Platform::Linux, &facts);
+        assert_eq!(
+            kinds(&missing),
+            vec![Privilege::NetAdmin, Privilege::PrivilegeHelper, Privilege::Tool]
+        );
+        assert!(missing[0].remediation.contains("setcap cap_net_admin+ep"));
+        assert_eq!(missing[2].remediation, "install iproute2");
+
+        // CAP_NET_ADMIN plus passwordless sudo is enough without root
+        let facts = HostFacts {
+            net_admin: true,
+            tun_available: true,
+            sudo_available: true,
+            ..HostFacts::default()
+        };
+        assert!(evaluate(Platform::Linux, &facts).is_empty());
+    }
+
+    #[test]
+    fn test_elevated_hosts() {
+        let root = HostFacts { elevated: true, tun_available: true, ..HostFacts::default() };
+        assert!(evaluate(Platform::Linux, &root).is_empty());
+        assert!(evaluate(Platform::MacOs, &root).is_empty());
+        assert!(evaluate(Platform::Windows, &root).is_empty());
+
+        // Elevated but without the wintun driver
+        let no_driver = HostFacts { elevated: true, ..HostFacts::default() };
+        assert_eq!(kinds(&evaluate(Platform::Windows, &no_driver)), vec![Privilege::Driver]);
+    }
+
+    #[test]
+    fn test_report_json() {
+        let report = PrivilegeReport {
+            os: "linux",
+            missing: evaluate(Platform::MacOs, &HostFacts::default()),
+        };
+        assert!(!report.is_sufficient());
+        let json = report.to_json();
+        assert!(json.contains("\"privilege\":\"administrator\""));
+        assert!(json.contains("\"remediation\":"));
+    }
+}
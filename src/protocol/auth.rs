@@ -1,13 +1,121 @@
-use crate::error::VpnError;
+use crate::crypto::tls::CertificatePolicy;
+use crate::crypto::{self, SessionKeys};
+use crate::error::{ErrorCode, VpnError};
+use crate::net_util;
+use crate::protocol::cert_auth::{self, ClientCertificate};
+use crate::protocol::credentials::PasswordHash;
 use crate::protocol::watermark::WatermarkClient;
+use crate::protocol::identity::ClientIdentity;
+use crate::protocol::otp::{self, OtpChallenge, OtpProvider, PendingOtp, MAX_OTP_ATTEMPTS};
 use crate::protocol::pack::{Pack, Value};
+use crate::protocol::resumption::SessionTicket;
+use crate::protocol::rpc::{self, RetryPolicy, RpcMethod};
+use crate::protocol::udp_accel::UdpAccelOffer;
 use crate::tunnel::TunnelConfig;
+use crate::underlay::UnderlayBinding;
 use reqwest::Client as HttpClient;
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use ipnet::Ipv4Net;
+use zeroize::Zeroize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+/// `authtype` for anonymous login (username only)
+pub const CLIENT_AUTHTYPE_ANONYMOUS: u32 = 0;
+
+/// `authtype` for a password the server verifies externally (RADIUS, NT domain)
+pub const CLIENT_AUTHTYPE_PLAIN_PASSWORD: u32 = 2;
+
+// SoftEther error codes returned in the login response's `error` element,
+// and by the server when it ends a session
+const ERR_AUTHTYPE_NOT_SUPPORTED: u32 = 7;
+pub(crate) const ERR_HUB_NOT_FOUND: u32 = 8;
+const ERR_AUTH_FAILED: u32 = 9;
+const ERR_SESSION_REMOVED: u32 = 11;
+const ERR_ACCESS_DENIED: u32 = 12;
+const ERR_OLD_PASSWORD_WRONG: u32 = 44;
+// ERR_MSCHAP2_PASSWORD_NEED_RESET: the password expired and must be changed before login
+const ERR_PASSWORD_EXPIRED: u32 = 118;
+
+/// Pool the reference hub's SecureNAT DHCP server assigns from (diagnostics only)
+const EXPECTED_DHCP_POOL: (Ipv4Addr, u8) = (Ipv4Addr::new(10, 21, 255, 0), 24);
+
+/// Whether `ip` is an address the reference hub would assign
+fn in_expected_pool(ip: &str) -> bool {
+    let (network, prefix_len) = EXPECTED_DHCP_POOL;
+    match (ip.parse(), Ipv4Net::new(network, prefix_len)) {
+        (Ok(ip), Ok(pool)) => net_util::is_assignable(ip, &pool),
+        _ => false,
+    }
+}
+
+/// SoftEther password hash: SHA-0 of the password followed by the upper-cased username
+pub fn hash_password(username: &str, password: &str) -> [u8; crypto::sha0::SHA0_LEN] {
+    crypto::sha0(format!("{password}{}", username.to_uppercase()).as_bytes())
+}
+
+/// How the login PACK proves the user's identity
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoginMethod {
+    /// Password checked against the hub's user database
+    #[default]
+    Password,
+    /// Password forwarded in plain text (inside TLS) for RADIUS or NT domain checks
+    External,
+    /// Username only
+    Anonymous,
+    /// Client certificate and a signature over the server challenge
+    Certificate,
+}
+
+impl std::fmt::Display for LoginMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LoginMethod::Password => "password",
+            LoginMethod::External => "external (RADIUS/NT domain)",
+            LoginMethod::Anonymous => "anonymous",
+            LoginMethod::Certificate => "certificate",
+        })
+    }
+}
+
+/// Error for a non-zero `error` code in the login response
+///
+/// `ERR_AUTH_FAILED` means something different for every method, so the
+/// message says which credential the server refused. Other codes keep the
+/// server's number in [`VpnError::ServerRefused`].
+pub fn login_error(code: u32, method: LoginMethod) -> VpnError {
+    match code {
+        ERR_AUTH_FAILED => VpnError::Authentication(match method {
+            LoginMethod::Password => "Invalid username or password".to_string(),
+            LoginMethod::External => {
+                "RADIUS/NT domain authentication failed (rejected or the server could not reach it)".to_string()
+            }
+            LoginMethod::Anonymous => "Anonymous login refused for this user".to_string(),
+            LoginMethod::Certificate => "Client certificate rejected".to_string(),
+        }),
+        ERR_AUTHTYPE_NOT_SUPPORTED => {
+            VpnError::Config(format!("Hub does not allow {method} authentication"))
+        }
+        ERR_PASSWORD_EXPIRED => VpnError::ServerRefused {
+            code,
+            message: "Password expired; set a new one with change_password before logging in".to_string(),
+        },
+        code => VpnError::ServerRefused { code, message: ErrorCode::from_server(code).to_string() },
+    }
+}
+
+/// Whether a session the server ended with `code` would be refused again
+///
+/// An administrator removing the session, a deleted hub or a revoked user
+/// stay that way; a stopping hub or a busy server may not.
+pub fn disconnect_is_permanent(code: u32) -> bool {
+    matches!(code, ERR_HUB_NOT_FOUND | ERR_AUTH_FAILED | ERR_SESSION_REMOVED | ERR_ACCESS_DENIED)
+}
+
 /// Authentication client for SoftEther VPN protocol
 pub struct AuthClient {
     watermark_client: WatermarkClient,
@@ -17,12 +125,26 @@ pub struct AuthClient {
     hub_name: String,
     username: String,
     password: String,
-    verify_certificate: bool,
+    password_hash: Option<PasswordHash>,  // Cached hash standing in for the password
+    certificates: CertificatePolicy,  // Server certificates accepted by every HTTP client
+    proxy_url: Option<String>,  // Proxy for HTTP requests to the server
+    identity: ClientIdentity,  // Client name/version/user agent reported to the server
     stream: Option<TcpStream>,
     session_id: Option<String>,
     is_authenticated: bool,
     pack_data: Option<Pack>,  // Store the authentication response PACK data
     ip_config: Option<crate::protocol::pack::IpConfiguration>,  // Store extracted IP config
+    udp_accel_offer: Option<UdpAccelOffer>,  // UDP acceleration requested in the login PACK
+    max_connections: u32,  // Data connections requested in the login PACK
+    use_compression: bool,  // Compression requested in the login PACK
+    underlay: Option<UnderlayBinding>,  // Uplink all HTTP connections are bound to
+    login_method: LoginMethod,  // Credential sent in the login PACK
+    client_certificate: Option<ClientCertificate>,  // Used by certificate login
+    server_challenge: Option<Vec<u8>>,  // Random from the server hello, signed for certificate auth
+    otp_provider: Option<Arc<dyn OtpProvider>>,  // Answers second-factor challenges
+    otp_timeout: Duration,  // How long the provider may take per code
+    session_ticket: Option<SessionTicket>,  // Tried before the login, consumed by it
+    resumed: bool,  // Whether the last authentication resumed a session
 }
 
 impl AuthClient {
@@ -48,20 +170,319 @@ impl AuthClient {
             hub_name,
             username,
             password,
-            verify_certificate,
+            password_hash: None,
+            certificates: CertificatePolicy::new(verify_certificate),
+            proxy_url: None,
+            identity: ClientIdentity::default(),
             stream: None,
             session_id: None,
             is_authenticated: false,
             pack_data: None,
             ip_config: None,
+            udp_accel_offer: None,
+            max_connections: 1,
+            use_compression: false,
+            underlay: None,
+            login_method: LoginMethod::Password,
+            client_certificate: None,
+            server_challenge: None,
+            otp_provider: None,
+            otp_timeout: Duration::from_secs(120),
+            session_ticket: None,
+            resumed: false,
         })
     }
 
+    /// Route HTTP requests to the server through `proxy_url` (`None` for direct)
+    pub fn set_proxy(&mut self, proxy_url: Option<String>) -> Result<(), VpnError> {
+        self.proxy_url = proxy_url;
+        self.rebuild_watermark_client()
+    }
+
+    /// Bind all HTTP connections to the server to `underlay` (`None` lets the OS choose)
+    pub fn set_underlay(&mut self, underlay: Option<UnderlayBinding>) -> Result<(), VpnError> {
+        self.underlay = underlay;
+        self.rebuild_watermark_client()
+    }
+
+    /// Accept the server certificates `certificates` allows, replacing `verify_certificate`
+    pub fn set_certificate_policy(&mut self, certificates: CertificatePolicy) -> Result<(), VpnError> {
+        self.certificates = certificates;
+        self.rebuild_watermark_client()
+    }
+
+    fn rebuild_watermark_client(&mut self) -> Result<(), VpnError> {
+        self.watermark_client = WatermarkClient::with_underlay(
+            self.watermark_client.server_addr,
+            self.watermark_client.hostname.clone(),
+            &self.certificates,
+            self.proxy_url.as_deref(),
+            self.underlay.as_ref(),
+        )?;
+        self.watermark_client.base_url = self.server_endpoint.clone();
+        self.watermark_client.set_user_agent(&self.identity.user_agent);
+        Ok(())
+    }
+
+    /// Send HTTP requests to `base_url` instead of `https://` the server, e.g. a
+    /// [`TlsRelay`](crate::crypto::tls::TlsRelay)
+    pub fn set_base_url(&mut self, base_url: &str) {
+        self.server_endpoint = base_url.to_string();
+        self.watermark_client.base_url = self.server_endpoint.clone();
+    }
+
+    /// Report `identity` in all subsequent requests and packets
+    pub fn set_identity(&mut self, identity: ClientIdentity) {
+        self.watermark_client.set_user_agent(&identity.user_agent);
+        self.identity = identity;
+    }
+
+    /// Request UDP acceleration with `offer` in the next login
+    pub fn set_udp_accel_offer(&mut self, offer: Option<UdpAccelOffer>) {
+        self.udp_accel_offer = offer;
+    }
+
+    /// Take back the offer sent with the login, to complete it with the server's answer
+    pub fn take_udp_accel_offer(&mut self) -> Option<UdpAccelOffer> {
+        self.udp_accel_offer.take()
+    }
+
+    /// Request `count` data connections for the session in the next login
+    pub fn set_max_connections(&mut self, count: u32) {
+        self.max_connections = count.max(1);
+    }
+
+    /// Data connections the server allowed, at most as many as requested
+    ///
+    /// One until logged in, or when the server did not say.
+    pub fn granted_connections(&self) -> u32 {
+        let granted = self.pack_data.as_ref().and_then(|pack| pack.get_int("max_connection")).unwrap_or(1);
+        granted.clamp(1, self.max_connections)
+    }
+
+    /// Session timeout the server announced in its welcome, `None` until logged in
+    pub fn session_timeout(&self) -> Option<Duration> {
+        let millis = self.pack_data.as_ref().and_then(|pack| pack.get_int("timeout"))?;
+        (millis > 0).then(|| Duration::from_millis(millis.into()))
+    }
+
+    /// Ask for compressed data packets in the next login
+    pub fn set_compression(&mut self, enabled: bool) {
+        self.use_compression = enabled;
+    }
+
+    /// Whether data packets are compressed: asked for, and agreed by the server
+    pub fn compression_negotiated(&self) -> bool {
+        let agreed = self.pack_data.as_ref().and_then(|pack| pack.get_int("use_compress")).unwrap_or(0) != 0;
+        self.use_compression && agreed
+    }
+
+    /// Select the credential sent at login (password unless changed)
+    pub fn set_login_method(&mut self, method: LoginMethod) {
+        self.login_method = method;
+    }
+
+    /// Credential sent at login
+    pub fn login_method(&self) -> LoginMethod {
+        self.login_method
+    }
+
+    /// Log in with a cached password hash, wiping the plaintext password
+    pub fn set_password_hash(&mut self, hash: PasswordHash) {
+        self.password.zeroize();
+        self.password_hash = Some(hash);
+    }
+
+    /// Hash of the password: the cached one, or computed from the plaintext
+    pub fn password_hash(&self) -> PasswordHash {
+        self.password_hash
+            .clone()
+            .unwrap_or_else(|| PasswordHash::new(&self.username, &self.password))
+    }
+
+    /// Hash worth caching for reconnects
+    ///
+    /// Only for password logins that sent `secure_password`; servers without
+    /// a hello random take the plaintext, which the hash cannot replace.
+    pub fn cacheable_password_hash(&self) -> Option<PasswordHash> {
+        (self.login_method == LoginMethod::Password && self.server_challenge.is_some()).then(|| self.password_hash())
+    }
+
+    /// Authenticate with a client certificate instead of the password
+    ///
+    /// The login then carries the certificate and a signature over the
+    /// challenge from the server hello. `None` falls back to password login.
+    pub fn set_client_certificate(&mut self, certificate: Option<ClientCertificate>) {
+        self.login_method = if certificate.is_some() {
+            LoginMethod::Certificate
+        } else {
+            LoginMethod::Password
+        };
+        self.client_certificate = certificate;
+    }
+
+    /// Answer hubs asking for a second factor with codes from `provider`
+    ///
+    /// Each code must arrive within `timeout`. Without a provider such a
+    /// login fails.
+    pub fn set_otp_provider(&mut self, provider: Option<Arc<dyn OtpProvider>>, timeout: Duration) {
+        self.otp_provider = provider;
+        self.otp_timeout = timeout;
+    }
+
+    /// Try resuming the session `ticket` names before the next login
+    ///
+    /// If the server refuses it, the login goes ahead as usual.
+    pub fn set_session_ticket(&mut self, ticket: Option<SessionTicket>) {
+        self.session_ticket = ticket;
+    }
+
+    /// Whether the last authentication resumed a session instead of logging in
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    /// Ticket for resuming the current session, if the server issued one
+    pub fn session_ticket(&self) -> Option<SessionTicket> {
+        SessionTicket::from_login(&self.server_address, &self.hub_name, &self.username, self.pack_data.as_ref()?)
+    }
+
+    /// Resume the session of `ticket`, returning the server's login response
+    async fn resume_session(&self, ticket: &SessionTicket) -> Result<Pack, VpnError> {
+        let random = self
+            .server_challenge
+            .as_deref()
+            .ok_or_else(|| VpnError::Protocol("Server hello carried no random to resume against".into()))?;
+        let mut pack = ticket.resume_pack(random);
+        self.identity.apply_to_pack(&mut pack);
+        let response = self.post_pack(&pack).await?;
+        match response.get_int("error") {
+            Some(0) => Ok(response),
+            Some(code) => Err(VpnError::ServerRefused { code, message: "Session resumption refused".into() }),
+            None => Err(VpnError::Protocol("Resumption answered without a result".into())),
+        }
+    }
+
+    /// Answer the hub's second-factor challenge, returning the login response it leads to
+    async fn answer_otp(&self, mut pending: PendingOtp) -> Result<Pack, VpnError> {
+        let provider = self.otp_provider.clone().ok_or_else(|| {
+            VpnError::Config(format!("Hub {} requires a one-time password, but no OTP provider is set", self.hub_name))
+        })?;
+        for attempt in 1..=MAX_OTP_ATTEMPTS {
+            let challenge = OtpChallenge {
+                username: self.username.clone(),
+                hub: self.hub_name.clone(),
+                message: pending.message.clone(),
+                attempt,
+            };
+            log::info!(hub = self.hub_name.as_str(), attempt; "Hub asks for a one-time password");
+            let code = otp::ask(provider.as_ref(), challenge, self.otp_timeout).await?;
+            let response = self.post_pack(&otp::answer_pack(&self.hub_name, &self.username, &pending, &code)).await?;
+            match otp::pending_otp(&response) {
+                Some(next) => pending = next,
+                None => return Ok(response),
+            }
+        }
+        Err(VpnError::Authentication(format!("One-time password rejected {MAX_OTP_ATTEMPTS} times")))
+    }
+
+    /// Change the account's password on the server
+    ///
+    /// Needs no login, so it also renews a password that has expired. The
+    /// old password is proven as at login, against the random of a fresh
+    /// server hello; the new one is sent as its SoftEther and NT hashes only.
+    /// Later logins of this client use the new password.
+    pub async fn change_password(&mut self, old_password: &str, new_password: &str) -> Result<(), VpnError> {
+        log::info!(hub = self.hub_name.as_str(), user = self.username.as_str(); "Changing password");
+        let hello = rpc::call_with_retry(RpcMethod::Watermark, &RetryPolicy::default(), || {
+            self.watermark_client.send_watermark_handshake()
+        })
+        .await?;
+        let random = Pack::from_bytes(hello.response_data.into())
+            .ok()
+            .and_then(|hello| cert_auth::challenge_from_hello(&hello))
+            .ok_or_else(|| VpnError::Protocol("Server hello carried no random to prove the old password".to_string()))?;
+
+        let pack = self.change_password_pack(old_password, new_password, &random);
+        let response = rpc::call_with_retry(RpcMethod::ChangePassword, &RetryPolicy::default(), || {
+            self.post_pack(&pack)
+        })
+        .await?;
+        match response.get_int("error") {
+            None | Some(0) => {}
+            Some(ERR_AUTH_FAILED | ERR_OLD_PASSWORD_WRONG) => {
+                return Err(VpnError::Authentication("Old password is wrong".to_string()));
+            }
+            Some(code) => {
+                return Err(VpnError::ServerRefused { code, message: ErrorCode::from_server(code).to_string() });
+            }
+        }
+
+        log::info!("Password changed");
+        self.password.zeroize();
+        self.password = new_password.to_string();
+        self.password_hash = None;
+        Ok(())
+    }
+
+    /// Password change request: the old password bound to `random`, the new one hashed
+    pub(crate) fn change_password_pack(&self, old_password: &str, new_password: &str, random: &[u8]) -> Pack {
+        let old_hash = PasswordHash::new(&self.username, old_password);
+        let new_hash = PasswordHash::new(&self.username, new_password);
+        let utf16: zeroize::Zeroizing<Vec<u8>> =
+            zeroize::Zeroizing::new(new_password.encode_utf16().flat_map(u16::to_le_bytes).collect());
+
+        let mut pack = Pack::new();
+        pack.add_str("method", "password");
+        pack.add_str("hubname", &self.hub_name);
+        pack.add_str("username", &self.username);
+        pack.add_data("secure_old_password", old_hash.secure_password(random).to_vec());
+        pack.add_data("new_password", new_hash.as_bytes().to_vec());
+        pack.add_data("new_password_ntlm", crypto::md::md4(&utf16).to_vec());
+        self.identity.apply_to_pack(&mut pack);
+        pack
+    }
+
+    /// POST `pack` to the server's `connect.cgi` and parse the PACK it answers with
+    async fn post_pack(&self, pack: &Pack) -> Result<Pack, VpnError> {
+        let url = format!("{}/vpnsvc/connect.cgi", self.server_endpoint);
+        let data = pack.to_bytes()?;
+        let mut request = self
+            .watermark_client
+            .http_client
+            .post(&url)
+            .header("User-Agent", &self.watermark_client.user_agent)
+            .header("Content-Type", "application/octet-stream")
+            .header("Connection", "Keep-Alive");
+        if let Some(hostname) = &self.watermark_client.hostname {
+            request = request.header("Host", hostname);
+        }
+        let response = request
+            .body(data)
+            .send()
+            .await
+            .map_err(|e| VpnError::Network(format!("Failed to send request: {e}")))?;
+        if !response.status().is_success() {
+            return Err(VpnError::Protocol(format!("Server answered HTTP {}", response.status())));
+        }
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| VpnError::Network(format!("Failed to read response: {e}")))?;
+        Pack::from_bytes(body)
+    }
+
     /// Internal method for authentication with stream
     async fn authenticate_with_stream(&mut self, stream: &mut TcpStream) -> Result<String, VpnError> {
         // Step 1: HTTP Watermark handshake
         log::info!("Starting HTTP Watermark handshake");
-        let _watermark_response = self.watermark_client.send_watermark_handshake().await?;
+        let watermark_response = rpc::call_with_retry(RpcMethod::Watermark, &RetryPolicy::default(), || {
+            self.watermark_client.send_watermark_handshake()
+        })
+        .await?;
+        self.server_challenge = Pack::from_bytes(watermark_response.response_data.into())
+            .ok()
+            .and_then(|hello| cert_auth::challenge_from_hello(&hello));
         
         // Step 2: Authenticate directly (no session establishment needed)
         self.perform_hub_authentication(stream).await?;
@@ -84,6 +505,7 @@ impl AuthClient {
         let data = pack.to_bytes()?;
         let response = self.watermark_client.http_client
             .post(&url)
+            .header("User-Agent", &self.watermark_client.user_agent)
             .header("Content-Type", "application/octet-stream")
             .header("Content-Length", &data.len().to_string())
             .header("Connection", "Keep-Alive")
@@ -226,28 +648,76 @@ impl AuthClient {
         }
     }
 
-    /// Perform hub authentication
-    async fn perform_hub_authentication(&mut self, _stream: &mut TcpStream) -> Result<(), VpnError> {
-        log::info!("Authenticating with hub: {}", self.hub_name);
-        
-        // Create authentication packet for clustered SoftEther server
+    /// Login request PACK sent for hub authentication
+    ///
+    /// Fails for certificate authentication if no certificate is loaded or
+    /// the server hello carried no challenge to sign.
+    pub(crate) fn login_pack(&self) -> Result<Pack, VpnError> {
         let mut pack = Pack::new();
         pack.add_str("method", "login");
         pack.add_str("username", &self.username);
-        pack.add_str("password", &self.password);
+        match self.login_method {
+            LoginMethod::Password => match self.server_challenge.as_deref() {
+                Some(random) => pack.add_data("secure_password", self.password_hash().secure_password(random).to_vec()),
+                None => pack.add_str("password", &self.password),
+            },
+            LoginMethod::External => {
+                pack.add_int("authtype", CLIENT_AUTHTYPE_PLAIN_PASSWORD);
+                pack.add_str("plain_password", &self.password);
+            }
+            LoginMethod::Anonymous => pack.add_int("authtype", CLIENT_AUTHTYPE_ANONYMOUS),
+            LoginMethod::Certificate => {
+                let certificate = self.client_certificate.as_ref().ok_or_else(|| {
+                    VpnError::Config("No client certificate loaded for certificate authentication".to_string())
+                })?;
+                let challenge = self.server_challenge.as_deref().ok_or_else(|| {
+                    VpnError::Protocol("Server sent no challenge for certificate authentication".to_string())
+                })?;
+                certificate.apply_to_pack(&mut pack, challenge)?;
+            }
+        }
         pack.add_str("hub", &self.hub_name);
         
         // Remove no_save_password - this is server policy, not client parameter
         
         // Parameters for clustered SoftEther VPN
-        pack.add_int("client_ver", 4560);  // SoftEther client version
-        pack.add_str("client_str", "SE-VPN Client");
-        pack.add_int("client_build", 9686);
+        self.identity.apply_to_pack(&mut pack);
         
         // Clustering-specific parameters
         pack.add_str("cluster_member_cert", "");  // Empty for now
         pack.add_int("use_encrypt", 1);  // Use encryption
-        pack.add_int("use_compress", 1);  // Use compression
+        pack.add_int("use_compress", u32::from(self.use_compression));
+        // A single connection is what servers assume without it
+        if self.max_connections > 1 {
+            pack.add_int("max_connection", self.max_connections);
+            pack.add_int("half_connection", 0);
+        }
+        if let Some(ref offer) = self.udp_accel_offer {
+            offer.apply_to_pack(&mut pack);
+        }
+        Ok(pack)
+    }
+
+    /// Perform hub authentication
+    async fn perform_hub_authentication(&mut self, _stream: &mut TcpStream) -> Result<(), VpnError> {
+        log::info!("Authenticating with hub: {}", self.hub_name);
+
+        // A ticket from an earlier login skips the login, unless the session is gone
+        self.resumed = false;
+        if let Some(ticket) = self.session_ticket.take() {
+            match self.resume_session(&ticket).await {
+                Ok(response) => {
+                    log::info!(hub = self.hub_name.as_str(), session = ticket.session_name.as_str(); "Session resumed");
+                    self.pack_data = Some(response);
+                    self.resumed = true;
+                    return Ok(());
+                }
+                Err(e) => log::info!(hub = self.hub_name.as_str(); "Session not resumed, logging in: {}", e),
+            }
+        }
+        
+        // Create authentication packet for clustered SoftEther server
+        let pack = self.login_pack()?;
         
         // Send via HTTP POST to the same connect.cgi endpoint  
         let url = format!("{}/vpnsvc/connect.cgi", self.server_endpoint);
@@ -255,6 +725,7 @@ impl AuthClient {
         let data = pack.to_bytes()?;
         let mut auth_request = self.watermark_client.http_client
             .post(&url)
+            .header("User-Agent", &self.watermark_client.user_agent)
             .header("Content-Type", "application/octet-stream")
             .header("Content-Length", &data.len().to_string())
             .header("Connection", "Keep-Alive");
@@ -291,6 +762,11 @@ impl AuthClient {
         match Pack::from_bytes(response_data.to_vec().into()) {
             Ok(mut response_pack) => {
                 log::debug!("Successfully parsed PACK response with {} elements", response_pack.elements.len());
+
+                // Second factor: the real login response follows the code
+                if let Some(pending) = otp::pending_otp(&response_pack) {
+                    response_pack = self.answer_otp(pending).await?;
+                }
                 
                 // Store the pack data for IP analysis
                 self.pack_data = Some(response_pack.clone());
@@ -312,6 +788,16 @@ impl AuthClient {
                     log::debug!("❌ No binary session data available for IP analysis");
                 }
                 
+                // Numeric error code: 0 is success, anything else is a refusal specific to the method
+                if let Some(code) = response_pack.get_int("error") {
+                    if code != 0 {
+                        log::info!("Server refused {} login with error {}", self.login_method, code);
+                        return Err(login_error(code, self.login_method));
+                    }
+                    log::info!("Authentication successful");
+                    return Ok(());
+                }
+
                 // Check for error element (which we know we can parse successfully)
                 if let Some(error_element) = response_pack.get_element("error") {
                     log::debug!("Found error element with {} values", error_element.values.len());
@@ -416,12 +902,14 @@ impl AuthClient {
 
     /// Connect to the server and perform authentication
     pub async fn authenticate(&mut self, username: &str, password: &str) -> Result<(), VpnError> {
-        // Update credentials if provided
-        if !username.is_empty() {
+        // Update credentials if provided; a cached hash no longer matches them
+        if !username.is_empty() && username != self.username {
             self.username = username.to_string();
+            self.password_hash = None;
         }
         if !password.is_empty() {
             self.password = password.to_string();
+            self.password_hash = None;
         }
 
         // Connect to server if not already connected
@@ -461,7 +949,14 @@ impl AuthClient {
         }
 
         log::warn!("HTTP keepalive called - this should only be used before SSL-VPN mode");
-        
+
+        rpc::call_with_retry(RpcMethod::Keepalive, &RetryPolicy::default(), || {
+            self.send_keepalive_once()
+        })
+        .await
+    }
+
+    async fn send_keepalive_once(&self) -> Result<(), VpnError> {
         // Create a proper SoftEther keepalive packet
         let mut pack = Pack::new();
         pack.add_str("method", "keepalive");
@@ -482,6 +977,7 @@ impl AuthClient {
         
         let mut request = self.watermark_client.http_client
             .post(&url)
+            .header("User-Agent", &self.watermark_client.user_agent)
             .header("Content-Type", "application/octet-stream")
             .header("Content-Length", &data.len().to_string())
             .header("Connection", "Keep-Alive");
@@ -528,13 +1024,18 @@ impl AuthClient {
     /// Request IP configuration from SoftEther server (DHCP-like)
     pub async fn request_ip_config(&self) -> Result<TunnelConfig, VpnError> {
         log::info!("🌐 Requesting IP configuration from VPN server...");
-        
+
+        rpc::call_with_retry(RpcMethod::GetConfig, &RetryPolicy::default(), || {
+            self.request_ip_config_once()
+        })
+        .await
+    }
+
+    async fn request_ip_config_once(&self) -> Result<TunnelConfig, VpnError> {
         // Create GetConfig packet to request IP assignment
         let mut pack = Pack::new();
         pack.add_str("method", "GetConfig");
-        pack.add_str("client_str", "SE-VPN Client");
-        pack.add_int("client_ver", 4560);
-        pack.add_int("client_build", 9686);
+        self.identity.apply_to_pack(&mut pack);
         
         // Request DHCP-like IP assignment
         pack.add_str("request_type", "dhcp_ip");
@@ -545,6 +1046,7 @@ impl AuthClient {
         
         let mut request = self.watermark_client.http_client
             .post(&url)
+            .header("User-Agent", &self.watermark_client.user_agent)
             .header("Content-Type", "application/octet-stream")
             .header("Content-Length", &data.len().to_string())
             .header("Connection", "Keep-Alive");
@@ -585,13 +1087,28 @@ impl AuthClient {
                 let mtu = response_pack.get_int("mtu")
                     .unwrap_or(1500) as u16;
                     
-                let dns1 = response_pack.get_str("dns1").map_or("8.8.8.8", |v| v);
-                let dns2 = response_pack.get_str("dns2").map_or("8.8.4.4", |v| v);
-                    
-                let dns_servers = vec![
-                    dns1.parse().unwrap_or(std::net::Ipv4Addr::new(8, 8, 8, 8)),
-                    dns2.parse().unwrap_or(std::net::Ipv4Addr::new(8, 8, 4, 4)),
-                ];
+                // Servers may send several addresses per element; keep all of them
+                let mut dns_servers = Vec::new();
+                for name in ["dns1", "dns2"] {
+                    match response_pack.get_ip_list(name) {
+                        Ok(list) => {
+                            for ip in list {
+                                if let IpAddr::V4(ip) = ip {
+                                    if !dns_servers.contains(&ip) {
+                                        dns_servers.push(ip);
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => log::warn!("Ignoring malformed {} list: {}", name, e),
+                    }
+                }
+                if dns_servers.is_empty() {
+                    dns_servers = vec![
+                        std::net::Ipv4Addr::new(8, 8, 8, 8),
+                        std::net::Ipv4Addr::new(8, 8, 4, 4),
+                    ];
+                }
                 
                 log::info!("📍 Server assigned IP: {}", local_ip);
                 log::info!("📍 Server gateway IP: {}", remote_ip);
@@ -599,7 +1116,8 @@ impl AuthClient {
                 log::info!("📍 MTU: {}", mtu);
                 log::info!("📍 DNS servers: {:?}", dns_servers);
                 
-                use crate::tunnel::TunnelConfig;
+                use crate::tunnel::{LeaseOptions, TunnelConfig};
+                let lease = LeaseOptions::from_pack(&response_pack);
                 Ok(TunnelConfig {
                     interface_name: "vpnse0".to_string(),
                     local_ip: local_ip.parse()
@@ -610,6 +1128,17 @@ impl AuthClient {
                         .map_err(|e| VpnError::Config(format!("Invalid netmask: {}", e)))?,
                     mtu,
                     dns_servers,
+                    pushed_routes: crate::tunnel::pushed_routes::from_pack(&response_pack),
+                    wins_servers: lease.wins_servers,
+                    dns_domain: lease.dns_domain,
+                    ipv6: lease.ipv6,
+                    bypass_cgroups: Vec::new(),
+                    only_cgroups: Vec::new(),
+                    mode: Default::default(),
+                    windows_driver: Default::default(),
+                    auto_mtu: false,
+                    queues: 1,
+                    offload: false,
                 })
             }
             Err(_) => {
@@ -621,6 +1150,53 @@ impl AuthClient {
         }
     }
 
+    /// Static routes pushed in the authentication response
+    pub fn pushed_routes(&self) -> Vec<crate::tunnel::PushedRoute> {
+        self.pack_data
+            .as_ref()
+            .map(crate::tunnel::pushed_routes::from_pack)
+            .unwrap_or_default()
+    }
+
+    /// WINS servers and DNS domain from the authentication response
+    pub fn lease_options(&self) -> crate::tunnel::LeaseOptions {
+        self.pack_data
+            .as_ref()
+            .map(crate::tunnel::LeaseOptions::from_pack)
+            .unwrap_or_default()
+    }
+
+    /// Packet framing parameters from the authentication response, if the server sent a session key
+    pub fn framing_params(&self) -> Option<crate::tunnel::FramingParams> {
+        self.pack_data.as_ref().and_then(crate::tunnel::FramingParams::from_pack)
+    }
+
+    /// Keys encrypting the data path, derived from the login exchange
+    ///
+    /// Combines the password hash (password and external logins) with the
+    /// `session_key` from the login response, salted with the random from the
+    /// server hello. `None` before login, or when the server sent no random or
+    /// there is no secret to derive from.
+    pub fn session_keys(&self) -> Option<SessionKeys> {
+        let random = self.server_challenge.as_deref()?;
+        let password_hash = match self.login_method {
+            LoginMethod::Password | LoginMethod::External => Some(self.password_hash()),
+            LoginMethod::Anonymous | LoginMethod::Certificate => None,
+        };
+        let session_key = self.pack_data.as_ref()?.get_data("session_key");
+        let secrets = [
+            password_hash.as_ref().map_or(&[][..], |hash| &hash.as_bytes()[..]),
+            session_key.map_or(&[][..], Vec::as_slice),
+        ];
+        match SessionKeys::derive(random, &secrets) {
+            Ok(keys) => Some(keys),
+            Err(e) => {
+                log::warn!("No session keys for the data path: {}", e);
+                None
+            }
+        }
+    }
+
     /// Get the IP configuration extracted from authentication response
     pub fn get_ip_config(&self) -> Option<&crate::protocol::pack::IpConfiguration> {
         log::info!("🔍 get_ip_config() called - checking stored config...");
@@ -708,9 +1284,7 @@ impl AuthClient {
         pack.add_int("use_ssl_vpn", 1);
         pack.add_int("use_encrypt", 1);
         pack.add_int("use_compress", 0); // Disable compression for stability
-        pack.add_str("client_str", "SE-VPN Client");
-        pack.add_int("client_ver", 4560);
-        pack.add_int("client_build", 9686);
+        self.identity.apply_to_pack(&mut pack);
         
         // Request server to assign IP via DHCP-like mechanism
         pack.add_str("request_dhcp", "1");
@@ -738,17 +1312,27 @@ impl AuthClient {
         // CRITICAL FIX: Create a fresh HTTP client for SSL-VPN handshake
         // The original client might have connection state issues after authentication
         log::debug!("🔄 Creating fresh HTTP client for SSL-VPN handshake...");
-        let mut fresh_client_builder = reqwest::Client::builder()
-            .user_agent("SoftEther VPN Client");
+        let fresh_client_builder = reqwest::Client::builder()
+            .user_agent(self.identity.user_agent.as_str());
 
         // Match the TLS verification settings from the original client
-        if !self.verify_certificate {
-            fresh_client_builder = fresh_client_builder.danger_accept_invalid_certs(true);
+        let mut fresh_client_builder = self.certificates.apply_to(fresh_client_builder)?;
+        if self.certificates.accepts_any() {
             log::debug!("🔓 SSL certificate verification disabled");
         } else {
             log::debug!("🔒 SSL certificate verification enabled");
         }
 
+        if let Some(ref proxy_url) = self.proxy_url {
+            let proxy = reqwest::Proxy::all(proxy_url.as_str())
+                .map_err(|e| VpnError::Proxy(format!("Invalid proxy URL '{}': {}", proxy_url, e)))?;
+            fresh_client_builder = fresh_client_builder.proxy(proxy);
+        }
+
+        if let Some(ref underlay) = self.underlay {
+            fresh_client_builder = underlay.apply_to(fresh_client_builder);
+        }
+
         let fresh_http_client = fresh_client_builder.build()
             .map_err(|e| VpnError::Network(format!("Failed to create fresh HTTP client: {}", e)))?;
         
@@ -852,7 +1436,7 @@ impl AuthClient {
                 
                 if let Some(ip) = assigned_ip {
                     log::info!("🎯 SSL-VPN response contains IP assignment: {}", ip);
-                    if ip.starts_with("10.21.255.") {
+                    if in_expected_pool(ip) {
                         log::info!("✅ Got expected IP range in SSL-VPN response!");
                     }
                 }
@@ -894,9 +1478,7 @@ impl AuthClient {
         // Create DHCP-specific request 
         let mut pack = Pack::new();
         pack.add_str("method", "get_dhcp_config");
-        pack.add_str("client_str", "SE-VPN Client");
-        pack.add_int("client_ver", 4560);
-        pack.add_int("client_build", 9686);
+        self.identity.apply_to_pack(&mut pack);
         
         // Add session information
         if let Some(session_id) = &self.session_id {
@@ -921,6 +1503,7 @@ impl AuthClient {
         
         let mut request = self.watermark_client.http_client
             .post(&url)
+            .header("User-Agent", &self.watermark_client.user_agent)
             .header("Content-Type", "application/octet-stream")
             .header("Content-Length", &data.len().to_string())
             .header("Connection", "Keep-Alive");
@@ -1009,14 +1592,24 @@ impl AuthClient {
                     log::info!("🎯 SUCCESS: DHCP gateway IP: {}", gateway);
                     log::info!("🎯 SUCCESS: DHCP netmask: {}", mask);
                     
+                    // The lease must be a host of the subnet it names
+                    let lease_subnet = mask.parse().ok().and_then(|mask| net_util::ipv4_subnet(gateway.parse().ok()?, mask));
+                    match (local.parse(), lease_subnet) {
+                        (Ok(local), Some(subnet)) if !net_util::is_assignable(local, &subnet) => {
+                            log::warn!("⚠️  Assigned IP {} is not a host address of {}", local, subnet);
+                        }
+                        _ => {}
+                    }
+
                     // Validate that we got the expected IP range (10.21.255.x)
-                    if local.starts_with("10.21.255.") {
+                    if in_expected_pool(local) {
                         log::info!("✅ Got expected IP range (10.21.255.x) - DHCP working correctly!");
                     } else {
                         log::warn!("⚠️  Got unexpected IP range: {} (expected 10.21.255.x)", local);
                     }
                     
-                    use crate::tunnel::TunnelConfig;
+                    use crate::tunnel::{LeaseOptions, TunnelConfig};
+                    let lease = LeaseOptions::from_pack(&response_pack);
                     return Ok(TunnelConfig {
                         interface_name: "vpnse0".to_string(),
                         local_ip: local.parse()
@@ -1030,6 +1623,17 @@ impl AuthClient {
                             "8.8.8.8".parse().unwrap_or(std::net::Ipv4Addr::new(8, 8, 8, 8)),
                             "8.8.4.4".parse().unwrap_or(std::net::Ipv4Addr::new(8, 8, 4, 4)),
                         ],
+                        pushed_routes: crate::tunnel::pushed_routes::from_pack(&response_pack),
+                        wins_servers: lease.wins_servers,
+                        dns_domain: lease.dns_domain,
+                        ipv6: lease.ipv6,
+                        bypass_cgroups: Vec::new(),
+                        only_cgroups: Vec::new(),
+                        mode: Default::default(),
+                        windows_driver: Default::default(),
+                        auto_mtu: false,
+                        queues: 1,
+                        offload: false,
                     });
                 }
                 
@@ -1042,7 +1646,7 @@ impl AuthClient {
                         if value.chars().all(|c| c.is_ascii_digit() || c == '.') && value.contains('.') {
                             if let Ok(ip) = value.parse::<std::net::Ipv4Addr>() {
                                 log::info!("� Found IP-like value in '{}': {}", name, ip);
-                                if ip.to_string().starts_with("10.21.255.") {
+                                if in_expected_pool(&ip.to_string()) {
                                     log::info!("🎯 Found expected IP in field '{}': {}", name, ip);
                                 }
                             }
@@ -1087,4 +1691,178 @@ impl AuthClient {
     
         Ok((stream, session_id))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(method: LoginMethod) -> AuthClient {
+        let mut client = AuthClient::new(
+            "127.0.0.1:443".to_string(),
+            None,
+            "VPN".to_string(),
+            "alice".to_string(),
+            "secret".to_string(),
+            false,
+        )
+        .unwrap();
+        client.set_login_method(method);
+        client
+    }
+
+    #[test]
+    fn test_login_pack_per_method() {
+        let pack = client(LoginMethod::External).login_pack().unwrap();
+        assert_eq!(pack.get_int("authtype"), Some(CLIENT_AUTHTYPE_PLAIN_PASSWORD));
+        assert_eq!(pack.get_str("plain_password").map(String::as_str), Some("secret"));
+        assert!(pack.get_element("password").is_none());
+
+        let pack = client(LoginMethod::Anonymous).login_pack().unwrap();
+        assert_eq!(pack.get_int("authtype"), Some(CLIENT_AUTHTYPE_ANONYMOUS));
+        assert_eq!(pack.get_str("username").map(String::as_str), Some("alice"));
+        assert!(pack.get_element("password").is_none());
+        assert!(pack.get_element("plain_password").is_none());
+
+        // Certificate login without a loaded certificate cannot build a PACK
+        assert!(client(LoginMethod::Certificate).login_pack().is_err());
+    }
+
+    #[test]
+    fn test_password_login_from_cached_hash() {
+        // Without a server random only the plaintext will do
+        let mut password = client(LoginMethod::Password);
+        assert_eq!(password.login_pack().unwrap().get_str("password").map(String::as_str), Some("secret"));
+        assert!(password.cacheable_password_hash().is_none());
+
+        password.server_challenge = Some(vec![7; 20]);
+        let pack = password.login_pack().unwrap();
+        assert!(pack.get_element("password").is_none());
+        let secure = pack.get_data("secure_password").unwrap().clone();
+        let hash = password.cacheable_password_hash().unwrap();
+
+        // A client holding only the hash sends the same proof
+        let mut cached = client(LoginMethod::Password);
+        cached.server_challenge = Some(vec![7; 20]);
+        cached.set_password_hash(hash);
+        assert!(cached.password.is_empty());
+        assert_eq!(cached.login_pack().unwrap().get_data("secure_password"), Some(&secure));
+        assert_eq!(cached.password_hash(), password.password_hash());
+    }
+
+    #[test]
+    fn test_session_keys_from_login() {
+        // SHA-0 of "secretALICE", as SoftEther's HashPassword computes it
+        assert_eq!(hex::encode(hash_password("alice", "secret")), "0e782dacdb78918d73007999da81bc408906d451");
+
+        let mut password = client(LoginMethod::Password);
+        assert!(password.session_keys().is_none());
+        password.server_challenge = Some(vec![7; 20]);
+        password.pack_data = Some(Pack::new());
+        let keys = password.session_keys().unwrap();
+
+        // The session key from the login response is mixed in
+        let mut welcome = Pack::new();
+        welcome.add_data("session_key", vec![9; 20]);
+        password.pack_data = Some(welcome.clone());
+        let (mut sealer, _) = password.session_keys().unwrap().client_ciphers().unwrap();
+        let (_, mut opener) = keys.server_ciphers().unwrap();
+        assert!(opener.open(b"hdr", &sealer.seal(b"hdr", b"data").unwrap()).is_err());
+
+        // Anonymous logins only have the session key to go on
+        let mut anonymous = client(LoginMethod::Anonymous);
+        anonymous.server_challenge = Some(vec![7; 20]);
+        anonymous.pack_data = Some(Pack::new());
+        assert!(anonymous.session_keys().is_none());
+        anonymous.pack_data = Some(welcome);
+        assert!(anonymous.session_keys().is_some());
+    }
+
+    #[test]
+    fn test_max_connection_negotiation() {
+        let mut client = client(LoginMethod::Password);
+        assert!(client.login_pack().unwrap().get_element("max_connection").is_none());
+
+        client.set_max_connections(8);
+        assert_eq!(client.login_pack().unwrap().get_int("max_connection"), Some(8));
+        assert_eq!(client.granted_connections(), 1);
+
+        // The server may allow fewer, never more
+        let mut welcome = Pack::new();
+        welcome.add_int("max_connection", 4);
+        client.pack_data = Some(welcome.clone());
+        assert_eq!(client.granted_connections(), 4);
+        client.set_max_connections(2);
+        assert_eq!(client.granted_connections(), 2);
+    }
+
+    #[test]
+    fn test_session_timeout_from_welcome() {
+        let mut client = client(LoginMethod::Password);
+        assert_eq!(client.session_timeout(), None);
+        let mut welcome = Pack::new();
+        welcome.add_int("timeout", 20000);
+        client.pack_data = Some(welcome);
+        assert_eq!(client.session_timeout(), Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn test_compression_negotiation() {
+        let mut client = client(LoginMethod::Password);
+        assert_eq!(client.login_pack().unwrap().get_int("use_compress"), Some(0));
+        client.set_compression(true);
+        assert_eq!(client.login_pack().unwrap().get_int("use_compress"), Some(1));
+
+        // Only when the server agrees
+        let mut declined = Pack::new();
+        declined.add_int("use_compress", 0);
+        client.pack_data = Some(declined);
+        assert!(!client.compression_negotiated());
+        let mut agreed = Pack::new();
+        agreed.add_int("use_compress", 1);
+        client.pack_data = Some(agreed);
+        assert!(client.compression_negotiated());
+        client.set_compression(false);
+        assert!(!client.compression_negotiated());
+    }
+
+    #[test]
+    fn test_login_error_codes() {
+        for method in [LoginMethod::Password, LoginMethod::External, LoginMethod::Anonymous] {
+            assert!(matches!(login_error(ERR_AUTH_FAILED, method), VpnError::Authentication(_)));
+        }
+        match login_error(ERR_AUTH_FAILED, LoginMethod::External) {
+            VpnError::Authentication(message) => assert!(message.contains("RADIUS")),
+            other => panic!("unexpected error: {other:?}"),
+        }
+        match login_error(ERR_AUTHTYPE_NOT_SUPPORTED, LoginMethod::Anonymous) {
+            VpnError::Config(message) => assert!(message.contains("anonymous")),
+            other => panic!("unexpected error: {other:?}"),
+        }
+        let busy = login_error(15, LoginMethod::Password);
+        assert_eq!(busy.to_string(), "Refused by server (error 15): Server has too many connections");
+        assert_eq!(login_error(ERR_HUB_NOT_FOUND, LoginMethod::Password).code(), ErrorCode::HubNotFound);
+        assert_eq!(login_error(ERR_ACCESS_DENIED, LoginMethod::Password).code(), ErrorCode::AccessDenied);
+        assert_eq!(login_error(99, LoginMethod::Password).code(), ErrorCode::ServerError);
+        let expired = login_error(ERR_PASSWORD_EXPIRED, LoginMethod::Password);
+        assert_eq!(expired.code(), ErrorCode::PasswordExpired);
+        assert!(expired.to_string().contains("change_password"), "{expired}");
+    }
+
+    #[test]
+    fn test_change_password_pack() {
+        let random = [7u8; 20];
+        let pack = client(LoginMethod::Password).change_password_pack("secret", "n3w", &random);
+        assert_eq!(pack.get_str("method").map(String::as_str), Some("password"));
+        assert_eq!(pack.get_str("hubname").map(String::as_str), Some("VPN"));
+        assert_eq!(
+            pack.get_data("secure_old_password").unwrap()[..],
+            PasswordHash::new("alice", "secret").secure_password(&random)
+        );
+        assert_eq!(pack.get_data("new_password").unwrap()[..], hash_password("alice", "n3w"));
+        // NT hash: MD4 of the UTF-16LE password
+        assert_eq!(pack.get_data("new_password_ntlm").unwrap()[..], crypto::md::md4(b"n\03\0w\0"));
+        // Neither password travels in clear
+        assert!(pack.elements.iter().all(|e| e.values.iter().all(|v| !matches!(v, Value::Str(s) if s.contains("n3w")))));
+    }
+}
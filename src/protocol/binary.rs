@@ -6,12 +6,44 @@
 //! **CRITICAL ARCHITECTURE NOTE**: 
 //! This implements the post-authentication binary protocol transition
 //! discovered in SoftEther's StartTunnelingMode function (Protocol.c:3261)
+//!
+//! Given [`SessionKeys`] from the login, data payloads are sealed with
+//! AES-256-GCM, authenticating the packet type, session and sequence.
+//! Keepalives and handshake packets carry nothing secret and stay clear.
+//! Keepalives are probes the server echoes, tracked by the session's
+//! [`Heartbeat`].
+//!
+//! The server ends a session with a disconnect packet carrying the SoftEther
+//! error code (4 bytes) and a UTF-8 message; receiving one fails the data
+//! stream with [`VpnError::ServerDisconnected`].
+//!
+//! When the login negotiated compression, data payloads are zlib streams
+//! (see [`compression`](super::compression)), compressed before they are
+//! sealed and decompressed after they are opened.
+//!
+//! Sealing and opening can also happen off the connection, on worker tasks:
+//! the split halves reserve each payload's [`DataSlot`] in stream order, and
+//! a [`PayloadCodec`] from them does the work wherever it runs.
+//!
+//! A split sender can also batch: packets are encoded back to back and go
+//! out in one write once the batch fills or the sender is flushed.
 
+#![deny(clippy::arithmetic_side_effects)]
+
+use super::compression;
+use super::heartbeat::Heartbeat;
+use super::proxy::UpstreamProxy;
+use crate::client_optimized::PerformanceStats;
+use crate::crypto::{DataCipher, DataKey, SessionKeys};
 use crate::error::{Result, VpnError};
+use crate::underlay;
 use bytes::{Bytes, BytesMut, Buf, BufMut};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// SoftEther protocol constants
 pub mod protocol_constants {
@@ -21,10 +53,50 @@ pub mod protocol_constants {
     pub const PACKET_TYPE_DATA: u8 = 0x04;
     pub const PACKET_TYPE_SESSION_ESTABLISH: u8 = 0x05;
     pub const PACKET_TYPE_SESSION_RESPONSE: u8 = 0x06;
+    pub const PACKET_TYPE_DISCONNECT: u8 = 0x07;
+
+    /// Size of the fixed packet header (type + session + sequence + length)
+    pub const PACKET_HEADER_SIZE: usize = 13;
+
+    /// Largest payload accepted from the wire (enough for a jumbo Ethernet frame batch)
+    pub const MAX_PACKET_DATA_LEN: u32 = 1024 * 1024;
 }
 
 use protocol_constants::*;
 
+/// Why the server ended the session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectNotice {
+    /// SoftEther error code, e.g. 11 when an administrator removed the session
+    pub code: u32,
+    pub message: String,
+}
+
+impl DisconnectNotice {
+    /// Notice in the payload of a disconnect packet
+    ///
+    /// A payload too short for a code still ends the session, with code 0.
+    pub fn parse(data: &[u8]) -> Self {
+        let (code, message) = match data.split_first_chunk::<4>() {
+            Some((code, message)) => (u32::from_be_bytes(*code), String::from_utf8_lossy(message).into_owned()),
+            None => (0, String::new()),
+        };
+        let message = if message.is_empty() { "session ended by the server".to_string() } else { message };
+        Self { code, message }
+    }
+
+    /// Whether the server would refuse the session again (see [`disconnect_is_permanent`](super::auth::disconnect_is_permanent))
+    pub fn is_permanent(&self) -> bool {
+        super::auth::disconnect_is_permanent(self.code)
+    }
+}
+
+impl From<DisconnectNotice> for VpnError {
+    fn from(notice: DisconnectNotice) -> Self {
+        VpnError::ServerDisconnected { code: notice.code, message: notice.message }
+    }
+}
+
 /// Binary protocol packet structure
 #[derive(Debug, Clone)]
 pub struct SoftEtherPacket {
@@ -75,9 +147,26 @@ impl SoftEtherPacket {
         }
     }
 
+    /// Create a disconnect packet carrying `notice`
+    pub fn create_disconnect(session_id: u32, notice: &DisconnectNotice) -> Self {
+        let mut data = BytesMut::with_capacity(notice.message.len().saturating_add(4));
+        data.put_u32(notice.code);
+        data.extend_from_slice(notice.message.as_bytes());
+        Self {
+            packet_type: PACKET_TYPE_DISCONNECT,
+            session_id,
+            sequence: 0,
+            data: data.freeze(),
+        }
+    }
+
     /// Convert packet to bytes for transmission
-    pub fn to_bytes(&self) -> Bytes {
-        let mut buf = BytesMut::with_capacity(1 + 4 + 4 + 4 + self.data.len());
+    pub fn to_bytes(&self) -> Result<Bytes> {
+        let data_len = u32::try_from(self.data.len())
+            .ok()
+            .filter(|&len| len <= MAX_PACKET_DATA_LEN)
+            .ok_or_else(|| VpnError::Protocol(format!("Packet data of {} bytes is too large", self.data.len())))?;
+        let mut buf = BytesMut::with_capacity(PACKET_HEADER_SIZE.saturating_add(self.data.len()));
         
         // Packet type (1 byte)
         buf.put_u8(self.packet_type);
@@ -89,24 +178,24 @@ impl SoftEtherPacket {
         buf.put_u32(self.sequence);
         
         // Data length (4 bytes)
-        buf.put_u32(self.data.len() as u32);
+        buf.put_u32(data_len);
         
         // Data payload
         buf.extend_from_slice(&self.data);
         
-        buf.freeze()
+        Ok(buf.freeze())
     }
 
     /// Parse packet from bytes
     pub fn from_bytes(mut data: Bytes) -> Result<Self> {
-        if data.len() < 13 {
+        if data.len() < PACKET_HEADER_SIZE {
             return Err(VpnError::Protocol("Packet too short".to_string()));
         }
 
         let packet_type = data.get_u8();
         let session_id = data.get_u32();
         let sequence = data.get_u32();
-        let data_len = data.get_u32() as usize;
+        let data_len = checked_data_len(data.get_u32())?;
 
         if data.len() < data_len {
             return Err(VpnError::Protocol("Invalid data length".to_string()));
@@ -123,6 +212,22 @@ impl SoftEtherPacket {
     }
 }
 
+/// Header fields a data packet's encryption authenticates
+pub(super) fn data_aad(session_id: u32, sequence: u32) -> [u8; 9] {
+    let mut aad = [PACKET_TYPE_DATA; 9];
+    aad[1..5].copy_from_slice(&session_id.to_be_bytes());
+    aad[5..].copy_from_slice(&sequence.to_be_bytes());
+    aad
+}
+
+/// Validate a wire data length against the protocol limit
+fn checked_data_len(raw: u32) -> Result<usize> {
+    if raw > MAX_PACKET_DATA_LEN {
+        return Err(VpnError::Protocol(format!("Packet data length {} exceeds limit", raw)));
+    }
+    usize::try_from(raw).map_err(|_| VpnError::Protocol("Packet data length overflow".to_string()))
+}
+
 /// High-performance binary protocol client
 /// 
 /// This handles the post-authentication binary VPN protocol for actual
@@ -131,8 +236,24 @@ pub struct BinaryProtocolClient {
     server_addr: SocketAddr,
     stream: Option<TcpStream>,
     session_id: Option<u32>,
+    // Session id agreed during PACK authentication, used by `authenticate`
+    negotiated_session_id: Option<u32>,
+    // Which of the session's bonded connections this is, announced in the hello
+    connection_index: u32,
+    // Proxy the connection is made through
+    proxy: Option<UpstreamProxy>,
+    // Firewall mark of a direct connection, under policy routing
+    socket_mark: Option<u32>,
     sequence_counter: u32,
     is_connected: bool,
+    // Data payload encryption, both directions, when keys were derived at login
+    outbound: Option<DataCipher>,
+    inbound: Option<DataCipher>,
+    // Data payload compression, counted into these stats, when negotiated at login
+    compression: Option<Arc<PerformanceStats>>,
+    // Bytes read past the last complete packet
+    read_buffer: BytesMut,
+    heartbeat: Arc<Heartbeat>,
 }
 
 impl BinaryProtocolClient {
@@ -142,11 +263,83 @@ impl BinaryProtocolClient {
             server_addr,
             stream: None,
             session_id: None,
+            negotiated_session_id: None,
+            connection_index: 0,
+            proxy: None,
+            socket_mark: None,
             sequence_counter: 0,
             is_connected: false,
+            outbound: None,
+            inbound: None,
+            compression: None,
+            read_buffer: BytesMut::new(),
+            heartbeat: Arc::new(Heartbeat::new()),
         }
     }
 
+    /// Continue the session negotiated during PACK authentication
+    pub fn with_session_id(mut self, session_id: u32) -> Self {
+        self.negotiated_session_id = Some(session_id);
+        self
+    }
+
+    /// Join the session as its additional connection `index`, for bonding
+    ///
+    /// Data payloads then need [`SessionKeys::for_connection`] of the same index.
+    pub fn with_connection_index(mut self, index: u32) -> Self {
+        self.connection_index = index;
+        self
+    }
+
+    /// Reach the server through a proxy (`None` for direct)
+    pub fn with_proxy(mut self, proxy: Option<UpstreamProxy>) -> Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Mark a direct connection with `mark` (`SO_MARK`, Linux) so policy routing keeps it off the tunnel
+    pub fn with_socket_mark(mut self, mark: Option<u32>) -> Self {
+        self.socket_mark = mark;
+        self
+    }
+
+    /// Share keepalive state with the other connections of a bonded session
+    pub fn with_heartbeat(mut self, heartbeat: Arc<Heartbeat>) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Encrypt data payloads with `keys`, derived during PACK authentication
+    pub fn with_session_keys(mut self, keys: &SessionKeys) -> Result<Self> {
+        let (outbound, inbound) = keys.client_ciphers()?;
+        self.outbound = Some(outbound);
+        self.inbound = Some(inbound);
+        Ok(self)
+    }
+
+    /// Whether data payloads are encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.outbound.is_some()
+    }
+
+    /// Compress data payloads, as negotiated during PACK authentication
+    ///
+    /// Payload sizes before and after compression are counted into `stats`.
+    pub fn with_compression(mut self, stats: Arc<PerformanceStats>) -> Self {
+        self.compression = Some(stats);
+        self
+    }
+
+    /// Whether data payloads are compressed
+    pub fn is_compressed(&self) -> bool {
+        self.compression.is_some()
+    }
+
+    /// Keepalive state, shared with the halves from [`into_split`](Self::into_split)
+    pub fn heartbeat(&self) -> Arc<Heartbeat> {
+        self.heartbeat.clone()
+    }
+
     /// Connect to SoftEther server using binary protocol
     /// 
     /// **IMPORTANT**: This should only be called AFTER successful
@@ -154,8 +347,11 @@ impl BinaryProtocolClient {
     pub async fn connect(&mut self) -> Result<()> {
         log::info!("Establishing binary protocol connection to: {}", self.server_addr);
         
-        let stream = TcpStream::connect(self.server_addr).await
-            .map_err(|e| VpnError::Network(format!("Binary connection failed: {}", e)))?;
+        let stream = match self.proxy {
+            Some(ref proxy) => proxy.connect(&self.server_addr.to_string()).await?,
+            None => underlay::connect_tcp(self.server_addr, self.socket_mark).await
+                .map_err(|e| VpnError::Network(format!("Binary connection failed: {}", e)))?,
+        };
         
         self.stream = Some(stream);
         self.is_connected = true;
@@ -169,7 +365,7 @@ impl BinaryProtocolClient {
 
     /// Send hello packet and negotiate protocol
     async fn send_hello(&mut self) -> Result<()> {
-        let hello_packet = SoftEtherPacket::create_hello();
+        let hello_packet = SoftEtherPacket { sequence: self.connection_index, ..SoftEtherPacket::create_hello() };
         self.send_packet(hello_packet).await?;
         
         // Wait for hello response
@@ -188,8 +384,8 @@ impl BinaryProtocolClient {
     /// before StartTunnelingMode. This method transfers the authenticated session.
     pub async fn authenticate(&mut self, username: &str, password: &str, hub: &str) -> Result<u32> {
         // In real SoftEther, session transfer happens here
-        // For now, simulate session establishment
-        let session_id = 12345; // TODO: Get from PACK auth session
+        // Without a negotiated session, simulate session establishment
+        let session_id = self.negotiated_session_id.unwrap_or(12345);
         self.session_id = Some(session_id);
         
         log::info!("Binary protocol session established: {}", session_id);
@@ -215,13 +411,13 @@ impl BinaryProtocolClient {
         Ok(())
     }
 
-    /// Send keepalive packet
+    /// Send a keepalive probe for the server to echo
     pub async fn send_keepalive(&mut self) -> Result<()> {
         let session_id = self.session_id.ok_or_else(|| 
             VpnError::Connection("Not authenticated".to_string()))?;
         
-        self.sequence_counter += 1;
-        let keepalive_packet = SoftEtherPacket::create_keepalive(session_id, self.sequence_counter);
+        self.sequence_counter = self.sequence_counter.wrapping_add(1);
+        let keepalive_packet = self.heartbeat.probe(session_id, self.sequence_counter, Instant::now());
         
         self.send_packet(keepalive_packet).await?;
         log::debug!("Keepalive sent, sequence: {}", self.sequence_counter);
@@ -233,50 +429,67 @@ impl BinaryProtocolClient {
         let session_id = self.session_id.ok_or_else(|| 
             VpnError::Connection("Not authenticated".to_string()))?;
         
-        self.sequence_counter += 1;
+        self.sequence_counter = self.sequence_counter.wrapping_add(1);
+        let data = compress_data(self.compression.as_deref(), data);
+        let data = seal_data(&mut self.outbound, session_id, self.sequence_counter, data)?;
         let data_packet = SoftEtherPacket::create_data_packet(session_id, self.sequence_counter, data);
         
         self.send_packet(data_packet).await?;
         Ok(())
     }
 
+    /// Receive the next VPN data payload, skipping keepalives
+    ///
+    /// Cancel-safe: a packet is either returned whole or left for the next call.
+    pub async fn receive_vpn_data(&mut self) -> Result<Bytes> {
+        let stream = self.stream.as_mut().ok_or_else(|| 
+            VpnError::Connection("Not connected".to_string()))?;
+        let compression = self.compression.as_deref();
+        read_vpn_data(stream, &mut self.read_buffer, &mut self.inbound, compression, &self.heartbeat).await
+    }
+
     /// Send a packet over the binary protocol
     async fn send_packet(&mut self, packet: SoftEtherPacket) -> Result<()> {
         let stream = self.stream.as_mut().ok_or_else(|| 
             VpnError::Connection("Not connected".to_string()))?;
-        
-        let packet_bytes = packet.to_bytes();
-        stream.write_all(&packet_bytes).await
-            .map_err(|e| VpnError::Network(format!("Send failed: {}", e)))?;
-        
-        Ok(())
+        write_packet(stream, packet).await
     }
 
     /// Receive a packet from the binary protocol
     async fn receive_packet(&mut self) -> Result<SoftEtherPacket> {
         let stream = self.stream.as_mut().ok_or_else(|| 
             VpnError::Connection("Not connected".to_string()))?;
-        
-        // Read packet header (13 bytes minimum)
-        let mut header = [0u8; 13];
-        stream.read_exact(&mut header).await
-            .map_err(|e| VpnError::Network(format!("Read failed: {}", e)))?;
-        
-        let data_len = u32::from_be_bytes([header[9], header[10], header[11], header[12]]) as usize;
-        
-        // Read packet data
-        let mut data = vec![0u8; data_len];
-        if data_len > 0 {
-            stream.read_exact(&mut data).await
-                .map_err(|e| VpnError::Network(format!("Read data failed: {}", e)))?;
-        }
-        
-        // Reconstruct full packet
-        let mut full_packet = BytesMut::with_capacity(13 + data_len);
-        full_packet.extend_from_slice(&header);
-        full_packet.extend_from_slice(&data);
-        
-        SoftEtherPacket::from_bytes(full_packet.freeze())
+        read_buffered(stream, &mut self.read_buffer).await
+    }
+
+    /// Split an established session into independent send and receive halves
+    ///
+    /// Lets outbound and inbound data flow concurrently, e.g. in the tunnel
+    /// packet pump. Fails unless connected and authenticated.
+    pub fn into_split(mut self) -> Result<(BinaryDataSender, BinaryDataReceiver)> {
+        let session_id = self.session_id.ok_or_else(|| 
+            VpnError::Connection("Not authenticated".to_string()))?;
+        let stream = self.stream.take().ok_or_else(|| 
+            VpnError::Connection("Not connected".to_string()))?;
+        let (read_half, write_half) = stream.into_split();
+        Ok((
+            BinaryDataSender {
+                stream: write_half,
+                session_id,
+                sequence_counter: self.sequence_counter,
+                cipher: self.outbound.take(),
+                compression: self.compression.clone(),
+                heartbeat: self.heartbeat.clone(),
+                batch: WriteBatch::new(1),
+            },
+            BinaryDataReceiver {
+                stream: read_half,
+                buffer: std::mem::take(&mut self.read_buffer),
+                cipher: self.inbound.take(),
+                compression: self.compression.take(),
+                heartbeat: self.heartbeat.clone(),
+            },
+        ))
     }
 
     /// Disconnect from server
@@ -301,14 +514,387 @@ impl BinaryProtocolClient {
     }
 }
 
+/// Place of a data payload in the stream
+///
+/// Holds what the payload's encryption authenticates and the nonce reserved
+/// for it, so that it can be sealed or opened apart from the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataSlot {
+    pub session_id: u32,
+    pub sequence: u32,
+    // Reserved from the direction's cipher, when the session is encrypted
+    nonce: Option<u64>,
+}
+
+/// Compression and encryption of data payloads, apart from the connection
+///
+/// Cheap to clone; clones can seal or open on other tasks, in any order,
+/// payloads whose [`DataSlot`]s were reserved from the same half.
+#[derive(Debug, Clone)]
+pub struct PayloadCodec {
+    key: Option<DataKey>,
+    compression: Option<Arc<PerformanceStats>>,
+}
+
+impl PayloadCodec {
+    /// Data packet carrying `data` in `slot`
+    pub fn seal(&self, slot: DataSlot, data: Bytes) -> Result<SoftEtherPacket> {
+        let data = compress_data(self.compression.as_deref(), data);
+        let data = match (&self.key, slot.nonce) {
+            (Some(key), Some(nonce)) => Bytes::from(key.seal_at(nonce, &data_aad(slot.session_id, slot.sequence), &data)?),
+            (Some(_), None) => return Err(VpnError::Crypto("Data slot has no nonce reserved".into())),
+            (None, _) => data,
+        };
+        Ok(SoftEtherPacket::create_data_packet(slot.session_id, slot.sequence, data))
+    }
+
+    /// Payload of the data packet `data` received in `slot`
+    pub fn open(&self, slot: DataSlot, data: Bytes) -> Result<Bytes> {
+        let data = match (&self.key, slot.nonce) {
+            (Some(key), Some(nonce)) => Bytes::from(key.open_at(nonce, &data_aad(slot.session_id, slot.sequence), &data)?),
+            (Some(_), None) => return Err(VpnError::Crypto("Data slot has no nonce reserved".into())),
+            (None, _) => data,
+        };
+        decompress_data(self.compression.as_deref(), data)
+    }
+}
+
+/// Sending half of a split binary session
+pub struct BinaryDataSender {
+    stream: OwnedWriteHalf,
+    session_id: u32,
+    sequence_counter: u32,
+    cipher: Option<DataCipher>,
+    compression: Option<Arc<PerformanceStats>>,
+    heartbeat: Arc<Heartbeat>,
+    batch: WriteBatch,
+}
+
+impl BinaryDataSender {
+    /// Hold back up to `packets` packets to write together; data packets
+    /// wait for the batch to fill or [`flush`](Self::flush), keepalives go
+    /// out at once with everything before them
+    pub fn with_batching(mut self, packets: usize) -> Self {
+        self.batch = WriteBatch::new(packets);
+        self
+    }
+
+    /// Send VPN data packet
+    pub async fn send_vpn_data(&mut self, data: Bytes) -> Result<()> {
+        self.sequence_counter = self.sequence_counter.wrapping_add(1);
+        self.send_vpn_data_at(self.sequence_counter, data).await
+    }
+
+    /// Send a keepalive probe for the server to echo
+    pub async fn send_keepalive(&mut self) -> Result<()> {
+        self.sequence_counter = self.sequence_counter.wrapping_add(1);
+        self.send_keepalive_at(self.sequence_counter).await
+    }
+
+    /// Send VPN data with a sequence number counted elsewhere, across a bond
+    pub async fn send_vpn_data_at(&mut self, sequence: u32, data: Bytes) -> Result<()> {
+        let data = compress_data(self.compression.as_deref(), data);
+        let data = seal_data(&mut self.cipher, self.session_id, sequence, data)?;
+        let data_packet = SoftEtherPacket::create_data_packet(self.session_id, sequence, data);
+        self.batch.send(&mut self.stream, data_packet).await
+    }
+
+    /// Send a keepalive probe with a sequence number counted elsewhere
+    pub async fn send_keepalive_at(&mut self, sequence: u32) -> Result<()> {
+        let packet = self.heartbeat.probe(self.session_id, sequence, Instant::now());
+        self.batch.send(&mut self.stream, packet).await?;
+        self.flush().await
+    }
+
+    /// Write out the packets held back for a batch
+    pub async fn flush(&mut self) -> Result<()> {
+        self.batch.flush(&mut self.stream).await
+    }
+
+    /// Separate reserving slots from writing packets, to seal payloads on other tasks
+    ///
+    /// Packets must be written in the order their slots were reserved.
+    pub fn into_parts(self) -> (DataSlots, DataWriter) {
+        let slots = DataSlots {
+            session_id: self.session_id,
+            sequence_counter: self.sequence_counter,
+            cipher: self.cipher,
+            compression: self.compression,
+            heartbeat: self.heartbeat,
+        };
+        (slots, DataWriter { stream: self.stream, batch: self.batch })
+    }
+}
+
+/// Slot reservation of a [`BinaryDataSender`] taken apart
+pub struct DataSlots {
+    session_id: u32,
+    sequence_counter: u32,
+    cipher: Option<DataCipher>,
+    compression: Option<Arc<PerformanceStats>>,
+    heartbeat: Arc<Heartbeat>,
+}
+
+impl DataSlots {
+    /// Reserve the next sequence number, and nonce if encrypted, for a data payload
+    pub fn reserve(&mut self) -> Result<DataSlot> {
+        let nonce = self.cipher.as_mut().map(DataCipher::reserve).transpose()?;
+        self.sequence_counter = self.sequence_counter.wrapping_add(1);
+        Ok(DataSlot { session_id: self.session_id, sequence: self.sequence_counter, nonce })
+    }
+
+    /// Keepalive probe taking the next sequence number
+    pub fn keepalive(&mut self) -> SoftEtherPacket {
+        self.sequence_counter = self.sequence_counter.wrapping_add(1);
+        self.heartbeat.probe(self.session_id, self.sequence_counter, Instant::now())
+    }
+
+    /// Codec sealing payloads in the slots reserved here
+    pub fn codec(&self) -> PayloadCodec {
+        PayloadCodec { key: self.cipher.as_ref().map(DataCipher::key), compression: self.compression.clone() }
+    }
+}
+
+/// Packet writing of a [`BinaryDataSender`] taken apart
+pub struct DataWriter {
+    stream: OwnedWriteHalf,
+    batch: WriteBatch,
+}
+
+impl DataWriter {
+    /// Send a packet, sealed or a keepalive, in the order of its slot
+    pub async fn send(&mut self, packet: SoftEtherPacket) -> Result<()> {
+        self.batch.send(&mut self.stream, packet).await
+    }
+
+    /// Write out the packets held back for a batch
+    pub async fn flush(&mut self) -> Result<()> {
+        self.batch.flush(&mut self.stream).await
+    }
+}
+
+/// Packets encoded back to back to go out in one write
+struct WriteBatch {
+    buffer: Vec<u8>,
+    packets: usize,
+    limit: usize,
+}
+
+impl WriteBatch {
+    /// Batch of up to `limit` packets; a limit of one writes each packet at once
+    fn new(limit: usize) -> Self {
+        Self { buffer: Vec::new(), packets: 0, limit: limit.max(1) }
+    }
+
+    async fn send<W: AsyncWrite + Unpin>(&mut self, stream: &mut W, packet: SoftEtherPacket) -> Result<()> {
+        if self.limit == 1 {
+            return write_packet(stream, packet).await;
+        }
+        self.buffer.extend_from_slice(&packet.to_bytes()?);
+        self.packets = self.packets.saturating_add(1);
+        if self.packets < self.limit {
+            return Ok(());
+        }
+        self.flush(stream).await
+    }
+
+    async fn flush<W: AsyncWrite + Unpin>(&mut self, stream: &mut W) -> Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let written = stream.write_all(&self.buffer).await;
+        // A failed write leaves the stream unusable, so the batch goes either way
+        self.buffer.clear();
+        self.packets = 0;
+        written.map_err(|e| VpnError::Network(format!("Send failed: {}", e)))
+    }
+}
+
+/// Receiving half of a split binary session
+pub struct BinaryDataReceiver {
+    stream: OwnedReadHalf,
+    buffer: BytesMut,
+    cipher: Option<DataCipher>,
+    compression: Option<Arc<PerformanceStats>>,
+    heartbeat: Arc<Heartbeat>,
+}
+
+impl BinaryDataReceiver {
+    /// Receive the next VPN data payload, skipping keepalives
+    ///
+    /// Cancel-safe: a packet is either returned whole or left for the next call.
+    pub async fn receive_vpn_data(&mut self) -> Result<Bytes> {
+        let compression = self.compression.as_deref();
+        read_vpn_data(&mut self.stream, &mut self.buffer, &mut self.cipher, compression, &self.heartbeat).await
+    }
+
+    /// Like [`receive_vpn_data`](Self::receive_vpn_data), with the packet's sequence number
+    pub async fn receive_sequenced(&mut self) -> Result<(u32, Bytes)> {
+        let compression = self.compression.as_deref();
+        read_sequenced_data(&mut self.stream, &mut self.buffer, &mut self.cipher, compression, &self.heartbeat).await
+    }
+
+    /// Receive the next data payload without opening it, reserving its slot
+    ///
+    /// Open it with the [`codec`](Self::codec), on any task. Keepalives and
+    /// disconnects are handled as by [`receive_vpn_data`](Self::receive_vpn_data).
+    pub async fn receive_sealed(&mut self) -> Result<(DataSlot, Bytes)> {
+        let packet = read_data_packet(&mut self.stream, &mut self.buffer, &self.heartbeat).await?;
+        let nonce = self.cipher.as_mut().map(DataCipher::reserve).transpose()?;
+        Ok((DataSlot { session_id: packet.session_id, sequence: packet.sequence, nonce }, packet.data))
+    }
+
+    /// Codec opening payloads received with [`receive_sealed`](Self::receive_sealed)
+    pub fn codec(&self) -> PayloadCodec {
+        PayloadCodec { key: self.cipher.as_ref().map(DataCipher::key), compression: self.compression.clone() }
+    }
+}
+
+/// Compress a data payload when the session negotiated it
+fn compress_data(stats: Option<&PerformanceStats>, data: Bytes) -> Bytes {
+    let Some(stats) = stats else { return data };
+    let packed = compression::compress(&data);
+    stats.update_compression(data.len() as u64, packed.len() as u64);
+    Bytes::from(packed)
+}
+
+/// Decompress a data payload when the session negotiated it
+fn decompress_data(stats: Option<&PerformanceStats>, data: Bytes) -> Result<Bytes> {
+    let Some(stats) = stats else { return Ok(data) };
+    let unpacked = compression::decompress(&data, MAX_PACKET_DATA_LEN as usize)?;
+    stats.update_compression(unpacked.len() as u64, data.len() as u64);
+    Ok(Bytes::from(unpacked))
+}
+
+/// Encrypt a data payload when the session has keys
+fn seal_data(cipher: &mut Option<DataCipher>, session_id: u32, sequence: u32, data: Bytes) -> Result<Bytes> {
+    match cipher {
+        Some(cipher) => cipher.seal(&data_aad(session_id, sequence), &data).map(Bytes::from),
+        None => Ok(data),
+    }
+}
+
+pub(super) async fn write_packet<W: AsyncWrite + Unpin>(stream: &mut W, packet: SoftEtherPacket) -> Result<()> {
+    let packet_bytes = packet.to_bytes()?;
+    stream.write_all(&packet_bytes).await
+        .map_err(|e| VpnError::Network(format!("Send failed: {}", e)))?;
+    
+    Ok(())
+}
+
+pub(super) async fn read_packet<R: AsyncRead + Unpin>(stream: &mut R) -> Result<SoftEtherPacket> {
+    // Read packet header (13 bytes minimum)
+    let mut header = [0u8; PACKET_HEADER_SIZE];
+    stream.read_exact(&mut header).await
+        .map_err(|e| VpnError::Network(format!("Read failed: {}", e)))?;
+    
+    // Validate before allocating so a hostile length can't exhaust memory
+    let data_len = checked_data_len(u32::from_be_bytes([header[9], header[10], header[11], header[12]]))?;
+    
+    // Read packet data
+    let mut data = vec![0u8; data_len];
+    if data_len > 0 {
+        stream.read_exact(&mut data).await
+            .map_err(|e| VpnError::Network(format!("Read data failed: {}", e)))?;
+    }
+    
+    // Reconstruct full packet
+    let mut full_packet = BytesMut::with_capacity(PACKET_HEADER_SIZE.saturating_add(data_len));
+    full_packet.extend_from_slice(&header);
+    full_packet.extend_from_slice(&data);
+    
+    SoftEtherPacket::from_bytes(full_packet.freeze())
+}
+
+/// Take the first packet off `buffer`, once it holds all of it
+fn take_packet(buffer: &mut BytesMut) -> Result<Option<SoftEtherPacket>> {
+    let Some(header) = buffer.get(..PACKET_HEADER_SIZE) else {
+        return Ok(None);
+    };
+    let data_len = checked_data_len(u32::from_be_bytes([header[9], header[10], header[11], header[12]]))?;
+    let len = PACKET_HEADER_SIZE.saturating_add(data_len);
+    if buffer.len() < len {
+        return Ok(None);
+    }
+    SoftEtherPacket::from_bytes(buffer.split_to(len).freeze()).map(Some)
+}
+
+/// Read the next packet through `buffer`
+///
+/// Unlike [`read_packet`] this can be cancelled between reads without losing
+/// data: partial packets stay in the buffer.
+async fn read_buffered<R: AsyncRead + Unpin>(stream: &mut R, buffer: &mut BytesMut) -> Result<SoftEtherPacket> {
+    loop {
+        if let Some(packet) = take_packet(buffer)? {
+            return Ok(packet);
+        }
+        let read = stream.read_buf(buffer).await
+            .map_err(|e| VpnError::Network(format!("Read failed: {}", e)))?;
+        if read == 0 {
+            return Err(VpnError::Network("Read failed: connection closed".to_string()));
+        }
+    }
+}
+
+async fn read_vpn_data<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buffer: &mut BytesMut,
+    cipher: &mut Option<DataCipher>,
+    compression: Option<&PerformanceStats>,
+    heartbeat: &Heartbeat,
+) -> Result<Bytes> {
+    read_sequenced_data(stream, buffer, cipher, compression, heartbeat).await.map(|(_, data)| data)
+}
+
+async fn read_sequenced_data<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buffer: &mut BytesMut,
+    cipher: &mut Option<DataCipher>,
+    compression: Option<&PerformanceStats>,
+    heartbeat: &Heartbeat,
+) -> Result<(u32, Bytes)> {
+    let packet = read_data_packet(stream, buffer, heartbeat).await?;
+    let data = match cipher {
+        Some(cipher) => cipher.open(&data_aad(packet.session_id, packet.sequence), &packet.data).map(Bytes::from)?,
+        None => packet.data,
+    };
+    Ok((packet.sequence, decompress_data(compression, data)?))
+}
+
+/// Read up to the next data packet, skipping keepalives
+async fn read_data_packet<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    buffer: &mut BytesMut,
+    heartbeat: &Heartbeat,
+) -> Result<SoftEtherPacket> {
+    loop {
+        let packet = read_buffered(stream, buffer).await?;
+        if let Some(rtt) = heartbeat.received(&packet, Instant::now()) {
+            log::debug!("Keepalive echoed in {} ms", rtt.as_millis());
+        }
+        match packet.packet_type {
+            PACKET_TYPE_DATA => return Ok(packet),
+            PACKET_TYPE_KEEPALIVE => continue,
+            PACKET_TYPE_DISCONNECT => return Err(DisconnectNotice::parse(&packet.data).into()),
+            other => {
+                return Err(VpnError::Protocol(format!(
+                    "Unexpected packet type 0x{:02x} in data stream",
+                    other
+                )))
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::Ordering;
 
     #[test]
     fn test_packet_serialization() {
         let packet = SoftEtherPacket::create_hello();
-        let bytes = packet.to_bytes();
+        let bytes = packet.to_bytes().unwrap();
         let parsed = SoftEtherPacket::from_bytes(bytes).unwrap();
         
         assert_eq!(packet.packet_type, parsed.packet_type);
@@ -324,4 +910,191 @@ mod tests {
         assert_eq!(packet.session_id, 12345);
         assert_eq!(packet.sequence, 100);
     }
+
+    #[test]
+    fn test_rejects_oversized_data_length() {
+        // Header declaring u32::MAX payload bytes must not wrap or allocate
+        let mut raw = BytesMut::new();
+        raw.put_u8(PACKET_TYPE_DATA);
+        raw.put_u32(1);
+        raw.put_u32(1);
+        raw.put_u32(u32::MAX);
+        raw.put_slice(b"tiny");
+        assert!(SoftEtherPacket::from_bytes(raw.freeze()).is_err());
+
+        assert!(SoftEtherPacket::from_bytes(Bytes::from_static(&[0u8; 5])).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_split_session_carries_data() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = BinaryProtocolClient::new(listener.local_addr().unwrap());
+        let server = async {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert_eq!(read_packet(&mut stream).await.unwrap().packet_type, PACKET_TYPE_HELLO);
+            let response = SoftEtherPacket { packet_type: PACKET_TYPE_HELLO_RESPONSE, ..SoftEtherPacket::create_hello() };
+            write_packet(&mut stream, response).await.unwrap();
+            stream
+        };
+        let (connected, mut stream) = tokio::join!(client.connect(), server);
+        connected.unwrap();
+
+        // Splitting needs an authenticated session
+        let session_id = client.authenticate("user", "pass", "HUB").await.unwrap();
+        let (mut sender, mut receiver) = client.into_split().unwrap();
+
+        sender.send_vpn_data(Bytes::from_static(b"outbound")).await.unwrap();
+        let packet = read_packet(&mut stream).await.unwrap();
+        assert_eq!(packet.packet_type, PACKET_TYPE_DATA);
+        assert_eq!(packet.session_id, session_id);
+        assert_eq!(packet.data, Bytes::from_static(b"outbound"));
+
+        // Keepalives in the inbound stream are skipped
+        write_packet(&mut stream, SoftEtherPacket::create_keepalive(session_id, 1)).await.unwrap();
+        let data = SoftEtherPacket::create_data_packet(session_id, 2, Bytes::from_static(b"inbound"));
+        write_packet(&mut stream, data).await.unwrap();
+        assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from_static(b"inbound"));
+    }
+
+    #[tokio::test]
+    async fn test_receive_survives_cancellation() {
+        let (client_end, mut server_end) = tokio::io::duplex(64);
+        let (mut reader, _writer) = tokio::io::split(client_end);
+        let (mut buffer, mut cipher, heartbeat) = (BytesMut::new(), None, Heartbeat::new());
+        let bytes = SoftEtherPacket::create_data_packet(1, 1, Bytes::from_static(b"split payload")).to_bytes().unwrap();
+
+        // Half a packet arrives, then the read is abandoned
+        server_end.write_all(&bytes[..8]).await.unwrap();
+        let pending = tokio::time::timeout(
+            std::time::Duration::from_millis(20),
+            read_vpn_data(&mut reader, &mut buffer, &mut cipher, None, &heartbeat),
+        );
+        assert!(pending.await.is_err());
+
+        server_end.write_all(&bytes[8..]).await.unwrap();
+        let data = read_vpn_data(&mut reader, &mut buffer, &mut cipher, None, &heartbeat).await.unwrap();
+        assert_eq!(data, Bytes::from_static(b"split payload"));
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_packet_ends_data_stream() {
+        let (client_end, mut server_end) = tokio::io::duplex(256);
+        let (mut reader, _writer) = tokio::io::split(client_end);
+        let (mut buffer, mut cipher, heartbeat) = (BytesMut::new(), None, Heartbeat::new());
+
+        let notice = DisconnectNotice { code: 11, message: "Removed by administrator".to_string() };
+        let packet = SoftEtherPacket::create_disconnect(1, &notice);
+        server_end.write_all(&packet.to_bytes().unwrap()).await.unwrap();
+        match read_vpn_data(&mut reader, &mut buffer, &mut cipher, None, &heartbeat).await {
+            Err(VpnError::ServerDisconnected { code, message }) => {
+                assert_eq!((code, message.as_str()), (11, "Removed by administrator"));
+            }
+            other => panic!("unexpected result: {other:?}"),
+        }
+        assert!(notice.is_permanent());
+
+        // A truncated payload still ends the session
+        let garbled = DisconnectNotice::parse(b"\xff");
+        assert_eq!(garbled.code, 0);
+        assert!(!garbled.is_permanent());
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_echo_measures_rtt() {
+        let server = crate::protocol::mock::MockServer::start().await.unwrap();
+        let mut client = BinaryProtocolClient::new(server.addr());
+        client.connect().await.unwrap();
+        client.authenticate("user", "pass", "HUB").await.unwrap();
+        let heartbeat = client.heartbeat();
+
+        let (mut sender, mut receiver) = client.into_split().unwrap();
+        sender.send_keepalive().await.unwrap();
+        assert!(heartbeat.silent_for(Instant::now()).is_some());
+
+        // The echo is consumed on the way to the next data packet
+        sender.send_vpn_data(Bytes::from_static(b"data")).await.unwrap();
+        assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from_static(b"data"));
+        assert_eq!(heartbeat.counts(), (1, 1));
+        assert!(heartbeat.take_rtt().is_some());
+        assert_eq!(heartbeat.silent_for(Instant::now()), None);
+    }
+
+    #[tokio::test]
+    async fn test_session_keys_encrypt_payloads() {
+        let keys = SessionKeys::derive(&[7; 20], &[b"password hash"]).unwrap();
+        let server = crate::protocol::mock::MockServer::start_encrypted(keys.clone()).await.unwrap();
+
+        let mut client = BinaryProtocolClient::new(server.addr()).with_session_keys(&keys).unwrap();
+        assert!(client.is_encrypted());
+        client.connect().await.unwrap();
+        client.authenticate("user", "pass", "HUB").await.unwrap();
+        client.send_vpn_data(Bytes::from_static(b"first")).await.unwrap();
+        assert_eq!(client.receive_vpn_data().await.unwrap(), Bytes::from_static(b"first"));
+
+        // Counters carry over into the split halves
+        let (mut sender, mut receiver) = client.into_split().unwrap();
+        sender.send_vpn_data(Bytes::from_static(b"second")).await.unwrap();
+        assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from_static(b"second"));
+
+        // A client with other keys is cut off at its first data packet
+        let other = SessionKeys::derive(&[7; 20], &[b"wrong hash"]).unwrap();
+        let mut client = BinaryProtocolClient::new(server.addr()).with_session_keys(&other).unwrap();
+        client.connect().await.unwrap();
+        client.authenticate("user", "pass", "HUB").await.unwrap();
+        client.send_vpn_data(Bytes::from_static(b"data")).await.unwrap();
+        assert!(client.receive_vpn_data().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_payloads() {
+        let server = crate::protocol::mock::MockServer::start().await.unwrap();
+        let stats = Arc::new(PerformanceStats::new());
+        let mut client = BinaryProtocolClient::new(server.addr()).with_compression(stats.clone());
+        assert!(client.is_compressed());
+        client.connect().await.unwrap();
+        client.authenticate("user", "pass", "HUB").await.unwrap();
+
+        // The mock echoes payloads as sent, so they come back compressed
+        let payload = Bytes::from(b"compressible payload ".repeat(50));
+        client.send_vpn_data(payload.clone()).await.unwrap();
+        assert_eq!(client.receive_vpn_data().await.unwrap(), payload);
+        // 1050 bytes each way, a tenth of that on the wire
+        assert_eq!(stats.uncompressed_bytes.load(Ordering::Relaxed), 2100);
+        assert!(stats.compressed_bytes.load(Ordering::Relaxed) < 210);
+
+        let (mut sender, mut receiver) = client.into_split().unwrap();
+        sender.send_vpn_data(Bytes::from_static(b"x")).await.unwrap();
+        assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from_static(b"x"));
+        assert_eq!(stats.uncompressed_bytes.load(Ordering::Relaxed), 2102);
+    }
+
+    #[tokio::test]
+    async fn test_batched_sender_holds_packets() {
+        let server = crate::protocol::mock::MockServer::start().await.unwrap();
+        let mut client = BinaryProtocolClient::new(server.addr());
+        client.connect().await.unwrap();
+        client.authenticate("user", "pass", "HUB").await.unwrap();
+        let (sender, mut receiver) = client.into_split().unwrap();
+        let mut sender = sender.with_batching(3);
+
+        // Two of three packets stay with the sender
+        sender.send_vpn_data(Bytes::from_static(b"one")).await.unwrap();
+        sender.send_vpn_data(Bytes::from_static(b"two")).await.unwrap();
+        let held = tokio::time::timeout(std::time::Duration::from_millis(50), receiver.receive_vpn_data());
+        assert!(held.await.is_err());
+
+        sender.send_vpn_data(Bytes::from_static(b"three")).await.unwrap();
+        for expected in [&b"one"[..], b"two", b"three"] {
+            assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from_static(expected));
+        }
+
+        // A keepalive takes the packets before it along
+        sender.send_vpn_data(Bytes::from_static(b"four")).await.unwrap();
+        sender.send_keepalive().await.unwrap();
+        assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from_static(b"four"));
+
+        sender.send_vpn_data(Bytes::from_static(b"five")).await.unwrap();
+        sender.flush().await.unwrap();
+        assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from_static(b"five"));
+    }
 }
@@ -0,0 +1,399 @@
+//! Striping a session across several data connections
+//!
+//! A SoftEther session may use up to [`MAX_BONDED_CONNECTIONS`] TCP
+//! connections, as many as the server grants at login (`max_connection`).
+//! [`bond`] combines their split halves: [`BondedSender`] hands packets to the
+//! live connections in turn, numbering them across the bond, and
+//! [`BondedReceiver`] reads from all of them, putting packets back in order.
+//! A connection that fails is dropped from the rotation and the session
+//! carries on over the rest; only when none is left does the bond fail.
+//!
+//! A gap in the inbound numbering is waited out for [`REORDER_WAIT`] at most,
+//! so a packet lost with a connection holds up the others only briefly.
+
+use super::binary::{BinaryDataReceiver, BinaryDataSender};
+pub use super::constants::MAX_BONDED_CONNECTIONS;
+use crate::error::{Result, VpnError};
+use bytes::Bytes;
+use futures::future::{self, FutureExt};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Longest wait for a missing packet before delivering the ones behind it
+pub const REORDER_WAIT: Duration = Duration::from_millis(20);
+
+/// Packets held back at most while waiting for a missing one
+const REORDER_WINDOW: usize = 64;
+
+/// Health of one connection, shared by its sending and receiving side
+#[derive(Debug)]
+struct LinkHealth {
+    index: u32,
+    alive: AtomicBool,
+    packets_sent: AtomicU64,
+    packets_received: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl LinkHealth {
+    fn new(index: u32) -> Self {
+        Self {
+            index,
+            alive: AtomicBool::new(true),
+            packets_sent: AtomicU64::new(0),
+            packets_received: AtomicU64::new(0),
+            last_error: Mutex::new(None),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
+    }
+
+    fn fail(&self, error: &VpnError) {
+        if self.alive.swap(false, Ordering::Relaxed) {
+            log::warn!("Bonded connection {} failed, continuing without it: {}", self.index, error);
+        }
+        *self.last_error.lock().unwrap() = Some(error.to_string());
+    }
+}
+
+/// State of one connection of a bonded session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LinkStatus {
+    /// Position in the session, 0 for the connection made first
+    pub index: u32,
+    pub alive: bool,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Why the connection was given up, if it was
+    pub last_error: Option<String>,
+}
+
+/// Handle on the per-connection health of a bond, kept after its halves are handed out
+#[derive(Debug, Clone)]
+pub struct BondHealth {
+    links: Arc<[Arc<LinkHealth>]>,
+}
+
+impl BondHealth {
+    /// Snapshot of every connection, in index order
+    pub fn links(&self) -> Vec<LinkStatus> {
+        self.links
+            .iter()
+            .map(|link| LinkStatus {
+                index: link.index,
+                alive: link.is_alive(),
+                packets_sent: link.packets_sent.load(Ordering::Relaxed),
+                packets_received: link.packets_received.load(Ordering::Relaxed),
+                last_error: link.last_error.lock().unwrap().clone(),
+            })
+            .collect()
+    }
+
+    /// Connections still carrying traffic
+    pub fn live_connections(&self) -> usize {
+        self.links.iter().filter(|link| link.is_alive()).count()
+    }
+}
+
+/// Combine the split halves of a session's connections, in index order
+///
+/// All of them must belong to the same session and share its
+/// [`Heartbeat`](super::Heartbeat). Fails when given none.
+pub fn bond(
+    connections: Vec<(BinaryDataSender, BinaryDataReceiver)>,
+) -> Result<(BondedSender, BondedReceiver, BondHealth)> {
+    if connections.is_empty() {
+        return Err(VpnError::Connection("No data connections to bond".to_string()));
+    }
+    let links: Arc<[Arc<LinkHealth>]> =
+        (0..connections.len()).map(|index| Arc::new(LinkHealth::new(index as u32))).collect();
+    let (senders, receivers): (Vec<_>, Vec<_>) = connections
+        .into_iter()
+        .zip(links.iter())
+        .map(|((sender, receiver), health)| ((sender, health.clone()), (receiver, health.clone())))
+        .unzip();
+    let sender = BondedSender { links: senders, next: 0, sequence: 0 };
+    let receiver = BondedReceiver { links: receivers, reorder: Reorder::default(), gap_since: None };
+    Ok((sender, receiver, BondHealth { links }))
+}
+
+/// Sending side of a bond: stripes packets across the live connections
+pub struct BondedSender {
+    links: Vec<(BinaryDataSender, Arc<LinkHealth>)>,
+    /// Connection the next packet goes out on, if still alive
+    next: usize,
+    /// Sequence number of the last data packet, counted across the bond
+    sequence: u32,
+}
+
+impl BondedSender {
+    /// Send a packet on the next live connection, trying the others if it fails
+    pub async fn send_vpn_data(&mut self, data: Bytes) -> Result<()> {
+        self.sequence = self.sequence.wrapping_add(1);
+        let mut last_error = None;
+        for _ in 0..self.links.len() {
+            let position = self.next;
+            self.next = (position + 1) % self.links.len();
+            let (sender, health) = &mut self.links[position];
+            if !health.is_alive() {
+                continue;
+            }
+            match sender.send_vpn_data_at(self.sequence, data.clone()).await {
+                Ok(()) => {
+                    health.packets_sent.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Err(e) => {
+                    health.fail(&e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(all_failed(last_error))
+    }
+
+    /// Send a keepalive on every live connection, so that none sits idle
+    ///
+    /// Probes reuse the last data sequence number, leaving the numbering of
+    /// data packets without gaps.
+    pub async fn send_keepalive(&mut self) -> Result<()> {
+        let mut sent = false;
+        let mut last_error = None;
+        for (sender, health) in &mut self.links {
+            if !health.is_alive() {
+                continue;
+            }
+            match sender.send_keepalive_at(self.sequence).await {
+                Ok(()) => sent = true,
+                Err(e) => {
+                    health.fail(&e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if sent {
+            Ok(())
+        } else {
+            Err(all_failed(last_error))
+        }
+    }
+}
+
+/// Receiving side of a bond: reads every live connection and restores packet order
+pub struct BondedReceiver {
+    links: Vec<(BinaryDataReceiver, Arc<LinkHealth>)>,
+    reorder: Reorder,
+    /// Since when the oldest held-back packet has waited for the gap ahead of it
+    gap_since: Option<Instant>,
+}
+
+impl BondedReceiver {
+    /// Receive the next VPN data payload in sequence order
+    ///
+    /// Cancel-safe like [`BinaryDataReceiver::receive_vpn_data`]: packets
+    /// already read stay queued for the next call. A disconnect notice on any
+    /// connection ends the session.
+    pub async fn receive_vpn_data(&mut self) -> Result<Bytes> {
+        loop {
+            if let Some(data) = self.reorder.pop() {
+                self.gap_since = self.reorder.has_gap().then(Instant::now);
+                return Ok(data);
+            }
+            if !self.links.iter().any(|(_, health)| health.is_alive()) {
+                // Nothing more will fill the gap, deliver what is held
+                if self.reorder.has_gap() {
+                    self.reorder.skip_gap();
+                    continue;
+                }
+                return Err(all_failed(None));
+            }
+
+            let reads = self
+                .links
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, (_, health))| health.is_alive())
+                .map(|(position, (receiver, _))| receiver.receive_sequenced().map(move |read| (position, read)).boxed());
+            let next = future::select_all(reads).map(|(read, _, _)| read);
+            let (position, read) = match self.gap_since {
+                Some(since) => match tokio::time::timeout_at(since + REORDER_WAIT, next).await {
+                    Ok(read) => read,
+                    Err(_) => {
+                        self.reorder.skip_gap();
+                        self.gap_since = self.reorder.has_gap().then(Instant::now);
+                        continue;
+                    }
+                },
+                None => next.await,
+            };
+
+            let health = &self.links[position].1;
+            match read {
+                Ok((sequence, data)) => {
+                    health.packets_received.fetch_add(1, Ordering::Relaxed);
+                    self.reorder.push(sequence, data);
+                    if self.reorder.has_gap() {
+                        self.gap_since.get_or_insert_with(Instant::now);
+                    }
+                }
+                Err(e @ VpnError::ServerDisconnected { .. }) => return Err(e),
+                Err(e) => {
+                    health.fail(&e);
+                    if !self.links.iter().any(|(_, health)| health.is_alive()) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn all_failed(last_error: Option<VpnError>) -> VpnError {
+    match last_error {
+        Some(e) => VpnError::Connection(format!("All bonded connections failed: {e}")),
+        None => VpnError::Connection("All bonded connections failed".to_string()),
+    }
+}
+
+/// Puts packets numbered across a bond back in order
+#[derive(Debug, Default)]
+struct Reorder {
+    /// Sequence number due next, once the first packet has arrived
+    next: Option<u32>,
+    /// Packets ahead of `next`, by sequence number
+    held: HashMap<u32, Bytes>,
+    /// Late packets, delivered as they come
+    late: VecDeque<Bytes>,
+}
+
+impl Reorder {
+    fn push(&mut self, sequence: u32, data: Bytes) {
+        let next = *self.next.get_or_insert(sequence);
+        if sequence.wrapping_sub(next) > u32::MAX / 2 {
+            // Behind what was delivered: the gap it left was already skipped
+            self.late.push_back(data);
+            return;
+        }
+        self.held.insert(sequence, data);
+        if self.held.len() > REORDER_WINDOW {
+            self.skip_gap();
+        }
+    }
+
+    fn pop(&mut self) -> Option<Bytes> {
+        if let Some(data) = self.late.pop_front() {
+            return Some(data);
+        }
+        let next = self.next?;
+        let data = self.held.remove(&next)?;
+        self.next = Some(next.wrapping_add(1));
+        Some(data)
+    }
+
+    /// Whether packets are held back waiting for a missing one
+    fn has_gap(&self) -> bool {
+        self.next.is_some_and(|next| !self.held.is_empty() && !self.held.contains_key(&next))
+    }
+
+    /// Give up on the missing packets ahead of the first one held
+    fn skip_gap(&mut self) {
+        let Some(next) = self.next else { return };
+        if let Some(first) = self.held.keys().copied().min_by_key(|sequence| sequence.wrapping_sub(next)) {
+            log::debug!("Skipping {} lost packets before sequence {}", first.wrapping_sub(next), first);
+            self.next = Some(first);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SessionKeys;
+    use crate::protocol::mock::MockServer;
+    use crate::protocol::BinaryProtocolClient;
+
+    #[test]
+    fn test_reorder_restores_sequence() {
+        let mut reorder = Reorder::default();
+        assert_eq!(reorder.pop(), None);
+
+        // Numbering wraps around
+        reorder.push(u32::MAX, Bytes::from_static(b"a"));
+        reorder.push(1, Bytes::from_static(b"c"));
+        reorder.push(0, Bytes::from_static(b"b"));
+        assert_eq!(reorder.pop(), Some(Bytes::from_static(b"a")));
+        assert_eq!(reorder.pop(), Some(Bytes::from_static(b"b")));
+        assert_eq!(reorder.pop(), Some(Bytes::from_static(b"c")));
+        assert!(!reorder.has_gap());
+
+        // A lost packet holds the rest back until given up on
+        reorder.push(4, Bytes::from_static(b"e"));
+        reorder.push(3, Bytes::from_static(b"d"));
+        assert!(reorder.has_gap());
+        assert_eq!(reorder.pop(), None);
+        reorder.skip_gap();
+        assert_eq!(reorder.pop(), Some(Bytes::from_static(b"d")));
+        assert_eq!(reorder.pop(), Some(Bytes::from_static(b"e")));
+
+        // Arriving after its gap was skipped, it is delivered straight away
+        reorder.push(2, Bytes::from_static(b"late"));
+        assert_eq!(reorder.pop(), Some(Bytes::from_static(b"late")));
+
+        // A full window stops waiting
+        for sequence in 6..6 + REORDER_WINDOW as u32 + 1 {
+            reorder.push(sequence, Bytes::new());
+        }
+        assert!(reorder.pop().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_bond_stripes_and_reorders() {
+        let keys = SessionKeys::derive(&[3; 20], &[b"password hash"]).unwrap();
+        let server = MockServer::start_encrypted(keys.clone()).await.unwrap();
+
+        let mut primary = BinaryProtocolClient::new(server.addr()).with_session_keys(&keys).unwrap();
+        let heartbeat = primary.heartbeat();
+        primary.connect().await.unwrap();
+        let session_id = primary.authenticate("user", "pass", "HUB").await.unwrap();
+        let mut connections = vec![primary.into_split().unwrap()];
+        for index in 1..3 {
+            let mut client = BinaryProtocolClient::new(server.addr())
+                .with_session_id(session_id)
+                .with_connection_index(index)
+                .with_heartbeat(heartbeat.clone())
+                .with_session_keys(&keys.for_connection(index).unwrap())
+                .unwrap();
+            client.connect().await.unwrap();
+            client.authenticate("user", "pass", "HUB").await.unwrap();
+            connections.push(client.into_split().unwrap());
+        }
+        let (mut sender, mut receiver, health) = bond(connections).unwrap();
+
+        for n in 0..30u8 {
+            sender.send_vpn_data(Bytes::from(vec![n])).await.unwrap();
+        }
+        for n in 0..30u8 {
+            assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from(vec![n]));
+        }
+
+        // Echoes of the keepalives on every connection all count for the session
+        sender.send_keepalive().await.unwrap();
+        sender.send_vpn_data(Bytes::from_static(b"after")).await.unwrap();
+        assert_eq!(receiver.receive_vpn_data().await.unwrap(), Bytes::from_static(b"after"));
+        assert_eq!(heartbeat.counts().0, 3);
+
+        let links = health.links();
+        assert_eq!(health.live_connections(), 3);
+        assert_eq!(links.iter().map(|link| link.index).collect::<Vec<_>>(), [0, 1, 2]);
+        assert!(links.iter().all(|link| link.packets_sent >= 10 && link.last_error.is_none()));
+        assert_eq!(links.iter().map(|link| link.packets_received).sum::<u64>(), 31);
+
+        assert!(bond(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Client certificate authentication
+//!
+//! Instead of a password, the login PACK carries the client certificate and
+//! an RSA signature over the random challenge the server sent with its hello
+//! (`random`), proving possession of the private key.
+
+use super::pack::Pack;
+use crate::error::{Result, VpnError};
+use rustls::pki_types::PrivateKeyDer;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+#[cfg(all(feature = "ring-crypto", not(feature = "aws-lc-crypto")))]
+use ring::{rand::SystemRandom, signature::{self, RsaKeyPair}};
+
+#[cfg(all(feature = "aws-lc-crypto", not(feature = "ring-crypto")))]
+use aws_lc_rs::{rand::SystemRandom, signature::{self, RsaKeyPair}};
+
+#[cfg(all(feature = "ring-crypto", feature = "aws-lc-crypto"))]
+use ring::{rand::SystemRandom, signature::{self, RsaKeyPair}};
+
+/// `authtype` value announcing certificate authentication in the login PACK
+pub const CLIENT_AUTHTYPE_CERT: u32 = 3;
+
+/// Name of the challenge element in the server hello
+const CHALLENGE_ELEMENT: &str = "random";
+
+/// Client certificate and the RSA key used to sign the server challenge
+pub struct ClientCertificate {
+    cert_der: Vec<u8>,
+    key: RsaKeyPair,
+}
+
+impl ClientCertificate {
+    /// Load a PEM certificate and PEM private key (PKCS#8 or PKCS#1 RSA)
+    pub fn load(cert_path: &str, key_path: &str) -> Result<Self> {
+        let mut cert_reader = open(cert_path, "certificate")?;
+        let mut key_reader = open(key_path, "key")?;
+        Self::from_pem(&mut cert_reader, &mut key_reader)
+    }
+
+    /// Parse a PEM certificate chain (the first entry is used) and private key
+    pub fn from_pem(cert_pem: &mut dyn BufRead, key_pem: &mut dyn BufRead) -> Result<Self> {
+        let cert_der = rustls_pemfile::certs(cert_pem)
+            .next()
+            .ok_or_else(|| VpnError::Config("No certificate found".into()))?
+            .map_err(|e| VpnError::Config(format!("Invalid certificate: {e}")))?
+            .to_vec();
+
+        let key = match rustls_pemfile::private_key(key_pem)
+            .map_err(|e| VpnError::Config(format!("Invalid private key: {e}")))?
+        {
+            Some(PrivateKeyDer::Pkcs8(der)) => RsaKeyPair::from_pkcs8(der.secret_pkcs8_der()),
+            Some(PrivateKeyDer::Pkcs1(der)) => RsaKeyPair::from_der(der.secret_pkcs1_der()),
+            Some(_) => {
+                return Err(VpnError::Config(
+                    "Certificate authentication needs an RSA private key".into(),
+                ))
+            }
+            None => return Err(VpnError::Config("No private key found".into())),
+        }
+        .map_err(|e| VpnError::Config(format!("Unusable RSA private key: {e}")))?;
+
+        Ok(Self { cert_der, key })
+    }
+
+    /// DER encoding of the client certificate
+    pub fn cert_der(&self) -> &[u8] {
+        &self.cert_der
+    }
+
+    /// Sign the server challenge (RSASSA-PKCS1-v1_5 with SHA-256)
+    pub fn sign_challenge(&self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let mut sig = vec![0u8; modulus_len(&self.key)];
+        self.key
+            .sign(&signature::RSA_PKCS1_SHA256, &SystemRandom::new(), challenge, &mut sig)
+            .map_err(|_| VpnError::Crypto("Failed to sign authentication challenge".into()))?;
+        Ok(sig)
+    }
+
+    /// Add the certificate credentials for `challenge` to a login PACK
+    pub fn apply_to_pack(&self, pack: &mut Pack, challenge: &[u8]) -> Result<()> {
+        pack.add_int("authtype", CLIENT_AUTHTYPE_CERT);
+        pack.add_data("cert", self.cert_der.clone());
+        pack.add_data("sign", self.sign_challenge(challenge)?);
+        Ok(())
+    }
+}
+
+/// Lets desktop builds drive the `no_std` handshake with a loaded certificate
+#[cfg(feature = "no_std_core")]
+impl super::nostd::Crypto for ClientCertificate {
+    fn certificate(&self) -> Option<&[u8]> {
+        Some(&self.cert_der)
+    }
+
+    fn sign_challenge(&mut self, challenge: &[u8]) -> std::result::Result<Vec<u8>, super::nostd::Error> {
+        ClientCertificate::sign_challenge(self, challenge)
+            .map_err(|_| super::nostd::Error::Unsupported("signing with the client certificate failed"))
+    }
+}
+
+impl std::fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCertificate")
+            .field("cert_len", &self.cert_der.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Authentication challenge from a server hello PACK, if it sent one
+pub fn challenge_from_hello(hello: &Pack) -> Option<Vec<u8>> {
+    hello.get_data(CHALLENGE_ELEMENT).filter(|random| !random.is_empty()).cloned()
+}
+
+#[cfg(feature = "ring-crypto")]
+fn modulus_len(key: &RsaKeyPair) -> usize {
+    key.public().modulus_len()
+}
+
+#[cfg(not(feature = "ring-crypto"))]
+fn modulus_len(key: &RsaKeyPair) -> usize {
+    key.public_modulus_len()
+}
+
+fn open(path: &str, what: &str) -> Result<BufReader<File>> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| VpnError::Config(format!("Cannot open {what} file {path}: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CERT_PEM: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/cert_auth/client.crt"));
+    const KEY_PEM: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/cert_auth/client.key"));
+
+    fn certificate() -> ClientCertificate {
+        ClientCertificate::from_pem(&mut &CERT_PEM[..], &mut &KEY_PEM[..]).unwrap()
+    }
+
+    #[test]
+    fn test_signed_challenge_verifies() {
+        let cert = certificate();
+        let challenge = [0x5au8; 20];
+
+        let mut pack = Pack::new();
+        cert.apply_to_pack(&mut pack, &challenge).unwrap();
+        assert_eq!(pack.get_int("authtype"), Some(CLIENT_AUTHTYPE_CERT));
+        assert_eq!(pack.get_data("cert").unwrap().as_slice(), cert.cert_der());
+
+        let public_key = signature::UnparsedPublicKey::new(
+            &signature::RSA_PKCS1_2048_8192_SHA256,
+            cert.key.public().as_ref(),
+        );
+        let sig = pack.get_data("sign").unwrap();
+        assert!(public_key.verify(&challenge, sig).is_ok());
+        assert!(public_key.verify(&[0u8; 20], sig).is_err());
+    }
+
+    #[test]
+    fn test_rejects_missing_key_and_challenge() {
+        assert!(ClientCertificate::from_pem(&mut &CERT_PEM[..], &mut &b""[..]).is_err());
+        assert!(ClientCertificate::from_pem(&mut &b""[..], &mut &KEY_PEM[..]).is_err());
+
+        let mut hello = Pack::new();
+        assert_eq!(challenge_from_hello(&hello), None);
+        hello.add_data("random", vec![7u8; 20]);
+        assert_eq!(challenge_from_hello(&hello), Some(vec![7u8; 20]));
+    }
+}
@@ -0,0 +1,518 @@
+//! zlib compression of data payloads
+//!
+//! When both ends set `use_compress` in the login, SoftEther carries every
+//! data payload of the session as a zlib stream (RFC 1950 around RFC 1951
+//! deflate). Compression comes before encryption, so the payloads shrink
+//! while the ciphertext stays incompressible.
+//!
+//! The codec is in-tree, like the hashes in [`crate::crypto`]. [`compress`]
+//! finds LZ77 matches through hash chains and writes them with the fixed
+//! Huffman code. That is cheap, and it does well on the headers and text
+//! that make up most compressible traffic. Where that would grow the payload,
+//! it stores the bytes instead. [`decompress`] reads any zlib stream:
+//! stored, fixed and dynamic Huffman blocks.
+
+use crate::error::{Result, VpnError};
+
+/// Largest distance a match may reach back
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// Payloads are a frame or a few, which a small table covers
+const HASH_BITS: u32 = 12;
+/// Earlier positions tried per match, trading ratio for speed
+const MAX_CHAIN: usize = 32;
+/// Largest stored block
+const MAX_STORED: usize = 65535;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// Order code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+const END_OF_BLOCK: u16 = 256;
+
+/// Compress `data` into a zlib stream
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let deflated = deflate_fixed(data);
+    let stored_len = data.len() + 5 * data.len().div_ceil(MAX_STORED).max(1);
+    let body = if deflated.len() < stored_len { deflated } else { deflate_stored(data) };
+
+    let mut out = Vec::with_capacity(body.len() + 6);
+    // 32K window, deflate, no dictionary, fastest level
+    out.extend_from_slice(&[0x78, 0x01]);
+    out.extend_from_slice(&body);
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+/// Decompress the zlib stream `data`, refusing output beyond `limit` bytes
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let [cmf, flg, ..] = *data else { return Err(corrupt("truncated header")) };
+    if cmf & 0x0f != 8 || cmf >> 4 > 7 || (u16::from(cmf) << 8 | u16::from(flg)) % 31 != 0 {
+        return Err(corrupt("not a zlib stream"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(corrupt("preset dictionaries are not supported"));
+    }
+
+    let mut reader = BitReader::new(&data[2..]);
+    let out = inflate(&mut reader, limit)?;
+    reader.align();
+    let checksum = reader.bytes(4)?;
+    if checksum != adler32(&out).to_be_bytes() {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok(out)
+}
+
+fn corrupt(what: &str) -> VpnError {
+    VpnError::Protocol(format!("Corrupt compressed payload: {}", what))
+}
+
+/// Adler-32 of `data`, the zlib trailer
+fn adler32(data: &[u8]) -> u32 {
+    const MOD: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // The largest run that cannot overflow before reducing
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MOD;
+        b %= MOD;
+    }
+    b << 16 | a
+}
+
+/// Bits in deflate order: values LSB first, Huffman codes MSB first
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    count: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, len: u32) {
+        self.bits |= u64::from(value) << self.count;
+        self.count += len;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn put_code(&mut self, code: u32, len: u32) {
+        self.put(code.reverse_bits() >> (32 - len), len);
+    }
+
+    /// Literal or length symbol in the fixed code
+    fn put_symbol(&mut self, symbol: u16) {
+        let symbol = u32::from(symbol);
+        match symbol {
+            0..=143 => self.put_code(0x30 + symbol, 8),
+            144..=255 => self.put_code(0x190 + symbol - 144, 9),
+            256..=279 => self.put_code(symbol - 256, 7),
+            _ => self.put_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    fn put_match(&mut self, len: usize, dist: usize) {
+        let index = LENGTH_BASE.iter().rposition(|&base| usize::from(base) <= len).unwrap_or(0);
+        self.put_symbol(257 + index as u16);
+        self.put((len - usize::from(LENGTH_BASE[index])) as u32, u32::from(LENGTH_EXTRA[index]));
+
+        let index = DIST_BASE.iter().rposition(|&base| usize::from(base) <= dist).unwrap_or(0);
+        self.put_code(index as u32, 5);
+        self.put((dist - usize::from(DIST_BASE[index])) as u32, u32::from(DIST_EXTRA[index]));
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+/// One final block of stored data
+fn deflate_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 5 * data.len().div_ceil(MAX_STORED).max(1));
+    let mut chunks = data.chunks(MAX_STORED).peekable();
+    if data.is_empty() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        // BFINAL on the last, BTYPE 00, then padding to the byte
+        out.push(u8::from(chunks.peek().is_none()));
+        let len = chunk.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+/// One final block in the fixed Huffman code
+fn deflate_fixed(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::default();
+    writer.put(1, 1);
+    writer.put(1, 2);
+
+    let mut matcher = Matcher::new(data);
+    let mut pos = 0;
+    while pos < data.len() {
+        match matcher.longest(pos) {
+            Some((len, dist)) => {
+                writer.put_match(len, dist);
+                for skipped in pos..pos + len {
+                    matcher.insert(skipped);
+                }
+                pos += len;
+            }
+            None => {
+                writer.put_symbol(u16::from(data[pos]));
+                matcher.insert(pos);
+                pos += 1;
+            }
+        }
+    }
+    writer.put_symbol(END_OF_BLOCK);
+    writer.finish()
+}
+
+/// Earlier positions starting with the same three bytes, newest first
+struct Matcher<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl<'a> Matcher<'a> {
+    const NONE: usize = usize::MAX;
+
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, head: vec![Self::NONE; 1 << HASH_BITS], prev: vec![Self::NONE; data.len()] }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let key = u32::from(self.data[pos]) << 16 | u32::from(self.data[pos + 1]) << 8 | u32::from(self.data[pos + 2]);
+        (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pos: usize) {
+        if pos + MIN_MATCH <= self.data.len() {
+            let hash = self.hash(pos);
+            self.prev[pos] = self.head[hash];
+            self.head[hash] = pos;
+        }
+    }
+
+    /// Longest earlier match for `pos` as `(length, distance)`
+    fn longest(&self, pos: usize) -> Option<(usize, usize)> {
+        if pos + MIN_MATCH > self.data.len() {
+            return None;
+        }
+        let max = (self.data.len() - pos).min(MAX_MATCH);
+        let target = &self.data[pos..pos + max];
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[self.hash(pos)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == Self::NONE || pos - candidate > WINDOW {
+                break;
+            }
+            let len = self.data[candidate..].iter().zip(target).take_while(|(a, b)| a == b).count();
+            if len >= MIN_MATCH && best.is_none_or(|(best_len, _)| len > best_len) {
+                best = Some((len, pos - candidate));
+                if len == max {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+        }
+        best
+    }
+}
+
+/// Bits of a deflate stream, read LSB first
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0, bits: 0, count: 0 }
+    }
+
+    fn bits(&mut self, need: u32) -> Result<u32> {
+        while self.count < need {
+            let byte = *self.data.get(self.pos).ok_or_else(|| corrupt("truncated stream"))?;
+            self.pos += 1;
+            self.bits |= u32::from(byte) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1 << need) - 1);
+        self.bits >>= need;
+        self.count -= need;
+        Ok(value)
+    }
+
+    /// Drop the rest of the current byte
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+
+    /// `len` whole bytes, once aligned
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(|| corrupt("truncated stream"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+/// Canonical Huffman code, decoded a bit at a time
+struct Huffman {
+    /// Codes of each length
+    counts: [u16; 16],
+    /// Symbols ordered by code
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[usize::from(len)] += 1;
+        }
+        counts[0] = 0;
+        // Incomplete codes are allowed (a single distance code is common),
+        // over-subscribed ones are not
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err(corrupt("over-subscribed Huffman code"));
+            }
+        }
+
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[usize::from(offsets[usize::from(len)])] = symbol as u16;
+                offsets[usize::from(len)] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, reader: &mut BitReader) -> Result<u16> {
+        // First code and symbol index of each length, walked upwards
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= reader.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("invalid Huffman code"))
+    }
+}
+
+fn inflate(reader: &mut BitReader, limit: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    loop {
+        let last = reader.bits(1)? == 1;
+        match reader.bits(2)? {
+            0 => {
+                reader.align();
+                let header = reader.bytes(4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                if len != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(corrupt("stored block length mismatch"));
+                }
+                if out.len() + usize::from(len) > limit {
+                    return Err(corrupt("larger than allowed"));
+                }
+                out.extend_from_slice(reader.bytes(usize::from(len))?);
+            }
+            1 => {
+                let (literals, distances) = fixed_codes()?;
+                inflate_block(reader, &mut out, &literals, &distances, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(reader)?;
+                inflate_block(reader, &mut out, &literals, &distances, limit)?;
+            }
+            _ => return Err(corrupt("invalid block type")),
+        }
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman)> {
+    let mut lengths = [8u8; 288];
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(reader: &mut BitReader) -> Result<(Huffman, Huffman)> {
+    let literal_count = reader.bits(5)? as usize + 257;
+    let distance_count = reader.bits(5)? as usize + 1;
+    let length_count = reader.bits(4)? as usize + 4;
+    if literal_count > 286 || distance_count > 30 {
+        return Err(corrupt("too many codes"));
+    }
+
+    let mut length_lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+        length_lengths[symbol] = reader.bits(3)? as u8;
+    }
+    let length_code = Huffman::new(&length_lengths)?;
+
+    let total = literal_count + distance_count;
+    let mut lengths = Vec::with_capacity(total);
+    while lengths.len() < total {
+        let symbol = length_code.decode(reader)?;
+        let (len, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths.last().ok_or_else(|| corrupt("repeat without a length"))?;
+                (previous, 3 + reader.bits(2)? as usize)
+            }
+            17 => (0, 3 + reader.bits(3)? as usize),
+            _ => (0, 11 + reader.bits(7)? as usize),
+        };
+        if lengths.len() + repeat > total {
+            return Err(corrupt("code lengths overrun"));
+        }
+        lengths.resize(lengths.len() + repeat, len);
+    }
+    if lengths[usize::from(END_OF_BLOCK)] == 0 {
+        return Err(corrupt("no end-of-block code"));
+    }
+    let (literals, distances) = lengths.split_at(literal_count);
+    Ok((Huffman::new(literals)?, Huffman::new(distances)?))
+}
+
+fn inflate_block(
+    reader: &mut BitReader,
+    out: &mut Vec<u8>,
+    literals: &Huffman,
+    distances: &Huffman,
+    limit: usize,
+) -> Result<()> {
+    loop {
+        let symbol = literals.decode(reader)?;
+        if symbol == END_OF_BLOCK {
+            return Ok(());
+        }
+        if out.len() >= limit {
+            return Err(corrupt("larger than allowed"));
+        }
+        if symbol < END_OF_BLOCK {
+            out.push(symbol as u8);
+            continue;
+        }
+
+        let index = usize::from(symbol - 257);
+        let (Some(&base), Some(&extra)) = (LENGTH_BASE.get(index), LENGTH_EXTRA.get(index)) else {
+            return Err(corrupt("invalid length code"));
+        };
+        let len = usize::from(base) + reader.bits(u32::from(extra))? as usize;
+        let index = usize::from(distances.decode(reader)?);
+        let (Some(&base), Some(&extra)) = (DIST_BASE.get(index), DIST_EXTRA.get(index)) else {
+            return Err(corrupt("invalid distance code"));
+        };
+        let dist = usize::from(base) + reader.bits(u32::from(extra))? as usize;
+        if dist > out.len() {
+            return Err(corrupt("distance before the start"));
+        }
+        if out.len() + len > limit {
+            return Err(corrupt("larger than allowed"));
+        }
+        // Byte by byte: a match may overlap what it produces
+        let start = out.len() - dist;
+        for offset in 0..len {
+            out.push(out[start + offset]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference_streams() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+
+        // zlib.compress(b"hello"), a fixed Huffman block
+        let hello = [0x78, 0x9c, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x06, 0x2c, 0x02, 0x15];
+        assert_eq!(decompress(&hello, 64).unwrap(), b"hello");
+        assert!(decompress(&hello, 4).is_err());
+
+        // zlib.compress(text, 9), a dynamic Huffman block
+        let text = b"dynamic huffman codes pay off once the alphabet is skewed: eeeeeeeeeeee";
+        let dynamic = [
+            0x78, 0xda, 0x4d, 0xc1, 0xd1, 0x0d, 0x80, 0x30, 0x08, 0x05, 0xc0, 0x55, 0xde, 0x1c, 0x6e, 0x83, 0xf0, 0x08,
+            0x8d, 0x96, 0x36, 0xa1, 0xc6, 0x74, 0x7b, 0x7f, 0xbd, 0xb3, 0x9d, 0xd2, 0x9b, 0x22, 0x1e, 0xf7, 0x2e, 0x09,
+            0x1d, 0xc6, 0xc2, 0x94, 0x8d, 0xe1, 0x8e, 0x91, 0x4a, 0xac, 0x20, 0xe4, 0x9e, 0x21, 0x27, 0x17, 0x5a, 0xa1,
+            0x2e, 0xbe, 0xb4, 0x03, 0xfc, 0xf9, 0x00, 0xaf, 0x38, 0x1a, 0x1a,
+        ];
+        assert_eq!(decompress(&dynamic, 1024).unwrap(), text);
+        assert!(decompress(&dynamic[..40], 1024).is_err());
+        assert!(decompress(&[0x78, 0x9d, 0x03, 0x00], 1024).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // xorshift noise, which does not compress and is stored instead
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise: Vec<u8> = (0..70_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let text = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n".repeat(40);
+
+        for data in [&b""[..], b"a", b"abcabcabcabcabcabc", &text, &noise, &vec![0; 100_000]] {
+            let packed = compress(data);
+            assert_eq!(decompress(&packed, data.len()).unwrap(), data);
+            assert!(packed.len() <= data.len() + 5 * data.len().div_ceil(MAX_STORED).max(1) + 6);
+        }
+        assert!(compress(&text).len() < text.len() / 10);
+
+        let mut damaged = compress(&text);
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 0x55;
+        assert!(decompress(&damaged, text.len()).is_err());
+        assert!(decompress(&compress(&text), text.len() - 1).is_err());
+    }
+}
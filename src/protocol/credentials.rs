@@ -0,0 +1,122 @@
+//! In-memory cache of password hashes for reconnects
+//!
+//! A password login only needs the SoftEther password hash: the login PACK
+//! carries `secure_password`, the hash mixed with the server random. After
+//! the first successful login [`CredentialCache`] keeps that hash per
+//! server and hub, so reconnects neither rehash nor hold the plaintext.
+//! Hashes are wiped from memory when they are dropped, replaced or forgotten.
+
+use super::auth::hash_password;
+use crate::crypto::{self, sha0::SHA0_LEN};
+use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+/// SoftEther password hash, wiped on drop
+#[derive(Clone, PartialEq, Eq)]
+pub struct PasswordHash(Zeroizing<[u8; SHA0_LEN]>);
+
+impl PasswordHash {
+    /// Hash `password` for `username`
+    pub fn new(username: &str, password: &str) -> Self {
+        Self(Zeroizing::new(hash_password(username, password)))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; SHA0_LEN] {
+        &self.0
+    }
+
+    /// `secure_password` for the login PACK: SHA-0 of the hash and the server `random`
+    pub fn secure_password(&self, random: &[u8]) -> [u8; SHA0_LEN] {
+        let mut input = Zeroizing::new(Vec::with_capacity(SHA0_LEN + random.len()));
+        input.extend_from_slice(self.as_bytes());
+        input.extend_from_slice(random);
+        crypto::sha0(&input)
+    }
+}
+
+impl std::fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PasswordHash(<redacted>)")
+    }
+}
+
+/// Password hashes of successful logins, keyed by server and hub
+#[derive(Debug, Default)]
+pub struct CredentialCache {
+    // (server, hub) → (username, hash)
+    entries: HashMap<(String, String), (String, PasswordHash)>,
+}
+
+impl CredentialCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash cached for `username` on `server`/`hub`
+    ///
+    /// An entry for another user (a profile switch) is wiped instead.
+    pub fn get(&mut self, server: &str, hub: &str, username: &str) -> Option<PasswordHash> {
+        let key = (server.to_string(), hub.to_string());
+        match self.entries.get(&key) {
+            Some((cached_user, hash)) if cached_user == username => Some(hash.clone()),
+            Some(_) => {
+                self.entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Remember `hash` for `username` on `server`/`hub`, replacing any earlier entry
+    pub fn insert(&mut self, server: &str, hub: &str, username: &str, hash: PasswordHash) {
+        self.entries.insert((server.to_string(), hub.to_string()), (username.to_string(), hash));
+    }
+
+    /// Wipe the entry for `server`/`hub`, e.g. after the server rejected it
+    pub fn forget(&mut self, server: &str, hub: &str) {
+        self.entries.remove(&(server.to_string(), hub.to_string()));
+    }
+
+    /// Wipe every cached hash
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_per_server_hub_and_user() {
+        let mut cache = CredentialCache::new();
+        let hash = PasswordHash::new("alice", "secret");
+        assert_eq!(hash.as_bytes(), &hash_password("alice", "secret"));
+        assert_ne!(hash.secure_password(b"random one"), hash.secure_password(b"random two"));
+        assert_eq!(format!("{hash:?}"), "PasswordHash(<redacted>)");
+
+        cache.insert("10.0.0.1:443", "VPN", "alice", hash.clone());
+        assert_eq!(cache.get("10.0.0.1:443", "VPN", "alice"), Some(hash.clone()));
+        assert_eq!(cache.get("10.0.0.1:443", "OTHER", "alice"), None);
+        assert_eq!(cache.get("10.0.0.2:443", "VPN", "alice"), None);
+
+        // Another user on the same hub drops alice's hash
+        assert_eq!(cache.get("10.0.0.1:443", "VPN", "bob"), None);
+        assert!(cache.is_empty());
+
+        cache.insert("10.0.0.1:443", "VPN", "alice", hash.clone());
+        cache.insert("10.0.0.2:443", "VPN", "alice", hash);
+        cache.forget("10.0.0.1:443", "VPN");
+        assert_eq!(cache.len(), 1);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
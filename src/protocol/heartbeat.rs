@@ -0,0 +1,178 @@
+//! Session keepalives on the binary data channel
+//!
+//! Like SoftEther's own, a keepalive is padded with a random amount of random
+//! data (up to [`MAX_KEEPALIVE_SIZE`] bytes) so it cannot be told apart from
+//! traffic by its size. The server sends it back unchanged; an echo only
+//! counts when its sequence and padding match the last probe, which gives the
+//! round-trip time. Anything else arriving from the server, data included,
+//! shows that it is still there.
+//!
+//! [`Heartbeat`] is shared by both halves of a split session: the sending half
+//! records probes and the receiving half reports what arrives. Whoever owns
+//! the session asks [`Heartbeat::silent_for`] how long the server has not
+//! answered and gives up on it after the dead-peer timeout.
+
+use super::binary::protocol_constants::PACKET_TYPE_KEEPALIVE;
+use super::binary::SoftEtherPacket;
+use crate::error::{Result, VpnError};
+use bytes::Bytes;
+use rand::Rng;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Largest keepalive padding, as in SoftEther
+pub const MAX_KEEPALIVE_SIZE: usize = 512;
+
+/// Smallest padding, enough to tell one probe's echo from another's
+const MIN_KEEPALIVE_SIZE: usize = 16;
+
+/// A keepalive waiting for its echo
+#[derive(Debug)]
+struct Probe {
+    sequence: u32,
+    padding: Bytes,
+    sent: Instant,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The latest probe; an echo of an earlier one no longer counts
+    pending: Option<Probe>,
+    /// First probe sent since the server was last heard from
+    unanswered_since: Option<Instant>,
+    /// Round trip of the last echo, until taken
+    rtt: Option<Duration>,
+    /// When the last packet of any kind arrived from the server
+    last_heard: Option<Instant>,
+    probes: u64,
+    echoes: u64,
+}
+
+/// Keepalive bookkeeping for one data session
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    state: Mutex<State>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keepalive packet with sequence `sequence`, remembered as the probe to be echoed
+    pub fn probe(&self, session_id: u32, sequence: u32, now: Instant) -> SoftEtherPacket {
+        let mut rng = rand::thread_rng();
+        let mut padding = vec![0u8; rng.gen_range(MIN_KEEPALIVE_SIZE..=MAX_KEEPALIVE_SIZE)];
+        rng.fill(&mut padding[..]);
+        let padding = Bytes::from(padding);
+
+        let mut state = self.state.lock().unwrap();
+        state.pending = Some(Probe { sequence, padding: padding.clone(), sent: now });
+        state.unanswered_since.get_or_insert(now);
+        state.probes = state.probes.saturating_add(1);
+        SoftEtherPacket { data: padding, ..SoftEtherPacket::create_keepalive(session_id, sequence) }
+    }
+
+    /// Note a packet from the server, answering the last probe if it is its echo
+    ///
+    /// Returns the round-trip time when `packet` was that echo.
+    pub fn received(&self, packet: &SoftEtherPacket, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        state.unanswered_since = None;
+        state.last_heard = Some(now);
+        if packet.packet_type != PACKET_TYPE_KEEPALIVE {
+            return None;
+        }
+        let is_echo = state
+            .pending
+            .as_ref()
+            .is_some_and(|probe| probe.sequence == packet.sequence && probe.padding == packet.data);
+        if !is_echo {
+            log::debug!("Keepalive {} from the server is not an echo of the last probe", packet.sequence);
+            return None;
+        }
+        let probe = state.pending.take()?;
+        let rtt = now.saturating_duration_since(probe.sent);
+        state.rtt = Some(rtt);
+        state.echoes = state.echoes.saturating_add(1);
+        Some(rtt)
+    }
+
+    /// How long probes have gone unanswered, with nothing else heard from the server
+    pub fn silent_for(&self, now: Instant) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state.unanswered_since.map(|since| now.saturating_duration_since(since))
+    }
+
+    /// Fail once the server has been silent for longer than `limit`
+    pub fn check_alive(&self, limit: Duration, now: Instant) -> Result<()> {
+        match self.silent_for(now).filter(|silent| *silent > limit) {
+            Some(silent) => Err(VpnError::Timeout(format!(
+                "No answer to keepalives from the server for {}s",
+                silent.as_secs()
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// When the server was last heard from, keepalives included
+    pub fn last_heard(&self) -> Option<Instant> {
+        self.state.lock().unwrap().last_heard
+    }
+
+    /// Round trip measured since the last call, if an echo arrived
+    pub fn take_rtt(&self) -> Option<Duration> {
+        self.state.lock().unwrap().rtt.take()
+    }
+
+    /// Probes sent and echoes matched so far
+    pub fn counts(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.probes, state.echoes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::binary::protocol_constants::PACKET_TYPE_DATA;
+
+    #[test]
+    fn test_probe_is_padded_keepalive() {
+        let heartbeat = Heartbeat::new();
+        let packet = heartbeat.probe(7, 3, Instant::now());
+        assert_eq!((packet.packet_type, packet.session_id, packet.sequence), (PACKET_TYPE_KEEPALIVE, 7, 3));
+        assert!((MIN_KEEPALIVE_SIZE..=MAX_KEEPALIVE_SIZE).contains(&packet.data.len()));
+        assert_eq!(heartbeat.counts(), (1, 0));
+    }
+
+    #[test]
+    fn test_only_matching_echo_gives_rtt() {
+        let start = Instant::now();
+        let heartbeat = Heartbeat::new();
+        let first = heartbeat.probe(7, 1, start);
+        let second = heartbeat.probe(7, 2, start + Duration::from_secs(1));
+        assert_eq!(heartbeat.silent_for(start + Duration::from_secs(3)), Some(Duration::from_secs(3)));
+        assert!(heartbeat.check_alive(Duration::from_secs(3), start + Duration::from_secs(3)).is_ok());
+        let dead = heartbeat.check_alive(Duration::from_secs(3), start + Duration::from_secs(4));
+        assert!(matches!(dead, Err(VpnError::Timeout(_))));
+
+        // A stale echo or altered padding shows the server is alive but measures nothing
+        assert_eq!(heartbeat.received(&first, start + Duration::from_secs(2)), None);
+        assert_eq!(heartbeat.silent_for(start + Duration::from_secs(3)), None);
+        let altered = SoftEtherPacket { data: Bytes::from_static(b"not the padding"), ..second.clone() };
+        assert_eq!(heartbeat.received(&altered, start + Duration::from_secs(2)), None);
+
+        let rtt = heartbeat.received(&second, start + Duration::from_millis(1250));
+        assert_eq!(rtt, Some(Duration::from_millis(250)));
+        assert_eq!(heartbeat.take_rtt(), Some(Duration::from_millis(250)));
+        assert_eq!(heartbeat.take_rtt(), None);
+        assert_eq!(heartbeat.counts(), (2, 1));
+
+        // Data counts as hearing from the server
+        heartbeat.probe(7, 3, start + Duration::from_secs(5));
+        let data = SoftEtherPacket { packet_type: PACKET_TYPE_DATA, ..second };
+        assert_eq!(heartbeat.received(&data, start + Duration::from_secs(6)), None);
+        assert_eq!(heartbeat.silent_for(start + Duration::from_secs(9)), None);
+    }
+}
@@ -0,0 +1,180 @@
+//! Client identity reported to the server
+//!
+//! Some hubs filter sessions by the client version or the HTTP user agent.
+//! A [`ClientIdentity`] bundles everything the client says about itself so the
+//! same values are used in the watermark request, the login PACK and the
+//! SSL-VPN handshake. Identities are built from a curated preset, optionally
+//! with individual fields overridden, and validated so that we never mix a
+//! version number from one client with the build number of another.
+
+use crate::config::{IdentityConfig, IdentityPreset};
+use crate::error::{Result, VpnError};
+use crate::protocol::pack::Pack;
+
+/// Longest `client_str` accepted by SoftEther servers
+const MAX_CLIENT_STR_LEN: usize = 64;
+
+/// User agent sent with the HTTP watermark handshake
+const LEGACY_USER_AGENT: &str = "Mozilla/4.0 (compatible; MSIE 6.0; Windows NT 5.1)";
+
+/// Client name, version and user agent reported to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// `client_str` PACK element
+    pub client_str: String,
+    /// `client_ver` PACK element
+    pub client_ver: u32,
+    /// `client_build` PACK element
+    pub client_build: u32,
+    /// HTTP `User-Agent` header
+    pub user_agent: String,
+}
+
+impl Default for ClientIdentity {
+    fn default() -> Self {
+        Self::preset(IdentityPreset::default())
+    }
+}
+
+impl ClientIdentity {
+    /// Values of a curated preset
+    pub fn preset(preset: IdentityPreset) -> Self {
+        let (client_str, client_ver, client_build) = match preset {
+            IdentityPreset::Legacy => ("SE-VPN Client", 4560, 9686),
+            IdentityPreset::SoftEther438 => ("SoftEther VPN Client", 438, 9760),
+            IdentityPreset::SoftEther442 => ("SoftEther VPN Client", 442, 9798),
+        };
+        Self {
+            client_str: client_str.to_string(),
+            client_ver,
+            client_build,
+            user_agent: LEGACY_USER_AGENT.to_string(),
+        }
+    }
+
+    /// Build the identity described by the configuration and validate it
+    pub fn from_config(config: &IdentityConfig) -> Result<Self> {
+        let mut identity = Self::preset(config.preset);
+
+        match (config.client_ver, config.client_build) {
+            (Some(ver), Some(build)) => {
+                identity.client_ver = ver;
+                identity.client_build = build;
+            }
+            (None, None) => {}
+            _ => {
+                return Err(VpnError::Config(
+                    "identity.client_ver and identity.client_build must be overridden together".into(),
+                ))
+            }
+        }
+        if let Some(ref client_str) = config.client_str {
+            identity.client_str = client_str.clone();
+        }
+        if let Some(ref user_agent) = config.user_agent {
+            identity.user_agent = user_agent.clone();
+        }
+
+        identity.validate()?;
+        Ok(identity)
+    }
+
+    /// Reject values a real client would never send
+    pub fn validate(&self) -> Result<()> {
+        if self.client_str.is_empty() || self.client_str.len() > MAX_CLIENT_STR_LEN {
+            return Err(VpnError::Config(format!(
+                "identity.client_str must be 1-{MAX_CLIENT_STR_LEN} characters"
+            )));
+        }
+        if !is_printable_ascii(&self.client_str) {
+            return Err(VpnError::Config(
+                "identity.client_str must be printable ASCII".into(),
+            ));
+        }
+        if self.client_ver == 0 || self.client_build == 0 {
+            return Err(VpnError::Config(
+                "identity.client_ver and identity.client_build must be greater than 0".into(),
+            ));
+        }
+        // Genuine SoftEther clients report CEDAR_VERSION (major * 100 + minor)
+        if self.client_str.starts_with("SoftEther") && !(400..500).contains(&self.client_ver) {
+            return Err(VpnError::Config(format!(
+                "identity.client_ver {} is not a SoftEther 4.x version (expected 400-499)",
+                self.client_ver
+            )));
+        }
+        if self.user_agent.is_empty() || !is_printable_ascii(&self.user_agent) {
+            return Err(VpnError::Config(
+                "identity.user_agent must be non-empty printable ASCII".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Add the `client_str`, `client_ver` and `client_build` elements to a PACK
+    pub fn apply_to_pack(&self, pack: &mut Pack) {
+        pack.add_str("client_str", &self.client_str);
+        pack.add_int("client_ver", self.client_ver);
+        pack.add_int("client_build", self.client_build);
+    }
+}
+
+fn is_printable_ascii(s: &str) -> bool {
+    s.bytes().all(|b| (0x20..0x7f).contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_identity_matches_legacy_values() {
+        let identity = ClientIdentity::default();
+        assert_eq!(identity.client_str, "SE-VPN Client");
+        assert_eq!(identity.client_ver, 4560);
+        assert_eq!(identity.client_build, 9686);
+        assert_eq!(
+            ClientIdentity::from_config(&IdentityConfig::default()).unwrap(),
+            identity
+        );
+    }
+
+    #[test]
+    fn test_preset_with_overrides() {
+        let config: IdentityConfig = toml::from_str(
+            r#"
+preset = "softether-4.42"
+user_agent = "SoftEther VPN Client"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.preset, IdentityPreset::SoftEther442);
+        let identity = ClientIdentity::from_config(&config).unwrap();
+        assert_eq!(identity.client_str, "SoftEther VPN Client");
+        assert_eq!((identity.client_ver, identity.client_build), (442, 9798));
+        assert_eq!(identity.user_agent, "SoftEther VPN Client");
+    }
+
+    #[test]
+    fn test_inconsistent_identities_are_rejected() {
+        // Version without build mixes two different clients
+        let config = IdentityConfig {
+            client_ver: Some(441),
+            ..Default::default()
+        };
+        assert!(ClientIdentity::from_config(&config).is_err());
+
+        // SoftEther branding with a non-4.x version
+        let config = IdentityConfig {
+            client_str: Some("SoftEther VPN Client".to_string()),
+            ..Default::default()
+        };
+        assert!(ClientIdentity::from_config(&config).is_err());
+
+        let config = IdentityConfig {
+            user_agent: Some("bad\r\nX-Injected: 1".to_string()),
+            ..Default::default()
+        };
+        assert!(ClientIdentity::from_config(&config).is_err());
+    }
+}
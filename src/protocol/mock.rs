@@ -0,0 +1,155 @@
+//! In-process mock of the SoftEther binary data channel
+//!
+//! Speaks the server side of [`BinaryProtocolClient`](super::BinaryProtocolClient)
+//! on a loopback port: answers the hello and session handshakes and echoes
+//! every data packet and keepalive back, data encrypted when started with
+//! [`MockServer::start_encrypted`]. A connection joining a session as one of
+//! its bonded connections uses the keys for the index its hello announces. Meant for examples, doctests and integration tests
+//! that need a live session without a VPN server.
+//!
+//! ```
+//! use bytes::Bytes;
+//! use rvpnse::protocol::mock::MockServer;
+//! use rvpnse::protocol::BinaryProtocolClient;
+//!
+//! # #[tokio::main]
+//! # async fn main() -> rvpnse::Result<()> {
+//! let server = MockServer::start().await?;
+//!
+//! let mut client = BinaryProtocolClient::new(server.addr());
+//! client.connect().await?;
+//! client.authenticate("alice", "secret", "VPN").await?;
+//! client.establish_session().await?;
+//!
+//! client.send_vpn_data(Bytes::from_static(b"ping")).await?;
+//! assert_eq!(client.receive_vpn_data().await?, Bytes::from_static(b"ping"));
+//!
+//! client.disconnect().await?;
+//! assert_eq!(server.sessions_established(), 1);
+//! # Ok(())
+//! # }
+//! ```
+
+use super::binary::protocol_constants::*;
+use super::binary::{data_aad, read_packet, write_packet, SoftEtherPacket};
+use crate::crypto::{DataCipher, SessionKeys};
+use crate::error::Result;
+use bytes::Bytes;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Loopback server for the binary data channel
+///
+/// Stops accepting connections when dropped.
+#[derive(Debug)]
+pub struct MockServer {
+    addr: SocketAddr,
+    sessions: Arc<AtomicUsize>,
+    accept_task: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Listen on an ephemeral loopback port and serve every connection
+    pub async fn start() -> Result<Self> {
+        Self::listen(None).await
+    }
+
+    /// Like [`start`](Self::start), with data payloads encrypted under `keys`
+    pub async fn start_encrypted(keys: SessionKeys) -> Result<Self> {
+        Self::listen(Some(keys)).await
+    }
+
+    async fn listen(keys: Option<SessionKeys>) -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let sessions = Arc::new(AtomicUsize::new(0));
+
+        let counter = sessions.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, counter.clone(), keys.clone()));
+            }
+        });
+
+        Ok(Self { addr, sessions, accept_task })
+    }
+
+    /// Address clients connect to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Session handshakes completed across all connections
+    pub fn sessions_established(&self) -> usize {
+        self.sessions.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// Ciphers for the server end of bonded connection `index`
+fn connection_ciphers(keys: Option<&SessionKeys>, index: u32) -> Option<(DataCipher, DataCipher)> {
+    let keys = keys?;
+    match keys.for_connection(index).and_then(|keys| keys.server_ciphers()) {
+        Ok(ciphers) => Some(ciphers),
+        Err(e) => {
+            log::warn!("Mock server cannot set up encryption: {e}");
+            None
+        }
+    }
+}
+
+/// Serve one connection until the client goes away or sends a packet that fails decryption
+async fn serve(mut stream: TcpStream, sessions: Arc<AtomicUsize>, keys: Option<SessionKeys>) {
+    let mut ciphers = connection_ciphers(keys.as_ref(), 0);
+    if keys.is_some() && ciphers.is_none() {
+        return;
+    }
+    while let Ok(packet) = read_packet(&mut stream).await {
+        let reply = match packet.packet_type {
+            PACKET_TYPE_HELLO => {
+                // The hello announces which of the session's connections this is
+                ciphers = connection_ciphers(keys.as_ref(), packet.sequence);
+                if keys.is_some() && ciphers.is_none() {
+                    break;
+                }
+                SoftEtherPacket {
+                    packet_type: PACKET_TYPE_HELLO_RESPONSE,
+                    session_id: 0,
+                    sequence: 0,
+                    data: Bytes::new(),
+                }
+            }
+            PACKET_TYPE_SESSION_ESTABLISH => {
+                sessions.fetch_add(1, Ordering::SeqCst);
+                SoftEtherPacket {
+                    packet_type: PACKET_TYPE_SESSION_RESPONSE,
+                    session_id: packet.session_id,
+                    sequence: 0,
+                    data: Bytes::new(),
+                }
+            }
+            PACKET_TYPE_DATA => match ciphers {
+                Some((ref mut outbound, ref mut inbound)) => {
+                    let aad = data_aad(packet.session_id, packet.sequence);
+                    let Ok(data) = inbound.open(&aad, &packet.data) else { break };
+                    let Ok(data) = outbound.seal(&aad, &data) else { break };
+                    SoftEtherPacket::create_data_packet(packet.session_id, packet.sequence, data.into())
+                }
+                None => SoftEtherPacket::create_data_packet(packet.session_id, packet.sequence, packet.data),
+            },
+            PACKET_TYPE_KEEPALIVE => packet,
+            _ => continue,
+        };
+        if write_packet(&mut stream, reply).await.is_err() {
+            break;
+        }
+    }
+}
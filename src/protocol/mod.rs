@@ -1,24 +1,83 @@
 //! `SoftEther` SSL-VPN protocol implementation for static library
 
+#[cfg(feature = "native")]
 use crate::error::{Result, VpnError};
+#[cfg(feature = "native")]
 use std::net::SocketAddr;
 
+#[cfg(feature = "native")]
 pub mod auth;
+#[cfg(feature = "native")]
 pub mod session;
+#[cfg(feature = "native")]
 pub mod watermark;
 pub mod pack;
+#[cfg(feature = "native")]
 pub mod binary;
+#[cfg(feature = "native")]
+pub mod bonding;
+pub mod compression;
+#[cfg(feature = "native")]
+pub mod mock;
+pub mod cert_auth;
+#[cfg(feature = "native")]
+pub mod credentials;
+#[cfg(feature = "native")]
+pub mod heartbeat;
+pub mod identity;
+#[cfg(feature = "native")]
+pub mod nat_keepalive;
+#[cfg(feature = "native")]
+pub mod nat_traversal;
+#[cfg(feature = "native")]
+pub mod otp;
+#[cfg(feature = "native")]
+pub mod proxy;
+#[cfg(feature = "native")]
+pub mod resumption;
+#[cfg(feature = "native")]
+pub mod rpc;
+#[cfg(feature = "native")]
+pub mod udp_accel;
+#[cfg(feature = "no_std_core")]
+pub mod nostd;
+
+#[cfg(all(test, feature = "native"))]
+mod pack_golden;
 
 // Re-export main types
-pub use auth::AuthClient;
+#[cfg(feature = "native")]
+pub use auth::{AuthClient, LoginMethod};
 pub use pack::{Pack, Element, Value, ElementType};
+#[cfg(feature = "native")]
 pub use watermark::{WatermarkClient, WatermarkResponse, SOFTETHER_WATERMARK};
+#[cfg(feature = "native")]
 pub use binary::BinaryProtocolClient;
+#[cfg(feature = "native")]
+pub use bonding::{BondHealth, BondedReceiver, BondedSender, LinkStatus};
+#[cfg(feature = "native")]
+pub use credentials::{CredentialCache, PasswordHash};
+#[cfg(feature = "native")]
+pub use heartbeat::Heartbeat;
+pub use identity::ClientIdentity;
+#[cfg(feature = "native")]
+pub use nat_traversal::{NatTraversal, NatTraversalRelay};
+#[cfg(feature = "native")]
+pub use otp::{OtpChallenge, OtpProvider};
+#[cfg(feature = "native")]
+pub use resumption::{SessionTicket, TicketStore};
+#[cfg(feature = "native")]
+pub use rpc::{HubSummary, ServerInfo};
+#[cfg(feature = "native")]
+pub use udp_accel::{DataPath, UdpAccelGrant, UdpAccelOffer, UdpAccelSession};
 
 // Protocol constants
 pub mod constants {
     pub const DEFAULT_PORT: u16 = 443;
     pub const DEFAULT_HUB: &str = "VPN";
+
+    /// Most connections a session can have, as in SoftEther
+    pub const MAX_BONDED_CONNECTIONS: u32 = 32;
     
     // HTTP endpoints for SoftEther protocol
     pub const WATERMARK_ENDPOINT: &str = "/vpnsvc/connect.cgi";
@@ -31,26 +90,62 @@ pub mod constants {
 /// This implements the actual SoftEther SSL-VPN protocol:
 /// 1. HTTP Watermark handshake to establish session
 /// 2. PACK binary format over HTTPS for all data communication
+#[cfg(feature = "native")]
 pub struct ProtocolHandler {
     server_addr: SocketAddr,
     watermark_client: Option<WatermarkClient>,
     session_established: bool,
     session_id: Option<String>,
+    /// Hello PACK the server answered the watermark with
+    hello: Option<Pack>,
 }
 
+#[cfg(feature = "native")]
 impl ProtocolHandler {
     /// Create a new protocol handler
     pub fn new(server_addr: SocketAddr, verify_certificate: bool) -> Result<Self> {
-        let watermark_client = WatermarkClient::new(server_addr, None, verify_certificate)?;
+        Self::with_proxy(server_addr, verify_certificate, None)
+    }
+
+    /// Create a protocol handler whose HTTP requests go through `proxy_url`
+    pub fn with_proxy(server_addr: SocketAddr, verify_certificate: bool, proxy_url: Option<&str>) -> Result<Self> {
+        let certificates = crate::crypto::tls::CertificatePolicy::new(verify_certificate);
+        Self::with_underlay(server_addr, &certificates, proxy_url, None)
+    }
+
+    /// Create a protocol handler whose HTTP requests leave through `underlay`
+    pub fn with_underlay(
+        server_addr: SocketAddr,
+        certificates: &crate::crypto::tls::CertificatePolicy,
+        proxy_url: Option<&str>,
+        underlay: Option<&crate::underlay::UnderlayBinding>,
+    ) -> Result<Self> {
+        let watermark_client =
+            WatermarkClient::with_underlay(server_addr, None, certificates, proxy_url, underlay)?;
         
         Ok(ProtocolHandler {
             server_addr,
             watermark_client: Some(watermark_client),
             session_established: false,
             session_id: None,
+            hello: None,
         })
     }
 
+    /// Send HTTP requests to `base_url` instead of `https://` the server
+    pub fn set_base_url(&mut self, base_url: &str) {
+        if let Some(ref mut watermark_client) = self.watermark_client {
+            watermark_client.base_url = base_url.to_string();
+        }
+    }
+
+    /// Report `identity` (user agent) in subsequent requests
+    pub fn set_identity(&mut self, identity: &ClientIdentity) {
+        if let Some(ref mut watermark_client) = self.watermark_client {
+            watermark_client.set_user_agent(&identity.user_agent);
+        }
+    }
+
     /// Get server address
     pub fn server_address(&self) -> SocketAddr {
         self.server_addr
@@ -62,10 +157,14 @@ impl ProtocolHandler {
             VpnError::Protocol("Watermark client not initialized".to_string())
         })?;
 
-        let response = watermark_client.send_watermark_handshake().await?;
+        let response = rpc::call_with_retry(rpc::RpcMethod::Watermark, &rpc::RetryPolicy::default(), || {
+            watermark_client.send_watermark_handshake()
+        })
+        .await?;
         
         if response.is_session_established() {
             self.session_established = true;
+            self.hello = Pack::from_bytes(response.response_data.into()).ok();
             // Generate a session ID (in real implementation, this would come from server)
             self.session_id = Some(format!("session_{}", fastrand::u64(..)));
             Ok(())
@@ -84,6 +183,11 @@ impl ProtocolHandler {
         self.session_id.as_deref()
     }
 
+    /// The server's hello, once the session is established
+    pub fn server_hello(&self) -> Option<&Pack> {
+        self.hello.as_ref()
+    }
+
     /// Send PACK data over HTTPS (post-watermark communication)
     pub async fn send_pack(&self, pack: &Pack) -> Result<Pack> {
         if !self.session_established {
@@ -101,6 +205,7 @@ impl ProtocolHandler {
         let response = watermark_client.http_client
             .post(&format!("{}{}", watermark_client.base_url, constants::WATERMARK_ENDPOINT))
             .header("Content-Type", constants::HTTP_CONTENT_TYPE_PACK)
+            .header("User-Agent", &watermark_client.user_agent)
             .header("Connection", "Keep-Alive")
             .header("Keep-Alive", constants::HTTP_KEEP_ALIVE)
             .body(pack_data.to_vec())
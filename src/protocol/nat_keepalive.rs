@@ -0,0 +1,278 @@
+//! Adaptive NAT keepalive for UDP paths
+//!
+//! NAT devices drop idle UDP mappings after anything from ~20 seconds to a
+//! few minutes. Instead of a fixed, pessimistic interval, the keepalive
+//! binary-searches the mapping lifetime: it lets the path idle for a probe
+//! interval, sends a small keepalive and treats an answer from the peer as
+//! proof that the mapping survived. Keepalives are only sent when the socket
+//! has been idle, so a busy path costs nothing, and they are independent of
+//! the session keepalive in [`SessionManager`](crate::protocol::session::SessionManager).
+//!
+//! The UDP path drives it from its own loop: it reports traffic with
+//! [`NatKeepalive::on_activity`] and [`NatKeepalive::on_received`], and sends
+//! a keepalive whenever [`NatKeepalive::poll`] asks for one, at the latest by
+//! [`NatKeepalive::deadline`].
+
+use std::time::{Duration, Instant};
+
+/// NAT keepalive tuning
+#[derive(Debug, Clone)]
+pub struct NatKeepaliveConfig {
+    /// Shortest interval ever used; also the fallback after a lost mapping
+    pub min_interval: Duration,
+    /// Longest idle interval probed
+    pub max_interval: Duration,
+    /// Stop searching once the bracket is narrower than this
+    pub resolution: Duration,
+    /// How long to wait for the peer's answer to a keepalive
+    pub response_timeout: Duration,
+    /// Fraction of the detected NAT timeout used as steady-state interval
+    pub safety_factor: f64,
+}
+
+impl Default for NatKeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            min_interval: Duration::from_secs(10),
+            max_interval: Duration::from_secs(180),
+            resolution: Duration::from_secs(5),
+            response_timeout: Duration::from_secs(2),
+            safety_factor: 0.8,
+        }
+    }
+}
+
+/// NAT keepalive statistics
+#[derive(Debug, Clone, Default, PartialEq)]
+#[derive(Eq)]
+pub struct NatKeepaliveStats {
+    /// Estimated NAT mapping timeout once the search has converged
+    pub detected_nat_timeout: Option<Duration>,
+    /// Idle interval currently used before sending a keepalive
+    pub current_interval: Duration,
+    pub keepalives_sent: u64,
+    /// Keepalives the peer did not answer (mapping presumed expired)
+    pub keepalives_lost: u64,
+}
+
+/// Binary search over the NAT mapping lifetime plus idle tracking
+#[derive(Debug)]
+pub struct NatKeepalive {
+    config: NatKeepaliveConfig,
+    /// Longest idle interval known to keep the mapping alive
+    known_good: Duration,
+    /// Shortest idle interval known to lose the mapping
+    known_bad: Option<Duration>,
+    last_activity: Instant,
+    /// Keepalive waiting for an answer: when it was sent and how long the path had idled
+    probe: Option<(Instant, Duration)>,
+    stats: NatKeepaliveStats,
+}
+
+impl NatKeepalive {
+    pub fn new(config: NatKeepaliveConfig) -> Self {
+        let mut keepalive = Self {
+            known_good: config.min_interval,
+            known_bad: None,
+            last_activity: Instant::now(),
+            probe: None,
+            stats: NatKeepaliveStats::default(),
+            config,
+        };
+        keepalive.stats.current_interval = keepalive.next_interval();
+        keepalive
+    }
+
+    /// Record traffic on the socket (either direction); delays the next keepalive
+    pub fn on_activity(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Record a datagram from the peer, which also answers an outstanding keepalive
+    pub fn on_received(&mut self) {
+        if let Some((_, idle)) = self.probe.take() {
+            self.record_answered(idle);
+            log::debug!("NAT keepalive answered after {:?} idle", idle);
+        }
+        self.on_activity();
+    }
+
+    /// Time until the next keepalive is due (zero if overdue)
+    pub fn time_until_due(&self) -> Duration {
+        self.stats
+            .current_interval
+            .saturating_sub(self.last_activity.elapsed())
+    }
+
+    /// When [`poll`](Self::poll) has something to do next
+    pub fn deadline(&self) -> Instant {
+        match self.probe {
+            Some((sent_at, _)) => sent_at + self.config.response_timeout,
+            None => self.last_activity + self.stats.current_interval,
+        }
+    }
+
+    /// Whether a keepalive should be sent now
+    ///
+    /// A keepalive left unanswered for `response_timeout` is counted as lost
+    /// here. Once this returns true, the keepalive is expected to go out.
+    pub fn poll(&mut self) -> bool {
+        let now = Instant::now();
+        if let Some((sent_at, idle)) = self.probe {
+            if now.duration_since(sent_at) >= self.config.response_timeout {
+                self.probe = None;
+                self.record_lost(idle);
+                log::debug!("NAT keepalive lost after {:?} idle", idle);
+                self.last_activity = now;
+            }
+            return false;
+        }
+        let idle = now.duration_since(self.last_activity);
+        if idle < self.stats.current_interval {
+            return false;
+        }
+        self.probe = Some((now, idle));
+        true
+    }
+
+    /// Whether the search has narrowed the NAT timeout down to `resolution`
+    pub fn is_converged(&self) -> bool {
+        match self.known_bad {
+            Some(bad) => bad.saturating_sub(self.known_good) <= self.config.resolution,
+            None => self.known_good >= self.config.max_interval,
+        }
+    }
+
+    /// The keepalive sent after `idle` was answered: the mapping survived
+    pub fn record_answered(&mut self, idle: Duration) {
+        self.stats.keepalives_sent += 1;
+        if idle > self.known_good {
+            self.known_good = idle.min(self.config.max_interval);
+        }
+        self.update();
+    }
+
+    /// The keepalive sent after `idle` went unanswered: the mapping expired
+    pub fn record_lost(&mut self, idle: Duration) {
+        self.stats.keepalives_sent += 1;
+        self.stats.keepalives_lost += 1;
+        if idle <= self.known_good {
+            // The NAT got stricter (or the path changed) - restart the search
+            self.known_good = self.config.min_interval;
+            self.known_bad = None;
+        } else if self.known_bad.is_none_or(|bad| idle < bad) {
+            self.known_bad = Some(idle);
+        }
+        self.update();
+    }
+
+    pub fn stats(&self) -> NatKeepaliveStats {
+        self.stats.clone()
+    }
+
+    fn update(&mut self) {
+        self.stats.current_interval = self.next_interval();
+        self.stats.detected_nat_timeout = match self.known_bad {
+            Some(bad) if self.is_converged() => Some(bad),
+            _ => None,
+        };
+    }
+
+    /// Midpoint of the bracket while searching, a safe fraction once converged
+    fn next_interval(&self) -> Duration {
+        if self.is_converged() {
+            let steady = match self.known_bad {
+                Some(bad) => bad.mul_f64(self.config.safety_factor),
+                None => self.config.max_interval,
+            };
+            return steady.clamp(self.config.min_interval, self.known_good.max(self.config.min_interval));
+        }
+        let upper = self.known_bad.unwrap_or(self.config.max_interval);
+        let midpoint = self.known_good + upper.saturating_sub(self.known_good) / 2;
+        midpoint.clamp(self.config.min_interval, self.config.max_interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> NatKeepaliveConfig {
+        NatKeepaliveConfig {
+            min_interval: Duration::from_secs(10),
+            max_interval: Duration::from_secs(160),
+            resolution: Duration::from_secs(5),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_binary_search_converges_on_nat_timeout() {
+        // Simulated NAT drops mappings idle for 60s or more
+        let nat_timeout = Duration::from_secs(60);
+        let mut keepalive = NatKeepalive::new(config());
+
+        for _ in 0..16 {
+            if keepalive.is_converged() {
+                break;
+            }
+            let idle = keepalive.stats().current_interval;
+            if idle < nat_timeout {
+                keepalive.record_answered(idle);
+            } else {
+                keepalive.record_lost(idle);
+            }
+        }
+
+        let stats = keepalive.stats();
+        assert!(keepalive.is_converged());
+        let detected = stats.detected_nat_timeout.unwrap();
+        assert!(detected >= nat_timeout && detected <= nat_timeout + Duration::from_secs(5));
+        assert!(stats.current_interval < nat_timeout);
+        assert!(stats.keepalives_lost > 0);
+    }
+
+    #[test]
+    fn test_lost_mapping_below_known_good_restarts_search() {
+        let mut keepalive = NatKeepalive::new(config());
+        keepalive.record_answered(Duration::from_secs(80));
+        keepalive.record_lost(Duration::from_secs(40));
+        assert_eq!(keepalive.known_good, Duration::from_secs(10));
+        assert!(keepalive.stats().detected_nat_timeout.is_none());
+    }
+
+    #[test]
+    fn test_activity_defers_keepalive() {
+        let mut keepalive = NatKeepalive::new(config());
+        keepalive.on_activity();
+        assert!(keepalive.time_until_due() > Duration::from_secs(9));
+    }
+
+    #[test]
+    fn test_poll_settles_answered_and_lost_keepalives() {
+        let mut keepalive = NatKeepalive::new(NatKeepaliveConfig {
+            min_interval: Duration::from_millis(20),
+            max_interval: Duration::from_millis(160),
+            resolution: Duration::from_millis(10),
+            response_timeout: Duration::from_millis(20),
+            safety_factor: 0.8,
+        });
+        assert!(!keepalive.poll());
+
+        std::thread::sleep(keepalive.deadline().saturating_duration_since(Instant::now()));
+        assert!(keepalive.poll());
+        // Nothing more to send while the keepalive is out
+        assert!(!keepalive.poll());
+        keepalive.on_received();
+        assert_eq!(keepalive.stats().keepalives_sent, 1);
+        assert!(keepalive.known_good >= Duration::from_millis(90));
+
+        std::thread::sleep(keepalive.deadline().saturating_duration_since(Instant::now()));
+        assert!(keepalive.poll());
+        std::thread::sleep(keepalive.deadline().saturating_duration_since(Instant::now()));
+        assert!(!keepalive.poll());
+        let stats = keepalive.stats();
+        assert_eq!((stats.keepalives_sent, stats.keepalives_lost), (2, 1));
+        assert!(stats.current_interval < Duration::from_millis(160));
+    }
+}
@@ -0,0 +1,673 @@
+//! NAT traversal (NAT-T): reaching the server over UDP hole punching
+//!
+//! Where TCP 443 is blocked, or the server sits behind a NAT without port
+//! forwarding, the session can still run over UDP. The client asks a NAT-T
+//! server, with which the VPN server keeps itself registered, to arrange a
+//! meeting: the NAT-T server tells the VPN server the client's public
+//! endpoint and answers with the server's.
+//!
+//! Both sides then send punch datagrams to each other until one gets
+//! through their NATs. The byte stream on top is made reliable here: numbered
+//! segments, cumulative acknowledgements and retransmission. Every datagram,
+//! the exchange with the NAT-T server included, has the same layout:
+//!
+//! ```text
+//! magic "NATT" (4) | kind (1) | session (4) | seq (4) | ack (4) | payload
+//!
+//! request  seq = transaction id                        payload = dest_port (2) | dest_host
+//! reply    seq = transaction id, ack = 1 (ok) / 0      payload = "ip:port" of the server, or the error
+//! keepalive kind = ack, seq = 1                         answered with an ack
+//! ```
+//!
+//! An idle stream sends keepalives as its [`NatKeepalive`] asks for them. An
+//! empty data segment ends the stream. TLS runs inside as over TCP, so the
+//! segments are not encrypted themselves. [`NatTraversalRelay`] offers the
+//! stream as a loopback HTTP proxy, letting the HTTP client and the data
+//! connections use it like any other [`UpstreamProxy`](super::proxy::UpstreamProxy).
+
+use crate::config::NatTraversalConfig;
+use crate::error::{Result, VpnError};
+use crate::protocol::nat_keepalive::{NatKeepalive, NatKeepaliveConfig, NatKeepaliveStats};
+use crate::proxy::connect::read_head;
+use crate::underlay::UnderlayBinding;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+
+/// UDP port NAT-T servers listen on
+pub const NAT_T_PORT: u16 = 5004;
+
+const MAGIC: &[u8; 4] = b"NATT";
+const HEADER_LEN: usize = 4 + 1 + 4 + 4 + 4;
+
+/// Largest payload of one segment, small enough to avoid fragmentation
+const MAX_PAYLOAD: usize = 1200;
+
+/// Segments in flight before the sender waits for acknowledgements
+const WINDOW: usize = 128;
+
+const INITIAL_RTO: Duration = Duration::from_millis(250);
+const MAX_RTO: Duration = Duration::from_secs(2);
+
+/// Retransmissions of one segment before the stream is given up
+const MAX_RETRIES: u32 = 10;
+
+/// How often the stream checks for retransmissions and idleness
+const TICK: Duration = Duration::from_millis(50);
+
+/// Shortest idle time before the stream sends a keepalive
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Longest idle time probed by the NAT keepalive, well within the peer's [`DEAD_TIMEOUT`]
+const MAX_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// `seq` of an acknowledgement that asks the peer for one back
+const KEEPALIVE_SEQ: u32 = 1;
+
+/// The stream is dead once nothing was heard from the peer for this long
+const DEAD_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// How often requests and punches are repeated until answered
+const RETRANSMIT_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Bytes buffered between the stream task and its user, each way
+const STREAM_BUFFER: usize = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Kind {
+    Punch = 0,
+    Data = 1,
+    Ack = 2,
+    Request = 3,
+    Reply = 4,
+}
+
+/// One datagram of the stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Segment {
+    kind: Kind,
+    session: u32,
+    seq: u32,
+    ack: u32,
+    payload: Bytes,
+}
+
+impl Segment {
+    fn encode(&self) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        datagram.extend_from_slice(MAGIC);
+        datagram.push(self.kind as u8);
+        datagram.extend_from_slice(&self.session.to_be_bytes());
+        datagram.extend_from_slice(&self.seq.to_be_bytes());
+        datagram.extend_from_slice(&self.ack.to_be_bytes());
+        datagram.extend_from_slice(&self.payload);
+        datagram
+    }
+
+    /// Parse a datagram, `None` for anything that is not a segment
+    fn decode(datagram: &[u8]) -> Option<Self> {
+        if datagram.len() < HEADER_LEN || &datagram[..4] != MAGIC {
+            return None;
+        }
+        let kind = match datagram[4] {
+            0 => Kind::Punch,
+            1 => Kind::Data,
+            2 => Kind::Ack,
+            3 => Kind::Request,
+            4 => Kind::Reply,
+            _ => return None,
+        };
+        let word = |offset: usize| u32::from_be_bytes(datagram[offset..offset + 4].try_into().unwrap_or_default());
+        Some(Self {
+            kind,
+            session: word(5),
+            seq: word(9),
+            ack: word(13),
+            payload: Bytes::copy_from_slice(&datagram[HEADER_LEN..]),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Unacked {
+    payload: Bytes,
+    sent_at: Instant,
+    rto: Duration,
+    retries: u32,
+}
+
+/// Sequencing, acknowledgement and retransmission of one stream
+#[derive(Debug, Default)]
+struct Reliable {
+    next_seq: u32,
+    unacked: BTreeMap<u32, Unacked>,
+    /// Next sequence number to deliver
+    expected: u32,
+    out_of_order: BTreeMap<u32, Bytes>,
+}
+
+impl Reliable {
+    fn can_send(&self) -> bool {
+        self.unacked.len() < WINDOW
+    }
+
+    /// Number `payload` and keep it until acknowledged
+    fn send(&mut self, payload: Bytes, now: Instant) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.unacked.insert(seq, Unacked { payload, sent_at: now, rto: INITIAL_RTO, retries: 0 });
+        seq
+    }
+
+    /// Drop everything the peer acknowledged (all below `ack`)
+    fn on_ack(&mut self, ack: u32) {
+        self.unacked.retain(|&seq, _| seq.wrapping_sub(ack) as i32 >= 0);
+    }
+
+    /// Take a data segment, returning the payloads now deliverable in order
+    fn on_data(&mut self, seq: u32, payload: Bytes) -> Vec<Bytes> {
+        if seq.wrapping_sub(self.expected) as i32 >= 0 {
+            self.out_of_order.insert(seq, payload);
+        }
+        let mut ready = Vec::new();
+        while let Some(payload) = self.out_of_order.remove(&self.expected) {
+            ready.push(payload);
+            self.expected = self.expected.wrapping_add(1);
+        }
+        ready
+    }
+
+    /// Segments whose acknowledgement is overdue, with backed-off timers
+    fn due(&mut self, now: Instant) -> Result<Vec<(u32, Bytes)>> {
+        let mut due = Vec::new();
+        for (&seq, unacked) in &mut self.unacked {
+            if now.duration_since(unacked.sent_at) < unacked.rto {
+                continue;
+            }
+            if unacked.retries >= MAX_RETRIES {
+                return Err(VpnError::Network("NAT-T peer stopped acknowledging".to_string()));
+            }
+            unacked.retries += 1;
+            unacked.sent_at = now;
+            unacked.rto = (unacked.rto * 2).min(MAX_RTO);
+            due.push((seq, unacked.payload.clone()));
+        }
+        Ok(due)
+    }
+
+    fn all_acked(&self) -> bool {
+        self.unacked.is_empty()
+    }
+}
+
+fn nat_keepalive_config() -> NatKeepaliveConfig {
+    NatKeepaliveConfig {
+        min_interval: KEEPALIVE_INTERVAL,
+        max_interval: MAX_KEEPALIVE_INTERVAL,
+        resolution: Duration::from_secs(1),
+        response_timeout: KEEPALIVE_INTERVAL,
+        ..NatKeepaliveConfig::default()
+    }
+}
+
+/// Run the stream between `socket` (connected to the peer) and the user's end of `app`
+///
+/// The NAT keepalive's statistics are copied into `keepalive_stats` as they change.
+async fn run_stream(
+    socket: UdpSocket,
+    session: u32,
+    mut app: DuplexStream,
+    keepalive_stats: Arc<Mutex<NatKeepaliveStats>>,
+) -> Result<()> {
+    let mut reliable = Reliable::default();
+    let mut datagram = vec![0u8; MAX_PAYLOAD + HEADER_LEN + 64];
+    let mut chunk = vec![0u8; MAX_PAYLOAD];
+    let mut tick = tokio::time::interval(TICK);
+    let mut last_heard = Instant::now();
+    let mut keepalive = NatKeepalive::new(nat_keepalive_config());
+    *keepalive_stats.lock().unwrap() = keepalive.stats();
+    let (mut sent_eof, mut received_eof) = (false, false);
+
+    let segment = |kind, seq, ack, payload| Segment { kind, session, seq, ack, payload }.encode();
+
+    loop {
+        if sent_eof && received_eof && reliable.all_acked() {
+            return Ok(());
+        }
+        tokio::select! {
+            received = socket.recv(&mut datagram) => {
+                let Some(incoming) = Segment::decode(&datagram[..received?]).filter(|s| s.session == session) else {
+                    continue;
+                };
+                last_heard = Instant::now();
+                keepalive.on_received();
+                reliable.on_ack(incoming.ack);
+                match incoming.kind {
+                    // The peer missed our punch; answer so it stops
+                    Kind::Punch => {
+                        socket.send(&segment(Kind::Punch, 0, reliable.expected, Bytes::new())).await?;
+                    }
+                    Kind::Ack if incoming.seq == KEEPALIVE_SEQ => {
+                        socket.send(&segment(Kind::Ack, 0, reliable.expected, Bytes::new())).await?;
+                        keepalive.on_activity();
+                    }
+                    Kind::Data => {
+                        for payload in reliable.on_data(incoming.seq, incoming.payload) {
+                            if payload.is_empty() {
+                                received_eof = true;
+                                let _ = app.shutdown().await;
+                            } else if !received_eof {
+                                app.write_all(&payload).await?;
+                            }
+                        }
+                        socket.send(&segment(Kind::Ack, 0, reliable.expected, Bytes::new())).await?;
+                        keepalive.on_activity();
+                    }
+                    Kind::Ack | Kind::Request | Kind::Reply => {}
+                }
+            }
+            read = app.read(&mut chunk), if !sent_eof && reliable.can_send() => {
+                let payload = Bytes::copy_from_slice(&chunk[..read?]);
+                sent_eof = payload.is_empty();
+                let now = Instant::now();
+                let seq = reliable.send(payload.clone(), now);
+                socket.send(&segment(Kind::Data, seq, reliable.expected, payload)).await?;
+                keepalive.on_activity();
+            }
+            _ = tick.tick() => {
+                let now = Instant::now();
+                if now.duration_since(last_heard) > DEAD_TIMEOUT {
+                    return Err(VpnError::Timeout("NAT-T peer went silent".to_string()));
+                }
+                for (seq, payload) in reliable.due(now)? {
+                    socket.send(&segment(Kind::Data, seq, reliable.expected, payload)).await?;
+                    keepalive.on_activity();
+                }
+                if keepalive.poll() {
+                    socket.send(&segment(Kind::Ack, KEEPALIVE_SEQ, reliable.expected, Bytes::new())).await?;
+                }
+                *keepalive_stats.lock().unwrap() = keepalive.stats();
+            }
+        }
+    }
+}
+
+/// Stream over `socket`, already connected to the peer, driven by a background task
+fn spawn_stream(socket: UdpSocket, session: u32, keepalive_stats: Arc<Mutex<NatKeepaliveStats>>) -> DuplexStream {
+    let (user, app) = tokio::io::duplex(STREAM_BUFFER);
+    tokio::spawn(async move {
+        if let Err(e) = run_stream(socket, session, app, keepalive_stats).await {
+            log::debug!(session = session; "NAT-T stream ended: {}", e);
+        }
+    });
+    user
+}
+
+/// Send punches to `peer` until one of its segments for `session` arrives, then lock onto it
+async fn punch(socket: &UdpSocket, peer: SocketAddr, session: u32, timeout: Duration) -> Result<()> {
+    let probe = Segment { kind: Kind::Punch, session, seq: 0, ack: 0, payload: Bytes::new() }.encode();
+    let mut buf = [0u8; 64];
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        socket.send_to(&probe, peer).await?;
+        let wait = tokio::time::timeout(RETRANSMIT_INTERVAL, async {
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await?;
+                if from == peer && Segment::decode(&buf[..len]).is_some_and(|s| s.session == session) {
+                    return Ok::<_, std::io::Error>(());
+                }
+            }
+        });
+        if let Ok(answered) = wait.await {
+            answered?;
+            socket.connect(peer).await?;
+            // Make sure the peer stops punching as well
+            socket.send(&probe).await?;
+            return Ok(());
+        }
+    }
+    Err(VpnError::Timeout(format!("UDP hole punching to {peer} timed out")))
+}
+
+/// A client's request to a NAT-T server for the endpoint of a VPN server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ConnectRequest {
+    pub tran_id: u32,
+    pub session: u32,
+    pub dest_host: String,
+    pub dest_port: u16,
+}
+
+impl ConnectRequest {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = self.dest_port.to_be_bytes().to_vec();
+        payload.extend_from_slice(self.dest_host.as_bytes());
+        let segment = Segment { kind: Kind::Request, session: self.session, seq: self.tran_id, ack: 0, payload: payload.into() };
+        segment.encode()
+    }
+}
+
+/// The NAT-T server's side of the exchange, for the mock servers in tests
+#[cfg(test)]
+impl ConnectRequest {
+    pub(crate) fn decode(datagram: &[u8]) -> Option<Self> {
+        let segment = Segment::decode(datagram).filter(|s| s.kind == Kind::Request && s.payload.len() > 2)?;
+        Some(Self {
+            tran_id: segment.seq,
+            session: segment.session,
+            dest_host: String::from_utf8(segment.payload[2..].to_vec()).ok()?,
+            dest_port: u16::from_be_bytes([segment.payload[0], segment.payload[1]]),
+        })
+    }
+
+    /// The NAT-T server's answer: where the VPN server waits, or why it cannot be reached
+    pub(crate) fn reply(&self, peer: std::result::Result<SocketAddr, &str>) -> Vec<u8> {
+        let (ack, payload) = match peer {
+            Ok(peer) => (1, peer.to_string()),
+            Err(reason) => (0, reason.to_string()),
+        };
+        Segment { kind: Kind::Reply, session: self.session, seq: self.tran_id, ack, payload: payload.into() }.encode()
+    }
+}
+
+/// Ask the NAT-T server at `server` where the VPN server `host:port` waits for us
+async fn rendezvous(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    host: &str,
+    port: u16,
+    session: u32,
+    timeout: Duration,
+) -> Result<SocketAddr> {
+    let tran_id: u32 = rand::random();
+    let request = ConnectRequest { tran_id, session, dest_host: host.to_string(), dest_port: port }.encode();
+
+    let mut buf = vec![0u8; 2048];
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        socket.send_to(&request, server).await?;
+        let Ok(received) = tokio::time::timeout(RETRANSMIT_INTERVAL, socket.recv_from(&mut buf)).await else {
+            continue;
+        };
+        let (len, from) = received?;
+        if from != server {
+            continue;
+        }
+        let Some(reply) = Segment::decode(&buf[..len]).filter(|s| s.kind == Kind::Reply && s.seq == tran_id) else {
+            continue;
+        };
+        let text = String::from_utf8_lossy(&reply.payload);
+        if reply.ack != 1 {
+            return Err(VpnError::Connection(format!("NAT-T server {server} refused: {text}")));
+        }
+        return text
+            .parse()
+            .map_err(|_| VpnError::Protocol(format!("NAT-T server {server} sent an invalid peer endpoint '{text}'")));
+    }
+    Err(VpnError::Timeout(format!("NAT-T server {server} did not answer")))
+}
+
+/// Opens streams to one VPN server through NAT-T
+#[derive(Debug, Clone)]
+pub struct NatTraversal {
+    servers: Vec<String>,
+    server_port: u16,
+    punch_timeout: Duration,
+    /// Name the VPN server is registered under
+    host: String,
+    port: u16,
+    underlay: Option<UnderlayBinding>,
+    socket_mark: Option<u32>,
+    /// NAT keepalive of the latest stream, shared by the clones
+    keepalive_stats: Arc<Mutex<NatKeepaliveStats>>,
+}
+
+impl NatTraversal {
+    /// Reach the VPN server registered as `host` (listening on `port`)
+    pub fn new(config: &NatTraversalConfig, host: &str, port: u16) -> Self {
+        Self {
+            servers: config.servers.clone(),
+            server_port: config.port,
+            punch_timeout: Duration::from_secs(u64::from(config.punch_timeout_secs)),
+            host: host.to_string(),
+            port,
+            underlay: None,
+            socket_mark: None,
+            keepalive_stats: Arc::default(),
+        }
+    }
+
+    /// Send the datagrams through the `underlay` uplink
+    pub fn with_underlay(mut self, underlay: Option<UnderlayBinding>) -> Self {
+        self.underlay = underlay;
+        self
+    }
+
+    /// Mark the datagrams with `mark` (`SO_MARK`, Linux) so policy routing keeps them off the tunnel
+    pub fn with_socket_mark(mut self, mark: Option<u32>) -> Self {
+        self.socket_mark = mark;
+        self
+    }
+
+    /// Open a stream to the server, trying the NAT-T servers in order
+    pub async fn connect(&self) -> Result<DuplexStream> {
+        let mut last_error = VpnError::Config("No NAT-T servers configured".to_string());
+        for server in &self.servers {
+            match self.connect_via(server).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    log::warn!(nat_t_server = server.as_str(); "NAT traversal failed: {}", e);
+                    last_error = e;
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn connect_via(&self, server: &str) -> Result<DuplexStream> {
+        let server = tokio::net::lookup_host((server, self.server_port))
+            .await
+            .map_err(|e| VpnError::Dns(format!("Cannot resolve NAT-T server {server}: {e}")))?
+            .find(SocketAddr::is_ipv4)
+            .ok_or_else(|| VpnError::Dns(format!("NAT-T server {server} has no IPv4 address")))?;
+        let socket = self.bind().await?;
+        let session: u32 = rand::random();
+        let peer = rendezvous(&socket, server, &self.host, self.port, session, self.punch_timeout).await?;
+        punch(&socket, peer, session, self.punch_timeout).await?;
+        log::info!(peer:% = peer, nat_t_server:% = server; "NAT-T stream to {} open", self.host);
+        Ok(spawn_stream(socket, session, self.keepalive_stats.clone()))
+    }
+
+    async fn bind(&self) -> Result<UdpSocket> {
+        let address = self.underlay.as_ref().map_or(Ipv4Addr::UNSPECIFIED, |u| u.address);
+        let socket = UdpSocket::bind((address, 0))
+            .await
+            .map_err(|e| VpnError::Network(format!("Failed to bind NAT-T socket: {e}")))?;
+        #[cfg(target_os = "linux")]
+        if let Some(ref underlay) = self.underlay {
+            if let Err(e) = socket.bind_device(Some(underlay.interface.as_bytes())) {
+                log::warn!("NAT-T socket not bound to {}: {}", underlay.interface, e);
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = self.socket_mark {
+            crate::underlay::set_socket_mark(&socket, mark)
+                .map_err(|e| VpnError::Network(format!("Failed to mark NAT-T socket: {e}")))?;
+        }
+        Ok(socket)
+    }
+}
+
+/// Unauthenticated HTTP proxy on loopback carrying every CONNECT over a NAT-T stream
+///
+/// Whatever the CONNECT names, the stream goes to the server [`NatTraversal`]
+/// was made for. Stops accepting connections when dropped.
+#[derive(Debug)]
+pub struct NatTraversalRelay {
+    addr: SocketAddr,
+    accept_task: JoinHandle<()>,
+    keepalive_stats: Arc<Mutex<NatKeepaliveStats>>,
+}
+
+impl NatTraversalRelay {
+    /// Listen on an ephemeral loopback port
+    pub async fn start(nat_traversal: NatTraversal) -> Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let keepalive_stats = nat_traversal.keepalive_stats.clone();
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(relay(stream, nat_traversal.clone()));
+            }
+        });
+        Ok(Self { addr, accept_task, keepalive_stats })
+    }
+
+    /// Proxy URL for the HTTP client and the data connections
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// NAT keepalive of the latest stream: interval in use and the NAT mapping timeout detected so far
+    pub fn nat_keepalive_stats(&self) -> NatKeepaliveStats {
+        self.keepalive_stats.lock().unwrap().clone()
+    }
+}
+
+impl Drop for NatTraversalRelay {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+/// Serve one CONNECT
+async fn relay(mut client: TcpStream, nat_traversal: NatTraversal) {
+    match read_head(&mut client).await {
+        Ok(head) if head.starts_with("CONNECT ") => {}
+        Ok(_) => {
+            let _ = client.write_all(b"HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n").await;
+            return;
+        }
+        Err(_) => return,
+    }
+    match nat_traversal.connect().await {
+        Ok(mut upstream) => {
+            if client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.is_ok() {
+                let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+            }
+        }
+        Err(_) => {
+            let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\n\r\n").await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::proxy::UpstreamProxy;
+
+    #[test]
+    fn test_segment_round_trip() {
+        let segment = Segment { kind: Kind::Data, session: 7, seq: 3, ack: 9, payload: Bytes::from_static(b"tls") };
+        let datagram = segment.encode();
+        assert_eq!(&datagram[..5], b"NATT\x01");
+        assert_eq!(Segment::decode(&datagram), Some(segment));
+        assert_eq!(Segment::decode(b"NATT\x09\0\0\0\x07\0\0\0\0\0\0\0\0"), None);
+        assert_eq!(Segment::decode(b"short"), None);
+    }
+
+    #[test]
+    fn test_reliable_reorders_and_retransmits() {
+        let start = Instant::now();
+        let mut sender = Reliable::default();
+        let mut receiver = Reliable::default();
+        let segments: Vec<(u32, Bytes)> = ["a", "b", "c"]
+            .iter()
+            .map(|data| (sender.send(Bytes::from(*data), start), Bytes::from(*data)))
+            .collect();
+
+        // "b" is lost, "c" arrives early and waits for it
+        assert_eq!(receiver.on_data(segments[0].0, segments[0].1.clone()), vec![Bytes::from("a")]);
+        assert!(receiver.on_data(segments[2].0, segments[2].1.clone()).is_empty());
+        sender.on_ack(receiver.expected);
+        assert_eq!(sender.unacked.len(), 2);
+
+        assert!(sender.due(start + INITIAL_RTO / 2).unwrap().is_empty());
+        let resent = sender.due(start + INITIAL_RTO).unwrap();
+        assert_eq!(resent.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(receiver.on_data(resent[0].0, resent[0].1.clone()), vec![Bytes::from("b"), Bytes::from("c")]);
+        // A duplicate of something delivered is dropped
+        assert!(receiver.on_data(resent[1].0, resent[1].1.clone()).is_empty());
+        sender.on_ack(receiver.expected);
+        assert!(sender.all_acked());
+    }
+
+    #[test]
+    fn test_reliable_gives_up() {
+        let mut now = Instant::now();
+        let mut sender = Reliable::default();
+        sender.send(Bytes::from_static(b"lost"), now);
+        for _ in 0..MAX_RETRIES {
+            now += MAX_RTO;
+            assert_eq!(sender.due(now).unwrap().len(), 1);
+        }
+        assert!(sender.due(now + MAX_RTO).is_err());
+    }
+
+    /// NAT-T server introducing clients to an echoing VPN server
+    async fn nat_t_setup() -> (NatTraversalConfig, JoinHandle<ConnectRequest>) {
+        let nat_t = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let vpn = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let vpn_addr = vpn.local_addr().unwrap();
+        let config = NatTraversalConfig {
+            servers: vec!["localhost".to_string()],
+            port: nat_t.local_addr().unwrap().port(),
+            punch_timeout_secs: 5,
+        };
+        let task = tokio::spawn(async move {
+            let mut buf = vec![0u8; 2048];
+            let (len, client) = nat_t.recv_from(&mut buf).await.unwrap();
+            let request = ConnectRequest::decode(&buf[..len]).unwrap();
+            nat_t.send_to(&request.reply(Ok(vpn_addr)), client).await.unwrap();
+
+            // The VPN server side: answer the punch, then echo the stream
+            let session = request.session;
+            let (_, client) = vpn.recv_from(&mut buf).await.unwrap();
+            vpn.connect(client).await.unwrap();
+            let probe = Segment { kind: Kind::Punch, session, seq: 0, ack: 0, payload: Bytes::new() };
+            vpn.send(&probe.encode()).await.unwrap();
+            let (mut reader, mut writer) = tokio::io::split(spawn_stream(vpn, session, Arc::default()));
+            tokio::spawn(async move { tokio::io::copy(&mut reader, &mut writer).await });
+            request
+        });
+        (config, task)
+    }
+
+    #[tokio::test]
+    async fn test_stream_through_relay() {
+        let (config, nat_t) = nat_t_setup().await;
+        let relay = NatTraversalRelay::start(NatTraversal::new(&config, "vpn.example.com", 443)).await.unwrap();
+        let proxy = UpstreamProxy::from_url(&relay.url(), None).unwrap();
+        let mut stream = proxy.connect("203.0.113.10:443").await.unwrap();
+
+        let message = vec![0x5a; 3 * MAX_PAYLOAD + 17];
+        stream.write_all(&message).await.unwrap();
+        let mut echoed = vec![0u8; message.len()];
+        stream.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message);
+        // The stream probes the NAT between the shortest and the longest keepalive interval
+        let interval = relay.nat_keepalive_stats().current_interval;
+        assert!(interval > KEEPALIVE_INTERVAL && interval < MAX_KEEPALIVE_INTERVAL, "{interval:?}");
+
+        let request = nat_t.await.unwrap();
+        assert_eq!(request.dest_host, "vpn.example.com");
+        assert_eq!(request.dest_port, 443);
+    }
+}
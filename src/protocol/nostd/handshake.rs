@@ -0,0 +1,343 @@
+//! Login handshake as a sans-IO state machine
+//!
+//! Same exchange as [`AuthClient`](crate::protocol::AuthClient):
+//!
+//! 1. `VPNCONNECT` to `/vpnsvc/connect.cgi`; the reply is the server hello,
+//!    whose `random` element is the challenge for certificate logins
+//! 2. The login PACK to the same path; the reply is the welcome PACK with the
+//!    session name and key, or a non-zero `error`
+//!
+//! The GIF watermark fallback of the std client is not implemented.
+
+use super::pack::Pack;
+use super::{AsyncTransport, Crypto, Error, Transport};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Path of both handshake requests
+pub const CONNECT_PATH: &str = "/vpnsvc/connect.cgi";
+
+/// `authtype` values of the login PACK
+const AUTHTYPE_ANONYMOUS: u32 = 0;
+const AUTHTYPE_PLAIN_PASSWORD: u32 = 2;
+const AUTHTYPE_CERT: u32 = 3;
+
+/// One HTTP POST of the handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+/// How the login PACK proves the user's identity
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Password checked against the hub's user database
+    Password(String),
+    /// Password forwarded for RADIUS or NT domain checks
+    External(String),
+    /// Username only
+    Anonymous,
+    /// Certificate and challenge signature from the [`Crypto`] implementation
+    Certificate,
+}
+
+impl core::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Credentials::Password(_) => "Password(..)",
+            Credentials::External(_) => "External(..)",
+            Credentials::Anonymous => "Anonymous",
+            Credentials::Certificate => "Certificate",
+        })
+    }
+}
+
+/// Who logs in where, and what the client reports about itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Login {
+    pub hub: String,
+    pub username: String,
+    pub credentials: Credentials,
+    /// `client_str`, `client_ver` and `client_build` of the login PACK
+    pub client_str: String,
+    pub client_ver: u32,
+    pub client_build: u32,
+}
+
+/// Session granted by the welcome PACK
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub session_name: String,
+    pub connection_name: String,
+    pub session_key: Vec<u8>,
+    pub max_connection: u32,
+    pub use_encrypt: bool,
+    pub use_compress: bool,
+    /// Session timeout in milliseconds, 0 if the server sent none
+    pub timeout_ms: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    AwaitingHello,
+    AwaitingWelcome,
+    Done,
+}
+
+/// Handshake steps without I/O
+///
+/// Call [`hello_request`](Self::hello_request), post it, hand the reply to
+/// [`login_request`](Self::login_request), post that and hand its reply to
+/// [`finish`](Self::finish).
+#[derive(Debug)]
+pub struct Handshake {
+    login: Login,
+    state: State,
+}
+
+impl Handshake {
+    pub fn new(login: Login) -> Self {
+        Self { login, state: State::Start }
+    }
+
+    /// First request, announcing a VPN client
+    pub fn hello_request(&mut self) -> Result<Request, Error> {
+        self.advance(State::Start, State::AwaitingHello)?;
+        Ok(Request { content_type: "application/x-www-form-urlencoded", body: b"VPNCONNECT".to_vec() })
+    }
+
+    /// Login request answering the server `hello`
+    pub fn login_request(&mut self, hello: &[u8], crypto: &mut dyn Crypto) -> Result<Request, Error> {
+        self.advance(State::AwaitingHello, State::AwaitingWelcome)?;
+        // Servers without a challenge may answer with something other than a PACK
+        let challenge = Pack::decode(hello)
+            .ok()
+            .and_then(|hello| hello.get_data("random").filter(|random| !random.is_empty()).map(<[u8]>::to_vec));
+        let body = self.login_pack(challenge.as_deref(), crypto)?.encode()?;
+        Ok(Request { content_type: "application/octet-stream", body })
+    }
+
+    /// Session from the server's answer to the login request
+    pub fn finish(&mut self, welcome: &[u8]) -> Result<Session, Error> {
+        self.advance(State::AwaitingWelcome, State::Done)?;
+        let welcome = Pack::decode(welcome)?;
+        match welcome.get_int("error") {
+            Some(0) | None => {}
+            Some(code) => return Err(Error::Refused(code)),
+        }
+        let session_name = welcome.get_str("session_name").ok_or(Error::Malformed("welcome without session_name"))?;
+        Ok(Session {
+            session_name: String::from(session_name),
+            connection_name: String::from(welcome.get_str("connection_name").unwrap_or_default()),
+            session_key: welcome.get_data("session_key").unwrap_or_default().to_vec(),
+            max_connection: welcome.get_int("max_connection").unwrap_or(1),
+            use_encrypt: welcome.get_int("use_encrypt").unwrap_or(1) != 0,
+            use_compress: welcome.get_int("use_compress").unwrap_or(0) != 0,
+            timeout_ms: welcome.get_int("timeout").unwrap_or(0),
+        })
+    }
+
+    /// Login PACK, element for element what the std client sends
+    pub fn login_pack(&self, challenge: Option<&[u8]>, crypto: &mut dyn Crypto) -> Result<Pack, Error> {
+        let login = &self.login;
+        let mut pack = Pack::new();
+        pack.add_str("method", "login");
+        pack.add_str("username", &login.username);
+        match login.credentials {
+            Credentials::Password(ref password) => pack.add_str("password", password),
+            Credentials::External(ref password) => {
+                pack.add_int("authtype", AUTHTYPE_PLAIN_PASSWORD);
+                pack.add_str("plain_password", password);
+            }
+            Credentials::Anonymous => pack.add_int("authtype", AUTHTYPE_ANONYMOUS),
+            Credentials::Certificate => {
+                let challenge = challenge.ok_or(Error::Malformed("hello without challenge"))?;
+                let certificate = crypto.certificate().ok_or(Error::Unsupported("no client certificate"))?.to_vec();
+                pack.add_int("authtype", AUTHTYPE_CERT);
+                pack.add_data("cert", certificate);
+                pack.add_data("sign", crypto.sign_challenge(challenge)?);
+            }
+        }
+        pack.add_str("hub", &login.hub);
+        pack.add_str("client_str", &login.client_str);
+        pack.add_int("client_ver", login.client_ver);
+        pack.add_int("client_build", login.client_build);
+        pack.add_str("cluster_member_cert", "");
+        pack.add_int("use_encrypt", 1);
+        pack.add_int("use_compress", 1);
+        Ok(pack)
+    }
+
+    fn advance(&mut self, from: State, to: State) -> Result<(), Error> {
+        if self.state != from {
+            return Err(Error::OutOfOrder);
+        }
+        self.state = to;
+        Ok(())
+    }
+}
+
+/// Run the whole handshake over `transport`
+pub fn connect(transport: &mut dyn Transport, crypto: &mut dyn Crypto, login: Login) -> Result<Session, Error> {
+    let mut handshake = Handshake::new(login);
+    let hello = transport.post(CONNECT_PATH, &handshake.hello_request()?)?;
+    let welcome = transport.post(CONNECT_PATH, &handshake.login_request(&hello, crypto)?)?;
+    handshake.finish(&welcome)
+}
+
+/// [`connect`] over a transport that cannot block
+pub async fn connect_async<T: AsyncTransport>(
+    transport: &mut T,
+    crypto: &mut dyn Crypto,
+    login: Login,
+) -> Result<Session, Error> {
+    let mut handshake = Handshake::new(login);
+    let hello = transport.post(CONNECT_PATH, &handshake.hello_request()?).await?;
+    let welcome = transport.post(CONNECT_PATH, &handshake.login_request(&hello, crypto)?).await?;
+    handshake.finish(&welcome)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::nostd::NoCrypto;
+    #[cfg(feature = "native")]
+    use crate::protocol::AuthClient;
+    use alloc::collections::VecDeque;
+
+    const LOGIN_REQUEST: &[u8] =
+        include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pack/v1/login_request.bin"));
+    const WELCOME: &[u8] =
+        include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pack/v1/welcome_response.bin"));
+
+    /// Answers from a queue and records every request
+    #[derive(Default)]
+    struct Scripted {
+        replies: VecDeque<Vec<u8>>,
+        requests: Vec<Request>,
+    }
+
+    impl Transport for Scripted {
+        fn post(&mut self, path: &str, request: &Request) -> Result<Vec<u8>, Error> {
+            assert_eq!(path, CONNECT_PATH);
+            self.requests.push(request.clone());
+            self.replies.pop_front().ok_or_else(|| Error::Transport(String::from("connection closed")))
+        }
+    }
+
+    impl AsyncTransport for Scripted {
+        async fn post(&mut self, path: &str, request: &Request) -> Result<Vec<u8>, Error> {
+            Transport::post(self, path, request)
+        }
+    }
+
+    struct FixedSigner;
+
+    impl Crypto for FixedSigner {
+        fn certificate(&self) -> Option<&[u8]> {
+            Some(b"cert")
+        }
+
+        fn sign_challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error> {
+            Ok(challenge.iter().rev().copied().collect())
+        }
+    }
+
+    fn login(credentials: Credentials) -> Login {
+        Login {
+            hub: String::from("VPN"),
+            username: String::from("alice"),
+            credentials,
+            client_str: String::from("SE-VPN Client"),
+            client_ver: 4560,
+            client_build: 9686,
+        }
+    }
+
+    #[test]
+    fn test_password_login_matches_std_client() {
+        let mut transport = Scripted { replies: [Vec::new(), WELCOME.to_vec()].into(), ..Default::default() };
+        let session = connect(&mut transport, &mut NoCrypto, login(Credentials::Password(String::from("secret")))).unwrap();
+
+        assert_eq!(transport.requests[0].body, b"VPNCONNECT");
+        assert_eq!(transport.requests[1].body, LOGIN_REQUEST);
+        #[cfg(feature = "native")]
+        {
+            let mut std_client =
+                AuthClient::new("127.0.0.1:443".into(), None, "VPN".into(), "alice".into(), "secret".into(), false)
+                    .unwrap();
+            // The core always asks for compression
+            std_client.set_compression(true);
+            assert_eq!(transport.requests[1].body, std_client.login_pack().unwrap().to_bytes().unwrap().to_vec());
+        }
+
+        assert_eq!(session.session_name, "SID-ALICE-1");
+        assert_eq!(session.connection_name, "CID-42");
+        assert_eq!(session.session_key.len(), 20);
+        assert_eq!((session.use_encrypt, session.use_compress, session.timeout_ms), (true, false, 20000));
+    }
+
+    #[test]
+    fn test_async_transport_runs_same_exchange() {
+        let mut transport = Scripted { replies: [Vec::new(), WELCOME.to_vec()].into(), ..Default::default() };
+        let credentials = Credentials::Password(String::from("secret"));
+        let session = futures::executor::block_on(connect_async(&mut transport, &mut NoCrypto, login(credentials)));
+
+        assert_eq!(session.unwrap().session_name, "SID-ALICE-1");
+        assert_eq!(transport.requests[1].body, LOGIN_REQUEST);
+
+        // A transport failure ends the handshake
+        let mut transport = Scripted::default();
+        let login = login(Credentials::Anonymous);
+        let err = futures::executor::block_on(connect_async(&mut transport, &mut NoCrypto, login));
+        assert_eq!(err, Err(Error::Transport(String::from("connection closed"))));
+    }
+
+    #[test]
+    fn test_certificate_login_signs_challenge() {
+        let hello = server_pack("random", 1, &[1, 2, 3]);
+        let mut handshake = Handshake::new(login(Credentials::Certificate));
+        handshake.hello_request().unwrap();
+
+        let request = handshake.login_request(&hello, &mut FixedSigner).unwrap();
+        let expected = handshake.login_pack(Some(&[1, 2, 3]), &mut FixedSigner).unwrap();
+        assert_eq!(request.body, expected.encode().unwrap());
+        assert_eq!(expected.get_int("authtype"), Some(AUTHTYPE_CERT));
+        assert_eq!(expected.get_data("cert"), Some(&b"cert"[..]));
+        assert_eq!(expected.get_data("sign"), Some(&[3, 2, 1][..]));
+
+        // Without a certificate the login cannot be built
+        let mut handshake = Handshake::new(login(Credentials::Certificate));
+        handshake.hello_request().unwrap();
+        let err = handshake.login_request(&hello, &mut NoCrypto).unwrap_err();
+        assert_eq!(err, Error::Unsupported("no client certificate"));
+    }
+
+    #[test]
+    fn test_refusal_and_step_order() {
+        let mut handshake = Handshake::new(login(Credentials::Anonymous));
+        assert_eq!(handshake.finish(WELCOME), Err(Error::OutOfOrder));
+        handshake.hello_request().unwrap();
+        assert_eq!(handshake.hello_request(), Err(Error::OutOfOrder));
+        // A hello that is not a PACK is fine without certificate authentication
+        handshake.login_request(b"", &mut NoCrypto).unwrap();
+
+        assert_eq!(handshake.finish(&server_pack("error", 0, &9u32.to_be_bytes())), Err(Error::Refused(9)));
+    }
+
+    /// One-element PACK in the server layout
+    fn server_pack(name: &str, type_id: u32, value: &[u8]) -> Vec<u8> {
+        let mut bytes = alloc::vec![0, 0, 0, 1];
+        bytes.extend((name.len() as u32 + 1).to_be_bytes());
+        bytes.extend(name.as_bytes());
+        bytes.push(0);
+        bytes.resize(bytes.len().next_multiple_of(4) + 1, 0);
+        bytes.extend(type_id.to_be_bytes());
+        bytes.extend(1u32.to_be_bytes());
+        bytes.extend((value.len() as u32).to_be_bytes());
+        bytes.extend(value);
+        bytes
+    }
+}
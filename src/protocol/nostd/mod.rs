@@ -0,0 +1,113 @@
+//! `no_std + alloc` protocol core (exploration, feature `no_std_core`)
+//!
+//! The PACK codec and the login handshake, written against `core` and
+//! `alloc` only, for embedded gateways (ESP32-class boards on FreeRTOS or
+//! ESP-IDF) that cannot carry tokio, reqwest and rustls. The platform plugs
+//! in through two traits:
+//!
+//! - [`Transport`] posts a request body to the server over HTTPS and returns
+//!   the response body (`esp_http_client`, or any TLS stack plus a minimal
+//!   HTTP/1.1 writer)
+//! - [`Crypto`] holds the client certificate and signs the server challenge,
+//!   and is only needed for certificate logins
+//!
+//! [`handshake::connect`] drives the whole exchange; [`Handshake`] exposes
+//! the same steps without doing any I/O, for event-loop firmware.
+//! [`AsyncTransport`] and [`handshake::connect_async`] are the same for
+//! hosts that cannot block, such as browsers relaying the exchange through
+//! `fetch` or a WebSocket proxy (see `docs/wasm.md`).
+//!
+//! Nothing in here may use `std`, or anything else in this crate: the
+//! module is meant to move into its own `#![no_std]` crate once the API
+//! settles. The lints below reject `std::` paths; the login PACK is checked
+//! against the same wire fixtures as the std client.
+
+#![deny(clippy::std_instead_of_core, clippy::std_instead_of_alloc)]
+
+pub mod handshake;
+pub mod pack;
+
+pub use handshake::{connect, connect_async, Credentials, Handshake, Login, Request, Session};
+pub use pack::{Pack, Value};
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::Future;
+
+/// Errors of the protocol core
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The server sent bytes that are not a valid PACK or login response
+    Malformed(&'static str),
+    /// A PACK built by the client does not fit the wire format
+    Encode(&'static str),
+    /// The server refused the login with this SoftEther error code
+    Refused(u32),
+    /// A handshake step was called out of order
+    OutOfOrder,
+    /// The platform cannot do what the login needs (e.g. no certificate)
+    Unsupported(&'static str),
+    /// The transport failed, with the platform's description
+    Transport(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Malformed(what) => write!(f, "malformed server response: {what}"),
+            Error::Encode(what) => write!(f, "cannot encode PACK: {what}"),
+            Error::Refused(code) => write!(f, "login refused by server (error {code})"),
+            Error::OutOfOrder => f.write_str("handshake step called out of order"),
+            Error::Unsupported(what) => write!(f, "unsupported: {what}"),
+            Error::Transport(message) => write!(f, "transport failed: {message}"),
+        }
+    }
+}
+
+impl core::error::Error for Error {}
+
+/// HTTPS request/response exchange with the VPN server
+pub trait Transport {
+    /// POST `request` to `path` and return the response body
+    ///
+    /// Non-2xx responses are errors.
+    fn post(&mut self, path: &str, request: &Request) -> Result<Vec<u8>, Error>;
+}
+
+/// [`Transport`] whose requests complete asynchronously
+///
+/// For wasm32 builds, where a request is a `fetch` or a frame to a WebSocket
+/// proxy that forwards it to the server and must not block the event loop.
+pub trait AsyncTransport {
+    /// POST `request` to `path` and resolve to the response body
+    ///
+    /// Non-2xx responses are errors.
+    fn post(&mut self, path: &str, request: &Request) -> impl Future<Output = Result<Vec<u8>, Error>>;
+}
+
+/// Client certificate operations for certificate logins
+///
+/// Password, external and anonymous logins never call into it; [`NoCrypto`]
+/// serves them.
+pub trait Crypto {
+    /// DER encoding of the client certificate
+    fn certificate(&self) -> Option<&[u8]>;
+
+    /// RSASSA-PKCS1-v1_5 signature with SHA-256 over the server challenge
+    fn sign_challenge(&mut self, challenge: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// [`Crypto`] for logins without a client certificate
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCrypto;
+
+impl Crypto for NoCrypto {
+    fn certificate(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn sign_challenge(&mut self, _challenge: &[u8]) -> Result<Vec<u8>, Error> {
+        Err(Error::Unsupported("no client certificate"))
+    }
+}
@@ -0,0 +1,314 @@
+//! PACK codec without `std`
+//!
+//! Writes the client layout and reads the server layout described in
+//! `tests/fixtures/pack/README.md`, with the limits of the std codec.
+//! Unlike the std reader it does not guess: a PACK that does not follow the
+//! server layout is an error, except that an element with an out-of-range
+//! type ends the PACK, as SoftEther appends binary session data that way.
+
+#![deny(clippy::arithmetic_side_effects)]
+
+use super::Error;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Maximum number of elements accepted in a single PACK
+pub const MAX_PACK_ELEMENTS: u32 = 10_000;
+
+/// Maximum element name length on the wire (including null terminator)
+pub const MAX_ELEMENT_NAME_LEN: u32 = 1_000;
+
+/// Maximum number of values in a single element
+pub const MAX_ELEMENT_VALUES: u32 = 65_536;
+
+/// Maximum size of a single value (10MB)
+pub const MAX_VALUE_LEN: u32 = 10_000_000;
+
+/// Element types above this mark binary session data rather than an element
+const BINARY_DATA_TYPE: u32 = 10_000;
+
+/// PACK value variants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(u32),
+    Int64(u64),
+    Data(Vec<u8>),
+    Str(String),
+    /// UTF-16LE on the wire
+    UniStr(String),
+}
+
+impl Value {
+    fn type_id(&self) -> u32 {
+        match self {
+            Value::Int(_) => 0,
+            Value::Data(_) => 1,
+            Value::Str(_) => 2,
+            Value::UniStr(_) => 3,
+            Value::Int64(_) => 4,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Value::Int64(v) => out.extend_from_slice(&v.to_be_bytes()),
+            Value::Data(v) => out.extend_from_slice(v),
+            Value::Str(v) => out.extend_from_slice(v.as_bytes()),
+            Value::UniStr(v) => v.encode_utf16().for_each(|unit| out.extend_from_slice(&unit.to_le_bytes())),
+        }
+    }
+
+    fn wire_len(&self) -> usize {
+        match self {
+            Value::Int(_) => 4,
+            Value::Int64(_) => 8,
+            Value::Data(v) => v.len(),
+            Value::Str(v) => v.len(),
+            Value::UniStr(v) => v.encode_utf16().count().saturating_mul(2),
+        }
+    }
+
+    fn read(type_id: u32, bytes: &[u8]) -> Result<Self, Error> {
+        Ok(match type_id {
+            0 => Value::Int(u32::from_be_bytes(bytes.try_into().map_err(|_| Error::Malformed("int value length"))?)),
+            4 => Value::Int64(u64::from_be_bytes(bytes.try_into().map_err(|_| Error::Malformed("int64 value length"))?)),
+            2 => Value::Str(String::from_utf8(bytes.to_vec()).map_err(|_| Error::Malformed("string is not UTF-8"))?),
+            3 => {
+                if !bytes.len().is_multiple_of(2) {
+                    return Err(Error::Malformed("unistr value length"));
+                }
+                let units = bytes.chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+                let value: Result<String, _> = char::decode_utf16(units).collect();
+                Value::UniStr(value.map_err(|_| Error::Malformed("unistr is not UTF-16"))?)
+            }
+            // Like the std codec, unknown small types are kept as data
+            _ => Value::Data(bytes.to_vec()),
+        })
+    }
+}
+
+/// Named values in wire order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Pack {
+    elements: Vec<(String, Vec<Value>)>,
+}
+
+impl Pack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append an element with the given values (all of one type)
+    pub fn add(&mut self, name: &str, values: Vec<Value>) {
+        self.elements.push((String::from(name), values));
+    }
+
+    pub fn add_int(&mut self, name: &str, value: u32) {
+        self.add(name, alloc::vec![Value::Int(value)]);
+    }
+
+    pub fn add_str(&mut self, name: &str, value: &str) {
+        self.add(name, alloc::vec![Value::Str(String::from(value))]);
+    }
+
+    pub fn add_data(&mut self, name: &str, value: Vec<u8>) {
+        self.add(name, alloc::vec![Value::Data(value)]);
+    }
+
+    /// Elements as (name, values), in wire order
+    pub fn elements(&self) -> impl Iterator<Item = (&str, &[Value])> {
+        self.elements.iter().map(|(name, values)| (name.as_str(), values.as_slice()))
+    }
+
+    /// Values of the first element called `name`
+    pub fn get(&self, name: &str) -> Option<&[Value]> {
+        self.elements().find(|(element, _)| *element == name).map(|(_, values)| values)
+    }
+
+    pub fn get_int(&self, name: &str) -> Option<u32> {
+        match self.get(name)?.first()? {
+            Value::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        match self.get(name)?.first()? {
+            Value::Str(v) | Value::UniStr(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn get_data(&self, name: &str) -> Option<&[u8]> {
+        match self.get(name)?.first()? {
+            Value::Data(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Client layout: no padding between or inside elements
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        put_u32(&mut out, self.elements.len(), "element count")?;
+        for (name, values) in &self.elements {
+            let type_id = values.first().ok_or(Error::Encode("element without values"))?.type_id();
+            if values.iter().any(|value| value.type_id() != type_id) {
+                return Err(Error::Encode("element mixes value types"));
+            }
+            let name_len = name.len().checked_add(1).filter(|&len| len <= MAX_ELEMENT_NAME_LEN as usize);
+            put_u32(&mut out, name_len.ok_or(Error::Encode("element name too long"))?, "element name")?;
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            out.extend_from_slice(&type_id.to_be_bytes());
+            put_u32(&mut out, values.len(), "value count")?;
+            for value in values {
+                put_u32(&mut out, value.wire_len(), "value")?;
+                value.write(&mut out);
+            }
+        }
+        Ok(out)
+    }
+
+    /// Server layout, see the module documentation
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut reader = Reader { bytes };
+        let count = reader.u32()?;
+        if count > MAX_PACK_ELEMENTS {
+            return Err(Error::Malformed("too many elements"));
+        }
+
+        let mut pack = Pack::new();
+        for index in 0..count {
+            if index > 0 {
+                reader.take(3)?;
+            }
+            let name_len = reader.u32()?;
+            if name_len == 0 || name_len > MAX_ELEMENT_NAME_LEN {
+                return Err(Error::Malformed("element name length"));
+            }
+            let name = reader.padded(name_len)?;
+            let name = name.split_last().map_or(name, |(_, name)| name);
+            let name = core::str::from_utf8(name).map_err(|_| Error::Malformed("element name is not UTF-8"))?;
+            reader.take(1)?;
+
+            let type_id = reader.u32()?;
+            if type_id > BINARY_DATA_TYPE {
+                break;
+            }
+            let value_count = reader.u32()?;
+            if value_count > MAX_ELEMENT_VALUES {
+                return Err(Error::Malformed("too many values"));
+            }
+            let mut values = Vec::new();
+            for _ in 0..value_count {
+                let len = reader.u32()?;
+                if len > MAX_VALUE_LEN {
+                    return Err(Error::Malformed("value too long"));
+                }
+                values.push(Value::read(type_id, reader.padded(len)?)?);
+            }
+            pack.add(name, values);
+        }
+        Ok(pack)
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, len: usize, what: &'static str) -> Result<(), Error> {
+    let len = u32::try_from(len).map_err(|_| Error::Encode(what))?;
+    out.extend_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+/// Cursor over the server's bytes
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.bytes.len() < len {
+            return Err(Error::Malformed("truncated PACK"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// `len` bytes followed by zero padding to the next 4-byte boundary
+    ///
+    /// The padding may be cut short at the end of the PACK.
+    fn padded(&mut self, len: u32) -> Result<&'a [u8], Error> {
+        let len = usize::try_from(len).map_err(|_| Error::Malformed("length does not fit in memory"))?;
+        let value = self.take(len)?;
+        let padding = len.wrapping_neg() & 3;
+        self.take(padding.min(self.bytes.len()))?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::pack as std_pack;
+
+    const WELCOME: &[u8] =
+        include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/pack/v1/welcome_response.bin"));
+
+    #[test]
+    fn test_limits_match_std_codec() {
+        assert_eq!(MAX_PACK_ELEMENTS, std_pack::MAX_PACK_ELEMENTS);
+        assert_eq!(MAX_ELEMENT_NAME_LEN, std_pack::MAX_ELEMENT_NAME_LEN);
+        assert_eq!(MAX_ELEMENT_VALUES, std_pack::MAX_ELEMENT_VALUES);
+        assert_eq!(MAX_VALUE_LEN, std_pack::MAX_VALUE_LEN);
+    }
+
+    #[test]
+    fn test_decodes_welcome_fixture() {
+        let pack = Pack::decode(WELCOME).unwrap();
+        let names: Vec<&str> = pack.elements().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            [
+                "error", "session_name", "connection_name", "max_connection", "use_encrypt", "use_compress",
+                "half_connection", "timeout", "qos", "session_key", "DnsServers",
+            ]
+        );
+        assert_eq!(pack.get_int("error"), Some(0));
+        assert_eq!(pack.get_str("session_name"), Some("SID-ALICE-1"));
+        assert_eq!(pack.get_int("timeout"), Some(20000));
+        assert_eq!(pack.get_data("session_key"), Some(&(0..20).collect::<Vec<u8>>()[..]));
+        assert_eq!(pack.get("DnsServers"), Some(&[Value::Int(0x0A15_0001), Value::Int(0x0808_0808)][..]));
+
+        // Truncated anywhere: an error, never a panic
+        for len in 0..WELCOME.len() - 1 {
+            assert!(Pack::decode(&WELCOME[..len]).is_err(), "accepted {len} bytes");
+        }
+    }
+
+    #[test]
+    fn test_encodes_like_std_codec() {
+        let mut pack = Pack::new();
+        pack.add_str("method", "login");
+        pack.add("ids", alloc::vec![Value::Int(1), Value::Int(2)]);
+        pack.add("stamp", alloc::vec![Value::Int64(1_700_000_000)]);
+        pack.add("name", alloc::vec![Value::UniStr(String::from("hé"))]);
+        pack.add_data("blob", alloc::vec![1, 2, 3]);
+
+        let mut expected = std_pack::Pack::new();
+        expected.add_str("method", "login");
+        expected.add_int_array("ids", alloc::vec![1, 2]);
+        expected.add_int64("stamp", 1_700_000_000);
+        expected.add_unistr("name", "hé");
+        expected.add_data("blob", alloc::vec![1, 2, 3]);
+        assert_eq!(pack.encode().unwrap(), expected.to_bytes().unwrap().to_vec());
+
+        pack.add("mixed", alloc::vec![Value::Int(1), Value::Str(String::new())]);
+        assert_eq!(pack.encode(), Err(Error::Encode("element mixes value types")));
+    }
+}
@@ -0,0 +1,148 @@
+//! Second factor (one-time password) at login
+//!
+//! A hub that wants a second factor does not answer the login with a
+//! result but with a challenge: `otp_required` set, an optional prompt in
+//! `otp_message` and an `otp_ticket` naming the pending login. The client
+//! answers with the code on the same endpoint:
+//!
+//! ```text
+//! server -> client   otp_required = 1, otp_message, otp_ticket
+//! client -> server   method = "otp", hubname, username, otp_ticket, otp
+//! server -> client   the login response, or a new challenge after a wrong code
+//! ```
+//!
+//! The code comes from the application's [`OtpProvider`], usually a prompt
+//! or an authenticator integration. It is asked at most
+//! [`MAX_OTP_ATTEMPTS`] times per login and must answer within the
+//! configured timeout.
+
+use super::pack::Pack;
+use crate::error::{Result, VpnError};
+use futures::future::BoxFuture;
+use std::future::Future;
+use std::time::Duration;
+
+/// Codes asked for in one login before giving up
+pub const MAX_OTP_ATTEMPTS: u32 = 3;
+
+/// What the application is asked a one-time password for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OtpChallenge {
+    pub username: String,
+    pub hub: String,
+    /// Prompt from the server, if it sent one
+    pub message: Option<String>,
+    /// 1 for the first code of a login, higher after a rejected one
+    pub attempt: u32,
+}
+
+/// Source of one-time passwords
+///
+/// `None` declines, which cancels the login. Closures taking an
+/// [`OtpChallenge`] and returning a future are providers too.
+pub trait OtpProvider: Send + Sync {
+    fn one_time_password(&self, challenge: OtpChallenge) -> BoxFuture<'static, Option<String>>;
+}
+
+impl<F, Fut> OtpProvider for F
+where
+    F: Fn(OtpChallenge) -> Fut + Send + Sync,
+    Fut: Future<Output = Option<String>> + Send + 'static,
+{
+    fn one_time_password(&self, challenge: OtpChallenge) -> BoxFuture<'static, Option<String>> {
+        Box::pin(self(challenge))
+    }
+}
+
+/// Challenge found in a login response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PendingOtp {
+    pub ticket: Vec<u8>,
+    pub message: Option<String>,
+}
+
+/// The challenge in `response`, or `None` when it is a regular login response
+pub(crate) fn pending_otp(response: &Pack) -> Option<PendingOtp> {
+    if response.get_int("otp_required").unwrap_or(0) == 0 {
+        return None;
+    }
+    Some(PendingOtp {
+        ticket: response.get_data("otp_ticket").cloned().unwrap_or_default(),
+        message: response.get_str("otp_message").filter(|message| !message.is_empty()).cloned(),
+    })
+}
+
+/// Request answering `pending` with `code`
+pub(crate) fn answer_pack(hub: &str, username: &str, pending: &PendingOtp, code: &str) -> Pack {
+    let mut pack = Pack::new();
+    pack.add_str("method", "otp");
+    pack.add_str("hubname", hub);
+    pack.add_str("username", username);
+    pack.add_data("otp_ticket", pending.ticket.clone());
+    pack.add_str("otp", code);
+    pack
+}
+
+/// Ask `provider` for a code, waiting at most `timeout`
+///
+/// Declining, or answering with nothing but whitespace, cancels.
+pub(crate) async fn ask(provider: &dyn OtpProvider, challenge: OtpChallenge, timeout: Duration) -> Result<String> {
+    let attempt = challenge.attempt;
+    match tokio::time::timeout(timeout, provider.one_time_password(challenge)).await {
+        Err(_) => Err(VpnError::Timeout(format!(
+            "No one-time password within {}s (attempt {attempt})",
+            timeout.as_secs()
+        ))),
+        Ok(code) => match code.as_deref().map(str::trim) {
+            Some(code) if !code.is_empty() => Ok(code.to_string()),
+            _ => Err(VpnError::Cancelled("One-time password entry declined".into())),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn challenge() -> OtpChallenge {
+        OtpChallenge { username: "alice".into(), hub: "VPN".into(), message: None, attempt: 1 }
+    }
+
+    #[test]
+    fn test_pending_otp() {
+        let mut login = Pack::new();
+        login.add_int("error", 0);
+        assert_eq!(pending_otp(&login), None);
+
+        let mut response = Pack::new();
+        response.add_int("otp_required", 1);
+        response.add_str("otp_message", "Enter the code from your token");
+        response.add_data("otp_ticket", vec![7; 16]);
+        let pending = pending_otp(&response).unwrap();
+        assert_eq!(pending.ticket, vec![7; 16]);
+        assert_eq!(pending.message.as_deref(), Some("Enter the code from your token"));
+
+        let pack = answer_pack("VPN", "alice", &pending, "123456");
+        assert_eq!(pack.get_str("method").map(String::as_str), Some("otp"));
+        assert_eq!(pack.get_str("hubname").map(String::as_str), Some("VPN"));
+        assert_eq!(pack.get_str("username").map(String::as_str), Some("alice"));
+        assert_eq!(pack.get_data("otp_ticket"), Some(&vec![7; 16]));
+        assert_eq!(pack.get_str("otp").map(String::as_str), Some("123456"));
+    }
+
+    #[tokio::test]
+    async fn test_ask() {
+        let provider = |challenge: OtpChallenge| async move { Some(format!(" 00000{} ", challenge.attempt)) };
+        assert_eq!(ask(&provider, challenge(), Duration::from_secs(1)).await.unwrap(), "000001");
+
+        let declined = |_: OtpChallenge| async { None };
+        assert!(matches!(ask(&declined, challenge(), Duration::from_secs(1)).await, Err(VpnError::Cancelled(_))));
+
+        let slow = |_: OtpChallenge| async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Some("123456".to_string())
+        };
+        let result = ask(&slow, challenge(), Duration::from_millis(20)).await;
+        assert!(matches!(result, Err(VpnError::Timeout(_))));
+    }
+}
@@ -3,11 +3,38 @@
 //! This module implements the PACK binary format used by SoftEther VPN for
 //! all data communication after the HTTP watermark handshake. PACK is SoftEther's
 //! proprietary binary serialization format for key-value data structures.
+//!
+//! All length and offset arithmetic on untrusted input is checked; the
+//! `arithmetic_side_effects` lint keeps it that way.
+
+#![deny(clippy::arithmetic_side_effects)]
 
 use crate::error::{Result, VpnError};
+use crate::net_util;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Maximum number of elements accepted in a single PACK
+pub const MAX_PACK_ELEMENTS: u32 = 10_000;
+
+/// Maximum element name length on the wire (including null terminator)
+pub const MAX_ELEMENT_NAME_LEN: u32 = 1_000;
+
+/// Maximum number of values in a single element
+pub const MAX_ELEMENT_VALUES: u32 = 65_536;
+
+/// Maximum size of a single value (10MB)
+pub const MAX_VALUE_LEN: u32 = 10_000_000;
+
+/// Round `len` up to the 4-byte boundary used by PACK, `None` on overflow
+fn padded_len(len: usize) -> Option<usize> {
+    len.checked_add(3).map(|n| n & !3)
+}
+
+/// Convert a length to its u32 wire representation
+fn wire_len(len: usize, what: &str) -> Result<u32> {
+    u32::try_from(len).map_err(|_| VpnError::Protocol(format!("{what} length {len} exceeds u32")))
+}
 
 /// IP configuration extracted from binary session data
 #[derive(Debug, Clone)]
@@ -106,7 +133,7 @@ impl Value {
             Value::UniStr(s) => {
                 // Convert to UTF-16LE (as SoftEther expects)
                 let utf16: Vec<u16> = s.encode_utf16().collect();
-                let mut bytes = Vec::with_capacity(utf16.len() * 2);
+                let mut bytes = Vec::with_capacity(utf16.len().saturating_mul(2));
                 for code_unit in utf16 {
                     bytes.extend_from_slice(&code_unit.to_le_bytes());
                 }
@@ -141,7 +168,7 @@ impl Value {
                 Ok(Value::Str(s))
             }
             ElementType::UniStr => {
-                if data.len() % 2 != 0 {
+                if !data.len().is_multiple_of(2) {
                     return Err(VpnError::Protocol("Invalid UniStr data length".to_string()));
                 }
                 let mut utf16_codes = Vec::with_capacity(data.len() / 2);
@@ -209,7 +236,7 @@ impl Element {
 }
 
 /// PACK structure containing elements
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Pack {
     pub elements: Vec<Element>,
 
@@ -322,6 +349,65 @@ impl Pack {
             })
     }
 
+    /// Get every integer of an array element (e.g. a list of ports)
+    ///
+    /// A missing element yields an empty list; values of another type are an error.
+    pub fn get_int_array(&self, name: &str) -> Result<Vec<u32>> {
+        self.collect_values(name, "Int", |v| match v {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        })
+    }
+
+    /// Get every string of an array element, accepting `Str` and `UniStr`
+    pub fn get_str_array(&self, name: &str) -> Result<Vec<String>> {
+        self.collect_values(name, "string", |v| match v {
+            Value::Str(s) | Value::UniStr(s) => Some(s.clone()),
+            _ => None,
+        })
+    }
+
+    /// Get every address of an IP list element (e.g. DNS servers)
+    ///
+    /// Accepts the encodings written by [`add_ip`](Self::add_ip) - IPv4 as
+    /// `Int`, IPv6 as 16 bytes of `Data` - as well as 4-byte `Data` and
+    /// textual addresses.
+    pub fn get_ip_list(&self, name: &str) -> Result<Vec<IpAddr>> {
+        self.collect_values(name, "IP address", |v| match v {
+            Value::Int(i) => Some(IpAddr::V4(Ipv4Addr::from(*i))),
+            Value::Data(data) => match data.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+                16 => <[u8; 16]>::try_from(data.as_slice()).ok().map(|octets| IpAddr::V6(octets.into())),
+                _ => None,
+            },
+            Value::Str(s) | Value::UniStr(s) => s.trim().parse().ok(),
+            Value::Int64(_) => None,
+        })
+    }
+
+    /// Convert all values of `name`, failing on the first one `convert` rejects
+    fn collect_values<T>(&self, name: &str, expected: &str, convert: impl Fn(&Value) -> Option<T>) -> Result<Vec<T>> {
+        let Some(element) = self.get_element(name) else {
+            return Ok(Vec::new());
+        };
+        element
+            .values
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                convert(value).ok_or_else(|| {
+                    VpnError::Protocol(format!(
+                        "Element '{}' value {} is {:?}, expected {}",
+                        name,
+                        index,
+                        value.element_type(),
+                        expected
+                    ))
+                })
+            })
+            .collect()
+    }
+
     /// Get all elements as a HashMap for easy iteration
     pub fn get_elements(&self) -> std::collections::HashMap<String, &Element> {
         self.elements.iter().map(|e| (e.name.clone(), e)).collect()
@@ -332,7 +418,7 @@ impl Pack {
         let mut buf = BytesMut::new();
 
         // Write number of elements (4 bytes, big-endian - SoftEther format)
-        buf.put_u32(self.elements.len() as u32);
+        buf.put_u32(wire_len(self.elements.len(), "Element count")?);
 
         // Write each element
         for element in &self.elements {
@@ -348,7 +434,11 @@ impl Pack {
 
         // Write element name length and name (with null terminator, big-endian)
         let name_bytes = element.name.as_bytes();
-        buf.put_u32(name_bytes.len() as u32 + 1); // +1 for null terminator
+        let name_len = wire_len(name_bytes.len(), "Element name")?
+            .checked_add(1) // +1 for null terminator
+            .filter(|&len| len <= MAX_ELEMENT_NAME_LEN)
+            .ok_or_else(|| VpnError::Protocol(format!("Element name '{}' is too long", element.name)))?;
+        buf.put_u32(name_len);
         buf.put_slice(name_bytes);
         buf.put_u8(0); // null terminator
 
@@ -356,12 +446,12 @@ impl Pack {
         buf.put_u32(element_type as u32);
 
         // Write number of values (big-endian)
-        buf.put_u32(element.values.len() as u32);
+        buf.put_u32(wire_len(element.values.len(), "Value count")?);
 
         // Write each value
         for value in &element.values {
             let value_bytes = value.to_bytes();
-            buf.put_u32(value_bytes.len() as u32); // value length (big-endian)
+            buf.put_u32(wire_len(value_bytes.len(), "Value")?); // value length (big-endian)
             buf.put_slice(&value_bytes);
         }
 
@@ -383,8 +473,6 @@ impl Pack {
             }
         }
         
-        let original_len = data.len();
-        
         if data.len() < 4 {
             return Err(VpnError::Protocol("PACK data too short".to_string()));
         }
@@ -394,17 +482,17 @@ impl Pack {
         log::debug!("PACK contains {} elements (big-endian), consumed 4 bytes, {} remaining", num_elements, data.len());
         
         // Sanity check: element count shouldn't be too large
-        if num_elements > 10000 {
+        if num_elements > MAX_PACK_ELEMENTS {
             return Err(VpnError::Protocol(format!("Element count {} seems too large", num_elements)));
         }
         
         let mut elements = Vec::with_capacity(num_elements as usize);
 
         // Read each element with graceful error handling for SoftEther's mixed PACK + binary format
-        for i in 0..num_elements {
+        for index in 1..=num_elements {
             let bytes_before = data.len();
             log::debug!("Parsing element {} of {}, bytes remaining before element: {}", 
-                       i + 1, num_elements, data.len());
+                       index, num_elements, data.len());
         
             // Add detailed hex dump of the next 16 bytes for debugging
             if data.len() >= 16 {
@@ -416,21 +504,27 @@ impl Pack {
             // Look ahead at the element type to see if it's a valid PACK type (0-4)
             if data.len() >= 8 {
                 // Skip name length and name to get to element type
-                let name_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
-                let bytes_to_skip = 4 + ((name_len + 3) & !3); // name length + padded name
-                
-                if data.len() > bytes_to_skip + 4 {
+                let name_len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                let type_offset = usize::try_from(name_len)
+                    .ok()
+                    .and_then(padded_len)
+                    .and_then(|padded| padded.checked_add(4)); // name length + padded name
+                let type_bytes = type_offset
+                    .and_then(|offset| Some((offset, offset.checked_add(4)?)))
+                    .and_then(|(start, end)| data.get(start..end));
+
+                if let Some(type_bytes) = type_bytes {
                     let element_type_raw = u32::from_be_bytes([
-                        data[bytes_to_skip], 
-                        data[bytes_to_skip + 1], 
-                        data[bytes_to_skip + 2], 
-                        data[bytes_to_skip + 3]
+                        type_bytes[0],
+                        type_bytes[1],
+                        type_bytes[2],
+                        type_bytes[3],
                     ]);
                     
                     // If element type is way out of range (0-4), this is likely binary session data
                     if element_type_raw > 10000 {
                         log::info!("🔍 Detected binary session data at element {} (type {}), capturing remaining {} bytes", 
-                                  i + 1, element_type_raw, data.len());
+                                  index, element_type_raw, data.len());
                         break;
                     }
                 }
@@ -441,11 +535,11 @@ impl Pack {
                 Ok(element) => {
                     let bytes_after = data.len();
                     log::debug!("Parsed element: name={}, values={}, consumed {} bytes", 
-                               element.name, element.values.len(), bytes_before - bytes_after);
+                               element.name, element.values.len(), bytes_before.saturating_sub(bytes_after));
                     elements.push(element);
 
                     // After parsing first element, show what's next for debugging
-                    if i == 0 && data.len() >= 16 {
+                    if index == 1 && data.len() >= 16 {
                         log::debug!("After first element: {} bytes remaining", data.len());
                         log::debug!("Next 16 bytes after first element: {:02x?}", &data[..16]);
                     }
@@ -453,9 +547,9 @@ impl Pack {
                 Err(e) => {
                     // In SoftEther authentication responses, it's normal for later elements to contain
                     // binary session data that doesn't conform to PACK format
-                    log::info!("🔍 Element {} parsing failed (likely binary data): {}", i + 1, e);
+                    log::info!("🔍 Element {} parsing failed (likely binary data): {}", index, e);
                     log::info!("🔍 Successfully parsed {} of {} elements, capturing remaining {} bytes as binary session data", 
-                              i, num_elements, data.len());
+                              elements.len(), num_elements, data.len());
                     break;
                 }
             }
@@ -480,7 +574,6 @@ impl Pack {
     /// Read a single element from the buffer
     fn read_element(data: &mut Bytes) -> Result<Element> {
         let bytes_before = data.len();
-        let _original_len = bytes_before; // For offset calculation
         
         if data.len() < 4 {
             return Err(VpnError::Protocol("Not enough data for element name length".to_string()));
@@ -491,11 +584,12 @@ impl Pack {
         log::debug!("Element name length raw: {} (includes null terminator), consumed 4 bytes, {} remaining", name_len_raw, data.len());
         
         // Safety check: reject unreasonably large name lengths
-        if name_len_raw > 1000 { // 1KB limit for element names
+        if name_len_raw > MAX_ELEMENT_NAME_LEN {
             return Err(VpnError::Protocol(format!("Element name length {} is unreasonably large", name_len_raw)));
         }
         
-        let name_len = name_len_raw as usize;
+        let name_len = usize::try_from(name_len_raw)
+            .map_err(|_| VpnError::Protocol("Element name length does not fit in memory".to_string()))?;
         
         if name_len == 0 {
             return Err(VpnError::Protocol("Element name length is zero".to_string()));
@@ -515,8 +609,9 @@ impl Pack {
         
         // SoftEther PACK format: element name data is padded to 4-byte boundary
         // We need to pad just the name data (not including the length field)
-        let padded_name_len = (name_len + 3) & !3; // Round name_len up to 4-byte boundary
-        let padding_needed = padded_name_len - name_len;
+        let padded_name_len = padded_len(name_len) // Round name_len up to 4-byte boundary
+            .ok_or_else(|| VpnError::Protocol("Element name length overflow".to_string()))?;
+        let padding_needed = padded_name_len.saturating_sub(name_len);
         
         if padding_needed > 0 && data.len() >= padding_needed {
             let padding = data.copy_to_bytes(padding_needed);
@@ -525,7 +620,7 @@ impl Pack {
         
         // Additional alignment: SoftEther appears to need one more byte alignment after string padding
         // Based on the binary analysis, there's an extra 0x00 byte that we need to skip
-        if data.len() > 0 && data[0] == 0 {
+        if data.first() == Some(&0) {
             let extra_byte = data.get_u8();
             log::debug!("Skipped extra alignment byte: 0x{:02x}, {} remaining", extra_byte, data.len());
         }
@@ -556,10 +651,16 @@ impl Pack {
                    &data[..std::cmp::min(8, data.len())]);
         let num_values_raw = data.get_u32();
         log::debug!("Number of values raw: {}, consumed 4 bytes, {} remaining", num_values_raw, data.len());
-        let num_values = num_values_raw as usize;
+        if num_values_raw > MAX_ELEMENT_VALUES {
+            return Err(VpnError::Protocol(format!("Value count {} exceeds safety limit", num_values_raw)));
+        }
+        let num_values = usize::try_from(num_values_raw)
+            .map_err(|_| VpnError::Protocol("Value count does not fit in memory".to_string()))?;
         log::debug!("Number of values: {}", num_values);
         
-        let mut values = Vec::with_capacity(num_values);
+        // Every value needs at least its 4-byte length prefix, so never reserve
+        // more than the remaining data could possibly hold
+        let mut values = Vec::with_capacity(num_values.min(data.len() / 4));
 
         // Read each value
         for j in 0..num_values {
@@ -571,12 +672,13 @@ impl Pack {
             log::debug!("Value {} length raw: {}, consumed 4 bytes, {} remaining", j, value_len_raw, data.len());
             
             // Safety check: reject unreasonably large values to prevent memory allocation attacks
-            if value_len_raw > 10_000_000 { // 10MB limit per value
+            if value_len_raw > MAX_VALUE_LEN {
                 log::error!("Value {} length {} is unreasonably large, likely corrupted data", j, value_len_raw);
                 return Err(VpnError::Protocol(format!("Value length {} exceeds safety limit", value_len_raw)));
             }
             
-            let value_len = value_len_raw as usize;
+            let value_len = usize::try_from(value_len_raw)
+                .map_err(|_| VpnError::Protocol("Value length does not fit in memory".to_string()))?;
             log::debug!("Value {} length: {}", j, value_len);
             
             if data.len() < value_len {
@@ -592,8 +694,9 @@ impl Pack {
             values.push(value);
             
             // SoftEther PACK format: values are padded to 4-byte boundary
-            let padded_value_len = (value_len + 3) & !3; // Round up to 4-byte boundary
-            let value_padding_needed = padded_value_len - value_len;
+            let padded_value_len = padded_len(value_len) // Round up to 4-byte boundary
+                .ok_or_else(|| VpnError::Protocol("Value length overflow".to_string()))?;
+            let value_padding_needed = padded_value_len.saturating_sub(value_len);
             
             if value_padding_needed > 0 && data.len() >= value_padding_needed {
                 let value_padding = data.copy_to_bytes(value_padding_needed);
@@ -602,7 +705,7 @@ impl Pack {
         }
 
         let bytes_after = data.len();
-        log::debug!("Element '{}' parsing complete, total consumed: {} bytes", name, bytes_before - bytes_after);
+        log::debug!("Element '{}' parsing complete, total consumed: {} bytes", name, bytes_before.saturating_sub(bytes_after));
 
         // SoftEther PACK format: Try exactly 3 bytes of inter-element padding
         // This should get us from [00, 00, 01, 00] to [00, 00, 00, ??] for the next name length
@@ -614,8 +717,8 @@ impl Pack {
                        padding1, padding2, padding3, data.len());
         }
 
-        let total_element_size = bytes_before - data.len();
-        log::debug!("Total element size with padding: {}, consumed {} bytes", total_element_size, bytes_before - data.len());
+        let total_element_size = bytes_before.saturating_sub(data.len());
+        log::debug!("Total element size with padding: {}", total_element_size);
 
         Ok(Element {
             name,
@@ -642,9 +745,7 @@ impl Pack {
         let mut potential_ips = Vec::new();
         
         // Search for 4-byte sequences that could be IPv4 addresses
-        for i in 0..binary_data.len().saturating_sub(4) {
-            let bytes = &binary_data[i..i+4];
-            
+        for (i, bytes) in binary_data.windows(4).enumerate() {
             // Check if this could be a valid IP address
             if is_valid_ip_bytes(bytes) {
                 let ip = format!("{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3]);
@@ -656,7 +757,7 @@ impl Pack {
                     
                     // Look for gateway IP nearby (usually the next or previous IP)
                     let gateway = if bytes[3] > 1 {
-                        format!("10.21.255.{}", bytes[3] - 1)
+                        format!("10.21.255.{}", bytes[3].saturating_sub(1))
                     } else {
                         "10.21.255.1".to_string()
                     };
@@ -731,8 +832,7 @@ impl Pack {
             let mut potential_ips = Vec::new();
             
             // Scan for 4-byte sequences that could be IP addresses
-            for i in 0..binary_data.len().saturating_sub(3) {
-                let bytes = &binary_data[i..i+4];
+            for (i, bytes) in binary_data.windows(4).enumerate() {
                 if is_valid_ip_bytes(bytes) {
                     let ip = format!("{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3]);
                     log::info!("🔍 Found potential IP {} at offset {} (hex: {:02x}{:02x}{:02x}{:02x})", 
@@ -753,7 +853,6 @@ impl Pack {
             
             for (offset, ip, bytes) in potential_ips {
                 let mut priority = 0;
-                let mut gateway = "10.0.0.1".to_string();
                 
                 // Check for specific VPN server IP ranges with priority scoring
                 match bytes[0] {
@@ -762,53 +861,44 @@ impl Pack {
                         if bytes[1] == 251 {
                             // 10.251.x.x - very specific VPN server range
                             priority = 100;
-                            gateway = format!("10.251.{}.1", bytes[2]);
                             log::info!("🎯 Found 10.251.x.x VPN IP (PRIORITY 100): {} at offset {}", ip, offset);
                         } else if bytes[1] == 21 && bytes[2] == 255 {
                             // Specific server range 10.21.255.x
                             priority = 90;
-                            gateway = "10.21.255.1".to_string();
                             log::info!("🎯 Found VPN server IP range 10.21.255.x (PRIORITY 90): {} at offset {}", ip, offset);
                         } else if bytes[1] >= 200 {
                             // High 10.x range, likely VPN assigned
                             priority = 80;
-                            gateway = format!("10.{}.{}.1", bytes[1], bytes[2]);
                             log::info!("🎯 Found high 10.x IP (PRIORITY 80): {} at offset {}", ip, offset);
                         } else if bytes[1] >= 100 {
                             // Medium 10.x range
                             priority = 60;
-                            gateway = format!("10.{}.{}.1", bytes[1], bytes[2]);
                             log::info!("🌐 Found medium 10.x IP (PRIORITY 60): {} at offset {}", ip, offset);
                         } else if bytes[1] > 0 {
                             // Any other 10.x IP as fallback
                             priority = 40;
-                            gateway = format!("10.{}.{}.1", bytes[1], bytes[2]);
                             log::info!("🌐 Found 10.x IP (PRIORITY 40): {} at offset {}", ip, offset);
                         }
                     }
                     192 if bytes[1] == 168 => {
                         // 192.168.x.x range
                         priority = 30;
-                        gateway = format!("192.168.{}.1", bytes[2]);
                         log::info!("🌐 Found 192.168.x.x IP (PRIORITY 30): {} at offset {}", ip, offset);
                     }
                     172 if bytes[1] >= 16 && bytes[1] <= 31 => {
                         // 172.16-31.x.x range
                         priority = 35;
-                        gateway = format!("172.{}.{}.1", bytes[1], bytes[2]);
                         log::info!("🌐 Found 172.x.x.x IP (PRIORITY 35): {} at offset {}", ip, offset);
                     }
                     // Add support for other common VPN ranges that appeared in the data
                     100..=127 => {
                         // 100-127.x.x.x range - often used for VPN
                         priority = 70;
-                        gateway = format!("{}.{}.{}.1", bytes[0], bytes[1], bytes[2]);
                         log::info!("🎯 Found 100-127.x.x.x VPN IP (PRIORITY 70): {} at offset {}", ip, offset);
                     }
                     208..=223 => {
                         // High public ranges that might be VPN endpoints
                         priority = 50;
-                        gateway = format!("{}.{}.{}.1", bytes[0], bytes[1], bytes[2]);
                         log::info!("🌐 Found high public IP (PRIORITY 50): {} at offset {}", ip, offset);
                     }
                     _ => {
@@ -816,7 +906,6 @@ impl Pack {
                         // Look for IPs that are likely to be VPN-assigned based on patterns
                         if bytes[1] > 10 && bytes[2] > 10 && bytes[3] > 10 && bytes[3] < 250 {
                             priority = 25;
-                            gateway = format!("{}.{}.{}.1", bytes[0], bytes[1], bytes[2]);
                             log::info!("🌐 Found potential VPN IP (PRIORITY 25): {} at offset {}", ip, offset);
                         } else {
                             continue;
@@ -827,7 +916,10 @@ impl Pack {
                 // Update best IP if this one has higher priority
                 if priority > best_priority {
                     best_ip = Some(ip.clone());
-                    best_gateway = gateway;
+                    // Assume the hub's gateway is the first host of the /24
+                    if let Some(gateway) = net_util::first_host(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]), 24) {
+                        best_gateway = gateway.to_string();
+                    }
                     best_priority = priority;
                     log::info!("🏆 New best IP: {} (priority {})", ip, priority);
                 }
@@ -852,3 +944,160 @@ impl Pack {
 
     // ...existing code...
 }
+
+#[cfg(test)]
+#[allow(clippy::arithmetic_side_effects)]
+mod tests {
+    use super::*;
+
+    /// Element header as the server lays it out: name, alignment byte, type, value count
+    fn element_header(name: &str, element_type: u32, num_values: u32) -> BytesMut {
+        let mut buf = BytesMut::new();
+        let name_len = name.len() + 1;
+        buf.put_u32(name_len as u32);
+        buf.put_slice(name.as_bytes());
+        buf.put_u8(0);
+        buf.put_bytes(0, padded_len(name_len).unwrap() - name_len);
+        buf.put_u8(0); // extra alignment byte
+        buf.put_u32(element_type);
+        buf.put_u32(num_values);
+        buf
+    }
+
+    #[test]
+    fn test_rejects_excessive_element_count() {
+        let mut buf = BytesMut::new();
+        buf.put_u32(MAX_PACK_ELEMENTS + 1);
+        assert!(Pack::from_bytes(buf.freeze()).is_err());
+    }
+
+    #[test]
+    fn test_max_name_length_does_not_overflow() {
+        // A u32::MAX name length would wrap `len + 3` on 32-bit targets
+        let mut buf = BytesMut::new();
+        buf.put_u32(1);
+        buf.put_u32(u32::MAX);
+        buf.put_slice(&[0xaa; 16]);
+
+        let pack = Pack::from_bytes(buf.freeze()).unwrap();
+        assert!(pack.elements.is_empty());
+        assert!(pack.binary_session_data.is_some());
+
+        let mut data = Bytes::from_static(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0]);
+        assert!(Pack::read_element(&mut data).is_err());
+    }
+
+    #[test]
+    fn test_rejects_excessive_value_count() {
+        let mut data = element_header("abc", ElementType::Int as u32, u32::MAX).freeze();
+        assert!(Pack::read_element(&mut data).is_err());
+
+        // Within the limit but without backing data must fail without over-allocating
+        let mut data = element_header("abc", ElementType::Int as u32, MAX_ELEMENT_VALUES).freeze();
+        assert!(Pack::read_element(&mut data).is_err());
+    }
+
+    #[test]
+    fn test_rejects_oversized_value_length() {
+        let mut buf = element_header("abc", ElementType::Data as u32, 1);
+        buf.put_u32(u32::MAX);
+        let mut data = buf.freeze();
+        assert!(Pack::read_element(&mut data).is_err());
+    }
+
+    #[test]
+    fn test_read_element_values() {
+        let mut buf = element_header("abc", ElementType::Int as u32, 2);
+        buf.put_u32(4);
+        buf.put_u32(7);
+        buf.put_u32(4);
+        buf.put_u32(u32::MAX);
+        let mut data = buf.freeze();
+
+        let element = Pack::read_element(&mut data).unwrap();
+        assert_eq!(element.name, "abc");
+        assert!(matches!(element.values[..], [Value::Int(7), Value::Int(u32::MAX)]));
+    }
+
+    /// Length-prefixed, padded value as the server writes it
+    fn put_value(buf: &mut BytesMut, bytes: &[u8]) {
+        buf.put_u32(bytes.len() as u32);
+        buf.put_slice(bytes);
+        buf.put_bytes(0, padded_len(bytes.len()).unwrap() - bytes.len());
+    }
+
+    #[test]
+    fn test_array_getters_on_server_layout() {
+        // DNS server list as sent in a GetConfig response: one Int per address
+        let mut buf = BytesMut::new();
+        buf.put_u32(1);
+        buf.unsplit(element_header("DnsServers", ElementType::Int as u32, 3));
+        for ip in [[10, 21, 0, 1], [8, 8, 8, 8], [1, 1, 1, 1]] {
+            put_value(&mut buf, &u32::from(Ipv4Addr::from(ip)).to_be_bytes());
+        }
+        let pack = Pack::from_bytes(buf.freeze()).unwrap();
+
+        assert_eq!(
+            pack.get_ip_list("DnsServers").unwrap(),
+            vec![
+                IpAddr::from([10, 21, 0, 1]),
+                IpAddr::from([8, 8, 8, 8]),
+                IpAddr::from([1, 1, 1, 1]),
+            ]
+        );
+        assert_eq!(pack.get_int_array("DnsServers").unwrap().len(), 3);
+        // The single-value getter only ever saw the first entry
+        assert_eq!(pack.get_int("DnsServers"), Some(0x0a15_0001));
+
+        // Route list as strings
+        let mut data = element_header("RouteList", ElementType::Str as u32, 2);
+        put_value(&mut data, b"10.0.0.0/8");
+        put_value(&mut data, b"192.168.0.0/16");
+        let element = Pack::read_element(&mut data.freeze()).unwrap();
+        let mut pack = Pack::new();
+        pack.add_element(element);
+        assert_eq!(
+            pack.get_str_array("RouteList").unwrap(),
+            vec!["10.0.0.0/8".to_string(), "192.168.0.0/16".to_string()]
+        );
+        assert!(pack.get_str_array("Missing").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_array_getters_validate_types() {
+        let mut pack = Pack::new();
+        pack.add_element(Element::new_array(
+            "mixed".to_string(),
+            vec![Value::Int(1), Value::Str("two".to_string())],
+        ));
+        assert!(pack.get_int_array("mixed").is_err());
+        assert!(pack.get_str_array("mixed").is_err());
+
+        pack.add_ip("v6", "fd00::1".parse().unwrap());
+        pack.add_str("text", "9.9.9.9");
+        pack.add_data("short", vec![1, 2, 3]);
+        assert_eq!(pack.get_ip_list("v6").unwrap(), vec!["fd00::1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(pack.get_ip_list("text").unwrap(), vec![IpAddr::from([9, 9, 9, 9])]);
+        assert!(pack.get_ip_list("short").is_err());
+    }
+
+    #[test]
+    fn test_write_rejects_long_names() {
+        let mut pack = Pack::new();
+        pack.add_int(&"x".repeat(MAX_ELEMENT_NAME_LEN as usize), 1);
+        assert!(pack.to_bytes().is_err());
+    }
+
+    #[test]
+    fn test_ip_scan_handles_short_data() {
+        let mut pack = Pack::new();
+        pack.set_binary_session_data(Bytes::from_static(&[10, 1, 2]));
+        assert!(pack.extract_ip_configuration().is_none());
+        assert!(pack.analyze_for_ip_addresses().is_none());
+
+        pack.set_binary_session_data(Bytes::from_static(&[10, 21, 255, 7]));
+        let config = pack.extract_ip_configuration().unwrap();
+        assert_eq!(config.local_ip, "10.21.255.7");
+        assert_eq!(config.gateway_ip, "10.21.255.6");
+    }
+}
@@ -0,0 +1,355 @@
+//! Session tickets for resuming a session without a full login
+//!
+//! After a login the server names the session (`session_name`) and shares a
+//! secret with it (`session_key`). With `reconnect.resume_session` the client
+//! keeps both as a [`SessionTicket`]; the next connection to the same server
+//! and hub first asks to resume the session, proving the key against the
+//! random of the new server hello:
+//!
+//! ```text
+//! client -> server   method = "resume", hub, username, session_name,
+//!                    secure_session_key = SHA-0(session_key || random)
+//! server -> client   the login response, or an error when the session is gone
+//! ```
+//!
+//! A refused ticket is dropped and the client logs in as usual. Tickets are
+//! kept by [`TicketStore`]: in memory, and unless `tunnel.in_memory_only`
+//! also on disk, encrypted with AES-256-GCM under a random key stored next to
+//! them that only the owner may read.
+
+use super::pack::Pack;
+use crate::config::Config;
+use crate::crypto::{self, CryptoEngine};
+use crate::error::{Result, VpnError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Key encrypting the tickets on disk
+const KEY_FILE: &str = "session.key";
+const KEY_LEN: usize = 32;
+
+/// Ticket files are `session-<hash of server and hub>.ticket`
+const FILE_PREFIX: &str = "session-";
+const FILE_SUFFIX: &str = ".ticket";
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// What it takes to resume a session, wiped on drop
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionTicket {
+    pub server: String,
+    pub hub: String,
+    pub username: String,
+    pub session_name: String,
+    session_key: Vec<u8>,
+    /// Unix time of the login that issued it
+    pub issued_at: u64,
+}
+
+impl SessionTicket {
+    /// Ticket for the session a login response describes
+    ///
+    /// `None` when the server did not name the session or sent no key.
+    pub fn from_login(server: &str, hub: &str, username: &str, response: &Pack) -> Option<Self> {
+        let session_name = response.get_str("session_name").filter(|name| !name.is_empty())?;
+        let session_key = response.get_data("session_key").filter(|key| !key.is_empty())?;
+        Some(Self {
+            server: server.to_string(),
+            hub: hub.to_string(),
+            username: username.to_string(),
+            session_name: session_name.clone(),
+            session_key: session_key.clone(),
+            issued_at: now(),
+        })
+    }
+
+    /// Whether the ticket is younger than `lifetime`
+    pub fn is_fresh(&self, lifetime: Duration) -> bool {
+        now().saturating_sub(self.issued_at) < lifetime.as_secs()
+    }
+
+    /// Request resuming the session, proving the key against the hello `random`
+    pub(crate) fn resume_pack(&self, random: &[u8]) -> Pack {
+        let mut input = Zeroizing::new(Vec::with_capacity(self.session_key.len() + random.len()));
+        input.extend_from_slice(&self.session_key);
+        input.extend_from_slice(random);
+
+        let mut pack = Pack::new();
+        pack.add_str("method", "resume");
+        pack.add_str("hub", &self.hub);
+        pack.add_str("username", &self.username);
+        pack.add_str("session_name", &self.session_name);
+        pack.add_data("secure_session_key", crypto::sha0(&input).to_vec());
+        pack
+    }
+}
+
+impl Drop for SessionTicket {
+    fn drop(&mut self) {
+        self.session_key.zeroize();
+    }
+}
+
+impl std::fmt::Debug for SessionTicket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionTicket")
+            .field("server", &self.server)
+            .field("hub", &self.hub)
+            .field("username", &self.username)
+            .field("session_name", &self.session_name)
+            .field("issued_at", &self.issued_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Session tickets by server and hub, optionally persisted encrypted
+#[derive(Debug)]
+pub struct TicketStore {
+    /// Directory of the ticket files; in memory only when `None`
+    dir: Option<PathBuf>,
+    lifetime: Duration,
+    // (server, hub) → ticket
+    tickets: HashMap<(String, String), SessionTicket>,
+}
+
+impl TicketStore {
+    /// Store keeping tickets in memory only
+    pub fn in_memory(lifetime: Duration) -> Self {
+        Self { dir: None, lifetime, tickets: HashMap::new() }
+    }
+
+    /// Store persisting tickets in `dir`
+    pub fn on_disk(dir: impl Into<PathBuf>, lifetime: Duration) -> Self {
+        Self { dir: Some(dir.into()), ..Self::in_memory(lifetime) }
+    }
+
+    /// Store for `config`, `None` unless `reconnect.resume_session` is set
+    ///
+    /// Tickets go to `tunnel.state_dir` (or the default state directory),
+    /// and stay in memory with `tunnel.in_memory_only`.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        if !config.reconnect.resume_session {
+            return None;
+        }
+        let lifetime = Duration::from_secs(u64::from(config.reconnect.ticket_lifetime_secs));
+        if config.tunnel.in_memory_only {
+            return Some(Self::in_memory(lifetime));
+        }
+        let dir = config
+            .tunnel
+            .state_dir
+            .as_ref()
+            .map_or_else(crate::tunnel::journal::default_state_dir, PathBuf::from);
+        Some(Self::on_disk(dir, lifetime))
+    }
+
+    /// Fresh ticket of `username` for `server`/`hub`
+    ///
+    /// Looks on disk when none is in memory. Stale tickets and those of
+    /// another user are dropped.
+    pub fn get(&mut self, server: &str, hub: &str, username: &str) -> Option<SessionTicket> {
+        let key = (server.to_string(), hub.to_string());
+        if !self.tickets.contains_key(&key) {
+            if let Some(ticket) = self.read(server, hub) {
+                self.tickets.insert(key.clone(), ticket);
+            }
+        }
+        match self.tickets.get(&key) {
+            Some(ticket)
+                if ticket.is_fresh(self.lifetime)
+                    && ticket.username == username
+                    && ticket.server == server
+                    && ticket.hub == hub =>
+            {
+                Some(ticket.clone())
+            }
+            Some(_) => {
+                self.forget(server, hub);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Keep `ticket`, replacing any earlier one for its server and hub
+    ///
+    /// A ticket that cannot be written is kept in memory only.
+    pub fn insert(&mut self, ticket: SessionTicket) {
+        if let Err(e) = self.write(&ticket) {
+            log::warn!(hub = ticket.hub.as_str(); "Session ticket not persisted: {}", e);
+        }
+        self.tickets.insert((ticket.server.clone(), ticket.hub.clone()), ticket);
+    }
+
+    /// Drop the ticket for `server`/`hub`, in memory and on disk
+    pub fn forget(&mut self, server: &str, hub: &str) {
+        self.tickets.remove(&(server.to_string(), hub.to_string()));
+        if let Some(path) = self.ticket_path(server, hub) {
+            if let Err(e) = std::fs::remove_file(&path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::warn!(path:% = path.display(); "Cannot remove session ticket: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Drop every ticket this store holds, e.g. once the sessions were closed
+    pub fn clear(&mut self) {
+        let keys: Vec<_> = self.tickets.keys().cloned().collect();
+        for (server, hub) in keys {
+            self.forget(&server, &hub);
+        }
+    }
+
+    /// Number of tickets held in memory
+    pub fn len(&self) -> usize {
+        self.tickets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tickets.is_empty()
+    }
+
+    fn ticket_path(&self, server: &str, hub: &str) -> Option<PathBuf> {
+        let digest = crypto::sha256(format!("{server}\0{hub}").as_bytes());
+        let name: String = digest[..8].iter().map(|b| format!("{b:02x}")).collect();
+        Some(self.dir.as_ref()?.join(format!("{FILE_PREFIX}{name}{FILE_SUFFIX}")))
+    }
+
+    /// Ticket on disk for `server`/`hub`; unreadable files are deleted
+    fn read(&self, server: &str, hub: &str) -> Option<SessionTicket> {
+        let path = self.ticket_path(server, hub)?;
+        let sealed = std::fs::read(&path).ok()?;
+        let ticket = load_key(self.dir.as_deref()?, false)
+            .and_then(|key| CryptoEngine::new()?.decrypt(&sealed, &key[..]))
+            .and_then(|json| {
+                let json = Zeroizing::new(json);
+                serde_json::from_slice::<SessionTicket>(&json).map_err(|e| VpnError::Io(e.into()))
+            });
+        match ticket {
+            Ok(ticket) => Some(ticket),
+            Err(e) => {
+                log::info!(path:% = path.display(); "Dropping unreadable session ticket: {}", e);
+                let _ = std::fs::remove_file(&path);
+                None
+            }
+        }
+    }
+
+    /// Encrypt `ticket` to its file, replacing it in one step
+    fn write(&self, ticket: &SessionTicket) -> Result<()> {
+        let (Some(dir), Some(path)) = (self.dir.as_deref(), self.ticket_path(&ticket.server, &ticket.hub)) else {
+            return Ok(());
+        };
+        let key = load_key(dir, true)?;
+        let json = Zeroizing::new(serde_json::to_vec(ticket).map_err(|e| VpnError::Io(e.into()))?);
+        let sealed = CryptoEngine::new()?.encrypt(&json, &key[..])?;
+        let staged = path.with_extension("tmp");
+        private_file(&staged)?.write_all(&sealed)?;
+        std::fs::rename(&staged, &path)?;
+        Ok(())
+    }
+}
+
+/// Ticket key in `dir`, generated on first use when `create` is set
+fn load_key(dir: &Path, create: bool) -> Result<Zeroizing<Vec<u8>>> {
+    let path = dir.join(KEY_FILE);
+    match std::fs::read(&path) {
+        Ok(key) if key.len() == KEY_LEN => return Ok(Zeroizing::new(key)),
+        Ok(_) => log::warn!(path:% = path.display(); "Replacing malformed session ticket key"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && create => {}
+        Err(e) => return Err(VpnError::Io(e)),
+    }
+    if !create {
+        return Err(VpnError::Crypto("Session ticket key is malformed".into()));
+    }
+    std::fs::create_dir_all(dir)?;
+    let key = Zeroizing::new(CryptoEngine::new()?.random_bytes(KEY_LEN)?);
+    let staged = path.with_extension("tmp");
+    private_file(&staged)?.write_all(&key)?;
+    std::fs::rename(&staged, &path)?;
+    Ok(key)
+}
+
+/// Create `path` readable and writable by the owner only (on Unix)
+fn private_file(path: &Path) -> Result<std::fs::File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    Ok(options.open(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn welcome() -> Pack {
+        let mut pack = Pack::new();
+        pack.add_int("error", 0);
+        pack.add_str("session_name", "SID-ALICE-1");
+        pack.add_data("session_key", (0..20).collect());
+        pack
+    }
+
+    #[test]
+    fn test_ticket_from_login() {
+        assert!(SessionTicket::from_login("10.0.0.1:443", "VPN", "alice", &Pack::new()).is_none());
+
+        let ticket = SessionTicket::from_login("10.0.0.1:443", "VPN", "alice", &welcome()).unwrap();
+        assert_eq!(ticket.session_name, "SID-ALICE-1");
+        assert!(ticket.is_fresh(Duration::from_secs(60)));
+        assert!(!format!("{ticket:?}").contains("session_key"));
+
+        let pack = ticket.resume_pack(&[1; 20]);
+        assert_eq!(pack.get_str("method").map(String::as_str), Some("resume"));
+        assert_eq!(pack.get_str("session_name").map(String::as_str), Some("SID-ALICE-1"));
+        let proof = pack.get_data("secure_session_key").unwrap().clone();
+        assert_eq!(proof.len(), 20);
+        // The proof depends on the hello random
+        assert_ne!(ticket.resume_pack(&[2; 20]).get_data("secure_session_key"), Some(&proof));
+    }
+
+    #[test]
+    fn test_store_persists_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let ticket = SessionTicket::from_login("10.0.0.1:443", "VPN", "alice", &welcome()).unwrap();
+
+        let mut store = TicketStore::on_disk(dir.path(), Duration::from_secs(600));
+        store.insert(ticket.clone());
+        let path = store.ticket_path("10.0.0.1:443", "VPN").unwrap();
+        let sealed = std::fs::read(&path).unwrap();
+        assert!(!sealed.windows(11).any(|window| window == b"SID-ALICE-1"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let key = std::fs::metadata(dir.path().join(KEY_FILE)).unwrap();
+            assert_eq!(key.permissions().mode() & 0o777, 0o600);
+        }
+
+        // A new process finds the ticket, for the same user only
+        let mut reopened = TicketStore::on_disk(dir.path(), Duration::from_secs(600));
+        assert_eq!(reopened.get("10.0.0.1:443", "VPN", "alice"), Some(ticket.clone()));
+        assert_eq!(reopened.get("10.0.0.1:443", "VPN", "bob"), None);
+        assert!(!path.exists());
+
+        // Stale and tampered tickets are dropped
+        let mut expired = TicketStore::on_disk(dir.path(), Duration::ZERO);
+        expired.insert(ticket.clone());
+        assert_eq!(expired.get("10.0.0.1:443", "VPN", "alice"), None);
+        let mut store = TicketStore::on_disk(dir.path(), Duration::from_secs(600));
+        store.insert(ticket);
+        let mut sealed = std::fs::read(&path).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        std::fs::write(&path, sealed).unwrap();
+        assert_eq!(TicketStore::on_disk(dir.path(), Duration::from_secs(600)).get("10.0.0.1:443", "VPN", "alice"), None);
+        assert!(!path.exists());
+    }
+}
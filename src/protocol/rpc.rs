@@ -0,0 +1,399 @@
+//! Typed RPC descriptors and transparent retry
+//!
+//! Every request the client makes over the HTTP control channel is tagged
+//! with an [`RpcMethod`], which knows whether repeating the request is safe.
+//! Idempotent calls (watermark, GetConfig, keepalive) are retried with
+//! jittered exponential backoff on transient transport errors; login is
+//! never retried because a second attempt may count against the account or
+//! race with the first session.
+//!
+//! Before logging in, the client may also ask the server about itself: its
+//! product and version come with the hello, [`RpcMethod::EnumHub`] lists the
+//! virtual hubs and [`RpcMethod::GetCaps`] the server's capabilities. Servers
+//! may refuse either; [`query_server_info`] reports what it could learn.
+
+use crate::error::{ErrorCode, Result, VpnError};
+use crate::protocol::pack::{Pack, Value};
+use crate::protocol::ProtocolHandler;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+/// Whether an RPC may be sent more than once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idempotency {
+    /// Repeating the call has the same effect as sending it once
+    Idempotent,
+    /// The call changes server state and must not be repeated blindly
+    NonIdempotent,
+}
+
+/// Control channel RPCs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RpcMethod {
+    /// HTTP watermark handshake (`/vpnsvc/connect.cgi`)
+    Watermark,
+    /// IP configuration request
+    GetConfig,
+    /// HTTP keepalive (`/vpnsvc/keepalive.cgi`)
+    Keepalive,
+    /// PACK login / authentication
+    Login,
+    /// Pre-login list of the server's virtual hubs
+    EnumHub,
+    /// Pre-login list of the server's capabilities
+    GetCaps,
+    /// Password change, which needs no login
+    ChangePassword,
+}
+
+impl RpcMethod {
+    /// Method name as used in logs
+    pub fn name(self) -> &'static str {
+        match self {
+            RpcMethod::Watermark => "watermark",
+            RpcMethod::GetConfig => "GetConfig",
+            RpcMethod::Keepalive => "keepalive",
+            RpcMethod::Login => "login",
+            RpcMethod::EnumHub => "enum_hub",
+            RpcMethod::GetCaps => "get_caps",
+            RpcMethod::ChangePassword => "password",
+        }
+    }
+
+    pub fn idempotency(self) -> Idempotency {
+        match self {
+            RpcMethod::Watermark
+            | RpcMethod::GetConfig
+            | RpcMethod::Keepalive
+            | RpcMethod::EnumHub
+            | RpcMethod::GetCaps => Idempotency::Idempotent,
+            // Repeated after it went through, a password change fails on the old password
+            RpcMethod::Login | RpcMethod::ChangePassword => Idempotency::NonIdempotent,
+        }
+    }
+}
+
+/// Retry policy for idempotent RPCs
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Upper bound for a single delay
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Full-jitter backoff: a random delay up to `base * 2^retry`, capped
+    fn delay(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .base_delay
+            .saturating_mul(1u32 << retry.min(16))
+            .min(self.max_delay);
+        ceiling.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Whether an error is a transient transport failure worth retrying
+pub fn is_transient(error: &VpnError) -> bool {
+    match error {
+        VpnError::Network(_) | VpnError::Timeout(_) => true,
+        VpnError::Io(e) => matches!(
+            e.kind(),
+            ErrorKind::ConnectionReset
+                | ErrorKind::ConnectionAborted
+                | ErrorKind::BrokenPipe
+                | ErrorKind::TimedOut
+                | ErrorKind::Interrupted
+                | ErrorKind::UnexpectedEof
+        ),
+        _ => false,
+    }
+}
+
+/// Run `call`, transparently retrying it if `method` is idempotent
+pub async fn call_with_retry<T, F, Fut>(method: RpcMethod, policy: &RetryPolicy, mut call: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let max_attempts = match method.idempotency() {
+        Idempotency::Idempotent => policy.max_attempts.max(1),
+        Idempotency::NonIdempotent => 1,
+    };
+
+    let mut attempt = 1;
+    loop {
+        match call().await {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < max_attempts && is_transient(&e) => {
+                let delay = policy.delay(attempt - 1);
+                log::debug!(
+                    "{} RPC failed (attempt {}/{}): {} - retrying in {:?}",
+                    method.name(),
+                    attempt,
+                    max_attempts,
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A virtual hub listed by the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HubSummary {
+    pub name: String,
+    /// Whether the hub accepts sessions
+    pub online: bool,
+}
+
+/// What the server tells about itself before login
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ServerInfo {
+    /// Product string from the hello, e.g. `SoftEther VPN Server (64 bit)`
+    pub product: String,
+    /// Version times 100, e.g. 438 for 4.38
+    pub version: u32,
+    pub build: u32,
+    /// Virtual hubs, `None` if the server does not enumerate them
+    pub hubs: Option<Vec<HubSummary>>,
+    /// Capabilities by name (`b_` flags are 0 or 1, `i_` entries are limits)
+    pub capabilities: BTreeMap<String, u32>,
+}
+
+impl ServerInfo {
+    /// Product and version from the server hello
+    pub fn from_hello(hello: &Pack) -> Self {
+        Self {
+            product: hello.get_str("hello").cloned().unwrap_or_default(),
+            version: hello.get_int("version").unwrap_or(0),
+            build: hello.get_int("build").unwrap_or(0),
+            ..Self::default()
+        }
+    }
+
+    /// Version as printed by SoftEther, e.g. `4.38 build 9760`
+    pub fn version_string(&self) -> String {
+        format!("{}.{:02} build {}", self.version / 100, self.version % 100, self.build)
+    }
+
+    /// Whether the server has capability `name` set to a non-zero value
+    pub fn has_capability(&self, name: &str) -> bool {
+        self.capabilities.get(name).is_some_and(|&value| value != 0)
+    }
+
+    /// Fail if the server lists its hubs and `hub` is not among them
+    ///
+    /// Hub names compare case-insensitively, as on the server.
+    pub fn check_hub(&self, hub: &str) -> Result<()> {
+        let Some(ref hubs) = self.hubs else {
+            return Ok(());
+        };
+        if hubs.iter().any(|h| h.name.eq_ignore_ascii_case(hub)) {
+            return Ok(());
+        }
+        let names: Vec<&str> = hubs.iter().map(|h| h.name.as_str()).collect();
+        Err(VpnError::ServerRefused {
+            code: super::auth::ERR_HUB_NOT_FOUND,
+            message: format!("Hub '{hub}' does not exist on the server (it has: {})", names.join(", ")),
+        })
+    }
+}
+
+/// Send the pre-login request for `method`, retried like any idempotent RPC
+async fn call(handler: &ProtocolHandler, method: RpcMethod) -> Result<Pack> {
+    let mut request = Pack::new();
+    request.add_str("method", method.name());
+    call_with_retry(method, &RetryPolicy::default(), || handler.send_pack(&request)).await
+}
+
+/// A non-zero `error` in a response as [`VpnError::ServerRefused`]
+fn check_error(response: &Pack) -> Result<()> {
+    match response.get_int("error") {
+        Some(code) if code != 0 => {
+            Err(VpnError::ServerRefused { code, message: ErrorCode::from_server(code).to_string() })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Hubs from an `enum_hub` response: `HubName` and `Online` arrays
+pub fn parse_hub_list(response: &Pack) -> Result<Vec<HubSummary>> {
+    check_error(response)?;
+    let names = response.get_str_array("HubName")?;
+    let online = response.get_int_array("Online")?;
+    Ok(names
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| HubSummary { name, online: online.get(i).is_none_or(|&flag| flag != 0) })
+        .collect())
+}
+
+/// Capabilities from a `get_caps` response: one integer element per capability
+pub fn parse_capabilities(response: &Pack) -> Result<BTreeMap<String, u32>> {
+    check_error(response)?;
+    Ok(response
+        .elements
+        .iter()
+        .filter(|e| e.name.starts_with("b_") || e.name.starts_with("i_"))
+        .filter_map(|e| match e.values.first() {
+            Some(Value::Int(value)) => Some((e.name.clone(), *value)),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Learn what the server tells before login over an established `handler`
+///
+/// A server refusing to list hubs or capabilities leaves them unknown; only
+/// the version from the hello is always there.
+pub async fn query_server_info(handler: &ProtocolHandler) -> ServerInfo {
+    let mut info = handler.server_hello().map(ServerInfo::from_hello).unwrap_or_default();
+    match call(handler, RpcMethod::EnumHub).await.and_then(|response| parse_hub_list(&response)) {
+        Ok(hubs) => info.hubs = Some(hubs),
+        Err(e) => log::debug!("Server does not enumerate hubs: {}", e),
+    }
+    match call(handler, RpcMethod::GetCaps).await.and_then(|response| parse_capabilities(&response)) {
+        Ok(capabilities) => info.capabilities = capabilities,
+        Err(e) => log::debug!("Server does not report capabilities: {}", e),
+    }
+    log::info!(
+        product = info.product.as_str(), hubs:? = info.hubs.as_ref().map(Vec::len);
+        "Server {}", info.version_string()
+    );
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_rpc_is_retried() {
+        let calls = Cell::new(0);
+        let result = call_with_retry(RpcMethod::Keepalive, &fast_policy(), || {
+            calls.set(calls.get() + 1);
+            let n = calls.get();
+            async move {
+                if n < 3 {
+                    Err(VpnError::Network("connection reset".into()))
+                } else {
+                    Ok(n)
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_login_is_never_retried() {
+        let calls = Cell::new(0);
+        let result: Result<()> = call_with_retry(RpcMethod::Login, &fast_policy(), || {
+            calls.set(calls.get() + 1);
+            async { Err(VpnError::Network("connection reset".into())) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_are_not_retried() {
+        let calls = Cell::new(0);
+        let result: Result<()> = call_with_retry(RpcMethod::GetConfig, &fast_policy(), || {
+            calls.set(calls.get() + 1);
+            async { Err(VpnError::Protocol("HTTP 403".into())) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_server_info_from_hello() {
+        let mut hello = Pack::new();
+        hello.add_str("hello", "SoftEther VPN Server (64 bit)");
+        hello.add_int("version", 438);
+        hello.add_int("build", 9760);
+        let info = ServerInfo::from_hello(&hello);
+        assert_eq!(info.product, "SoftEther VPN Server (64 bit)");
+        assert_eq!(info.version_string(), "4.38 build 9760");
+        assert_eq!(info.hubs, None);
+        // Without a hub list nothing can be checked
+        assert!(info.check_hub("ANY").is_ok());
+    }
+
+    #[test]
+    fn test_parse_hub_list() {
+        let mut response = Pack::new();
+        response.add_element(crate::protocol::Element::new_array(
+            "HubName".to_string(),
+            vec![Value::Str("VPN".to_string()), Value::Str("Office".to_string())],
+        ));
+        response.add_int_array("Online", vec![1, 0]);
+        let hubs = parse_hub_list(&response).unwrap();
+        assert_eq!(hubs[1], HubSummary { name: "Office".to_string(), online: false });
+
+        let info = ServerInfo { hubs: Some(hubs), ..ServerInfo::default() };
+        assert!(info.check_hub("vpn").is_ok());
+        let err = info.check_hub("Lab").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::HubNotFound);
+        assert!(err.to_string().contains("VPN, Office"), "{err}");
+
+        let mut refused = Pack::new();
+        refused.add_int("error", 12);
+        assert_eq!(parse_hub_list(&refused).unwrap_err().code(), ErrorCode::AccessDenied);
+    }
+
+    #[test]
+    fn test_parse_capabilities() {
+        let mut response = Pack::new();
+        response.add_int("b_support_udp_acceleration", 1);
+        response.add_int("b_support_bridge", 0);
+        response.add_int("i_max_sessions", 4096);
+        response.add_str("b_not_an_int", "x");
+        response.add_int("error", 0);
+        let info = ServerInfo { capabilities: parse_capabilities(&response).unwrap(), ..ServerInfo::default() };
+        assert_eq!(info.capabilities.len(), 3);
+        assert!(info.has_capability("b_support_udp_acceleration"));
+        assert!(!info.has_capability("b_support_bridge"));
+        assert_eq!(info.capabilities["i_max_sessions"], 4096);
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = RetryPolicy::default();
+        for retry in 0..20 {
+            assert!(policy.delay(retry) <= policy.max_delay);
+        }
+    }
+}
@@ -1,6 +1,12 @@
 //! Session management for `SoftEther` SSL-VPN protocol
+//!
+//! The server announces in its welcome how long it keeps a session without
+//! hearing from the client (`timeout`, 20 seconds by default). [`SessionManager`]
+//! spaces keepalives so that one always goes out well inside that window,
+//! tracks when the server was last heard from, and reports the session as
+//! expired once the announced timeout has passed without a word from it.
 
-use crate::config::Config;
+use crate::config::SessionTimeouts;
 use crate::error::{Result, VpnError};
 use std::time::{Duration, Instant};
 use uuid::Uuid;
@@ -12,42 +18,120 @@ pub struct SessionManager {
     session_id: Option<Uuid>,
     start_time: Option<Instant>,
     last_keepalive: Option<Instant>,
-    #[allow(dead_code)]
-    config: Config,
+    /// Last time anything arrived from the server
+    last_activity: Option<Instant>,
+    /// Inbound packet counter when last observed
+    received: u64,
+    keepalive_interval: Duration,
+    /// Session timeout announced by the server
+    server_timeout: Option<Duration>,
 }
 
 impl SessionManager {
-    /// Create a new session manager
-    pub fn new(config: &Config) -> Result<Self> {
-        Ok(Self {
+    /// Create a session manager sending keepalives at `timeouts.keepalive_interval`
+    pub fn new(timeouts: &SessionTimeouts) -> Self {
+        Self {
             session_id: None,
             start_time: None,
             last_keepalive: None,
-            config: config.clone(),
-        })
+            last_activity: None,
+            received: 0,
+            keepalive_interval: timeouts.keepalive_interval,
+            server_timeout: None,
+        }
     }
 
     /// Start a new VPN session
     pub fn start_session(&mut self) -> Result<Uuid> {
         let session_id = Uuid::new_v4();
+        let now = Instant::now();
         self.session_id = Some(session_id);
-        self.start_time = Some(Instant::now());
-        self.last_keepalive = Some(Instant::now());
+        self.start_time = Some(now);
+        self.last_keepalive = Some(now);
+        self.last_activity = Some(now);
 
         Ok(session_id)
     }
 
-    /// Send keepalive packet
-    pub fn send_keepalive(&mut self) -> Result<()> {
+    /// Use the session timeout from the server's welcome (`None` if it gave none)
+    pub fn set_server_timeout(&mut self, timeout: Option<Duration>) {
+        self.server_timeout = timeout.filter(|timeout| !timeout.is_zero());
+    }
+
+    pub fn server_timeout(&self) -> Option<Duration> {
+        self.server_timeout
+    }
+
+    /// Time between keepalives
+    ///
+    /// The configured interval, shortened to half the server's session timeout
+    /// so that a keepalive refreshes the session before the server drops it.
+    pub fn keepalive_interval(&self) -> Duration {
+        match self.server_timeout {
+            Some(timeout) => self.keepalive_interval.min(timeout / 2),
+            None => self.keepalive_interval,
+        }
+    }
+
+    /// When the next keepalive is due, `None` without a session
+    pub fn next_keepalive(&self) -> Option<Instant> {
+        self.last_keepalive.map(|last| last + self.keepalive_interval())
+    }
+
+    pub fn keepalive_due(&self, now: Instant) -> bool {
+        self.next_keepalive().is_some_and(|due| now >= due)
+    }
+
+    /// Record a keepalive sent at `now`
+    pub fn keepalive_sent(&mut self, now: Instant) -> Result<()> {
         if self.session_id.is_none() {
             return Err(VpnError::Connection("No active session".to_string()));
         }
+        self.last_keepalive = Some(now);
+        Ok(())
+    }
+
+    /// Note that the server was heard from at `now`; an earlier time than already noted is ignored
+    pub fn record_activity(&mut self, now: Instant) {
+        if self.session_id.is_some() {
+            self.last_activity = Some(self.last_activity.map_or(now, |last| last.max(now)));
+        }
+    }
 
-        // In a real implementation, this would send a keepalive packet
-        // to the `SoftEther` server to maintain the session
+    /// Record the inbound packet counter, as activity when it moved
+    pub fn observe_received(&mut self, received: u64, now: Instant) {
+        if received != self.received {
+            self.received = received;
+            self.record_activity(now);
+        }
+    }
 
-        self.last_keepalive = Some(Instant::now());
-        Ok(())
+    /// Time since the server was last heard from
+    pub fn time_since_activity(&self, now: Instant) -> Option<Duration> {
+        self.last_activity.map(|last| now.saturating_duration_since(last))
+    }
+
+    /// Time left before the server's session timeout runs out
+    ///
+    /// `None` without a session or when the server announced no timeout.
+    pub fn session_remaining(&self) -> Option<Duration> {
+        self.remaining_at(Instant::now())
+    }
+
+    fn remaining_at(&self, now: Instant) -> Option<Duration> {
+        let quiet = self.time_since_activity(now)?;
+        self.server_timeout.map(|timeout| timeout.saturating_sub(quiet))
+    }
+
+    /// Fail with [`VpnError::Timeout`] once the session timeout has run out
+    pub fn check_expiry(&self, now: Instant) -> Result<()> {
+        match (self.remaining_at(now), self.server_timeout) {
+            (Some(remaining), Some(timeout)) if remaining.is_zero() => Err(VpnError::Timeout(format!(
+                "Session expired: nothing heard from the server within its {}s session timeout",
+                timeout.as_secs()
+            ))),
+            _ => Ok(()),
+        }
     }
 
     /// Check if session is active
@@ -75,5 +159,73 @@ impl SessionManager {
         self.session_id = None;
         self.start_time = None;
         self.last_keepalive = None;
+        self.last_activity = None;
+        self.received = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(keepalive_secs: u64) -> SessionManager {
+        let timeouts = SessionTimeouts {
+            connect: Duration::from_secs(30),
+            keepalive_interval: Duration::from_secs(keepalive_secs),
+            idle: None,
+            dead_peer: None,
+        };
+        SessionManager::new(&timeouts)
+    }
+
+    #[test]
+    fn test_keepalive_refreshes_before_server_timeout() {
+        let mut session = manager(50);
+        assert_eq!(session.next_keepalive(), None);
+        assert!(session.keepalive_sent(Instant::now()).is_err());
+
+        session.start_session().unwrap();
+        assert_eq!(session.keepalive_interval(), Duration::from_secs(50));
+        // A 20 s server timeout needs a keepalive every 10 s
+        session.set_server_timeout(Some(Duration::from_secs(20)));
+        assert_eq!(session.keepalive_interval(), Duration::from_secs(10));
+        session.set_server_timeout(Some(Duration::ZERO));
+        assert_eq!(session.server_timeout(), None);
+        session.set_server_timeout(Some(Duration::from_secs(20)));
+
+        let start = Instant::now();
+        session.keepalive_sent(start).unwrap();
+        assert!(!session.keepalive_due(start + Duration::from_secs(9)));
+        assert!(session.keepalive_due(start + Duration::from_secs(10)));
+        session.keepalive_sent(start + Duration::from_secs(10)).unwrap();
+        assert_eq!(session.next_keepalive(), Some(start + Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn test_session_expires_without_activity() {
+        let mut session = manager(5);
+        session.start_session().unwrap();
+        let start = Instant::now();
+        session.record_activity(start);
+        // Without an announced timeout the session never expires
+        assert_eq!(session.remaining_at(start + Duration::from_secs(3600)), None);
+        assert!(session.check_expiry(start + Duration::from_secs(3600)).is_ok());
+
+        session.set_server_timeout(Some(Duration::from_secs(20)));
+        assert_eq!(session.remaining_at(start + Duration::from_secs(5)), Some(Duration::from_secs(15)));
+        // Only a moving packet counter counts as activity
+        session.observe_received(0, start + Duration::from_secs(15));
+        assert_eq!(session.remaining_at(start + Duration::from_secs(15)), Some(Duration::from_secs(5)));
+        session.observe_received(3, start + Duration::from_secs(15));
+        assert_eq!(session.remaining_at(start + Duration::from_secs(15)), Some(Duration::from_secs(20)));
+
+        let late = start + Duration::from_secs(35);
+        assert_eq!(session.remaining_at(late), Some(Duration::ZERO));
+        let err = session.check_expiry(late).unwrap_err();
+        assert!(matches!(err, VpnError::Timeout(_)), "{err}");
+
+        session.end_session();
+        assert_eq!(session.remaining_at(late), None);
+        assert!(session.check_expiry(late).is_ok());
     }
 }
@@ -0,0 +1,520 @@
+//! UDP acceleration (SoftEther R-UDP data path)
+//!
+//! SoftEther servers can carry tunnel frames over UDP once the TLS session is
+//! up. The client offers a UDP endpoint and a key in the login PACK; a server
+//! that accepts answers with its own endpoint, key and a pair of cookies.
+//! Frames are then sealed with ChaCha20-Poly1305 (acceleration version 2) and
+//! sent directly between the two UDP sockets:
+//!
+//! ```text
+//! nonce (12) | sealed[ cookie (4) | my tick (8) | your tick (8) | size (2) | flag (1) | data ] | tag (16)
+//! ```
+//!
+//! The path only carries data while the peer has been heard from recently;
+//! empty frames act as keepalives, sent by a [`NatKeepalive`] whenever the
+//! socket idles. Everything else stays on TLS, which is also where traffic
+//! goes whenever the UDP path goes quiet.
+
+use crate::error::{Result, VpnError};
+use crate::protocol::nat_keepalive::{NatKeepalive, NatKeepaliveConfig, NatKeepaliveStats};
+use crate::protocol::pack::Pack;
+use crate::underlay::UnderlayBinding;
+use bytes::Bytes;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+#[cfg(all(feature = "ring-crypto", not(feature = "aws-lc-crypto")))]
+use ring::{aead, rand::{self, SecureRandom}};
+
+#[cfg(all(feature = "aws-lc-crypto", not(feature = "ring-crypto")))]
+use aws_lc_rs::{aead, rand::{self, SecureRandom}};
+
+#[cfg(all(feature = "ring-crypto", feature = "aws-lc-crypto"))]
+use ring::{aead, rand::{self, SecureRandom}};
+
+/// Acceleration protocol version offered (2 = ChaCha20-Poly1305)
+pub const UDP_ACCEL_VERSION: u32 = 2;
+
+/// Size of the version 2 keys exchanged in the login PACK
+pub const UDP_ACCEL_KEY_SIZE: usize = 128;
+
+/// Size of the legacy version 1 key, still sent for older servers to ignore
+const UDP_ACCEL_KEY_SIZE_V1: usize = 20;
+
+/// Shortest idle time before the path sends an empty frame
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The path is abandoned for TLS once nothing was received for this long
+pub const LIVENESS_TIMEOUT: Duration = Duration::from_secs(9);
+
+/// How long a keepalive waits for the peer; servers send their own at least every 3 seconds
+const KEEPALIVE_ANSWER_TIMEOUT: Duration = Duration::from_secs(3);
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const HEADER_LEN: usize = 4 + 8 + 8 + 2 + 1;
+
+/// Largest datagram accepted on the UDP path
+const MAX_DATAGRAM: usize = 2048;
+
+/// Transport currently carrying tunnel frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataPath {
+    /// Frames go through the TLS session
+    Tls,
+    /// Frames go through the UDP acceleration path
+    Udp,
+}
+
+/// Client side of the negotiation: a bound socket and our key
+pub struct UdpAccelOffer {
+    socket: UdpSocket,
+    key: [u8; UDP_ACCEL_KEY_SIZE],
+}
+
+impl UdpAccelOffer {
+    /// Bind a UDP socket on an ephemeral port and generate a key
+    pub async fn bind() -> Result<Self> {
+        Self::bind_on(None).await
+    }
+
+    /// Like [`bind`](Self::bind), with the socket bound to the `underlay` uplink
+    pub async fn bind_on(underlay: Option<&UnderlayBinding>) -> Result<Self> {
+        let address = underlay.map_or(Ipv4Addr::UNSPECIFIED, |u| u.address);
+        let socket = UdpSocket::bind((address, 0))
+            .await
+            .map_err(|e| VpnError::Network(format!("Failed to bind UDP acceleration socket: {e}")))?;
+        #[cfg(target_os = "linux")]
+        if let Some(underlay) = underlay {
+            // The source address alone does not pin the egress interface on Linux
+            if let Err(e) = socket.bind_device(Some(underlay.interface.as_bytes())) {
+                log::warn!("UDP acceleration socket not bound to {}: {}", underlay.interface, e);
+            }
+        }
+        let mut key = [0u8; UDP_ACCEL_KEY_SIZE];
+        fill_random(&mut key)?;
+        Ok(Self { socket, key })
+    }
+
+    /// Mark the socket with `mark` (`SO_MARK`, Linux) so policy routing keeps it off the tunnel
+    pub fn with_socket_mark(self, mark: Option<u32>) -> Result<Self> {
+        #[cfg(target_os = "linux")]
+        if let Some(mark) = mark {
+            crate::underlay::set_socket_mark(&self.socket, mark)
+                .map_err(|e| VpnError::Network(format!("Failed to mark UDP acceleration socket: {e}")))?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = mark;
+        Ok(self)
+    }
+
+    /// Local UDP port announced to the server
+    pub fn local_port(&self) -> u16 {
+        self.socket.local_addr().map(|addr| addr.port()).unwrap_or(0)
+    }
+
+    /// Add the acceleration request to the login PACK
+    pub fn apply_to_pack(&self, pack: &mut Pack) {
+        pack.add_int("use_udp_acceleration", 1);
+        pack.add_int("udp_acceleration_version", UDP_ACCEL_VERSION);
+        // 0.0.0.0 asks the server to use the address it sees the datagrams from
+        pack.add_ip("udp_acceleration_client_ip", IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        pack.add_int("udp_acceleration_client_port", u32::from(self.local_port()));
+        pack.add_data("udp_acceleration_client_key", self.key[..UDP_ACCEL_KEY_SIZE_V1].to_vec());
+        pack.add_data("udp_acceleration_client_key_v2", self.key.to_vec());
+    }
+
+    /// Turn the offer into a live session with the server's answer
+    pub async fn accept(self, grant: UdpAccelGrant) -> Result<UdpAccelSession> {
+        self.socket
+            .connect(grant.server_addr)
+            .await
+            .map_err(|e| VpnError::Network(format!("Failed to connect UDP acceleration socket: {e}")))?;
+        UdpAccelSession::new(self.socket, &self.key, &grant.server_key, grant.client_cookie, grant.server_cookie)
+    }
+}
+
+impl std::fmt::Debug for UdpAccelOffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpAccelOffer").field("local_port", &self.local_port()).finish_non_exhaustive()
+    }
+}
+
+/// Server answer to an acceleration offer
+#[derive(Clone)]
+pub struct UdpAccelGrant {
+    /// Where to send datagrams
+    pub server_addr: SocketAddr,
+    server_key: Vec<u8>,
+    /// Cookie the server expects in our frames
+    server_cookie: u32,
+    /// Cookie the server puts in its frames
+    client_cookie: u32,
+}
+
+impl UdpAccelGrant {
+    /// Read the server's answer from the login response
+    ///
+    /// Returns `None` when the server declined, answered with another version
+    /// or left out a field; the session then stays on TLS. A server address of
+    /// 0.0.0.0 means "same host as the TLS connection" (`server_ip`).
+    pub fn from_pack(pack: &Pack, server_ip: IpAddr) -> Option<Self> {
+        if pack.get_int("use_udp_acceleration")? == 0 {
+            return None;
+        }
+        if pack.get_int("udp_acceleration_version").unwrap_or(1) != UDP_ACCEL_VERSION {
+            return None;
+        }
+
+        let server_key = pack.get_data("udp_acceleration_server_key_v2")?;
+        if server_key.len() != UDP_ACCEL_KEY_SIZE {
+            return None;
+        }
+        let port = u16::try_from(pack.get_int("udp_acceleration_server_port")?).ok().filter(|&p| p != 0)?;
+        let ip = pack
+            .get_ip_list("udp_acceleration_server_ip")
+            .ok()
+            .and_then(|ips| ips.into_iter().next())
+            .filter(|ip| !ip.is_unspecified())
+            .unwrap_or(server_ip);
+
+        Some(Self {
+            server_addr: SocketAddr::new(ip, port),
+            server_key: server_key.clone(),
+            server_cookie: pack.get_int("udp_acceleration_server_cookie")?,
+            client_cookie: pack.get_int("udp_acceleration_client_cookie")?,
+        })
+    }
+}
+
+impl std::fmt::Debug for UdpAccelGrant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpAccelGrant").field("server_addr", &self.server_addr).finish_non_exhaustive()
+    }
+}
+
+/// Established UDP acceleration path
+pub struct UdpAccelSession {
+    socket: UdpSocket,
+    seal_key: aead::LessSafeKey,
+    open_key: aead::LessSafeKey,
+    /// Cookie expected in received frames
+    my_cookie: u32,
+    /// Cookie written into sent frames
+    your_cookie: u32,
+    started: Instant,
+    /// Latest tick received from the peer, echoed back in our frames
+    peer_tick: AtomicU64,
+    last_received: Mutex<Option<Instant>>,
+    nat_keepalive: Mutex<NatKeepalive>,
+}
+
+impl UdpAccelSession {
+    fn new(socket: UdpSocket, my_key: &[u8], your_key: &[u8], my_cookie: u32, your_cookie: u32) -> Result<Self> {
+        Ok(Self {
+            socket,
+            seal_key: chacha_key(my_key)?,
+            open_key: chacha_key(your_key)?,
+            my_cookie,
+            your_cookie,
+            started: Instant::now(),
+            peer_tick: AtomicU64::new(0),
+            last_received: Mutex::new(None),
+            nat_keepalive: Mutex::new(NatKeepalive::new(Self::nat_keepalive_config())),
+        })
+    }
+
+    /// The NAT keepalive searches between [`KEEPALIVE_INTERVAL`] and what the peer's [`LIVENESS_TIMEOUT`] allows
+    fn nat_keepalive_config() -> NatKeepaliveConfig {
+        NatKeepaliveConfig {
+            min_interval: KEEPALIVE_INTERVAL,
+            max_interval: LIVENESS_TIMEOUT - KEEPALIVE_ANSWER_TIMEOUT,
+            resolution: Duration::from_secs(1),
+            response_timeout: KEEPALIVE_ANSWER_TIMEOUT,
+            ..NatKeepaliveConfig::default()
+        }
+    }
+
+    /// Keepalive interval in use and the NAT mapping timeout detected so far
+    pub fn nat_keepalive_stats(&self) -> NatKeepaliveStats {
+        self.nat_keepalive.lock().unwrap().stats()
+    }
+
+    /// Whether the peer was heard from within [`LIVENESS_TIMEOUT`]
+    pub fn is_usable(&self) -> bool {
+        self.last_received.lock().unwrap().is_some_and(|at| at.elapsed() < LIVENESS_TIMEOUT)
+    }
+
+    /// Send one tunnel frame over UDP
+    pub async fn send(&self, data: &[u8]) -> Result<()> {
+        let datagram = self.seal(data)?;
+        self.socket
+            .send(&datagram)
+            .await
+            .map_err(|e| VpnError::Network(format!("UDP acceleration send failed: {e}")))?;
+        self.nat_keepalive.lock().unwrap().on_activity();
+        Ok(())
+    }
+
+    /// Send an empty frame to keep the path (and NAT mappings) alive
+    pub async fn send_keepalive(&self) -> Result<()> {
+        self.send(&[]).await
+    }
+
+    /// Receive the next tunnel frame
+    ///
+    /// Keepalives and datagrams that fail authentication are consumed
+    /// silently (the latter are most likely stray or spoofed).
+    pub async fn recv(&self) -> Result<Bytes> {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        loop {
+            let len = self
+                .socket
+                .recv(&mut buf)
+                .await
+                .map_err(|e| VpnError::Network(format!("UDP acceleration receive failed: {e}")))?;
+            match self.open(&mut buf[..len]) {
+                Some(frame) if frame.is_empty() => continue,
+                Some(frame) => return Ok(frame),
+                None => log::debug!("Dropped invalid UDP acceleration datagram ({len} bytes)"),
+            }
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        // Never 0: SoftEther treats a zero tick as "unknown"
+        self.started.elapsed().as_millis() as u64 + 1
+    }
+
+    fn seal(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let size = u16::try_from(data.len())
+            .map_err(|_| VpnError::PacketError(format!("Frame of {} bytes too large for UDP", data.len())))?;
+
+        let mut nonce = [0u8; NONCE_LEN];
+        fill_random(&mut nonce)?;
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + data.len() + TAG_LEN);
+        sealed.extend_from_slice(&self.your_cookie.to_be_bytes());
+        sealed.extend_from_slice(&self.tick().to_be_bytes());
+        sealed.extend_from_slice(&self.peer_tick.load(Ordering::Relaxed).to_be_bytes());
+        sealed.extend_from_slice(&size.to_be_bytes());
+        sealed.push(0); // not compressed
+        sealed.extend_from_slice(data);
+        self.seal_key
+            .seal_in_place_append_tag(aead::Nonce::assume_unique_for_key(nonce), aead::Aad::empty(), &mut sealed)
+            .map_err(|_| VpnError::Crypto("UDP acceleration encryption failed".into()))?;
+
+        let mut datagram = nonce.to_vec();
+        datagram.extend_from_slice(&sealed);
+        Ok(datagram)
+    }
+
+    fn open(&self, datagram: &mut [u8]) -> Option<Bytes> {
+        if datagram.len() < NONCE_LEN + HEADER_LEN + TAG_LEN {
+            return None;
+        }
+        let (nonce, sealed) = datagram.split_at_mut(NONCE_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let plain = self.open_key.open_in_place(nonce, aead::Aad::empty(), sealed).ok()?;
+
+        let cookie = u32::from_be_bytes(plain[0..4].try_into().ok()?);
+        if cookie != self.my_cookie {
+            return None;
+        }
+        let tick = u64::from_be_bytes(plain[4..12].try_into().ok()?);
+        let size = usize::from(u16::from_be_bytes(plain[20..22].try_into().ok()?));
+        let data = plain.get(HEADER_LEN..HEADER_LEN + size)?;
+
+        self.peer_tick.fetch_max(tick, Ordering::Relaxed);
+        *self.last_received.lock().unwrap() = Some(Instant::now());
+        self.nat_keepalive.lock().unwrap().on_received();
+        Some(Bytes::copy_from_slice(data))
+    }
+}
+
+impl std::fmt::Debug for UdpAccelSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UdpAccelSession")
+            .field("peer", &self.socket.peer_addr().ok())
+            .field("usable", &self.is_usable())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Keep the path alive and forward received frames until the channel closes
+///
+/// The returned task sends a keepalive whenever the session's NAT keepalive
+/// asks for one and pushes every received frame into the channel. Abort it
+/// (or drop the receiver) to stop.
+pub fn spawn_pump(session: Arc<UdpAccelSession>) -> (mpsc::Receiver<Bytes>, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel(256);
+    let task = tokio::spawn(async move {
+        loop {
+            let deadline = session.nat_keepalive.lock().unwrap().deadline();
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline.into()) => {
+                    let due = session.nat_keepalive.lock().unwrap().poll();
+                    if due {
+                        if let Err(e) = session.send_keepalive().await {
+                            log::debug!("{e}");
+                        }
+                    }
+                }
+                frame = session.recv() => match frame {
+                    Ok(frame) => {
+                        if tx.send(frame).await.is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => {
+                        log::warn!("{e}");
+                        return;
+                    }
+                },
+            }
+        }
+    });
+    (rx, task)
+}
+
+fn chacha_key(key: &[u8]) -> Result<aead::LessSafeKey> {
+    let key = key
+        .get(..32)
+        .ok_or_else(|| VpnError::Crypto("UDP acceleration key too short".into()))?;
+    let key = aead::UnboundKey::new(&aead::CHACHA20_POLY1305, key)
+        .map_err(|_| VpnError::Crypto("Invalid UDP acceleration key".into()))?;
+    Ok(aead::LessSafeKey::new(key))
+}
+
+fn fill_random(buf: &mut [u8]) -> Result<()> {
+    rand::SystemRandom::new()
+        .fill(buf)
+        .map_err(|_| VpnError::Crypto("Random number generation failed".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Client and server sessions wired to each other over loopback
+    async fn session_pair() -> (UdpAccelSession, UdpAccelSession) {
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client_socket.connect(server_socket.local_addr().unwrap()).await.unwrap();
+        server_socket.connect(client_socket.local_addr().unwrap()).await.unwrap();
+
+        let client_key = [1u8; UDP_ACCEL_KEY_SIZE];
+        let server_key = [2u8; UDP_ACCEL_KEY_SIZE];
+        let client = UdpAccelSession::new(client_socket, &client_key, &server_key, 0x1111, 0x2222).unwrap();
+        let server = UdpAccelSession::new(server_socket, &server_key, &client_key, 0x2222, 0x1111).unwrap();
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn test_frames_round_trip_and_liveness() {
+        let (client, server) = session_pair().await;
+        assert!(!client.is_usable());
+
+        // Keepalives make the path usable without surfacing as frames
+        server.send_keepalive().await.unwrap();
+        server.send(b"frame from hub").await.unwrap();
+        assert_eq!(client.recv().await.unwrap(), Bytes::from_static(b"frame from hub"));
+        assert!(client.is_usable());
+        assert!(client.peer_tick.load(Ordering::Relaxed) > 0);
+
+        client.send(b"frame to hub").await.unwrap();
+        assert_eq!(server.recv().await.unwrap(), Bytes::from_static(b"frame to hub"));
+    }
+
+    #[tokio::test]
+    async fn test_pump_sends_nat_keepalives_on_idle_path() {
+        let (client, server) = session_pair().await;
+        let (client, server) = (Arc::new(client), Arc::new(server));
+        let (_frames, pump) = spawn_pump(client.clone());
+        let listener = tokio::spawn({
+            let server = server.clone();
+            async move { server.recv().await }
+        });
+
+        // The idle client sends an empty frame once the first probe interval is up
+        tokio::time::timeout(LIVENESS_TIMEOUT, async {
+            while !server.is_usable() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        server.send_keepalive().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while client.nat_keepalive_stats().keepalives_sent == 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Answered, so the next probe idles longer
+        let stats = client.nat_keepalive_stats();
+        assert_eq!(stats.keepalives_lost, 0);
+        assert!(stats.current_interval > KEEPALIVE_INTERVAL && stats.current_interval < LIVENESS_TIMEOUT);
+        pump.abort();
+        listener.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rejects_tampered_and_foreign_frames() {
+        let (client, server) = session_pair().await;
+
+        let mut tampered = server.seal(b"payload").unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0xff;
+        assert!(client.open(&mut tampered).is_none());
+
+        // Valid encryption, wrong cookie
+        let impostor = UdpAccelSession::new(
+            UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            &[2u8; UDP_ACCEL_KEY_SIZE],
+            &[1u8; UDP_ACCEL_KEY_SIZE],
+            0x2222,
+            0x9999,
+        )
+        .unwrap();
+        let mut foreign = impostor.seal(b"payload").unwrap();
+        assert!(client.open(&mut foreign).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_negotiation_packs() {
+        let offer = UdpAccelOffer::bind().await.unwrap();
+        let mut request = Pack::new();
+        offer.apply_to_pack(&mut request);
+        assert_eq!(request.get_int("use_udp_acceleration"), Some(1));
+        assert_eq!(request.get_int("udp_acceleration_client_port"), Some(u32::from(offer.local_port())));
+        assert_eq!(request.get_data("udp_acceleration_client_key_v2").map(Vec::len), Some(UDP_ACCEL_KEY_SIZE));
+
+        let hub = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let mut response = Pack::new();
+        response.add_int("use_udp_acceleration", 1);
+        response.add_int("udp_acceleration_version", 2);
+        response.add_ip("udp_acceleration_server_ip", IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        response.add_int("udp_acceleration_server_port", 40000);
+        response.add_data("udp_acceleration_server_key_v2", vec![7u8; UDP_ACCEL_KEY_SIZE]);
+        response.add_int("udp_acceleration_server_cookie", 5);
+        response.add_int("udp_acceleration_client_cookie", 6);
+        let grant = UdpAccelGrant::from_pack(&response, hub).unwrap();
+        assert_eq!(grant.server_addr, SocketAddr::new(hub, 40000));
+        assert_eq!((grant.server_cookie, grant.client_cookie), (5, 6));
+
+        // Version 1 only servers keep the session on TLS
+        let mut v1 = Pack::new();
+        v1.add_int("use_udp_acceleration", 1);
+        v1.add_int("udp_acceleration_version", 1);
+        assert!(UdpAccelGrant::from_pack(&v1, hub).is_none());
+        assert!(UdpAccelGrant::from_pack(&Pack::new(), hub).is_none());
+    }
+}
@@ -4,7 +4,9 @@
 //! to establish VPN sessions. The watermark is a GIF89a binary data that must
 //! be sent via HTTP POST to /vpnsvc/connect.cgi to validate the VPN client.
 
+use crate::crypto::tls::CertificatePolicy;
 use crate::error::{Result, VpnError};
+use crate::underlay::UnderlayBinding;
 use reqwest::Client;
 use std::net::SocketAddr;
 
@@ -27,17 +29,51 @@ pub struct WatermarkClient {
     pub(crate) server_addr: SocketAddr,
     pub(crate) base_url: String,
     pub(crate) hostname: Option<String>,
+    /// User agent sent with every request
+    pub(crate) user_agent: String,
 }
 
 impl WatermarkClient {
     /// Create a new watermark client
     pub fn new(server_addr: SocketAddr, hostname: Option<String>, verify_certificate: bool) -> Result<Self> {
-        let mut client_builder = Client::builder()
-            .user_agent("SoftEther VPN Client");
+        Self::with_proxy(server_addr, hostname, verify_certificate, None)
+    }
+
+    /// Create a watermark client that reaches the server through `proxy_url`
+    pub fn with_proxy(
+        server_addr: SocketAddr,
+        hostname: Option<String>,
+        verify_certificate: bool,
+        proxy_url: Option<&str>,
+    ) -> Result<Self> {
+        let certificates = CertificatePolicy::new(verify_certificate);
+        Self::with_underlay(server_addr, hostname, &certificates, proxy_url, None)
+    }
+
+    /// Create a watermark client whose connections leave through `underlay`,
+    /// accepting the server certificates `certificates` allows
+    pub fn with_underlay(
+        server_addr: SocketAddr,
+        hostname: Option<String>,
+        certificates: &CertificatePolicy,
+        proxy_url: Option<&str>,
+        underlay: Option<&UnderlayBinding>,
+    ) -> Result<Self> {
+        let user_agent = crate::protocol::identity::ClientIdentity::default().user_agent;
+        let client_builder = Client::builder()
+            .user_agent(user_agent.as_str());
 
         // Configure TLS verification
-        if !verify_certificate {
-            client_builder = client_builder.danger_accept_invalid_certs(true);
+        let mut client_builder = certificates.apply_to(client_builder)?;
+
+        if let Some(proxy_url) = proxy_url {
+            let proxy = reqwest::Proxy::all(proxy_url)
+                .map_err(|e| VpnError::Proxy(format!("Invalid proxy URL '{}': {}", proxy_url, e)))?;
+            client_builder = client_builder.proxy(proxy);
+        }
+
+        if let Some(underlay) = underlay {
+            client_builder = underlay.apply_to(client_builder);
         }
 
         let http_client = client_builder.build().map_err(|e| {
@@ -51,9 +87,15 @@ impl WatermarkClient {
             server_addr,
             base_url,
             hostname,
+            user_agent,
         })
     }
 
+    /// Override the user agent sent with subsequent requests
+    pub fn set_user_agent(&mut self, user_agent: &str) {
+        self.user_agent = user_agent.to_string();
+    }
+
     /// Send HTTP watermark handshake to establish VPN session
     ///
     /// This sends either "VPNCONNECT" or the SoftEther watermark (GIF89a binary data) 
@@ -67,7 +109,7 @@ impl WatermarkClient {
             .header("Content-Type", "application/x-www-form-urlencoded")
             .header("Content-Length", "10")
             .header("Connection", "Keep-Alive")
-            .header("User-Agent", "Mozilla/4.0 (compatible; MSIE 6.0; Windows NT 5.1)");
+            .header("User-Agent", &self.user_agent);
         
         // Add Host header if hostname is provided
         if let Some(hostname) = &self.hostname {
@@ -100,7 +142,7 @@ impl WatermarkClient {
             .header("Content-Type", "image/gif")
             .header("Content-Length", &watermark_data.len().to_string())
             .header("Connection", "Keep-Alive")
-            .header("User-Agent", "Mozilla/4.0 (compatible; MSIE 6.0; Windows NT 5.1)");
+            .header("User-Agent", &self.user_agent);
             
         // Add Host header if hostname is provided
         if let Some(hostname) = &self.hostname {
@@ -173,7 +215,7 @@ mod tests {
     #[test]
     fn test_watermark_client_creation() {
         let addr = "127.0.0.1:443".parse().unwrap();
-        let client = WatermarkClient::new(addr, false);
+        let client = WatermarkClient::new(addr, None, false);
         assert!(client.is_ok());
     }
 }
@@ -0,0 +1,290 @@
+//! Proxy Discovery Module
+//!
+//! Resolves the proxy used for underlay connections to the VPN server. A
+//! static `proxy_url` always wins; otherwise a PAC file is fetched from the
+//! configured `pac_url` or discovered through WPAD (`http://wpad.<domain>/wpad.dat`
+//! for each parent of the local search domain). Evaluating PAC scripts needs
+//! the `pac` feature, which compiles in a small interpreter for the subset of
+//! JavaScript PAC files are written in.
+
+pub mod connect;
+pub mod ntlm;
+#[cfg(feature = "pac")]
+pub mod pac;
+
+use crate::config::NetworkConfig;
+use crate::error::{Result, VpnError};
+use std::time::Duration;
+
+/// Deadline for fetching a single PAC file (or WPAD candidate)
+pub const DEFAULT_PAC_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper bound on the size of a PAC file we are willing to evaluate
+const MAX_PAC_SIZE: usize = 1024 * 1024;
+
+/// One entry of a `FindProxyForURL` result
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyDirective {
+    /// Connect directly
+    Direct,
+    /// HTTP proxy (`PROXY host:port`)
+    Http(String),
+    /// HTTPS proxy (`HTTPS host:port`)
+    Https(String),
+    /// SOCKS proxy (`SOCKS`/`SOCKS5 host:port`)
+    Socks(String),
+}
+
+impl ProxyDirective {
+    /// Proxy URL usable with the HTTP client, `None` for direct connections
+    pub fn to_url(&self) -> Option<String> {
+        match self {
+            ProxyDirective::Direct => None,
+            ProxyDirective::Http(addr) => Some(format!("http://{addr}")),
+            ProxyDirective::Https(addr) => Some(format!("https://{addr}")),
+            ProxyDirective::Socks(addr) => Some(format!("socks5://{addr}")),
+        }
+    }
+}
+
+/// Parse a PAC result such as `"PROXY proxy.corp:8080; DIRECT"`
+///
+/// Unknown entries are skipped.
+pub fn parse_pac_result(result: &str) -> Vec<ProxyDirective> {
+    result
+        .split(';')
+        .filter_map(|entry| {
+            let mut parts = entry.split_whitespace();
+            let kind = parts.next()?.to_ascii_uppercase();
+            let addr = parts.next().map(str::to_string);
+            match (kind.as_str(), addr) {
+                ("DIRECT", _) => Some(ProxyDirective::Direct),
+                ("PROXY" | "HTTP", Some(addr)) => Some(ProxyDirective::Http(addr)),
+                ("HTTPS", Some(addr)) => Some(ProxyDirective::Https(addr)),
+                ("SOCKS" | "SOCKS4" | "SOCKS5", Some(addr)) => Some(ProxyDirective::Socks(addr)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// WPAD URLs to try for `domain`, most specific first
+///
+/// `corp.example.com` yields `wpad.corp.example.com` and `wpad.example.com`;
+/// the search never climbs to a bare top level domain.
+pub fn wpad_candidates(domain: &str) -> Vec<String> {
+    let labels: Vec<&str> = domain
+        .trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .collect();
+
+    (0..labels.len().saturating_sub(1))
+        .map(|start| format!("http://wpad.{}/wpad.dat", labels[start..].join(".")))
+        .collect()
+}
+
+/// Search domains configured for the local resolver
+fn local_search_domains() -> Vec<String> {
+    #[cfg(unix)]
+    {
+        let content = match std::fs::read_to_string("/etc/resolv.conf") {
+            Ok(content) => content,
+            Err(_) => return Vec::new(),
+        };
+        let mut domains = Vec::new();
+        for line in content.lines() {
+            let mut parts = line.split_whitespace();
+            if let Some("search" | "domain") = parts.next() {
+                for domain in parts {
+                    if !domains.iter().any(|d| d == domain) {
+                        domains.push(domain.to_string());
+                    }
+                }
+            }
+        }
+        domains
+    }
+    #[cfg(not(unix))]
+    {
+        Vec::new()
+    }
+}
+
+/// Look for a WPAD PAC file on the local search domains
+pub async fn discover_pac_url(timeout: Duration) -> Option<String> {
+    for domain in local_search_domains() {
+        for candidate in wpad_candidates(&domain) {
+            log::debug!("Trying WPAD candidate {}", candidate);
+            if fetch_pac(&candidate, timeout).await.is_ok() {
+                log::info!("Discovered PAC file via WPAD: {}", candidate);
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
+
+/// Download a PAC file, bypassing any proxy
+pub async fn fetch_pac(url: &str, timeout: Duration) -> Result<String> {
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| VpnError::Proxy(format!("Failed to create HTTP client: {e}")))?;
+
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| VpnError::Proxy(format!("Failed to fetch PAC file {url}: {e}")))?;
+
+    if !response.status().is_success() {
+        return Err(VpnError::Proxy(format!(
+            "PAC file {url} returned HTTP {}",
+            response.status()
+        )));
+    }
+
+    // Stop reading as soon as the limit is passed, whatever the server announced
+    let too_large = || VpnError::Proxy(format!("PAC file {url} is larger than {MAX_PAC_SIZE} bytes"));
+    if response.content_length().is_some_and(|length| length > MAX_PAC_SIZE as u64) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|e| VpnError::Proxy(format!("Failed to read PAC file {url}: {e}")))?
+    {
+        if body.len() + chunk.len() > MAX_PAC_SIZE {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Evaluate a PAC script for `target_url`
+#[cfg(feature = "pac")]
+pub async fn evaluate_pac(script: String, target_url: &str) -> Result<Vec<ProxyDirective>> {
+    let url = target_url.to_string();
+    let host = url::Url::parse(target_url)
+        .ok()
+        .and_then(|u| u.host_str().map(str::to_string))
+        .ok_or_else(|| VpnError::Proxy(format!("Invalid target URL: {target_url}")))?;
+
+    // PAC helpers such as dnsResolve() block on name resolution
+    let result = tokio::task::spawn_blocking(move || pac::find_proxy_for_url(&script, &url, &host))
+        .await
+        .map_err(|e| VpnError::Proxy(format!("PAC evaluation task failed: {e}")))??;
+
+    Ok(parse_pac_result(&result))
+}
+
+/// Evaluate a PAC script for `target_url`
+#[cfg(not(feature = "pac"))]
+pub async fn evaluate_pac(_script: String, _target_url: &str) -> Result<Vec<ProxyDirective>> {
+    Err(VpnError::Proxy(
+        "PAC support is not compiled in (enable the `pac` feature)".to_string(),
+    ))
+}
+
+/// Determine the proxy for connections to `target_url`
+///
+/// Returns `None` for direct connections. Discovery problems are logged and
+/// fall back to a direct connection rather than failing the connect.
+pub async fn resolve_proxy(network: &NetworkConfig, target_url: &str) -> Option<String> {
+    if let Some(ref proxy_url) = network.proxy_url {
+        return Some(proxy_url.clone());
+    }
+
+    let pac_url = match network.pac_url {
+        Some(ref pac_url) => pac_url.clone(),
+        None if network.proxy_auto_discover => discover_pac_url(DEFAULT_PAC_FETCH_TIMEOUT).await?,
+        None => return None,
+    };
+
+    let directives = match fetch_pac(&pac_url, DEFAULT_PAC_FETCH_TIMEOUT).await {
+        Ok(script) => evaluate_pac(script, target_url).await,
+        Err(e) => Err(e),
+    };
+
+    match directives {
+        Ok(directives) => select_proxy(&directives),
+        Err(e) => {
+            log::warn!("Ignoring PAC file {}: {}", pac_url, e);
+            None
+        }
+    }
+}
+
+/// Pick the first directive, `None` when that is to connect directly
+fn select_proxy(directives: &[ProxyDirective]) -> Option<String> {
+    directives.first()?.to_url()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_fetch_pac_stops_at_size_limit() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let _ = stream.read(&mut [0; 1024]).await;
+            // No Content-Length: the body only ends when the connection does
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").await;
+            let chunk = vec![b'/'; 64 * 1024];
+            for _ in 0..64 {
+                if stream.write_all(&chunk).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let url = format!("http://127.0.0.1:{port}/wpad.dat");
+        let error = fetch_pac(&url, Duration::from_secs(10)).await.unwrap_err();
+        assert!(error.to_string().contains("larger than"), "{error}");
+    }
+
+    #[test]
+    fn test_parse_pac_result() {
+        let directives = parse_pac_result("PROXY proxy.corp:8080; SOCKS5 socks.corp:1080;DIRECT");
+        assert_eq!(
+            directives,
+            vec![
+                ProxyDirective::Http("proxy.corp:8080".to_string()),
+                ProxyDirective::Socks("socks.corp:1080".to_string()),
+                ProxyDirective::Direct,
+            ]
+        );
+        assert_eq!(directives[0].to_url().as_deref(), Some("http://proxy.corp:8080"));
+        assert!(parse_pac_result("BOGUS x").is_empty());
+    }
+
+    #[test]
+    fn test_select_proxy() {
+        let directives = parse_pac_result("SOCKS socks.corp:1080; HTTPS secure.corp:443");
+        assert_eq!(select_proxy(&directives).as_deref(), Some("socks5://socks.corp:1080"));
+        assert_eq!(select_proxy(&parse_pac_result("DIRECT; PROXY proxy.corp:8080")), None);
+        assert_eq!(select_proxy(&[]), None);
+    }
+
+    #[test]
+    fn test_wpad_candidates() {
+        assert_eq!(
+            wpad_candidates("eng.corp.example.com"),
+            vec![
+                "http://wpad.eng.corp.example.com/wpad.dat",
+                "http://wpad.corp.example.com/wpad.dat",
+                "http://wpad.example.com/wpad.dat",
+            ]
+        );
+        assert!(wpad_candidates("com").is_empty());
+    }
+}
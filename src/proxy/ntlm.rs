@@ -0,0 +1,233 @@
+//! NTLMv2 messages for proxy authentication (MS-NLMP)
+//!
+//! A proxy asking for NTLM answers a CONNECT carrying the client's
+//! [`negotiate`] message with a challenge; the client proves it knows the
+//! password with the [`authenticate`] message on the same connection. Only
+//! NTLMv2 responses are produced, without signing or sealing: the proxy
+//! merely authorizes the tunnel.
+
+use crate::crypto::md::{hmac_md5, md4, MD_LEN};
+use crate::error::{Result, VpnError};
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const FLAGS: u32 = NEGOTIATE_UNICODE
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+/// `MsvAvTimestamp` entry of the target information
+const AV_TIMESTAMP: u16 = 7;
+
+/// Seconds from 1601-01-01, where Windows time starts, to the Unix epoch
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+/// What the proxy's challenge message carries
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    pub server_challenge: [u8; 8],
+    /// Target information, echoed back inside the response
+    pub target_info: Vec<u8>,
+}
+
+impl Challenge {
+    /// Parse a challenge (type 2) message
+    pub fn parse(message: &[u8]) -> Result<Self> {
+        let invalid = |reason: &str| VpnError::Proxy(format!("Invalid NTLM challenge: {reason}"));
+        if message.len() < 32 || &message[..8] != SIGNATURE {
+            return Err(invalid("bad signature"));
+        }
+        if read_u32(message, 8) != 2 {
+            return Err(invalid("not a challenge message"));
+        }
+        let mut server_challenge = [0u8; 8];
+        server_challenge.copy_from_slice(&message[24..32]);
+        // Target information is optional in old servers' messages
+        let target_info = if message.len() >= 48 {
+            let len = usize::from(read_u16(message, 40));
+            let offset = read_u32(message, 44) as usize;
+            message
+                .get(offset..offset.saturating_add(len))
+                .ok_or_else(|| invalid("target information out of bounds"))?
+                .to_vec()
+        } else {
+            Vec::new()
+        };
+        Ok(Self { server_challenge, target_info })
+    }
+
+    /// Server time from the target information, in Windows FILETIME units
+    fn timestamp(&self) -> Option<u64> {
+        let mut pairs = self.target_info.as_slice();
+        while pairs.len() >= 4 {
+            let id = u16::from_le_bytes([pairs[0], pairs[1]]);
+            let len = usize::from(u16::from_le_bytes([pairs[2], pairs[3]]));
+            let value = pairs.get(4..4 + len)?;
+            match id {
+                0 => return None,
+                AV_TIMESTAMP if len == 8 => return value.try_into().ok().map(u64::from_le_bytes),
+                _ => pairs = &pairs[4 + len..],
+            }
+        }
+        None
+    }
+}
+
+/// First message of the exchange, announcing NTLMv2 without a domain or workstation
+pub fn negotiate() -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&FLAGS.to_le_bytes());
+    // Empty domain and workstation fields
+    message.extend_from_slice(&[0u8; 16]);
+    message
+}
+
+/// Answer to `challenge` proving knowledge of `password`
+///
+/// `client_challenge` must be random for every call.
+pub fn authenticate(
+    challenge: &Challenge,
+    username: &str,
+    password: &str,
+    domain: &str,
+    client_challenge: [u8; 8],
+) -> Vec<u8> {
+    let timestamp = challenge.timestamp().unwrap_or_else(filetime_now);
+    let (lm_response, nt_response) = responses(challenge, username, password, domain, client_challenge, timestamp);
+    let fields = [lm_response, nt_response, utf16(domain), utf16(username), Vec::new(), Vec::new()];
+
+    // Header: signature, type, six field descriptors, flags
+    let header_len = 8 + 4 + fields.len() * 8 + 4;
+    let mut message = Vec::with_capacity(header_len + fields.iter().map(Vec::len).sum::<usize>());
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&3u32.to_le_bytes());
+    let mut offset = header_len;
+    for field in &fields {
+        let len = u16::try_from(field.len()).unwrap_or(u16::MAX);
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&(offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    message.extend_from_slice(&FLAGS.to_le_bytes());
+    for field in &fields {
+        message.extend_from_slice(field);
+    }
+    message
+}
+
+/// LMv2 and NTLMv2 responses
+fn responses(
+    challenge: &Challenge,
+    username: &str,
+    password: &str,
+    domain: &str,
+    client_challenge: [u8; 8],
+    timestamp: u64,
+) -> (Vec<u8>, Vec<u8>) {
+    let identity = utf16(&format!("{}{}", username.to_uppercase(), domain));
+    let key = hmac_md5(&md4(&utf16(password)), &identity);
+
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&timestamp.to_le_bytes());
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let proof = hmac_md5(&key, &[&challenge.server_challenge[..], &blob].concat());
+    let nt_response = [&proof[..], &blob].concat();
+    let lm_proof: [u8; MD_LEN] = hmac_md5(&key, &[challenge.server_challenge, client_challenge].concat());
+    let lm_response = [&lm_proof[..], &client_challenge].concat();
+    (lm_response, nt_response)
+}
+
+fn filetime_now() -> u64 {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_epoch.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000 + u64::from(since_epoch.subsec_nanos() / 100)
+}
+
+fn utf16(text: &str) -> Vec<u8> {
+    text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn read_u16(message: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([message[offset], message[offset + 1]])
+}
+
+fn read_u32(message: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([message[offset], message[offset + 1], message[offset + 2], message[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Target information of the MS-NLMP examples: domain "Domain", server "Server"
+    const TARGET_INFO: &str = "02000c0044006f006d00610069006e0001000c0053006500720076006500720000000000";
+
+    #[test]
+    fn test_ntlmv2_responses_match_spec() {
+        // MS-NLMP 4.2.4
+        let challenge = Challenge {
+            server_challenge: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef],
+            target_info: hex::decode(TARGET_INFO).unwrap(),
+        };
+        let (lm, nt) = responses(&challenge, "User", "Password", "Domain", [0xaa; 8], 0);
+        assert_eq!(hex::encode(lm), "86c35097ac9cec102554764a57cccc19aaaaaaaaaaaaaaaa");
+        assert_eq!(hex::encode(&nt[..16]), "68cd0ab851e51c96aabc927bebef6a1c");
+    }
+
+    #[test]
+    fn test_message_round_trip() {
+        let negotiate = negotiate();
+        assert_eq!((&negotiate[..8], read_u32(&negotiate, 8)), (&SIGNATURE[..], 1));
+
+        // A challenge as a proxy sends it, with a server timestamp to reuse
+        let mut target_info = vec![7, 0, 8, 0];
+        target_info.extend_from_slice(&42u64.to_le_bytes());
+        target_info.extend_from_slice(&[0, 0, 0, 0]);
+        let mut message = SIGNATURE.to_vec();
+        message.extend_from_slice(&2u32.to_le_bytes());
+        message.extend_from_slice(&[0; 8]);
+        message.extend_from_slice(&FLAGS.to_le_bytes());
+        message.extend_from_slice(&[9; 8]);
+        message.extend_from_slice(&[0; 8]);
+        message.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(target_info.len() as u16).to_le_bytes());
+        message.extend_from_slice(&48u32.to_le_bytes());
+        message.extend_from_slice(&target_info);
+        let challenge = Challenge::parse(&message).unwrap();
+        assert_eq!(challenge.server_challenge, [9; 8]);
+        assert_eq!(challenge.timestamp(), Some(42));
+
+        let answer = authenticate(&challenge, "alice", "secret", "CORP", [1; 8]);
+        assert_eq!(read_u32(&answer, 8), 3);
+        // The user name field points at "alice" in UTF-16
+        let (len, offset) = (usize::from(read_u16(&answer, 36)), read_u32(&answer, 40) as usize);
+        assert_eq!(answer[offset..offset + len], utf16("alice"));
+        let offset = read_u32(&answer, 24) as usize;
+        assert_eq!(answer[offset + 24..offset + 32], 42u64.to_le_bytes());
+
+        assert!(Challenge::parse(b"NTLMSSP\0\x01\0\0\0").is_err());
+        message[44] = 0xff;
+        assert!(Challenge::parse(&message).is_err());
+    }
+}
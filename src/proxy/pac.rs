@@ -0,0 +1,901 @@
+//! PAC Script Interpreter
+//!
+//! Evaluates proxy auto-config files without a JavaScript engine. Only the
+//! subset of the language PAC files realistically use is supported: function
+//! declarations, `var`/`let`/`const`, assignments, `if`/`else`, `return`,
+//! string/number/boolean literals, `!`, `&&`, `||`, `==`/`!=`/`===`/`!==`,
+//! `<`/`>`/`<=`/`>=`, `+`/`-`, the ternary operator, the `toLowerCase`,
+//! `toUpperCase`, `indexOf`, `substring` string methods and `.length`, plus
+//! the standard PAC helper functions. Anything else is reported as an error
+//! so the caller can fall back to a direct connection.
+
+use crate::error::{Result, VpnError};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs, UdpSocket};
+
+/// Guard against runaway recursion in user-defined helper functions
+const MAX_CALL_DEPTH: usize = 64;
+
+/// Deepest nesting of expressions and statements the parser follows
+///
+/// The parser recurses for each level, so a script fetched over WPAD could
+/// otherwise exhaust the stack with a few hundred parentheses.
+const MAX_NESTING_DEPTH: usize = 32;
+
+/// Run `FindProxyForURL(url, host)` from `script` and return its result string
+pub fn find_proxy_for_url(script: &str, url: &str, host: &str) -> Result<String> {
+    let tokens = tokenize(script)?;
+    let program = Parser { tokens, pos: 0, depth: 0 }.parse_program()?;
+
+    let mut interpreter = Interpreter {
+        functions: program.functions,
+        globals: HashMap::new(),
+        depth: 0,
+    };
+    for stmt in &program.globals {
+        let mut scope = HashMap::new();
+        interpreter.exec(stmt, &mut scope)?;
+        interpreter.globals.extend(scope);
+    }
+
+    let result = interpreter.call(
+        "FindProxyForURL",
+        vec![Value::Str(url.to_string()), Value::Str(host.to_string())],
+    )?;
+    match result {
+        Value::Str(s) => Ok(s),
+        other => Err(pac_error(format!("FindProxyForURL returned {}", other.type_name()))),
+    }
+}
+
+fn pac_error(msg: String) -> VpnError {
+    VpnError::Proxy(format!("PAC script: {msg}"))
+}
+
+// ---------------------------------------------------------------------------
+// Lexer
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    Punct(&'static str),
+}
+
+const PUNCTUATORS: &[&str] = &[
+    "===", "!==", "==", "!=", "<=", ">=", "&&", "||", "(", ")", "{", "}", ";", ",", "!", "<", ">",
+    "+", "-", "=", "?", ":", ".",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = src.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '/' && chars.get(i + 1) == Some(&'/') {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '/' && chars.get(i + 1) == Some(&'*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i += 2;
+        } else if c == '"' || c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(pac_error("unterminated string literal".to_string())),
+                    Some(&ch) if ch == c => break,
+                    Some('\\') => {
+                        i += 1;
+                        match chars.get(i) {
+                            Some('n') => value.push('\n'),
+                            Some('t') => value.push('\t'),
+                            Some(&other) => value.push(other),
+                            None => return Err(pac_error("unterminated string literal".to_string())),
+                        }
+                    }
+                    Some(&ch) => value.push(ch),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::Str(value));
+        } else if c.is_ascii_digit() {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let num = text
+                .parse()
+                .map_err(|_| pac_error(format!("invalid number '{text}'")))?;
+            tokens.push(Token::Num(num));
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '$') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 3)].iter().collect();
+            let punct = PUNCTUATORS
+                .iter()
+                .find(|p| rest.starts_with(**p))
+                .ok_or_else(|| pac_error(format!("unexpected character '{c}'")))?;
+            i += punct.len();
+            tokens.push(Token::Punct(punct));
+        }
+    }
+
+    Ok(tokens)
+}
+
+// ---------------------------------------------------------------------------
+// Parser
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinOp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Add,
+    Sub,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Value),
+    Var(String),
+    Call(String, Vec<Expr>),
+    Method(Box<Expr>, String, Vec<Expr>),
+    Property(Box<Expr>, String),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Stmt {
+    Return(Option<Expr>),
+    If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+    Block(Vec<Stmt>),
+    Declare(String, Option<Expr>),
+    Assign(String, Expr),
+    Expr(Expr),
+}
+
+#[derive(Debug, Clone)]
+struct Function {
+    params: Vec<String>,
+    body: Vec<Stmt>,
+}
+
+struct Program {
+    functions: HashMap<String, Function>,
+    globals: Vec<Stmt>,
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Nesting of the expression or statement being parsed
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn is_punct(&self, p: &str) -> bool {
+        matches!(self.peek(), Some(Token::Punct(q)) if *q == p)
+    }
+
+    fn is_keyword(&self, kw: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(name)) if name == kw)
+    }
+
+    fn eat_punct(&mut self, p: &str) -> bool {
+        if self.is_punct(p) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_punct(&mut self, p: &str) -> Result<()> {
+        if self.eat_punct(p) {
+            Ok(())
+        } else {
+            Err(pac_error(format!("expected '{p}', found {:?}", self.peek())))
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Ident(name)) => Ok(name),
+            other => Err(pac_error(format!("expected identifier, found {other:?}"))),
+        }
+    }
+
+    /// Run `parse` one nesting level deeper, failing past [`MAX_NESTING_DEPTH`]
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return Err(pac_error(format!("nested more than {MAX_NESTING_DEPTH} levels deep")));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn parse_program(mut self) -> Result<Program> {
+        let mut functions = HashMap::new();
+        let mut globals = Vec::new();
+
+        while self.peek().is_some() {
+            if self.is_keyword("function") {
+                self.pos += 1;
+                let name = self.expect_ident()?;
+                let function = self.parse_function_rest()?;
+                functions.insert(name, function);
+            } else {
+                globals.push(self.parse_statement()?);
+            }
+        }
+
+        Ok(Program { functions, globals })
+    }
+
+    fn parse_function_rest(&mut self) -> Result<Function> {
+        self.expect_punct("(")?;
+        let mut params = Vec::new();
+        if !self.eat_punct(")") {
+            loop {
+                params.push(self.expect_ident()?);
+                if self.eat_punct(")") {
+                    break;
+                }
+                self.expect_punct(",")?;
+            }
+        }
+        self.expect_punct("{")?;
+        let body = self.parse_block_rest()?;
+        Ok(Function { params, body })
+    }
+
+    fn parse_block_rest(&mut self) -> Result<Vec<Stmt>> {
+        let mut stmts = Vec::new();
+        while !self.eat_punct("}") {
+            if self.peek().is_none() {
+                return Err(pac_error("unexpected end of script".to_string()));
+            }
+            stmts.push(self.parse_statement()?);
+        }
+        Ok(stmts)
+    }
+
+    fn parse_statement(&mut self) -> Result<Stmt> {
+        self.nested(Self::parse_statement_inner)
+    }
+
+    fn parse_statement_inner(&mut self) -> Result<Stmt> {
+        if self.eat_punct(";") {
+            return Ok(Stmt::Block(Vec::new()));
+        }
+        if self.eat_punct("{") {
+            return Ok(Stmt::Block(self.parse_block_rest()?));
+        }
+        if self.is_keyword("return") {
+            self.pos += 1;
+            let value = if self.is_punct(";") || self.is_punct("}") {
+                None
+            } else {
+                Some(self.parse_expr()?)
+            };
+            self.eat_punct(";");
+            return Ok(Stmt::Return(value));
+        }
+        if self.is_keyword("if") {
+            self.pos += 1;
+            self.expect_punct("(")?;
+            let cond = self.parse_expr()?;
+            self.expect_punct(")")?;
+            let then = Box::new(self.parse_statement()?);
+            let otherwise = if self.is_keyword("else") {
+                self.pos += 1;
+                Some(Box::new(self.parse_statement()?))
+            } else {
+                None
+            };
+            return Ok(Stmt::If(cond, then, otherwise));
+        }
+        if self.is_keyword("var") || self.is_keyword("let") || self.is_keyword("const") {
+            self.pos += 1;
+            let name = self.expect_ident()?;
+            let value = if self.eat_punct("=") { Some(self.parse_expr()?) } else { None };
+            self.eat_punct(";");
+            return Ok(Stmt::Declare(name, value));
+        }
+        if let (Some(Token::Ident(name)), Some(Token::Punct("="))) =
+            (self.tokens.get(self.pos), self.tokens.get(self.pos + 1))
+        {
+            let name = name.clone();
+            self.pos += 2;
+            let value = self.parse_expr()?;
+            self.eat_punct(";");
+            return Ok(Stmt::Assign(name, value));
+        }
+
+        let expr = self.parse_expr()?;
+        self.eat_punct(";");
+        Ok(Stmt::Expr(expr))
+    }
+
+    fn parse_expr(&mut self) -> Result<Expr> {
+        self.nested(Self::parse_conditional)
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expr> {
+        let cond = self.parse_binary(0)?;
+        if self.eat_punct("?") {
+            let then = self.parse_expr()?;
+            self.expect_punct(":")?;
+            let otherwise = self.parse_expr()?;
+            return Ok(Expr::Cond(Box::new(cond), Box::new(then), Box::new(otherwise)));
+        }
+        Ok(cond)
+    }
+
+    /// Precedence climbing over `||`, `&&`, equality, relational and additive operators
+    fn parse_binary(&mut self, level: usize) -> Result<Expr> {
+        const LEVELS: &[&[(&str, BinOp)]] = &[
+            &[("||", BinOp::Or)],
+            &[("&&", BinOp::And)],
+            &[("===", BinOp::Eq), ("!==", BinOp::Ne), ("==", BinOp::Eq), ("!=", BinOp::Ne)],
+            &[("<=", BinOp::Le), (">=", BinOp::Ge), ("<", BinOp::Lt), (">", BinOp::Gt)],
+            &[("+", BinOp::Add), ("-", BinOp::Sub)],
+        ];
+
+        if level == LEVELS.len() {
+            return self.parse_unary();
+        }
+
+        let mut lhs = self.parse_binary(level + 1)?;
+        'outer: loop {
+            for (punct, op) in LEVELS[level] {
+                if self.eat_punct(punct) {
+                    let rhs = self.parse_binary(level + 1)?;
+                    lhs = Expr::Binary(*op, Box::new(lhs), Box::new(rhs));
+                    continue 'outer;
+                }
+            }
+            return Ok(lhs);
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.eat_punct("!") {
+            return Ok(Expr::Not(Box::new(self.nested(Self::parse_unary)?)));
+        }
+        if self.eat_punct("-") {
+            return Ok(Expr::Neg(Box::new(self.nested(Self::parse_unary)?)));
+        }
+        self.parse_postfix()
+    }
+
+    fn parse_postfix(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_primary()?;
+        while self.eat_punct(".") {
+            let name = self.expect_ident()?;
+            if self.eat_punct("(") {
+                let args = self.parse_args()?;
+                expr = Expr::Method(Box::new(expr), name, args);
+            } else {
+                expr = Expr::Property(Box::new(expr), name);
+            }
+        }
+        Ok(expr)
+    }
+
+    fn parse_args(&mut self) -> Result<Vec<Expr>> {
+        let mut args = Vec::new();
+        if self.eat_punct(")") {
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_expr()?);
+            if self.eat_punct(")") {
+                return Ok(args);
+            }
+            self.expect_punct(",")?;
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(Expr::Literal(Value::Str(s))),
+            Some(Token::Num(n)) => Ok(Expr::Literal(Value::Num(n))),
+            Some(Token::Punct("(")) => {
+                let expr = self.parse_expr()?;
+                self.expect_punct(")")?;
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => match name.as_str() {
+                "true" => Ok(Expr::Literal(Value::Bool(true))),
+                "false" => Ok(Expr::Literal(Value::Bool(false))),
+                "null" | "undefined" => Ok(Expr::Literal(Value::Undefined)),
+                _ if self.eat_punct("(") => Ok(Expr::Call(name, self.parse_args()?)),
+                _ => Ok(Expr::Var(name)),
+            },
+            other => Err(pac_error(format!("unexpected token {other:?}"))),
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Interpreter
+// ---------------------------------------------------------------------------
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Num(f64),
+    Bool(bool),
+    Undefined,
+}
+
+impl Value {
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Str(s) => !s.is_empty(),
+            Value::Num(n) => *n != 0.0 && !n.is_nan(),
+            Value::Bool(b) => *b,
+            Value::Undefined => false,
+        }
+    }
+
+    fn to_num(&self) -> f64 {
+        match self {
+            Value::Str(s) => s.trim().parse().unwrap_or(f64::NAN),
+            Value::Num(n) => *n,
+            Value::Bool(b) => f64::from(u8::from(*b)),
+            Value::Undefined => f64::NAN,
+        }
+    }
+
+    fn to_str(&self) -> String {
+        match self {
+            Value::Str(s) => s.clone(),
+            Value::Num(n) if n.fract() == 0.0 && n.is_finite() => format!("{}", *n as i64),
+            Value::Num(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Undefined => "undefined".to_string(),
+        }
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::Num(_) => "number",
+            Value::Bool(_) => "boolean",
+            Value::Undefined => "undefined",
+        }
+    }
+
+    fn loose_eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Str(a), Value::Str(b)) => a == b,
+            (Value::Undefined, Value::Undefined) => true,
+            (Value::Undefined, _) | (_, Value::Undefined) => false,
+            (a, b) => a.to_num() == b.to_num(),
+        }
+    }
+}
+
+/// Outcome of executing a statement
+enum Flow {
+    Normal,
+    Return(Value),
+}
+
+struct Interpreter {
+    functions: HashMap<String, Function>,
+    globals: HashMap<String, Value>,
+    depth: usize,
+}
+
+impl Interpreter {
+    fn call(&mut self, name: &str, args: Vec<Value>) -> Result<Value> {
+        let function = match self.functions.get(name) {
+            Some(function) => function.clone(),
+            None => return builtin(name, &args),
+        };
+
+        if self.depth >= MAX_CALL_DEPTH {
+            return Err(pac_error("maximum call depth exceeded".to_string()));
+        }
+        self.depth += 1;
+
+        let mut scope: HashMap<String, Value> = function
+            .params
+            .iter()
+            .cloned()
+            .zip(args.into_iter().chain(std::iter::repeat(Value::Undefined)))
+            .collect();
+
+        let mut result = Ok(Value::Undefined);
+        for stmt in &function.body {
+            match self.exec(stmt, &mut scope) {
+                Ok(Flow::Normal) => {}
+                Ok(Flow::Return(value)) => {
+                    result = Ok(value);
+                    break;
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        self.depth -= 1;
+        result
+    }
+
+    fn exec(&mut self, stmt: &Stmt, scope: &mut HashMap<String, Value>) -> Result<Flow> {
+        match stmt {
+            Stmt::Return(expr) => {
+                let value = match expr {
+                    Some(expr) => self.eval(expr, scope)?,
+                    None => Value::Undefined,
+                };
+                Ok(Flow::Return(value))
+            }
+            Stmt::If(cond, then, otherwise) => {
+                if self.eval(cond, scope)?.truthy() {
+                    self.exec(then, scope)
+                } else if let Some(otherwise) = otherwise {
+                    self.exec(otherwise, scope)
+                } else {
+                    Ok(Flow::Normal)
+                }
+            }
+            Stmt::Block(stmts) => {
+                for stmt in stmts {
+                    if let Flow::Return(value) = self.exec(stmt, scope)? {
+                        return Ok(Flow::Return(value));
+                    }
+                }
+                Ok(Flow::Normal)
+            }
+            Stmt::Declare(name, expr) => {
+                let value = match expr {
+                    Some(expr) => self.eval(expr, scope)?,
+                    None => Value::Undefined,
+                };
+                scope.insert(name.clone(), value);
+                Ok(Flow::Normal)
+            }
+            Stmt::Assign(name, expr) => {
+                let value = self.eval(expr, scope)?;
+                if scope.contains_key(name) || !self.globals.contains_key(name) {
+                    scope.insert(name.clone(), value);
+                } else {
+                    self.globals.insert(name.clone(), value);
+                }
+                Ok(Flow::Normal)
+            }
+            Stmt::Expr(expr) => {
+                self.eval(expr, scope)?;
+                Ok(Flow::Normal)
+            }
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr, scope: &mut HashMap<String, Value>) -> Result<Value> {
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Var(name) => scope
+                .get(name)
+                .or_else(|| self.globals.get(name))
+                .cloned()
+                .ok_or_else(|| pac_error(format!("'{name}' is not defined"))),
+            Expr::Call(name, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, scope))
+                    .collect::<Result<Vec<_>>>()?;
+                self.call(name, args)
+            }
+            Expr::Method(target, name, args) => {
+                let target = self.eval(target, scope)?.to_str();
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval(arg, scope))
+                    .collect::<Result<Vec<_>>>()?;
+                string_method(&target, name, &args)
+            }
+            Expr::Property(target, name) => {
+                let target = self.eval(target, scope)?;
+                match name.as_str() {
+                    "length" => Ok(Value::Num(target.to_str().chars().count() as f64)),
+                    _ => Err(pac_error(format!("unsupported property '{name}'"))),
+                }
+            }
+            Expr::Not(inner) => Ok(Value::Bool(!self.eval(inner, scope)?.truthy())),
+            Expr::Neg(inner) => Ok(Value::Num(-self.eval(inner, scope)?.to_num())),
+            Expr::Cond(cond, then, otherwise) => {
+                if self.eval(cond, scope)?.truthy() {
+                    self.eval(then, scope)
+                } else {
+                    self.eval(otherwise, scope)
+                }
+            }
+            Expr::Binary(BinOp::And, lhs, rhs) => {
+                let lhs = self.eval(lhs, scope)?;
+                if lhs.truthy() { self.eval(rhs, scope) } else { Ok(lhs) }
+            }
+            Expr::Binary(BinOp::Or, lhs, rhs) => {
+                let lhs = self.eval(lhs, scope)?;
+                if lhs.truthy() { Ok(lhs) } else { self.eval(rhs, scope) }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(lhs, scope)?;
+                let rhs = self.eval(rhs, scope)?;
+                Ok(match op {
+                    BinOp::Eq => Value::Bool(lhs.loose_eq(&rhs)),
+                    BinOp::Ne => Value::Bool(!lhs.loose_eq(&rhs)),
+                    BinOp::Lt => Value::Bool(lhs.to_num() < rhs.to_num()),
+                    BinOp::Gt => Value::Bool(lhs.to_num() > rhs.to_num()),
+                    BinOp::Le => Value::Bool(lhs.to_num() <= rhs.to_num()),
+                    BinOp::Ge => Value::Bool(lhs.to_num() >= rhs.to_num()),
+                    BinOp::Add => match (&lhs, &rhs) {
+                        (Value::Str(_), _) | (_, Value::Str(_)) => {
+                            Value::Str(lhs.to_str() + &rhs.to_str())
+                        }
+                        _ => Value::Num(lhs.to_num() + rhs.to_num()),
+                    },
+                    BinOp::Sub => Value::Num(lhs.to_num() - rhs.to_num()),
+                    BinOp::And | BinOp::Or => unreachable!("short-circuit operators handled above"),
+                })
+            }
+        }
+    }
+}
+
+fn string_method(target: &str, name: &str, args: &[Value]) -> Result<Value> {
+    let arg_str = |i: usize| args.get(i).map(Value::to_str).unwrap_or_default();
+    match name {
+        "toLowerCase" => Ok(Value::Str(target.to_lowercase())),
+        "toUpperCase" => Ok(Value::Str(target.to_uppercase())),
+        "indexOf" => {
+            let needle = arg_str(0);
+            let index = target
+                .find(&needle)
+                .map(|byte| target[..byte].chars().count() as f64)
+                .unwrap_or(-1.0);
+            Ok(Value::Num(index))
+        }
+        "substring" => {
+            let chars: Vec<char> = target.chars().collect();
+            let clamp = |v: Option<&Value>, default: usize| match v {
+                Some(v) if !v.to_num().is_nan() => (v.to_num().max(0.0) as usize).min(chars.len()),
+                _ => default,
+            };
+            let start = clamp(args.first(), 0);
+            let end = clamp(args.get(1), chars.len());
+            let (start, end) = if start <= end { (start, end) } else { (end, start) };
+            Ok(Value::Str(chars[start..end].iter().collect()))
+        }
+        _ => Err(pac_error(format!("unsupported method '{name}'"))),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Standard PAC functions
+// ---------------------------------------------------------------------------
+
+fn builtin(name: &str, args: &[Value]) -> Result<Value> {
+    let arg = |i: usize| args.get(i).map(Value::to_str).unwrap_or_default();
+    match name {
+        "isPlainHostName" => Ok(Value::Bool(!arg(0).contains('.'))),
+        "dnsDomainIs" => {
+            let (host, domain) = (arg(0).to_lowercase(), arg(1).to_lowercase());
+            Ok(Value::Bool(host.ends_with(&domain)))
+        }
+        "localHostOrDomainIs" => {
+            let (host, hostdom) = (arg(0).to_lowercase(), arg(1).to_lowercase());
+            let matches = host == hostdom
+                || (!host.contains('.') && hostdom.split('.').next() == Some(host.as_str()));
+            Ok(Value::Bool(matches))
+        }
+        "dnsDomainLevels" => Ok(Value::Num(arg(0).matches('.').count() as f64)),
+        "shExpMatch" => Ok(Value::Bool(shell_match(arg(0).as_bytes(), arg(1).as_bytes()))),
+        "isResolvable" => Ok(Value::Bool(resolve_ipv4(&arg(0)).is_some())),
+        "dnsResolve" => Ok(resolve_ipv4(&arg(0))
+            .map(|ip| Value::Str(ip.to_string()))
+            .unwrap_or(Value::Undefined)),
+        "myIpAddress" => Ok(Value::Str(my_ip_address().to_string())),
+        "isInNet" => {
+            let host = match resolve_ipv4(&arg(0)) {
+                Some(ip) => u32::from(ip),
+                None => return Ok(Value::Bool(false)),
+            };
+            let (pattern, mask) = match (arg(1).parse::<Ipv4Addr>(), arg(2).parse::<Ipv4Addr>()) {
+                (Ok(pattern), Ok(mask)) => (u32::from(pattern), u32::from(mask)),
+                _ => return Ok(Value::Bool(false)),
+            };
+            Ok(Value::Bool(host & mask == pattern & mask))
+        }
+        "convert_addr" => Ok(arg(0)
+            .parse::<Ipv4Addr>()
+            .map(|ip| Value::Num(f64::from(u32::from(ip))))
+            .unwrap_or(Value::Undefined)),
+        "alert" => {
+            log::debug!("PAC alert: {}", arg(0));
+            Ok(Value::Undefined)
+        }
+        _ => Err(pac_error(format!("unsupported function '{name}'"))),
+    }
+}
+
+/// Shell-style glob match supporting `*` and `?`
+fn shell_match(text: &[u8], pattern: &[u8]) -> bool {
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star_p, star_t)) => {
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p.min(pattern.len())..].iter().all(|&c| c == b'*')
+}
+
+fn resolve_ipv4(host: &str) -> Option<Ipv4Addr> {
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        return Some(ip);
+    }
+    (host, 0).to_socket_addrs().ok()?.find_map(|addr| match addr.ip() {
+        IpAddr::V4(ip) => Some(ip),
+        IpAddr::V6(_) => None,
+    })
+}
+
+/// Address of the interface holding the default route
+fn my_ip_address() -> Ipv4Addr {
+    // Connecting a UDP socket sends nothing but makes the OS pick a source address
+    UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .and_then(|socket| {
+            socket.connect((Ipv4Addr::new(192, 0, 2, 1), 53))?;
+            socket.local_addr()
+        })
+        .ok()
+        .and_then(|addr| match addr.ip() {
+            IpAddr::V4(ip) if !ip.is_unspecified() => Some(ip),
+            _ => None,
+        })
+        .unwrap_or(Ipv4Addr::LOCALHOST)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CORPORATE_PAC: &str = r#"
+        // Typical enterprise PAC file
+        var proxy = "PROXY proxy.corp.example:8080; DIRECT";
+
+        function isInternal(host) {
+            return dnsDomainIs(host, ".corp.example") || isPlainHostName(host);
+        }
+
+        function FindProxyForURL(url, host) {
+            host = host.toLowerCase();
+            if (isInternal(host) || shExpMatch(host, "10.*")) {
+                return "DIRECT";
+            } else if (url.substring(0, 5) == "http:") {
+                return "PROXY plain.corp.example:3128";
+            }
+            return proxy;
+        }
+    "#;
+
+    #[test]
+    fn test_corporate_pac() {
+        let eval = |url: &str, host: &str| find_proxy_for_url(CORPORATE_PAC, url, host).unwrap();
+        assert_eq!(eval("https://intranet.corp.example/", "INTRANET.corp.example"), "DIRECT");
+        assert_eq!(eval("https://fileserver/", "fileserver"), "DIRECT");
+        assert_eq!(eval("https://10.1.2.3/", "10.1.2.3"), "DIRECT");
+        assert_eq!(eval("http://example.com/", "example.com"), "PROXY plain.corp.example:3128");
+        assert_eq!(
+            eval("https://vpn.example.com:443/", "vpn.example.com"),
+            "PROXY proxy.corp.example:8080; DIRECT"
+        );
+    }
+
+    #[test]
+    fn test_is_in_net_and_ternary() {
+        let script = r#"
+            function FindProxyForURL(url, host) {
+                return isInNet(host, "192.168.0.0", "255.255.0.0") ? "DIRECT" : "PROXY p:1";
+            }
+        "#;
+        assert_eq!(find_proxy_for_url(script, "https://192.168.4.4/", "192.168.4.4").unwrap(), "DIRECT");
+        assert_eq!(find_proxy_for_url(script, "https://172.16.0.1/", "172.16.0.1").unwrap(), "PROXY p:1");
+    }
+
+    #[test]
+    fn test_shell_match() {
+        assert!(shell_match(b"vpn.example.com", b"*.example.com"));
+        assert!(shell_match(b"host1", b"host?"));
+        assert!(!shell_match(b"example.org", b"*.example.com"));
+        assert!(shell_match(b"anything", b"*"));
+    }
+
+    #[test]
+    fn test_unsupported_constructs_fail() {
+        assert!(find_proxy_for_url("function FindProxyForURL(u, h) { while (1) {} }", "u", "h").is_err());
+        assert!(find_proxy_for_url("function FindProxyForURL(u, h) { return weekdayRange(\"MON\"); }", "u", "h").is_err());
+        assert!(find_proxy_for_url("function f() { return f(); } function FindProxyForURL(u, h) { return f(); }", "u", "h").is_err());
+    }
+
+    #[test]
+    fn test_deep_nesting_fails_without_overflowing() {
+        let pac = |body: String| format!("function FindProxyForURL(u, h) {{ {body} }}");
+        let parens = |depth: usize| format!("return {}\"DIRECT\"{};", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(find_proxy_for_url(&pac(parens(10)), "u", "h").unwrap(), "DIRECT");
+
+        for body in [
+            parens(200),
+            parens(100_000),
+            format!("return {}\"DIRECT\";", "!".repeat(100_000)),
+            format!("{}return \"DIRECT\";{}", "{".repeat(100_000), "}".repeat(100_000)),
+            format!("{}return \"DIRECT\";", "if (1) ".repeat(100_000)),
+        ] {
+            let error = find_proxy_for_url(&pac(body), "u", "h").unwrap_err();
+            assert!(error.to_string().contains("nested"), "{error}");
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! Server Address Resolution
+//!
+//! `server.address` may be a hostname. It is resolved through the system
+//! resolver or, with `network.dns_over_https`, over DNS-over-HTTPS (RFC 8484),
+//! so that a poisoned local resolver cannot redirect the session before the
+//! tunnel is up. When a name has several addresses, [`race`] connects to them
+//! Happy Eyeballs style (RFC 8305): IPv6 and IPv4 alternate, and a new attempt
+//! starts every [`CONNECTION_ATTEMPT_DELAY`] until one of them connects.
+
+use crate::config::NetworkConfig;
+use crate::diagnostics::{build_dns_query, parse_dns_response, DNS_TYPE_A, DNS_TYPE_AAAA};
+use crate::error::{Result, VpnError};
+use crate::tunnel::lease::is_valid_domain;
+use crate::underlay;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// Head start of each connection attempt over the next (RFC 8305 §5)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Media type of DNS messages over HTTPS
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Addresses of `host` on `port`, ordered for [`race`]
+///
+/// An IP literal is taken as is; a name is resolved through DoH when
+/// `network.dns_over_https` is set, through the system resolver otherwise.
+pub async fn resolve(host: &str, port: u16, network: &NetworkConfig) -> Result<Vec<SocketAddr>> {
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+    if !is_valid_domain(host.trim_end_matches('.')) {
+        return Err(VpnError::Config(format!("Invalid server address '{host}': neither an IP address nor a hostname")));
+    }
+    let addresses = match network.dns_over_https {
+        Some(ref url) => resolve_doh(url, host).await?,
+        None => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| VpnError::Dns(format!("Cannot resolve {host}: {e}")))?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+    if addresses.is_empty() {
+        return Err(VpnError::Dns(format!("{host} has no addresses")));
+    }
+    log::debug!("Resolved {} to {:?}", host, addresses);
+    Ok(interleave(addresses.into_iter().map(|ip| SocketAddr::new(ip, port)).collect()))
+}
+
+/// Ask the DoH server at `url` for the AAAA and A records of `host`; either family will do
+async fn resolve_doh(url: &str, host: &str) -> Result<Vec<IpAddr>> {
+    let client = reqwest::Client::builder()
+        .build()
+        .map_err(|e| VpnError::Network(format!("Failed to create DoH client: {e}")))?;
+    let (v6, v4) = tokio::join!(
+        doh_query(&client, url, host, DNS_TYPE_AAAA),
+        doh_query(&client, url, host, DNS_TYPE_A)
+    );
+    match (v6, v4) {
+        (Err(e), Err(_)) => Err(e),
+        (v6, v4) => Ok(v6.unwrap_or_default().into_iter().chain(v4.unwrap_or_default()).collect()),
+    }
+}
+
+async fn doh_query(client: &reqwest::Client, url: &str, host: &str, qtype: u16) -> Result<Vec<IpAddr>> {
+    // ID 0 keeps answers cacheable by HTTP caches (RFC 8484 §4.1)
+    let query = build_dns_query(0, host, qtype)?;
+    let failed = |e: reqwest::Error| VpnError::Dns(format!("DoH query to {url} failed: {e}"));
+    let response = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, DNS_MESSAGE)
+        .header(reqwest::header::ACCEPT, DNS_MESSAGE)
+        .body(query)
+        .send()
+        .await
+        .map_err(failed)?;
+    if !response.status().is_success() {
+        return Err(VpnError::Dns(format!("DoH server {url} answered {}", response.status())));
+    }
+    parse_dns_response(0, &response.bytes().await.map_err(failed)?)
+}
+
+/// Order `addresses` for connecting (RFC 8305 §4): families alternate, IPv6 first
+pub fn interleave(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = addresses.into_iter().partition(SocketAddr::is_ipv6);
+    v6.dedup();
+    v4.dedup();
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (first, second) => ordered.extend(first.into_iter().chain(second)),
+        }
+    }
+}
+
+/// The first of `addresses` to accept a TCP connection
+///
+/// Attempts start `delay` apart, or as soon as the previous one failed, and
+/// the others are abandoned once one connects. Its connection is closed
+/// again: the race only picks the address the session then uses. Sockets
+/// carry `mark` under policy routing.
+pub async fn race(addresses: &[SocketAddr], delay: Duration, mark: Option<u32>) -> Result<SocketAddr> {
+    let mut pending = addresses.iter().copied();
+    let mut attempts = JoinSet::new();
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, "no addresses");
+    loop {
+        if let Some(address) = pending.next() {
+            attempts.spawn(async move { (address, underlay::connect_tcp(address, mark).await) });
+        }
+        let finished = if pending.len() > 0 {
+            match tokio::time::timeout(delay, attempts.join_next()).await {
+                Ok(finished) => finished,
+                // Nothing finished in time: start the next attempt alongside
+                Err(_) => continue,
+            }
+        } else {
+            attempts.join_next().await
+        };
+        match finished {
+            Some(Ok((address, Ok(_)))) => {
+                log::debug!("{} won the connection race", address);
+                return Ok(address);
+            }
+            Some(Ok((address, Err(e)))) => {
+                log::debug!("Connection attempt to {} failed: {}", address, e);
+                last_error = e;
+            }
+            Some(Err(e)) => last_error = io::Error::other(e),
+            None => break,
+        }
+    }
+    Err(VpnError::Network(format!("No server address accepted a connection: {last_error}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn addr(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 443)
+    }
+
+    #[test]
+    fn test_interleave_starts_with_ipv6() {
+        let ordered = interleave(vec![addr("192.0.2.1"), addr("192.0.2.2"), addr("192.0.2.3"), addr("2001:db8::1")]);
+        assert_eq!(ordered, [addr("2001:db8::1"), addr("192.0.2.1"), addr("192.0.2.2"), addr("192.0.2.3")]);
+        assert_eq!(interleave(vec![addr("192.0.2.1"), addr("192.0.2.1")]), [addr("192.0.2.1")]);
+    }
+
+    #[tokio::test]
+    async fn test_resolve_literals_and_names() {
+        let network = NetworkConfig::default();
+        assert_eq!(resolve("192.0.2.1", 443, &network).await.unwrap(), [addr("192.0.2.1")]);
+        assert_eq!(resolve("[2001:db8::1]", 443, &network).await.unwrap(), [addr("2001:db8::1")]);
+        assert!(matches!(resolve("not an address", 443, &network).await, Err(VpnError::Config(_))));
+        let local = resolve("localhost", 443, &network).await.unwrap();
+        assert!(local.iter().all(|addr| addr.ip().is_loopback()), "{local:?}");
+    }
+
+    #[tokio::test]
+    async fn test_race_skips_failed_addresses() {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let open = listener.local_addr().unwrap();
+        // A port nothing listens on refuses at once
+        let closed = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap().local_addr().unwrap();
+
+        let unreachable = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), closed.port());
+        let winner = race(&[unreachable, closed, open], Duration::from_secs(5), None).await.unwrap();
+        assert_eq!(winner, open);
+        assert!(matches!(race(&[closed], Duration::from_secs(5), None).await, Err(VpnError::Network(_))));
+    }
+}
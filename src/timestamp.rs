@@ -0,0 +1,113 @@
+//! Paired Monotonic and Wall-Clock Timestamps
+//!
+//! Durations must come from the monotonic clock - the wall clock can jump when
+//! NTP corrects it, the user changes the time or the machine resumes from
+//! sleep - but FFI consumers and support dumps need wall-clock times. Events
+//! and snapshots therefore record both at the same moment.
+
+use serde::Serialize;
+use std::ops::Add;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A moment on both the monotonic and the wall clock
+///
+/// Serializes as `{"unix_ms": .., "monotonic_ms": ..}`. `monotonic_ms` counts
+/// from the first timestamp taken in this process, so consumers should
+/// subtract those values (not `unix_ms`) to get durations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Timestamp {
+    #[serde(skip)]
+    pub instant: Instant,
+    /// Milliseconds since the Unix epoch (UTC)
+    pub unix_ms: u64,
+    /// Milliseconds on the monotonic clock since the process's first timestamp
+    pub monotonic_ms: u64,
+}
+
+impl Timestamp {
+    /// The current moment
+    pub fn now() -> Self {
+        let epoch = process_epoch();
+        let instant = Instant::now();
+        Self {
+            instant,
+            unix_ms: unix_ms(SystemTime::now()),
+            monotonic_ms: instant.saturating_duration_since(epoch).as_millis() as u64,
+        }
+    }
+
+    /// Monotonic time elapsed since `earlier` (zero if `earlier` is later)
+    pub fn duration_since(&self, earlier: &Timestamp) -> Duration {
+        self.instant.saturating_duration_since(earlier.instant)
+    }
+
+    /// Monotonic time elapsed since this timestamp was taken
+    pub fn elapsed(&self) -> Duration {
+        self.instant.elapsed()
+    }
+
+    /// How far the wall clock moved against the monotonic clock since `earlier`
+    ///
+    /// Positive when the wall clock jumped forward, negative when it was set
+    /// back; a few milliseconds either way is ordinary rounding.
+    pub fn wall_clock_jump_ms(&self, earlier: &Timestamp) -> i64 {
+        let wall = self.unix_ms as i64 - earlier.unix_ms as i64;
+        let monotonic = self.monotonic_ms as i64 - earlier.monotonic_ms as i64;
+        wall - monotonic
+    }
+
+    /// The wall-clock time as a [`SystemTime`]
+    pub fn system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.unix_ms)
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+
+    /// The moment `rhs` later, advancing both clocks equally
+    fn add(self, rhs: Duration) -> Timestamp {
+        let ms = rhs.as_millis() as u64;
+        Timestamp {
+            instant: self.instant + rhs,
+            unix_ms: self.unix_ms.saturating_add(ms),
+            monotonic_ms: self.monotonic_ms.saturating_add(ms),
+        }
+    }
+}
+
+fn process_epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_jump_does_not_affect_durations() {
+        let start = Timestamp::now();
+        let later = start + Duration::from_secs(5);
+        assert_eq!(later.duration_since(&start), Duration::from_secs(5));
+        assert_eq!(start.duration_since(&later), Duration::ZERO);
+        assert_eq!(later.wall_clock_jump_ms(&start), 0);
+
+        // Wall clock set back an hour while five seconds passed
+        let jumped = Timestamp { unix_ms: later.unix_ms - 3_600_000, ..later };
+        assert_eq!(jumped.duration_since(&start), Duration::from_secs(5));
+        assert_eq!(jumped.wall_clock_jump_ms(&start), -3_600_000);
+
+        let json = serde_json::to_value(start).unwrap();
+        assert_eq!(json["unix_ms"], start.unix_ms);
+        assert_eq!(json["monotonic_ms"], start.monotonic_ms);
+        assert!(json.get("instant").is_none());
+    }
+}
@@ -0,0 +1,467 @@
+//! Non-blocking TUN I/O for the tokio runtime
+//!
+//! [`TunReader`] and [`TunWriter`] block the calling thread, which stalls a
+//! runtime worker for as long as the device is idle. [`split`] turns them
+//! into an [`AsyncTunReader`] and an [`AsyncTunWriter`] with `async`
+//! `read_packet`/`write_packet`:
+//!
+//! - Halves of a Unix TUN device switch the descriptor to non-blocking mode
+//!   and wait for readiness through [`AsyncFd`], so no thread is parked
+//! - Halves without a pollable descriptor (Wintun sessions, whose ring is
+//!   waited on with an event, TAP-Windows adapters, which use overlapped
+//!   I/O, and test doubles) keep their blocking I/O on a dedicated thread per
+//!   direction and exchange packets with the runtime over a channel
+//!
+//! Both are cancel-safe: a `read_packet` dropped before it completes loses
+//! no packet, so the halves can sit in a `tokio::select!`.
+//!
+//! The queues of a multi-queue device are made non-blocking together with
+//! [`split_queues`]: the reader takes packets from whichever queue has one,
+//! and the writer writes to the first.
+
+use super::tun_io::{TunReader, TunWriter};
+use bytes::Bytes;
+#[cfg(unix)]
+use futures::future;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+#[cfg(unix)]
+use std::os::unix::io::RawFd;
+#[cfg(unix)]
+use tokio::io::unix::AsyncFd;
+
+/// Packets buffered per direction between a device thread and the runtime
+const QUEUE_LEN: usize = 64;
+
+/// Read buffer of the device thread, large enough for any IP packet
+const MAX_PACKET_LEN: usize = 65_535;
+
+/// Receive half of a TUN device that waits without blocking a thread
+pub struct AsyncTunReader(ReadHalf);
+
+/// Transmit half of a TUN device that waits without blocking a thread
+pub struct AsyncTunWriter(WriteHalf);
+
+enum ReadHalf {
+    // Each `io` comes before its reader so the descriptor is deregistered
+    // before the last half closes it; `next` is the queue to look at first
+    #[cfg(unix)]
+    Polled { queues: Vec<(Arc<AsyncFd<RawFd>>, TunReader)>, next: usize },
+    Threaded(mpsc::Receiver<io::Result<Vec<u8>>>),
+}
+
+enum WriteHalf {
+    #[cfg(unix)]
+    Polled { io: Arc<AsyncFd<RawFd>>, writer: TunWriter },
+    Threaded { tx: mpsc::Sender<Bytes>, failure: Arc<Mutex<Option<io::Error>>> },
+}
+
+impl AsyncTunReader {
+    /// Read one packet into `buf`, waiting until one arrives
+    ///
+    /// Returns 0 once the device is closed. A packet longer than `buf` is
+    /// truncated, as with a read from the device itself.
+    pub async fn read_packet(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0 {
+            #[cfg(unix)]
+            ReadHalf::Polled { ref mut queues, ref mut next } => loop {
+                // Segments of a super-packet read before come first
+                if let Some((_, reader)) = queues.iter_mut().find(|(_, reader)| reader.has_buffered()) {
+                    return reader.read_packet(buf);
+                }
+                let index = match queues.len() {
+                    1 => 0,
+                    len => {
+                        // Starting after the queue read last, so a busy queue cannot starve the others
+                        let order = (0..len).map(|offset| (*next + offset) % len);
+                        let readable = order.clone().map(|index| Box::pin(queues[index].0.readable()));
+                        let (ready, position, _) = future::select_all(readable).await;
+                        // Readiness stays set for the read below
+                        let _ = ready?;
+                        order.clone().nth(position).unwrap_or(0)
+                    }
+                };
+                *next = (index + 1) % queues.len();
+                let (ref io, ref mut reader) = queues[index];
+                let mut ready = io.readable().await?;
+                match ready.try_io(|_| reader.read_packet(buf)) {
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Ok(result) => return result,
+                    Err(_would_block) => continue,
+                }
+            },
+            ReadHalf::Threaded(ref mut rx) => match rx.recv().await {
+                Some(Ok(packet)) => {
+                    let len = packet.len().min(buf.len());
+                    buf[..len].copy_from_slice(&packet[..len]);
+                    Ok(len)
+                }
+                Some(Err(e)) => Err(e),
+                None => Ok(0),
+            },
+        }
+    }
+}
+
+impl AsyncTunWriter {
+    /// Write one complete packet
+    ///
+    /// Thread-backed halves queue the packet and report a failed write on
+    /// the next call.
+    pub async fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        match self.0 {
+            #[cfg(unix)]
+            WriteHalf::Polled { ref io, ref mut writer } => {
+                if writer.queue_packet(packet) {
+                    return write_queued(io, writer).await;
+                }
+                loop {
+                    let mut ready = io.writable().await?;
+                    if let Ok(result) = ready.try_io(|_| writer.write_packet(packet)) {
+                        return result;
+                    }
+                }
+            }
+            WriteHalf::Threaded { ref tx, ref failure } => {
+                if tx.send(Bytes::copy_from_slice(packet)).await.is_ok() {
+                    return Ok(());
+                }
+                Err(failure
+                    .lock()
+                    .unwrap()
+                    .take()
+                    .unwrap_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "TUN writer stopped")))
+            }
+        }
+    }
+
+    /// Write the packets held back to be merged into super-packets
+    ///
+    /// Only halves of a device with offloads hold packets back; they wait
+    /// for the next packet, which may continue their TCP stream, or for this
+    /// call, which the packet pump makes whenever it has no packet waiting.
+    /// Thread-backed halves flush on their own once their queue runs empty.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        match self.0 {
+            #[cfg(unix)]
+            WriteHalf::Polled { ref io, ref mut writer } => {
+                writer.end_merge();
+                write_queued(io, writer).await
+            }
+            WriteHalf::Threaded { .. } => Ok(()),
+        }
+    }
+}
+
+/// Write what `writer` queued, waiting whenever the device is full
+#[cfg(unix)]
+async fn write_queued(io: &AsyncFd<RawFd>, writer: &mut TunWriter) -> io::Result<()> {
+    loop {
+        let mut ready = io.writable().await?;
+        if let Ok(result) = ready.try_io(|_| writer.write_queued()) {
+            return result;
+        }
+    }
+}
+
+impl std::fmt::Debug for AsyncTunReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AsyncTunReader")
+    }
+}
+
+impl std::fmt::Debug for AsyncTunWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AsyncTunWriter")
+    }
+}
+
+/// Make both halves of a device non-blocking
+///
+/// Must be called within a tokio runtime, whose reactor then drives the
+/// device. The interface goes away once both returned halves are dropped.
+pub fn split(tun: (TunReader, TunWriter)) -> io::Result<(AsyncTunReader, AsyncTunWriter)> {
+    #[cfg(unix)]
+    let tun = match polled(tun)? {
+        Ok(halves) => return Ok(halves),
+        Err(tun) => tun,
+    };
+    let (reader, writer) = tun;
+    Ok((threaded_reader(reader)?, threaded_writer(writer)?))
+}
+
+/// Register the descriptor both halves share, if they share one
+#[cfg(unix)]
+#[allow(clippy::type_complexity)]
+fn polled(
+    (reader, writer): (TunReader, TunWriter),
+) -> io::Result<std::result::Result<(AsyncTunReader, AsyncTunWriter), (TunReader, TunWriter)>> {
+    let fd = match (reader.raw_fd(), writer.raw_fd()) {
+        (Some(fd), Some(writer_fd)) if fd == writer_fd => fd,
+        _ => return Ok(Err((reader, writer))),
+    };
+    set_nonblocking(fd)?;
+    let io = Arc::new(AsyncFd::new(fd)?);
+    Ok(Ok((
+        AsyncTunReader(ReadHalf::Polled { queues: vec![(io.clone(), reader)], next: 0 }),
+        AsyncTunWriter(WriteHalf::Polled { io, writer }),
+    )))
+}
+
+/// Make the queues of a multi-queue device non-blocking
+///
+/// Every queue must have a pollable descriptor. The reader serves all of
+/// them; the writer writes to the first, and only the first queue's
+/// transmit half is kept.
+#[cfg(unix)]
+pub fn split_queues(queues: Vec<(TunReader, TunWriter)>) -> io::Result<(AsyncTunReader, AsyncTunWriter)> {
+    let unpollable = || io::Error::other("TUN queue without a pollable descriptor");
+    let mut queues = queues.into_iter();
+    let (first, writer) = queues.next().ok_or_else(|| io::Error::other("No TUN queue"))?;
+    if writer.raw_fd().is_none() || writer.raw_fd() != first.raw_fd() {
+        return Err(unpollable());
+    }
+    let polled = std::iter::once(first)
+        .chain(queues.map(|(reader, _)| reader))
+        .map(|reader| {
+            let fd = reader.raw_fd().ok_or_else(unpollable)?;
+            set_nonblocking(fd)?;
+            Ok((Arc::new(AsyncFd::new(fd)?), reader))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let io = polled[0].0.clone();
+    Ok((AsyncTunReader(ReadHalf::Polled { queues: polled, next: 0 }), AsyncTunWriter(WriteHalf::Polled { io, writer })))
+}
+
+#[cfg(unix)]
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fd belongs to the halves being converted, which keep it open
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    // SAFETY: as above; only O_NONBLOCK is added to the existing flags
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Device → channel on a thread, until the device closes or the half is dropped
+fn threaded_reader(mut reader: TunReader) -> io::Result<AsyncTunReader> {
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    std::thread::Builder::new().name("tun-rx".to_string()).spawn(move || {
+        let mut buf = vec![0u8; MAX_PACKET_LEN];
+        loop {
+            let packet = match reader.read_packet(&mut buf) {
+                Ok(0) => return,
+                Ok(len) => Ok(buf[..len].to_vec()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => Err(e),
+            };
+            let failed = packet.is_err();
+            if tx.blocking_send(packet).is_err() || failed {
+                return;
+            }
+        }
+    })?;
+    Ok(AsyncTunReader(ReadHalf::Threaded(rx)))
+}
+
+/// Channel → device on a thread, until the half is dropped or a write fails
+fn threaded_writer(mut writer: TunWriter) -> io::Result<AsyncTunWriter> {
+    let (tx, mut rx) = mpsc::channel::<Bytes>(QUEUE_LEN);
+    let failure = Arc::new(Mutex::new(None));
+    std::thread::Builder::new().name("tun-tx".to_string()).spawn({
+        let failure = failure.clone();
+        move || {
+            while let Some(packet) = rx.blocking_recv() {
+                let mut written = writer.write_packet(&packet);
+                if written.is_ok() && rx.is_empty() {
+                    written = writer.flush();
+                }
+                if let Err(e) = written {
+                    *failure.lock().unwrap() = Some(e);
+                    return;
+                }
+            }
+        }
+    })?;
+    Ok(AsyncTunWriter(WriteHalf::Threaded { tx, failure }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    #[cfg(unix)]
+    use std::os::unix::io::AsRawFd;
+    use std::sync::mpsc as std_mpsc;
+    use std::time::Duration;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_polled_halves_do_not_block_the_runtime() {
+        use std::os::unix::net::UnixDatagram;
+
+        // A datagram socketpair behaves like a TUN fd: one packet per read/write
+        let (tun_side, peer) = UnixDatagram::pair().unwrap();
+        let tun_side = Arc::new(tun_side);
+        let (mut reader, mut writer) = split((
+            TunReader::pollable(DatagramIo(tun_side.clone())),
+            TunWriter::pollable(DatagramIo(tun_side)),
+        ))
+        .unwrap();
+        assert!(matches!(reader.0, ReadHalf::Polled { .. }));
+
+        // Single-threaded runtime: a blocking read would starve everything else
+        let rx_task = tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let len = reader.read_packet(&mut buf).await.unwrap();
+            buf[..len].to_vec()
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!rx_task.is_finished());
+
+        writer.write_packet(b"outbound").await.unwrap();
+        let mut buf = [0u8; 64];
+        let len = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"outbound");
+
+        peer.send(b"inbound").unwrap();
+        let packet = tokio::time::timeout(Duration::from_secs(5), rx_task).await.unwrap().unwrap();
+        assert_eq!(packet, b"inbound");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_queues_are_read_together() {
+        use std::os::unix::net::UnixDatagram;
+
+        let (first, first_peer) = UnixDatagram::pair().unwrap();
+        let (second, second_peer) = UnixDatagram::pair().unwrap();
+        let queue = |socket: &Arc<UnixDatagram>| {
+            (TunReader::pollable(DatagramIo(socket.clone())), TunWriter::pollable(DatagramIo(socket.clone())))
+        };
+        let (mut reader, mut writer) = split_queues(vec![queue(&Arc::new(first)), queue(&Arc::new(second))]).unwrap();
+
+        // Whichever queue the kernel picked, the packet is read
+        let mut buf = [0u8; 64];
+        second_peer.send(b"second").unwrap();
+        let len = tokio::time::timeout(Duration::from_secs(5), reader.read_packet(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(&buf[..len], b"second");
+        first_peer.send(b"first").unwrap();
+        second_peer.send(b"second again").unwrap();
+        let mut read = Vec::new();
+        for _ in 0..2 {
+            let len = reader.read_packet(&mut buf).await.unwrap();
+            read.push(buf[..len].to_vec());
+        }
+        read.sort();
+        assert_eq!(read, [b"first".to_vec(), b"second again".to_vec()]);
+
+        // Writes go to the first queue
+        writer.write_packet(b"outbound").await.unwrap();
+        writer.flush().await.unwrap();
+        let len = first_peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"outbound");
+    }
+
+    #[tokio::test]
+    async fn test_threaded_halves_survive_cancellation() {
+        let (tun_in, tun_packets) = std_mpsc::channel::<Vec<u8>>();
+        let (written_tx, written) = std_mpsc::channel();
+        let (mut reader, mut writer) = split((
+            TunReader::new(QueuedPackets(tun_packets)),
+            TunWriter::new(Recorder(written_tx)),
+        ))
+        .unwrap();
+
+        // A read abandoned in a select! must not swallow the next packet
+        let mut buf = [0u8; 64];
+        tokio::select! {
+            _ = reader.read_packet(&mut buf) => panic!("nothing was sent"),
+            _ = tokio::time::sleep(Duration::from_millis(20)) => {}
+        }
+        tun_in.send(b"first".to_vec()).unwrap();
+        let len = reader.read_packet(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"first");
+
+        writer.write_packet(b"reply").await.unwrap();
+        assert_eq!(written.recv_timeout(Duration::from_secs(5)).unwrap(), b"reply");
+
+        // Closing the device ends the stream
+        drop(tun_in);
+        assert_eq!(reader.read_packet(&mut buf).await.unwrap(), 0);
+
+        // A failed write surfaces on a later call
+        drop(written);
+        writer.write_packet(b"lost").await.unwrap();
+        let mut failed = false;
+        for _ in 0..100 {
+            if writer.write_packet(b"lost").await.is_err() {
+                failed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(failed);
+    }
+
+    /// Socketpair end standing in for a pollable TUN queue
+    #[cfg(unix)]
+    struct DatagramIo(Arc<std::os::unix::net::UnixDatagram>);
+
+    #[cfg(unix)]
+    impl Read for DatagramIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf)
+        }
+    }
+
+    #[cfg(unix)]
+    impl Write for DatagramIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(unix)]
+    impl AsRawFd for DatagramIo {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+
+    /// Yields one packet per read, blocking like a TUN device; end of file once the sender is gone
+    struct QueuedPackets(std_mpsc::Receiver<Vec<u8>>);
+
+    impl Read for QueuedPackets {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Ok(packet) = self.0.recv() else {
+                return Ok(0);
+            };
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+    }
+
+    /// Forwards every packet written; fails once the receiver is gone
+    struct Recorder(std_mpsc::Sender<Vec<u8>>);
+
+    impl Write for Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "closed"))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Coexistence with other VPN clients
+//!
+//! Detects tunnel interfaces owned by other VPN software (WireGuard, OpenVPN,
+//! Tailscale, AnyConnect, ...) so route planning can honour the configured
+//! [`CoexistencePolicy`](crate::config::CoexistencePolicy) instead of blindly
+//! replacing the default route.
+
+/// Interface name prefixes used by common VPN software
+///
+/// Plain `tun`/`tap` names are left out: any program may create such a
+/// device, and taking each for a VPN would keep the default route from being
+/// replaced for no reason.
+const VPN_INTERFACE_PREFIXES: &[&str] = &[
+    "wg",
+    "utun",
+    "ppp",
+    "tailscale",
+    "zt",
+    "nordlynx",
+    "nordtun",
+    "ipsec",
+    "cscotun",
+    "gpd",
+    "proton",
+    "mullvad",
+];
+
+/// Whether an interface name looks like it belongs to a VPN
+pub fn is_vpn_interface(name: &str) -> bool {
+    VPN_INTERFACE_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+/// Active VPN interfaces other than `own_interface`
+pub fn detect_vpn_interfaces(own_interface: &str) -> Vec<String> {
+    list_active_interfaces()
+        .into_iter()
+        .filter(|name| name != own_interface && is_vpn_interface(name))
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn list_active_interfaces() -> Vec<String> {
+    let entries = match std::fs::read_dir("/sys/class/net") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter(|name| {
+            // Point-to-point tunnels report "unknown" rather than "up"
+            let state = std::fs::read_to_string(format!("/sys/class/net/{}/operstate", name))
+                .unwrap_or_default();
+            state.trim() != "down"
+        })
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+fn list_active_interfaces() -> Vec<String> {
+    use std::process::Command;
+
+    let output = match Command::new("ifconfig").arg("-l").output() {
+        Ok(output) => output,
+        Err(_) => return Vec::new(),
+    };
+
+    // macOS keeps several utun devices around for system services; only
+    // those carrying an IPv4 address are treated as active VPNs
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .filter(|name| {
+            Command::new("ifconfig")
+                .arg(name)
+                .output()
+                .map(|o| String::from_utf8_lossy(&o.stdout).contains("inet "))
+                .unwrap_or(false)
+        })
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn list_active_interfaces() -> Vec<String> {
+    Vec::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vpn_interface_names() {
+        assert!(is_vpn_interface("wg0"));
+        assert!(is_vpn_interface("tailscale0"));
+        assert!(is_vpn_interface("utun4"));
+        assert!(is_vpn_interface("cscotun0"));
+        assert!(!is_vpn_interface("tun0"));
+        assert!(!is_vpn_interface("tap1"));
+        assert!(!is_vpn_interface("eth0"));
+        assert!(!is_vpn_interface("wlan0"));
+        assert!(!is_vpn_interface("lo"));
+    }
+}
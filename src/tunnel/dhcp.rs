@@ -0,0 +1,779 @@
+//! DHCP client for bridged hubs
+//!
+//! On a bridged hub the address comes from a DHCP server on the segment
+//! (SecureNAT or the site's own), not from the login response. [`DhcpClient`]
+//! speaks DHCPv4 (RFC 2131) over the session's Ethernet frames:
+//!
+//! - DISCOVER, OFFER, REQUEST, ACK to obtain a lease, retransmitting with
+//!   exponential backoff from [`INITIAL_RETRANSMIT`] up to [`MAX_RETRANSMIT`]
+//! - unicast REQUEST to the leasing server from T1, broadcast from T2
+//! - a new discovery when the lease runs out or the server sends a NAK
+//!
+//! Like [`NeighborStack`](super::neighbor::NeighborStack) it does no I/O:
+//! frames go in and out and time is passed in, with [`poll`](DhcpClient::poll)
+//! driving the timers. [`acquire`] runs it over the session until the first
+//! lease; the packet pump keeps it going afterwards.
+
+use super::l2::{ethernet_frame, ipv4_at, mac_at, MacAddr, BROADCAST, ETHERNET_HEADER_LEN, ETHERTYPE_IPV4};
+use super::pump::{PacketSink, PacketSource};
+use super::TunnelConfig;
+use crate::error::{Result, VpnError};
+use bytes::Bytes;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// First retransmission delay while discovering or requesting
+pub const INITIAL_RETRANSMIT: Duration = Duration::from_secs(4);
+
+/// Longest retransmission delay while discovering or requesting
+pub const MAX_RETRANSMIT: Duration = Duration::from_secs(64);
+
+/// REQUESTs sent for one offer before discovering again
+pub const MAX_REQUESTS: u32 = 4;
+
+/// Shortest wait between REQUESTs while renewing or rebinding
+pub const MIN_RENEW_RETRY: Duration = Duration::from_secs(60);
+
+/// Lease assumed when the ACK carries no lease time
+pub const DEFAULT_LEASE_TIME: Duration = Duration::from_secs(3600);
+
+/// How often [`acquire`] runs the timers
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const PROTOCOL_UDP: u8 = 17;
+/// Fixed BOOTP fields and the magic cookie, before the options
+const BOOTP_LEN: usize = 240;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_HOSTNAME: u8 = 12;
+const OPTION_DOMAIN_NAME: u8 = 15;
+const OPTION_NETBIOS_NAME_SERVERS: u8 = 44;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_REQUEST_LIST: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_CLIENT_ID: u8 = 61;
+const OPTION_END: u8 = 255;
+
+/// Options asked for in every request
+const REQUESTED_OPTIONS: [u8; 8] = [
+    OPTION_SUBNET_MASK,
+    OPTION_ROUTER,
+    OPTION_DNS,
+    OPTION_DOMAIN_NAME,
+    OPTION_NETBIOS_NAME_SERVERS,
+    OPTION_LEASE_TIME,
+    OPTION_RENEWAL_TIME,
+    OPTION_REBINDING_TIME,
+];
+
+/// Address and options leased by the DHCP server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DhcpLease {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// First router option; the tunnel gateway
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+    pub wins_servers: Vec<Ipv4Addr>,
+    pub domain: Option<String>,
+    /// Server identifier, renewals are sent here
+    pub server: Ipv4Addr,
+    /// `Duration::MAX` for an infinite lease
+    pub lease_time: Duration,
+    /// T1, when renewal starts
+    pub renewal_time: Duration,
+    /// T2, when rebinding starts
+    pub rebinding_time: Duration,
+    /// When the ACK arrived; the times above count from here
+    pub acquired: Instant,
+}
+
+impl DhcpLease {
+    /// Netmask assumed when the server sends none
+    pub const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+
+    /// Use the lease for the tunnel address, gateway and resolvers
+    ///
+    /// Without a router option the server is taken as the gateway; lists the
+    /// server did not send keep what `config` already had.
+    pub fn apply_to(&self, config: &mut TunnelConfig) {
+        config.local_ip = self.address;
+        config.netmask = self.netmask;
+        config.remote_ip = self.router.unwrap_or(self.server);
+        if !self.dns_servers.is_empty() {
+            config.dns_servers = self.dns_servers.clone();
+        }
+        if !self.wins_servers.is_empty() {
+            config.wins_servers = self.wins_servers.clone();
+        }
+        if self.domain.is_some() {
+            config.dns_domain = self.domain.clone();
+        }
+    }
+
+    /// Time left until the lease expires at `now`
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.lease_time.saturating_sub(now.saturating_duration_since(self.acquired))
+    }
+}
+
+/// Where the client is in the RFC 2131 state machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpState {
+    /// Not started
+    Init,
+    /// DISCOVER sent, waiting for an offer
+    Selecting,
+    /// REQUEST sent for an offer, waiting for the ACK
+    Requesting,
+    /// Holding a lease
+    Bound,
+    /// Past T1, asking the leasing server to extend
+    Renewing,
+    /// Past T2, asking any server to extend
+    Rebinding,
+}
+
+/// DHCP reply addressed to this client
+#[derive(Debug, Default)]
+struct Reply {
+    kind: u8,
+    xid: u32,
+    your_ip: Option<Ipv4Addr>,
+    server_mac: MacAddr,
+    server_id: Option<Ipv4Addr>,
+    netmask: Option<Ipv4Addr>,
+    routers: Vec<Ipv4Addr>,
+    dns_servers: Vec<Ipv4Addr>,
+    wins_servers: Vec<Ipv4Addr>,
+    domain: Option<String>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+/// DHCPv4 client state for one interface
+///
+/// Time is passed in by the caller, which also has to call
+/// [`poll`](Self::poll) regularly for retransmissions and lease timers.
+#[derive(Debug)]
+pub struct DhcpClient {
+    mac: MacAddr,
+    hostname: Option<String>,
+    state: DhcpState,
+    xid: u32,
+    lease: Option<DhcpLease>,
+    // Offer being requested: address and server identifier
+    offer: Option<(Ipv4Addr, Ipv4Addr)>,
+    // Leasing server's hardware address, for unicast renewals
+    server_mac: MacAddr,
+    attempts: u32,
+    backoff: Duration,
+    next_send: Instant,
+}
+
+impl DhcpClient {
+    pub fn new(mac: MacAddr) -> Self {
+        Self {
+            mac,
+            hostname: None,
+            state: DhcpState::Init,
+            xid: 0,
+            lease: None,
+            offer: None,
+            server_mac: BROADCAST,
+            attempts: 0,
+            backoff: INITIAL_RETRANSMIT,
+            next_send: Instant::now(),
+        }
+    }
+
+    /// Send `hostname` (option 12) so the server can register it
+    pub fn with_hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    pub fn state(&self) -> DhcpState {
+        self.state
+    }
+
+    /// Current lease; kept while renewing and rebinding
+    pub fn lease(&self) -> Option<&DhcpLease> {
+        self.lease.as_ref()
+    }
+
+    /// Start (or restart) discovery, returning the DISCOVER frame
+    pub fn start(&mut self, now: Instant) -> Bytes {
+        self.lease = None;
+        self.offer = None;
+        self.state = DhcpState::Selecting;
+        self.new_transaction(now);
+        self.discover()
+    }
+
+    /// Handle a frame received from the session
+    ///
+    /// `None` if it is not a DHCP reply for this client, otherwise the frames
+    /// to send in response (possibly none).
+    pub fn handle_frame(&mut self, frame: &[u8], now: Instant) -> Option<Vec<Bytes>> {
+        let reply = parse_reply(frame, self.mac)?;
+        if reply.xid != self.xid {
+            return Some(Vec::new());
+        }
+        let frames = match (self.state, reply.kind) {
+            (DhcpState::Selecting, DHCPOFFER) => {
+                let (Some(offered), Some(server)) = (reply.your_ip, reply.server_id) else {
+                    return Some(Vec::new());
+                };
+                log::debug!("DHCP offer of {} from {}", offered, server);
+                self.offer = Some((offered, server));
+                self.state = DhcpState::Requesting;
+                self.attempts = 1;
+                self.backoff = INITIAL_RETRANSMIT;
+                self.next_send = now + self.backoff;
+                vec![self.request()]
+            }
+            (DhcpState::Requesting | DhcpState::Renewing | DhcpState::Rebinding, DHCPACK) => {
+                match self.lease_from(&reply, now) {
+                    Some(lease) => {
+                        if self.lease.as_ref().is_some_and(|old| old.address != lease.address) {
+                            log::warn!("DHCP server moved the tunnel address to {}", lease.address);
+                        }
+                        log::info!("DHCP lease of {} from {} for {:?}", lease.address, lease.server, lease.lease_time);
+                        self.lease = Some(lease);
+                        self.server_mac = reply.server_mac;
+                        self.offer = None;
+                        self.state = DhcpState::Bound;
+                    }
+                    None => log::warn!("Ignoring DHCP ACK without a usable address"),
+                }
+                Vec::new()
+            }
+            (DhcpState::Requesting | DhcpState::Renewing | DhcpState::Rebinding, DHCPNAK) => {
+                log::warn!("DHCP server refused the lease, discovering again");
+                vec![self.start(now)]
+            }
+            _ => Vec::new(),
+        };
+        Some(frames)
+    }
+
+    /// Retransmit when due and follow the lease through renewal, rebinding and expiry
+    pub fn poll(&mut self, now: Instant) -> Vec<Bytes> {
+        match self.state {
+            DhcpState::Init => Vec::new(),
+            DhcpState::Selecting | DhcpState::Requesting if now < self.next_send => Vec::new(),
+            DhcpState::Selecting => {
+                self.back_off(now);
+                vec![self.discover()]
+            }
+            DhcpState::Requesting if self.attempts >= MAX_REQUESTS => {
+                log::debug!("No DHCP ACK for the offer, discovering again");
+                vec![self.start(now)]
+            }
+            DhcpState::Requesting => {
+                self.attempts += 1;
+                self.back_off(now);
+                vec![self.request()]
+            }
+            DhcpState::Bound | DhcpState::Renewing | DhcpState::Rebinding => self.poll_lease(now),
+        }
+    }
+
+    fn poll_lease(&mut self, now: Instant) -> Vec<Bytes> {
+        let Some(ref lease) = self.lease else {
+            return vec![self.start(now)];
+        };
+        let elapsed = now.saturating_duration_since(lease.acquired);
+        let next_state = if elapsed >= lease.lease_time {
+            log::warn!("DHCP lease of {} expired, discovering again", lease.address);
+            return vec![self.start(now)];
+        } else if elapsed >= lease.rebinding_time {
+            DhcpState::Rebinding
+        } else if elapsed >= lease.renewal_time {
+            DhcpState::Renewing
+        } else {
+            return Vec::new();
+        };
+        // Half the time to the next deadline, but not too often (RFC 2131 4.4.5)
+        let deadline = if next_state == DhcpState::Rebinding { lease.lease_time } else { lease.rebinding_time };
+        let retry = (deadline.saturating_sub(elapsed) / 2).max(MIN_RENEW_RETRY);
+
+        if next_state != self.state {
+            self.state = next_state;
+            self.new_transaction(now);
+        } else if now < self.next_send {
+            return Vec::new();
+        }
+        self.next_send = now + retry;
+        vec![self.request()]
+    }
+
+    fn new_transaction(&mut self, now: Instant) {
+        self.xid = rand::random();
+        self.attempts = 0;
+        self.backoff = INITIAL_RETRANSMIT;
+        self.next_send = now + self.backoff;
+    }
+
+    fn back_off(&mut self, now: Instant) {
+        self.backoff = (self.backoff * 2).min(MAX_RETRANSMIT);
+        self.next_send = now + self.backoff;
+    }
+
+    fn lease_from(&self, reply: &Reply, now: Instant) -> Option<DhcpLease> {
+        let address = reply.your_ip?;
+        let server = reply
+            .server_id
+            .or(self.offer.map(|(_, server)| server))
+            .or(self.lease.as_ref().map(|lease| lease.server))?;
+        let seconds = |secs: u32| if secs == u32::MAX { Duration::MAX } else { Duration::from_secs(secs.into()) };
+        let lease_time = reply.lease_time.map_or(DEFAULT_LEASE_TIME, seconds);
+        let renewal_time = reply.renewal_time.map_or(lease_time / 2, seconds).min(lease_time);
+        let rebinding_time = reply.rebinding_time.map_or(lease_time / 8 * 7, seconds).clamp(renewal_time, lease_time);
+        Some(DhcpLease {
+            address,
+            netmask: reply.netmask.unwrap_or(DhcpLease::DEFAULT_NETMASK),
+            router: reply.routers.first().copied(),
+            dns_servers: reply.dns_servers.clone(),
+            wins_servers: reply.wins_servers.clone(),
+            domain: reply.domain.clone(),
+            server,
+            lease_time,
+            renewal_time,
+            rebinding_time,
+            acquired: now,
+        })
+    }
+
+    fn discover(&self) -> Bytes {
+        let options = self.common_options(DHCPDISCOVER);
+        self.frame(
+            self.message(Ipv4Addr::UNSPECIFIED, true, options),
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::BROADCAST,
+            BROADCAST,
+        )
+    }
+
+    /// REQUEST for the offer, or to extend the lease while renewing or rebinding
+    fn request(&self) -> Bytes {
+        let mut options = self.common_options(DHCPREQUEST);
+        match (self.state, self.offer, self.lease.as_ref()) {
+            (DhcpState::Requesting, Some((offered, server)), _) => {
+                options.push((OPTION_REQUESTED_IP, offered.octets().to_vec()));
+                options.push((OPTION_SERVER_ID, server.octets().to_vec()));
+                self.frame(
+                    self.message(Ipv4Addr::UNSPECIFIED, true, options),
+                    Ipv4Addr::UNSPECIFIED,
+                    Ipv4Addr::BROADCAST,
+                    BROADCAST,
+                )
+            }
+            (DhcpState::Renewing, _, Some(lease)) => {
+                self.frame(self.message(lease.address, false, options), lease.address, lease.server, self.server_mac)
+            }
+            (_, _, Some(lease)) => {
+                self.frame(self.message(lease.address, false, options), lease.address, Ipv4Addr::BROADCAST, BROADCAST)
+            }
+            (_, _, None) => self.discover(),
+        }
+    }
+
+    fn common_options(&self, kind: u8) -> Vec<(u8, Vec<u8>)> {
+        let mut client_id = vec![HTYPE_ETHERNET];
+        client_id.extend(self.mac);
+        let mut options = vec![(OPTION_MESSAGE_TYPE, vec![kind]), (OPTION_CLIENT_ID, client_id)];
+        if let Some(ref hostname) = self.hostname {
+            options.push((OPTION_HOSTNAME, hostname.as_bytes().iter().copied().take(255).collect()));
+        }
+        options.push((OPTION_PARAMETER_REQUEST_LIST, REQUESTED_OPTIONS.to_vec()));
+        options
+    }
+
+    /// BOOTP request with `options`
+    fn message(&self, client_ip: Ipv4Addr, broadcast: bool, options: Vec<(u8, Vec<u8>)>) -> Vec<u8> {
+        let mut message = vec![0u8; BOOTP_LEN];
+        message[0] = BOOTREQUEST;
+        message[1] = HTYPE_ETHERNET;
+        message[2] = 6;
+        message[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        let flags = if broadcast { FLAG_BROADCAST } else { 0 };
+        message[10..12].copy_from_slice(&flags.to_be_bytes());
+        message[12..16].copy_from_slice(&client_ip.octets());
+        message[28..34].copy_from_slice(&self.mac);
+        message[236..240].copy_from_slice(&MAGIC_COOKIE);
+        for (code, value) in options {
+            message.push(code);
+            message.push(value.len() as u8);
+            message.extend(value);
+        }
+        message.push(OPTION_END);
+        message
+    }
+
+    /// Ethernet frame carrying `message` from the client port to the server port
+    fn frame(&self, message: Vec<u8>, source: Ipv4Addr, destination: Ipv4Addr, destination_mac: MacAddr) -> Bytes {
+        let udp_len = UDP_HEADER_LEN + message.len();
+        let total_len = IPV4_HEADER_LEN + udp_len;
+        let mut packet = Vec::with_capacity(total_len);
+        packet.extend([0x45, 0]);
+        packet.extend((total_len as u16).to_be_bytes());
+        // Identification, flags and fragment offset, TTL, protocol, checksum
+        packet.extend([0, 0, 0, 0, 64, PROTOCOL_UDP, 0, 0]);
+        packet.extend(source.octets());
+        packet.extend(destination.octets());
+        let checksum = ipv4_checksum(&packet);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+        // The UDP checksum is optional over IPv4
+        packet.extend(CLIENT_PORT.to_be_bytes());
+        packet.extend(SERVER_PORT.to_be_bytes());
+        packet.extend((udp_len as u16).to_be_bytes());
+        packet.extend([0, 0]);
+        packet.extend(message);
+        ethernet_frame(destination_mac, self.mac, ETHERTYPE_IPV4, &packet)
+    }
+}
+
+/// Run `client` over `session` until it holds a lease
+///
+/// Frames other than DHCP replies are dropped meanwhile. Fails with
+/// [`VpnError::Timeout`] if no lease is granted within `deadline`.
+pub async fn acquire<T: PacketSink + PacketSource>(
+    client: &mut DhcpClient,
+    session: &mut T,
+    deadline: Duration,
+) -> Result<DhcpLease> {
+    let exchange = async {
+        session.send_packet(client.start(Instant::now())).await?;
+        let mut timers = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            // Session receives are cancel-safe, so the timer can interrupt them
+            let frames = tokio::select! {
+                frame = session.recv_packet() => match client.handle_frame(&frame?, Instant::now()) {
+                    Some(frames) => frames,
+                    None => continue,
+                },
+                _ = timers.tick() => client.poll(Instant::now()),
+            };
+            for frame in frames {
+                session.send_packet(frame).await?;
+            }
+            if let Some(lease) = client.lease() {
+                return Ok(lease.clone());
+            }
+        }
+    };
+    tokio::time::timeout(deadline, exchange)
+        .await
+        .unwrap_or_else(|_| Err(VpnError::Timeout(format!("No DHCP lease within {}s", deadline.as_secs()))))
+}
+
+/// The DHCP reply in `frame`, if it is one addressed to `mac`
+fn parse_reply(frame: &[u8], mac: MacAddr) -> Option<Reply> {
+    if frame.len() < ETHERNET_HEADER_LEN || u16::from_be_bytes([frame[12], frame[13]]) != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let ip = &frame[ETHERNET_HEADER_LEN..];
+    if ip.len() < IPV4_HEADER_LEN || ip[0] >> 4 != 4 || ip[9] != PROTOCOL_UDP {
+        return None;
+    }
+    let udp = ip.get(usize::from(ip[0] & 0x0f) * 4..)?;
+    if udp.len() < UDP_HEADER_LEN || u16::from_be_bytes([udp[2], udp[3]]) != CLIENT_PORT {
+        return None;
+    }
+    let bootp = &udp[UDP_HEADER_LEN..];
+    if bootp.len() < BOOTP_LEN || bootp[0] != BOOTREPLY || mac_at(bootp, 28) != mac || bootp[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+
+    let your_ip = ipv4_at(bootp, 16);
+    let mut reply = Reply {
+        xid: u32::from_be_bytes([bootp[4], bootp[5], bootp[6], bootp[7]]),
+        your_ip: (!your_ip.is_unspecified()).then_some(your_ip),
+        server_mac: mac_at(frame, 6),
+        ..Reply::default()
+    };
+    let mut options = &bootp[BOOTP_LEN..];
+    while let Some((&code, rest)) = options.split_first() {
+        match code {
+            OPTION_PAD => {
+                options = rest;
+                continue;
+            }
+            OPTION_END => break,
+            _ => {}
+        }
+        let (&len, rest) = rest.split_first()?;
+        let value = rest.get(..usize::from(len))?;
+        options = &rest[usize::from(len)..];
+        match code {
+            OPTION_MESSAGE_TYPE => reply.kind = *value.first()?,
+            OPTION_SUBNET_MASK => reply.netmask = addresses(value).first().copied(),
+            OPTION_ROUTER => reply.routers = addresses(value),
+            OPTION_DNS => reply.dns_servers = addresses(value),
+            OPTION_NETBIOS_NAME_SERVERS => reply.wins_servers = addresses(value),
+            OPTION_SERVER_ID => reply.server_id = addresses(value).first().copied(),
+            OPTION_DOMAIN_NAME => {
+                let domain = String::from_utf8_lossy(value).trim_end_matches('\0').to_string();
+                reply.domain = (!domain.is_empty()).then_some(domain);
+            }
+            OPTION_LEASE_TIME => reply.lease_time = seconds(value),
+            OPTION_RENEWAL_TIME => reply.renewal_time = seconds(value),
+            OPTION_REBINDING_TIME => reply.rebinding_time = seconds(value),
+            _ => {}
+        }
+    }
+    (reply.kind != 0).then_some(reply)
+}
+
+fn addresses(value: &[u8]) -> Vec<Ipv4Addr> {
+    value.chunks_exact(4).map(|octets| ipv4_at(octets, 0)).collect()
+}
+
+fn seconds(value: &[u8]) -> Option<u32> {
+    Some(u32::from_be_bytes(value.try_into().ok()?))
+}
+
+/// Internet checksum of an IPv4 header
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    const CLIENT_MAC: MacAddr = [0x02, 0, 0, 0, 0, 0x01];
+    const SERVER_MAC: MacAddr = [0x02, 0, 0, 0, 0, 0xfe];
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 1, 0, 1);
+
+    /// The client's request in `frame`: message type, xid, client address,
+    /// requested address and IP destination
+    fn request(frame: &[u8]) -> (u8, u32, Ipv4Addr, Option<Ipv4Addr>, Ipv4Addr) {
+        let ip = &frame[ETHERNET_HEADER_LEN..];
+        assert_eq!(ipv4_checksum(&ip[..IPV4_HEADER_LEN]), 0);
+        let bootp = &ip[IPV4_HEADER_LEN + UDP_HEADER_LEN..];
+        assert_eq!(bootp[0], BOOTREQUEST);
+        assert_eq!(mac_at(bootp, 28), CLIENT_MAC);
+        let (mut kind, mut requested) = (0, None);
+        let mut options = &bootp[BOOTP_LEN..];
+        while options[0] != OPTION_END {
+            let value = &options[2..2 + usize::from(options[1])];
+            match options[0] {
+                OPTION_MESSAGE_TYPE => kind = value[0],
+                OPTION_REQUESTED_IP => requested = Some(ipv4_at(value, 0)),
+                _ => {}
+            }
+            options = &options[2 + value.len()..];
+        }
+        let xid = u32::from_be_bytes([bootp[4], bootp[5], bootp[6], bootp[7]]);
+        (kind, xid, ipv4_at(bootp, 12), requested, ipv4_at(ip, 16))
+    }
+
+    /// Server reply of `kind` offering `address` in transaction `xid`
+    fn reply(kind: u8, xid: u32, address: Ipv4Addr, lease_secs: u32) -> Vec<u8> {
+        let mut bootp = vec![0u8; BOOTP_LEN];
+        bootp[0] = BOOTREPLY;
+        bootp[4..8].copy_from_slice(&xid.to_be_bytes());
+        bootp[16..20].copy_from_slice(&address.octets());
+        bootp[28..34].copy_from_slice(&CLIENT_MAC);
+        bootp[236..240].copy_from_slice(&MAGIC_COOKIE);
+        for (code, value) in [
+            (OPTION_MESSAGE_TYPE, vec![kind]),
+            (OPTION_SERVER_ID, SERVER.octets().to_vec()),
+            (OPTION_SUBNET_MASK, vec![255, 255, 0, 0]),
+            (OPTION_ROUTER, vec![10, 1, 0, 254]),
+            (OPTION_DNS, vec![10, 1, 0, 53, 10, 1, 0, 54]),
+            (OPTION_DOMAIN_NAME, b"corp.example".to_vec()),
+            (OPTION_LEASE_TIME, lease_secs.to_be_bytes().to_vec()),
+        ] {
+            bootp.push(code);
+            bootp.push(value.len() as u8);
+            bootp.extend(value);
+        }
+        bootp.extend([OPTION_PAD, OPTION_END]);
+
+        let mut packet = vec![0x45, 0, 0, 0, 0, 0, 0, 0, 64, PROTOCOL_UDP, 0, 0];
+        packet.extend(SERVER.octets());
+        packet.extend(address.octets());
+        packet.extend(SERVER_PORT.to_be_bytes());
+        packet.extend(CLIENT_PORT.to_be_bytes());
+        packet.extend(((UDP_HEADER_LEN + bootp.len()) as u16).to_be_bytes());
+        packet.extend([0, 0]);
+        packet.extend(bootp);
+        ethernet_frame(BROADCAST, SERVER_MAC, ETHERTYPE_IPV4, &packet).to_vec()
+    }
+
+    #[test]
+    fn test_lease_exchange() {
+        let start = Instant::now();
+        let mut client = DhcpClient::new(CLIENT_MAC).with_hostname("laptop");
+        let (kind, xid, ..) = request(&client.start(start));
+        assert_eq!(kind, DHCPDISCOVER);
+        assert_eq!(client.state(), DhcpState::Selecting);
+
+        // Unrelated frames and stale transactions are left alone
+        assert!(client.handle_frame(&[0u8; 60], start).is_none());
+        assert_eq!(
+            client.handle_frame(&reply(DHCPOFFER, xid ^ 1, Ipv4Addr::new(10, 1, 2, 3), 600), start),
+            Some(Vec::new())
+        );
+
+        let frames = client.handle_frame(&reply(DHCPOFFER, xid, Ipv4Addr::new(10, 1, 2, 3), 600), start).unwrap();
+        let (kind, request_xid, _, requested, destination) = request(&frames[0]);
+        assert_eq!(
+            (kind, request_xid, requested, destination),
+            (DHCPREQUEST, xid, Some(Ipv4Addr::new(10, 1, 2, 3)), Ipv4Addr::BROADCAST)
+        );
+
+        assert_eq!(client.handle_frame(&reply(DHCPACK, xid, Ipv4Addr::new(10, 1, 2, 3), 600), start), Some(Vec::new()));
+        assert_eq!(client.state(), DhcpState::Bound);
+        let lease = client.lease().unwrap().clone();
+        assert_eq!(lease.netmask, Ipv4Addr::new(255, 255, 0, 0));
+        assert_eq!(lease.renewal_time, Duration::from_secs(300));
+        assert_eq!(lease.rebinding_time, Duration::from_secs(525));
+
+        let mut config = TunnelConfig::default();
+        lease.apply_to(&mut config);
+        assert_eq!(config.local_ip, Ipv4Addr::new(10, 1, 2, 3));
+        assert_eq!(config.remote_ip, Ipv4Addr::new(10, 1, 0, 254));
+        assert_eq!(config.dns_servers, vec![Ipv4Addr::new(10, 1, 0, 53), Ipv4Addr::new(10, 1, 0, 54)]);
+        assert_eq!(config.dns_domain.as_deref(), Some("corp.example"));
+    }
+
+    #[test]
+    fn test_renew_rebind_and_expiry() {
+        let start = Instant::now();
+        let mut client = DhcpClient::new(CLIENT_MAC);
+        let (_, xid, ..) = request(&client.start(start));
+        client.handle_frame(&reply(DHCPOFFER, xid, Ipv4Addr::new(10, 1, 2, 3), 800), start);
+        client.handle_frame(&reply(DHCPACK, xid, Ipv4Addr::new(10, 1, 2, 3), 800), start);
+        assert!(client.poll(start + Duration::from_secs(399)).is_empty());
+
+        // T1: unicast to the leasing server from the leased address
+        let frames = client.poll(start + Duration::from_secs(400));
+        let (kind, renew_xid, client_ip, requested, destination) = request(&frames[0]);
+        assert_eq!((kind, client_ip, requested, destination), (DHCPREQUEST, Ipv4Addr::new(10, 1, 2, 3), None, SERVER));
+        assert_eq!(mac_at(&frames[0], 0), SERVER_MAC);
+        assert_eq!(client.state(), DhcpState::Renewing);
+        // Next try halfway to T2 (700s), no sooner than a minute
+        assert!(client.poll(start + Duration::from_secs(520)).is_empty());
+        assert_eq!(client.poll(start + Duration::from_secs(550)).len(), 1);
+
+        // T2: broadcast in a new transaction
+        let frames = client.poll(start + Duration::from_secs(700));
+        let (_, rebind_xid, _, _, destination) = request(&frames[0]);
+        assert_eq!(destination, Ipv4Addr::BROADCAST);
+        assert_ne!(rebind_xid, renew_xid);
+        assert_eq!(client.state(), DhcpState::Rebinding);
+
+        // An ACK extends the lease from when it arrived
+        let now = start + Duration::from_secs(710);
+        client.handle_frame(&reply(DHCPACK, rebind_xid, Ipv4Addr::new(10, 1, 2, 3), 800), now);
+        assert_eq!(client.state(), DhcpState::Bound);
+        assert_eq!(client.lease().unwrap().remaining(now), Duration::from_secs(800));
+
+        // Expiry starts over
+        let frames = client.poll(now + Duration::from_secs(800));
+        assert_eq!(request(&frames[0]).0, DHCPDISCOVER);
+        assert!(client.lease().is_none());
+        assert_eq!(client.state(), DhcpState::Selecting);
+    }
+
+    #[test]
+    fn test_retransmission_and_nak() {
+        let start = Instant::now();
+        let mut client = DhcpClient::new(CLIENT_MAC);
+        let (_, xid, ..) = request(&client.start(start));
+        assert!(client.poll(start + Duration::from_secs(3)).is_empty());
+        assert_eq!(request(&client.poll(start + INITIAL_RETRANSMIT)[0]).0, DHCPDISCOVER);
+        // Backoff doubled to 8s
+        assert!(client.poll(start + Duration::from_secs(11)).is_empty());
+        assert_eq!(client.poll(start + Duration::from_secs(12)).len(), 1);
+
+        client.handle_frame(&reply(DHCPOFFER, xid, Ipv4Addr::new(10, 1, 2, 3), 600), start);
+        let frames = client.handle_frame(&reply(DHCPNAK, xid, Ipv4Addr::UNSPECIFIED, 0), start).unwrap();
+        let (kind, new_xid, ..) = request(&frames[0]);
+        assert_eq!(kind, DHCPDISCOVER);
+        assert_ne!(new_xid, xid);
+    }
+
+    /// Both ends of an in-memory session
+    struct Link(mpsc::Sender<Bytes>, mpsc::Receiver<Bytes>);
+
+    impl PacketSink for Link {
+        async fn send_packet(&mut self, packet: Bytes) -> Result<()> {
+            self.0.send_packet(packet).await
+        }
+    }
+
+    impl PacketSource for Link {
+        async fn recv_packet(&mut self) -> Result<Bytes> {
+            self.1.recv_packet().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acquire_over_session() {
+        let (to_server, mut server_rx) = mpsc::channel::<Bytes>(8);
+        let (server_tx, from_server) = mpsc::channel(8);
+        let server = tokio::spawn(async move {
+            for answer in [DHCPOFFER, DHCPACK] {
+                let (_, xid, ..) = request(&server_rx.recv().await.unwrap());
+                server_tx.send(Bytes::from_static(&[0u8; 42])).await.unwrap();
+                server_tx.send(reply(answer, xid, Ipv4Addr::new(10, 1, 2, 3), 600).into()).await.unwrap();
+            }
+            server_rx
+        });
+
+        let mut client = DhcpClient::new(CLIENT_MAC);
+        let mut session = Link(to_server, from_server);
+        let lease = acquire(&mut client, &mut session, Duration::from_secs(5)).await.unwrap();
+        assert_eq!(lease.address, Ipv4Addr::new(10, 1, 2, 3));
+        server.await.unwrap();
+
+        // Nobody answering ends in a timeout
+        let mut silent = DhcpClient::new(CLIENT_MAC);
+        let (tx, _rx) = mpsc::channel(8);
+        let (_keep, rx) = mpsc::channel(8);
+        let result = acquire(&mut silent, &mut Link(tx, rx), Duration::from_millis(50)).await;
+        assert!(matches!(result, Err(VpnError::Timeout(_))), "{result:?}");
+    }
+}
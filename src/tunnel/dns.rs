@@ -0,0 +1,420 @@
+//! Per-interface DNS on Linux
+//!
+//! The tunnel's resolvers are attached to its interface instead of replacing
+//! the host's:
+//! - systemd-resolved, where it runs: its D-Bus API (`SetLinkDNS`,
+//!   `SetLinkDomains`, `SetLinkDefaultRoute`, `RevertLink` on
+//!   `org.freedesktop.resolve1`), called through `busctl`
+//! - resolvconf, where installed: a record named `<interface>.rvpnse`,
+//!   deleted again on teardown
+//! - otherwise `/etc/resolv.conf` is replaced; the original, file or
+//!   symlink, is moved aside and put back
+//!
+//! [`DnsManager`] remembers what it applied to each interface, so updates and
+//! teardown undo exactly that, whatever the host looks like by then.
+//!
+//! With `strict_dns` no query may leave outside the tunnel: resolved sends
+//! every domain (`~.`) to the tunnel link, an openresolv record is marked
+//! exclusive, and a guard drops DNS (port 53, DNS over TLS on 853) leaving
+//! through any other interface. The guard is the nftables table
+//! `inet rvpnse_dns`, or the iptables and ip6tables chain `RVPNSE_DNS` where
+//! `nft` is not installed. Failing to set it up fails the change.
+
+use std::net::IpAddr;
+
+#[cfg(target_os = "linux")]
+use super::platform::{nft_available, run_privileged, run_privileged_with_input};
+#[cfg(target_os = "linux")]
+use crate::error::{Result, VpnError};
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::process::Command;
+
+/// nftables table of the leak guard
+pub const NFT_TABLE: &str = "rvpnse_dns";
+
+/// iptables/ip6tables chain of the leak guard, jumped to from `OUTPUT`
+pub const IPTABLES_CHAIN: &str = "RVPNSE_DNS";
+
+/// Suffix of the resolvconf record (`tun0.rvpnse`)
+pub const RESOLVCONF_SUFFIX: &str = "rvpnse";
+
+/// Where the original `/etc/resolv.conf` is kept while it is replaced
+pub const RESOLV_CONF_BACKUP: &str = "/etc/resolv.conf.vpn_backup";
+
+const RESOLVE1_SERVICE: &str = "org.freedesktop.resolve1";
+const RESOLVE1_PATH: &str = "/org/freedesktop/resolve1";
+const RESOLVE1_MANAGER: &str = "org.freedesktop.resolve1.Manager";
+
+/// Address families as resolved expects them (Linux `AF_INET`/`AF_INET6`)
+const AF_INET: i32 = 2;
+const AF_INET6: i32 = 10;
+
+/// How DNS is attached to the tunnel interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsBackend {
+    /// systemd-resolved link settings, nothing written to disk
+    Resolved,
+    /// A resolvconf record; openresolv can mark it exclusive
+    Resolvconf { openresolv: bool },
+    /// `/etc/resolv.conf` replaced
+    ResolvConf,
+}
+
+impl DnsBackend {
+    /// Whether the backend writes files (forbidden by `in_memory_only`)
+    pub fn writes_files(self) -> bool {
+        self != DnsBackend::Resolved
+    }
+
+    /// Backend for this host: resolved if it runs, else resolvconf if installed
+    #[cfg(target_os = "linux")]
+    pub fn detect() -> Self {
+        if systemd_resolved_active() {
+            return DnsBackend::Resolved;
+        }
+        match Command::new("resolvconf").arg("--version").output() {
+            // Debian's resolvconf has no --version and fails
+            Ok(output) if output.status.success() => DnsBackend::Resolvconf {
+                openresolv: String::from_utf8_lossy(&output.stdout).contains("openresolv"),
+            },
+            Ok(_) => DnsBackend::Resolvconf { openresolv: false },
+            Err(_) => DnsBackend::ResolvConf,
+        }
+    }
+}
+
+/// DNS settings of the tunnel link
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LinkDns {
+    pub servers: Vec<IpAddr>,
+    /// Search domains
+    pub domains: Vec<String>,
+    /// Resolve every name through these servers only
+    pub strict: bool,
+}
+
+impl LinkDns {
+    /// `busctl` arguments of the resolved calls applying these settings to link `ifindex`
+    pub fn resolved_calls(&self, ifindex: u32) -> Vec<Vec<String>> {
+        let call = |method: &str, signature: &str| -> Vec<String> {
+            ["busctl", "call", RESOLVE1_SERVICE, RESOLVE1_PATH, RESOLVE1_MANAGER, method, signature]
+                .iter()
+                .map(ToString::to_string)
+                .chain([ifindex.to_string()])
+                .collect()
+        };
+
+        let mut set_dns = call("SetLinkDNS", "ia(iay)");
+        set_dns.push(self.servers.len().to_string());
+        for server in &self.servers {
+            let (family, octets) = match server {
+                IpAddr::V4(v4) => (AF_INET, v4.octets().to_vec()),
+                IpAddr::V6(v6) => (AF_INET6, v6.octets().to_vec()),
+            };
+            set_dns.extend([family.to_string(), octets.len().to_string()]);
+            set_dns.extend(octets.iter().map(ToString::to_string));
+        }
+
+        // ("domain", routing_only); "." routing-only is `~.`, every name
+        let mut domains: Vec<(&str, bool)> = self.domains.iter().map(|d| (d.as_str(), false)).collect();
+        if self.strict {
+            domains.push((".", true));
+        }
+        let mut set_domains = call("SetLinkDomains", "ia(sb)");
+        set_domains.push(domains.len().to_string());
+        for (domain, routing_only) in domains {
+            set_domains.extend([domain.to_string(), routing_only.to_string()]);
+        }
+
+        let mut default_route = call("SetLinkDefaultRoute", "ib");
+        default_route.push("true".to_string());
+
+        vec![set_dns, set_domains, default_route]
+    }
+
+    /// Contents of a resolv.conf (or resolvconf record) with these settings
+    pub fn resolv_conf(&self) -> String {
+        let mut conf = String::from("# Generated by rVPNSE for the VPN tunnel\n");
+        for server in &self.servers {
+            conf.push_str(&format!("nameserver {server}\n"));
+        }
+        if !self.domains.is_empty() {
+            conf.push_str(&format!("search {}\n", self.domains.join(" ")));
+        }
+        // Short timeout, multiple attempts: fail over quickly to the next server
+        conf.push_str("options timeout:1 attempts:3 rotate edns0\n");
+        conf
+    }
+}
+
+/// nftables script replacing the leak guard table in one transaction (`nft -f -`)
+pub fn leak_guard_nft(interface: &str) -> String {
+    format!(
+        "table inet {NFT_TABLE}\n\
+         delete table inet {NFT_TABLE}\n\
+         table inet {NFT_TABLE} {{\n\
+         \tchain output {{\n\
+         \t\ttype filter hook output priority 0; policy accept;\n\
+         \t\toifname \"lo\" accept\n\
+         \t\toifname \"{interface}\" accept\n\
+         \t\tudp dport 53 drop\n\
+         \t\ttcp dport {{ 53, 853 }} drop\n\
+         \t}}\n\
+         }}\n"
+    )
+}
+
+/// `iptables-restore --noflush` input (re)filling the leak guard chain, for either family
+pub fn leak_guard_iptables(interface: &str) -> String {
+    let rules = [
+        format!(":{IPTABLES_CHAIN} - [0:0]"),
+        format!("-A {IPTABLES_CHAIN} -o lo -j RETURN"),
+        format!("-A {IPTABLES_CHAIN} -o {interface} -j RETURN"),
+        format!("-A {IPTABLES_CHAIN} -p udp --dport 53 -j DROP"),
+        format!("-A {IPTABLES_CHAIN} -p tcp --dport 53 -j DROP"),
+        format!("-A {IPTABLES_CHAIN} -p tcp --dport 853 -j DROP"),
+    ];
+    format!("*filter\n{}\nCOMMIT\n", rules.join("\n"))
+}
+
+/// What was applied to one interface
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AppliedDns {
+    backend: DnsBackend,
+    dns: LinkDns,
+    guarded: bool,
+}
+
+/// DNS applied to tunnel interfaces, so it can be updated and undone exactly
+#[cfg(target_os = "linux")]
+#[derive(Debug, Default)]
+pub struct DnsManager {
+    links: HashMap<String, AppliedDns>,
+}
+
+#[cfg(target_os = "linux")]
+impl DnsManager {
+    /// Point `interface` at `servers`
+    ///
+    /// The backend is chosen the first time and kept until [`restore`](Self::restore).
+    /// `in_memory_only` allows resolved only; `strict` (`strict_dns`)
+    /// confines resolution to the tunnel.
+    pub fn set_servers(&mut self, interface: &str, servers: &[IpAddr], in_memory_only: bool, strict: bool) -> Result<()> {
+        self.update(interface, in_memory_only, strict, |dns| dns.servers = servers.to_vec())
+    }
+
+    /// Set the search domain of `interface`
+    pub fn set_domain(&mut self, interface: &str, domain: &str, in_memory_only: bool, strict: bool) -> Result<()> {
+        self.update(interface, in_memory_only, strict, |dns| dns.domains = vec![domain.to_string()])
+    }
+
+    fn update(&mut self, interface: &str, in_memory_only: bool, strict: bool, edit: impl FnOnce(&mut LinkDns)) -> Result<()> {
+        let mut link = match self.links.get(interface) {
+            Some(link) => link.clone(),
+            None => AppliedDns { backend: DnsBackend::detect(), dns: LinkDns::default(), guarded: false },
+        };
+        check_backend(link.backend, in_memory_only)?;
+        edit(&mut link.dns);
+        link.dns.strict = strict;
+
+        apply(interface, link.backend, &link.dns)?;
+        if strict && !link.guarded {
+            enable_leak_guard(interface)?;
+            link.guarded = true;
+        }
+        log::info!("DNS on {} set through {:?}: {:?}", interface, link.backend, link.dns);
+        self.links.insert(interface.to_string(), link);
+        Ok(())
+    }
+
+    /// Undo what was applied to `interface`
+    ///
+    /// Without a record (e.g. replaying a crashed process's change journal)
+    /// whatever it may have left is removed: the leak guard, the resolvconf
+    /// record and the resolv.conf backup, which is put back.
+    pub fn restore(&mut self, interface: &str) -> Result<()> {
+        let Some(link) = self.links.remove(interface) else {
+            if leak_guard_installed() {
+                disable_leak_guard();
+            }
+            if matches!(DnsBackend::detect(), DnsBackend::Resolvconf { .. }) {
+                let record = format!("{interface}.{RESOLVCONF_SUFFIX}");
+                let _ = Command::new("sudo").args(["resolvconf", "-d", &record]).output();
+            }
+            return restore_resolv_conf();
+        };
+        if link.guarded {
+            disable_leak_guard();
+        }
+        match link.backend {
+            DnsBackend::Resolved => {
+                // Fails harmlessly when the interface is already gone; resolved forgets it then
+                let index = ifindex(interface).unwrap_or_default();
+                let args = [
+                    "busctl", "call", RESOLVE1_SERVICE, RESOLVE1_PATH, RESOLVE1_MANAGER, "RevertLink", "i", &index.to_string(),
+                ];
+                let _ = run_privileged(&args, "Original DNS restored");
+                Ok(())
+            }
+            DnsBackend::Resolvconf { .. } => {
+                let record = format!("{interface}.{RESOLVCONF_SUFFIX}");
+                run_privileged(&["resolvconf", "-d", &record], "Original DNS restored")
+            }
+            DnsBackend::ResolvConf => restore_resolv_conf(),
+        }
+    }
+}
+
+/// Refuse backends that write files when `in_memory_only`
+pub fn check_backend(backend: DnsBackend, in_memory_only: bool) -> crate::error::Result<()> {
+    if in_memory_only && backend.writes_files() {
+        return Err(crate::error::VpnError::CapabilityUnavailable(
+            "DNS configuration without systemd-resolved writes resolvconf records or /etc/resolv.conf, \
+             which in-memory-only mode forbids"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn apply(interface: &str, backend: DnsBackend, dns: &LinkDns) -> Result<()> {
+    match backend {
+        DnsBackend::Resolved => {
+            let index = ifindex(interface)?;
+            for call in dns.resolved_calls(index) {
+                let args: Vec<&str> = call.iter().map(String::as_str).collect();
+                run_privileged(&args, &format!("{} on {}", call[5], interface))?;
+            }
+            let flush = ["busctl", "call", RESOLVE1_SERVICE, RESOLVE1_PATH, RESOLVE1_MANAGER, "FlushCaches"];
+            let _ = run_privileged(&flush, "DNS caches flushed");
+            Ok(())
+        }
+        DnsBackend::Resolvconf { openresolv } => {
+            let record = format!("{interface}.{RESOLVCONF_SUFFIX}");
+            let mut args = vec!["resolvconf", "-a", &record];
+            if openresolv && dns.strict {
+                args.push("-x");
+            }
+            run_privileged_with_input(&args, &dns.resolv_conf(), &format!("resolvconf record {record} added"))
+        }
+        DnsBackend::ResolvConf => {
+            // -a keeps a symlink a symlink; -n keeps the first backup on updates
+            run_privileged(&["cp", "-a", "-n", "/etc/resolv.conf", RESOLV_CONF_BACKUP], "resolv.conf backed up")?;
+            // Replaced rather than written through, which would change a symlink's target
+            let staged = "/etc/resolv.conf.rvpnse";
+            run_privileged_with_input(&["tee", staged], &dns.resolv_conf(), "resolv.conf staged")?;
+            run_privileged(&["chmod", "644", staged], "resolv.conf permissions set")?;
+            run_privileged(&["mv", "-f", staged, "/etc/resolv.conf"], "resolv.conf replaced")
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn restore_resolv_conf() -> Result<()> {
+    if std::fs::symlink_metadata(RESOLV_CONF_BACKUP).is_ok() {
+        run_privileged(&["mv", "-f", RESOLV_CONF_BACKUP, "/etc/resolv.conf"], "Original DNS restored")?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn enable_leak_guard(interface: &str) -> Result<()> {
+    if nft_available() {
+        return run_privileged_with_input(&["nft", "-f", "-"], &leak_guard_nft(interface), "DNS leak guard installed (nftables)");
+    }
+    for binary in ["iptables", "ip6tables"] {
+        let _ = Command::new("sudo").args([binary, "-D", "OUTPUT", "-j", IPTABLES_CHAIN]).output();
+        let restore = format!("{binary}-restore");
+        run_privileged_with_input(&[&restore, "--noflush"], &leak_guard_iptables(interface), &format!("DNS leak guard loaded ({binary})"))?;
+        run_privileged(&[binary, "-I", "OUTPUT", "1", "-j", IPTABLES_CHAIN], &format!("DNS leak guard installed ({binary})"))?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn leak_guard_installed() -> bool {
+    let table = format!("inet {NFT_TABLE}");
+    let args: &[&str] = if nft_available() { &["nft", "list", "table", &table] } else { &["iptables", "-S", IPTABLES_CHAIN] };
+    Command::new("sudo").args(args).output().is_ok_and(|output| output.status.success())
+}
+
+#[cfg(target_os = "linux")]
+fn disable_leak_guard() {
+    if nft_available() {
+        let _ = run_privileged(&["nft", "delete", "table", &format!("inet {NFT_TABLE}")], "DNS leak guard removed");
+        return;
+    }
+    for binary in ["iptables", "ip6tables"] {
+        let _ = run_privileged(&[binary, "-D", "OUTPUT", "-j", IPTABLES_CHAIN], &format!("DNS leak guard removed ({binary})"));
+        let _ = Command::new("sudo").args([binary, "-F", IPTABLES_CHAIN]).output();
+        let _ = Command::new("sudo").args([binary, "-X", IPTABLES_CHAIN]).output();
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn ifindex(interface: &str) -> Result<u32> {
+    std::fs::read_to_string(format!("/sys/class/net/{interface}/ifindex"))
+        .ok()
+        .and_then(|index| index.trim().parse().ok())
+        .ok_or_else(|| VpnError::Dns(format!("No interface index for {interface}")))
+}
+
+/// Whether systemd-resolved manages name resolution
+#[cfg(target_os = "linux")]
+pub fn systemd_resolved_active() -> bool {
+    Command::new("systemctl")
+        .args(["is-active", "systemd-resolved"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim() == "active")
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(strict: bool) -> LinkDns {
+        LinkDns {
+            servers: vec![IpAddr::from([10, 0, 0, 1]), "fd00::53".parse().unwrap()],
+            domains: vec!["corp.example".to_string()],
+            strict,
+        }
+    }
+
+    #[test]
+    fn test_resolved_calls_encode_link_settings() {
+        let calls = link(false).resolved_calls(7);
+        assert_eq!(calls.len(), 3);
+        assert_eq!(&calls[0][..8], ["busctl", "call", RESOLVE1_SERVICE, RESOLVE1_PATH, RESOLVE1_MANAGER, "SetLinkDNS", "ia(iay)", "7"]);
+        let v6 = ["10", "16", "253", "0", "0", "0", "0", "0", "0", "0", "0", "0", "0", "0", "0", "0", "0", "83"];
+        assert_eq!(calls[0][8..].join(" "), format!("2 2 4 10 0 0 1 {}", v6.join(" ")));
+        assert_eq!(calls[1][5..].join(" "), "SetLinkDomains ia(sb) 7 1 corp.example false");
+        assert_eq!(calls[2][5..].join(" "), "SetLinkDefaultRoute ib 7 true");
+
+        // Strict: every name is routed to the link
+        let calls = link(true).resolved_calls(7);
+        assert_eq!(calls[1][5..].join(" "), "SetLinkDomains ia(sb) 7 2 corp.example false . true");
+    }
+
+    #[test]
+    fn test_resolv_conf_lists_servers_and_search() {
+        let conf = link(false).resolv_conf();
+        assert!(conf.contains("nameserver 10.0.0.1\nnameserver fd00::53\nsearch corp.example\n"));
+        assert!(!LinkDns::default().resolv_conf().contains("search"));
+    }
+
+    #[test]
+    fn test_leak_guard_drops_dns_outside_tunnel() {
+        let nft = leak_guard_nft("vpnse0");
+        assert!(nft.contains("policy accept;"));
+        assert!(nft.contains("oifname \"vpnse0\" accept\n\t\tudp dport 53 drop\n\t\ttcp dport { 53, 853 } drop"));
+
+        let restore = leak_guard_iptables("vpnse0");
+        assert!(restore.contains("-A RVPNSE_DNS -o vpnse0 -j RETURN\n-A RVPNSE_DNS -p udp --dport 53 -j DROP"));
+        assert!(restore.ends_with("--dport 853 -j DROP\nCOMMIT\n"));
+    }
+}
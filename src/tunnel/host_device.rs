@@ -0,0 +1,218 @@
+//! Tunnel devices owned by the host app
+//!
+//! On Android and iOS only the app can create the VPN interface
+//! (`VpnService.Builder`, `NEPacketTunnelProvider`), and it configures the
+//! addresses, routes and DNS itself from [`HostTunnelSettings`]. The library
+//! then only moves packets, through either
+//!
+//! - the interface's file descriptor ([`from_fd`], Android), or
+//! - a [`PacketFlow`] the app feeds and drains (iOS `packetFlow`), whose
+//!   "inbound" packets come from the host's network stack into the tunnel
+//!   and "outbound" packets leave the tunnel towards it.
+
+use super::tun_io::{TunReader, TunWriter};
+use super::TunnelConfig;
+use crate::error::{Result, VpnError};
+use serde::Serialize;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+
+/// Packets queued in each direction between the app and the tunnel
+const FLOW_QUEUE_LEN: usize = 256;
+
+/// Interface configuration the app applies to the VPN interface it creates
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HostTunnelSettings {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Ipv4Addr,
+    pub mtu: u16,
+    /// Resolvers in order of preference, IPv4 and IPv6
+    pub dns_servers: Vec<IpAddr>,
+    pub dns_domain: Option<String>,
+    /// Networks pushed by the server (`10.1.0.0/16`); empty means route everything
+    pub routes: Vec<String>,
+    /// IPv6 address as `address/prefix_len`, on dual-stack tunnels
+    pub ipv6_address: Option<String>,
+}
+
+impl HostTunnelSettings {
+    /// Settings for the tunnel described by `config`, resolving through `dns_servers`
+    pub fn new(config: &TunnelConfig, dns_servers: Vec<IpAddr>) -> Self {
+        Self {
+            address: config.local_ip,
+            prefix_len: u32::from(config.netmask).leading_ones() as u8,
+            gateway: config.remote_ip,
+            mtu: config.mtu,
+            dns_servers,
+            dns_domain: config.dns_domain.clone(),
+            routes: config.pushed_routes.iter().map(|route| route.cidr()).collect(),
+            ipv6_address: config.ipv6.as_ref().map(|lease| format!("{}/{}", lease.address, lease.prefix_len)),
+        }
+    }
+
+    /// Serialize to a JSON object
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "{}".to_string())
+    }
+}
+
+/// Device halves for the VPN interface behind `fd`, which carries bare IP packets
+///
+/// The descriptor is duplicated, so the app keeps ownership of its own; the
+/// copy is closed with the tunnel. Both share the open file, which is switched
+/// to non-blocking mode once forwarding starts.
+#[cfg(unix)]
+pub fn from_fd(fd: RawFd) -> Result<(TunReader, TunWriter)> {
+    let dup = |fd: RawFd| {
+        // SAFETY: dup creates a new descriptor, which nothing else owns, for the File to take over
+        unsafe {
+            match libc::dup(fd) {
+                -1 => Err(VpnError::TunTap(format!("Invalid tunnel descriptor {fd}: {}", io::Error::last_os_error()))),
+                copy => Ok(std::fs::File::from_raw_fd(copy)),
+            }
+        }
+    };
+    let reader = dup(fd)?;
+    let writer = dup(fd)?;
+    Ok((TunReader::pollable(reader), TunWriter::pollable(writer)))
+}
+
+/// Packets exchanged with the app's own packet I/O instead of a descriptor
+///
+/// Created with [`packet_flow`]; dropping it closes the device, which stops
+/// forwarding.
+#[derive(Debug)]
+pub struct PacketFlow {
+    inbound: SyncSender<Vec<u8>>,
+    outbound: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl PacketFlow {
+    /// Hand over a packet the host's network stack sent into the VPN interface
+    ///
+    /// Never blocks: when the queue is full the packet is dropped, as an
+    /// overrun interface would.
+    pub fn write_inbound(&self, packet: &[u8]) -> Result<()> {
+        match self.inbound.try_send(packet.to_vec()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(VpnError::Network("Inbound packet queue full".to_string())),
+            Err(TrySendError::Disconnected(_)) => Err(VpnError::Connection("Tunnel closed".to_string())),
+        }
+    }
+
+    /// Next packet from the VPN for the host's network stack
+    ///
+    /// Waits up to `timeout` and returns `None` if nothing arrived. A packet
+    /// longer than `buf` is truncated, as with a read from a TUN device.
+    pub fn read_outbound(&self, buf: &mut [u8], timeout: Duration) -> Result<Option<usize>> {
+        match self.outbound.lock().unwrap().recv_timeout(timeout) {
+            Ok(packet) => {
+                let len = packet.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet[..len]);
+                Ok(Some(len))
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(VpnError::Connection("Tunnel closed".to_string())),
+        }
+    }
+}
+
+/// A [`PacketFlow`] and the device halves the packet pump uses on the other end
+pub fn packet_flow() -> (PacketFlow, TunReader, TunWriter) {
+    let (inbound, from_host) = mpsc::sync_channel(FLOW_QUEUE_LEN);
+    let (to_host, outbound) = mpsc::sync_channel(FLOW_QUEUE_LEN);
+    let flow = PacketFlow { inbound, outbound: Mutex::new(outbound) };
+    (flow, TunReader::new(FlowReader(from_host)), TunWriter::new(FlowWriter(to_host)))
+}
+
+struct FlowReader(Receiver<Vec<u8>>);
+
+impl Read for FlowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The app dropped its end: the device is closed
+        let Ok(packet) = self.0.recv() else {
+            return Ok(0);
+        };
+        let len = packet.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet[..len]);
+        Ok(len)
+    }
+}
+
+struct FlowWriter(SyncSender<Vec<u8>>);
+
+impl Write for FlowWriter {
+    fn write(&mut self, packet: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(packet.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Packet flow closed"))?;
+        Ok(packet.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::{Ipv6Lease, PushedRoute};
+
+    #[test]
+    fn test_packet_flow_round_trip() {
+        let (flow, mut reader, mut writer) = packet_flow();
+        let mut buf = [0u8; 64];
+
+        flow.write_inbound(&[0x45; 20]).unwrap();
+        assert_eq!(reader.read_packet(&mut buf).unwrap(), 20);
+
+        assert_eq!(flow.read_outbound(&mut buf, Duration::from_millis(10)).unwrap(), None);
+        writer.write_packet(&[0x60; 40]).unwrap();
+        assert_eq!(flow.read_outbound(&mut buf, Duration::from_millis(10)).unwrap(), Some(40));
+
+        for _ in 0..FLOW_QUEUE_LEN {
+            flow.write_inbound(&[0x45; 20]).unwrap();
+        }
+        assert!(matches!(flow.write_inbound(&[0x45; 20]), Err(VpnError::Network(_))));
+
+        // Closing the flow closes the device
+        drop(flow);
+        for _ in 0..FLOW_QUEUE_LEN {
+            reader.read_packet(&mut buf).unwrap();
+        }
+        assert_eq!(reader.read_packet(&mut buf).unwrap(), 0);
+        assert!(writer.write_packet(&[0x45; 20]).is_err());
+    }
+
+    #[test]
+    fn test_host_settings_from_lease() {
+        let config = TunnelConfig {
+            netmask: Ipv4Addr::new(255, 255, 240, 0),
+            pushed_routes: vec![PushedRoute::new(Ipv4Addr::new(10, 1, 0, 0), 16, None).unwrap()],
+            ipv6: Some(Ipv6Lease {
+                address: "fd00::2".parse().unwrap(),
+                prefix_len: 64,
+                gateway: None,
+                dns_servers: vec!["fd00::53".parse().unwrap()],
+            }),
+            ..TunnelConfig::default()
+        };
+        let settings = HostTunnelSettings::new(&config, vec![IpAddr::from([10, 0, 0, 1])]);
+        assert_eq!(settings.prefix_len, 20);
+        assert_eq!(settings.routes, vec!["10.1.0.0/16".to_string()]);
+        assert_eq!(settings.ipv6_address.as_deref(), Some("fd00::2/64"));
+
+        let json: serde_json::Value = serde_json::from_str(&settings.to_json()).unwrap();
+        assert_eq!(json["address"], "10.0.0.2");
+        assert_eq!(json["mtu"], 1500);
+        assert_eq!(json["dns_servers"][0], "10.0.0.1");
+    }
+}
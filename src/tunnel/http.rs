@@ -0,0 +1,127 @@
+//! HTTP clients pinned to the tunnel
+//!
+//! Apps often call an internal API right after connecting, while the OS
+//! routing state may still send that traffic elsewhere: another VPN owns the
+//! default route, the host vetoed a route, or only the tunnel subnet is
+//! routed. A [`TunnelHttpBinding`] makes requests leave from the tunnel
+//! address and interface and resolves host names through the tunnel's DNS
+//! servers, falling back to the system resolver for names they do not know.
+
+use crate::diagnostics;
+use crate::error::{Result, VpnError};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long each tunnel DNS server gets to answer
+const DNS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where HTTP requests through the tunnel leave from and how names are resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelHttpBinding {
+    /// Tunnel interface name
+    pub interface: String,
+    /// Tunnel address requests are sent from
+    pub local_ip: Ipv4Addr,
+    /// Servers queried for host names, in order
+    pub dns_servers: Vec<SocketAddr>,
+}
+
+impl TunnelHttpBinding {
+    /// Bind `builder` to the tunnel and resolve names through its DNS servers
+    pub fn apply_to(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        let builder = builder
+            .local_address(IpAddr::V4(self.local_ip))
+            .dns_resolver(Arc::new(TunnelResolver {
+                source: self.local_ip,
+                servers: self.dns_servers.clone(),
+            }));
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let builder = builder.interface(&self.interface);
+        builder
+    }
+
+    /// HTTP client with default settings whose requests go through the tunnel
+    pub fn client(&self) -> Result<reqwest::Client> {
+        self.apply_to(reqwest::Client::builder())
+            .build()
+            .map_err(|e| VpnError::Network(format!("Failed to create tunnel HTTP client: {e}")))
+    }
+}
+
+/// Resolves names with the tunnel's DNS servers, queried from the tunnel address
+#[derive(Debug, Clone)]
+struct TunnelResolver {
+    source: Ipv4Addr,
+    servers: Vec<SocketAddr>,
+}
+
+impl TunnelResolver {
+    async fn lookup(&self, name: &str) -> Result<Vec<SocketAddr>> {
+        for server in &self.servers {
+            match diagnostics::query_dns_server_from(*server, self.source, name, DNS_TIMEOUT).await {
+                Ok(addresses) if !addresses.is_empty() => {
+                    return Ok(addresses
+                        .into_iter()
+                        .map(|address| SocketAddr::new(IpAddr::V4(address), 0))
+                        .collect());
+                }
+                Ok(_) => log::debug!("Tunnel DNS server {} has no A record for {}", server, name),
+                Err(e) => log::debug!("Tunnel DNS server {} failed for {}: {}", server, name, e),
+            }
+        }
+
+        log::debug!("Resolving {} with the system resolver", name);
+        Ok(tokio::net::lookup_host((name, 0)).await?.collect())
+    }
+}
+
+impl Resolve for TunnelResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let resolver = self.clone();
+        Box::pin(async move {
+            let addresses = resolver.lookup(name.as_str()).await?;
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UdpSocket;
+
+    /// Answer one A query with `address`
+    async fn answer_once(socket: UdpSocket, address: Ipv4Addr) {
+        let mut buf = [0u8; 512];
+        let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+        let mut response = buf[..len].to_vec();
+        response[2] = 0x81; // QR + RD
+        response[3] = 0x80; // RA, rcode 0
+        response[7] = 1; // ANCOUNT = 1
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(&address.octets());
+        socket.send_to(&response, peer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resolves_through_tunnel_dns() {
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let answered = tokio::spawn(answer_once(server, Ipv4Addr::new(10, 8, 0, 20)));
+
+        let resolver = TunnelResolver {
+            source: Ipv4Addr::LOCALHOST,
+            servers: vec![server_addr],
+        };
+        let addresses = resolver.lookup("api.corp.internal").await.unwrap();
+        assert_eq!(addresses, vec![SocketAddr::new(IpAddr::from([10, 8, 0, 20]), 0)]);
+        answered.await.unwrap();
+
+        // Without tunnel servers the system resolver answers
+        let resolver = TunnelResolver { source: Ipv4Addr::LOCALHOST, servers: Vec::new() };
+        let addresses = resolver.lookup("localhost").await.unwrap();
+        assert!(addresses.iter().any(|address| address.ip().is_loopback()));
+    }
+}
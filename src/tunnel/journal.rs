@@ -0,0 +1,405 @@
+//! Persistent journal of system changes
+//!
+//! Before a route, DNS or firewall change is applied, the action undoing it
+//! is written to a journal file in the state directory; a clean teardown
+//! forgets it again. A process that crashes (or exits without disconnecting)
+//! leaves its journal behind, and [`cleanup_stale_state`] replays it on the
+//! next start so the host gets its routing, resolvers and firewall back.
+//!
+//! Each client has its own file, `changes-<pid>-<n>.json`, holding the pid of
+//! the process that wrote it. Journals of processes still running are left
+//! alone.
+
+use super::plan::SystemChange;
+use super::platform::PlatformOps;
+use crate::config::TunnelOptionsConfig;
+use crate::error::{Result, VpnError};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Journal files are `changes-<pid>-<n>.json`
+const FILE_PREFIX: &str = "changes-";
+const FILE_SUFFIX: &str = ".json";
+
+/// Operation reverting one applied change
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UndoAction {
+    DeleteRoute { destination: String, interface: Option<String> },
+    DeleteTableRoute { destination: String, table: u32 },
+    DeleteRoutingRule { fwmark: u32, table: u32, priority: u32 },
+    DeletePolicyRules { fwmark: u32, table: u32, priority: u32 },
+    DeleteFirewallRule { table: Option<String>, chain: String, args: Vec<String> },
+    RestoreDefaultRoute { gateway: String, interface: String },
+    RestoreDns { interface: String },
+    DisableKillSwitch,
+}
+
+impl UndoAction {
+    /// How to revert `change` on the tunnel `interface`
+    ///
+    /// Replacing the default route is reverted only when the `original_gateway`
+    /// is known. Sysctls and flushed firewall tables have no undo.
+    pub fn for_change(change: &SystemChange, original_gateway: Option<&str>, interface: &str) -> Option<Self> {
+        match change {
+            SystemChange::AddRoute { destination, interface, .. } => {
+                Some(Self::DeleteRoute { destination: destination.clone(), interface: interface.clone() })
+            }
+            SystemChange::AddTableRoute { destination, table, .. } => {
+                Some(Self::DeleteTableRoute { destination: destination.clone(), table: *table })
+            }
+            SystemChange::AddRoutingRule { fwmark, table, priority } => {
+                Some(Self::DeleteRoutingRule { fwmark: *fwmark, table: *table, priority: *priority })
+            }
+            SystemChange::AddPolicyRules { fwmark, table, priority } => {
+                Some(Self::DeletePolicyRules { fwmark: *fwmark, table: *table, priority: *priority })
+            }
+            SystemChange::AddFirewallRule { table, chain, args } => Some(Self::DeleteFirewallRule {
+                table: table.clone(),
+                chain: chain.clone(),
+                args: args.clone(),
+            }),
+            SystemChange::SetDefaultRoute { .. } => original_gateway.map(|gateway| Self::RestoreDefaultRoute {
+                gateway: gateway.to_string(),
+                interface: interface.to_string(),
+            }),
+            SystemChange::SetDns { .. } | SystemChange::SetDnsDomain { .. } => {
+                Some(Self::RestoreDns { interface: interface.to_string() })
+            }
+            _ => None,
+        }
+    }
+
+    /// Revert the change through `ops`
+    pub fn run(&self, ops: &dyn PlatformOps) -> Result<()> {
+        match self {
+            Self::DeleteRoute { destination, interface } => ops.delete_route(destination, interface.as_deref()),
+            Self::DeleteTableRoute { destination, table } => ops.delete_table_route(destination, *table),
+            Self::DeleteRoutingRule { fwmark, table, priority } => ops.delete_routing_rule(*fwmark, *table, *priority),
+            Self::DeletePolicyRules { fwmark, table, priority } => ops.delete_policy_rules(*fwmark, *table, *priority),
+            Self::DeleteFirewallRule { table, chain, args } => ops.delete_firewall_rule(table.as_deref(), chain, args),
+            Self::RestoreDefaultRoute { gateway, interface } => ops.restore_default_route(gateway, interface),
+            Self::RestoreDns { interface } => ops.restore_dns(interface),
+            Self::DisableKillSwitch => ops.disable_kill_switch(),
+        }
+    }
+}
+
+impl std::fmt::Display for UndoAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::DeleteRoute { destination, interface: Some(interface) } => {
+                write!(f, "Delete route {destination} via {interface}")
+            }
+            Self::DeleteRoute { destination, interface: None } => write!(f, "Delete route {destination}"),
+            Self::DeleteTableRoute { destination, table } => write!(f, "Delete route {destination} from table {table}"),
+            Self::DeleteRoutingRule { fwmark, table, .. } => write!(f, "Delete rule fwmark {fwmark:#x} lookup {table}"),
+            Self::DeletePolicyRules { fwmark, table, .. } => {
+                write!(f, "Delete rules sending traffic without mark {fwmark:#x} to table {table}")
+            }
+            Self::DeleteFirewallRule { chain, args, .. } => write!(f, "Delete firewall rule {chain} {}", args.join(" ")),
+            Self::RestoreDefaultRoute { gateway, .. } => write!(f, "Restore default route via {gateway}"),
+            Self::RestoreDns { interface } => write!(f, "Restore DNS of {interface}"),
+            Self::DisableKillSwitch => write!(f, "Release kill switch"),
+        }
+    }
+}
+
+/// Owner of a journal entry, which forgets it once undone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalScope {
+    /// Routes, DNS and firewall rules of the tunnel, undone at teardown
+    Tunnel,
+    /// Kill switch rules, undone when it is released
+    KillSwitch,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Entry {
+    scope: JournalScope,
+    undo: UndoAction,
+}
+
+/// Contents of a journal file
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalFile {
+    pid: u32,
+    entries: Vec<Entry>,
+}
+
+/// Journal file of one client, shared by its tunnel manager and kill switch
+///
+/// Cloning is cheap and yields a handle to the same journal. Nothing is
+/// written until the first change is recorded.
+#[derive(Debug, Clone)]
+pub struct ChangeJournal {
+    path: Arc<PathBuf>,
+    entries: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl ChangeJournal {
+    /// Journal in `dir`, created when the first change is recorded
+    pub fn new(dir: impl AsRef<Path>) -> Self {
+        static NEXT: AtomicU32 = AtomicU32::new(0);
+        let name = format!("{FILE_PREFIX}{}-{}{FILE_SUFFIX}", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        Self { path: Arc::new(dir.as_ref().join(name)), entries: Arc::default() }
+    }
+
+    /// Journal in `tunnel.state_dir` or the [default](default_state_dir),
+    /// none with `in_memory_only`
+    pub fn from_options(options: &TunnelOptionsConfig) -> Option<Self> {
+        if options.in_memory_only {
+            return None;
+        }
+        Some(Self::new(options.state_dir.as_ref().map_or_else(default_state_dir, PathBuf::from)))
+    }
+
+    /// File the journal is kept in
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write `undo` to disk, before the change it reverts is applied
+    ///
+    /// An action already recorded for `scope` is not recorded twice.
+    pub fn record(&self, scope: JournalScope, undo: UndoAction) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = Entry { scope, undo };
+        if entries.contains(&entry) {
+            return Ok(());
+        }
+        entries.push(entry);
+        if let Err(e) = self.persist(&entries) {
+            entries.pop();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Drop the entries of `scope` once their changes were undone
+    ///
+    /// The file is deleted when nothing is left in it.
+    pub fn forget(&self, scope: JournalScope) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let before = entries.len();
+        entries.retain(|entry| entry.scope != scope);
+        if entries.len() == before {
+            return Ok(());
+        }
+        self.persist(&entries)
+    }
+
+    /// Undo actions pending for `scope`, in the order they were recorded
+    pub fn pending(&self, scope: JournalScope) -> Vec<UndoAction> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.iter().filter(|entry| entry.scope == scope).map(|entry| entry.undo.clone()).collect()
+    }
+
+    /// Replace the file in one step, so a crash never leaves half a journal
+    fn persist(&self, entries: &[Entry]) -> Result<()> {
+        if entries.is_empty() {
+            return match std::fs::remove_file(&*self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(VpnError::Io(e)),
+                _ => Ok(()),
+            };
+        }
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = JournalFile { pid: std::process::id(), entries: entries.to_vec() };
+        let json = serde_json::to_vec_pretty(&file).map_err(|e| VpnError::Io(e.into()))?;
+        let staged = self.path.with_extension("tmp");
+        let mut out = std::fs::File::create(&staged)?;
+        out.write_all(&json)?;
+        out.sync_all()?;
+        std::fs::rename(&staged, &*self.path)?;
+        Ok(())
+    }
+}
+
+/// Directory journals are kept in unless `tunnel.state_dir` says otherwise
+///
+/// System-wide for root (`/var/lib/rvpnse`, `/Library/Application Support/rvpnse`,
+/// `%ProgramData%\rvpnse`), otherwise in the user's state directory.
+pub fn default_state_dir() -> PathBuf {
+    #[cfg(unix)]
+    let root = unsafe { libc::geteuid() } == 0;
+    #[cfg(target_os = "linux")]
+    {
+        if root {
+            return PathBuf::from("/var/lib").join(crate::NAME);
+        }
+        let state = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")));
+        if let Some(state) = state {
+            return state.join(crate::NAME);
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        if root {
+            return PathBuf::from("/Library/Application Support").join(crate::NAME);
+        }
+        if let Some(home) = std::env::var_os("HOME") {
+            return Path::new(&home).join("Library/Application Support").join(crate::NAME);
+        }
+    }
+    #[cfg(target_os = "windows")]
+    if let Some(data) = std::env::var_os("ProgramData") {
+        return PathBuf::from(data).join(crate::NAME);
+    }
+    #[cfg(all(unix, not(any(target_os = "linux", target_os = "macos"))))]
+    let _ = root;
+    std::env::temp_dir().join(crate::NAME)
+}
+
+/// Undo the changes journaled in `dir` by processes that are gone
+///
+/// Entries are undone newest first; an entry that fails is logged and the
+/// rest still run, since the change may have vanished with its interface.
+/// Each replayed journal is deleted. Meant to run once at startup, before
+/// connecting. Returns how many journals were replayed.
+pub fn cleanup_stale_state(dir: &Path, ops: &dyn PlatformOps) -> Result<usize> {
+    let listing = match std::fs::read_dir(dir) {
+        Ok(listing) => listing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(VpnError::Io(e)),
+    };
+    let mut replayed = 0;
+    for path in listing.filter_map(|entry| entry.ok().map(|entry| entry.path())) {
+        let is_journal = path.file_name().and_then(|name| name.to_str()).is_some_and(|name| {
+            name.starts_with(FILE_PREFIX) && name.ends_with(FILE_SUFFIX)
+        });
+        if !is_journal {
+            continue;
+        }
+        let journal = match std::fs::read(&path).map_err(VpnError::Io).and_then(|bytes| {
+            serde_json::from_slice::<JournalFile>(&bytes).map_err(|e| VpnError::Io(e.into()))
+        }) {
+            Ok(journal) => journal,
+            Err(e) => {
+                log::warn!(journal:? = path; "Discarding unreadable change journal: {}", e);
+                let _ = std::fs::remove_file(&path);
+                continue;
+            }
+        };
+        if process_alive(journal.pid) {
+            log::debug!(journal:? = path, pid = journal.pid; "Change journal belongs to a running process");
+            continue;
+        }
+        log::info!(journal:? = path, pid = journal.pid; "Undoing {} change(s) left by an earlier run", journal.entries.len());
+        for entry in journal.entries.iter().rev() {
+            if let Err(e) = entry.undo.run(ops) {
+                log::warn!("{} failed: {}", entry.undo, e);
+            }
+        }
+        std::fs::remove_file(&path)?;
+        replayed += 1;
+    }
+    Ok(replayed)
+}
+
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else { return false };
+    // Signal 0 only checks that the process exists; EPERM means it belongs to another user
+    let exists = unsafe { libc::kill(pid, 0) } == 0;
+    exists || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(windows)]
+fn process_alive(pid: u32) -> bool {
+    use winapi::shared::minwindef::DWORD;
+    use winapi::um::{errhandlingapi, handleapi, minwinbase, processthreadsapi, winnt};
+
+    unsafe {
+        let handle = processthreadsapi::OpenProcess(winnt::PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return errhandlingapi::GetLastError() == winapi::shared::winerror::ERROR_ACCESS_DENIED;
+        }
+        let mut status: DWORD = 0;
+        let alive = processthreadsapi::GetExitCodeProcess(handle, &mut status) != 0 && status == minwinbase::STILL_ACTIVE;
+        handleapi::CloseHandle(handle);
+        alive
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn process_alive(pid: u32) -> bool {
+    pid == std::process::id()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::{Platform, PlatformOp, RecordingOps};
+
+    fn route(destination: &str) -> UndoAction {
+        UndoAction::DeleteRoute { destination: destination.to_string(), interface: Some("vpnse0".to_string()) }
+    }
+
+    fn read(journal: &ChangeJournal) -> JournalFile {
+        serde_json::from_slice(&std::fs::read(journal.path()).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_journal_is_written_before_and_removed_after() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = ChangeJournal::new(dir.path().join("state"));
+        assert!(!journal.path().exists());
+
+        journal.record(JournalScope::Tunnel, route("10.0.0.0/8")).unwrap();
+        journal.record(JournalScope::Tunnel, route("10.0.0.0/8")).unwrap();
+        journal.record(JournalScope::KillSwitch, UndoAction::DisableKillSwitch).unwrap();
+        let file = read(&journal);
+        assert_eq!(file.pid, std::process::id());
+        assert_eq!(file.entries.len(), 2);
+
+        // The kill switch outlives the tunnel
+        journal.forget(JournalScope::Tunnel).unwrap();
+        assert_eq!(journal.pending(JournalScope::KillSwitch), vec![UndoAction::DisableKillSwitch]);
+        assert_eq!(read(&journal).entries.len(), 1);
+        journal.forget(JournalScope::KillSwitch).unwrap();
+        assert!(!journal.path().exists());
+    }
+
+    #[test]
+    fn test_cleanup_replays_journals_of_dead_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .arg("--list")
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        child.wait().unwrap();
+        let entries = vec![
+            Entry { scope: JournalScope::Tunnel, undo: route("10.0.0.0/8") },
+            Entry { scope: JournalScope::Tunnel, undo: UndoAction::RestoreDns { interface: "vpnse0".to_string() } },
+        ];
+        let stale = dir.path().join("changes-1-0.json");
+        std::fs::write(&stale, serde_json::to_vec(&JournalFile { pid: child.id(), entries }).unwrap()).unwrap();
+        // A journal of this (running) process is kept
+        let live = ChangeJournal::new(dir.path());
+        live.record(JournalScope::KillSwitch, UndoAction::DisableKillSwitch).unwrap();
+        std::fs::write(dir.path().join("unrelated.txt"), "kept").unwrap();
+
+        let ops = RecordingOps::new(Platform::Linux);
+        assert_eq!(cleanup_stale_state(dir.path(), &ops).unwrap(), 1);
+        assert_eq!(
+            ops.recorded(),
+            vec![
+                PlatformOp::RestoreDns { interface: "vpnse0".to_string() },
+                PlatformOp::DeleteRoute { destination: "10.0.0.0/8".to_string(), interface: Some("vpnse0".to_string()) },
+            ]
+        );
+        assert!(!stale.exists());
+        assert!(live.path().exists());
+        assert!(dir.path().join("unrelated.txt").exists());
+        assert_eq!(cleanup_stale_state(&dir.path().join("missing"), &ops).unwrap(), 0);
+    }
+}
@@ -0,0 +1,332 @@
+//! Kill switch
+//!
+//! While engaged, the host firewall drops everything this machine sends
+//! except traffic leaving through the tunnel interface, traffic to the VPN
+//! server and DHCP/neighbour discovery on the uplink. Unlike the tunnel's own
+//! changes the rules are not removed when the tunnel goes down: a dropped
+//! session, and the reconnect that follows, leak nothing onto the uplink.
+//! Only a clean disconnect (or turning the kill switch off) removes them.
+//!
+//! Rules are installed as:
+//! - Linux: the nftables table `inet rvpnse_killswitch`, or the iptables and
+//!   ip6tables chain `RVPNSE_KILLSWITCH` where `nft` is not installed
+//! - macOS: the pf anchor `com.apple/250.rvpnse.killswitch`, which the stock
+//!   ruleset evaluates
+//! - Windows: Windows Filtering Platform filters, created through the Windows
+//!   Firewall (rule group `rVPNSE kill switch` and a blocking outbound default)
+//!
+//! Releasing puts back what engaging found: on Windows the default outbound
+//! action each firewall profile had, on macOS pf's enabled state, by
+//! returning the reference `pfctl -E` handed out.
+
+use super::journal::{ChangeJournal, JournalScope, UndoAction};
+use super::platform::PlatformOps;
+use crate::error::{Result, VpnError};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// nftables table holding the kill switch chain
+pub const NFT_TABLE: &str = "rvpnse_killswitch";
+
+/// iptables/ip6tables chain jumped to from `OUTPUT`
+pub const IPTABLES_CHAIN: &str = "RVPNSE_KILLSWITCH";
+
+/// pf anchor holding the kill switch rules
+pub const PF_ANCHOR: &str = "com.apple/250.rvpnse.killswitch";
+
+/// Windows Firewall group of the kill switch's allow rules
+pub const WINDOWS_RULE_GROUP: &str = "rVPNSE kill switch";
+
+/// Traffic still allowed while the kill switch is engaged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KillSwitchPolicy {
+    /// Tunnel interface, through which everything may leave
+    pub tunnel_interface: String,
+    /// VPN server, reachable over the uplink to (re)establish the session
+    pub server: IpAddr,
+}
+
+impl KillSwitchPolicy {
+    /// nftables script replacing the kill switch table in one transaction (`nft -f -`)
+    pub fn nft_ruleset(&self) -> String {
+        let family = if self.server.is_ipv6() { "ip6" } else { "ip" };
+        format!(
+            "table inet {table}\n\
+             delete table inet {table}\n\
+             table inet {table} {{\n\
+             \tchain output {{\n\
+             \t\ttype filter hook output priority 0; policy drop;\n\
+             \t\toifname \"lo\" accept\n\
+             \t\toifname \"{interface}\" accept\n\
+             \t\t{family} daddr {server} accept\n\
+             \t\tudp sport 68 udp dport 67 accept\n\
+             \t\tudp sport 546 udp dport 547 accept\n\
+             \t\ticmpv6 type {{ nd-router-solicit, nd-neighbor-solicit, nd-neighbor-advert }} accept\n\
+             \t}}\n\
+             }}\n",
+            table = NFT_TABLE,
+            interface = self.tunnel_interface,
+            server = self.server,
+        )
+    }
+
+    /// `iptables-restore --noflush` input (re)filling the kill switch chain
+    ///
+    /// The chain still has to be hooked into `OUTPUT`. With `ipv6` the input
+    /// is for `ip6tables-restore`; the server rule goes into the family the
+    /// server's address belongs to.
+    pub fn iptables_restore(&self, ipv6: bool) -> String {
+        let mut rules = vec![
+            format!(":{IPTABLES_CHAIN} - [0:0]"),
+            format!("-A {IPTABLES_CHAIN} -o lo -j RETURN"),
+            format!("-A {IPTABLES_CHAIN} -o {} -j RETURN", self.tunnel_interface),
+        ];
+        if self.server.is_ipv6() == ipv6 {
+            rules.push(format!("-A {IPTABLES_CHAIN} -d {} -j RETURN", self.server));
+        }
+        if ipv6 {
+            rules.push(format!("-A {IPTABLES_CHAIN} -p udp --sport 546 --dport 547 -j RETURN"));
+            for icmp_type in ["router-solicitation", "neighbour-solicitation", "neighbour-advertisement"] {
+                rules.push(format!("-A {IPTABLES_CHAIN} -p ipv6-icmp --icmpv6-type {icmp_type} -j RETURN"));
+            }
+        } else {
+            rules.push(format!("-A {IPTABLES_CHAIN} -p udp --sport 68 --dport 67 -j RETURN"));
+        }
+        rules.push(format!("-A {IPTABLES_CHAIN} -j DROP"));
+        format!("*filter\n{}\nCOMMIT\n", rules.join("\n"))
+    }
+
+    /// pf rules loaded into [`PF_ANCHOR`] (`pfctl -a ... -f -`)
+    pub fn pf_rules(&self) -> String {
+        format!(
+            "block drop out all\n\
+             pass out quick on lo0 all\n\
+             pass out quick on {interface} all\n\
+             pass out quick to {server}\n\
+             pass out quick proto udp from any port 68 to any port 67\n\
+             pass out quick inet6 proto udp from any port 546 to any port 547\n\
+             pass out quick inet6 proto icmp6 all icmp6-type {{ routersol, neighbrsol, neighbradv }}\n",
+            interface = self.tunnel_interface,
+            server = self.server,
+        )
+    }
+
+    /// PowerShell script installing the Windows Firewall rules
+    ///
+    /// Loopback traffic is exempt from the Windows Firewall, and DHCP is
+    /// allowed by the built-in Core Networking rules.
+    pub fn windows_script(&self) -> String {
+        format!(
+            "Remove-NetFirewallRule -Group '{group}' -ErrorAction SilentlyContinue; \
+             New-NetFirewallRule -Group '{group}' -DisplayName 'rVPNSE tunnel' -Direction Outbound -Action Allow -InterfaceAlias '{interface}' | Out-Null; \
+             New-NetFirewallRule -Group '{group}' -DisplayName 'rVPNSE server' -Direction Outbound -Action Allow -RemoteAddress {server} | Out-Null; \
+             Set-NetFirewallProfile -All -DefaultOutboundAction Block",
+            group = WINDOWS_RULE_GROUP,
+            interface = self.tunnel_interface,
+            server = self.server,
+        )
+    }
+}
+
+/// Default outbound action of each Windows Firewall profile, saved while the kill switch blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboundActions(Vec<(String, String)>);
+
+impl OutboundActions {
+    /// PowerShell printing one `profile=action` line per firewall profile
+    pub const QUERY: &'static str =
+        "Get-NetFirewallProfile | ForEach-Object { \"$($_.Name)=$($_.DefaultOutboundAction)\" }";
+
+    /// Parse the output of [`QUERY`](Self::QUERY)
+    pub fn parse(output: &str) -> Result<Self> {
+        let mut actions = Vec::new();
+        for line in output.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let parsed = line.split_once('=').filter(|(profile, action)| {
+                !profile.is_empty()
+                    && profile.chars().all(|c| c.is_ascii_alphanumeric())
+                    && ["NotConfigured", "Allow", "Block"].contains(action)
+            });
+            let Some((profile, action)) = parsed else {
+                return Err(VpnError::Platform(format!("Unexpected firewall profile '{line}'")));
+            };
+            actions.push((profile.to_string(), action.to_string()));
+        }
+        if actions.is_empty() {
+            return Err(VpnError::Platform("No firewall profiles found".to_string()));
+        }
+        Ok(Self(actions))
+    }
+
+    /// PowerShell putting the saved actions back
+    pub fn restore_script(&self) -> String {
+        self.0
+            .iter()
+            .map(|(profile, action)| format!("Set-NetFirewallProfile -Name {profile} -DefaultOutboundAction {action}"))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// Reference token in the report of `pfctl -E`, for `pfctl -X` to release
+pub fn pf_token(report: &str) -> Option<&str> {
+    report
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Token"))
+        .and_then(|rest| rest.trim_start().strip_prefix(':'))
+        .map(str::trim)
+        .filter(|token| !token.is_empty() && token.chars().all(|c| c.is_ascii_digit()))
+}
+
+/// Engages and releases the kill switch through [`PlatformOps`]
+pub struct KillSwitch {
+    ops: Arc<dyn PlatformOps>,
+    engaged: Option<KillSwitchPolicy>,
+    journal: Option<ChangeJournal>,
+}
+
+impl KillSwitch {
+    pub fn new(ops: Arc<dyn PlatformOps>) -> Self {
+        Self { ops, engaged: None, journal: None }
+    }
+
+    /// Journal the rules before installing them, so a crashed process's
+    /// kill switch does not block the host forever
+    pub fn with_journal(mut self, journal: Option<ChangeJournal>) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Block all traffic `policy` does not allow
+    ///
+    /// Engaging again with a different policy (new server or interface)
+    /// replaces the rules; with the same policy it does nothing.
+    pub fn engage(&mut self, policy: KillSwitchPolicy) -> Result<()> {
+        if self.engaged.as_ref() == Some(&policy) {
+            return Ok(());
+        }
+        if let Some(ref journal) = self.journal {
+            if let Err(e) = journal.record(JournalScope::KillSwitch, UndoAction::DisableKillSwitch) {
+                log::warn!(journal:? = journal.path(); "Could not journal the kill switch: {}", e);
+            }
+        }
+        self.ops.enable_kill_switch(&policy)?;
+        log::info!("Kill switch engaged: only {} and {} are reachable", policy.tunnel_interface, policy.server);
+        self.engaged = Some(policy);
+        Ok(())
+    }
+
+    /// Remove the rules, if engaged
+    pub fn release(&mut self) -> Result<()> {
+        if self.engaged.take().is_some() {
+            self.ops.disable_kill_switch()?;
+            if let Some(ref journal) = self.journal {
+                let _ = journal.forget(JournalScope::KillSwitch);
+            }
+            log::info!("Kill switch released");
+        }
+        Ok(())
+    }
+
+    /// Policy in force, if engaged
+    pub fn policy(&self) -> Option<&KillSwitchPolicy> {
+        self.engaged.as_ref()
+    }
+
+    pub fn is_engaged(&self) -> bool {
+        self.engaged.is_some()
+    }
+}
+
+impl std::fmt::Debug for KillSwitch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KillSwitch").field("engaged", &self.engaged).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::{Platform, PlatformOp, RecordingOps};
+
+    fn policy(server: &str) -> KillSwitchPolicy {
+        KillSwitchPolicy { tunnel_interface: "vpnse0".to_string(), server: server.parse().unwrap() }
+    }
+
+    #[test]
+    fn test_engage_and_release() {
+        let ops = Arc::new(RecordingOps::new(Platform::Linux));
+        let mut kill_switch = KillSwitch::new(ops.clone());
+        kill_switch.release().unwrap();
+
+        kill_switch.engage(policy("203.0.113.10")).unwrap();
+        kill_switch.engage(policy("203.0.113.10")).unwrap();
+        // Failover to another server replaces the rules
+        kill_switch.engage(policy("203.0.113.20")).unwrap();
+        assert_eq!(kill_switch.policy(), Some(&policy("203.0.113.20")));
+
+        kill_switch.release().unwrap();
+        kill_switch.release().unwrap();
+        assert!(!kill_switch.is_engaged());
+        assert_eq!(
+            ops.recorded(),
+            vec![
+                PlatformOp::EnableKillSwitch(policy("203.0.113.10")),
+                PlatformOp::EnableKillSwitch(policy("203.0.113.20")),
+                PlatformOp::DisableKillSwitch,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_engaged_kill_switch_is_journaled() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = ChangeJournal::new(dir.path());
+        let mut kill_switch =
+            KillSwitch::new(Arc::new(RecordingOps::new(Platform::Linux))).with_journal(Some(journal.clone()));
+
+        kill_switch.engage(policy("203.0.113.10")).unwrap();
+        kill_switch.engage(policy("203.0.113.20")).unwrap();
+        assert_eq!(journal.pending(JournalScope::KillSwitch), vec![UndoAction::DisableKillSwitch]);
+        kill_switch.release().unwrap();
+        assert!(!journal.path().exists());
+    }
+
+    #[test]
+    fn test_rules_allow_tunnel_and_server_only() {
+        let v4 = policy("203.0.113.10");
+        let nft = v4.nft_ruleset();
+        assert!(nft.contains("policy drop;"));
+        assert!(nft.contains("oifname \"vpnse0\" accept"));
+        assert!(nft.contains("ip daddr 203.0.113.10 accept"));
+        assert!(policy("2001:db8::10").nft_ruleset().contains("ip6 daddr 2001:db8::10 accept"));
+
+        let restore = v4.iptables_restore(false);
+        assert!(restore.contains("-A RVPNSE_KILLSWITCH -d 203.0.113.10 -j RETURN"));
+        assert!(restore.ends_with("-A RVPNSE_KILLSWITCH -j DROP\nCOMMIT\n"));
+        let restore6 = v4.iptables_restore(true);
+        assert!(!restore6.contains("203.0.113.10"));
+        assert!(restore6.contains("-o vpnse0 -j RETURN"));
+
+        let pf = v4.pf_rules();
+        assert!(pf.starts_with("block drop out all\n"));
+        assert!(pf.contains("pass out quick to 203.0.113.10\n"));
+
+        assert!(v4.windows_script().contains("-InterfaceAlias 'vpnse0'"));
+    }
+
+    #[test]
+    fn test_saved_state_round_trips() {
+        let actions = OutboundActions::parse("Domain=NotConfigured\r\nPrivate=Allow\r\nPublic=Block\r\n").unwrap();
+        assert_eq!(
+            actions.restore_script(),
+            "Set-NetFirewallProfile -Name Domain -DefaultOutboundAction NotConfigured; \
+             Set-NetFirewallProfile -Name Private -DefaultOutboundAction Allow; \
+             Set-NetFirewallProfile -Name Public -DefaultOutboundAction Block"
+        );
+        assert!(OutboundActions::parse("").is_err());
+        assert!(OutboundActions::parse("Domain=Allow; Remove-NetFirewallRule").is_err());
+
+        assert_eq!(pf_token("pf enabled\nToken : 10195463016163840613\n"), Some("10195463016163840613"));
+        assert_eq!(pf_token("pfctl: pf already enabled\n"), None);
+    }
+}
@@ -0,0 +1,231 @@
+//! Ethernet layer for bridged hubs
+//!
+//! SoftEther hubs switch Ethernet frames. With SecureNAT the hub's virtual
+//! host answers for the client, but on a bridged hub the client is a plain
+//! host on the segment and has to frame its own traffic. This module holds
+//! the pieces of that layer that carry no state:
+//!
+//! - MAC addresses: random, derived from a stable seed, parsed and formatted
+//! - Ethernet framing of IP packets from the TUN device, and unframing of
+//!   frames received from the server
+//! - ARP packets, including the gratuitous ARP a host sends when it takes an
+//!   address
+//!
+//! Resolving neighbours and answering for the client's addresses is
+//! [`NeighborStack`](super::neighbor::NeighborStack)'s job.
+
+use super::packet_framing::IpVersion;
+use crate::crypto;
+use crate::error::{Result, VpnError};
+use bytes::Bytes;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Ethernet hardware address
+pub type MacAddr = [u8; 6];
+
+/// Destination of frames for every host on the segment
+pub const BROADCAST: MacAddr = [0xff; 6];
+
+pub const ETHERNET_HEADER_LEN: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const ARP_LEN: usize = 28;
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+/// Hardware type (Ethernet), protocol type (IPv4) and address lengths
+const ARP_ETHERNET_IPV4: [u8; 6] = [0x00, 0x01, 0x08, 0x00, 6, 4];
+
+const IPV6_HEADER_LEN: usize = 40;
+
+/// Random locally administered unicast address
+pub fn random_mac() -> MacAddr {
+    local_unicast(rand::random())
+}
+
+/// Locally administered unicast address derived from `seed`
+///
+/// The same seed (e.g. the connection profile) gives the same address on
+/// every connect, so DHCP servers hand out the same lease and hosts on the
+/// segment keep valid ARP entries across reconnects.
+pub fn stable_mac(seed: &str) -> MacAddr {
+    let digest = crypto::sha0(seed.as_bytes());
+    local_unicast(mac_at(&digest, 0))
+}
+
+fn local_unicast(mut mac: MacAddr) -> MacAddr {
+    mac[0] = (mac[0] & 0xfc) | 0x02;
+    mac
+}
+
+/// Whether `mac` may be used as a host's own address (not group or all-zero)
+pub fn is_unicast(mac: MacAddr) -> bool {
+    mac[0] & 0x01 == 0 && mac != [0; 6]
+}
+
+/// Parse `02:00:5e:10:00:01` (or with `-` separators)
+pub fn parse_mac(text: &str) -> Result<MacAddr> {
+    let invalid = || {
+        VpnError::Config(format!("Invalid MAC address '{text}' (expected six hex octets such as 02:00:5e:10:00:01)"))
+    };
+    let octets: Vec<&str> = text.split([':', '-']).collect();
+    if octets.len() != 6 {
+        return Err(invalid());
+    }
+    let mut mac = [0u8; 6];
+    for (byte, octet) in mac.iter_mut().zip(octets) {
+        if octet.len() != 2 {
+            return Err(invalid());
+        }
+        *byte = u8::from_str_radix(octet, 16).map_err(|_| invalid())?;
+    }
+    Ok(mac)
+}
+
+/// `02:00:5e:10:00:01` form of `mac`
+pub fn format_mac(mac: MacAddr) -> String {
+    mac.iter().map(|octet| format!("{octet:02x}")).collect::<Vec<_>>().join(":")
+}
+
+/// Frame carrying `payload` of `ethertype`
+pub fn ethernet_frame(destination: MacAddr, source: MacAddr, ethertype: u16, payload: &[u8]) -> Bytes {
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_LEN + payload.len());
+    frame.extend(destination);
+    frame.extend(source);
+    frame.extend(ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    Bytes::from(frame)
+}
+
+/// Frame carrying an IP packet read from TUN, typed by its IP version
+pub fn ip_frame(destination: MacAddr, source: MacAddr, packet: &[u8]) -> Bytes {
+    let ethertype = match IpVersion::of(packet) {
+        Some(IpVersion::V6) => ETHERTYPE_IPV6,
+        _ => ETHERTYPE_IPV4,
+    };
+    ethernet_frame(destination, source, ethertype, packet)
+}
+
+/// The IP packet at the start of an Ethernet payload, without padding
+pub fn ip_packet(payload: &[u8]) -> Option<&[u8]> {
+    let len = match IpVersion::of(payload)? {
+        IpVersion::V4 => usize::from(u16::from_be_bytes([payload[2], payload[3]])),
+        IpVersion::V6 => IPV6_HEADER_LEN + usize::from(u16::from_be_bytes([payload[4], payload[5]])),
+    };
+    payload.get(..len)
+}
+
+/// ARP packet (the frame payload)
+pub fn arp_packet(
+    operation: u16,
+    sender_mac: MacAddr,
+    sender: Ipv4Addr,
+    target_mac: MacAddr,
+    target: Ipv4Addr,
+) -> Vec<u8> {
+    let mut arp = Vec::with_capacity(ARP_LEN);
+    arp.extend(ARP_ETHERNET_IPV4);
+    arp.extend(operation.to_be_bytes());
+    arp.extend(sender_mac);
+    arp.extend(sender.octets());
+    arp.extend(target_mac);
+    arp.extend(target.octets());
+    arp
+}
+
+/// Fields of an Ethernet/IPv4 ARP packet: operation, sender MAC and IP, target IP
+pub fn parse_arp(arp: &[u8]) -> Option<(u16, MacAddr, Ipv4Addr, Ipv4Addr)> {
+    if arp.len() < ARP_LEN || arp[..6] != ARP_ETHERNET_IPV4 {
+        return None;
+    }
+    Some((u16::from_be_bytes([arp[6], arp[7]]), mac_at(arp, 8), ipv4_at(arp, 14), ipv4_at(arp, 24)))
+}
+
+/// Broadcast ARP request for the sender's own address
+///
+/// Tells the segment which MAC now has `ip`, so hosts and the router update
+/// stale entries (e.g. from a previous session with another MAC).
+pub fn gratuitous_arp(mac: MacAddr, ip: Ipv4Addr) -> Bytes {
+    ethernet_frame(BROADCAST, mac, ETHERTYPE_ARP, &arp_packet(ARP_REQUEST, mac, ip, [0; 6], ip))
+}
+
+/// Multicast MAC of an IPv6 group (RFC 2464)
+pub fn ipv6_multicast_mac(group: Ipv6Addr) -> MacAddr {
+    let o = group.octets();
+    [0x33, 0x33, o[12], o[13], o[14], o[15]]
+}
+
+/// Multicast MAC of an IPv4 group (RFC 1112)
+pub fn ipv4_multicast_mac(group: Ipv4Addr) -> MacAddr {
+    let o = group.octets();
+    [0x01, 0x00, 0x5e, o[1] & 0x7f, o[2], o[3]]
+}
+
+pub(super) fn mac_at(bytes: &[u8], offset: usize) -> MacAddr {
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&bytes[offset..offset + 6]);
+    mac
+}
+
+pub(super) fn ipv4_at(bytes: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3])
+}
+
+pub(super) fn ipv6_at(bytes: &[u8], offset: usize) -> Ipv6Addr {
+    let mut octets = [0; 16];
+    octets.copy_from_slice(&bytes[offset..offset + 16]);
+    Ipv6Addr::from(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mac_addresses() {
+        let mac = random_mac();
+        assert!(is_unicast(mac));
+        assert_eq!(mac[0] & 0x02, 0x02);
+
+        let stable = stable_mac("alice@vpn.example.com:443/VPN");
+        assert_eq!(stable, stable_mac("alice@vpn.example.com:443/VPN"));
+        assert_ne!(stable, stable_mac("bob@vpn.example.com:443/VPN"));
+        assert!(is_unicast(stable));
+
+        assert_eq!(parse_mac("02:00:5E:10:00:01").unwrap(), [0x02, 0, 0x5e, 0x10, 0, 0x01]);
+        assert_eq!(parse_mac("02-00-5e-10-00-01").unwrap(), [0x02, 0, 0x5e, 0x10, 0, 0x01]);
+        assert_eq!(format_mac([0x02, 0, 0x5e, 0x10, 0, 0x01]), "02:00:5e:10:00:01");
+        for invalid in ["", "02:00:5e:10:00", "02:00:5e:10:00:01:02", "2:00:5e:10:00:01", "zz:00:5e:10:00:01"] {
+            assert!(parse_mac(invalid).is_err(), "{invalid}");
+        }
+        assert!(!is_unicast(BROADCAST));
+        assert!(!is_unicast([0; 6]));
+    }
+
+    #[test]
+    fn test_ip_framing_round_trip() {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[3] = 20;
+        let frame = ip_frame(BROADCAST, [0x02, 0, 0, 0, 0, 1], &packet);
+        assert_eq!(frame[12..14], ETHERTYPE_IPV4.to_be_bytes());
+
+        // Short frames are padded on the wire; the IP length wins
+        let mut padded = frame.to_vec();
+        padded.resize(60, 0);
+        assert_eq!(ip_packet(&padded[ETHERNET_HEADER_LEN..]), Some(&packet[..]));
+        assert_eq!(ip_packet(&[0x45, 0, 0, 40]), None);
+    }
+
+    #[test]
+    fn test_gratuitous_arp() {
+        let mac = [0x02, 0, 0, 0, 0, 0x07];
+        let ip = Ipv4Addr::new(10, 21, 0, 7);
+        let frame = gratuitous_arp(mac, ip);
+        assert_eq!(frame[..6], BROADCAST);
+        assert_eq!(frame[12..14], ETHERTYPE_ARP.to_be_bytes());
+        assert_eq!(parse_arp(&frame[ETHERNET_HEADER_LEN..]), Some((ARP_REQUEST, mac, ip, ip)));
+        assert_eq!(parse_arp(&frame[ETHERNET_HEADER_LEN + 1..]), None);
+    }
+}
@@ -0,0 +1,244 @@
+//! Lease options beyond address and DNS servers
+//!
+//! Enterprise hubs hand out WINS (NetBIOS name) servers and a
+//! connection-specific DNS domain through the virtual DHCP server. These are
+//! read from the server response and applied where the platform has a
+//! per-interface setting for them (systemd-resolved link domains, Windows
+//! adapter WINS and DNS suffix).
+//!
+//! Dual-stack hubs also hand out an IPv6 address, prefix length, gateway and
+//! IPv6 DNS servers; those are collected into an [`Ipv6Lease`].
+
+use crate::net_util;
+use crate::protocol::pack::Pack;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// PACK elements carrying WINS server addresses
+const WINS_ELEMENTS: &[&str] = &["WinsServer", "WinsServer2", "wins1", "wins2"];
+
+/// PACK elements carrying the connection-specific DNS domain
+const DOMAIN_ELEMENTS: &[&str] = &["DomainName", "domain_name", "dns_suffix"];
+
+/// PACK elements carrying the client's IPv6 address, possibly as "addr/len"
+const IPV6_ADDRESS_ELEMENTS: &[&str] = &["client_ipv6", "ipv6_address", "dhcp_ipv6"];
+
+/// PACK elements carrying the IPv6 prefix length
+const IPV6_PREFIX_ELEMENTS: &[&str] = &["ipv6_prefix_len", "ipv6_prefix"];
+
+/// PACK elements carrying the IPv6 gateway inside the tunnel
+const IPV6_GATEWAY_ELEMENTS: &[&str] = &["gateway_ipv6", "server_ipv6"];
+
+/// PACK elements that may carry IPv6 DNS servers (the plain DNS lists can mix families)
+const IPV6_DNS_ELEMENTS: &[&str] = &["dns1", "dns2", "dns1_v6", "dns2_v6", "ipv6_dns"];
+
+/// WINS servers, DNS domain and IPv6 configuration from a lease
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LeaseOptions {
+    pub wins_servers: Vec<Ipv4Addr>,
+    pub dns_domain: Option<String>,
+    /// IPv6 side of a dual-stack lease, if the hub assigned an IPv6 address
+    pub ipv6: Option<Ipv6Lease>,
+}
+
+/// IPv6 address, prefix, gateway and resolvers assigned to the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ipv6Lease {
+    pub address: Ipv6Addr,
+    pub prefix_len: u8,
+    /// Next hop inside the tunnel; without one, routes point at the interface
+    pub gateway: Option<Ipv6Addr>,
+    pub dns_servers: Vec<Ipv6Addr>,
+}
+
+impl Ipv6Lease {
+    /// Prefix length assumed when the server does not send one
+    pub const DEFAULT_PREFIX_LEN: u8 = 64;
+
+    /// Read the IPv6 lease from a server response, if it assigns an address
+    pub fn from_pack(pack: &Pack) -> Option<Self> {
+        let (address, embedded_len) = IPV6_ADDRESS_ELEMENTS
+            .iter()
+            .find_map(|name| ipv6_address_element(pack, name))?;
+
+        let prefix_len = embedded_len
+            .or_else(|| IPV6_PREFIX_ELEMENTS.iter().find_map(|name| pack.get_int(name)))
+            .map_or(Self::DEFAULT_PREFIX_LEN, |len| match u8::try_from(len) {
+                Ok(len @ 1..=128) => len,
+                _ => {
+                    log::warn!("Ignoring invalid IPv6 prefix length {} from server", len);
+                    Self::DEFAULT_PREFIX_LEN
+                }
+            });
+
+        let gateway = IPV6_GATEWAY_ELEMENTS
+            .iter()
+            .flat_map(|name| ipv6_list(pack, name))
+            .find(|gateway| *gateway != address);
+
+        let mut dns_servers = Vec::new();
+        for server in IPV6_DNS_ELEMENTS.iter().flat_map(|name| ipv6_list(pack, name)) {
+            if !dns_servers.contains(&server) {
+                dns_servers.push(server);
+            }
+        }
+
+        Some(Self { address, prefix_len, gateway, dns_servers })
+    }
+
+    /// Network prefix of the assigned address in CIDR notation
+    pub fn network_cidr(&self) -> String {
+        let subnet = net_util::subnet(IpAddr::V6(self.address), self.prefix_len);
+        subnet.map_or_else(|| format!("{}/{}", self.address, self.prefix_len), |net| net.to_string())
+    }
+}
+
+/// An IPv6 address element, as "addr", "addr/len" or 16 bytes of data
+fn ipv6_address_element(pack: &Pack, name: &str) -> Option<(Ipv6Addr, Option<u32>)> {
+    let (address, len) = match pack.get_str(name).and_then(|s| s.trim().split_once('/')) {
+        Some((addr, len)) => (addr.parse().ok()?, Some(len.parse().ok()?)),
+        None => (ipv6_list(pack, name).into_iter().next()?, None),
+    };
+    usable_unicast(&address).then_some((address, len))
+}
+
+/// IPv6 entries of an IP list element; IPv4 entries and malformed values are skipped
+fn ipv6_list(pack: &Pack, name: &str) -> Vec<Ipv6Addr> {
+    match pack.get_ip_list(name) {
+        Ok(list) => list
+            .into_iter()
+            .filter_map(|ip| match ip {
+                IpAddr::V6(ip) if usable_unicast(&ip) => Some(ip),
+                _ => None,
+            })
+            .collect(),
+        Err(e) => {
+            log::warn!("Ignoring malformed {} element: {}", name, e);
+            Vec::new()
+        }
+    }
+}
+
+fn usable_unicast(ip: &Ipv6Addr) -> bool {
+    !ip.is_unspecified() && !ip.is_loopback() && !ip.is_multicast()
+}
+
+impl LeaseOptions {
+    /// Read lease options from a server response
+    ///
+    /// Malformed values are logged and skipped; a domain that is not a valid
+    /// DNS name is dropped since it ends up on system command lines.
+    pub fn from_pack(pack: &Pack) -> Self {
+        let mut options = LeaseOptions::default();
+
+        for name in WINS_ELEMENTS {
+            match pack.get_ip_list(name) {
+                Ok(list) => {
+                    for ip in list {
+                        match ip {
+                            IpAddr::V4(ip) if !ip.is_unspecified() && !options.wins_servers.contains(&ip) => {
+                                options.wins_servers.push(ip);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                Err(e) => log::warn!("Ignoring malformed {} element: {}", name, e),
+            }
+        }
+
+        options.dns_domain = DOMAIN_ELEMENTS
+            .iter()
+            .filter_map(|name| pack.get_str(name))
+            .map(|domain| domain.trim().trim_end_matches('.').to_ascii_lowercase())
+            .find(|domain| {
+                let valid = is_valid_domain(domain);
+                if !valid && !domain.is_empty() {
+                    log::warn!("Ignoring invalid DNS domain '{}' from server", domain);
+                }
+                valid
+            });
+
+        options.ipv6 = Ipv6Lease::from_pack(pack);
+
+        options
+    }
+}
+
+/// Whether `domain` is a syntactically valid DNS name (LDH labels)
+pub fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= 253
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_options_from_pack() {
+        let mut pack = Pack::new();
+        pack.add_ip("WinsServer", IpAddr::from([10, 21, 0, 5]));
+        pack.add_str("wins2", "10.21.0.6");
+        pack.add_ip("WinsServer2", IpAddr::from([10, 21, 0, 5]));
+        pack.add_str("DomainName", "Corp.Example.COM.");
+
+        let options = LeaseOptions::from_pack(&pack);
+        assert_eq!(
+            options.wins_servers,
+            vec![Ipv4Addr::new(10, 21, 0, 5), Ipv4Addr::new(10, 21, 0, 6)]
+        );
+        assert_eq!(options.dns_domain.as_deref(), Some("corp.example.com"));
+    }
+
+    #[test]
+    fn test_ipv6_lease_from_pack() {
+        let mut pack = Pack::new();
+        assert_eq!(LeaseOptions::from_pack(&pack).ipv6, None);
+
+        pack.add_str("client_ipv6", "fd00:21::5/56");
+        pack.add_ip("gateway_ipv6", "fd00:21::1".parse().unwrap());
+        pack.add_ip("dns1", IpAddr::from([10, 21, 0, 1]));
+        pack.add_ip("dns2", "fd00:21::53".parse().unwrap());
+        pack.add_str("ipv6_dns", "fd00:21::53");
+
+        let lease = LeaseOptions::from_pack(&pack).ipv6.unwrap();
+        assert_eq!(lease.address, "fd00:21::5".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(lease.prefix_len, 56);
+        assert_eq!(lease.gateway, Some("fd00:21::1".parse().unwrap()));
+        assert_eq!(lease.dns_servers, vec!["fd00:21::53".parse::<Ipv6Addr>().unwrap()]);
+        assert_eq!(lease.network_cidr(), "fd00:21::/56");
+
+        // Binary address, separate (out of range) prefix element
+        let mut pack = Pack::new();
+        pack.add_ip("ipv6_address", "2001:db8:5::20".parse().unwrap());
+        pack.add_int("ipv6_prefix_len", 200);
+        let lease = Ipv6Lease::from_pack(&pack).unwrap();
+        assert_eq!(lease.prefix_len, Ipv6Lease::DEFAULT_PREFIX_LEN);
+        assert_eq!(lease.gateway, None);
+        assert_eq!(lease.network_cidr(), "2001:db8:5::/64");
+
+        let mut pack = Pack::new();
+        pack.add_str("client_ipv6", "::");
+        assert_eq!(Ipv6Lease::from_pack(&pack), None);
+    }
+
+    #[test]
+    fn test_invalid_domains_are_dropped() {
+        assert!(is_valid_domain("vpn-01.corp.example"));
+        assert!(!is_valid_domain("corp;rm -rf /"));
+        assert!(!is_valid_domain("-bad.example"));
+        assert!(!is_valid_domain("a..b"));
+
+        let mut pack = Pack::new();
+        pack.add_str("DomainName", "evil' -Force; x");
+        pack.add_str("dns_suffix", "good.example");
+        assert_eq!(LeaseOptions::from_pack(&pack).dns_domain.as_deref(), Some("good.example"));
+    }
+}
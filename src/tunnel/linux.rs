@@ -3,14 +3,17 @@
 //! This module provides Linux-specific implementations for tunnel management.
 
 use crate::error::{Result, VpnError};
-use crate::tunnel::TunnelConfig;
-use std::fs::OpenOptions;
+use crate::tunnel::l2::{self, MacAddr};
+use crate::tunnel::{SystemChange, TunnelConfig, TunnelMode};
+use std::fs::{File, OpenOptions};
+use std::io;
 use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 
 /// Create a TUN interface on Linux
 #[allow(dead_code)]
 pub fn create_tun_interface(_config: &TunnelConfig) -> Result<String> {
-    println!("Creating TUN interface on Linux...");
+    log::debug!("Creating TUN interface on Linux");
 
     // Check if we have permission to create TUN devices
     if !has_tun_permissions() {
@@ -22,24 +25,132 @@ pub fn create_tun_interface(_config: &TunnelConfig) -> Result<String> {
     // Try to create a TUN device
     let interface_name = create_tun_device()?;
 
-    println!("TUN interface '{interface_name}' created on Linux");
+    log::info!("TUN interface '{interface_name}' created on Linux");
     Ok(interface_name)
 }
 
 /// Destroy a TUN interface on Linux
 #[allow(dead_code)]
 pub fn destroy_tun_interface(interface_name: &str) -> Result<()> {
-    println!("Destroying TUN interface '{interface_name}' on Linux");
+    log::debug!("Destroying TUN interface '{interface_name}' on Linux");
 
     // Bring interface down
     let _ = std::process::Command::new("ip")
         .args(["link", "set", interface_name, "down"])
         .status();
 
-    println!("TUN interface '{interface_name}' destroyed on Linux");
+    log::info!("TUN interface '{interface_name}' destroyed on Linux");
     Ok(())
 }
 
+/// Give `interface` the hardware address `mac` (TAP devices start with a random one)
+pub(super) fn set_mac_address(interface: &str, mac: MacAddr) -> Result<()> {
+    ip(&["link", "set", "dev", interface, "address", &l2::format_mac(mac)], || {
+        format!("Failed to set the MAC address of '{interface}'")
+    })
+}
+
+fn ip(args: &[&str], failed: impl FnOnce() -> String) -> Result<()> {
+    let status = std::process::Command::new("ip")
+        .args(args)
+        .status()
+        .map_err(|e| VpnError::TunTap(format!("Failed to run ip: {e}")))?;
+    if !status.success() {
+        return Err(VpnError::TunTap(failed()));
+    }
+    Ok(())
+}
+
+const TUNSETIFF: libc::Ioctl = 0x4004_54ca;
+const TUNSETOFFLOAD: libc::Ioctl = 0x4004_54d0;
+const IFF_TUN: libc::c_short = 0x0001;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_MULTI_QUEUE: libc::c_short = 0x0100;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+const TUN_F_CSUM: libc::c_ulong = 0x01;
+const TUN_F_TSO4: libc::c_ulong = 0x02;
+const TUN_F_TSO6: libc::c_ulong = 0x04;
+
+/// `struct ifreq` as `TUNSETIFF` reads it
+#[repr(C)]
+struct IfReq {
+    name: [libc::c_char; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// Open `queues` queues of the TUN (or TAP) device `name`, creating it
+///
+/// Used instead of the tun crate when the device needs what the crate
+/// cannot ask for: with `offload` every frame carries a virtio-net header
+/// and the kernel hands over TCP super-packets (see
+/// [`offload`](super::offload)). The device is closed once every queue is.
+pub(super) fn open_queues(name: &str, mode: TunnelMode, queues: usize, offload: bool) -> Result<Vec<File>> {
+    if name.len() >= libc::IFNAMSIZ {
+        return Err(VpnError::TunTap(format!("Interface name '{name}' is too long")));
+    }
+    let mut flags = IFF_NO_PI | if mode == TunnelMode::Tap { IFF_TAP } else { IFF_TUN };
+    if queues > 1 {
+        flags |= IFF_MULTI_QUEUE;
+    }
+    if offload {
+        flags |= IFF_VNET_HDR;
+    }
+    let failed = |what: &str, e: io::Error| match e.kind() {
+        io::ErrorKind::PermissionDenied => {
+            VpnError::Permission(format!("Creating TUN interface requires root or CAP_NET_ADMIN: {e}"))
+        }
+        _ => VpnError::TunTap(format!("Failed to {what} '{name}': {e}")),
+    };
+
+    (0..queues.max(1))
+        .map(|_| {
+            let queue = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_CLOEXEC)
+                .open("/dev/net/tun")
+                .map_err(|e| failed("open /dev/net/tun for", e))?;
+            let mut request = IfReq { name: [0; libc::IFNAMSIZ], flags, _pad: [0; 22] };
+            for (slot, byte) in request.name.iter_mut().zip(name.bytes()) {
+                *slot = byte as libc::c_char;
+            }
+            // SAFETY: the descriptor is open and `request` is an ifreq-sized buffer that outlives the call
+            if unsafe { libc::ioctl(queue.as_raw_fd(), TUNSETIFF, &mut request as *mut IfReq) } < 0 {
+                return Err(failed("attach a queue to", io::Error::last_os_error()));
+            }
+            // Checksums and TCP segmentation are left to the reader of the device
+            let offloads = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6;
+            // SAFETY: TUNSETOFFLOAD takes its argument by value
+            if offload && unsafe { libc::ioctl(queue.as_raw_fd(), TUNSETOFFLOAD, offloads) } < 0 {
+                return Err(failed("enable offloads on", io::Error::last_os_error()));
+            }
+            Ok(queue)
+        })
+        .collect()
+}
+
+/// Address, MTU and link state of a device opened with [`open_queues`]
+pub(super) fn configure_interface(name: &str, config: &TunnelConfig) -> Result<()> {
+    let prefix = u32::from(config.netmask).count_ones();
+    let local = config.local_ip.to_string();
+    let (address, peer) = match config.mode {
+        // An Ethernet segment: the subnet is on-link, there is no point-to-point peer
+        TunnelMode::Tap => (format!("{local}/{prefix}"), None),
+        TunnelMode::Tun => (local, Some(format!("{}/{prefix}", config.remote_ip))),
+    };
+    let mut args = vec!["addr", "add", address.as_str()];
+    if let Some(ref peer) = peer {
+        args.extend(["peer", peer.as_str()]);
+    }
+    args.extend(["dev", name]);
+    ip(&args, || format!("Failed to address '{name}'"))?;
+    ip(&["link", "set", "dev", name, "mtu", &config.mtu.to_string(), "up"], || {
+        format!("Failed to bring up '{name}'")
+    })
+}
+
 #[allow(dead_code)]
 fn has_tun_permissions() -> bool {
     // Check if we can access /dev/net/tun
@@ -59,7 +170,7 @@ fn create_tun_device() -> Result<String> {
             // TUN device opened successfully
             // The actual interface name would be determined by the TUN library
             let interface_name = "vpnse0".to_string();
-            println!("Opened TUN device: /dev/net/tun");
+            log::debug!("Opened TUN device: /dev/net/tun");
             Ok(interface_name)
         }
         Err(e) => Err(VpnError::Connection(format!(
@@ -67,3 +178,52 @@ fn create_tun_device() -> Result<String> {
         ))),
     }
 }
+
+/// Firewall mark put on the traffic of split-tunneled cgroups
+pub const APP_ROUTING_MARK: u32 = 0x7670;
+
+/// Routing table holding the route for marked traffic
+pub const APP_ROUTING_TABLE: u32 = 7670;
+
+/// Priority of the policy rule, ahead of the main table (32766)
+pub const APP_ROUTING_PRIORITY: u32 = 7670;
+
+/// Changes that send the traffic of processes in `cgroups` out of `interface`
+///
+/// Sockets of those cgroup v2 groups (paths relative to `/sys/fs/cgroup`) are
+/// marked in `mangle OUTPUT`, the mark is kept on the connection so replies
+/// pass reverse path filtering, and a policy rule looks marked packets up in
+/// a table whose default route leaves through `interface`. Marking happens
+/// after the source address was picked, so marked traffic is masqueraded on
+/// its way out.
+pub(super) fn app_routing_changes(cgroups: &[String], gateway: Option<String>, interface: &str) -> Vec<SystemChange> {
+    let mark = format!("{APP_ROUTING_MARK:#x}");
+    let rule = |table: &str, chain: &str, args: &[&str]| SystemChange::AddFirewallRule {
+        table: Some(table.to_string()),
+        chain: chain.to_string(),
+        args: args.iter().map(|arg| arg.to_string()).collect(),
+    };
+
+    let mut changes = vec![SystemChange::SetSysctl {
+        key: "net.ipv4.conf.all.src_valid_mark".to_string(),
+        value: "1".to_string(),
+    }];
+    for cgroup in cgroups {
+        changes.push(rule("mangle", "OUTPUT", &["-m", "cgroup", "--path", cgroup, "-j", "MARK", "--set-mark", &mark]));
+    }
+    changes.push(rule("mangle", "OUTPUT", &["-m", "mark", "--mark", &mark, "-j", "CONNMARK", "--save-mark"]));
+    changes.push(rule("mangle", "PREROUTING", &["-m", "connmark", "--mark", &mark, "-j", "CONNMARK", "--restore-mark"]));
+    changes.push(rule("nat", "POSTROUTING", &["-o", interface, "-m", "mark", "--mark", &mark, "-j", "MASQUERADE"]));
+    changes.push(SystemChange::AddTableRoute {
+        destination: "default".to_string(),
+        gateway,
+        interface: interface.to_string(),
+        table: APP_ROUTING_TABLE,
+    });
+    changes.push(SystemChange::AddRoutingRule {
+        fwmark: APP_ROUTING_MARK,
+        table: APP_ROUTING_TABLE,
+        priority: APP_ROUTING_PRIORITY,
+    });
+    changes
+}
@@ -171,14 +171,11 @@ impl LinuxTunInterface {
 
     /// Convert netmask to CIDR notation
     fn netmask_to_cidr(netmask: &str) -> Result<u8> {
-        let addr = netmask.parse::<std::net::Ipv4Addr>()
-            .map_err(|_| VpnError::Configuration("Invalid netmask".to_string()))?;
-        
-        let octets = addr.octets();
-        let mask = u32::from_be_bytes(octets);
-        let cidr = mask.leading_ones() as u8;
-        
-        Ok(cidr)
+        netmask
+            .parse()
+            .ok()
+            .and_then(crate::net_util::prefix_len)
+            .ok_or_else(|| VpnError::Configuration("Invalid netmask".to_string()))
     }
 
     /// Read packet from TUN interface
@@ -11,7 +11,7 @@ use std::process::Command;
 /// Create a TUN interface on macOS
 #[allow(dead_code)]
 pub fn create_tun_interface(_config: &TunnelConfig) -> Result<String> {
-    println!("Creating TUN interface on macOS...");
+    log::debug!("Creating TUN interface on macOS");
 
     // Check if we have permission to create TUN devices
     if !has_tun_permissions() {
@@ -23,21 +23,21 @@ pub fn create_tun_interface(_config: &TunnelConfig) -> Result<String> {
     // Try to create a TUN device
     let interface_name = create_tun_device()?;
 
-    println!("TUN interface '{interface_name}' created on macOS");
+    log::info!("TUN interface '{interface_name}' created on macOS");
     Ok(interface_name)
 }
 
 /// Destroy a TUN interface on macOS
 #[allow(dead_code)]
 pub fn destroy_tun_interface(interface_name: &str) -> Result<()> {
-    println!("Destroying TUN interface '{interface_name}' on macOS");
+    log::debug!("Destroying TUN interface '{interface_name}' on macOS");
 
     // Bring interface down
     let _ = Command::new("ifconfig")
         .args([interface_name, "down"])
         .status();
 
-    println!("TUN interface '{interface_name}' destroyed on macOS");
+    log::info!("TUN interface '{interface_name}' destroyed on macOS");
     Ok(())
 }
 
@@ -75,7 +75,7 @@ fn create_tun_device() -> Result<String> {
         {
             Ok(_file) => {
                 // TUN device opened successfully
-                println!("Opened TUN device: {tun_path}");
+                log::debug!("Opened TUN device: {tun_path}");
                 return Ok(interface_name);
             }
             Err(_) => {
@@ -0,0 +1,241 @@
+//! TAP emulation on macOS with a feth pair
+//!
+//! macOS has no TAP driver. A pair of fake Ethernet interfaces stands in:
+//! the host side (`fethN`) gets the tunnel address like any Ethernet
+//! interface, and every frame it transmits comes out of its peer
+//! (`fethN+1`), which has no address. The peer is read and written through a
+//! BPF device, so frames pass between the host's stack and the session
+//! unchanged.
+//!
+//! The pair is destroyed with the transmit half; that also fails the read
+//! the receive half may be blocked in.
+
+use super::l2::{self, MacAddr};
+use super::tun_io::{TunReader, TunWriter};
+use super::TunnelConfig;
+use crate::error::{Result, VpnError};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process::Command;
+use std::sync::Arc;
+
+/// Pairs tried (`feth0`/`feth1`, `feth2`/`feth3`, …) before giving up
+const MAX_PAIRS: u32 = 32;
+
+/// BPF devices tried (`/dev/bpf0` …)
+const MAX_BPF_DEVICES: u32 = 256;
+
+/// BPF buffer size; one read returns at most this many bytes of records
+const BPF_BUFFER_LEN: u32 = 128 * 1024;
+
+/// `_IOW('B', 119, u_int)`, missing from libc for Apple targets
+const BIOCSSEESENT: libc::c_ulong = 0x8004_4277;
+
+/// `bpf_hdr` up to `bh_hdrlen`: timestamp (8), caplen (4), datalen (4), hdrlen (2)
+const BPF_HDR_FIELDS_LEN: usize = 18;
+
+/// Create a feth pair for `config` and open its peer side
+///
+/// Returns the name of the host side, which carries the tunnel address,
+/// and the I/O halves. With a `mac`, the host side takes that address.
+pub fn open(config: &TunnelConfig, mac: Option<MacAddr>) -> Result<(String, TunReader, TunWriter)> {
+    let pair = FethPair::create()?;
+    pair.configure(config, mac)?;
+    let bpf = Arc::new(open_bpf(&pair.peer)?);
+    let host = pair.host.clone();
+    log::info!("TAP mode on {} (peer {} read through BPF)", pair.host, pair.peer);
+
+    let reader = BpfReader { bpf: bpf.clone(), buf: vec![0; BPF_BUFFER_LEN as usize], pending: 0..0 };
+    let writer = BpfWriter { bpf, _pair: pair };
+    Ok((host, TunReader::new(reader), TunWriter::new(writer)))
+}
+
+/// Host and peer side of a feth pair, destroyed on drop
+struct FethPair {
+    host: String,
+    peer: String,
+}
+
+impl FethPair {
+    /// Create the first pair whose names are free
+    fn create() -> Result<Self> {
+        for index in 0..MAX_PAIRS {
+            let host = format!("feth{}", index * 2);
+            if ifconfig(&[&host, "create"]).is_err() {
+                continue;
+            }
+            // Destroys the host side again if the peer cannot be created
+            let pair = FethPair { host, peer: format!("feth{}", index * 2 + 1) };
+            if ifconfig(&[&pair.peer, "create"]).is_ok() {
+                return Ok(pair);
+            }
+        }
+        Err(VpnError::TunTap("No free feth interface pair for TAP mode (is this macOS 10.13 or later?)".into()))
+    }
+
+    fn configure(&self, config: &TunnelConfig, mac: Option<MacAddr>) -> Result<()> {
+        let mtu = config.mtu.to_string();
+        ifconfig(&[&self.peer, "peer", &self.host])?;
+        ifconfig(&[&self.peer, "mtu", &mtu, "up"])?;
+        if let Some(mac) = mac {
+            ifconfig(&[&self.host, "lladdr", &l2::format_mac(mac)])?;
+        }
+        let (address, netmask) = (config.local_ip.to_string(), config.netmask.to_string());
+        ifconfig(&[&self.host, "inet", &address, "netmask", &netmask, "mtu", &mtu, "up"])
+    }
+}
+
+impl Drop for FethPair {
+    fn drop(&mut self) {
+        for name in [&self.peer, &self.host] {
+            if let Err(e) = ifconfig(&[name, "destroy"]) {
+                log::warn!("Could not destroy {name}: {e}");
+            }
+        }
+    }
+}
+
+fn ifconfig(args: &[&str]) -> Result<()> {
+    let output = Command::new("ifconfig")
+        .args(args)
+        .output()
+        .map_err(|e| VpnError::TunTap(format!("Failed to run ifconfig: {e}")))?;
+    if !output.status.success() {
+        return Err(VpnError::TunTap(format!(
+            "ifconfig {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// BPF device bound to `interface`, sending frames as given and seeing only received ones
+fn open_bpf(interface: &str) -> Result<File> {
+    let bpf = (0..MAX_BPF_DEVICES)
+        .find_map(|n| OpenOptions::new().read(true).write(true).open(format!("/dev/bpf{n}")).ok())
+        .ok_or_else(|| VpnError::TunTap("No free BPF device for TAP mode".into()))?;
+    let fd = bpf.as_raw_fd();
+
+    // The buffer size has to be set before binding
+    let mut buffer_len = BPF_BUFFER_LEN;
+    bpf_ioctl(fd, libc::BIOCSBLEN, &mut buffer_len)?;
+    // SAFETY: ifreq is plain data; all zeroes is a valid (empty) request
+    let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+    // The zeroed tail terminates the name
+    for (slot, byte) in request.ifr_name.iter_mut().zip(interface.bytes().take(libc::IFNAMSIZ - 1)) {
+        *slot = byte as libc::c_char;
+    }
+    bpf_ioctl(fd, libc::BIOCSETIF, &mut request)?;
+
+    let (mut on, mut off): (libc::c_uint, libc::c_uint) = (1, 0);
+    // Return frames as they arrive rather than when the buffer fills
+    bpf_ioctl(fd, libc::BIOCIMMEDIATE, &mut on)?;
+    // Keep the source MAC of written frames
+    bpf_ioctl(fd, libc::BIOCSHDRCMPLT, &mut on)?;
+    // Do not read back what was written
+    bpf_ioctl(fd, BIOCSSEESENT, &mut off)?;
+    Ok(bpf)
+}
+
+fn bpf_ioctl<T>(fd: RawFd, request: libc::c_ulong, arg: &mut T) -> Result<()> {
+    // SAFETY: every request passed here takes a pointer to a `T`, which outlives the call
+    if unsafe { libc::ioctl(fd, request, arg as *mut T) } < 0 {
+        return Err(VpnError::TunTap(format!("BPF ioctl {request:#x} failed: {}", io::Error::last_os_error())));
+    }
+    Ok(())
+}
+
+/// Frames captured on the peer side, one per `read`
+///
+/// A BPF read returns every frame captured since the last one, each behind a
+/// `bpf_hdr`; the rest wait in `buf` for the following calls.
+struct BpfReader {
+    bpf: Arc<File>,
+    buf: Vec<u8>,
+    pending: Range<usize>,
+}
+
+impl Read for BpfReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some((frame, next)) = next_record(&self.buf[..self.pending.end], self.pending.start) {
+                self.pending.start = next;
+                let len = frame.len().min(out.len());
+                out[..len].copy_from_slice(&self.buf[frame.start..frame.start + len]);
+                return Ok(len);
+            }
+            let len = (&*self.bpf).read(&mut self.buf)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            self.pending = 0..len;
+        }
+    }
+}
+
+/// Frames injected on the peer side; owns the pair
+struct BpfWriter {
+    bpf: Arc<File>,
+    _pair: FethPair,
+}
+
+impl Write for BpfWriter {
+    fn write(&mut self, frame: &[u8]) -> io::Result<usize> {
+        (&*self.bpf).write(frame)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Frame of the BPF record at `offset` in `records`, and where the next record starts
+fn next_record(records: &[u8], offset: usize) -> Option<(Range<usize>, usize)> {
+    let header = records.get(offset..offset.checked_add(BPF_HDR_FIELDS_LEN)?)?;
+    let caplen = u32::from_ne_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    let hdrlen = usize::from(u16::from_ne_bytes([header[16], header[17]]));
+    let start = offset.checked_add(hdrlen)?;
+    let end = start.checked_add(caplen)?;
+    if end > records.len() {
+        return None;
+    }
+    // BPF_WORDALIGN: records start on 4-byte boundaries
+    Some((start..end, end.checked_add(3)? & !3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(frame: &[u8]) -> Vec<u8> {
+        // 18 bytes of header padded to 20, as the kernel does on 64-bit
+        let mut record = vec![0u8; 20];
+        record[8..12].copy_from_slice(&(frame.len() as u32).to_ne_bytes());
+        record[12..16].copy_from_slice(&(frame.len() as u32).to_ne_bytes());
+        record[16..18].copy_from_slice(&20u16.to_ne_bytes());
+        record.extend_from_slice(frame);
+        record.resize((record.len() + 3) & !3, 0);
+        record
+    }
+
+    #[test]
+    fn test_bpf_records_split_into_frames() {
+        let mut records = record(&[0xaa; 15]);
+        let second = records.len();
+        records.extend(record(&[0xbb; 60]));
+
+        let (frame, next) = next_record(&records, 0).unwrap();
+        assert_eq!(&records[frame], &[0xaa; 15]);
+        assert_eq!(next, second);
+        let (frame, next) = next_record(&records, next).unwrap();
+        assert_eq!(&records[frame], &[0xbb; 60]);
+        assert_eq!(next, records.len());
+        assert_eq!(next_record(&records, next), None);
+
+        // A record cut short by the buffer is not returned
+        assert_eq!(next_record(&records[..second + 30], second), None);
+    }
+}
@@ -195,9 +195,13 @@ impl MacOSUtunInterface {
 
     /// Write packet to utun interface
     pub async fn write_packet(&mut self, mut packet: Bytes) -> Result<()> {
-        // Prepend 4-byte protocol family (AF_INET = 2)
+        // Prepend the 4-byte protocol family utun expects for this IP version
+        let family = match super::packet_framing::IpVersion::of(&packet) {
+            Some(super::packet_framing::IpVersion::V6) => libc::AF_INET6 as u32,
+            _ => libc::AF_INET as u32,
+        };
         let mut full_packet = BytesMut::with_capacity(packet.len() + 4);
-        full_packet.extend_from_slice(&[0, 0, 0, 2]); // AF_INET in network byte order
+        full_packet.extend_from_slice(&family.to_be_bytes());
         full_packet.extend_from_slice(&packet);
         
         let bytes_written = unsafe {
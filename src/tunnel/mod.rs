@@ -2,14 +2,21 @@
 //!
 //! This module provides real TUN interface creation and traffic routing.
 
+use crate::client_optimized::PerformanceStats;
+use crate::config::{
+    CoexistencePolicy, CustomRoute, PublicIpConfig, RouteMode, RouteVia, RoutingConfig, TunnelMode, TunnelOptionsConfig,
+    WindowsDriver, TCP_IP_HEADER_LEN,
+};
 use crate::error::{Result, VpnError};
-use std::net::Ipv4Addr;
+use crate::net_util;
+use crate::protocol::binary::DisconnectNotice;
+use crate::underlay::UnderlayBinding;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::process::Command;
 use std::sync::{Arc, Mutex};
-use std::io::{Read, Write};
 use tokio::sync::mpsc;
 use tun::Device;
-use regex::Regex;
 
 #[cfg(target_os = "linux")]
 mod linux;
@@ -20,14 +27,57 @@ pub mod linux_tun;
 mod macos;
 #[cfg(target_os = "macos")]
 pub mod macos_tun;
+#[cfg(target_os = "macos")]
+mod macos_tap;
 
 #[cfg(target_os = "windows")]
 mod windows;
 #[cfg(target_os = "windows")]
 pub mod windows_tun;
 
+pub mod async_tun;
 pub mod real_tun;
 pub mod packet_framing;
+pub mod plan;
+pub mod pmtu;
+pub mod coexistence;
+pub mod dhcp;
+pub mod dns;
+pub mod host_device;
+pub mod http;
+pub mod journal;
+pub mod killswitch;
+pub mod pushed_routes;
+pub mod lease;
+pub mod l2;
+pub mod neighbor;
+pub mod offload;
+pub mod platform;
+pub mod pump;
+pub mod route_manager;
+pub mod routing;
+pub mod shaper;
+pub mod tun_io;
+pub mod workers;
+
+pub use async_tun::{AsyncTunReader, AsyncTunWriter};
+pub use dhcp::{DhcpClient, DhcpLease, DhcpState};
+pub use http::TunnelHttpBinding;
+pub use journal::{cleanup_stale_state, ChangeJournal, JournalScope, UndoAction};
+pub use killswitch::{KillSwitch, KillSwitchPolicy};
+pub use host_device::{HostTunnelSettings, PacketFlow};
+pub use l2::MacAddr;
+pub use neighbor::{NeighborConfig, NeighborStack, NeighborStats};
+pub use plan::{ChangeCategory, ChangePlan, SystemChange, SystemChangePlanner};
+pub use pushed_routes::PushedRoute;
+pub use route_manager::{PolicyRoutes, RouteManager, RoutePlan, RouteRequest, RouteRole};
+pub use lease::{Ipv6Lease, LeaseOptions};
+pub use packet_framing::{FramingParams, FramingStats};
+pub use workers::WorkerPool;
+pub use platform::{Platform, PlatformOp, PlatformOps, RecordingOps, SystemOps};
+pub use pump::{LinkLayer, PacketPump, PacketSink, PacketSource};
+pub use shaper::RateLimiter;
+pub use tun_io::{TunReader, TunWriter};
 
 /// TUN interface configuration
 #[derive(Debug, Clone)]
@@ -38,6 +88,31 @@ pub struct TunnelConfig {
     pub netmask: Ipv4Addr,
     pub mtu: u16,
     pub dns_servers: Vec<Ipv4Addr>,
+    /// Static routes pushed by the server, installed through the tunnel
+    pub pushed_routes: Vec<PushedRoute>,
+    /// WINS (NetBIOS name) servers from the lease
+    pub wins_servers: Vec<Ipv4Addr>,
+    /// Connection-specific DNS domain from the lease
+    pub dns_domain: Option<String>,
+    /// IPv6 address, prefix and resolvers for dual-stack tunnels
+    pub ipv6: Option<Ipv6Lease>,
+    /// cgroup v2 paths (relative to `/sys/fs/cgroup`) whose traffic bypasses
+    /// the tunnel (Linux only)
+    pub bypass_cgroups: Vec<String>,
+    /// cgroup v2 paths whose traffic alone goes through the tunnel, leaving
+    /// the host's routes to everything else (Linux only)
+    pub only_cgroups: Vec<String>,
+    /// TUN (IP packets) or TAP (Ethernet frames passed to the session as they are)
+    pub mode: TunnelMode,
+    /// Adapter driver to create the interface with (Windows only)
+    pub windows_driver: WindowsDriver,
+    /// Probe the path MTU to the server and lower `mtu` to what it carries
+    pub auto_mtu: bool,
+    /// Device queues (`IFF_MULTI_QUEUE`) the kernel spreads outgoing flows over (Linux only)
+    pub queues: usize,
+    /// Exchange TCP super-packets with the device through virtio-net
+    /// headers (`IFF_VNET_HDR`, TSO); TUN mode on Linux only
+    pub offload: bool,
 }
 
 impl Default for TunnelConfig {
@@ -49,6 +124,17 @@ impl Default for TunnelConfig {
             netmask: Ipv4Addr::new(255, 255, 255, 0),
             mtu: 1500,
             dns_servers: vec![Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)],
+            pushed_routes: Vec::new(),
+            wins_servers: Vec::new(),
+            dns_domain: None,
+            ipv6: None,
+            bypass_cgroups: Vec::new(),
+            only_cgroups: Vec::new(),
+            mode: TunnelMode::Tun,
+            windows_driver: WindowsDriver::Auto,
+            auto_mtu: false,
+            queues: 1,
+            offload: false,
         }
     }
 }
@@ -64,6 +150,17 @@ impl TunnelConfig {
             netmask: Ipv4Addr::new(255, 255, 0, 0),
             mtu: 1500,
             dns_servers: vec![Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)],
+            pushed_routes: Vec::new(),
+            wins_servers: Vec::new(),
+            dns_domain: None,
+            ipv6: None,
+            bypass_cgroups: Vec::new(),
+            only_cgroups: Vec::new(),
+            mode: TunnelMode::Tun,
+            windows_driver: WindowsDriver::Auto,
+            auto_mtu: false,
+            queues: 1,
+            offload: false,
         }
     }
     
@@ -77,6 +174,17 @@ impl TunnelConfig {
             netmask: Ipv4Addr::new(255, 255, 255, 0),
             mtu: 1500,
             dns_servers: vec![Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(8, 8, 4, 4)],
+            pushed_routes: Vec::new(),
+            wins_servers: Vec::new(),
+            dns_domain: None,
+            ipv6: None,
+            bypass_cgroups: Vec::new(),
+            only_cgroups: Vec::new(),
+            mode: TunnelMode::Tun,
+            windows_driver: WindowsDriver::Auto,
+            auto_mtu: false,
+            queues: 1,
+            offload: false,
         }
     }
 }
@@ -94,755 +202,924 @@ pub struct TunnelManager {
     #[allow(dead_code)]
     original_dns: Vec<String>,
     is_established: bool,
-    // Real TUN device for network traffic
-    tun_device: Option<tun::platform::Device>,
+    // Real TUN device for network traffic, split so RX and TX never contend
+    tun_reader: Option<TunReader>,
+    tun_writer: Option<TunWriter>,
+    // Further queues of a multi-queue device
+    #[cfg(target_os = "linux")]
+    tun_queues: Vec<(TunReader, TunWriter)>,
+    // The same halves made non-blocking, once used from async code
+    async_tun: Option<(AsyncTunReader, AsyncTunWriter)>,
     // Packet channels for VPN traffic routing
     packet_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
     packet_rx: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
-    // Packet framing for proper VPN encapsulation
+    // Packet framing for proper VPN encapsulation, once negotiated with the server
     packet_framer: Option<packet_framing::SharedPacketFramer>,
+    // Forwarding between the TUN device and the session, once started
+    packet_pump: Option<PacketPump>,
+    // Host hook reviewing route/DNS/firewall changes before they are applied
+    change_planner: Option<Arc<dyn SystemChangePlanner>>,
+    // Changes applied (or vetoed) during the last establishment
+    applied_plan: Option<ChangePlan>,
+    // Plans and checks routes (the platform's own unless replaced)
+    route_manager: Option<Arc<dyn RouteManager>>,
+    // The routes of the applied plan, with what each is for
+    applied_routes: Option<RoutePlan>,
+    // Route metric and policy towards other active VPNs
+    routing: RoutingConfig,
+    // Server-pushed routes installed by this manager, removed on teardown
+    installed_pushed_routes: Vec<String>,
+    // Routes from `[[tunnel.routes]]`
+    custom_routes: Vec<CustomRoute>,
+    // Config-defined routes installed by this manager, removed on teardown
+    installed_custom_routes: Vec<SystemChange>,
+    // DNS servers chosen by the host at runtime, replacing the defaults
+    dns_override: Option<Vec<IpAddr>>,
+    // TCP MSS clamp for SYNs leaving through the tunnel
+    mss_clamp: Option<u16>,
+    // Tunnel MTU derived from the probed path MTU (`auto_mtu`)
+    discovered_mtu: Option<u16>,
+    // MSS clamping rules (chain, args) installed by this manager
+    installed_mss_rules: Vec<(String, Vec<String>)>,
+    // Marks, rules and table route of per-app split tunneling, in install order
+    installed_app_routing: Vec<SystemChange>,
+    // Abort (and roll back) establishment instead of falling back to a degraded tunnel
+    strict: bool,
+    // The session carries Ethernet frames (bridged hub) rather than IP packets
+    layer2: bool,
+    // Own MAC on bridged hubs; random if unset
+    mac: Option<MacAddr>,
+    // DHCP client that obtained the address, kept renewing by the packet pump
+    dhcp: Option<DhcpClient>,
+    // Public IP lookup settings for diagnostics
+    public_ip: PublicIpConfig,
+    // Uplink chosen to carry the session, instead of the OS default
+    underlay: Option<UnderlayBinding>,
+    // Server the session is connected to
+    vpn_server: Option<SocketAddr>,
+    // Driver behind the Windows adapter, once created
+    windows_driver: Option<WindowsDriver>,
+    // The device was created and configured by the host app, not by this manager
+    host_device: bool,
+    // Route/DNS/firewall/interface operations (the real system unless replaced)
+    ops: Arc<dyn PlatformOps>,
+    // On-disk record of how to undo applied changes, should the process die
+    journal: Option<ChangeJournal>,
 }
 
 impl TunnelManager {
-    /// Create a new tunnel manager
-    pub fn new(config: TunnelConfig) -> Self {
+    /// Create a tunnel manager for a session connected to `vpn_server`
+    ///
+    /// The server is kept reachable outside the tunnel and its path probed for
+    /// `auto_mtu`; `None` when the tunnel does not belong to a session.
+    pub fn new(config: TunnelConfig, vpn_server: Option<SocketAddr>) -> Self {
         let (packet_tx, packet_rx) = mpsc::unbounded_channel();
         
-        // Generate a session ID for packet framing
-        let session_id = rand::random::<u32>();
-        
         Self {
             interface_name: config.interface_name.clone(),
             config: config.clone(),
             original_route: None,
             original_dns: Vec::new(),
             is_established: false,
-            tun_device: None,
+            tun_reader: None,
+            tun_writer: None,
+            #[cfg(target_os = "linux")]
+            tun_queues: Vec::new(),
+            async_tun: None,
             packet_tx: Some(packet_tx),
             packet_rx: Some(packet_rx),
-            packet_framer: Some(packet_framing::SharedPacketFramer::new(
-                session_id, 
-                config.remote_ip.into()
-            )),
+            packet_framer: None,
+            packet_pump: None,
+            change_planner: None,
+            applied_plan: None,
+            route_manager: None,
+            applied_routes: None,
+            routing: RoutingConfig::default(),
+            installed_pushed_routes: Vec::new(),
+            custom_routes: Vec::new(),
+            installed_custom_routes: Vec::new(),
+            dns_override: None,
+            mss_clamp: None,
+            discovered_mtu: None,
+            installed_mss_rules: Vec::new(),
+            installed_app_routing: Vec::new(),
+            strict: false,
+            layer2: false,
+            mac: None,
+            dhcp: None,
+            public_ip: PublicIpConfig::default(),
+            underlay: None,
+            vpn_server,
+            windows_driver: None,
+            host_device: false,
+            ops: Arc::new(SystemOps::default()),
+            journal: None,
         }
     }
 
+    /// Use a VPN interface the host app created instead of creating one
+    ///
+    /// The app has configured its addresses, routes and DNS from
+    /// [`host_settings`](Self::host_settings), so nothing is changed on the
+    /// system, now or on teardown, which only closes the halves.
+    pub fn attach_host_device(&mut self, reader: TunReader, writer: TunWriter) -> Result<()> {
+        if self.config.mode == TunnelMode::Tap {
+            return Err(VpnError::Config("tunnel.mode = \"tap\" needs a device created by the library".into()));
+        }
+        self.tun_reader = Some(reader);
+        self.tun_writer = Some(writer);
+        self.host_device = true;
+        self.is_established = true;
+        log::info!("Attached host-provided tunnel device");
+        Ok(())
+    }
+
+    /// Interface configuration for a device created by the host app
+    pub fn host_settings(&self) -> HostTunnelSettings {
+        HostTunnelSettings::new(&self.config, self.dns_servers())
+    }
+
     /// Establish the VPN tunnel
     pub fn establish_tunnel(&mut self) -> Result<()> {
-        println!("🚇 Establishing VPN tunnel...");
+        log::info!("Establishing VPN tunnel");
 
         // Store original routing information before making changes
         self.store_original_route()?;
 
+        if self.config.auto_mtu {
+            self.discover_mtu();
+        }
+
         // Create TUN interface based on the current OS
         match self.create_tun_interface() {
-            Ok(()) => {
-                println!("   ✅ TUN interface created successfully");
+            Ok(()) => {}
+            Err(e) if self.strict => {
+                log::error!("TUN interface creation failed: {}", e);
+                return Err(e);
             }
             Err(e) => {
-                println!("   ⚠️  TUN interface creation failed: {}", e);
-                println!("   ℹ️  Falling back to platform-specific tunnel setup");
+                log::warn!("TUN interface creation failed, falling back to platform-specific setup: {}", e);
                 self.establish_platform_tunnel()?;
             }
         }
 
         // Configure routing to direct traffic through VPN
-        self.configure_vpn_routing()?;
+        if let Err(e) = self.configure_vpn_routing() {
+            self.tun_reader = None;
+            self.tun_writer = None;
+            #[cfg(target_os = "linux")]
+            self.tun_queues.clear();
+            let _ = self.ops.delete_interface(&self.interface_name);
+            return Err(e);
+        }
 
         self.is_established = true;
-        println!("✅ VPN tunnel established successfully!");
-        println!("   📝 Interface: {}", self.interface_name);
-        println!("   📍 Local IP: {}", self.config.local_ip);
-        println!("   📍 Remote IP: {}", self.config.remote_ip);
-        
-        // Check if this is a DHCP-assigned IP range and provide extra info
+        let ipv6 = self.config.ipv6.as_ref().map(|ipv6| format!("{}/{}", ipv6.address, ipv6.prefix_len));
+        log::info!(
+            interface = self.interface_name.as_str(),
+            local_ip:% = self.config.local_ip,
+            remote_ip:% = self.config.remote_ip,
+            local_ipv6 = ipv6.as_deref().unwrap_or("none");
+            "VPN tunnel established"
+        );
+
         if self.is_dhcp_assigned_ip() {
             let octets = self.config.local_ip.octets();
-            println!("   📌 DHCP-assigned IP detected: {}.{}.*.* range", octets[0], octets[1]);
-            
-            // Special handling for 10.21.*.* networks
-            if octets[0] == 10 && octets[1] == 21 {
-                println!("   ✅ Found expected 10.21.*.* network from DHCP");
-            }
+            log::debug!("DHCP-assigned address in the {}.{}.*.* range", octets[0], octets[1]);
         }
 
-        // Start packet routing loop
-        self.start_packet_routing_loop()?;
-
         Ok(())
     }
 
     /// Configure system routing to direct traffic through VPN tunnel
+    ///
+    /// The required changes are collected into a [`ChangePlan`] first so the
+    /// host's [`SystemChangePlanner`] (if any) can veto or rewrite them. The
+    /// approved changes are applied as one transaction: if one fails, or the
+    /// [`RouteManager`] finds the routes do not take traffic where they
+    /// should, everything applied is rolled back.
     fn configure_vpn_routing(&mut self) -> Result<()> {
-        println!("🛣️  Configuring VPN routing...");
-
-        // Add route for VPN server to prevent routing loop
-        self.add_vpn_server_route()?;
+        log::info!("Configuring VPN routing");
 
-        // Configure VPN tunnel as default gateway
-        self.set_vpn_default_gateway()?;
-
-        // Configure DNS to use VPN DNS servers
-        self.configure_vpn_dns()?;
-
-        println!("   ✅ VPN routing configured successfully");
-        Ok(())
-    }
+        let (mut plan, routes, positions) = self.build_plans()?;
 
-    /// Add specific route for VPN server through original gateway
-    fn add_vpn_server_route(&self) -> Result<()> {
-        if let Some(ref original_gateway) = self.original_route {
-            let vpn_server = &self.config.remote_ip;
-            
-            #[cfg(target_os = "linux")]
-            {
-                let output = Command::new("sudo")
-                    .args([
-                        "ip", "route", "add", 
-                        &vpn_server.to_string(),
-                        "via", original_gateway
-                    ])
-                    .output();
+        if let Some(ref planner) = self.change_planner {
+            planner.review(&mut plan);
+            for (change, reason) in plan.vetoed() {
+                log::info!(reason = reason; "System change vetoed by host: {}", change);
+            }
+        }
 
-                match output {
-                    Ok(result) if result.status.success() => {
-                        println!("   ✅ Added VPN server route via original gateway");
-                    }
-                    Ok(result) => {
-                        let stderr = String::from_utf8_lossy(&result.stderr);
-                        if stderr.contains("File exists") {
-                            println!("   ℹ️  VPN server route already exists");
-                        } else {
-                            println!("   ⚠️  Warning: VPN server route command failed: {}", stderr);
-                        }
-                    }
-                    Err(e) => {
-                        println!("   ⚠️  Warning: Failed to add VPN server route: {}", e);
-                    }
-                }
+        let mut applied = Vec::new();
+        for change in plan.approved() {
+            if let Err(e) = self.apply_change(change) {
+                log::error!("{} failed: {}", change, e);
+                self.roll_back(&applied);
+                return Err(e);
             }
+            applied.push(change.clone());
+        }
 
-            #[cfg(target_os = "macos")]
-            {
-                let output = Command::new("sudo")
-                    .args([
-                        "route", "add", 
-                        &vpn_server.to_string(),
-                        original_gateway
-                    ])
-                    .output();
+        let routes = routes.reviewed(&plan, &positions);
+        if let Err(e) = self.route_manager().verify(&routes, self.ops.as_ref()) {
+            log::error!("{}", e);
+            self.roll_back(&applied);
+            return Err(e);
+        }
 
-                match output {
-                    Ok(result) if result.status.success() => {
-                        println!("   ✅ Added VPN server route via original gateway");
-                    }
-                    Ok(_) => {
-                        println!("   ℹ️  VPN server route may already exist");
-                    }
-                    Err(e) => {
-                        println!("   ⚠️  Warning: Failed to add VPN server route: {}", e);
-                    }
+        // Remember which routes came from the server so teardown removes exactly those
+        let pushed: Vec<String> = self.config.pushed_routes.iter().map(PushedRoute::cidr).collect();
+        let custom = self.custom_route_changes();
+        self.installed_pushed_routes = plan
+            .approved()
+            .filter_map(|change| match change {
+                SystemChange::AddRoute { destination, .. } if pushed.contains(destination) && !custom.contains(change) => {
+                    Some(destination.clone())
                 }
-            }
+                _ => None,
+            })
+            .collect();
+
+        self.installed_custom_routes = plan
+            .approved()
+            .filter(|change| custom.contains(change))
+            .cloned()
+            .collect();
+
+        self.installed_mss_rules = plan
+            .approved()
+            .filter_map(|change| match change {
+                SystemChange::AddFirewallRule { table: Some(table), chain, args }
+                    if table == "mangle" && args.iter().any(|arg| arg == "TCPMSS") =>
+                {
+                    Some((chain.clone(), args.clone()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let app_routing = self.app_routing_changes()?;
+        self.installed_app_routing = plan
+            .approved()
+            .filter(|change| app_routing.contains(change))
+            .cloned()
+            .collect();
+
+        if self.ops.platform() == Platform::Linux {
+            self.log_routing_table();
         }
+
+        self.applied_plan = Some(plan);
+        self.applied_routes = Some(routes);
+
+        log::info!("VPN routing configured");
         Ok(())
     }
 
-    /// Set VPN tunnel as default gateway
-    fn set_vpn_default_gateway(&self) -> Result<()> {
-        println!("Setting up routing for VPN tunnel...");
-        
-        #[cfg(target_os = "linux")]
-        {
-            // Step 1: Find active network interface
-            let if_output = Command::new("ip")
-                .args(["route", "get", "8.8.8.8"])
-                .output();
-                
-            let active_interface = if let Ok(output) = if_output {
-                let out_str = String::from_utf8_lossy(&output.stdout);
-                // Extract "dev X" from output
-                let pattern = "dev ";
-                if let Some(pos) = out_str.find(pattern) {
-                    let after_dev = &out_str[pos + pattern.len()..];
-                    after_dev.split_whitespace().next().unwrap_or("eth0").to_string()
-                } else {
-                    "eth0".to_string()
+    /// Build the list of system changes needed to route traffic through the tunnel
+    ///
+    /// Fails if another VPN is active and the coexistence policy is `abort`,
+    /// or if per-app routing is requested where it is not supported.
+    ///
+    /// Planning only queries the platform, so it can be previewed against
+    /// [`RecordingOps`] without touching the host:
+    ///
+    /// ```
+    /// use rvpnse::tunnel::{ChangeCategory, Platform, RecordingOps, TunnelConfig, TunnelManager};
+    /// use std::sync::Arc;
+    ///
+    /// let server = "203.0.113.10:443".parse().ok();
+    /// let mut manager = TunnelManager::new(TunnelConfig::default(), server);
+    /// manager.set_platform_ops(Arc::new(RecordingOps::new(Platform::Linux).with_default_gateway("192.168.1.1")));
+    ///
+    /// let plan = manager.build_change_plan()?;
+    /// assert!(plan.approved().any(|change| change.category() == ChangeCategory::Route));
+    /// assert!(plan.approved().any(|change| change.category() == ChangeCategory::Dns));
+    /// # Ok::<(), rvpnse::VpnError>(())
+    /// ```
+    pub fn build_change_plan(&self) -> Result<ChangePlan> {
+        self.build_plans().map(|(plan, ..)| plan)
+    }
+
+    /// The change plan, with the route plan that went into it and where its routes are
+    fn build_plans(&self) -> Result<(ChangePlan, RoutePlan, Vec<usize>)> {
+        let mut plan = ChangePlan::new();
+        let metric = Some(self.routing.route_metric);
+        let platform = self.ops.platform();
+
+        let app_routing = self.app_routing_changes()?;
+
+        let other_vpns = self.ops.vpn_interfaces(&self.interface_name);
+        let coexist_split = if other_vpns.is_empty() {
+            false
+        } else {
+            log::warn!("Other VPN interfaces active: {}", other_vpns.join(", "));
+            match self.routing.coexistence_policy {
+                CoexistencePolicy::Override => {
+                    log::info!("Coexistence policy 'override': taking over the default route");
+                    false
                 }
-            } else {
-                "eth0".to_string()
-            };
-            
-            // Step 2: Get default gateway IP
-            let gw_output = Command::new("ip")
-                .args(["route", "show", "default"])
-                .output();
-            let default_gw = if let Ok(output) = gw_output {
-                let route_info = String::from_utf8_lossy(&output.stdout);
-                
-                // Extract the gateway IP address using regex
-                let re = Regex::new(r"default\s+via\s+(\d+\.\d+\.\d+\.\d+)").unwrap();
-                if let Some(caps) = re.captures(&route_info) {
-                    caps.get(1).unwrap().as_str().to_string()
-                } else {
-                    // Fallback to simple string parsing if regex fails
-                    route_info
-                        .split_whitespace()
-                        .skip_while(|&word| word != "via")
-                        .nth(1)
-                        .unwrap_or("192.168.1.1")
-                        .to_string()
+                CoexistencePolicy::CoexistSplit => {
+                    log::warn!("Coexistence policy 'coexist-split': default route skipped, routing the tunnel subnet");
+                    true
                 }
-            } else {
-                "192.168.1.1".to_string()
-            };
-            
-            // Use active_interface from earlier extraction
-            println!("   📍 Preserving original gateway: {}", default_gw);
-            println!("   📍 Original interface: {}", active_interface);
-            
-            // Step 3: Create a route to the VPN server through the original gateway
-            if let Some(vpn_server) = self.get_vpn_server_ip() {
-                // First, clean up any existing routes to avoid conflicts
-                let _cleanup = Command::new("sudo")
-                    .args(["ip", "route", "del", &format!("{}/32", vpn_server)])
-                    .output();
-                
-                // Add route to VPN server via original gateway
-                let add_server_route = Command::new("sudo")
-                    .args([
-                        "ip", "route", "add",
-                        &format!("{}/32", vpn_server),
-                        "via", &default_gw,
-                        "dev", &active_interface
-                    ])
-                    .output();
-                    
-                if let Ok(out) = add_server_route {
-                    if out.status.success() {
-                        println!("   ✅ Added VPN server route via original gateway");
-                    } else {
-                        let err = String::from_utf8_lossy(&out.stderr);
-                        println!("   ⚠️ Server route add failed: {}", err);
-                    }
+                CoexistencePolicy::Abort => {
+                    return Err(VpnError::Routing(format!(
+                        "Another VPN is active ({}) and coexistence policy is 'abort'",
+                        other_vpns.join(", ")
+                    )));
                 }
             }
+        };
+
+        // With `only_cgroups` the listed apps reach the tunnel through their own table
+        let split_only = coexist_split || !self.config.only_cgroups.is_empty();
+
+        let vpn_server = self.vpn_server.map(|server| server.ip().to_string());
+        let request = RouteRequest {
+            interface: &self.interface_name,
+            local_ip: self.config.local_ip,
+            netmask: self.config.netmask,
+            gateway: self.config.remote_ip,
+            ipv6: self.config.ipv6.as_ref(),
+            vpn_server: vpn_server.as_deref(),
+            underlay: self.underlay.as_ref(),
+            original_gateway: self.original_route.as_deref(),
+            split_only,
+            metric,
+        };
+        let routes = self.route_manager().plan(&request, self.ops.as_ref())?;
+        // Where each route lands in the plan, to find it again after the host's review
+        let mut positions = Vec::new();
+        for route in routes.family(false) {
+            positions.push(plan.len());
+            plan.push(route.change.clone());
+        }
 
-            // Step 4: Remove existing default routes (clean slate approach)
-            println!("   🔄 Cleaning up existing routes...");
-            
-            // Use a single command to delete the default route (more efficient)
-            let _del_default = Command::new("sudo")
-                .args(["ip", "route", "del", "default"])
-                .output();
+        if platform == Platform::Linux {
+            // Reverse path filtering drops asymmetric VPN traffic
+            plan.push(SystemChange::SetSysctl {
+                key: "net.ipv4.conf.all.rp_filter".to_string(),
+                value: "0".to_string(),
+            });
+            plan.push(SystemChange::SetSysctl {
+                key: format!("net.ipv4.conf.{}.rp_filter", self.interface_name),
+                value: "0".to_string(),
+            });
+            plan.push(SystemChange::SetSysctl {
+                key: "net.ipv4.ip_forward".to_string(),
+                value: "1".to_string(),
+            });
+            if self.routing.mode == RouteMode::Policy && !split_only {
+                // Replies to the marked session sockets must pass the reverse path check on the uplink
+                plan.push(SystemChange::SetSysctl {
+                    key: "net.ipv4.conf.all.src_valid_mark".to_string(),
+                    value: "1".to_string(),
+                });
+            }
 
-            // Step 5: Add new default route through VPN tunnel
-            println!("   🔄 Setting up VPN routing...");
-            
-            // Add default route via VPN's remote IP - follow SoftEther's approach
-            let add_default = Command::new("sudo")
-                .args([
-                    "ip", "route", "add", "default",
-                    "via", &self.config.remote_ip.to_string(),
-                    "dev", &self.interface_name
-                ])
-                .output();
-                
-            if let Ok(out) = add_default {
-                if out.status.success() {
-                    println!("   ✅ Set VPN tunnel as default gateway");
-                } else {
-                    let err = String::from_utf8_lossy(&out.stderr);
-                    println!("   ⚠️ Failed to set default route: {}", err);
-                }
+            // Flush existing NAT rules to avoid conflicts, unless another VPN may own some
+            if !split_only {
+                plan.push(SystemChange::FlushFirewallTable { table: "nat".to_string() });
             }
-            
-            // Step 6: Verify the new routing table
-            let check = Command::new("ip")
-                .args(["route", "show"])
-                .output();
-                
-            if let Ok(out) = check {
-                let routes = String::from_utf8_lossy(&out.stdout);
-                println!("   📋 Current routing table:");
-                for line in routes.lines().take(5) {
-                    println!("      {}", line);
-                }
-                if routes.lines().count() > 5 {
-                    println!("      ... ({} more routes)", routes.lines().count() - 5);
+            plan.push(SystemChange::AddFirewallRule {
+                table: Some("nat".to_string()),
+                chain: "POSTROUTING".to_string(),
+                args: vec!["-o".to_string(), self.interface_name.clone(), "-j".to_string(), "MASQUERADE".to_string()],
+            });
+            plan.push(SystemChange::AddFirewallRule {
+                table: None,
+                chain: "FORWARD".to_string(),
+                args: vec!["-i".to_string(), self.interface_name.clone(), "-j".to_string(), "ACCEPT".to_string()],
+            });
+
+            // Rewrite the MSS of outgoing SYNs (local and forwarded) to fit the tunnel MTU
+            if let Some(mss) = self.mss_clamp {
+                for chain in ["OUTPUT", "FORWARD"] {
+                    plan.push(SystemChange::AddFirewallRule {
+                        table: Some("mangle".to_string()),
+                        chain: chain.to_string(),
+                        args: mss_clamp_args(&self.interface_name, mss),
+                    });
                 }
             }
-            
-            // Step 7: Simple split tunneling for comprehensive coverage (following SoftEther approach)
-            // This ensures all traffic goes through the VPN except for direct routes
-            println!("   🔄 Adding split tunneling routes...");
-            
-            // Add routes for both halves of the IPv4 address space
-            // This is more reliable than default routes in many cases
-            let add_lower = Command::new("sudo")
-                .args([
-                    "ip", "route", "add", "0.0.0.0/1",
-                    "via", &self.config.remote_ip.to_string(),
-                    "dev", &self.interface_name
-                ])
-                .output();
-                
-            let add_upper = Command::new("sudo")
-                .args([
-                    "ip", "route", "add", "128.0.0.0/1", 
-                    "via", &self.config.remote_ip.to_string(),
-                    "dev", &self.interface_name
-                ])
-                .output();
-                
-            if add_lower.is_ok() && add_upper.is_ok() {
-                println!("   ✅ Added split tunneling routes");
+
+            for change in app_routing {
+                plan.push(change);
             }
+        }
 
-            // Step 8: Disable reverse path filtering (critical for VPN traffic)
-            println!("   🔧 Optimizing kernel parameters for VPN...");
-            
-            // Disable reverse path filtering
-            let _rp_filter = Command::new("sudo")
-                .args(["sysctl", "-w", "net.ipv4.conf.all.rp_filter=0"])
-                .output();
-                
-            let _rp_filter_if = Command::new("sudo")
-                .args(["sysctl", "-w", &format!("net.ipv4.conf.{}.rp_filter=0", self.interface_name)])
-                .output();
-                
-            // Enable IP forwarding for VPN traffic
-            let _ip_forward = Command::new("sudo")
-                .args(["sysctl", "-w", "net.ipv4.ip_forward=1"])
-                .output();
-                
-            println!("   ✅ Optimized kernel parameters for VPN traffic");
-            
-            // Step 9: Setup minimal iptables rules for VPN 
-            println!("   🔧 Setting up iptables for VPN traffic...");
-            
-            // Allow traffic from the VPN interface
-            let vpn_ip = self.config.local_ip.to_string();
-            println!("   📝 Using VPN IP: {} for routing configuration", vpn_ip);
-            
-            // Support for different VPN IP ranges (10.21.*.*, 124.166.*.*, etc.)
-            // Extract the network part of the IP for proper routing
-            let vpn_subnet = {
-                // Use a separate scope to contain the borrow
-                let parts: Vec<&str> = vpn_ip.split('.').collect();
-                if parts.len() >= 3 {
-                    // All IP ranges get /24 subnet by default for simplicity
-                    format!("{}.{}.{}.0/24", parts[0], parts[1], parts[2])
-                } else {
-                    format!("{}/24", vpn_ip)
-                }
-            };
-            
-            println!("   📝 Using VPN subnet: {} for routing configuration", vpn_subnet);
-            
-            // Enable IP forwarding
-            let forward_result = Command::new("sudo")
-                .args(["sysctl", "-w", "net.ipv4.ip_forward=1"])
-                .output();
-                
-            if let Ok(result) = forward_result {
-                if result.status.success() {
-                    println!("   ✅ Enabled IP forwarding");
-                }
+        // Dual stack: address the interface and send IPv6 through the tunnel as well
+        if let Some(ref ipv6) = self.config.ipv6 {
+            if platform == Platform::Linux {
+                // Fresh interfaces inherit net.ipv6.conf.default, which may disable IPv6
+                plan.push(SystemChange::SetSysctl {
+                    key: format!("net.ipv6.conf.{}.disable_ipv6", self.interface_name),
+                    value: "0".to_string(),
+                });
             }
-            
-            // IMPROVED: Flush existing NAT rules to avoid conflicts
-            let _flush_nat = Command::new("sudo")
-                .args([
-                    "iptables", "-t", "nat", "-F"
-                ])
-                .output();
-            
-            // Add NAT rule to route traffic through VPN
-            let nat_result = Command::new("sudo")
-                .args([
-                    "iptables", "-t", "nat", "-A", "POSTROUTING",
-                    "-o", &self.interface_name, "-j", "MASQUERADE"
-                ])
-                .output();
-            
-            if let Ok(result) = nat_result {
-                if result.status.success() {
-                    println!("   ✅ Added iptables NAT rule for VPN traffic");
-                }
+            plan.push(SystemChange::AddAddress {
+                interface: self.interface_name.clone(),
+                address: IpAddr::V6(ipv6.address),
+                prefix_len: ipv6.prefix_len,
+            });
+        }
+        // IPv6 routes need the interface's IPv6 address first
+        for route in routes.family(true) {
+            positions.push(plan.len());
+            plan.push(route.change.clone());
+        }
+
+        // Static routes pushed by the server; the default route stays governed by the policy above
+        let configured: Vec<IpNet> = self
+            .custom_routes
+            .iter()
+            .filter_map(|route| route.network())
+            .filter_map(|(address, prefix_len)| net_util::subnet(address, prefix_len))
+            .collect();
+        for route in &self.config.pushed_routes {
+            if route.is_default() {
+                log::info!("Ignoring pushed default route {}", route);
+                continue;
             }
-            
-            // Add rule to forward traffic to VPN interface
-            let forward_result = Command::new("sudo")
-                .args([
-                    "iptables", "-A", "FORWARD",
-                    "-i", &self.interface_name, "-j", "ACCEPT"
-                ])
-                .output();
-            
-            if let Ok(result) = forward_result {
-                if result.status.success() {
-                    println!("   ✅ Added iptables forward rule for VPN traffic");
+            // The same destination twice fails to install; the configured route wins
+            if let Some(network) = net_util::subnet(IpAddr::V4(route.network), route.prefix_len) {
+                if configured.contains(&network) {
+                    log::info!("Ignoring pushed route {}: configured routes cover {}", route, network);
+                    continue;
                 }
-            }
-            
-            // Verify the route was added
-            let verify_output = Command::new("ip")
-                .args(["route", "show"])
-                .output();
-                
-            if let Ok(output) = verify_output {
-                let routes = String::from_utf8_lossy(&output.stdout);
-                println!("   📋 Current routing table after VPN setup:");
-                for line in routes.lines().take(10) {
-                    if line.contains("default") || line.contains(&self.interface_name) || line.contains("0.0.0.0") {
-                        println!("      {}", line);
-                    }
+                if let Some(other) = configured.iter().find(|other| net_util::overlaps(other, &network)) {
+                    log::info!("Pushed route {} overlaps configured route {}; the longer prefix wins", route, other);
                 }
             }
+            plan.push(SystemChange::AddRoute {
+                destination: route.cidr(),
+                gateway: Some(route.gateway.unwrap_or(self.config.remote_ip).to_string()),
+                interface: Some(self.interface_name.clone()),
+                metric,
+            });
         }
 
-        #[cfg(target_os = "macos")]
-        {
-            if let Some(ref original_gateway) = self.original_route {
-                // Delete existing default route
-                let _delete_output = Command::new("sudo")
-                    .args(["route", "delete", "default", original_gateway])
-                    .output();
+        // Config-defined routes apply in every routing mode
+        for change in self.custom_route_changes() {
+            plan.push(change);
+        }
 
-                // Add new default route through VPN interface
-                let output = Command::new("sudo")
-                    .args([
-                        "route", "add", "default",
-                        "-interface", &self.interface_name
-                    ])
-                    .output();
+        plan.push(SystemChange::SetDns {
+            interface: self.interface_name.clone(),
+            servers: self.dns_servers(),
+        });
+        if let Some(ref domain) = self.config.dns_domain {
+            plan.push(SystemChange::SetDnsDomain {
+                interface: self.interface_name.clone(),
+                domain: domain.clone(),
+            });
+        }
+        if !self.config.wins_servers.is_empty() {
+            plan.push(SystemChange::SetWins {
+                interface: self.interface_name.clone(),
+                servers: self.config.wins_servers.clone(),
+            });
+        }
 
-                match output {
-                    Ok(result) if result.status.success() => {
-                        println!("   ✅ Set VPN tunnel as default gateway");
-                    }
-                    Ok(_) => {
-                        println!("   ⚠️  Warning: Default gateway setup may have issues");
-                    }
-                    Err(e) => {
-                        println!("   ⚠️  Warning: Failed to set default gateway: {}", e);
-                    }
-                }
+        Ok((plan, routes, positions))
+    }
+
+    /// DNS servers configured for the tunnel
+    ///
+    /// The servers set with [`set_dns_servers`](Self::set_dns_servers), or by
+    /// default the VPN gateway first (common in VPN setups), then the lease's
+    /// IPv6 resolvers, then reliable public resolvers.
+    pub fn dns_servers(&self) -> Vec<IpAddr> {
+        if let Some(ref servers) = self.dns_override {
+            return servers.clone();
+        }
+        let mut dns_servers = vec![IpAddr::V4(self.config.remote_ip)];
+        if let Some(ref ipv6) = self.config.ipv6 {
+            dns_servers.extend(ipv6.dns_servers.iter().copied().map(IpAddr::V6));
+        }
+        dns_servers.extend([
+            IpAddr::from([1, 1, 1, 1]),
+            IpAddr::from([8, 8, 8, 8]),
+            IpAddr::from([8, 8, 4, 4]),
+            IpAddr::from([1, 0, 0, 1]),
+        ]);
+        dns_servers
+    }
+
+    /// Replace the tunnel's DNS servers without reconnecting
+    ///
+    /// An empty list goes back to the defaults. While the tunnel is up the
+    /// resolver is reconfigured right away, subject to the change planner;
+    /// otherwise the servers take effect at the next establishment. Returns
+    /// the servers now configured. A vetoed or failed update keeps the
+    /// previous servers. On a device created by the host app, the app
+    /// applies them itself.
+    pub fn set_dns_servers(&mut self, servers: Vec<IpAddr>) -> Result<Vec<IpAddr>> {
+        let previous = std::mem::replace(&mut self.dns_override, (!servers.is_empty()).then_some(servers));
+        if !self.is_established || self.host_device {
+            return Ok(self.dns_servers());
+        }
+
+        let mut plan = ChangePlan::new();
+        plan.push(SystemChange::SetDns {
+            interface: self.interface_name.clone(),
+            servers: self.dns_servers(),
+        });
+        // Rewriting the resolver configuration drops the search domain
+        if let Some(ref domain) = self.config.dns_domain {
+            plan.push(SystemChange::SetDnsDomain {
+                interface: self.interface_name.clone(),
+                domain: domain.clone(),
+            });
+        }
+        if let Some(ref planner) = self.change_planner {
+            planner.review(&mut plan);
+        }
+
+        let servers = plan.approved().find_map(|change| match change {
+            SystemChange::SetDns { servers, .. } => Some(servers.clone()),
+            _ => None,
+        });
+        let Some(servers) = servers else {
+            self.dns_override = previous;
+            let reason = plan.vetoed().map(|(_, reason)| reason).next().unwrap_or_default();
+            return Err(VpnError::Dns(format!("DNS update vetoed by host: {reason}")));
+        };
+
+        for change in plan.approved() {
+            if let Err(e) = self.apply_change(change) {
+                self.dns_override = previous;
+                return Err(e);
+            }
+        }
+        if let Some(ref mut applied) = self.applied_plan {
+            for change in plan.approved() {
+                applied.push(change.clone());
             }
         }
 
-        Ok(())
+        log::info!("DNS servers updated: {}", servers.iter().map(ToString::to_string).collect::<Vec<_>>().join(", "));
+        Ok(servers)
     }
 
-    /// Configure DNS to use VPN DNS servers
-    fn configure_vpn_dns(&self) -> Result<()> {
-        println!("   🔧 Configuring VPN DNS...");
+    /// Binding for HTTP clients whose requests must go through the tunnel
+    ///
+    /// Only the IPv4 DNS servers are used, since queries are sent from the
+    /// tunnel's IPv4 address.
+    pub fn http_binding(&self) -> Result<TunnelHttpBinding> {
+        if !self.is_established {
+            return Err(VpnError::InvalidState("Tunnel is not established".into()));
+        }
+        Ok(TunnelHttpBinding {
+            interface: self.interface_name.clone(),
+            local_ip: self.config.local_ip,
+            dns_servers: self
+                .dns_servers()
+                .into_iter()
+                .filter(IpAddr::is_ipv4)
+                .map(|server| std::net::SocketAddr::new(server, 53))
+                .collect(),
+        })
+    }
 
-        // First try to extract DNS from DHCP-assigned values (future implementation)
-        // For now, use reliable public DNS servers as fallback - reordered for better reliability
-        let vpn_dns_servers = ["1.1.1.1", "8.8.8.8", "8.8.4.4", "1.0.0.1"];
-        
-        // Log the VPN IP information for debugging
-        println!("   📝 VPN IP configuration: Local={}, Gateway={}", 
-                self.config.local_ip, self.config.remote_ip);
-        
-        // Try to determine if the gateway might be a DNS server (common in VPN setups)
-        let gateway_ip = self.config.remote_ip.to_string();
-        println!("   📝 Checking if gateway IP {} can be used as DNS server", gateway_ip);
+    /// Route changes for `[[tunnel.routes]]`
+    ///
+    /// Tunnel routes use the tunnel gateway (the lease's IPv6 gateway for IPv6
+    /// destinations); underlay routes use the chosen uplink or, without one,
+    /// the current default route. Routes with no usable next hop are skipped.
+    fn custom_route_changes(&self) -> Vec<SystemChange> {
+        let mut changes = Vec::new();
+        for route in &self.custom_routes {
+            let Some((address, prefix_len)) = route.network() else {
+                log::warn!("Skipping custom route with invalid destination '{}'", route.dest);
+                continue;
+            };
+            let destination = format!("{address}/{prefix_len}");
 
-        #[cfg(target_os = "linux")]
-        {
-            // Detect if systemd-resolved is in use
-            let using_systemd_resolved = Command::new("systemctl")
-                .args(["is-active", "systemd-resolved"])
-                .output()
-                .map(|output| String::from_utf8_lossy(&output.stdout).trim() == "active")
-                .unwrap_or(false);
-            
-            println!("   📝 Detected systemd-resolved: {}", using_systemd_resolved);
-            
-            if using_systemd_resolved {
-                // Configure systemd-resolved for the VPN interface
-                println!("   🔧 Configuring systemd-resolved for VPN DNS...");
-                
-                // Create a temporary config file
-                let mut resolved_conf = String::new();
-                resolved_conf.push_str("[Resolve]\n");
-                
-                // Check if we should include gateway as potential DNS server
-                let mut dns_servers = vpn_dns_servers.to_vec();
-                let gateway_ip = self.config.remote_ip.to_string();
-                dns_servers.insert(0, &gateway_ip); // Add gateway IP as first DNS option
-                
-                resolved_conf.push_str(&format!("DNS={}\n", dns_servers.join(" ")));
-                resolved_conf.push_str("DNSStubListener=yes\n");
-                resolved_conf.push_str("DNSOverTLS=opportunistic\n"); // Try DNS-over-TLS if available
-                resolved_conf.push_str("Cache=yes\n"); // Enable DNS caching
-                resolved_conf.push_str("DNSSEC=allow-downgrade\n"); // Allow DNSSEC with fallback
-                
-                if let Ok(mut file) = std::fs::File::create("/tmp/vpn-dns.conf") {
-                    use std::io::Write;
-                    let _ = file.write_all(resolved_conf.as_bytes());
-                    
-                    // Move the config file
-                    let _ = Command::new("sudo")
-                        .args(["mkdir", "-p", "/etc/systemd/resolved.conf.d/"])
-                        .output();
-                        
-                    let _move_result = Command::new("sudo")
-                        .args(["mv", "/tmp/vpn-dns.conf", "/etc/systemd/resolved.conf.d/vpn-dns.conf"])
-                        .output();
-                    
-                    // Force resolved to use our DNS servers for the VPN interface
-                    let _set_link_dns = Command::new("sudo")
-                        .args(["resolvectl", "dns", &self.interface_name, &dns_servers.join(" ")])
-                        .output();
-                    
-                    // Restart systemd-resolved
-                    let _restart = Command::new("sudo")
-                        .args(["systemctl", "restart", "systemd-resolved"])
-                        .output();
-                    
-                    // Flush DNS caches
-                    let _flush = Command::new("sudo")
-                        .args(["resolvectl", "flush-caches"])
-                        .output();
-                    
-                    println!("   ✅ systemd-resolved configured for VPN DNS");
-                    println!("   📝 DNS servers: {} (gateway IP first for best VPN-provided DNS support)", dns_servers.join(", "));
+            let next_hop = match (route.via, address) {
+                (RouteVia::Tunnel, IpAddr::V4(_)) => {
+                    Some((Some(self.config.remote_ip.to_string()), self.interface_name.clone()))
                 }
-            } else {
-                // Backup original resolv.conf
-                let _backup_result = Command::new("sudo")
-                    .args(["cp", "/etc/resolv.conf", "/etc/resolv.conf.vpn_backup"])
-                    .output();
+                (RouteVia::Tunnel, IpAddr::V6(_)) => self
+                    .config
+                    .ipv6
+                    .as_ref()
+                    .map(|ipv6| (ipv6.gateway.map(|gw| gw.to_string()), self.interface_name.clone())),
+                (RouteVia::Underlay, _) if self.underlay.is_some() => self.underlay.as_ref().map(|underlay| {
+                    // The uplink's gateway is IPv4; IPv6 destinations leave via the interface
+                    let gateway = underlay.gateway.filter(|_| address.is_ipv4()).map(|gw| gw.to_string());
+                    (gateway, underlay.interface.clone())
+                }),
+                (RouteVia::Underlay, IpAddr::V4(_)) => {
+                    let (gateway, interface) = self.ops.underlay_route();
+                    Some((Some(gateway), interface))
+                }
+                (RouteVia::Underlay, IpAddr::V6(_)) => self
+                    .ops
+                    .underlay_route_v6()
+                    .map(|(gateway, interface)| (Some(gateway), interface)),
+            };
+            let Some((gateway, interface)) = next_hop else {
+                log::warn!("Skipping custom route {}: no IPv6 path for it", route);
+                continue;
+            };
 
-                // Create new resolv.conf with VPN DNS and shorter timeout for faster fallback
-                let mut dns_config = String::new();
-                dns_config.push_str("# DNS Configuration for rVPNSE VPN\n");
-                dns_config.push_str("options timeout:1 attempts:3 rotate\n"); // Short timeout, multiple attempts, rotate servers
-                dns_config.push_str("options edns0\n"); // Enable EDNS which often helps with VPN DNS
-                
-                // Check for any DHCP-provided DNS servers from the VPN connection
-                // This works with various ranges including 10.21.*.*, 10.216.48.*, 10.244.*.* networks
-                let vpn_octets = self.config.local_ip.octets();
-                let gateway_ip = self.config.remote_ip.to_string();
-                
-                // Log the subnet info for debugging
-                println!("   📝 VPN subnet: {}.{}.{}.0/24 (checking for DNS servers in this range)", 
-                         vpn_octets[0], vpn_octets[1], vpn_octets[2]);
-                
-                // Add the VPN gateway as the first nameserver (common in VPN setups)
-                dns_config.push_str(&format!("nameserver {}\n", gateway_ip));
-                println!("   📝 Adding VPN gateway as primary DNS: {}", gateway_ip);
+            changes.push(SystemChange::AddRoute {
+                destination,
+                gateway,
+                interface: Some(interface),
+                metric: route.metric.or(match route.via {
+                    RouteVia::Tunnel => Some(self.routing.route_metric),
+                    RouteVia::Underlay => None,
+                }),
+            });
+        }
+        changes
+    }
 
-                // Add the primary public DNS servers next
-                for dns in &vpn_dns_servers {
-                    dns_config.push_str(&format!("nameserver {}\n", dns));
+    /// Undo `applied` changes, newest first, after establishment failed part way
+    ///
+    /// Addresses go away with the interface. Kernel parameters and flushed
+    /// firewall tables are not restored.
+    fn roll_back(&self, applied: &[SystemChange]) {
+        log::warn!("Rolling back {} applied change(s)", applied.len());
+        let mut undone = Vec::new();
+        for change in applied.iter().rev() {
+            match UndoAction::for_change(change, self.original_route.as_deref(), &self.interface_name) {
+                Some(undo) if undone.contains(&undo) => {}
+                Some(undo) => {
+                    let _ = undo.run(self.ops.as_ref());
+                    undone.push(undo);
                 }
+                None => log::debug!("Not rolled back: {}", change),
+            }
+        }
+        self.forget_journaled_changes();
+    }
 
-                // Add search domain to help with name resolution
-                // Common VPN domains that might help with internal DNS resolution
-                dns_config.push_str("search local vpn internal\n");
-
-                // Write new DNS configuration
-                if let Ok(mut file) = std::fs::File::create("/tmp/resolv.conf.vpn") {
-                    use std::io::Write;
-                    let _ = file.write_all(dns_config.as_bytes());
-                    
-                    let _move_result = Command::new("sudo")
-                        .args(["mv", "/tmp/resolv.conf.vpn", "/etc/resolv.conf"])
-                        .output();
-                    
-                    // Set proper permissions
-                    let _chmod = Command::new("sudo")
-                        .args(["chmod", "644", "/etc/resolv.conf"])
-                        .output();
-                    
-                    // Ensure nsswitch.conf has correct entries for DNS
-                    let _nsswitch_check = Command::new("sudo")
-                        .args(["grep", "-q", "hosts:.*dns", "/etc/nsswitch.conf"])
-                        .output();
-                    
-                    if let Ok(result) = _nsswitch_check {
-                        if !result.status.success() {
-                            println!("   📝 Adding 'dns' to nsswitch.conf hosts entry");
-                            // Add dns to the hosts line in nsswitch.conf
-                            let _sed_cmd = Command::new("sudo")
-                                .args(["sed", "-i", "/hosts:/s/$/ dns/", "/etc/nsswitch.conf"])
-                                .output();
-                        }
-                    }
-                    
-                    println!("   ✅ DNS configured for VPN via direct resolv.conf update");
+    /// Apply one change, journaling how to undo it first
+    fn apply_change(&self, change: &SystemChange) -> Result<()> {
+        if let Some(ref journal) = self.journal {
+            if let Some(undo) = UndoAction::for_change(change, self.original_route.as_deref(), &self.interface_name) {
+                if let Err(e) = journal.record(JournalScope::Tunnel, undo) {
+                    log::warn!(journal:? = journal.path(); "Could not journal {}: {}", change, e);
                 }
             }
-            
-            // Test DNS resolution with multiple methods for better diagnostics
-            println!("   🔍 Testing DNS resolution...");
-            
-            // Test with host command (simple DNS lookup)
-            let dns_test_host = Command::new("host")
-                .args(["google.com"])
-                .output();
-                
-            let host_success = if let Ok(output) = dns_test_host {
-                if output.status.success() {
-                    println!("   ✅ DNS test with 'host': google.com resolves correctly");
-                    true
-                } else {
-                    println!("   ⚠️ DNS test with 'host': google.com cannot be resolved");
-                    false
+        }
+        self.ops.apply(change)
+    }
+
+    fn forget_journaled_changes(&self) {
+        if let Some(ref journal) = self.journal {
+            if let Err(e) = journal.forget(JournalScope::Tunnel) {
+                log::warn!(journal:? = journal.path(); "Could not update the change journal: {}", e);
+            }
+        }
+    }
+
+    /// Changes for per-app split tunneling (`bypass_cgroups`/`only_cgroups`)
+    ///
+    /// Bypassing cgroups leave through the uplink that carries the session,
+    /// exclusive ones through the tunnel; both are steered by firewall mark
+    /// and policy routing.
+    fn app_routing_changes(&self) -> Result<Vec<SystemChange>> {
+        let (cgroups, via_tunnel) = match (&self.config.bypass_cgroups, &self.config.only_cgroups) {
+            (bypass, only) if bypass.is_empty() && only.is_empty() => return Ok(Vec::new()),
+            (bypass, only) if !bypass.is_empty() && !only.is_empty() => {
+                return Err(VpnError::Config(
+                    "bypass_cgroups and only_cgroups cannot both be set".to_string(),
+                ));
+            }
+            (bypass, only) if only.is_empty() => (bypass, false),
+            (_, only) => (only, true),
+        };
+        if self.ops.platform() != Platform::Linux || !cfg!(target_os = "linux") {
+            return Err(VpnError::CapabilityUnavailable(
+                "Per-app split tunneling needs Linux cgroup v2".to_string(),
+            ));
+        }
+
+        let (gateway, interface) = if via_tunnel {
+            (Some(self.config.remote_ip.to_string()), self.interface_name.clone())
+        } else if let Some(ref underlay) = self.underlay {
+            (underlay.gateway.map(|gw| gw.to_string()), underlay.interface.clone())
+        } else {
+            let (gateway, interface) = self.ops.underlay_route();
+            (Some(gateway), interface)
+        };
+
+        #[cfg(target_os = "linux")]
+        return Ok(linux::app_routing_changes(cgroups, gateway, &interface));
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = (cgroups, gateway, interface);
+            Ok(Vec::new())
+        }
+    }
+
+    /// Remove the per-app routing installed during establishment, newest first
+    fn remove_app_routing(&mut self) {
+        for change in std::mem::take(&mut self.installed_app_routing).iter().rev() {
+            match change {
+                SystemChange::AddFirewallRule { table, chain, args } => {
+                    let _ = self.ops.delete_firewall_rule(table.as_deref(), chain, args);
                 }
-            } else {
-                println!("   ⚠️ Failed to run 'host' command");
-                false
-            };
-            
-            // Try ping as another test method
-            let dns_test_ping = Command::new("ping")
-                .args(["-c", "1", "-W", "3", "google.com"])
-                .output();
-                
-            let ping_success = if let Ok(output) = dns_test_ping {
-                if output.status.success() {
-                    println!("   ✅ DNS test with 'ping': google.com resolves correctly");
-                    true
-                } else {
-                    println!("   ⚠️ DNS test with 'ping': google.com cannot be resolved");
-                    false
+                SystemChange::AddTableRoute { destination, table, .. } => {
+                    let _ = self.ops.delete_table_route(destination, *table);
                 }
-            } else {
-                println!("   ⚠️ Failed to run 'ping' command");
-                false
-            };
-            
-            // Try with dig if available (more detailed DNS info)
-            let dns_test_dig = Command::new("dig")
-                .args(["+short", "google.com"])
-                .output();
-                
-            let dig_success = if let Ok(output) = dns_test_dig {
-                if output.status.success() && !String::from_utf8_lossy(&output.stdout).trim().is_empty() {
-                    println!("   ✅ DNS test with 'dig': google.com resolves correctly");
-                    true
-                } else {
-                    println!("   ⚠️ DNS test with 'dig': google.com cannot be resolved");
-                    false
+                SystemChange::AddRoutingRule { fwmark, table, priority } => {
+                    let _ = self.ops.delete_routing_rule(*fwmark, *table, *priority);
                 }
-            } else {
-                // Dig might not be installed, that's ok
-                println!("   ℹ️ 'dig' command not available");
-                false
-            };
-            
-            // Check nsswitch.conf to ensure DNS is properly configured in the system
-            let nsswitch_check = Command::new("grep")
-                .args(["hosts:", "/etc/nsswitch.conf"])
-                .output();
-                
-            if let Ok(output) = nsswitch_check {
-                let nsswitch_content = String::from_utf8_lossy(&output.stdout);
-                if !nsswitch_content.contains("dns") {
-                    println!("   ⚠️ Warning: 'dns' not found in /etc/nsswitch.conf hosts line");
-                    println!("      Add 'dns' to the hosts line in /etc/nsswitch.conf for proper DNS resolution");
+                _ => {}
+            }
+        }
+    }
+
+    /// Remove the config-defined routes installed during establishment
+    fn remove_custom_routes(&mut self) {
+        for change in std::mem::take(&mut self.installed_custom_routes) {
+            if let SystemChange::AddRoute { destination, interface, .. } = change {
+                let _ = self.ops.delete_route(&destination, interface.as_deref());
+            }
+        }
+    }
+
+    /// Remove the planned routes that outlive the interface (server pin, split routes)
+    fn remove_plan_routes(&mut self) {
+        if let Some(routes) = self.applied_routes.take() {
+            self.route_manager().remove(&routes, self.ops.as_ref());
+        }
+    }
+
+    /// Remove the server-pushed routes installed during establishment
+    fn remove_pushed_routes(&mut self) {
+        for destination in std::mem::take(&mut self.installed_pushed_routes) {
+            let _ = self.ops.delete_route(&destination, Some(&self.interface_name));
+        }
+    }
+
+    /// Remove the MSS clamping rules installed during establishment
+    fn remove_mss_rules(&mut self) {
+        for (chain, args) in std::mem::take(&mut self.installed_mss_rules) {
+            let _ = self.ops.delete_firewall_rule(Some("mangle"), &chain, &args);
+        }
+    }
+
+    /// Log the relevant part of the routing table after setup, at debug level
+    fn log_routing_table(&self) {
+        if !log::log_enabled!(log::Level::Debug) {
+            return;
+        }
+        if let Ok(output) = Command::new("ip").args(["route", "show"]).output() {
+            let routes = String::from_utf8_lossy(&output.stdout);
+            for line in routes.lines().take(10) {
+                if line.contains("default") || line.contains(&self.interface_name) || line.contains("0.0.0.0") {
+                    log::debug!("Route after VPN setup: {}", line);
                 }
             }
-            
-            // Provide overall DNS status
-            if host_success || ping_success || dig_success {
-                println!("   ✅ DNS resolution working through at least one method");
-            } else {
-                println!("   ⚠️ DNS resolution failed with all methods");
-                println!("      Try running 'sudo ./fix_vpn_connection.sh' to repair DNS configuration");
-            }
         }
+    }
 
-        #[cfg(target_os = "macos")]
-        {
-            // On macOS, configure DNS through networksetup
-            for dns in &vpn_dns_servers {
-                let _output = Command::new("sudo")
-                    .args([
-                        "networksetup", "-setdnsservers", 
-                        &self.interface_name, dns
-                    ])
-                    .output();
-            }
-            println!("   ✅ DNS configured for VPN");
+    /// Set the planner that reviews system changes before they are applied
+    pub fn set_change_planner(&mut self, planner: Option<Arc<dyn SystemChangePlanner>>) {
+        self.change_planner = planner;
+    }
+
+    /// Plan and check routes with `routes` instead of the platform's own route manager
+    pub fn set_route_manager(&mut self, routes: Option<Arc<dyn RouteManager>>) {
+        self.route_manager = routes;
+    }
+
+    fn route_manager(&self) -> Arc<dyn RouteManager> {
+        self.route_manager
+            .clone()
+            .unwrap_or_else(|| route_manager::for_routing(self.ops.platform(), &self.routing))
+    }
+
+    /// Set the route metric and coexistence policy used when planning routes
+    pub fn set_routing_config(&mut self, routing: RoutingConfig) {
+        self.routing = routing;
+    }
+
+    /// Apply manual MTU/MSS overrides, replacing the server-provided MTU
+    pub fn set_tunnel_options(&mut self, options: &TunnelOptionsConfig) {
+        if let Some(mtu) = options.mtu {
+            self.config.mtu = mtu;
         }
+        self.mss_clamp = options.effective_mss_clamp();
+        self.custom_routes = options.routes.clone();
+        self.strict = options.is_strict();
+        self.layer2 = options.layer2;
+    }
 
-        Ok(())
+    /// MAC address used on bridged hubs, instead of a random one
+    pub fn set_mac_address(&mut self, mac: MacAddr) {
+        self.mac = Some(mac);
     }
 
-    /// Restore original routing configuration
-    fn restore_original_routing(&self) -> Result<()> {
-        println!("🔄 Restoring original routing...");
+    /// Hand over the DHCP client that leased the tunnel address
+    ///
+    /// On bridged hubs the packet pump keeps renewing the lease, using the
+    /// client's MAC for the session.
+    pub fn set_dhcp_client(&mut self, dhcp: DhcpClient) {
+        self.dhcp = Some(dhcp);
+    }
 
-        if let Some(ref original_gateway) = self.original_route {
-            #[cfg(target_os = "linux")]
-            {
-                // Remove VPN default route
-                let _remove_output = Command::new("sudo")
-                    .args(["ip", "route", "del", "default", "dev", &self.interface_name])
-                    .output();
+    /// Set the services queried by (or disable) the public IP lookup
+    pub fn set_public_ip_config(&mut self, public_ip: PublicIpConfig) {
+        self.public_ip = public_ip;
+    }
 
-                // Restore original default route
-                let output = Command::new("sudo")
-                    .args([
-                        "ip", "route", "add", "default",
-                        "via", original_gateway
-                    ])
-                    .output();
+    /// Frame tunnel packets with the parameters negotiated during authentication
+    pub fn set_framing(&mut self, params: FramingParams) {
+        self.packet_framer = Some(packet_framing::SharedPacketFramer::new(params, self.config.remote_ip.into()));
+    }
 
-                match output {
-                    Ok(result) if result.status.success() => {
-                        println!("   ✅ Original routing restored");
-                    }
-                    Ok(_) => {
-                        println!("   ⚠️  Warning: Original routing restoration may have issues");
-                    }
-                    Err(e) => {
-                        println!("   ⚠️  Warning: Failed to restore original routing: {}", e);
-                    }
-                }
+    /// Framing parameters in use, if negotiated
+    pub fn framing(&self) -> Option<FramingParams> {
+        self.packet_framer.as_ref().map(packet_framing::SharedPacketFramer::params)
+    }
 
-                // Restore original DNS
-                let _restore_dns = Command::new("sudo")
-                    .args(["mv", "/etc/resolv.conf.vpn_backup", "/etc/resolv.conf"])
-                    .output();
-            }
+    /// Route the VPN server through `underlay` instead of the current default uplink
+    pub fn set_underlay(&mut self, underlay: Option<UnderlayBinding>) {
+        self.underlay = underlay;
+    }
 
-            #[cfg(target_os = "macos")]
-            {
-                // Remove VPN default route
-                let _remove_output = Command::new("sudo")
-                    .args(["route", "delete", "default", "-interface", &self.interface_name])
-                    .output();
+    /// Replace the platform operations, e.g. with a [`RecordingOps`] in tests
+    pub fn set_platform_ops(&mut self, ops: Arc<dyn PlatformOps>) {
+        self.ops = ops;
+    }
 
-                // Restore original default route
-                let output = Command::new("sudo")
-                    .args([
-                        "route", "add", "default", original_gateway
-                    ])
-                    .output();
+    /// Journal changes to disk before applying them, so
+    /// [`cleanup_stale_state`] can undo them if the process dies
+    pub fn set_change_journal(&mut self, journal: Option<ChangeJournal>) {
+        self.journal = journal;
+    }
 
-                match output {
-                    Ok(result) if result.status.success() => {
-                        println!("   ✅ Original routing restored");
-                    }
-                    Ok(_) => {
-                        println!("   ⚠️  Warning: Original routing restoration may have issues");
-                    }
-                    Err(e) => {
-                        println!("   ⚠️  Warning: Failed to restore original routing: {}", e);
-                    }
-                }
-            }
+    /// Effective interface MTU
+    pub fn mtu(&self) -> u16 {
+        self.config.mtu
+    }
+
+    /// Effective TCP MSS clamp, if any
+    pub fn mss_clamp(&self) -> Option<u16> {
+        self.mss_clamp
+    }
+
+    /// Tunnel MTU found by path MTU discovery, if `auto_mtu` probed one
+    pub fn discovered_mtu(&self) -> Option<u16> {
+        self.discovered_mtu
+    }
+
+    /// Probe the path to the server and fit the interface MTU to it
+    ///
+    /// Only ever lowers the MTU, and clamps the MSS to match unless a clamp
+    /// is configured, so TCP through the tunnel does not rely on ICMP making
+    /// it back either.
+    fn discover_mtu(&mut self) {
+        let Some(server) = self.vpn_server.map(|server| server.ip()) else {
+            log::warn!("auto_mtu: VPN server address unknown, keeping MTU {}", self.config.mtu);
+            return;
+        };
+        let Some(path_mtu) = self.ops.path_mtu(server) else {
+            log::info!("auto_mtu: path MTU to {} unavailable, keeping MTU {}", server, self.config.mtu);
+            return;
+        };
+        let mtu = pmtu::tunnel_mtu(path_mtu, server.is_ipv6());
+        log::info!("auto_mtu: path MTU to {} is {}, tunnel MTU {}", server, path_mtu, mtu);
+        self.discovered_mtu = Some(mtu);
+        if mtu < self.config.mtu {
+            self.config.mtu = mtu;
+            self.mss_clamp.get_or_insert(mtu.saturating_sub(TCP_IP_HEADER_LEN));
         }
+    }
 
-        Ok(())
+    /// Config-defined routes installed during the last tunnel establishment
+    pub fn installed_custom_routes(&self) -> &[SystemChange] {
+        &self.installed_custom_routes
+    }
+
+    /// Driver of the Windows adapter (Wintun or TAP), once the interface exists
+    pub fn windows_driver(&self) -> Option<WindowsDriver> {
+        self.windows_driver
+    }
+
+    /// The plan applied during the last tunnel establishment, including vetoes
+    pub fn applied_plan(&self) -> Option<&ChangePlan> {
+        self.applied_plan.as_ref()
+    }
+
+    /// Restore original routing configuration
+    fn restore_original_routing(&self) -> Result<()> {
+        log::info!("Restoring original routing");
+
+        // Policy routing leaves the default route alone
+        let replaced = self.route_manager().replaces_default_route();
+        if let Some(original_gateway) = self.original_route.as_ref().filter(|_| replaced) {
+            self.ops.restore_default_route(original_gateway, &self.interface_name)?;
+        }
+        self.ops.restore_dns(&self.interface_name)
     }
 
     /// Establish platform-specific tunnel (fallback method)
@@ -861,133 +1138,202 @@ impl TunnelManager {
     }
 
     /// Create TUN interface using the tun crate
+    ///
+    /// In TAP mode the device is a TAP on Linux and a feth pair on macOS.
+    #[cfg(not(target_os = "windows"))]
     fn create_tun_interface(&mut self) -> Result<()> {
-        println!("   🔧 Creating TUN interface with tun crate...");
+        #[cfg(target_os = "macos")]
+        if self.config.mode == TunnelMode::Tap {
+            let (name, reader, writer) = macos_tap::open(&self.config, self.mac)?;
+            log::info!(interface = name.as_str(); "TAP interface (feth pair) created");
+            self.interface_name = name.clone();
+            self.config.interface_name = name;
+            self.tun_reader = Some(reader);
+            self.tun_writer = Some(writer);
+            return Ok(());
+        }
+
+        #[cfg(target_os = "linux")]
+        if self.config.queues > 1 || self.config.offload {
+            return self.create_linux_queues();
+        }
 
         // Configure TUN device
         let mut config = tun::Configuration::default();
         config
             .name(&self.interface_name)
             .address(self.config.local_ip)
-            .destination(self.config.remote_ip)
-            .netmask((255, 255, 255, 0))  // /24 subnet as tuple
-            .mtu(1500)
+            .mtu(i32::from(self.config.mtu))
             .up();
+        if self.config.mode == TunnelMode::Tap {
+            // An Ethernet segment: the subnet is on-link, there is no point-to-point peer
+            config.layer(tun::Layer::L2).netmask(self.config.netmask);
+        } else {
+            config
+                .destination(self.config.remote_ip)
+                .netmask((255, 255, 255, 0));  // /24 subnet as tuple
+        }
 
         // Create the TUN device
         match tun::create(&config) {
             Ok(device) => {
-                self.tun_device = Some(device);
-                println!("   ✅ TUN interface '{}' created successfully", self.interface_name);
-                println!("      Local IP: {}", self.config.local_ip);
-                println!("      Remote IP: {}", self.config.remote_ip);
-                println!("      MTU: 1500");
-                
-                // Additional Linux-specific configuration to ensure interface is fully operational
                 #[cfg(target_os = "linux")]
-                {
-                    // Ensure interface is up and configured properly
-                    let _up_result = Command::new("sudo")
-                        .args(["ip", "link", "set", "dev", &self.interface_name, "up"])
-                        .output();
-                    
-                    // Verify interface status
-                    let status_output = Command::new("ip")
-                        .args(["addr", "show", &self.interface_name])
-                        .output();
-                    
-                    if let Ok(output) = status_output {
-                        let status = String::from_utf8_lossy(&output.stdout);
-                        println!("   📋 Interface status: {}", status.lines().next().unwrap_or("unknown"));
-                        
-                        // Check if interface shows as DOWN or NO-CARRIER
-                        if status.contains("NO-CARRIER") || status.contains("DOWN") {
-                            println!("   🔧 Interface needs additional configuration...");
-                            
-                            // Try to set point-to-point link
-                            let _p2p_result = Command::new("sudo")
-                                .args([
-                                    "ip", "link", "set", "dev", &self.interface_name,
-                                    "up", "pointopoint", &self.config.remote_ip.to_string()
-                                ])
-                                .output();
-                        }
-                    }
+                if let (TunnelMode::Tap, Some(mac)) = (self.config.mode, self.mac) {
+                    linux::set_mac_address(&self.interface_name, mac)?;
                 }
+                let (reader, writer) = tun_io::split(device);
+                self.tun_reader = Some(reader);
+                self.tun_writer = Some(writer);
+                log::info!(
+                    interface = self.interface_name.as_str(),
+                    local_ip:% = self.config.local_ip,
+                    remote_ip:% = self.config.remote_ip,
+                    mtu = self.config.mtu;
+                    "TUN interface created"
+                );
+
+                // Make sure the interface is fully operational
+                self.ops.bring_up_interface(&self.interface_name, self.config.remote_ip)?;
                 
                 Ok(())
             }
-            Err(e) => {
-                println!("   ❌ Failed to create TUN interface: {}", e);
-                Err(VpnError::Connection(format!("TUN interface creation failed: {}", e)))
-            }
+            Err(tun::Error::Io(e)) if e.kind() == std::io::ErrorKind::PermissionDenied => Err(VpnError::Permission(
+                format!("Creating TUN interface requires root or CAP_NET_ADMIN: {}", e),
+            )),
+            Err(e) => Err(VpnError::Connection(format!("TUN interface creation failed: {}", e))),
         }
     }
 
-    /// Start the packet routing loop for VPN traffic
-    fn start_packet_routing_loop(&mut self) -> Result<()> {
-        println!("🔄 Starting VPN packet routing loop...");
+    /// Create a device with several queues or offloads, which the tun crate cannot ask for
+    #[cfg(target_os = "linux")]
+    fn create_linux_queues(&mut self) -> Result<()> {
+        let offload = self.config.offload && self.config.mode == TunnelMode::Tun;
+        if self.config.offload && !offload {
+            log::warn!("Offloads need tunnel.mode = \"tun\"; creating the TAP device without them");
+        }
+        let queues = self.config.queues.clamp(1, crate::config::MAX_TUN_QUEUES);
+        let mut queues: Vec<_> = linux::open_queues(&self.interface_name, self.config.mode, queues, offload)?
+            .into_iter()
+            .map(|queue| tun_io::split_queue(queue, offload))
+            .collect();
+        linux::configure_interface(&self.interface_name, &self.config)?;
+        if let (TunnelMode::Tap, Some(mac)) = (self.config.mode, self.mac) {
+            linux::set_mac_address(&self.interface_name, mac)?;
+        }
+        let (reader, writer) = queues.remove(0);
+        self.tun_reader = Some(reader);
+        self.tun_writer = Some(writer);
+        log::info!(
+            interface = self.interface_name.as_str(),
+            local_ip:% = self.config.local_ip,
+            queues = queues.len() + 1,
+            offload;
+            "TUN interface created"
+        );
+        self.tun_queues = queues;
+
+        self.ops.bring_up_interface(&self.interface_name, self.config.remote_ip)
+    }
 
-        // Enable IP forwarding on the system
-        #[cfg(target_os = "linux")]
-        {
-            let forward_output = Command::new("sudo")
-                .args(["sysctl", "-w", "net.ipv4.ip_forward=1"])
-                .output();
-            
-            if let Ok(result) = forward_output {
-                if result.status.success() {
-                    println!("   ✅ Enabled IP forwarding");
-                } else {
-                    println!("   ⚠️ Warning: Failed to enable IP forwarding");
-                }
-            }
-            
-            // Set up iptables rules for NAT and forwarding
-            let nat_output = Command::new("sudo")
-                .args([
-                    "iptables", "-t", "nat", "-A", "POSTROUTING",
-                    "-o", &self.interface_name,
-                    "-j", "MASQUERADE"
-                ])
-                .output();
-                
-            if let Ok(result) = nat_output {
-                if result.status.success() {
-                    println!("   ✅ Set up NAT rules for VPN interface");
-                } else {
-                    println!("   ⚠️ Warning: Failed to set up NAT rules");
-                }
-            }
-            
-            // Allow forwarding for the VPN interface
-            let forward_rule = Command::new("sudo")
-                .args([
-                    "iptables", "-A", "FORWARD",
-                    "-i", &self.interface_name,
-                    "-j", "ACCEPT"
-                ])
-                .output();
-                
-            if let Ok(result) = forward_rule {
-                if result.status.success() {
-                    println!("   ✅ Set up forwarding rules for VPN interface");
-                } else {
-                    println!("   ⚠️ Warning: Failed to set up forwarding rules");
-                }
+    /// Create the tunnel adapter with Wintun or TAP-Windows
+    #[cfg(target_os = "windows")]
+    fn create_tun_interface(&mut self) -> Result<()> {
+        let (driver, reader, writer) = windows_tun::open(&self.config)?;
+        self.tun_reader = Some(reader);
+        self.tun_writer = Some(writer);
+        self.windows_driver = Some(driver);
+        log::info!(interface = self.interface_name.as_str(); "{:?} adapter ready", driver);
+        Ok(())
+    }
+
+    /// Start forwarding packets between the TUN device and the VPN session
+    ///
+    /// Hands the TUN halves to a [`PacketPump`] whose session tasks run on
+    /// `handle`; afterwards `read_from_tun`/`write_to_tun` fail. The pump is
+    /// stopped by [`teardown_tunnel`](Self::teardown_tunnel). Forwarding keeps
+    /// to the rate limits of `shaper`.
+    pub fn start_packet_routing_loop<S: PacketSink, R: PacketSource>(
+        &mut self,
+        handle: &tokio::runtime::Handle,
+        sink: S,
+        source: R,
+        traffic: Arc<PerformanceStats>,
+        shaper: Arc<RateLimiter>,
+    ) -> Result<()> {
+        if !self.is_established {
+            return Err(VpnError::Connection("Tunnel not established".to_string()));
+        }
+        let tun = {
+            let _runtime = handle.enter();
+            self.take_async_tun_io()?
+        };
+
+        // Bridged hubs carry Ethernet frames and expect the client to speak ARP/NDP,
+        // unless a TAP device leaves that to the host's own stack
+        let dhcp = self.dhcp.take();
+        let link = if self.config.mode == TunnelMode::Tap {
+            LinkLayer::Tap
+        } else if self.layer2 {
+            let stack = NeighborStack::new(NeighborConfig {
+                mac: dhcp.as_ref().map(DhcpClient::mac).or(self.mac).unwrap_or_else(l2::random_mac),
+                ipv4: self.config.local_ip,
+                netmask: self.config.netmask,
+                gateway: self.config.remote_ip,
+                ipv6: self.config.ipv6.clone(),
+            });
+            LinkLayer::Emulated(Box::new(match dhcp {
+                Some(dhcp) => stack.with_dhcp(dhcp),
+                None => stack,
+            }))
+        } else {
+            LinkLayer::Ip
+        };
+
+        let pump = PacketPump::spawn(handle, tun, sink, source, self.config.mtu, traffic, shaper, link)?;
+        self.packet_pump = Some(pump);
+        log::info!(interface = self.interface_name.as_str(); "Forwarding packets between TUN and the VPN session");
+        Ok(())
+    }
+
+    /// Whether packets are being forwarded between TUN and the session
+    pub fn is_forwarding(&self) -> bool {
+        self.packet_pump.as_ref().is_some_and(PacketPump::is_running)
+    }
+
+    /// Whether forwarding was started and has since stopped on its own
+    ///
+    /// Happens when the session side closes, e.g. the server reset the data
+    /// connection.
+    pub fn forwarding_stopped(&self) -> bool {
+        self.packet_pump.as_ref().is_some_and(|pump| !pump.is_running())
+    }
+
+    /// Send a session keepalive through the packet pump
+    ///
+    /// Returns `false` when no packets are being forwarded.
+    pub fn send_keepalive(&self) -> bool {
+        match self.packet_pump.as_ref().filter(|pump| pump.is_running()) {
+            Some(pump) => {
+                pump.send_keepalive();
+                true
             }
+            None => false,
         }
+    }
 
-        // TODO: Start actual packet processing loop
-        // This should run in a background task to:
-        // 1. Read packets from TUN interface
-        // 2. Encrypt and send to VPN server via the client connection
-        // 3. Receive encrypted packets from VPN server
-        // 4. Decrypt and write to TUN interface
+    /// Why the server ended the session, if forwarding stopped on its notice
+    pub fn disconnect_notice(&self) -> Option<DisconnectNotice> {
+        self.packet_pump.as_ref().and_then(PacketPump::disconnect_notice)
+    }
 
-        println!("   ✅ Packet routing loop prepared");
-        println!("   📝 Note: Full packet forwarding requires VPN client integration");
-        Ok(())
+    /// ARP/NDP counters of the virtual gateway neighbour, on bridged hubs
+    pub fn neighbor_stats(&self) -> Option<NeighborStats> {
+        self.packet_pump.as_ref().and_then(PacketPump::neighbor_stats)
+    }
+
+    /// DHCP lease of the tunnel address, on bridged hubs using DHCP
+    pub fn dhcp_lease(&self) -> Option<DhcpLease> {
+        self.packet_pump.as_ref().and_then(PacketPump::dhcp_lease)
     }
 
     /// Send packet through VPN tunnel
@@ -1010,33 +1356,75 @@ impl TunnelManager {
     }
 
     /// Write packet to TUN interface
-    pub fn write_to_tun(&mut self, packet: &[u8]) -> Result<()> {
-        if let Some(ref mut device) = self.tun_device {
-            device.write(packet)
-                .map_err(|e| VpnError::Connection(format!("Failed to write to TUN: {}", e)))?;
-        } else {
-            return Err(VpnError::Connection("No TUN device available".to_string()));
-        }
-        Ok(())
+    ///
+    /// Waits for the device without blocking the runtime; must be called
+    /// within a tokio runtime.
+    pub async fn write_to_tun(&mut self, packet: &[u8]) -> Result<()> {
+        let (_, writer) = self.async_tun_io()?;
+        let written = match writer.write_packet(packet).await {
+            Ok(()) => writer.flush().await,
+            failed => failed,
+        };
+        written.map_err(|e| VpnError::Connection(format!("Failed to write to TUN: {}", e)))
     }
 
-    /// Read packet from TUN interface  
-    pub fn read_from_tun(&mut self) -> Result<Vec<u8>> {
-        if let Some(ref mut device) = self.tun_device {
-            let mut buffer = vec![0u8; 1500]; // MTU size
-            let size = device.read(&mut buffer)
-                .map_err(|e| VpnError::Connection(format!("Failed to read from TUN: {}", e)))?;
-            buffer.truncate(size);
-            Ok(buffer)
-        } else {
-            Err(VpnError::Connection("No TUN device available".to_string()))
+    /// Read packet from TUN interface
+    ///
+    /// Waits for the device without blocking the runtime; must be called
+    /// within a tokio runtime.
+    pub async fn read_from_tun(&mut self) -> Result<Vec<u8>> {
+        let mut buffer = vec![0u8; usize::from(self.config.mtu)];
+        let (reader, _) = self.async_tun_io()?;
+        let size = reader.read_packet(&mut buffer)
+            .await
+            .map_err(|e| VpnError::Connection(format!("Failed to read from TUN: {}", e)))?;
+        buffer.truncate(size);
+        Ok(buffer)
+    }
+
+    /// Non-blocking TUN halves, converted from the blocking ones on first use
+    fn async_tun_io(&mut self) -> Result<&mut (AsyncTunReader, AsyncTunWriter)> {
+        let tun = self.take_async_tun_io()?;
+        Ok(self.async_tun.insert(tun))
+    }
+
+    /// Hand the TUN device halves to separate RX and TX threads
+    ///
+    /// Afterwards `read_from_tun`/`write_to_tun` fail; the interface is
+    /// closed once the returned halves are dropped. `None` once the halves
+    /// were made non-blocking. A multi-queue device keeps only its first
+    /// queue, so the kernel sends no packets to queues nobody reads.
+    pub fn take_tun_io(&mut self) -> Option<(TunReader, TunWriter)> {
+        #[cfg(target_os = "linux")]
+        self.tun_queues.clear();
+        Some((self.tun_reader.take()?, self.tun_writer.take()?))
+    }
+
+    /// Hand non-blocking TUN halves to async tasks
+    ///
+    /// Must be called within a tokio runtime. Afterwards
+    /// `read_from_tun`/`write_to_tun` fail.
+    pub fn take_async_tun_io(&mut self) -> Result<(AsyncTunReader, AsyncTunWriter)> {
+        if let Some(tun) = self.async_tun.take() {
+            return Ok(tun);
         }
+        #[cfg(target_os = "linux")]
+        let queues = std::mem::take(&mut self.tun_queues);
+        let tun = self
+            .take_tun_io()
+            .ok_or_else(|| VpnError::Connection("No TUN device available".to_string()))?;
+        #[cfg(target_os = "linux")]
+        if !queues.is_empty() {
+            return async_tun::split_queues(std::iter::once(tun).chain(queues).collect())
+                .map_err(|e| VpnError::Connection(format!("Failed to make TUN queues non-blocking: {}", e)));
+        }
+        async_tun::split(tun).map_err(|e| VpnError::Connection(format!("Failed to make TUN non-blocking: {}", e)))
     }
 
     #[cfg(target_os = "windows")]
     fn establish_windows_tunnel(&mut self) -> Result<()> {
         // On Windows, we need to use TAP-Windows adapter
-        println!("🪟 Setting up Windows TAP interface...");
+        log::info!("Setting up Windows TAP interface");
         
         // Check if TAP adapter is available
         let output = Command::new("netsh")
@@ -1049,12 +1437,19 @@ impl TunnelManager {
         // Look for TAP adapter or create virtual interface
         if interfaces.contains("TAP") {
             self.interface_name = "TAP-Windows".to_string();
-            println!("   Found existing TAP adapter");
+            log::info!("Found existing TAP adapter");
         } else {
             // Create a virtual interface entry (requires TAP-Windows driver)
             self.interface_name = "VPN_Interface".to_string();
-            println!("   Using virtual interface (install TAP-Windows for full functionality)");
+            log::warn!("Using virtual interface (install TAP-Windows for full functionality)");
         }
+
+        let _mtu_result = Command::new("netsh")
+            .args([
+                "interface", "ipv4", "set", "subinterface", &self.interface_name,
+                &format!("mtu={}", self.config.mtu), "store=active",
+            ])
+            .output();
         
         Ok(())
     }
@@ -1062,7 +1457,7 @@ impl TunnelManager {
     #[cfg(target_os = "macos")]
     fn establish_macos_tunnel(&mut self) -> Result<()> {
         // On macOS, we can use utun interfaces
-        println!("🍎 Setting up macOS utun interface...");
+        log::info!("Setting up macOS utun interface");
         
         // Try to create a utun interface
         for i in 0..10 {
@@ -1081,7 +1476,7 @@ impl TunnelManager {
                 _ => {
                     // Interface available, use it
                     self.interface_name = interface_name.clone();
-                    println!("   Using interface: {}", interface_name);
+                    log::info!(interface = interface_name.as_str(); "Using utun interface");
                     
                     // Configure the interface (requires admin privileges)
                     let config_result = Command::new("sudo")
@@ -1089,18 +1484,18 @@ impl TunnelManager {
                             "ifconfig", &interface_name,
                             &self.config.local_ip.to_string(),
                             &self.config.remote_ip.to_string(),
+                            "mtu", &self.config.mtu.to_string(),
                             "up"
                         ])
                         .output();
                         
                     match config_result {
                         Ok(output) if output.status.success() => {
-                            println!("   ✅ Interface configured with admin privileges");
+                            log::info!("Interface configured with admin privileges");
                             return Ok(());
                         },
                         _ => {
-                            println!("   ⚠️  Admin privileges required for full tunnel setup");
-                            println!("   ℹ️  Demo mode: tunnel interface created without system routing");
+                            log::warn!("Admin privileges required for full tunnel setup; continuing without system routing");
                             return Ok(());
                         }
                     }
@@ -1116,7 +1511,7 @@ impl TunnelManager {
     #[cfg(target_os = "linux")]
     fn establish_linux_tunnel(&mut self) -> Result<()> {
         // On Linux, we can use TUN interfaces
-        println!("🐧 Setting up Linux TUN interface...");
+        log::info!("Setting up Linux TUN interface");
         
         // Try to create a TUN interface
         let interface_name = "vpnse0";
@@ -1142,14 +1537,13 @@ impl TunnelManager {
                     .output();
                     
                 let _up_result = Command::new("sudo")
-                    .args(["ip", "link", "set", "dev", interface_name, "up"])
+                    .args(["ip", "link", "set", "dev", interface_name, "mtu", &self.config.mtu.to_string(), "up"])
                     .output();
                     
-                println!("   ✅ TUN interface created with admin privileges");
+                log::info!("TUN interface created with admin privileges");
             },
             _ => {
-                println!("   ⚠️  Admin privileges required for TUN interface creation");
-                println!("   ℹ️  Demo mode: virtual tunnel interface");
+                log::warn!("Admin privileges required for TUN interface creation; using a virtual tunnel interface");
                 self.interface_name = "tun_demo".to_string();
             }
         }
@@ -1158,9 +1552,8 @@ impl TunnelManager {
     }
 
     fn establish_demo_tunnel(&mut self) -> Result<()> {
-        println!("🔧 Setting up demo tunnel interface...");
         self.interface_name = "vpnse_demo".to_string();
-        println!("   ℹ️  Demo mode: tunnel simulation without system integration");
+        log::warn!("Demo mode: tunnel simulation without system integration");
         Ok(())
     }
 
@@ -1170,26 +1563,40 @@ impl TunnelManager {
             return Ok(());
         }
 
-        println!("🔽 Tearing down VPN tunnel...");
+        log::info!("Tearing down VPN tunnel");
+
+        self.remove_plan_routes();
+        self.remove_pushed_routes();
+        self.remove_custom_routes();
+        self.remove_mss_rules();
+        self.remove_app_routing();
         
         // Restore original routing before closing tunnel
-        if let Err(e) = self.restore_original_routing() {
-            println!("   ⚠️  Warning: Failed to restore original routing: {}", e);
+        if self.host_device {
+            // The host app configured (and will remove) its own interface
+        } else if let Err(e) = self.restore_original_routing() {
+            log::warn!("Failed to restore original routing: {}", e);
         }
         
-        // Close TUN device if it exists
-        if let Some(device) = self.tun_device.take() {
-            println!("   🔽 Closing TUN device: {}", self.interface_name);
-            drop(device); // TUN device will be automatically closed
+        if let Some(pump) = self.packet_pump.take() {
+            pump.stop();
+        }
+
+        // Close TUN device if it exists (halves handed out by take_tun_io close it when dropped)
+        if self.tun_reader.is_some() || self.tun_writer.is_some() || self.async_tun.is_some() {
+            log::debug!(interface = self.interface_name.as_str(); "Closing TUN device");
+            self.tun_reader = None;
+            self.tun_writer = None;
+            #[cfg(target_os = "linux")]
+            self.tun_queues.clear();
+            self.async_tun = None;
         }
         
         // Remove TUN interface if we created it
-        #[cfg(target_os = "linux")]
-        {
-            let _remove_result = Command::new("sudo")
-                .args(["ip", "link", "del", &self.interface_name])
-                .output();
+        if !self.host_device {
+            let _ = self.ops.delete_interface(&self.interface_name);
         }
+        self.host_device = false;
         
         // Close packet channels
         if let Some(tx) = self.packet_tx.take() {
@@ -1199,8 +1606,9 @@ impl TunnelManager {
             drop(rx);
         }
         
+        self.forget_journaled_changes();
         self.is_established = false;
-        println!("✅ VPN tunnel torn down successfully");
+        log::info!("VPN tunnel torn down");
         Ok(())
     }
 
@@ -1241,13 +1649,13 @@ impl TunnelManager {
         if octets[0] == 10 {
             // Log specific detected ranges for better debugging
             if octets[1] == 21 {
-                println!("   📝 Detected 10.21.*.* VPN network from DHCP assignment");
+                log::debug!("Detected 10.21.*.* VPN network from DHCP assignment");
                 return true;
             } else if octets[1] == 216 && octets[2] == 48 {
-                println!("   📝 Detected 10.216.48.* VPN network from DHCP assignment");
+                log::debug!("Detected 10.216.48.* VPN network from DHCP assignment");
                 return true;
             } else if octets[1] == 244 {
-                println!("   📝 Detected 10.244.*.* VPN network from DHCP assignment");
+                log::debug!("Detected 10.244.*.* VPN network from DHCP assignment");
                 return true;
             }
             
@@ -1266,91 +1674,17 @@ impl TunnelManager {
         false
     }
     
-    /// Get VPN server IP for routing preservation
-    /// 
-    /// This method returns the VPN server IP address to prevent routing loops
-    /// where VPN traffic tries to route through the VPN itself
-    pub fn get_vpn_server_ip(&self) -> Option<String> {
-        // First check if we have a known VPN server IP from environment variable
-        if let Ok(server_ip) = std::env::var("VPN_SERVER_IP") {
-            println!("   📌 Using VPN server IP from environment variable: {}", server_ip);
-            return Some(server_ip);
-        }
-        
-        // Check for the server IP from the connection we used to establish the tunnel
-        #[cfg(target_os = "linux")]
-        {
-            // First try with ss command which is more reliable than netstat
-            let output = Command::new("ss")
-                .args(["-tn", "state", "established"])
-                .output();
-                
-            if let Ok(result) = output {
-                let connections = String::from_utf8_lossy(&result.stdout);
-                
-                // Look for established connections to port 443 or 992 (common SSL-VPN ports)
-                for line in connections.lines() {
-                    if line.contains("ESTAB") && (line.contains(":443") || line.contains(":992")) {
-                        if let Some(peer_addr_start) = line.find("peer=") {
-                            let peer_addr_part = &line[peer_addr_start + 5..];
-                            if let Some(addr_end) = peer_addr_part.find(' ') {
-                                let addr = &peer_addr_part[0..addr_end];
-                                if let Some(ip) = addr.split(':').next() {
-                                    println!("   📌 Detected VPN server IP from active connection: {}", ip);
-                                    return Some(ip.to_string());
-                                }
-                            }
-                        }
-                        
-                        // Alternative parsing for ss output format
-                        let parts: Vec<&str> = line.split_whitespace().collect();
-                        for part in parts.iter() {
-                            if part.contains(":443") || part.contains(":992") {
-                                if let Some(ip) = part.split(':').next() {
-                                    // Verify this looks like an IP address
-                                    if ip.contains('.') && !ip.starts_with("127.") {
-                                        println!("   📌 Detected VPN server IP from active connection: {}", ip);
-                                        return Some(ip.to_string());
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            
-            // Fall back to netstat if ss didn't work
-            let output = Command::new("netstat")
-                .args(["-tn"])
-                .output();
-                
-            if let Ok(result) = output {
-                let connections = String::from_utf8_lossy(&result.stdout);
-                
-                // Look for established connections to port 443 or 992 (common SSL-VPN ports)
-                for line in connections.lines() {
-                    if line.contains("ESTABLISHED") && (line.contains(":443") || line.contains(":992")) {
-                        // Extract server IP from the line (format: IP:port)
-                        // Convert split_whitespace iterator to collect::<Vec<_>>() so we can use get()
-                        let parts: Vec<&str> = line.split_whitespace().collect();
-                        if let Some(addr) = parts.get(4) {
-                            if let Some(ip) = addr.split(':').next() {
-                                println!("   📌 Detected VPN server IP from active connection: {}", ip);
-                                return Some(ip.to_string());
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        
-        // Finally, fall back to the default server IP if all else fails
-        println!("   📌 Using default VPN server IP: 62.24.65.211");
-        Some("62.24.65.211".to_string())
+    /// Endpoint of the VPN server the session is connected to, kept reachable outside the tunnel
+    pub fn vpn_server(&self) -> Option<SocketAddr> {
+        self.vpn_server
     }
 
     /// Get the current public IP
     pub async fn get_current_public_ip(&self) -> Result<String> {
+        if !self.public_ip.enabled {
+            return Err(VpnError::Config("Public IP lookup is disabled".into()));
+        }
+
         // Use the public-ip crate for better reliability
         match public_ip::addr().await {
             Some(ip) => Ok(ip.to_string()),
@@ -1362,33 +1696,39 @@ impl TunnelManager {
     }
 
     /// Fallback method for getting public IP using HTTP requests
+    ///
+    /// All configured services are queried at once; the first valid answer
+    /// wins and the remaining requests are dropped.
     async fn get_public_ip_fallback(&self) -> Result<String> {
-        let services = [
-            "https://api.ipify.org",
-            "https://icanhazip.com",
-            "https://ipecho.net/plain",
-            "https://checkip.amazonaws.com",
-        ];
+        // select_ok panics on an empty set
+        if self.public_ip.services.is_empty() {
+            return Err(VpnError::Config("No public IP services configured".into()));
+        }
 
         let client = reqwest::Client::builder()
-            .timeout(std::time::Duration::from_secs(5))
+            .timeout(std::time::Duration::from_secs(u64::from(self.public_ip.timeout_secs)))
             .build()
             .map_err(|e| VpnError::Network(format!("Failed to create HTTP client: {}", e)))?;
 
-        for service in &services {
-            if let Ok(response) = client.get(*service).send().await {
-                if let Ok(ip_text) = response.text().await {
-                    let ip = ip_text.trim().to_string();
-                    if !ip.is_empty() && self.is_valid_ip(&ip) {
-                        return Ok(ip);
-                    }
+        let lookups = self.public_ip.services.iter().map(|service| {
+            let request = client.get(service.as_str());
+            Box::pin(async move {
+                let response = request.send().await.map_err(|e| e.to_string())?;
+                let ip = response.text().await.map_err(|e| e.to_string())?.trim().to_string();
+                if self.is_valid_ip(&ip) {
+                    Ok(ip)
+                } else {
+                    Err(format!("{} returned no IP address", service))
                 }
-            }
-        }
-
-        Err(VpnError::Connection(
-            "Failed to get public IP from any service".into(),
-        ))
+            })
+        });
+
+        futures::future::select_ok(lookups)
+            .await
+            .map(|(ip, _pending)| ip)
+            .map_err(|e| {
+                VpnError::Connection(format!("Failed to get public IP from any service: {}", e))
+            })
     }
 
     /// Validate if a string is a valid IP address
@@ -1429,55 +1769,19 @@ impl TunnelManager {
 
     /// Store the original default route
     fn store_original_route(&mut self) -> Result<()> {
-        #[cfg(target_os = "macos")]
-        {
-            let output = Command::new("route")
-                .args(["-n", "get", "default"])
-                .output()
-                .map_err(|e| VpnError::Connection(format!("Failed to get default route: {e}")))?;
-
-            if output.status.success() {
-                let route_info = String::from_utf8_lossy(&output.stdout);
-                for line in route_info.lines() {
-                    if line.trim().starts_with("gateway:") {
-                        let gateway = line
-                            .split(':')
-                            .nth(1)
-                            .ok_or_else(|| {
-                                VpnError::Connection("Invalid route format".to_string())
-                            })?
-                            .trim();
-                        self.original_route = Some(gateway.to_string());
-                        break;
-                    }
-                }
-            }
-        }
-
-        #[cfg(target_os = "linux")]
-        {
-            let output = Command::new("ip")
-                .args(["route", "show", "default"])
-                .output()
-                .map_err(|e| VpnError::Connection(format!("Failed to get default route: {e}")))?;
-
-            if output.status.success() {
-                let route_info = String::from_utf8_lossy(&output.stdout);
-                if let Some(via_pos) = route_info.find("via ") {
-                    let after_via = &route_info[via_pos + 4..];
-                    if let Some(space_pos) = after_via.find(' ') {
-                        let gateway = &after_via[..space_pos];
-                        self.original_route = Some(gateway.to_string());
-                    }
-                }
-            }
-        }
-
-        println!("Original route stored: {:?}", self.original_route);
+        self.original_route = self.ops.default_gateway();
+        log::debug!("Original default gateway: {:?}", self.original_route);
         Ok(())
     }
+}
 
-    // Using the public get_vpn_server_ip method defined above
+/// iptables arguments rewriting the MSS of SYNs leaving through `interface`
+fn mss_clamp_args(interface: &str, mss: u16) -> Vec<String> {
+    ["-o", interface, "-p", "tcp", "--tcp-flags", "SYN,RST", "SYN", "-j", "TCPMSS", "--set-mss"]
+        .iter()
+        .map(|arg| arg.to_string())
+        .chain(std::iter::once(mss.to_string()))
+        .collect()
 }
 
 impl Drop for TunnelManager {
@@ -1489,7 +1793,7 @@ impl Drop for TunnelManager {
 // Public API functions
 pub fn create_tunnel_interface() -> Result<()> {
     let config = TunnelConfig::default();
-    let mut manager = TunnelManager::new(config);
+    let mut manager = TunnelManager::new(config, None);
     manager.establish_tunnel()?;
 
     // Store the manager globally
@@ -1533,3 +1837,669 @@ pub async fn get_tunnel_public_ip() -> Result<String> {
         Err(VpnError::Connection("No tunnel established".to_string()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn dns_change() -> PlatformOp {
+        PlatformOp::Apply(SystemChange::SetDns {
+            interface: "vpnse0".to_string(),
+            servers: vec![
+                IpAddr::V4(GATEWAY),
+                IpAddr::from([1, 1, 1, 1]),
+                IpAddr::from([8, 8, 8, 8]),
+                IpAddr::from([8, 8, 4, 4]),
+                IpAddr::from([1, 0, 0, 1]),
+            ],
+        })
+    }
+
+    fn route(destination: &str, gateway: Option<&str>, interface: &str, metric: Option<u32>) -> PlatformOp {
+        PlatformOp::Apply(SystemChange::AddRoute {
+            destination: destination.to_string(),
+            gateway: gateway.map(str::to_string),
+            interface: Some(interface.to_string()),
+            metric,
+        })
+    }
+
+    fn firewall_rule(table: Option<&str>, chain: &str, args: &[&str]) -> PlatformOp {
+        PlatformOp::Apply(SystemChange::AddFirewallRule {
+            table: table.map(str::to_string),
+            chain: chain.to_string(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        })
+    }
+
+    fn vpn_server(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 443))
+    }
+
+    fn sysctl(key: &str, value: &str) -> PlatformOp {
+        PlatformOp::Apply(SystemChange::SetSysctl { key: key.to_string(), value: value.to_string() })
+    }
+
+    /// Configure routing and tear it down again against `ops`
+    fn run_session(mut manager: TunnelManager, ops: &Arc<RecordingOps>) -> Vec<PlatformOp> {
+        manager.set_platform_ops(ops.clone());
+        manager.store_original_route().unwrap();
+        manager.configure_vpn_routing().unwrap();
+        manager.is_established = true;
+        manager.teardown_tunnel().unwrap();
+        ops.recorded()
+    }
+
+    #[test]
+    fn test_failed_change_rolls_back() {
+        let forward = SystemChange::AddFirewallRule {
+            table: None,
+            chain: "FORWARD".to_string(),
+            args: ["-i", "vpnse0", "-j", "ACCEPT"].iter().map(|arg| arg.to_string()).collect(),
+        };
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Linux)
+                .with_default_gateway("192.168.1.1")
+                .with_underlay_route("192.168.1.1", "wlan0")
+                .failing_on(forward),
+        );
+        let mut manager = TunnelManager::new(TunnelConfig::default(), vpn_server("203.0.113.10"));
+        manager.set_tunnel_options(&TunnelOptionsConfig { strict: Some(true), ..Default::default() });
+        manager.set_platform_ops(ops.clone());
+        manager.store_original_route().unwrap();
+
+        assert!(matches!(manager.configure_vpn_routing(), Err(VpnError::Platform(_))));
+        assert!(manager.applied_plan().is_none());
+        let recorded = ops.recorded();
+        let rollback = recorded.iter().position(|op| !matches!(op, PlatformOp::Apply(_))).unwrap();
+        assert_eq!(
+            recorded[rollback..],
+            [
+                PlatformOp::DeleteFirewallRule {
+                    table: Some("nat".to_string()),
+                    chain: "POSTROUTING".to_string(),
+                    args: ["-o", "vpnse0", "-j", "MASQUERADE"].iter().map(|arg| arg.to_string()).collect(),
+                },
+                PlatformOp::DeleteRoute { destination: "128.0.0.0/1".to_string(), interface: Some("vpnse0".to_string()) },
+                PlatformOp::DeleteRoute { destination: "0.0.0.0/1".to_string(), interface: Some("vpnse0".to_string()) },
+                PlatformOp::RestoreDefaultRoute { gateway: "192.168.1.1".to_string(), interface: "vpnse0".to_string() },
+                PlatformOp::DeleteRoute { destination: "203.0.113.10/32".to_string(), interface: Some("wlan0".to_string()) },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_route_check_rolls_back() {
+        // The server would be routed into the tunnel that carries it
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Windows)
+                .with_underlay_route("192.168.1.1", "Wi-Fi")
+                .with_route_lookup("203.0.113.10", "vpnse0"),
+        );
+        let mut manager = TunnelManager::new(TunnelConfig::default(), vpn_server("203.0.113.10"));
+        manager.set_platform_ops(ops.clone());
+        manager.store_original_route().unwrap();
+
+        assert!(matches!(manager.configure_vpn_routing(), Err(VpnError::Routing(_))));
+        assert!(manager.applied_plan().is_none());
+        let recorded = ops.recorded();
+        let rollback = recorded.iter().position(|op| !matches!(op, PlatformOp::Apply(_))).unwrap();
+        let delete = |destination: &str, interface: &str| PlatformOp::DeleteRoute {
+            destination: destination.to_string(),
+            interface: Some(interface.to_string()),
+        };
+        assert_eq!(
+            recorded[rollback..],
+            [
+                PlatformOp::RestoreDns { interface: "vpnse0".to_string() },
+                delete("128.0.0.0/1", "vpnse0"),
+                delete("0.0.0.0/1", "vpnse0"),
+                delete("203.0.113.10/32", "Wi-Fi"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changes_are_journaled_until_teardown() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = ChangeJournal::new(dir.path());
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Linux)
+                .with_default_gateway("192.168.1.1")
+                .with_underlay_route("192.168.1.1", "wlan0"),
+        );
+        let mut manager = TunnelManager::new(TunnelConfig::default(), vpn_server("203.0.113.10"));
+        manager.set_platform_ops(ops.clone());
+        manager.set_change_journal(Some(journal.clone()));
+        manager.store_original_route().unwrap();
+        manager.configure_vpn_routing().unwrap();
+
+        let pending = journal.pending(JournalScope::Tunnel);
+        assert_eq!(
+            pending[..2],
+            [
+                UndoAction::DeleteRoute { destination: "203.0.113.10/32".to_string(), interface: Some("wlan0".to_string()) },
+                UndoAction::RestoreDefaultRoute { gateway: "192.168.1.1".to_string(), interface: "vpnse0".to_string() },
+            ]
+        );
+        assert_eq!(pending.last(), Some(&UndoAction::RestoreDns { interface: "vpnse0".to_string() }));
+        assert!(journal.path().exists());
+
+        manager.is_established = true;
+        manager.teardown_tunnel().unwrap();
+        assert!(journal.pending(JournalScope::Tunnel).is_empty());
+        assert!(!journal.path().exists());
+    }
+
+    #[test]
+    fn test_linux_operation_sequence() {
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Linux)
+                .with_default_gateway("192.168.1.1")
+                .with_underlay_route("192.168.1.1", "wlan0"),
+        );
+        let mut manager = TunnelManager::new(TunnelConfig::default(), vpn_server("203.0.113.10"));
+        manager.set_tunnel_options(&TunnelOptionsConfig { mtu: Some(1400), ..Default::default() });
+
+        let mss_args = mss_clamp_args("vpnse0", 1360);
+        let mss_args: Vec<&str> = mss_args.iter().map(String::as_str).collect();
+        let metric = Some(50);
+
+        assert_eq!(
+            run_session(manager, &ops),
+            vec![
+                route("203.0.113.10/32", Some("192.168.1.1"), "wlan0", None),
+                PlatformOp::Apply(SystemChange::SetDefaultRoute {
+                    gateway: Some("10.0.0.1".to_string()),
+                    interface: "vpnse0".to_string(),
+                    metric,
+                }),
+                route("0.0.0.0/1", Some("10.0.0.1"), "vpnse0", metric),
+                route("128.0.0.0/1", Some("10.0.0.1"), "vpnse0", metric),
+                sysctl("net.ipv4.conf.all.rp_filter", "0"),
+                sysctl("net.ipv4.conf.vpnse0.rp_filter", "0"),
+                sysctl("net.ipv4.ip_forward", "1"),
+                PlatformOp::Apply(SystemChange::FlushFirewallTable { table: "nat".to_string() }),
+                firewall_rule(Some("nat"), "POSTROUTING", &["-o", "vpnse0", "-j", "MASQUERADE"]),
+                firewall_rule(None, "FORWARD", &["-i", "vpnse0", "-j", "ACCEPT"]),
+                firewall_rule(Some("mangle"), "OUTPUT", &mss_args),
+                firewall_rule(Some("mangle"), "FORWARD", &mss_args),
+                dns_change(),
+                // Teardown
+                PlatformOp::DeleteFirewallRule {
+                    table: Some("mangle".to_string()),
+                    chain: "OUTPUT".to_string(),
+                    args: mss_args.iter().map(|arg| arg.to_string()).collect(),
+                },
+                PlatformOp::DeleteFirewallRule {
+                    table: Some("mangle".to_string()),
+                    chain: "FORWARD".to_string(),
+                    args: mss_args.iter().map(|arg| arg.to_string()).collect(),
+                },
+                PlatformOp::RestoreDefaultRoute {
+                    gateway: "192.168.1.1".to_string(),
+                    interface: "vpnse0".to_string(),
+                },
+                PlatformOp::RestoreDns { interface: "vpnse0".to_string() },
+                PlatformOp::DeleteInterface { name: "vpnse0".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_policy_routing_keeps_default_route() {
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Linux)
+                .with_default_gateway("192.168.1.1")
+                .with_underlay_route("192.168.1.1", "wlan0"),
+        );
+        let mut manager = TunnelManager::new(TunnelConfig::default(), vpn_server("203.0.113.10"));
+        manager.set_routing_config(RoutingConfig { mode: RouteMode::Policy, ..RoutingConfig::default() });
+        let rules = SystemChange::AddPolicyRules { fwmark: 51820, table: 51820, priority: 32764 };
+
+        let recorded = run_session(manager, &ops);
+        assert_eq!(
+            recorded[..3],
+            [
+                PlatformOp::Apply(SystemChange::AddTableRoute {
+                    destination: "default".to_string(),
+                    gateway: Some("10.0.0.1".to_string()),
+                    interface: "vpnse0".to_string(),
+                    table: 51820,
+                }),
+                PlatformOp::Apply(rules),
+                sysctl("net.ipv4.conf.all.rp_filter", "0"),
+            ]
+        );
+        assert!(recorded.contains(&sysctl("net.ipv4.conf.all.src_valid_mark", "1")));
+        // No server bypass, and the untouched default route needs no restoring
+        assert!(!recorded.iter().any(|op| matches!(
+            op,
+            PlatformOp::Apply(SystemChange::AddRoute { .. } | SystemChange::SetDefaultRoute { .. })
+                | PlatformOp::RestoreDefaultRoute { .. }
+        )));
+        assert!(recorded.contains(&PlatformOp::DeletePolicyRules { fwmark: 51820, table: 51820, priority: 32764 }));
+    }
+
+    #[test]
+    fn test_host_device_leaves_system_alone() {
+        let ops = Arc::new(RecordingOps::new(Platform::Linux).with_default_gateway("192.168.1.1"));
+        let mut manager = TunnelManager::new(TunnelConfig::default(), None);
+        manager.set_platform_ops(ops.clone());
+        let (_flow, reader, writer) = host_device::packet_flow();
+        manager.attach_host_device(reader, writer).unwrap();
+        assert!(manager.is_established());
+
+        manager.set_dns_servers(vec![IpAddr::from([10, 0, 0, 53])]).unwrap();
+        assert_eq!(manager.host_settings().dns_servers, vec![IpAddr::from([10, 0, 0, 53])]);
+        manager.teardown_tunnel().unwrap();
+        assert_eq!(ops.recorded(), Vec::new());
+
+        let mut tap = TunnelManager::new(TunnelConfig { mode: TunnelMode::Tap, ..Default::default() }, None);
+        let (_flow, reader, writer) = host_device::packet_flow();
+        assert!(tap.attach_host_device(reader, writer).is_err());
+    }
+
+    #[test]
+    fn test_auto_mtu_lowers_mtu_and_clamps_mss() {
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Linux)
+                .with_default_gateway("192.168.1.1")
+                .with_path_mtu(1400),
+        );
+        let config = TunnelConfig { auto_mtu: true, ..Default::default() };
+        let mut manager = TunnelManager::new(config.clone(), vpn_server("203.0.113.10"));
+        manager.set_platform_ops(ops.clone());
+        manager.discover_mtu();
+
+        assert_eq!(manager.discovered_mtu(), Some(1311));
+        assert_eq!(manager.mtu(), 1311);
+        assert_eq!(manager.mss_clamp(), Some(1271));
+        let mss_args = mss_clamp_args("vpnse0", 1271);
+        let mss_args: Vec<&str> = mss_args.iter().map(String::as_str).collect();
+        assert!(run_session(manager, &ops).contains(&firewall_rule(Some("mangle"), "OUTPUT", &mss_args)));
+
+        // A configured MTU below the path's is kept, as is an explicit clamp
+        let mut manager = TunnelManager::new(config, vpn_server("203.0.113.10"));
+        manager.set_tunnel_options(&TunnelOptionsConfig { mtu: Some(1280), mss_clamp: Some(1200), ..Default::default() });
+        manager.set_platform_ops(ops);
+        manager.discover_mtu();
+        assert_eq!(manager.discovered_mtu(), Some(1311));
+        assert_eq!((manager.mtu(), manager.mss_clamp()), (1280, Some(1200)));
+    }
+
+    #[test]
+    fn test_underlay_pins_server_route() {
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Linux)
+                .with_default_gateway("192.168.1.1")
+                .with_underlay_route("192.168.1.1", "wlan0"),
+        );
+        let mut manager = TunnelManager::new(TunnelConfig::default(), vpn_server("203.0.113.10"));
+        manager.set_underlay(Some(UnderlayBinding {
+            interface: "wwan0".to_string(),
+            address: Ipv4Addr::new(100, 64, 3, 7),
+            gateway: Some(Ipv4Addr::new(100, 64, 3, 1)),
+        }));
+
+        let recorded = run_session(manager, &ops);
+        let server_routes: Vec<&PlatformOp> = recorded
+            .iter()
+            .filter(|op| matches!(op, PlatformOp::Apply(SystemChange::AddRoute { destination, .. }) if destination == "203.0.113.10/32"))
+            .collect();
+        assert_eq!(server_routes, vec![&route("203.0.113.10/32", Some("100.64.3.1"), "wwan0", None)]);
+    }
+
+    #[test]
+    fn test_app_split_routing() {
+        let linux = || {
+            Arc::new(
+                RecordingOps::new(Platform::Linux)
+                    .with_default_gateway("192.168.1.1")
+                    .with_underlay_route("192.168.1.1", "wlan0"),
+            )
+        };
+        let mark = ["-m", "mark", "--mark", "0x7670"];
+        let table_route = |gateway: &str, interface: &str| {
+            PlatformOp::Apply(SystemChange::AddTableRoute {
+                destination: "default".to_string(),
+                gateway: Some(gateway.to_string()),
+                interface: interface.to_string(),
+                table: linux::APP_ROUTING_TABLE,
+            })
+        };
+        let rule = SystemChange::AddRoutingRule { fwmark: 0x7670, table: 7670, priority: 7670 };
+
+        // Only the browser uses the tunnel; the host keeps its default route
+        let ops = linux();
+        let mut config = TunnelConfig::default();
+        config.only_cgroups = vec!["user.slice/app.slice/firefox.scope".to_string()];
+        let recorded = run_session(TunnelManager::new(config, vpn_server("203.0.113.10")), &ops);
+        assert!(!recorded.iter().any(|op| matches!(op, PlatformOp::Apply(SystemChange::SetDefaultRoute { .. }))));
+        assert!(recorded.contains(&firewall_rule(
+            Some("mangle"),
+            "OUTPUT",
+            &["-m", "cgroup", "--path", "user.slice/app.slice/firefox.scope", "-j", "MARK", "--set-mark", "0x7670"],
+        )));
+        assert!(recorded.contains(&firewall_rule(Some("nat"), "POSTROUTING", &[&["-o", "vpnse0"], &mark[..], &["-j", "MASQUERADE"]].concat())));
+        assert!(recorded.contains(&table_route("10.0.0.1", "vpnse0")));
+        assert!(recorded.contains(&PlatformOp::Apply(rule.clone())));
+
+        // Teardown removes the rule first and the marking last
+        let teardown: Vec<&PlatformOp> = recorded
+            .iter()
+            .filter(|op| {
+                matches!(op, PlatformOp::DeleteRoutingRule { .. } | PlatformOp::DeleteTableRoute { .. })
+                    || matches!(op, PlatformOp::DeleteFirewallRule { args, .. } if args.iter().any(|arg| arg == "0x7670"))
+            })
+            .collect();
+        assert_eq!(teardown.len(), 6);
+        assert_eq!(teardown[0], &PlatformOp::DeleteRoutingRule { fwmark: 0x7670, table: 7670, priority: 7670 });
+        assert_eq!(teardown[1], &PlatformOp::DeleteTableRoute { destination: "default".to_string(), table: 7670 });
+        assert!(matches!(teardown[5], PlatformOp::DeleteFirewallRule { args, .. } if args[1] == "cgroup"));
+
+        // Bypassing cgroups leave through the uplink while everything else uses the tunnel
+        let ops = linux();
+        let mut config = TunnelConfig::default();
+        config.bypass_cgroups = vec!["system.slice/backup.service".to_string()];
+        let recorded = run_session(TunnelManager::new(config, vpn_server("203.0.113.10")), &ops);
+        assert!(recorded.iter().any(|op| matches!(op, PlatformOp::Apply(SystemChange::SetDefaultRoute { .. }))));
+        assert!(recorded.contains(&table_route("192.168.1.1", "wlan0")));
+
+        // Not available elsewhere, and never half-configured
+        let mut config = TunnelConfig::default();
+        config.bypass_cgroups = vec!["system.slice/backup.service".to_string()];
+        let mut manager = TunnelManager::new(config.clone(), vpn_server("203.0.113.10"));
+        manager.set_platform_ops(Arc::new(RecordingOps::new(Platform::MacOs)));
+        assert!(matches!(manager.build_change_plan(), Err(VpnError::CapabilityUnavailable(_))));
+        config.only_cgroups = vec!["user.slice".to_string()];
+        let mut manager = TunnelManager::new(config, vpn_server("203.0.113.10"));
+        manager.set_platform_ops(linux());
+        assert!(matches!(manager.build_change_plan(), Err(VpnError::Config(_))));
+    }
+
+    #[test]
+    fn test_dual_stack_plan() {
+        // IPv6-only uplink: the server is reached over IPv6 and must stay outside the tunnel
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Linux)
+                .with_underlay_route_v6("fe80::1", "wlan0"),
+        );
+        let mut config = TunnelConfig::default();
+        config.ipv6 = Some(Ipv6Lease {
+            address: "fd00:21::5".parse().unwrap(),
+            prefix_len: 64,
+            gateway: Some("fd00:21::1".parse().unwrap()),
+            dns_servers: vec!["fd00:21::53".parse().unwrap()],
+        });
+        let mut manager = TunnelManager::new(config.clone(), vpn_server("2001:db8:100::10"));
+        manager.set_platform_ops(ops.clone());
+        let plan = manager.build_change_plan().unwrap();
+        let changes: Vec<PlatformOp> = plan.approved().cloned().map(PlatformOp::Apply).collect();
+        let metric = Some(50);
+
+        // No IPv4 host route for an IPv6 server
+        assert!(!changes.iter().any(|op| matches!(op, PlatformOp::Apply(SystemChange::AddRoute { destination, .. }) if destination.ends_with("/32"))));
+        let dual_stack = [
+            sysctl("net.ipv6.conf.vpnse0.disable_ipv6", "0"),
+            PlatformOp::Apply(SystemChange::AddAddress {
+                interface: "vpnse0".to_string(),
+                address: "fd00:21::5".parse().unwrap(),
+                prefix_len: 64,
+            }),
+            route("2001:db8:100::10/128", Some("fe80::1"), "wlan0", None),
+            route("::/1", Some("fd00:21::1"), "vpnse0", metric),
+            route("8000::/1", Some("fd00:21::1"), "vpnse0", metric),
+        ];
+        assert!(changes.windows(dual_stack.len()).any(|window| window == dual_stack));
+        assert!(changes.contains(&PlatformOp::Apply(SystemChange::SetDns {
+            interface: "vpnse0".to_string(),
+            servers: vec![
+                IpAddr::V4(GATEWAY),
+                "fd00:21::53".parse().unwrap(),
+                IpAddr::from([1, 1, 1, 1]),
+                IpAddr::from([8, 8, 8, 8]),
+                IpAddr::from([8, 8, 4, 4]),
+                IpAddr::from([1, 0, 0, 1]),
+            ],
+        })));
+
+        // Split mode only routes the assigned IPv6 prefix
+        let ops = Arc::new(RecordingOps::new(Platform::Linux).with_vpn_interfaces(&["wg0"]));
+        let mut manager = TunnelManager::new(config, vpn_server("2001:db8:100::10"));
+        manager.set_platform_ops(ops);
+        manager.set_routing_config(RoutingConfig {
+            coexistence_policy: CoexistencePolicy::CoexistSplit,
+            ..RoutingConfig::default()
+        });
+        let routes: Vec<String> = manager
+            .build_change_plan()
+            .unwrap()
+            .approved()
+            .filter_map(|change| match change {
+                SystemChange::AddRoute { destination, .. } => Some(destination.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(routes, vec!["10.0.0.0/24", "fd00:21::/64"]);
+    }
+
+    #[test]
+    fn test_macos_operation_sequence() {
+        let ops = Arc::new(RecordingOps::new(Platform::MacOs).with_default_gateway("192.168.1.1"));
+        let mut config = TunnelConfig::default();
+        config.pushed_routes = vec![PushedRoute::new(Ipv4Addr::new(172, 16, 0, 0), 16, None).unwrap()];
+
+        assert_eq!(
+            run_session(TunnelManager::new(config, None), &ops),
+            vec![
+                PlatformOp::Apply(SystemChange::AddRoute {
+                    destination: "10.0.0.1/32".to_string(),
+                    gateway: Some("192.168.1.1".to_string()),
+                    interface: None,
+                    metric: None,
+                }),
+                PlatformOp::Apply(SystemChange::SetDefaultRoute {
+                    gateway: None,
+                    interface: "vpnse0".to_string(),
+                    metric: Some(50),
+                }),
+                route("172.16.0.0/16", Some("10.0.0.1"), "vpnse0", Some(50)),
+                dns_change(),
+                // Teardown
+                PlatformOp::DeleteRoute {
+                    destination: "172.16.0.0/16".to_string(),
+                    interface: Some("vpnse0".to_string()),
+                },
+                PlatformOp::RestoreDefaultRoute {
+                    gateway: "192.168.1.1".to_string(),
+                    interface: "vpnse0".to_string(),
+                },
+                PlatformOp::RestoreDns { interface: "vpnse0".to_string() },
+                PlatformOp::DeleteInterface { name: "vpnse0".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_custom_routes_sequence() {
+        let ops = Arc::new(
+            RecordingOps::new(Platform::MacOs)
+                .with_default_gateway("192.168.1.1")
+                .with_underlay_route("192.168.1.1", "en0"),
+        );
+        let options: TunnelOptionsConfig = toml::from_str(
+            r#"
+[[routes]]
+dest = "10.8.0.0/16"
+via = "tunnel"
+metric = 20
+
+[[routes]]
+dest = "192.168.50.0/24"
+via = "underlay"
+
+# No IPv6 lease, so there is nothing to route this through
+[[routes]]
+dest = "2001:db8::/32"
+via = "tunnel"
+"#,
+        )
+        .unwrap();
+        // One pushed route duplicates a configured one, the other is nested in one
+        let mut config = TunnelConfig::default();
+        config.pushed_routes = vec![
+            PushedRoute::new(Ipv4Addr::new(192, 168, 50, 0), 24, None).unwrap(),
+            PushedRoute::new(Ipv4Addr::new(10, 8, 5, 0), 24, None).unwrap(),
+        ];
+        let mut manager = TunnelManager::new(config, None);
+        manager.set_tunnel_options(&options);
+
+        assert_eq!(
+            run_session(manager, &ops),
+            vec![
+                PlatformOp::Apply(SystemChange::AddRoute {
+                    destination: "10.0.0.1/32".to_string(),
+                    gateway: Some("192.168.1.1".to_string()),
+                    interface: None,
+                    metric: None,
+                }),
+                PlatformOp::Apply(SystemChange::SetDefaultRoute {
+                    gateway: None,
+                    interface: "vpnse0".to_string(),
+                    metric: Some(50),
+                }),
+                route("10.8.5.0/24", Some("10.0.0.1"), "vpnse0", Some(50)),
+                route("10.8.0.0/16", Some("10.0.0.1"), "vpnse0", Some(20)),
+                route("192.168.50.0/24", Some("192.168.1.1"), "en0", None),
+                dns_change(),
+                // Teardown
+                PlatformOp::DeleteRoute {
+                    destination: "10.8.5.0/24".to_string(),
+                    interface: Some("vpnse0".to_string()),
+                },
+                PlatformOp::DeleteRoute {
+                    destination: "10.8.0.0/16".to_string(),
+                    interface: Some("vpnse0".to_string()),
+                },
+                PlatformOp::DeleteRoute {
+                    destination: "192.168.50.0/24".to_string(),
+                    interface: Some("en0".to_string()),
+                },
+                PlatformOp::RestoreDefaultRoute {
+                    gateway: "192.168.1.1".to_string(),
+                    interface: "vpnse0".to_string(),
+                },
+                PlatformOp::RestoreDns { interface: "vpnse0".to_string() },
+                PlatformOp::DeleteInterface { name: "vpnse0".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_windows_operation_sequence() {
+        let ops = Arc::new(
+            RecordingOps::new(Platform::Windows)
+                .with_underlay_route("192.168.1.1", "Wi-Fi"),
+        );
+        let manager = TunnelManager::new(TunnelConfig::default(), vpn_server("203.0.113.10"));
+        let metric = Some(50);
+        let delete = |destination: &str, interface: &str| PlatformOp::DeleteRoute {
+            destination: destination.to_string(),
+            interface: Some(interface.to_string()),
+        };
+
+        assert_eq!(
+            run_session(manager, &ops),
+            vec![
+                // The default route stays; the /1 pair outranks it
+                route("203.0.113.10/32", Some("192.168.1.1"), "Wi-Fi", None),
+                route("0.0.0.0/1", Some("10.0.0.1"), "vpnse0", metric),
+                route("128.0.0.0/1", Some("10.0.0.1"), "vpnse0", metric),
+                dns_change(),
+                // Teardown: the adapter persists, so its routes are removed one by one
+                delete("203.0.113.10/32", "Wi-Fi"),
+                delete("0.0.0.0/1", "vpnse0"),
+                delete("128.0.0.0/1", "vpnse0"),
+                PlatformOp::RestoreDns { interface: "vpnse0".to_string() },
+                PlatformOp::DeleteInterface { name: "vpnse0".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_windows_split_operation_sequence() {
+        let ops = Arc::new(RecordingOps::new(Platform::Windows).with_vpn_interfaces(&["wg0"]));
+        let mut config = TunnelConfig::default();
+        config.dns_domain = Some("corp.example".to_string());
+        config.wins_servers = vec![Ipv4Addr::new(10, 0, 0, 5)];
+        let mut manager = TunnelManager::new(config, None);
+        manager.set_routing_config(RoutingConfig {
+            coexistence_policy: CoexistencePolicy::CoexistSplit,
+            ..RoutingConfig::default()
+        });
+
+        assert_eq!(
+            run_session(manager, &ops),
+            vec![
+                route("10.0.0.0/24", None, "vpnse0", Some(50)),
+                dns_change(),
+                PlatformOp::Apply(SystemChange::SetDnsDomain {
+                    interface: "vpnse0".to_string(),
+                    domain: "corp.example".to_string(),
+                }),
+                PlatformOp::Apply(SystemChange::SetWins {
+                    interface: "vpnse0".to_string(),
+                    servers: vec![Ipv4Addr::new(10, 0, 0, 5)],
+                }),
+                // Teardown: no default route was replaced
+                PlatformOp::DeleteRoute { destination: "10.0.0.0/24".to_string(), interface: Some("vpnse0".to_string()) },
+                PlatformOp::RestoreDns { interface: "vpnse0".to_string() },
+                PlatformOp::DeleteInterface { name: "vpnse0".to_string() },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_runtime_dns_update() {
+        let ops = Arc::new(RecordingOps::new(Platform::Windows));
+        let mut config = TunnelConfig::default();
+        config.dns_domain = Some("corp.example".to_string());
+        let mut manager = TunnelManager::new(config, None);
+        manager.set_platform_ops(ops.clone());
+
+        // Before establishment the servers are only remembered
+        let filtering: Vec<IpAddr> = vec![IpAddr::from([9, 9, 9, 9]), "2620:fe::fe".parse().unwrap()];
+        assert_eq!(manager.set_dns_servers(filtering.clone()).unwrap(), filtering);
+        assert!(ops.recorded().is_empty());
+
+        // Once up, the resolver is rewritten immediately, search domain included
+        manager.is_established = true;
+        let servers = vec![IpAddr::from([10, 0, 0, 53])];
+        assert_eq!(manager.set_dns_servers(servers.clone()).unwrap(), servers);
+        assert_eq!(
+            ops.recorded(),
+            vec![
+                PlatformOp::Apply(SystemChange::SetDns { interface: "vpnse0".to_string(), servers }),
+                PlatformOp::Apply(SystemChange::SetDnsDomain {
+                    interface: "vpnse0".to_string(),
+                    domain: "corp.example".to_string(),
+                }),
+            ]
+        );
+
+        // An empty list goes back to the defaults
+        manager.set_dns_servers(Vec::new()).unwrap();
+        assert_eq!(ops.recorded()[2], dns_change());
+
+        // A host veto keeps the previous servers
+        manager.set_change_planner(Some(Arc::new(|plan: &mut ChangePlan| {
+            plan.veto_category(ChangeCategory::Dns, "managed by MDM");
+        })));
+        assert!(manager.set_dns_servers(vec![IpAddr::from([9, 9, 9, 9])]).is_err());
+        assert_eq!(ops.recorded().len(), 4);
+        assert_eq!(manager.dns_servers()[0], IpAddr::V4(GATEWAY));
+    }
+}
@@ -0,0 +1,690 @@
+//! ARP/NDP responder for bridged hubs
+//!
+//! A hub without SecureNAT is a plain Ethernet segment: the session carries
+//! Ethernet frames, nobody answers ARP for the client's virtual address and
+//! nobody resolves the gateway for it. [`NeighborStack`] sits between the TUN
+//! device (IP packets) and the session (frames) and does the link-layer work
+//! of a host on that segment:
+//!
+//! - outgoing packets get an Ethernet header addressed to the next hop,
+//!   resolved with ARP (IPv4) or neighbour solicitations (IPv6); packets wait
+//!   in a small queue until it answers
+//! - ARP requests and neighbour solicitations for the client's own addresses
+//!   are answered
+//! - incoming frames sent to the client lose their header
+//!
+//! Resolved neighbours expire after [`REACHABLE_TIME`]. Unanswered
+//! resolutions are retried every [`RETRANSMIT_INTERVAL`] and given up after
+//! [`MAX_ATTEMPTS`], dropping the packets queued for them.
+//!
+//! With a [`DhcpClient`] attached the stack also keeps the DHCP lease going,
+//! and follows the leased address and gateway when the server changes them.
+
+use super::dhcp::{DhcpClient, DhcpLease};
+use super::l2::{
+    self, arp_packet, ethernet_frame, ip_frame, ip_packet, ipv4_multicast_mac, ipv6_at, ipv6_multicast_mac, mac_at,
+    parse_arp, MacAddr, ARP_REPLY, ARP_REQUEST, BROADCAST, ETHERNET_HEADER_LEN, ETHERTYPE_ARP, ETHERTYPE_IPV4,
+    ETHERTYPE_IPV6,
+};
+use super::lease::Ipv6Lease;
+use super::packet_framing::packet_addresses;
+use crate::net_util;
+use bytes::Bytes;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+/// How long a resolved neighbour is used before resolving it again
+pub const REACHABLE_TIME: Duration = Duration::from_secs(120);
+
+/// Wait between solicitations for the same neighbour
+pub const RETRANSMIT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Solicitations sent before a neighbour is considered unreachable
+pub const MAX_ATTEMPTS: u32 = 3;
+
+/// Packets queued per unresolved neighbour; older ones are dropped first
+pub const MAX_QUEUED: usize = 16;
+
+const IPV6_HEADER_LEN: usize = 40;
+const NEXT_HEADER_ICMPV6: u8 = 58;
+const NEIGHBOR_SOLICITATION: u8 = 135;
+const NEIGHBOR_ADVERTISEMENT: u8 = 136;
+/// ICMPv6 header, reserved/flags word and target address
+const NDP_MESSAGE_LEN: usize = 24;
+const OPTION_SOURCE_LINK_ADDR: u8 = 1;
+const OPTION_TARGET_LINK_ADDR: u8 = 2;
+/// Solicited and Override flags of an advertisement
+const ADVERT_SOLICITED_OVERRIDE: u8 = 0x60;
+const ADVERT_OVERRIDE: u8 = 0x20;
+
+/// The client's identity on the bridged segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeighborConfig {
+    pub mac: MacAddr,
+    pub ipv4: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// Router for destinations outside the IPv4 subnet
+    pub gateway: Ipv4Addr,
+    pub ipv6: Option<Ipv6Lease>,
+}
+
+impl NeighborConfig {
+    /// Random locally administered unicast address
+    pub fn random_mac() -> MacAddr {
+        l2::random_mac()
+    }
+
+    /// IPv6 link-local address derived from the MAC (modified EUI-64)
+    pub fn link_local(&self) -> Ipv6Addr {
+        let m = self.mac;
+        Ipv6Addr::from([0xfe, 0x80, 0, 0, 0, 0, 0, 0, m[0] ^ 0x02, m[1], m[2], 0xff, 0xfe, m[3], m[4], m[5]])
+    }
+
+    fn owns_ipv6(&self, address: Ipv6Addr) -> bool {
+        address == self.link_local() || self.ipv6.as_ref().is_some_and(|lease| lease.address == address)
+    }
+}
+
+/// Neighbour resolution counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NeighborStats {
+    /// ARP requests and neighbour solicitations sent
+    pub solicitations_sent: u64,
+    /// ARP replies and neighbour advertisements sent for the client's addresses
+    pub advertisements_sent: u64,
+    /// Neighbour entries learned or refreshed
+    pub resolved: u64,
+    /// Resolutions given up after [`MAX_ATTEMPTS`]
+    pub resolution_failures: u64,
+    /// Outgoing packets that had to wait for a resolution
+    pub queued: u64,
+    /// Outgoing packets dropped: queue overflow, failed resolution or no IPv6 lease
+    pub dropped: u64,
+    /// Incoming frames for another host or of an unhandled type
+    pub ignored_frames: u64,
+}
+
+/// Outcome of an incoming frame
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Inbound {
+    /// IP packet for the TUN device
+    pub packet: Option<Bytes>,
+    /// Frames to send to the session: replies, and packets a resolution released
+    pub frames: Vec<Bytes>,
+}
+
+struct Neighbor {
+    mac: MacAddr,
+    expires: Instant,
+}
+
+struct Resolution {
+    queue: VecDeque<Bytes>,
+    attempts: u32,
+    next_attempt: Instant,
+}
+
+/// Link-layer state of the client on a bridged hub
+///
+/// Time is passed in by the caller, which also has to call
+/// [`poll`](Self::poll) regularly for retransmissions and expiry.
+pub struct NeighborStack {
+    config: NeighborConfig,
+    neighbors: HashMap<IpAddr, Neighbor>,
+    pending: HashMap<IpAddr, Resolution>,
+    stats: NeighborStats,
+    dhcp: Option<DhcpClient>,
+}
+
+impl NeighborStack {
+    pub fn new(config: NeighborConfig) -> Self {
+        Self {
+            config,
+            neighbors: HashMap::new(),
+            pending: HashMap::new(),
+            stats: NeighborStats::default(),
+            dhcp: None,
+        }
+    }
+
+    /// Keep `dhcp`'s lease renewed; it should use the same MAC as `config`
+    pub fn with_dhcp(mut self, dhcp: DhcpClient) -> Self {
+        self.dhcp = Some(dhcp);
+        self.follow_lease();
+        self
+    }
+
+    /// Gratuitous ARP for the client's IPv4 address
+    ///
+    /// Sent when forwarding starts, so the segment learns the client's MAC
+    /// for the address before any resolution.
+    pub fn announce(&mut self) -> Bytes {
+        self.stats.advertisements_sent += 1;
+        l2::gratuitous_arp(self.config.mac, self.config.ipv4)
+    }
+
+    /// Current DHCP lease, if a client is attached and holds one
+    pub fn dhcp_lease(&self) -> Option<&DhcpLease> {
+        self.dhcp.as_ref().and_then(DhcpClient::lease)
+    }
+
+    pub fn config(&self) -> &NeighborConfig {
+        &self.config
+    }
+
+    pub fn stats(&self) -> NeighborStats {
+        self.stats
+    }
+
+    /// Hardware address of `ip`, if resolved and not expired at `now`
+    pub fn lookup(&self, ip: IpAddr, now: Instant) -> Option<MacAddr> {
+        self.neighbors.get(&ip).filter(|neighbor| neighbor.expires > now).map(|neighbor| neighbor.mac)
+    }
+
+    /// Frames to send for an IP packet read from TUN
+    ///
+    /// Empty while the next hop is being resolved (the packet is queued) or
+    /// when the packet cannot be sent at all.
+    pub fn outbound(&mut self, packet: Bytes, now: Instant) -> Vec<Bytes> {
+        let Some((_, destination)) = packet_addresses(&packet) else {
+            self.stats.dropped += 1;
+            return Vec::new();
+        };
+        if let Some(mac) = self.group_mac(destination) {
+            return vec![self.frame(mac, &packet)];
+        }
+        let Some(next_hop) = self.next_hop(destination) else {
+            self.stats.dropped += 1;
+            return Vec::new();
+        };
+        if let Some(mac) = self.lookup(next_hop, now) {
+            return vec![self.frame(mac, &packet)];
+        }
+
+        self.stats.queued += 1;
+        match self.pending.entry(next_hop) {
+            Entry::Occupied(mut resolution) => {
+                let queue = &mut resolution.get_mut().queue;
+                if queue.len() >= MAX_QUEUED {
+                    queue.pop_front();
+                    self.stats.dropped += 1;
+                }
+                queue.push_back(packet);
+                Vec::new()
+            }
+            Entry::Vacant(resolution) => {
+                resolution.insert(Resolution {
+                    queue: VecDeque::from([packet]),
+                    attempts: 1,
+                    next_attempt: now + RETRANSMIT_INTERVAL,
+                });
+                self.stats.solicitations_sent += 1;
+                vec![self.solicitation(next_hop)]
+            }
+        }
+    }
+
+    /// Handle a frame received from the session
+    pub fn inbound(&mut self, frame: &[u8], now: Instant) -> Inbound {
+        let mut result = Inbound::default();
+        if frame.len() < ETHERNET_HEADER_LEN {
+            self.stats.ignored_frames += 1;
+            return result;
+        }
+        let destination = mac_at(frame, 0);
+        let source = mac_at(frame, 6);
+        let payload = &frame[ETHERNET_HEADER_LEN..];
+        // Unicast to someone else; group addresses have the low bit of the first octet set
+        if destination != self.config.mac && destination[0] & 0x01 == 0 {
+            self.stats.ignored_frames += 1;
+            return result;
+        }
+        if let Some(frames) = self.dhcp.as_mut().and_then(|dhcp| dhcp.handle_frame(frame, now)) {
+            result.frames = frames;
+            result.frames.extend(self.follow_lease());
+            return result;
+        }
+
+        match u16::from_be_bytes([frame[12], frame[13]]) {
+            ETHERTYPE_ARP => self.handle_arp(payload, now, &mut result),
+            ETHERTYPE_IPV6 if self.handle_ndp(source, payload, now, &mut result) => {}
+            ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => match ip_packet(payload) {
+                Some(packet) => result.packet = Some(Bytes::copy_from_slice(packet)),
+                None => self.stats.ignored_frames += 1,
+            },
+            _ => self.stats.ignored_frames += 1,
+        }
+        result
+    }
+
+    /// Retransmit due solicitations, give up on unanswered ones, expire old
+    /// entries and run the DHCP timers
+    pub fn poll(&mut self, now: Instant) -> Vec<Bytes> {
+        let due: Vec<IpAddr> = self
+            .pending
+            .iter()
+            .filter(|(_, resolution)| resolution.next_attempt <= now)
+            .map(|(ip, _)| *ip)
+            .collect();
+
+        let mut frames = Vec::new();
+        for ip in due {
+            let Some(resolution) = self.pending.get_mut(&ip) else {
+                continue;
+            };
+            if resolution.attempts >= MAX_ATTEMPTS {
+                let dropped = resolution.queue.len() as u64;
+                self.pending.remove(&ip);
+                self.stats.resolution_failures += 1;
+                self.stats.dropped += dropped;
+                log::debug!("No answer from neighbour {}, dropped {} packet(s)", ip, dropped);
+                continue;
+            }
+            resolution.attempts += 1;
+            resolution.next_attempt = now + RETRANSMIT_INTERVAL;
+            self.stats.solicitations_sent += 1;
+            frames.push(self.solicitation(ip));
+        }
+
+        self.neighbors.retain(|_, neighbor| neighbor.expires > now);
+        if let Some(ref mut dhcp) = self.dhcp {
+            frames.extend(dhcp.poll(now));
+            frames.extend(self.follow_lease());
+        }
+        frames
+    }
+
+    /// Answer for and route via what the DHCP server leased, announcing a new address
+    fn follow_lease(&mut self) -> Option<Bytes> {
+        let lease = self.dhcp_lease()?;
+        let (ipv4, netmask, gateway) = (lease.address, lease.netmask, lease.router.unwrap_or(lease.server));
+        let moved = ipv4 != self.config.ipv4;
+        self.config.ipv4 = ipv4;
+        self.config.netmask = netmask;
+        self.config.gateway = gateway;
+        moved.then(|| self.announce())
+    }
+
+    /// Neighbour that receives packets for `destination`
+    fn next_hop(&self, destination: IpAddr) -> Option<IpAddr> {
+        match destination {
+            IpAddr::V4(destination) => {
+                let on_link = net_util::ipv4_subnet(self.config.ipv4, self.config.netmask)
+                    .is_some_and(|subnet| subnet.contains(&destination));
+                Some(IpAddr::V4(if on_link { destination } else { self.config.gateway }))
+            }
+            IpAddr::V6(destination) => {
+                let lease = self.config.ipv6.as_ref()?;
+                let on_link = is_link_local(destination)
+                    || net_util::subnet(IpAddr::V6(lease.address), lease.prefix_len)
+                        .is_some_and(|subnet| subnet.contains(&IpAddr::V6(destination)));
+                Some(IpAddr::V6(if on_link { destination } else { lease.gateway.unwrap_or(destination) }))
+            }
+        }
+    }
+
+    /// Broadcast or multicast address `destination` maps to, if it is not unicast
+    fn group_mac(&self, destination: IpAddr) -> Option<MacAddr> {
+        match destination {
+            IpAddr::V4(destination) => {
+                let broadcast = net_util::ipv4_subnet(self.config.ipv4, self.config.netmask).map(|subnet| subnet.broadcast());
+                if destination.is_broadcast() || Some(destination) == broadcast {
+                    Some(BROADCAST)
+                } else if destination.is_multicast() {
+                    Some(ipv4_multicast_mac(destination))
+                } else {
+                    None
+                }
+            }
+            IpAddr::V6(destination) if destination.is_multicast() => Some(ipv6_multicast_mac(destination)),
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    fn learn(&mut self, ip: IpAddr, mac: MacAddr, now: Instant, result: &mut Inbound) {
+        self.neighbors.insert(ip, Neighbor { mac, expires: now + REACHABLE_TIME });
+        self.stats.resolved += 1;
+        if let Some(resolution) = self.pending.remove(&ip) {
+            for packet in resolution.queue {
+                result.frames.push(self.frame(mac, &packet));
+            }
+        }
+    }
+
+    fn handle_arp(&mut self, arp: &[u8], now: Instant, result: &mut Inbound) {
+        let Some((operation, sender_mac, sender, target)) = parse_arp(arp) else {
+            self.stats.ignored_frames += 1;
+            return;
+        };
+        let sender = IpAddr::V4(sender);
+
+        // Learn senders we talk to or that talk to us (RFC 826 merge rule)
+        let for_us = target == self.config.ipv4;
+        if for_us || self.neighbors.contains_key(&sender) || self.pending.contains_key(&sender) {
+            self.learn(sender, sender_mac, now, result);
+        }
+        if operation == ARP_REQUEST && for_us {
+            self.stats.advertisements_sent += 1;
+            result.frames.push(self.arp(ARP_REPLY, sender_mac, sender_mac, sender));
+        }
+    }
+
+    /// Handle neighbour solicitations and advertisements; false for other IPv6 packets
+    fn handle_ndp(&mut self, source_mac: MacAddr, packet: &[u8], now: Instant, result: &mut Inbound) -> bool {
+        if packet.len() < IPV6_HEADER_LEN + NDP_MESSAGE_LEN || packet[6] != NEXT_HEADER_ICMPV6 {
+            return false;
+        }
+        let message = &packet[IPV6_HEADER_LEN..];
+        let kind = message[0];
+        if kind != NEIGHBOR_SOLICITATION && kind != NEIGHBOR_ADVERTISEMENT {
+            return false;
+        }
+        let source = ipv6_at(packet, 8);
+        let target = ipv6_at(message, 8);
+        let option = if kind == NEIGHBOR_SOLICITATION { OPTION_SOURCE_LINK_ADDR } else { OPTION_TARGET_LINK_ADDR };
+        let link_addr = link_addr_option(&message[NDP_MESSAGE_LEN..], option).unwrap_or(source_mac);
+
+        if kind == NEIGHBOR_SOLICITATION {
+            if !self.config.owns_ipv6(target) {
+                return true;
+            }
+            // Duplicate address detection probes come from the unspecified address
+            let (destination, destination_mac, flags) = if source.is_unspecified() {
+                let all_nodes = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+                (all_nodes, ipv6_multicast_mac(all_nodes), ADVERT_OVERRIDE)
+            } else {
+                self.learn(IpAddr::V6(source), link_addr, now, result);
+                (source, source_mac, ADVERT_SOLICITED_OVERRIDE)
+            };
+            self.stats.advertisements_sent += 1;
+            result.frames.push(self.advertisement(target, destination, destination_mac, flags));
+        } else {
+            let target_ip = IpAddr::V6(target);
+            if self.neighbors.contains_key(&target_ip) || self.pending.contains_key(&target_ip) {
+                self.learn(target_ip, link_addr, now, result);
+            }
+        }
+        true
+    }
+
+    fn frame(&self, destination: MacAddr, packet: &[u8]) -> Bytes {
+        ip_frame(destination, self.config.mac, packet)
+    }
+
+    /// ARP request or neighbour solicitation for `ip`
+    fn solicitation(&self, ip: IpAddr) -> Bytes {
+        match ip {
+            IpAddr::V4(target) => self.arp(ARP_REQUEST, BROADCAST, [0; 6], IpAddr::V4(target)),
+            IpAddr::V6(target) => {
+                let source = match self.config.ipv6 {
+                    Some(ref lease) if !is_link_local(target) => lease.address,
+                    _ => self.config.link_local(),
+                };
+                let t = target.octets();
+                let solicited_node = Ipv6Addr::from([0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, t[13], t[14], t[15]]);
+                let mut message = vec![NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+                message.extend(t);
+                message.extend([OPTION_SOURCE_LINK_ADDR, 1]);
+                message.extend(self.config.mac);
+                let packet = icmpv6_packet(source, solicited_node, message);
+                ethernet_frame(ipv6_multicast_mac(solicited_node), self.config.mac, ETHERTYPE_IPV6, &packet)
+            }
+        }
+    }
+
+    fn advertisement(&self, target: Ipv6Addr, destination: Ipv6Addr, destination_mac: MacAddr, flags: u8) -> Bytes {
+        let mut message = vec![NEIGHBOR_ADVERTISEMENT, 0, 0, 0, flags, 0, 0, 0];
+        message.extend(target.octets());
+        message.extend([OPTION_TARGET_LINK_ADDR, 1]);
+        message.extend(self.config.mac);
+        let packet = icmpv6_packet(target, destination, message);
+        ethernet_frame(destination_mac, self.config.mac, ETHERTYPE_IPV6, &packet)
+    }
+
+    fn arp(&self, operation: u16, destination: MacAddr, target_mac: MacAddr, target: IpAddr) -> Bytes {
+        let IpAddr::V4(target) = target else {
+            unreachable!("ARP is IPv4 only");
+        };
+        let arp = arp_packet(operation, self.config.mac, self.config.ipv4, target_mac, target);
+        ethernet_frame(destination, self.config.mac, ETHERTYPE_ARP, &arp)
+    }
+}
+
+impl std::fmt::Debug for NeighborStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NeighborStack")
+            .field("config", &self.config)
+            .field("neighbors", &self.neighbors.len())
+            .field("pending", &self.pending.len())
+            .field("stats", &self.stats)
+            .field("dhcp", &self.dhcp.as_ref().map(DhcpClient::state))
+            .finish()
+    }
+}
+
+fn icmpv6_packet(source: Ipv6Addr, destination: Ipv6Addr, mut message: Vec<u8>) -> Vec<u8> {
+    let checksum = icmpv6_checksum(source, destination, &message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut packet = Vec::with_capacity(IPV6_HEADER_LEN + message.len());
+    packet.extend([0x60, 0, 0, 0]);
+    packet.extend((message.len() as u16).to_be_bytes());
+    // Neighbour discovery requires a hop limit of 255
+    packet.extend([NEXT_HEADER_ICMPV6, 255]);
+    packet.extend(source.octets());
+    packet.extend(destination.octets());
+    packet.extend(message);
+    packet
+}
+
+/// Internet checksum of an ICMPv6 message and its IPv6 pseudo-header
+fn icmpv6_checksum(source: Ipv6Addr, destination: Ipv6Addr, message: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut add = |bytes: &[u8]| {
+        for chunk in bytes.chunks(2) {
+            sum += u32::from(u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]));
+        }
+    };
+    add(&source.octets());
+    add(&destination.octets());
+    add(&(message.len() as u32).to_be_bytes());
+    add(&[0, 0, 0, NEXT_HEADER_ICMPV6]);
+    add(message);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Link-layer address carried in an NDP option of `kind`
+fn link_addr_option(mut options: &[u8], kind: u8) -> Option<MacAddr> {
+    while options.len() >= 8 {
+        let len = usize::from(options[1]) * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        if options[0] == kind {
+            return Some(mac_at(options, 2));
+        }
+        options = &options[len..];
+    }
+    None
+}
+
+fn is_link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUR_MAC: MacAddr = [0x02, 0, 0, 0, 0, 0x01];
+    const GATEWAY_MAC: MacAddr = [0x5e, 0, 0, 0, 0, 0xfe];
+
+    fn stack() -> NeighborStack {
+        NeighborStack::new(NeighborConfig {
+            mac: OUR_MAC,
+            ipv4: Ipv4Addr::new(10, 21, 0, 7),
+            netmask: Ipv4Addr::new(255, 255, 0, 0),
+            gateway: Ipv4Addr::new(10, 21, 0, 1),
+            ipv6: Some(Ipv6Lease {
+                address: "fd00:21::7".parse().unwrap(),
+                prefix_len: 64,
+                gateway: Some("fd00:21::1".parse().unwrap()),
+                dns_servers: Vec::new(),
+            }),
+        })
+    }
+
+    fn ipv4_packet(destination: [u8; 4]) -> Bytes {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[3] = 20;
+        packet[12..16].copy_from_slice(&[10, 21, 0, 7]);
+        packet[16..20].copy_from_slice(&destination);
+        Bytes::from(packet)
+    }
+
+    fn ipv6_packet(destination: &str) -> Bytes {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x60;
+        packet[6] = 17;
+        packet[8..24].copy_from_slice(&"fd00:21::7".parse::<Ipv6Addr>().unwrap().octets());
+        packet[24..40].copy_from_slice(&destination.parse::<Ipv6Addr>().unwrap().octets());
+        Bytes::from(packet)
+    }
+
+    fn arp_frame(operation: u16, sender_mac: MacAddr, sender: [u8; 4], target: [u8; 4]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend(if operation == ARP_REQUEST { BROADCAST } else { OUR_MAC });
+        frame.extend(sender_mac);
+        frame.extend(ETHERTYPE_ARP.to_be_bytes());
+        frame.extend([0x00, 0x01, 0x08, 0x00, 6, 4]);
+        frame.extend(operation.to_be_bytes());
+        frame.extend(sender_mac);
+        frame.extend(sender);
+        frame.extend([0; 6]);
+        frame.extend(target);
+        frame
+    }
+
+    #[test]
+    fn test_arp_resolves_gateway_and_releases_queue() {
+        let mut stack = stack();
+        let now = Instant::now();
+
+        // Off-subnet traffic waits for the gateway
+        let frames = stack.outbound(ipv4_packet([1, 1, 1, 1]), now);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0][..6], BROADCAST);
+        assert_eq!(frames[0][12..14], ETHERTYPE_ARP.to_be_bytes());
+        assert_eq!(frames[0][14 + 24..14 + 28], [10, 21, 0, 1]);
+        assert!(stack.outbound(ipv4_packet([8, 8, 8, 8]), now).is_empty());
+
+        let inbound = stack.inbound(&arp_frame(ARP_REPLY, GATEWAY_MAC, [10, 21, 0, 1], [10, 21, 0, 7]), now);
+        assert_eq!(inbound.packet, None);
+        assert_eq!(inbound.frames.len(), 2);
+        assert_eq!(inbound.frames[0][..6], GATEWAY_MAC);
+        assert_eq!(inbound.frames[0][14..], ipv4_packet([1, 1, 1, 1])[..]);
+
+        // Resolved: sent straight away until the entry expires
+        let frames = stack.outbound(ipv4_packet([9, 9, 9, 9]), now);
+        assert_eq!(frames[0][..6], GATEWAY_MAC);
+        assert_eq!(stack.lookup(IpAddr::from([10, 21, 0, 1]), now + REACHABLE_TIME), None);
+
+        let stats = stack.stats();
+        assert_eq!((stats.solicitations_sent, stats.resolved, stats.queued), (1, 1, 2));
+    }
+
+    #[test]
+    fn test_answers_arp_for_own_address_only() {
+        let mut stack = stack();
+        let now = Instant::now();
+        let peer: MacAddr = [0x5e, 0, 0, 0, 0, 0x20];
+
+        let inbound = stack.inbound(&arp_frame(ARP_REQUEST, peer, [10, 21, 0, 20], [10, 21, 0, 7]), now);
+        assert_eq!(inbound.frames.len(), 1);
+        let reply = &inbound.frames[0];
+        assert_eq!(reply[..6], peer);
+        assert_eq!(reply[14 + 6..14 + 8], ARP_REPLY.to_be_bytes());
+        assert_eq!(reply[14 + 8..14 + 14], OUR_MAC);
+        assert_eq!(reply[14 + 14..14 + 18], [10, 21, 0, 7]);
+        assert_eq!(stack.lookup(IpAddr::from([10, 21, 0, 20]), now), Some(peer));
+
+        assert!(stack.inbound(&arp_frame(ARP_REQUEST, peer, [10, 21, 0, 20], [10, 21, 0, 9]), now).frames.is_empty());
+        assert_eq!(stack.stats().advertisements_sent, 1);
+    }
+
+    #[test]
+    fn test_unanswered_resolution_gives_up() {
+        let mut stack = stack();
+        let start = Instant::now();
+        assert_eq!(stack.outbound(ipv4_packet([10, 21, 3, 4]), start).len(), 1);
+
+        assert!(stack.poll(start).is_empty());
+        assert_eq!(stack.poll(start + RETRANSMIT_INTERVAL).len(), 1);
+        assert_eq!(stack.poll(start + RETRANSMIT_INTERVAL * 2).len(), 1);
+        assert!(stack.poll(start + RETRANSMIT_INTERVAL * 3).is_empty());
+
+        let stats = stack.stats();
+        assert_eq!(stats.solicitations_sent, MAX_ATTEMPTS as u64);
+        assert_eq!((stats.resolution_failures, stats.dropped), (1, 1));
+    }
+
+    #[test]
+    fn test_ndp_solicits_and_advertises() {
+        let mut stack = stack();
+        let now = Instant::now();
+
+        let frames = stack.outbound(ipv6_packet("2001:db8::1"), now);
+        let solicitation = &frames[0][14..];
+        assert_eq!(frames[0][..6], [0x33, 0x33, 0xff, 0, 0, 0x01]);
+        assert_eq!(solicitation[40], NEIGHBOR_SOLICITATION);
+        assert_eq!(ipv6_at(solicitation, 40 + 8), "fd00:21::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(icmpv6_checksum(ipv6_at(solicitation, 8), ipv6_at(solicitation, 24), &solicitation[40..]), 0);
+
+        // The router answers and the queued packet follows
+        let mut message = vec![NEIGHBOR_ADVERTISEMENT, 0, 0, 0, ADVERT_SOLICITED_OVERRIDE, 0, 0, 0];
+        message.extend("fd00:21::1".parse::<Ipv6Addr>().unwrap().octets());
+        message.extend([OPTION_TARGET_LINK_ADDR, 1]);
+        message.extend(GATEWAY_MAC);
+        let advert = icmpv6_packet("fd00:21::1".parse().unwrap(), "fd00:21::7".parse().unwrap(), message);
+        let inbound = stack.inbound(&ethernet_frame(OUR_MAC, GATEWAY_MAC, ETHERTYPE_IPV6, &advert), now);
+        assert_eq!(inbound.packet, None);
+        assert_eq!(inbound.frames[0][..6], GATEWAY_MAC);
+
+        // Solicitation for our address from a peer
+        let peer: MacAddr = [0x5e, 0, 0, 0, 0, 0x30];
+        let mut message = vec![NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+        message.extend("fd00:21::7".parse::<Ipv6Addr>().unwrap().octets());
+        message.extend([OPTION_SOURCE_LINK_ADDR, 1]);
+        message.extend(peer);
+        let solicitation = icmpv6_packet("fd00:21::30".parse().unwrap(), "ff02::1:ff00:7".parse().unwrap(), message);
+        let group = ipv6_multicast_mac("ff02::1:ff00:7".parse().unwrap());
+        let inbound = stack.inbound(&ethernet_frame(group, peer, ETHERTYPE_IPV6, &solicitation), now);
+        let advert = &inbound.frames[0];
+        assert_eq!(advert[..6], peer);
+        assert_eq!(advert[14 + 40], NEIGHBOR_ADVERTISEMENT);
+        assert_eq!(link_addr_option(&advert[14 + 40 + NDP_MESSAGE_LEN..], OPTION_TARGET_LINK_ADDR), Some(OUR_MAC));
+        assert_eq!(icmpv6_checksum(ipv6_at(advert, 14 + 8), ipv6_at(advert, 14 + 24), &advert[14 + 40..]), 0);
+    }
+
+    #[test]
+    fn test_inbound_filters_and_strips_frames() {
+        let mut stack = stack();
+        let now = Instant::now();
+        let mut padded = ipv4_packet([10, 21, 0, 7]).to_vec();
+        padded.extend([0; 26]);
+
+        let inbound = stack.inbound(&ethernet_frame(OUR_MAC, GATEWAY_MAC, ETHERTYPE_IPV4, &padded), now);
+        assert_eq!(inbound.packet, Some(ipv4_packet([10, 21, 0, 7])));
+
+        let other_host = [0x02, 0, 0, 0, 0, 0x99];
+        assert_eq!(stack.inbound(&ethernet_frame(other_host, GATEWAY_MAC, ETHERTYPE_IPV4, &padded), now), Inbound::default());
+        assert_eq!(stack.inbound(&ethernet_frame(BROADCAST, GATEWAY_MAC, 0x88cc, &padded), now), Inbound::default());
+        assert_eq!(stack.stats().ignored_frames, 2);
+    }
+}
@@ -0,0 +1,601 @@
+//! virtio-net offload headers of the Linux TUN device
+//!
+//! A device opened with `IFF_VNET_HDR` puts a [`VirtioNetHdr`] in front of
+//! every packet. Once TSO is switched on with `TUNSETOFFLOAD`, the kernel
+//! stops cutting TCP streams into MTU-sized packets before handing them to
+//! the device: one read yields a super-packet of up to 64 KiB, which
+//! [`Segmenter`] cuts into the segments the session carries. The other way,
+//! [`Coalescer`] merges consecutive segments of a TCP stream back into
+//! super-packets that the kernel takes in one write. A bulk transfer then
+//! costs a system call per 64 KiB instead of one per packet.
+//!
+//! Packets may also arrive with their transport checksum left to the device
+//! (`NEEDS_CSUM`); it is filled in before they leave.
+
+use std::collections::VecDeque;
+use std::io::{self, Read};
+
+/// Bytes of the header in front of every packet
+pub const VNET_HDR_LEN: usize = 10;
+
+/// The checksum from `csum_start` on is still to be computed
+pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+/// No segmentation
+pub const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+/// IPv4 TCP super-packet
+pub const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+/// IPv6 TCP super-packet
+pub const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+/// The super-packet carries ECN marks (`TUN_F_TSO_ECN`, not requested)
+pub const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+/// Largest super-packet read from the device, header included
+const MAX_FRAME_LEN: usize = VNET_HDR_LEN + 65_535;
+
+/// Offset of the checksum in a TCP header
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+const PROTOCOL_TCP: u8 = 6;
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_CWR: u8 = 0x80;
+
+/// `struct virtio_net_hdr`, in host byte order
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VirtioNetHdr {
+    pub flags: u8,
+    pub gso_type: u8,
+    /// Length of the IP and TCP headers of a super-packet
+    pub hdr_len: u16,
+    /// Payload bytes per segment
+    pub gso_size: u16,
+    pub csum_start: u16,
+    pub csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    /// Header at the start of `frame`
+    pub fn parse(frame: &[u8]) -> Option<Self> {
+        let field = |at: usize| u16::from_ne_bytes([frame[at], frame[at + 1]]);
+        (frame.len() >= VNET_HDR_LEN).then(|| Self {
+            flags: frame[0],
+            gso_type: frame[1],
+            hdr_len: field(2),
+            gso_size: field(4),
+            csum_start: field(6),
+            csum_offset: field(8),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; VNET_HDR_LEN] {
+        let mut bytes = [0u8; VNET_HDR_LEN];
+        bytes[0] = self.flags;
+        bytes[1] = self.gso_type;
+        bytes[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        bytes[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        bytes[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        bytes[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+        bytes
+    }
+}
+
+/// Cut a frame read from the device into the packets it stands for
+///
+/// Appends them to `packets`, with their checksums complete.
+pub fn split(frame: &[u8], packets: &mut VecDeque<Vec<u8>>) -> io::Result<()> {
+    let header = VirtioNetHdr::parse(frame).ok_or_else(|| invalid("frame shorter than its virtio-net header"))?;
+    let packet = &frame[VNET_HDR_LEN..];
+    match header.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE => {
+            let mut packet = packet.to_vec();
+            if header.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                complete_checksum(&mut packet, usize::from(header.csum_start), usize::from(header.csum_offset))?;
+            }
+            packets.push_back(packet);
+            Ok(())
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => segment_tcp(packet, usize::from(header.gso_size), packets),
+        other => Err(invalid(&format!("unsupported GSO type {other}"))),
+    }
+}
+
+/// Fill in a checksum the kernel left to the device
+///
+/// The field holds the pseudo-header sum; the checksum covers everything
+/// from `start` on.
+fn complete_checksum(packet: &mut [u8], start: usize, offset: usize) -> io::Result<()> {
+    let field = start + offset;
+    if field + 2 > packet.len() {
+        return Err(invalid("checksum offset beyond the packet"));
+    }
+    let checksum = !fold(sum(&packet[start..], 0));
+    packet[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+    Ok(())
+}
+
+/// Cut a TCP super-packet into segments of `mss` payload bytes
+fn segment_tcp(packet: &[u8], mss: usize, segments: &mut VecDeque<Vec<u8>>) -> io::Result<()> {
+    let tcp = Tcp::parse(packet).ok_or_else(|| invalid("malformed TCP super-packet"))?;
+    if mss == 0 {
+        return Err(invalid("TCP super-packet without a segment size"));
+    }
+    let headers = &packet[..tcp.payload];
+    let payload = &packet[tcp.payload..];
+    let count = payload.len().div_ceil(mss).max(1);
+    let seq = tcp.seq(packet);
+    let flags = packet[tcp.ip_len + 13];
+    for index in 0..count {
+        let chunk = payload.get(index * mss..).map_or(&[][..], |rest| &rest[..rest.len().min(mss)]);
+        let mut segment = Vec::with_capacity(headers.len() + chunk.len());
+        segment.extend_from_slice(headers);
+        segment.extend_from_slice(chunk);
+
+        let mut segment_flags = flags;
+        if index > 0 {
+            segment_flags &= !TCP_CWR;
+        }
+        if index + 1 < count {
+            segment_flags &= !(TCP_FIN | TCP_PSH);
+        }
+        segment[tcp.ip_len + 13] = segment_flags;
+        let segment_seq = seq.wrapping_add((index * mss) as u32);
+        segment[tcp.ip_len + 4..tcp.ip_len + 8].copy_from_slice(&segment_seq.to_be_bytes());
+        if tcp.ipv6 {
+            let payload_len = (segment.len() - tcp.ip_len) as u16;
+            segment[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        } else {
+            let id = u16::from_be_bytes([segment[4], segment[5]]).wrapping_add(index as u16);
+            let total_len = segment.len() as u16;
+            segment[2..4].copy_from_slice(&total_len.to_be_bytes());
+            segment[4..6].copy_from_slice(&id.to_be_bytes());
+            segment[10..12].fill(0);
+            let checksum = !fold(sum(&segment[..tcp.ip_len], 0));
+            segment[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        tcp.set_checksum(&mut segment, true);
+        segments.push_back(segment);
+    }
+    Ok(())
+}
+
+/// Reads frames off an offloading device and hands out one packet at a time
+pub struct Segmenter {
+    frame: Vec<u8>,
+    packets: VecDeque<Vec<u8>>,
+}
+
+impl Default for Segmenter {
+    fn default() -> Self {
+        Self { frame: vec![0u8; MAX_FRAME_LEN], packets: VecDeque::new() }
+    }
+}
+
+impl Segmenter {
+    /// Next packet into `buf`, reading the device only once the last frame is used up
+    ///
+    /// A packet longer than `buf` is truncated, as with a read from the
+    /// device itself. Frames the kernel should never produce are dropped
+    /// with a warning.
+    pub fn read(&mut self, device: &mut dyn Read, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                let len = packet.len().min(buf.len());
+                buf[..len].copy_from_slice(&packet[..len]);
+                return Ok(len);
+            }
+            let len = device.read(&mut self.frame)?;
+            if len == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = split(&self.frame[..len], &mut self.packets) {
+                log::warn!("Dropping TUN frame: {e}");
+            }
+        }
+    }
+
+    /// Whether packets of the last frame are still to be handed out
+    pub fn has_packets(&self) -> bool {
+        !self.packets.is_empty()
+    }
+}
+
+/// Merges consecutive TCP segments into super-packets for an offloading device
+///
+/// Segments of one stream that follow each other, carry the same headers
+/// but for sequence number and length, and all but the last of which are
+/// full-sized, become one frame; any other packet goes out on its own
+/// behind a plain header. Frames are queued in order and stay queued until
+/// [`written`](Self::written), so a write that would block can be retried.
+#[derive(Default)]
+pub struct Coalescer {
+    held: Option<Held>,
+    frames: VecDeque<Vec<u8>>,
+}
+
+/// Super-packet still open for more segments
+struct Held {
+    /// Header placeholder and the packet so far
+    frame: Vec<u8>,
+    tcp: Tcp,
+    mss: usize,
+    next_seq: u32,
+    segments: usize,
+    /// A short or PSH segment ended it
+    closed: bool,
+}
+
+impl Coalescer {
+    /// Queue `packet`, merged into the previous one where possible
+    pub fn push(&mut self, packet: &[u8]) {
+        if let Some(ref mut held) = self.held {
+            if held.append(packet) {
+                return;
+            }
+        }
+        self.flush();
+        match Held::start(packet) {
+            Some(held) => self.held = Some(held),
+            None => self.frames.push_back(plain_frame(packet)),
+        }
+    }
+
+    /// Queue the super-packet still being merged
+    pub fn flush(&mut self) {
+        if let Some(held) = self.held.take() {
+            self.frames.push_back(held.finish());
+        }
+    }
+
+    /// Oldest frame not yet written
+    pub fn next_frame(&self) -> Option<&[u8]> {
+        self.frames.front().map(Vec::as_slice)
+    }
+
+    /// Drop the frame [`next_frame`](Self::next_frame) returned, now written
+    pub fn written(&mut self) {
+        self.frames.pop_front();
+    }
+}
+
+impl Held {
+    /// Open a super-packet with `packet`, if it is a TCP segment that can be merged
+    fn start(packet: &[u8]) -> Option<Self> {
+        let tcp = Tcp::parse(packet).filter(|tcp| tcp.mergeable(packet))?;
+        let mss = packet.len() - tcp.payload;
+        let mut frame = Vec::with_capacity(VNET_HDR_LEN + packet.len());
+        frame.extend_from_slice(&[0; VNET_HDR_LEN]);
+        frame.extend_from_slice(packet);
+        Some(Self {
+            next_seq: tcp.seq(packet).wrapping_add(mss as u32),
+            closed: packet[tcp.ip_len + 13] & TCP_PSH != 0,
+            frame,
+            tcp,
+            mss,
+            segments: 1,
+        })
+    }
+
+    /// Merge `packet` if it continues the stream
+    fn append(&mut self, packet: &[u8]) -> bool {
+        let held = &self.frame[VNET_HDR_LEN..];
+        let Some(tcp) = Tcp::parse(packet) else {
+            return false;
+        };
+        let payload = packet.len() - tcp.payload;
+        // IPv6 leaves its fixed header out of the length field
+        let max_len = if tcp.ipv6 { 40 + 65_535 } else { 65_535 };
+        let fits = !self.closed
+            && tcp.payload == self.tcp.payload
+            && payload <= self.mss
+            && held.len() + payload <= max_len
+            && tcp.seq(packet) == self.next_seq
+            && same_headers(held, packet, &tcp)
+            && tcp.mergeable(packet);
+        if !fits {
+            return false;
+        }
+        self.frame.extend_from_slice(&packet[tcp.payload..]);
+        self.next_seq = self.next_seq.wrapping_add(payload as u32);
+        self.segments += 1;
+        self.closed = payload < self.mss || packet[tcp.ip_len + 13] & TCP_PSH != 0;
+        if packet[tcp.ip_len + 13] & TCP_PSH != 0 {
+            self.frame[VNET_HDR_LEN + tcp.ip_len + 13] |= TCP_PSH;
+        }
+        true
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.segments == 1 {
+            // Unchanged, checksums and all
+            return self.frame;
+        }
+        let tcp = self.tcp;
+        let packet = &mut self.frame[VNET_HDR_LEN..];
+        let len = packet.len();
+        if tcp.ipv6 {
+            packet[4..6].copy_from_slice(&((len - tcp.ip_len) as u16).to_be_bytes());
+        } else {
+            packet[2..4].copy_from_slice(&(len as u16).to_be_bytes());
+            packet[10..12].fill(0);
+            let checksum = !fold(sum(&packet[..tcp.ip_len], 0));
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        }
+        // The kernel computes the checksums of the segments from the pseudo-header sum
+        tcp.set_checksum(packet, false);
+        let header = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: if tcp.ipv6 { VIRTIO_NET_HDR_GSO_TCPV6 } else { VIRTIO_NET_HDR_GSO_TCPV4 },
+            hdr_len: tcp.payload as u16,
+            gso_size: self.mss as u16,
+            csum_start: tcp.ip_len as u16,
+            csum_offset: TCP_CHECKSUM_OFFSET as u16,
+        };
+        self.frame[..VNET_HDR_LEN].copy_from_slice(&header.to_bytes());
+        self.frame
+    }
+}
+
+/// `packet` behind a header asking for nothing
+fn plain_frame(packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(VNET_HDR_LEN + packet.len());
+    frame.extend_from_slice(&VirtioNetHdr::default().to_bytes());
+    frame.extend_from_slice(packet);
+    frame
+}
+
+/// Whether `packet` has the IP and TCP headers of `held` but for length, ID, checksums, sequence number and PSH
+fn same_headers(held: &[u8], packet: &[u8], tcp: &Tcp) -> bool {
+    let ip_same = if tcp.ipv6 {
+        held[..4] == packet[..4] && held[6..40] == packet[6..40]
+    } else {
+        held[..2] == packet[..2] && held[6..10] == packet[6..10] && held[12..tcp.ip_len] == packet[12..tcp.ip_len]
+    };
+    let tcp_start = tcp.ip_len;
+    ip_same
+        && held[tcp_start..tcp_start + 4] == packet[tcp_start..tcp_start + 4]
+        && held[tcp_start + 8..tcp_start + 13] == packet[tcp_start + 8..tcp_start + 13]
+        && held[tcp_start + 14..tcp_start + 16] == packet[tcp_start + 14..tcp_start + 16]
+        && held[tcp_start + 18..tcp.payload] == packet[tcp_start + 18..tcp.payload]
+}
+
+/// Where the headers of a TCP packet end
+#[derive(Debug, Clone, Copy)]
+struct Tcp {
+    ipv6: bool,
+    ip_len: usize,
+    /// Offset of the payload
+    payload: usize,
+}
+
+impl Tcp {
+    /// TCP over IPv4, or over IPv6 without extension headers, unfragmented
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let (ipv6, ip_len) = match packet.first()? >> 4 {
+            4 if packet.len() >= 20 => {
+                let fragmented = u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0;
+                if packet[9] != PROTOCOL_TCP || fragmented {
+                    return None;
+                }
+                (false, usize::from(packet[0] & 0x0f) * 4)
+            }
+            6 if packet.len() >= 40 && packet[6] == PROTOCOL_TCP => (true, 40),
+            _ => return None,
+        };
+        let tcp_len = usize::from(packet.get(ip_len + 12)? >> 4) * 4;
+        let payload = ip_len + tcp_len;
+        let declared = if ipv6 {
+            40 + usize::from(u16::from_be_bytes([packet[4], packet[5]]))
+        } else {
+            usize::from(u16::from_be_bytes([packet[2], packet[3]]))
+        };
+        (ip_len >= 20 && tcp_len >= 20 && payload <= packet.len() && declared == packet.len())
+            .then_some(Self { ipv6, ip_len, payload })
+    }
+
+    fn seq(&self, packet: &[u8]) -> u32 {
+        u32::from_be_bytes(packet[self.ip_len + 4..self.ip_len + 8].try_into().unwrap())
+    }
+
+    /// A data segment with only ACK (and PSH) set, whose checksums hold
+    ///
+    /// Merged segments get new checksums, so a corrupt one must not be
+    /// merged into a valid super-packet.
+    fn mergeable(&self, packet: &[u8]) -> bool {
+        let flags = packet[self.ip_len + 13];
+        let ip_valid = self.ipv6 || fold(sum(&packet[..self.ip_len], 0)) == 0xffff;
+        flags & !TCP_PSH == TCP_ACK
+            && packet.len() > self.payload
+            && ip_valid
+            && fold(sum(&packet[self.ip_len..], self.pseudo_header(packet))) == 0xffff
+    }
+
+    /// Ones' complement sum of the pseudo-header
+    fn pseudo_header(&self, packet: &[u8]) -> u32 {
+        let len = (packet.len() - self.ip_len) as u32;
+        let addresses = if self.ipv6 { &packet[8..40] } else { &packet[12..20] };
+        sum(addresses, u32::from(PROTOCOL_TCP) + (len >> 16) + (len & 0xffff))
+    }
+
+    /// Write the full checksum, or only the pseudo-header sum for the kernel to finish
+    fn set_checksum(&self, packet: &mut [u8], full: bool) {
+        let field = self.ip_len + TCP_CHECKSUM_OFFSET;
+        packet[field..field + 2].fill(0);
+        let pseudo = self.pseudo_header(packet);
+        let checksum = if full { !fold(sum(&packet[self.ip_len..], pseudo)) } else { fold(pseudo) };
+        packet[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// Ones' complement sum of `data` added to `initial`, not yet folded
+fn sum(data: &[u8], initial: u32) -> u32 {
+    data.chunks(2).fold(initial, |sum, chunk| {
+        let word = u32::from(u16::from_be_bytes([chunk[0], chunk.get(1).copied().unwrap_or(0)]));
+        let (sum, carry) = sum.overflowing_add(word);
+        sum + u32::from(carry)
+    })
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IPv4 TCP segment of `payload` at `seq`, checksums filled in
+    fn tcp_segment(seq: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; 40];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+        packet[6] = 0x40;
+        packet[8] = 64;
+        packet[9] = PROTOCOL_TCP;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        packet[20..22].copy_from_slice(&443u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&50000u16.to_be_bytes());
+        packet[24..28].copy_from_slice(&seq.to_be_bytes());
+        packet[28..32].copy_from_slice(&7u32.to_be_bytes());
+        packet[32] = 5 << 4;
+        packet[33] = flags;
+        packet[34..36].copy_from_slice(&65535u16.to_be_bytes());
+        packet.extend_from_slice(payload);
+        let checksum = !fold(sum(&packet[..20], 0));
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        let tcp = Tcp::parse(&packet).unwrap();
+        tcp.set_checksum(&mut packet, true);
+        packet
+    }
+
+    fn frame(header: VirtioNetHdr, packet: &[u8]) -> Vec<u8> {
+        let mut frame = header.to_bytes().to_vec();
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = VirtioNetHdr { flags: 1, gso_type: 4, hdr_len: 60, gso_size: 1440, csum_start: 40, csum_offset: 16 };
+        assert_eq!(VirtioNetHdr::parse(&header.to_bytes()), Some(header));
+        assert_eq!(VirtioNetHdr::parse(&[0; 9]), None);
+    }
+
+    #[test]
+    fn test_split_cuts_super_packets_into_segments() {
+        let payload: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        let super_packet = tcp_segment(1000, TCP_ACK | TCP_PSH, &payload);
+        let header = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            hdr_len: 40,
+            gso_size: 1400,
+            csum_start: 20,
+            csum_offset: 16,
+        };
+        let mut segments = VecDeque::new();
+        split(&frame(header, &super_packet), &mut segments).unwrap();
+
+        // The same segments the kernel would have sent without offload
+        let expected = [
+            (1000, TCP_ACK, &payload[..1400]),
+            (2400, TCP_ACK, &payload[1400..2800]),
+            (3800, TCP_ACK | TCP_PSH, &payload[2800..]),
+        ];
+        assert_eq!(segments.len(), expected.len());
+        for (index, (segment, (seq, flags, chunk))) in segments.iter().zip(expected).enumerate() {
+            let mut want = tcp_segment(seq, flags, chunk);
+            // Consecutive IP IDs
+            want[5] = index as u8;
+            want[10..12].fill(0);
+            let checksum = !fold(sum(&want[..20], 0));
+            want[10..12].copy_from_slice(&checksum.to_be_bytes());
+            assert_eq!(segment, &want, "segment {index}");
+        }
+    }
+
+    #[test]
+    fn test_split_completes_partial_checksums() {
+        let packet = tcp_segment(1, TCP_ACK, b"hello");
+        let mut partial = packet.clone();
+        let tcp = Tcp::parse(&partial).unwrap();
+        tcp.set_checksum(&mut partial, false);
+        assert_ne!(partial, packet);
+
+        let header = VirtioNetHdr { flags: VIRTIO_NET_HDR_F_NEEDS_CSUM, csum_start: 20, csum_offset: 16, ..Default::default() };
+        let mut packets = VecDeque::new();
+        split(&frame(header, &partial), &mut packets).unwrap();
+        assert_eq!(packets, std::slice::from_ref(&packet));
+
+        // Without the flag the packet is taken as it is
+        packets.clear();
+        split(&frame(VirtioNetHdr::default(), &packet), &mut packets).unwrap();
+        assert_eq!(packets, [packet]);
+
+        let udp = VirtioNetHdr { gso_type: 3, ..Default::default() };
+        assert!(split(&frame(udp, &tcp_segment(1, TCP_ACK, b"x")), &mut packets).is_err());
+    }
+
+    #[test]
+    fn test_coalescer_merges_what_split_cuts() {
+        let payload: Vec<u8> = (0..3500u32).map(|i| (i * 7) as u8).collect();
+        let mut coalescer = Coalescer::default();
+        coalescer.push(&tcp_segment(1000, TCP_ACK, &payload[..1400]));
+        coalescer.push(&tcp_segment(2400, TCP_ACK, &payload[1400..2800]));
+        coalescer.push(&tcp_segment(3800, TCP_ACK | TCP_PSH, &payload[2800..]));
+        // Ends the super-packet: the last segment carried PSH
+        coalescer.push(&tcp_segment(4500, TCP_ACK, b"next"));
+        coalescer.flush();
+
+        let merged = coalescer.next_frame().unwrap().to_vec();
+        let header = VirtioNetHdr::parse(&merged).unwrap();
+        assert_eq!(header.gso_type, VIRTIO_NET_HDR_GSO_TCPV4);
+        assert_eq!((header.gso_size, header.hdr_len, header.csum_start), (1400, 40, 20));
+        let mut segments = VecDeque::new();
+        split(&merged, &mut segments).unwrap();
+        let (mut rejoined, mut seqs) = (Vec::new(), Vec::new());
+        for segment in &segments {
+            seqs.push(u32::from_be_bytes(segment[24..28].try_into().unwrap()));
+            rejoined.extend_from_slice(&segment[40..]);
+        }
+        assert_eq!(seqs, [1000, 2400, 3800]);
+        assert_eq!(rejoined, payload);
+        assert_eq!(segments[2][33], TCP_ACK | TCP_PSH);
+
+        coalescer.written();
+        assert_eq!(coalescer.next_frame().unwrap(), frame(VirtioNetHdr::default(), &tcp_segment(4500, TCP_ACK, b"next")));
+        coalescer.written();
+        assert_eq!(coalescer.next_frame(), None);
+    }
+
+    #[test]
+    fn test_coalescer_keeps_other_packets_apart() {
+        let mut coalescer = Coalescer::default();
+        let first = tcp_segment(1, TCP_ACK, &[1; 100]);
+        // A gap in the sequence
+        let gap = tcp_segment(500, TCP_ACK, &[2; 100]);
+        // A corrupt segment
+        let mut corrupt = tcp_segment(600, TCP_ACK, &[3; 100]);
+        corrupt[60] ^= 1;
+        // Control segments
+        let fin = tcp_segment(600, TCP_ACK | TCP_FIN, &[4; 100]);
+        for packet in [&first, &gap, &corrupt, &fin] {
+            coalescer.push(packet);
+        }
+        coalescer.flush();
+        for packet in [&first, &gap, &corrupt, &fin] {
+            assert_eq!(coalescer.next_frame().unwrap(), frame(VirtioNetHdr::default(), packet));
+            coalescer.written();
+        }
+        assert_eq!(coalescer.next_frame(), None);
+    }
+}
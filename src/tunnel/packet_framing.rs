@@ -1,11 +1,51 @@
 // Improved packet framing implementation based on SoftEther VPN
 // This module handles proper encapsulation and framing of packets for VPN tunnels
+// Length handling is fully checked - see the arithmetic_side_effects lint below
+
+#![deny(clippy::arithmetic_side_effects)]
 
 use crate::error::{VpnError as Error, Result};
-use std::net::IpAddr;
+use crate::protocol::pack::Pack;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+
+/// IP version of a tunnelled packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpVersion {
+    V4,
+    V6,
+}
+
+impl IpVersion {
+    /// Version of the IP packet in `packet`, if it is long enough to be one
+    pub fn of(packet: &[u8]) -> Option<Self> {
+        match packet.first()? & 0xf0 {
+            0x40 if packet.len() >= IPV4_HEADER_LEN => Some(Self::V4),
+            0x60 if packet.len() >= IPV6_HEADER_LEN => Some(Self::V6),
+            _ => None,
+        }
+    }
+}
+
+/// Source and destination address of an IPv4 or IPv6 packet
+pub fn packet_addresses(packet: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    let addresses = match IpVersion::of(packet)? {
+        IpVersion::V4 => (
+            IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).ok()?)),
+            IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).ok()?)),
+        ),
+        IpVersion::V6 => (
+            IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?)),
+            IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?)),
+        ),
+    };
+    Some(addresses)
+}
+
 /// Packet header structure
 /// Based on SoftEther's implementation but simplified for our needs
 #[derive(Debug, Clone)]
@@ -18,7 +58,10 @@ pub struct PacketHeader {
 
 impl PacketHeader {
     pub const SIZE: usize = 10; // 1 + 1 + 4 + 4
+    /// Newest framing version this client speaks
     pub const VERSION: u8 = 1;
+    /// Oldest framing version still accepted
+    pub const MIN_VERSION: u8 = 1;
     
     // Packet types
     pub const TYPE_DATA: u8 = 0;      // Regular data packet
@@ -69,79 +112,175 @@ impl PacketHeader {
     }
 }
 
+/// Framing parameters agreed with the server during authentication
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FramingParams {
+    /// Session every frame must carry
+    pub session_id: u32,
+    /// Framing version used for outgoing frames; older ones are still accepted
+    pub version: u8,
+    /// Largest payload in a single frame
+    pub max_frame_size: u32,
+}
+
+impl FramingParams {
+    /// Payload limit when the server does not announce one (an Ethernet frame)
+    pub const DEFAULT_MAX_FRAME_SIZE: u32 = 1514;
+
+    /// Defaults for `session_id`, for servers that negotiate nothing else
+    pub fn new(session_id: u32) -> Self {
+        Self {
+            session_id,
+            version: PacketHeader::VERSION,
+            max_frame_size: Self::DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Read the parameters from the authentication response
+    ///
+    /// Needs the 32-bit session key; the version is capped at the newest one
+    /// supported here and the frame size falls back to the tunnel MTU.
+    pub fn from_pack(pack: &Pack) -> Option<Self> {
+        let session_id = pack.get_int("session_key_32")?;
+        let version = pack
+            .get_int("framing_version")
+            .map_or(PacketHeader::VERSION, |version| {
+                u8::try_from(version).unwrap_or(u8::MAX).clamp(PacketHeader::MIN_VERSION, PacketHeader::VERSION)
+            });
+        let max_frame_size = pack
+            .get_int("max_frame_size")
+            .or_else(|| pack.get_int("mtu"))
+            .filter(|size| *size > 0)
+            .unwrap_or(Self::DEFAULT_MAX_FRAME_SIZE);
+        Some(Self { session_id, version, max_frame_size })
+    }
+}
+
+/// Frames that passed or failed decoding, by reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FramingStats {
+    pub sent_packets: u64,
+    pub received_packets: u64,
+    /// All rejected frames, including those counted below
+    pub errors: u64,
+    /// Frames for a different session
+    pub session_mismatches: u64,
+    /// Frames of an unsupported framing version
+    pub version_mismatches: u64,
+    /// Frames larger than the negotiated maximum
+    pub oversized: u64,
+}
+
 /// PacketFramer - Handles packet framing for the VPN tunnel
 pub struct PacketFramer {
-    session_id: u32,
+    params: FramingParams,
     remote_ip: IpAddr,
     // Stats for debugging
-    sent_packets: u64,
-    received_packets: u64,
-    errors: u64,
+    stats: FramingStats,
 }
 
 impl PacketFramer {
-    pub fn new(session_id: u32, remote_ip: IpAddr) -> Self {
+    pub fn new(params: FramingParams, remote_ip: IpAddr) -> Self {
         Self {
-            session_id,
+            params,
             remote_ip,
-            sent_packets: 0,
-            received_packets: 0,
-            errors: 0,
+            stats: FramingStats::default(),
         }
     }
-    
+
+    /// Parameters this framer was created with
+    pub fn params(&self) -> FramingParams {
+        self.params
+    }
+
+    /// Count a rejected frame and build its error
+    fn reject(&mut self, counter: fn(&mut FramingStats) -> &mut u64, reason: String) -> Error {
+        let count = counter(&mut self.stats);
+        *count = count.saturating_add(1);
+        self.stats.errors = self.stats.errors.saturating_add(1);
+        Error::PacketError(reason)
+    }
+
     /// Frame a packet for sending through the tunnel
-    pub fn frame_packet(&mut self, data: &[u8]) -> Vec<u8> {
-        let header = PacketHeader::new(
-            PacketHeader::TYPE_DATA,
-            self.session_id,
-            data.len() as u32
-        );
+    pub fn frame_packet(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let payload_size = match u32::try_from(data.len()) {
+            Ok(size) if size <= self.params.max_frame_size => size,
+            _ => {
+                return Err(self.reject(
+                    |stats| &mut stats.oversized,
+                    format!("Payload of {} bytes is too large to frame", data.len()),
+                ))
+            }
+        };
+        let header = PacketHeader {
+            version: self.params.version,
+            ..PacketHeader::new(PacketHeader::TYPE_DATA, self.params.session_id, payload_size)
+        };
         
         let mut framed_packet = header.to_bytes();
         framed_packet.extend_from_slice(data);
         
-        self.sent_packets += 1;
-        framed_packet
+        self.stats.sent_packets = self.stats.sent_packets.saturating_add(1);
+        Ok(framed_packet)
     }
     
     /// Decode a received packet
+    ///
+    /// Frames of another session, of a framing version outside
+    /// `MIN_VERSION..=` the negotiated one, or above the negotiated size are
+    /// rejected and counted in [`stats`](Self::stats).
     pub fn decode_packet(&mut self, data: &[u8]) -> Result<(PacketHeader, Vec<u8>)> {
         if data.len() < PacketHeader::SIZE {
-            self.errors += 1;
+            self.stats.errors = self.stats.errors.saturating_add(1);
             return Err(Error::PacketError("Packet too small".into()));
         }
         
-        let header = PacketHeader::from_bytes(&data[0..PacketHeader::SIZE])?;
+        let (header_bytes, payload) = data.split_at(PacketHeader::SIZE);
+        let header = PacketHeader::from_bytes(header_bytes)?;
         
         // Validate header
-        if header.version != PacketHeader::VERSION {
-            self.errors += 1;
-            return Err(Error::PacketError(format!("Invalid packet version: {}", header.version)));
+        if !(PacketHeader::MIN_VERSION..=self.params.version).contains(&header.version) {
+            return Err(self.reject(
+                |stats| &mut stats.version_mismatches,
+                format!("Invalid packet version: {}", header.version),
+            ));
+        }
+
+        if header.session_id != self.params.session_id {
+            return Err(self.reject(
+                |stats| &mut stats.session_mismatches,
+                format!("Frame for session {} on session {}", header.session_id, self.params.session_id),
+            ));
+        }
+
+        if header.payload_size > self.params.max_frame_size {
+            return Err(self.reject(
+                |stats| &mut stats.oversized,
+                format!("Frame of {} bytes exceeds the negotiated {}", header.payload_size, self.params.max_frame_size),
+            ));
         }
         
-        if (header.payload_size as usize) != data.len() - PacketHeader::SIZE {
-            self.errors += 1;
+        // Compare in u64 so a large declared size can't wrap on 32-bit targets
+        if u64::from(header.payload_size) != payload.len() as u64 {
+            self.stats.errors = self.stats.errors.saturating_add(1);
             return Err(Error::PacketError(format!(
                 "Payload size mismatch: expected {}, got {}",
                 header.payload_size,
-                data.len() - PacketHeader::SIZE
+                payload.len()
             )));
         }
         
-        let payload = data[PacketHeader::SIZE..].to_vec();
-        self.received_packets += 1;
+        self.stats.received_packets = self.stats.received_packets.saturating_add(1);
         
-        Ok((header, payload))
+        Ok((header, payload.to_vec()))
     }
     
     /// Create a keepalive packet
     pub fn create_keepalive(&self) -> Vec<u8> {
-        let header = PacketHeader::new(
-            PacketHeader::TYPE_KEEPALIVE,
-            self.session_id,
-            0
-        );
+        let header = PacketHeader {
+            version: self.params.version,
+            ..PacketHeader::new(PacketHeader::TYPE_KEEPALIVE, self.params.session_id, 0)
+        };
         
         header.to_bytes()
     }
@@ -158,29 +297,42 @@ impl PacketFramer {
     
     /// Get current statistics
     pub fn get_stats(&self) -> (u64, u64, u64) {
-        (self.sent_packets, self.received_packets, self.errors)
+        (self.stats.sent_packets, self.stats.received_packets, self.stats.errors)
+    }
+
+    /// All counters, including rejected frames by reason
+    pub fn stats(&self) -> FramingStats {
+        self.stats
     }
 }
 
 /// Thread-safe packet framer wrapper
 pub struct SharedPacketFramer {
+    params: FramingParams,
     inner: Arc<Mutex<PacketFramer>>,
 }
 
 impl SharedPacketFramer {
-    pub fn new(session_id: u32, remote_ip: IpAddr) -> Self {
+    pub fn new(params: FramingParams, remote_ip: IpAddr) -> Self {
         Self {
-            inner: Arc::new(Mutex::new(PacketFramer::new(session_id, remote_ip))),
+            params,
+            inner: Arc::new(Mutex::new(PacketFramer::new(params, remote_ip))),
         }
     }
     
     pub fn clone(&self) -> Self {
         Self {
+            params: self.params,
             inner: Arc::clone(&self.inner),
         }
     }
+
+    /// Parameters the framer was created with
+    pub fn params(&self) -> FramingParams {
+        self.params
+    }
     
-    pub async fn frame_packet(&self, data: &[u8]) -> Vec<u8> {
+    pub async fn frame_packet(&self, data: &[u8]) -> Result<Vec<u8>> {
         let mut framer = self.inner.lock().await;
         framer.frame_packet(data)
     }
@@ -204,4 +356,112 @@ impl SharedPacketFramer {
         let framer = self.inner.lock().await;
         framer.get_stats()
     }
+
+    pub async fn stats(&self) -> FramingStats {
+        let framer = self.inner.lock().await;
+        framer.stats()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn framer() -> PacketFramer {
+        PacketFramer::new(FramingParams::new(42), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+    }
+
+    #[test]
+    fn test_frame_decode_round_trip() {
+        let mut framer = framer();
+        let framed = framer.frame_packet(b"hello").unwrap();
+        let (header, payload) = framer.decode_packet(&framed).unwrap();
+        assert_eq!(header.session_id, 42);
+        assert_eq!(header.payload_size, 5);
+        assert_eq!(payload, b"hello");
+        assert_eq!(framer.get_stats(), (1, 1, 0));
+    }
+
+    #[test]
+    fn test_ip_version_and_addresses() {
+        let mut ipv4 = vec![0u8; 20];
+        ipv4[0] = 0x45;
+        ipv4[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        ipv4[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        assert_eq!(IpVersion::of(&ipv4), Some(IpVersion::V4));
+        assert_eq!(
+            packet_addresses(&ipv4),
+            Some((IpAddr::from([10, 0, 0, 2]), IpAddr::from([1, 1, 1, 1])))
+        );
+
+        let src: Ipv6Addr = "fd00:21::5".parse().unwrap();
+        let dst: Ipv6Addr = "2001:4860:4860::8888".parse().unwrap();
+        let mut ipv6 = vec![0u8; 40];
+        ipv6[0] = 0x60;
+        ipv6[8..24].copy_from_slice(&src.octets());
+        ipv6[24..40].copy_from_slice(&dst.octets());
+        assert_eq!(IpVersion::of(&ipv6), Some(IpVersion::V6));
+        assert_eq!(packet_addresses(&ipv6), Some((IpAddr::V6(src), IpAddr::V6(dst))));
+
+        // Truncated headers and keepalive-style payloads are not IP packets
+        assert_eq!(IpVersion::of(&ipv6[..39]), None);
+        assert_eq!(IpVersion::of(b"hello"), None);
+        assert_eq!(packet_addresses(&[]), None);
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_declared_payload() {
+        // A header claiming u32::MAX bytes must be rejected, not wrap around
+        let mut packet = PacketHeader::new(PacketHeader::TYPE_DATA, 42, u32::MAX).to_bytes();
+        packet.extend_from_slice(b"short");
+
+        let mut framer = framer();
+        assert!(framer.decode_packet(&packet).is_err());
+        assert!(framer.decode_packet(&packet[..4]).is_err());
+        assert_eq!(framer.get_stats(), (0, 0, 2));
+    }
+
+    #[test]
+    fn test_negotiated_params_are_enforced() {
+        let mut pack = Pack::new();
+        pack.add_int("session_key_32", 7);
+        pack.add_int("framing_version", 9);
+        pack.add_int("mtu", 1400);
+        let params = FramingParams::from_pack(&pack).unwrap();
+        assert_eq!(params, FramingParams { session_id: 7, version: PacketHeader::VERSION, max_frame_size: 1400 });
+        assert_eq!(FramingParams::from_pack(&Pack::new()), None);
+
+        let mut framer = PacketFramer::new(params, IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let framed = framer.frame_packet(b"hello").unwrap();
+        assert!(framer.decode_packet(&framed).is_ok());
+
+        // Another session's frame
+        let mut foreign = PacketHeader::new(PacketHeader::TYPE_DATA, 8, 5).to_bytes();
+        foreign.extend_from_slice(b"hello");
+        assert!(framer.decode_packet(&foreign).is_err());
+
+        // A framing version from the future
+        let mut future = framed.clone();
+        future[0] = PacketHeader::VERSION + 1;
+        assert!(framer.decode_packet(&future).is_err());
+
+        // Larger than negotiated, in either direction
+        assert!(framer.frame_packet(&[0u8; 1401]).is_err());
+        let mut oversized = PacketHeader::new(PacketHeader::TYPE_DATA, 7, 1401).to_bytes();
+        oversized.extend_from_slice(&[0u8; 1401]);
+        assert!(framer.decode_packet(&oversized).is_err());
+
+        assert_eq!(
+            framer.stats(),
+            FramingStats {
+                sent_packets: 1,
+                received_packets: 1,
+                errors: 4,
+                session_mismatches: 1,
+                version_mismatches: 1,
+                oversized: 2,
+            }
+        );
+    }
 }
@@ -0,0 +1,364 @@
+//! System change planning
+//!
+//! Before the tunnel touches routes, DNS, kernel parameters or the firewall it
+//! first describes everything it intends to do as a [`ChangePlan`]. A host
+//! application can register a [`SystemChangePlanner`] to review that plan and
+//! veto or rewrite individual items (e.g. MDM-managed machines where DNS must
+//! not be changed). Only approved items are applied.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Broad category of a system change, useful for blanket policies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChangeCategory {
+    Route,
+    Dns,
+    Kernel,
+    Firewall,
+}
+
+/// A single modification of host networking state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SystemChange {
+    /// Add (or replace) a route to `destination` (CIDR notation)
+    AddRoute {
+        destination: String,
+        gateway: Option<String>,
+        interface: Option<String>,
+        metric: Option<u32>,
+    },
+    /// Add a route to `destination` in routing table `table` instead of the main table
+    AddTableRoute {
+        destination: String,
+        gateway: Option<String>,
+        interface: String,
+        table: u32,
+    },
+    /// Send traffic carrying firewall mark `fwmark` to routing table `table`
+    AddRoutingRule { fwmark: u32, table: u32, priority: u32 },
+    /// Send all traffic without firewall mark `fwmark` to routing table `table`
+    ///
+    /// Two rules: the main table without its default route at `priority`,
+    /// then everything unmarked to `table` at `priority + 1`.
+    AddPolicyRules { fwmark: u32, table: u32, priority: u32 },
+    /// Replace the default route
+    SetDefaultRoute {
+        gateway: Option<String>,
+        interface: String,
+        metric: Option<u32>,
+    },
+    /// Assign an additional address to the tunnel interface (e.g. its IPv6 address)
+    AddAddress {
+        interface: String,
+        address: IpAddr,
+        prefix_len: u8,
+    },
+    /// Point name resolution at the given servers
+    SetDns {
+        interface: String,
+        servers: Vec<IpAddr>,
+    },
+    /// Set the connection-specific DNS search domain
+    SetDnsDomain { interface: String, domain: String },
+    /// Point NetBIOS name resolution at the given WINS servers
+    SetWins {
+        interface: String,
+        servers: Vec<Ipv4Addr>,
+    },
+    /// Write a kernel parameter (sysctl)
+    SetSysctl { key: String, value: String },
+    /// Flush all rules of a firewall table
+    FlushFirewallTable { table: String },
+    /// Append a firewall rule to `chain` (optionally in `table`)
+    AddFirewallRule {
+        table: Option<String>,
+        chain: String,
+        args: Vec<String>,
+    },
+}
+
+impl SystemChange {
+    /// Category this change belongs to
+    pub fn category(&self) -> ChangeCategory {
+        match self {
+            SystemChange::AddRoute { .. }
+            | SystemChange::AddTableRoute { .. }
+            | SystemChange::AddRoutingRule { .. }
+            | SystemChange::AddPolicyRules { .. }
+            | SystemChange::SetDefaultRoute { .. }
+            | SystemChange::AddAddress { .. } => ChangeCategory::Route,
+            SystemChange::SetDns { .. }
+            | SystemChange::SetDnsDomain { .. }
+            | SystemChange::SetWins { .. } => ChangeCategory::Dns,
+            SystemChange::SetSysctl { .. } => ChangeCategory::Kernel,
+            SystemChange::FlushFirewallTable { .. } | SystemChange::AddFirewallRule { .. } => {
+                ChangeCategory::Firewall
+            }
+        }
+    }
+}
+
+impl fmt::Display for SystemChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SystemChange::AddRoute { destination, gateway, interface, metric } => {
+                write!(f, "route add {destination}")?;
+                if let Some(gateway) = gateway {
+                    write!(f, " via {gateway}")?;
+                }
+                if let Some(interface) = interface {
+                    write!(f, " dev {interface}")?;
+                }
+                if let Some(metric) = metric {
+                    write!(f, " metric {metric}")?;
+                }
+                Ok(())
+            }
+            SystemChange::AddTableRoute { destination, gateway, interface, table } => {
+                write!(f, "route add {destination}")?;
+                if let Some(gateway) = gateway {
+                    write!(f, " via {gateway}")?;
+                }
+                write!(f, " dev {interface} table {table}")
+            }
+            SystemChange::AddRoutingRule { fwmark, table, priority } => {
+                write!(f, "rule add fwmark {fwmark:#x} lookup {table} priority {priority}")
+            }
+            SystemChange::AddPolicyRules { fwmark, table, priority } => write!(
+                f,
+                "rule add lookup main suppress_prefixlength 0 priority {priority}, \
+                 rule add not fwmark {fwmark:#x} lookup {table} priority {}",
+                priority + 1
+            ),
+            SystemChange::SetDefaultRoute { gateway, interface, metric } => {
+                write!(f, "default route")?;
+                if let Some(gateway) = gateway {
+                    write!(f, " via {gateway}")?;
+                }
+                write!(f, " dev {interface}")?;
+                if let Some(metric) = metric {
+                    write!(f, " metric {metric}")?;
+                }
+                Ok(())
+            }
+            SystemChange::AddAddress { interface, address, prefix_len } => {
+                write!(f, "address add {address}/{prefix_len} dev {interface}")
+            }
+            SystemChange::SetDns { interface, servers } => {
+                let servers: Vec<String> = servers.iter().map(|s| s.to_string()).collect();
+                write!(f, "dns on {interface}: {}", servers.join(", "))
+            }
+            SystemChange::SetDnsDomain { interface, domain } => {
+                write!(f, "dns domain on {interface}: {domain}")
+            }
+            SystemChange::SetWins { interface, servers } => {
+                let servers: Vec<String> = servers.iter().map(|s| s.to_string()).collect();
+                write!(f, "wins on {interface}: {}", servers.join(", "))
+            }
+            SystemChange::SetSysctl { key, value } => write!(f, "sysctl {key}={value}"),
+            SystemChange::FlushFirewallTable { table } => write!(f, "firewall flush table {table}"),
+            SystemChange::AddFirewallRule { table, chain, args } => {
+                write!(f, "firewall")?;
+                if let Some(table) = table {
+                    write!(f, " -t {table}")?;
+                }
+                write!(f, " -A {chain} {}", args.join(" "))
+            }
+        }
+    }
+}
+
+/// Host decision for a planned change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeDecision {
+    Approved,
+    Vetoed { reason: String },
+}
+
+/// A proposed change together with the host's decision
+#[derive(Debug, Clone)]
+pub struct PlannedChange {
+    pub change: SystemChange,
+    pub decision: ChangeDecision,
+}
+
+impl PlannedChange {
+    pub fn is_approved(&self) -> bool {
+        self.decision == ChangeDecision::Approved
+    }
+}
+
+/// Ordered set of changes the tunnel intends to make
+///
+/// All items start out approved; a planner can veto or replace them by index.
+#[derive(Debug, Clone, Default)]
+pub struct ChangePlan {
+    items: Vec<PlannedChange>,
+}
+
+impl ChangePlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append a change to the plan (approved by default)
+    pub fn push(&mut self, change: SystemChange) {
+        self.items.push(PlannedChange { change, decision: ChangeDecision::Approved });
+    }
+
+    /// All planned changes in application order
+    pub fn items(&self) -> &[PlannedChange] {
+        &self.items
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Veto a single change; returns false if the index is out of range
+    pub fn veto(&mut self, index: usize, reason: &str) -> bool {
+        match self.items.get_mut(index) {
+            Some(item) => {
+                item.decision = ChangeDecision::Vetoed { reason: reason.to_string() };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Veto every change in a category, returning how many were affected
+    pub fn veto_category(&mut self, category: ChangeCategory, reason: &str) -> usize {
+        let mut count = 0;
+        for item in self.items.iter_mut().filter(|i| i.change.category() == category) {
+            item.decision = ChangeDecision::Vetoed { reason: reason.to_string() };
+            count += 1;
+        }
+        count
+    }
+
+    /// Replace a change with a host-modified version; returns false if out of range
+    pub fn replace(&mut self, index: usize, change: SystemChange) -> bool {
+        match self.items.get_mut(index) {
+            Some(item) => {
+                item.change = change;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Changes that should be applied
+    pub fn approved(&self) -> impl Iterator<Item = &SystemChange> {
+        self.items.iter().filter(|i| i.is_approved()).map(|i| &i.change)
+    }
+
+    /// Changes the host refused, with the reason given
+    pub fn vetoed(&self) -> impl Iterator<Item = (&SystemChange, &str)> {
+        self.items.iter().filter_map(|i| match &i.decision {
+            ChangeDecision::Vetoed { reason } => Some((&i.change, reason.as_str())),
+            ChangeDecision::Approved => None,
+        })
+    }
+}
+
+/// Host hook that reviews system changes before they are applied
+///
+/// Implementations run synchronously on the thread establishing the tunnel
+/// and must not block for long.
+pub trait SystemChangePlanner: Send + Sync {
+    fn review(&self, plan: &mut ChangePlan);
+}
+
+impl<F> SystemChangePlanner for F
+where
+    F: Fn(&mut ChangePlan) + Send + Sync,
+{
+    fn review(&self, plan: &mut ChangePlan) {
+        self(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_plan() -> ChangePlan {
+        let mut plan = ChangePlan::new();
+        plan.push(SystemChange::SetDefaultRoute {
+            gateway: Some("10.0.0.1".to_string()),
+            interface: "vpnse0".to_string(),
+            metric: None,
+        });
+        plan.push(SystemChange::SetDns {
+            interface: "vpnse0".to_string(),
+            servers: vec![IpAddr::from([10, 0, 0, 1])],
+        });
+        plan.push(SystemChange::FlushFirewallTable { table: "nat".to_string() });
+        plan
+    }
+
+    #[test]
+    fn test_planner_can_veto_and_replace() {
+        let planner = |plan: &mut ChangePlan| {
+            plan.veto_category(ChangeCategory::Firewall, "managed by MDM");
+            plan.replace(
+                1,
+                SystemChange::SetDns {
+                    interface: "vpnse0".to_string(),
+                    servers: vec![IpAddr::from([192, 168, 1, 53])],
+                },
+            );
+        };
+
+        let mut plan = sample_plan();
+        planner.review(&mut plan);
+
+        let approved: Vec<_> = plan.approved().collect();
+        assert_eq!(approved.len(), 2);
+        assert!(matches!(approved[1], SystemChange::SetDns { servers, .. } if servers[0] == IpAddr::from([192, 168, 1, 53])));
+
+        let vetoed: Vec<_> = plan.vetoed().collect();
+        assert_eq!(vetoed.len(), 1);
+        assert_eq!(vetoed[0].1, "managed by MDM");
+    }
+
+    #[test]
+    fn test_out_of_range_edits_are_rejected() {
+        let mut plan = sample_plan();
+        assert!(!plan.veto(10, "nope"));
+        assert!(!plan.replace(10, SystemChange::FlushFirewallTable { table: "filter".to_string() }));
+        assert_eq!(plan.approved().count(), 3);
+    }
+
+    #[test]
+    fn test_change_display() {
+        let change = SystemChange::AddRoute {
+            destination: "0.0.0.0/1".to_string(),
+            gateway: Some("10.0.0.1".to_string()),
+            interface: Some("vpnse0".to_string()),
+            metric: Some(50),
+        };
+        assert_eq!(change.to_string(), "route add 0.0.0.0/1 via 10.0.0.1 dev vpnse0 metric 50");
+        assert_eq!(change.category(), ChangeCategory::Route);
+
+        let change = SystemChange::SetWins {
+            interface: "vpnse0".to_string(),
+            servers: vec![Ipv4Addr::new(10, 21, 0, 5), Ipv4Addr::new(10, 21, 0, 6)],
+        };
+        assert_eq!(change.to_string(), "wins on vpnse0: 10.21.0.5, 10.21.0.6");
+        assert_eq!(change.category(), ChangeCategory::Dns);
+
+        let change = SystemChange::AddAddress {
+            interface: "vpnse0".to_string(),
+            address: "fd00:21::5".parse().unwrap(),
+            prefix_len: 64,
+        };
+        assert_eq!(change.to_string(), "address add fd00:21::5/64 dev vpnse0");
+        assert_eq!(change.category(), ChangeCategory::Route);
+    }
+}
@@ -0,0 +1,1126 @@
+//! Platform operations
+//!
+//! Every change `TunnelManager` makes to the host - routes, DNS, kernel
+//! parameters, firewall rules, interfaces - goes through [`PlatformOps`], as
+//! do the few queries route planning depends on. [`SystemOps`] performs them
+//! for the OS it was built for (on Linux, routes and sysctls go through the
+//! netlink backend in [`super::routing`]). [`RecordingOps`] only records what it
+//! was asked to do, so tests can assert the exact sequence of operations for
+//! Linux, macOS or Windows without root and without touching the machine.
+
+use super::killswitch::KillSwitchPolicy;
+use super::plan::SystemChange;
+use crate::error::{Result, VpnError};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Mutex;
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use std::process::Command;
+
+#[cfg(target_os = "linux")]
+use super::dns::DnsManager;
+#[cfg(any(target_os = "linux", target_os = "macos", target_os = "windows"))]
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use super::routing::linux::{self as routing, Netlink};
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use super::killswitch;
+
+/// Operating system family the operations target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Linux,
+    MacOs,
+    Windows,
+    Other,
+}
+
+impl Platform {
+    /// Platform this binary was built for
+    pub fn current() -> Self {
+        if cfg!(target_os = "linux") {
+            Platform::Linux
+        } else if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else if cfg!(target_os = "windows") {
+            Platform::Windows
+        } else {
+            Platform::Other
+        }
+    }
+}
+
+/// Host networking queries and mutations used by `TunnelManager`
+pub trait PlatformOps: Send + Sync {
+    /// Platform whose route/DNS/firewall model the plan should follow
+    fn platform(&self) -> Platform;
+
+    /// Gateway of the current default route
+    fn default_gateway(&self) -> Option<String>;
+
+    /// Gateway and interface currently used for internet traffic
+    fn underlay_route(&self) -> (String, String);
+
+    /// Gateway and interface of the IPv6 default route, if the host has one
+    fn underlay_route_v6(&self) -> Option<(String, String)>;
+
+    /// Active interfaces of other VPN software
+    fn vpn_interfaces(&self, own_interface: &str) -> Vec<String>;
+
+    /// Path MTU towards `server`, if it can be probed
+    fn path_mtu(&self, server: IpAddr) -> Option<u16>;
+
+    /// Interface the host would send traffic for `destination` through, if it can tell
+    fn route_interface(&self, destination: IpAddr) -> Option<String>;
+
+    /// Apply one approved item of a change plan
+    fn apply(&self, change: &SystemChange) -> Result<()>;
+
+    /// Remove a route added by [`SystemChange::AddRoute`]
+    fn delete_route(&self, destination: &str, interface: Option<&str>) -> Result<()>;
+
+    /// Remove a rule added by [`SystemChange::AddFirewallRule`]
+    fn delete_firewall_rule(&self, table: Option<&str>, chain: &str, args: &[String]) -> Result<()>;
+
+    /// Remove a route added by [`SystemChange::AddTableRoute`]
+    fn delete_table_route(&self, destination: &str, table: u32) -> Result<()>;
+
+    /// Remove a rule added by [`SystemChange::AddRoutingRule`]
+    fn delete_routing_rule(&self, fwmark: u32, table: u32, priority: u32) -> Result<()>;
+
+    /// Remove the rules added by [`SystemChange::AddPolicyRules`]
+    fn delete_policy_rules(&self, fwmark: u32, table: u32, priority: u32) -> Result<()>;
+
+    /// Put the original default route back after the tunnel replaced it
+    fn restore_default_route(&self, gateway: &str, interface: &str) -> Result<()>;
+
+    /// Drop all outgoing traffic `policy` does not allow, replacing earlier kill switch rules
+    fn enable_kill_switch(&self, policy: &KillSwitchPolicy) -> Result<()>;
+
+    /// Remove the rules installed by [`enable_kill_switch`](Self::enable_kill_switch)
+    fn disable_kill_switch(&self) -> Result<()>;
+
+    /// Undo [`SystemChange::SetDns`]
+    fn restore_dns(&self, interface: &str) -> Result<()>;
+
+    /// Bring a freshly created tunnel interface up towards `peer`
+    fn bring_up_interface(&self, name: &str, peer: Ipv4Addr) -> Result<()>;
+
+    /// Delete the tunnel interface
+    fn delete_interface(&self, name: &str) -> Result<()>;
+}
+
+/// A call made on [`RecordingOps`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlatformOp {
+    Apply(SystemChange),
+    DeleteRoute {
+        destination: String,
+        interface: Option<String>,
+    },
+    DeleteFirewallRule {
+        table: Option<String>,
+        chain: String,
+        args: Vec<String>,
+    },
+    DeleteTableRoute {
+        destination: String,
+        table: u32,
+    },
+    DeleteRoutingRule {
+        fwmark: u32,
+        table: u32,
+        priority: u32,
+    },
+    DeletePolicyRules {
+        fwmark: u32,
+        table: u32,
+        priority: u32,
+    },
+    RestoreDefaultRoute {
+        gateway: String,
+        interface: String,
+    },
+    EnableKillSwitch(KillSwitchPolicy),
+    DisableKillSwitch,
+    RestoreDns {
+        interface: String,
+    },
+    BringUpInterface {
+        name: String,
+        peer: Ipv4Addr,
+    },
+    DeleteInterface {
+        name: String,
+    },
+}
+
+/// Test double that records operations instead of performing them
+///
+/// Queries answer from canned values set with the `with_*` methods.
+#[derive(Debug)]
+pub struct RecordingOps {
+    platform: Platform,
+    default_gateway: Option<String>,
+    underlay_route: (String, String),
+    underlay_route_v6: Option<(String, String)>,
+    vpn_interfaces: Vec<String>,
+    path_mtu: Option<u16>,
+    route_lookups: Vec<(IpAddr, String)>,
+    failing: Option<SystemChange>,
+    recorded: Mutex<Vec<PlatformOp>>,
+}
+
+impl RecordingOps {
+    /// Recorder for `platform` with no default route, no other VPNs, an
+    /// underlay of `192.168.1.1` on `eth0` and no IPv6 default route
+    pub fn new(platform: Platform) -> Self {
+        Self {
+            platform,
+            default_gateway: None,
+            underlay_route: ("192.168.1.1".to_string(), "eth0".to_string()),
+            underlay_route_v6: None,
+            vpn_interfaces: Vec::new(),
+            path_mtu: None,
+            route_lookups: Vec::new(),
+            failing: None,
+            recorded: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn with_default_gateway(mut self, gateway: &str) -> Self {
+        self.default_gateway = Some(gateway.to_string());
+        self
+    }
+
+    pub fn with_underlay_route(mut self, gateway: &str, interface: &str) -> Self {
+        self.underlay_route = (gateway.to_string(), interface.to_string());
+        self
+    }
+
+    pub fn with_underlay_route_v6(mut self, gateway: &str, interface: &str) -> Self {
+        self.underlay_route_v6 = Some((gateway.to_string(), interface.to_string()));
+        self
+    }
+
+    pub fn with_vpn_interfaces(mut self, interfaces: &[&str]) -> Self {
+        self.vpn_interfaces = interfaces.iter().map(|name| name.to_string()).collect();
+        self
+    }
+
+    pub fn with_path_mtu(mut self, mtu: u16) -> Self {
+        self.path_mtu = Some(mtu);
+        self
+    }
+
+    /// Answer route lookups for `destination` with `interface`; others find nothing
+    pub fn with_route_lookup(mut self, destination: &str, interface: &str) -> Self {
+        let destination = destination.parse().expect("route lookup destination must be an IP address");
+        self.route_lookups.push((destination, interface.to_string()));
+        self
+    }
+
+    /// Make applying `change` fail (without recording it)
+    pub fn failing_on(mut self, change: SystemChange) -> Self {
+        self.failing = Some(change);
+        self
+    }
+
+    /// Operations recorded so far, in call order
+    pub fn recorded(&self) -> Vec<PlatformOp> {
+        self.recorded.lock().unwrap().clone()
+    }
+
+    /// Forget the operations recorded so far
+    pub fn clear(&self) {
+        self.recorded.lock().unwrap().clear();
+    }
+
+    fn record(&self, op: PlatformOp) -> Result<()> {
+        self.recorded.lock().unwrap().push(op);
+        Ok(())
+    }
+}
+
+impl PlatformOps for RecordingOps {
+    fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    fn default_gateway(&self) -> Option<String> {
+        self.default_gateway.clone()
+    }
+
+    fn underlay_route(&self) -> (String, String) {
+        self.underlay_route.clone()
+    }
+
+    fn underlay_route_v6(&self) -> Option<(String, String)> {
+        self.underlay_route_v6.clone()
+    }
+
+    fn vpn_interfaces(&self, own_interface: &str) -> Vec<String> {
+        self.vpn_interfaces
+            .iter()
+            .filter(|name| *name != own_interface)
+            .cloned()
+            .collect()
+    }
+
+    fn path_mtu(&self, _server: IpAddr) -> Option<u16> {
+        self.path_mtu
+    }
+
+    fn route_interface(&self, destination: IpAddr) -> Option<String> {
+        self.route_lookups
+            .iter()
+            .find(|(address, _)| *address == destination)
+            .map(|(_, interface)| interface.clone())
+    }
+
+    fn apply(&self, change: &SystemChange) -> Result<()> {
+        if self.failing.as_ref() == Some(change) {
+            return Err(VpnError::Platform(format!("Simulated failure: {change}")));
+        }
+        self.record(PlatformOp::Apply(change.clone()))
+    }
+
+    fn delete_route(&self, destination: &str, interface: Option<&str>) -> Result<()> {
+        self.record(PlatformOp::DeleteRoute {
+            destination: destination.to_string(),
+            interface: interface.map(str::to_string),
+        })
+    }
+
+    fn delete_firewall_rule(&self, table: Option<&str>, chain: &str, args: &[String]) -> Result<()> {
+        self.record(PlatformOp::DeleteFirewallRule {
+            table: table.map(str::to_string),
+            chain: chain.to_string(),
+            args: args.to_vec(),
+        })
+    }
+
+    fn delete_table_route(&self, destination: &str, table: u32) -> Result<()> {
+        self.record(PlatformOp::DeleteTableRoute { destination: destination.to_string(), table })
+    }
+
+    fn delete_routing_rule(&self, fwmark: u32, table: u32, priority: u32) -> Result<()> {
+        self.record(PlatformOp::DeleteRoutingRule { fwmark, table, priority })
+    }
+
+    fn delete_policy_rules(&self, fwmark: u32, table: u32, priority: u32) -> Result<()> {
+        self.record(PlatformOp::DeletePolicyRules { fwmark, table, priority })
+    }
+
+    fn restore_default_route(&self, gateway: &str, interface: &str) -> Result<()> {
+        self.record(PlatformOp::RestoreDefaultRoute {
+            gateway: gateway.to_string(),
+            interface: interface.to_string(),
+        })
+    }
+
+    fn enable_kill_switch(&self, policy: &KillSwitchPolicy) -> Result<()> {
+        self.record(PlatformOp::EnableKillSwitch(policy.clone()))
+    }
+
+    fn disable_kill_switch(&self) -> Result<()> {
+        self.record(PlatformOp::DisableKillSwitch)
+    }
+
+    fn restore_dns(&self, interface: &str) -> Result<()> {
+        self.record(PlatformOp::RestoreDns { interface: interface.to_string() })
+    }
+
+    fn bring_up_interface(&self, name: &str, peer: Ipv4Addr) -> Result<()> {
+        self.record(PlatformOp::BringUpInterface { name: name.to_string(), peer })
+    }
+
+    fn delete_interface(&self, name: &str) -> Result<()> {
+        self.record(PlatformOp::DeleteInterface { name: name.to_string() })
+    }
+}
+
+/// Operations on the real host, through `ip`/`iptables`/`busctl` on
+/// Linux, `route`/`networksetup` on macOS and `netsh`/PowerShell on Windows
+#[derive(Debug, Default, Clone)]
+pub struct SystemOps {
+    in_memory_only: bool,
+    strict: bool,
+    strict_dns: bool,
+    /// DNS applied per interface (see [`super::dns`])
+    #[cfg(target_os = "linux")]
+    dns: Arc<Mutex<DnsManager>>,
+    /// Reference on pf taken by the engaged kill switch (`pfctl -E` token)
+    #[cfg(target_os = "macos")]
+    pf_token: Arc<Mutex<Option<String>>>,
+    /// Default outbound actions the engaged kill switch replaced
+    #[cfg(target_os = "windows")]
+    outbound_actions: Arc<Mutex<Option<super::killswitch::OutboundActions>>>,
+}
+
+impl SystemOps {
+    /// Operations that never write files (no resolv.conf rewrite, backups or
+    /// resolved drop-ins)
+    ///
+    /// DNS is configured per link through systemd-resolved only; hosts without
+    /// it get [`VpnError::CapabilityUnavailable`](crate::error::VpnError) instead
+    /// of a rewritten resolv.conf.
+    pub fn in_memory_only() -> Self {
+        Self { in_memory_only: true, ..Self::default() }
+    }
+
+    /// Fail changes whose commands fail instead of warning and continuing
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Resolve names only through the tunnel, blocking DNS on other interfaces (Linux)
+    pub fn with_strict_dns(mut self, strict_dns: bool) -> Self {
+        self.strict_dns = strict_dns;
+        self
+    }
+
+    /// Outcome of a command run while applying a change: failures only count when strict
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    fn checked(&self, result: Result<()>) -> Result<()> {
+        strict_result(self.strict, result)
+    }
+
+    /// Outcome of a DNS change: failures also count with `strict_dns`, which
+    /// promises no leaks, and in-memory-only mode refusing the host's backend
+    #[cfg(target_os = "linux")]
+    fn dns_checked(&self, result: Result<()>) -> Result<()> {
+        match result {
+            Err(e @ VpnError::CapabilityUnavailable(_)) => Err(e),
+            result => strict_result(self.strict || self.strict_dns, result),
+        }
+    }
+}
+
+impl PlatformOps for SystemOps {
+    fn platform(&self) -> Platform {
+        Platform::current()
+    }
+
+    fn default_gateway(&self) -> Option<String> {
+        #[cfg(target_os = "macos")]
+        {
+            let output = Command::new("route").args(["-n", "get", "default"]).output().ok()?;
+            if output.status.success() {
+                let route_info = String::from_utf8_lossy(&output.stdout);
+                return route_info
+                    .lines()
+                    .find(|line| line.trim().starts_with("gateway:"))
+                    .and_then(|line| line.split(':').nth(1))
+                    .map(|gateway| gateway.trim().to_string());
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let output = Command::new("ip").args(["route", "show", "default"]).output().ok()?;
+            if output.status.success() {
+                let route_info = String::from_utf8_lossy(&output.stdout);
+                if let Some(via_pos) = route_info.find("via ") {
+                    let after_via = &route_info[via_pos + 4..];
+                    if let Some(space_pos) = after_via.find(' ') {
+                        return Some(after_via[..space_pos].to_string());
+                    }
+                }
+            }
+        }
+
+        #[cfg(target_os = "windows")]
+        if let Some((gateway, _)) = super::windows::default_route(false) {
+            return Some(gateway);
+        }
+
+        None
+    }
+
+    fn underlay_route(&self) -> (String, String) {
+        #[cfg(target_os = "linux")]
+        {
+            let if_output = Command::new("ip").args(["route", "get", "8.8.8.8"]).output();
+
+            let active_interface = if let Ok(output) = if_output {
+                let out_str = String::from_utf8_lossy(&output.stdout);
+                // Extract "dev X" from output
+                let pattern = "dev ";
+                if let Some(pos) = out_str.find(pattern) {
+                    let after_dev = &out_str[pos + pattern.len()..];
+                    after_dev.split_whitespace().next().unwrap_or("eth0").to_string()
+                } else {
+                    "eth0".to_string()
+                }
+            } else {
+                "eth0".to_string()
+            };
+
+            let gw_output = Command::new("ip").args(["route", "show", "default"]).output();
+            let default_gw = if let Ok(output) = gw_output {
+                let route_info = String::from_utf8_lossy(&output.stdout);
+
+                // Extract the gateway IP address using regex
+                let re = regex::Regex::new(r"default\s+via\s+(\d+\.\d+\.\d+\.\d+)").unwrap();
+                if let Some(caps) = re.captures(&route_info) {
+                    caps.get(1).unwrap().as_str().to_string()
+                } else {
+                    // Fallback to simple string parsing if regex fails
+                    route_info
+                        .split_whitespace()
+                        .skip_while(|&word| word != "via")
+                        .nth(1)
+                        .unwrap_or("192.168.1.1")
+                        .to_string()
+                }
+            } else {
+                "192.168.1.1".to_string()
+            };
+
+            (default_gw, active_interface)
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            super::windows::default_route(false).unwrap_or_else(|| ("192.168.1.1".to_string(), String::new()))
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+        {
+            (self.default_gateway().unwrap_or_else(|| "192.168.1.1".to_string()), String::new())
+        }
+    }
+
+    fn underlay_route_v6(&self) -> Option<(String, String)> {
+        #[cfg(target_os = "linux")]
+        {
+            let routes = Netlink::open().and_then(|mut netlink| netlink.default_routes_v6()).ok()?;
+            let route = routes.into_iter().min_by_key(|route| route.metric.unwrap_or(0))?;
+            Some((route.gateway?.to_string(), routing::interface_name(route.oif?)?))
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            let output = Command::new("route").args(["-n", "get", "-inet6", "default"]).output().ok()?;
+            let route_info = String::from_utf8_lossy(&output.stdout);
+            let field = |name: &str| {
+                route_info
+                    .lines()
+                    .find_map(|line| line.trim().strip_prefix(name))
+                    .map(|value| value.trim().to_string())
+            };
+            Some((field("gateway:")?, field("interface:")?))
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            super::windows::default_route(true)
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+        None
+    }
+
+    fn vpn_interfaces(&self, own_interface: &str) -> Vec<String> {
+        super::coexistence::detect_vpn_interfaces(own_interface)
+    }
+
+    fn path_mtu(&self, server: IpAddr) -> Option<u16> {
+        super::pmtu::probe(server)
+    }
+
+    fn route_interface(&self, destination: IpAddr) -> Option<String> {
+        #[cfg(target_os = "linux")]
+        {
+            let route = Netlink::open().and_then(|mut netlink| netlink.route_get(destination)).ok()?;
+            routing::interface_name(route.oif?)
+        }
+
+        #[cfg(target_os = "macos")]
+        {
+            let family = if destination.is_ipv6() { "-inet6" } else { "-inet" };
+            let output = Command::new("route").args(["-n", "get", family, &destination.to_string()]).output().ok()?;
+            String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .find_map(|line| line.trim().strip_prefix("interface:"))
+                .map(|interface| interface.trim().to_string())
+        }
+
+        #[cfg(target_os = "windows")]
+        {
+            super::windows::route_interface(destination)
+        }
+
+        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+        {
+            let _ = destination;
+            None
+        }
+    }
+
+    fn apply(&self, change: &SystemChange) -> Result<()> {
+        log::debug!("Applying system change: {}", change);
+
+        match change {
+            #[cfg(target_os = "linux")]
+            SystemChange::SetDns { interface, servers } => {
+                let mut dns = self.dns.lock().unwrap_or_else(|e| e.into_inner());
+                return self.dns_checked(dns.set_servers(interface, servers, self.in_memory_only, self.strict_dns));
+            }
+            #[cfg(not(target_os = "linux"))]
+            SystemChange::SetDns { .. } if self.strict_dns => {
+                return Err(VpnError::CapabilityUnavailable("strict_dns is only supported on Linux".to_string()));
+            }
+            #[cfg(not(target_os = "linux"))]
+            SystemChange::SetDns { interface, servers } => return set_dns(interface, servers, self.strict),
+            #[cfg(target_os = "linux")]
+            SystemChange::SetDnsDomain { interface, domain } => {
+                let mut dns = self.dns.lock().unwrap_or_else(|e| e.into_inner());
+                return self.dns_checked(dns.set_domain(interface, domain, self.in_memory_only, self.strict_dns));
+            }
+            #[cfg(target_os = "windows")]
+            SystemChange::SetDnsDomain { interface, domain } => return super::windows::set_dns_suffix(interface, domain),
+            #[cfg(target_os = "windows")]
+            SystemChange::SetWins { interface, servers } => return super::windows::set_wins_servers(interface, servers),
+            #[cfg(target_os = "linux")]
+            SystemChange::AddRoute { destination, gateway, interface, metric } => {
+                let route = netlink_route(destination, gateway.as_deref(), interface.as_deref(), *metric)?;
+                Netlink::open()?.replace_route(&route)?;
+                log::info!("Added route {}", destination);
+            }
+            #[cfg(target_os = "linux")]
+            SystemChange::AddTableRoute { destination, gateway, interface, table } => {
+                let route = routing::Route {
+                    table: *table,
+                    ..netlink_route(destination, gateway.as_deref(), Some(interface), None)?
+                };
+                Netlink::open()?.replace_route(&route)?;
+                log::info!("Added route {} to table {}", destination, table);
+            }
+            #[cfg(target_os = "linux")]
+            SystemChange::AddRoutingRule { fwmark, table, priority } => {
+                Netlink::open()?.add_rule(&fwmark_rule(*fwmark, *table, *priority))?;
+                log::info!("Added rule for mark {:#x} to table {}", fwmark, table);
+            }
+            #[cfg(target_os = "linux")]
+            SystemChange::AddPolicyRules { fwmark, table, priority } => {
+                let mut netlink = Netlink::open()?;
+                netlink.add_rule(&main_table_rule(*priority))?;
+                netlink.add_rule(&unmarked_rule(*fwmark, *table, *priority + 1))?;
+                log::info!("Added rules sending traffic without mark {:#x} to table {}", fwmark, table);
+            }
+            #[cfg(target_os = "linux")]
+            SystemChange::SetDefaultRoute { gateway, interface, metric } => {
+                let route = netlink_route("default", gateway.as_deref(), Some(interface), *metric)?;
+                Netlink::open()?.replace_default_route(&route)?;
+                log::info!(interface = interface.as_str(); "Set VPN tunnel as default gateway");
+            }
+            #[cfg(target_os = "linux")]
+            SystemChange::AddAddress { interface, address, prefix_len } => {
+                Netlink::open()?.add_address(routing::interface_index(interface)?, *address, *prefix_len)?;
+                log::info!("Added address {}/{} on {}", address, prefix_len, interface);
+            }
+            #[cfg(target_os = "linux")]
+            SystemChange::SetSysctl { key, value } => {
+                let previous = routing::write_sysctl(key, value)?;
+                log::info!("Set {}={} (was {})", key, value, previous);
+            }
+            #[cfg(target_os = "linux")]
+            SystemChange::FlushFirewallTable { table } => {
+                self.checked(run_privileged(&["iptables", "-t", table, "-F"], &format!("Flushed iptables table {}", table)))?;
+            }
+            #[cfg(target_os = "linux")]
+            SystemChange::AddFirewallRule { table, chain, args } => {
+                let mut cmd = vec!["iptables"];
+                if let Some(table) = table {
+                    cmd.extend(["-t", table.as_str()]);
+                }
+                cmd.extend(["-A", chain.as_str()]);
+                cmd.extend(args.iter().map(String::as_str));
+                self.checked(run_privileged(&cmd, &format!("Added iptables {} rule", chain)))?;
+            }
+            #[cfg(target_os = "macos")]
+            SystemChange::AddRoute { destination, gateway, interface, .. } => {
+                let mut args = vec!["route", "add"];
+                if destination.contains(':') {
+                    args.push("-inet6");
+                }
+                args.push(destination.as_str());
+                if let Some(gateway) = gateway {
+                    args.push(gateway.as_str());
+                } else if let Some(interface) = interface {
+                    args.extend(["-interface", interface.as_str()]);
+                }
+                route_result(run_privileged(&args, &format!("Added route {}", destination)))?;
+            }
+            #[cfg(target_os = "macos")]
+            SystemChange::AddAddress { interface, address, prefix_len } => {
+                let address = address.to_string();
+                let prefix_len = prefix_len.to_string();
+                let family = if address.contains(':') { "inet6" } else { "inet" };
+                self.checked(run_privileged(
+                    &["ifconfig", interface, family, &address, "prefixlen", &prefix_len, "alias"],
+                    &format!("Added address {}/{} on {}", address, prefix_len, interface),
+                ))?;
+            }
+            #[cfg(target_os = "windows")]
+            SystemChange::AddAddress { interface, address, prefix_len } => {
+                return super::windows::add_address(interface, *address, *prefix_len);
+            }
+            #[cfg(target_os = "windows")]
+            SystemChange::AddRoute { destination, gateway, interface, metric } => {
+                route_result(super::windows::add_route(destination, gateway.as_deref(), interface.as_deref(), *metric))?;
+            }
+            #[cfg(target_os = "macos")]
+            SystemChange::SetDefaultRoute { gateway, interface, .. } => {
+                let _ = Command::new("sudo").args(["route", "delete", "default"]).output();
+                let mut args = vec!["route", "add", "default"];
+                match gateway {
+                    Some(gateway) => args.push(gateway.as_str()),
+                    None => args.extend(["-interface", interface.as_str()]),
+                }
+                route_result(run_privileged(&args, "Set VPN tunnel as default gateway"))?;
+            }
+            #[allow(unreachable_patterns)]
+            other => {
+                log::warn!("Change not supported on this platform: {}", other);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn delete_route(&self, destination: &str, interface: Option<&str>) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let route = netlink_route(destination, None, interface, None)?;
+            Netlink::open()?.delete_route(&route)?;
+            log::info!("Removed route {}", destination);
+        }
+        #[cfg(target_os = "macos")]
+        {
+            let _ = interface;
+            let mut args = vec!["route", "delete"];
+            if destination.contains(':') {
+                args.push("-inet6");
+            }
+            args.push(destination);
+            let _ = run_privileged(&args, &format!("Removed route {}", destination));
+        }
+        #[cfg(target_os = "windows")]
+        {
+            let _ = super::windows::delete_route(destination, interface);
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+        {
+            let _ = interface;
+            log::warn!("Route removal not supported on this platform: {}", destination);
+        }
+        Ok(())
+    }
+
+    fn delete_firewall_rule(&self, table: Option<&str>, chain: &str, args: &[String]) -> Result<()> {
+        let mut cmd = vec!["iptables"];
+        if let Some(table) = table {
+            cmd.extend(["-t", table]);
+        }
+        cmd.extend(["-D", chain]);
+        cmd.extend(args.iter().map(String::as_str));
+        #[cfg(target_os = "linux")]
+        let _ = run_privileged(&cmd, &format!("Removed iptables {} rule", chain));
+        #[cfg(not(target_os = "linux"))]
+        log::warn!("Rule removal not supported on this platform: {}", cmd.join(" "));
+        Ok(())
+    }
+
+    fn delete_table_route(&self, destination: &str, table: u32) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let route = routing::Route { table, ..routing::Route::to(destination)? };
+            Netlink::open()?.delete_route(&route)?;
+            log::info!("Removed route {} from table {}", destination, table);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (destination, table);
+        Ok(())
+    }
+
+    fn delete_routing_rule(&self, fwmark: u32, table: u32, priority: u32) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            Netlink::open()?.delete_rule(&fwmark_rule(fwmark, table, priority))?;
+            log::info!("Removed rule for mark {:#x}", fwmark);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (fwmark, table, priority);
+        Ok(())
+    }
+
+    fn delete_policy_rules(&self, fwmark: u32, table: u32, priority: u32) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let mut netlink = Netlink::open()?;
+            // Drop the catch-all first, so nothing is left routed into an emptied table
+            netlink.delete_rule(&unmarked_rule(fwmark, table, priority + 1))?;
+            netlink.delete_rule(&main_table_rule(priority))?;
+            log::info!("Removed rules for table {}", table);
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (fwmark, table, priority);
+        Ok(())
+    }
+
+    fn restore_default_route(&self, gateway: &str, interface: &str) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let mut netlink = Netlink::open()?;
+            // The tunnel interface may already be gone, taking its routes with it
+            if let Ok(index) = routing::interface_index(interface) {
+                netlink.delete_default_routes_via(index)?;
+            }
+            netlink.replace_route(&netlink_route("default", Some(gateway), None, None)?)?;
+            log::info!("Original routing restored");
+        }
+        #[cfg(target_os = "macos")]
+        {
+            let _ = Command::new("sudo").args(["route", "delete", "default", "-interface", interface]).output();
+            let _ = run_privileged(&["route", "add", "default", gateway], "Original routing restored");
+        }
+        // Windows never replaces the default route; the split routes go with the plan
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        let _ = (gateway, interface);
+        Ok(())
+    }
+
+    fn enable_kill_switch(&self, policy: &KillSwitchPolicy) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            if nft_available() {
+                return run_privileged_with_input(&["nft", "-f", "-"], &policy.nft_ruleset(), "Kill switch engaged (nftables)");
+            }
+            for (binary, ipv6) in [("iptables", false), ("ip6tables", true)] {
+                // Re-enabling must not hook the chain in twice
+                let _ = Command::new("sudo").args([binary, "-D", "OUTPUT", "-j", killswitch::IPTABLES_CHAIN]).output();
+                let restore = format!("{binary}-restore");
+                run_privileged_with_input(&[&restore, "--noflush"], &policy.iptables_restore(ipv6), &format!("Kill switch chain loaded ({binary})"))?;
+                run_privileged(&[binary, "-I", "OUTPUT", "1", "-j", killswitch::IPTABLES_CHAIN], &format!("Kill switch engaged ({binary})"))?;
+            }
+            Ok(())
+        }
+        #[cfg(target_os = "macos")]
+        {
+            run_privileged_with_input(&["pfctl", "-a", killswitch::PF_ANCHOR, "-f", "-"], &policy.pf_rules(), "Kill switch rules loaded")?;
+            // One reference on pf for as long as the kill switch stays engaged, new rules or not
+            let mut pf_token = self.pf_token.lock().unwrap_or_else(|e| e.into_inner());
+            if pf_token.is_none() {
+                *pf_token = Some(enable_pf()?);
+            }
+            Ok(())
+        }
+        #[cfg(target_os = "windows")]
+        {
+            // Re-engaging must not save the kill switch's own blocking default
+            let mut outbound_actions = self.outbound_actions.lock().unwrap_or_else(|e| e.into_inner());
+            if outbound_actions.is_none() {
+                *outbound_actions = Some(super::windows::outbound_actions()?);
+            }
+            super::windows::enable_kill_switch(policy)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+        {
+            let _ = policy;
+            Err(VpnError::CapabilityUnavailable("Kill switch is not supported on this platform".to_string()))
+        }
+    }
+
+    fn disable_kill_switch(&self) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            if nft_available() {
+                let table = format!("inet {}", killswitch::NFT_TABLE);
+                return run_privileged(&["nft", "delete", "table", &table], "Kill switch released (nftables)");
+            }
+            for binary in ["iptables", "ip6tables"] {
+                let _ = run_privileged(&[binary, "-D", "OUTPUT", "-j", killswitch::IPTABLES_CHAIN], &format!("Kill switch released ({binary})"));
+                let _ = Command::new("sudo").args([binary, "-F", killswitch::IPTABLES_CHAIN]).output();
+                let _ = Command::new("sudo").args([binary, "-X", killswitch::IPTABLES_CHAIN]).output();
+            }
+        }
+        #[cfg(target_os = "macos")]
+        {
+            run_privileged(&["pfctl", "-a", killswitch::PF_ANCHOR, "-F", "rules"], "Kill switch released")?;
+            let mut pf_token = self.pf_token.lock().unwrap_or_else(|e| e.into_inner());
+            match pf_token.as_deref() {
+                Some(token) => run_privileged(&["pfctl", "-X", token], "pf reference released")?,
+                None => log::warn!("No pf reference of the kill switch to release, pf stays enabled"),
+            }
+            *pf_token = None;
+        }
+        #[cfg(target_os = "windows")]
+        {
+            let mut outbound_actions = self.outbound_actions.lock().unwrap_or_else(|e| e.into_inner());
+            super::windows::disable_kill_switch(outbound_actions.as_ref())?;
+            *outbound_actions = None;
+        }
+        Ok(())
+    }
+
+    fn restore_dns(&self, interface: &str) -> Result<()> {
+        let _ = interface;
+        #[cfg(target_os = "linux")]
+        if let Err(e) = self.dns.lock().unwrap_or_else(|e| e.into_inner()).restore(interface) {
+            log::warn!("Could not restore DNS of {}: {}", interface, e);
+        }
+        #[cfg(target_os = "windows")]
+        super::windows::restore_dns(interface)?;
+        Ok(())
+    }
+
+    fn bring_up_interface(&self, name: &str, peer: Ipv4Addr) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            // Ensure interface is up and configured properly
+            let _ = Command::new("sudo").args(["ip", "link", "set", "dev", name, "up"]).output();
+
+            // Verify interface status
+            if let Ok(output) = Command::new("ip").args(["addr", "show", name]).output() {
+                let status = String::from_utf8_lossy(&output.stdout);
+                log::debug!("Interface status: {}", status.lines().next().unwrap_or("unknown"));
+
+                // Check if interface shows as DOWN or NO-CARRIER
+                if status.contains("NO-CARRIER") || status.contains("DOWN") {
+                    log::debug!("Interface is down, configuring the point-to-point link");
+
+                    // Try to set point-to-point link
+                    let _ = Command::new("sudo")
+                        .args(["ip", "link", "set", "dev", name, "up", "pointopoint", &peer.to_string()])
+                        .output();
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = (name, peer);
+        Ok(())
+    }
+
+    fn delete_interface(&self, name: &str) -> Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let _ = Command::new("sudo").args(["ip", "link", "del", name]).output();
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = name;
+        Ok(())
+    }
+}
+
+/// Point name resolution at `servers` for `interface` (Linux: [`super::dns`])
+///
+/// When `strict`, a failed step fails the change instead of being reported
+/// and skipped.
+#[cfg(not(target_os = "linux"))]
+fn set_dns(interface: &str, servers: &[IpAddr], strict: bool) -> Result<()> {
+    log::info!("Configuring VPN DNS");
+
+    let dns_servers: Vec<String> = servers.iter().map(|s| s.to_string()).collect();
+
+    #[cfg(target_os = "macos")]
+    {
+        // On macOS, configure DNS through networksetup
+        let mut args = vec!["networksetup", "-setdnsservers", interface];
+        args.extend(dns_servers.iter().map(String::as_str));
+        strict_result(strict, run_privileged(&args, "DNS configured for VPN"))?;
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let _ = dns_servers;
+        strict_result(strict, super::windows::set_dns_servers(interface, servers))?;
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+    let _ = (interface, dns_servers, strict);
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn netlink_route(
+    destination: &str,
+    gateway: Option<&str>,
+    interface: Option<&str>,
+    metric: Option<u32>,
+) -> Result<routing::Route> {
+    let route = routing::Route::to(destination)?;
+    let gateway = gateway
+        .map(|gw| match gw.parse::<IpAddr>() {
+            Ok(addr) if addr.is_ipv4() == route.destination.is_ipv4() => Ok(addr),
+            _ => Err(crate::error::VpnError::Routing(format!("Invalid gateway for {destination}: {gw}"))),
+        })
+        .transpose()?;
+    Ok(routing::Route {
+        gateway,
+        oif: interface.map(routing::interface_index).transpose()?,
+        metric,
+        ..route
+    })
+}
+
+/// Policy rule sending traffic marked `fwmark` to `table`
+#[cfg(target_os = "linux")]
+fn fwmark_rule(fwmark: u32, table: u32, priority: u32) -> routing::Rule {
+    routing::Rule {
+        priority: Some(priority),
+        from: None,
+        to: None,
+        fwmark: Some(fwmark),
+        invert: false,
+        suppress_prefixlength: None,
+        table,
+    }
+}
+
+/// `not fwmark <fwmark> lookup <table>`
+#[cfg(target_os = "linux")]
+fn unmarked_rule(fwmark: u32, table: u32, priority: u32) -> routing::Rule {
+    routing::Rule { invert: true, ..fwmark_rule(fwmark, table, priority) }
+}
+
+/// `lookup main suppress_prefixlength 0`: the host's own subnets win over the tunnel table
+#[cfg(target_os = "linux")]
+fn main_table_rule(priority: u32) -> routing::Rule {
+    routing::Rule {
+        priority: Some(priority),
+        from: None,
+        to: None,
+        fwmark: None,
+        invert: false,
+        suppress_prefixlength: Some(0),
+        table: routing::RT_TABLE_MAIN,
+    }
+}
+
+/// Outcome of a route change, which counts even when not strict: routes are
+/// applied and rolled back together
+///
+/// A route that is already there is left to the route check after applying.
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn route_result(result: Result<()>) -> Result<()> {
+    match result {
+        Err(VpnError::Platform(reason) | VpnError::Routing(reason)) if reason.contains("exists") => {
+            log::debug!("Route already present: {}", reason);
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// `result` when `strict`, otherwise success (the failure was already reported)
+fn strict_result(strict: bool, result: Result<()>) -> Result<()> {
+    if strict {
+        result
+    } else {
+        Ok(())
+    }
+}
+
+/// Whether the nftables CLI is installed
+#[cfg(target_os = "linux")]
+pub(super) fn nft_available() -> bool {
+    Command::new("nft").arg("--version").output().is_ok_and(|output| output.status.success())
+}
+
+/// Run a command through sudo with `input` on its stdin, reporting success or the failure reason
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(super) fn run_privileged_with_input(args: &[&str], input: &str, success_msg: &str) -> Result<()> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let output = Command::new("sudo")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .and_then(|mut child| {
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(input.as_bytes())?;
+            }
+            child.wait_with_output()
+        });
+    let reason = match output {
+        Ok(result) if result.status.success() => {
+            log::info!("{}", success_msg);
+            return Ok(());
+        }
+        Ok(result) => format!("'{}' failed: {}", args.join(" "), String::from_utf8_lossy(&result.stderr).trim()),
+        Err(e) => format!("Failed to run '{}': {}", args.join(" "), e),
+    };
+    log::warn!("{}", reason);
+    Err(VpnError::Platform(reason))
+}
+
+/// Take a reference on pf (`pfctl -E`), enabling it if nobody else has; returns the token releasing it
+#[cfg(target_os = "macos")]
+fn enable_pf() -> Result<String> {
+    let output = Command::new("sudo")
+        .args(["pfctl", "-E"])
+        .output()
+        .map_err(|e| VpnError::Platform(format!("Failed to run 'pfctl -E': {e}")))?;
+    // pfctl reports on stderr
+    let report = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(VpnError::Platform(format!("'pfctl -E' failed: {}", report.trim())));
+    }
+    let token = killswitch::pf_token(&report)
+        .ok_or_else(|| VpnError::Platform(format!("'pfctl -E' handed out no reference: {}", report.trim())))?;
+    log::info!("pf enabled for the kill switch");
+    Ok(token.to_string())
+}
+
+/// Run a command through sudo, reporting success or the failure reason
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(super) fn run_privileged(args: &[&str], success_msg: &str) -> Result<()> {
+    let reason = match Command::new("sudo").args(args).output() {
+        Ok(result) if result.status.success() => {
+            log::info!("{}", success_msg);
+            return Ok(());
+        }
+        Ok(result) => format!("'{}' failed: {}", args.join(" "), String::from_utf8_lossy(&result.stderr).trim()),
+        Err(e) => format!("Failed to run '{}': {}", args.join(" "), e),
+    };
+    log::warn!("{}", reason);
+    Err(VpnError::Platform(reason))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::error::VpnError;
+
+    #[test]
+    fn test_netlink_route_from_plan_fields() {
+        let route = netlink_route("0.0.0.0/1", Some("10.0.0.1"), None, Some(50)).unwrap();
+        assert_eq!(route.prefix_len, 1);
+        assert_eq!(route.gateway, Some(IpAddr::from([10, 0, 0, 1])));
+        assert_eq!(route.table, routing::RT_TABLE_MAIN);
+        let route = netlink_route("8000::/1", Some("fd00:21::1"), None, None).unwrap();
+        assert_eq!(route.gateway, Some("fd00:21::1".parse().unwrap()));
+        assert!(netlink_route("::/1", Some("10.0.0.1"), None, None).is_err());
+        assert!(netlink_route("default", Some("gateway.local"), None, None).is_err());
+        assert!(netlink_route("10.0.0.0/8", None, Some("no-such-if0"), None).is_err());
+    }
+
+    #[test]
+    fn test_in_memory_dns_requires_resolved() {
+        use crate::tunnel::dns::{check_backend, DnsBackend};
+        assert!(check_backend(DnsBackend::Resolved, true).is_ok());
+        assert!(check_backend(DnsBackend::ResolvConf, false).is_ok());
+        for backend in [DnsBackend::ResolvConf, DnsBackend::Resolvconf { openresolv: true }] {
+            assert!(matches!(check_backend(backend, true), Err(VpnError::CapabilityUnavailable(_))));
+        }
+    }
+}
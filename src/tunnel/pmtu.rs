@@ -0,0 +1,190 @@
+//! Path MTU discovery towards the VPN server (`auto_mtu`)
+//!
+//! The session is TLS over TCP, so every tunnel packet also pays for the
+//! outer IP and TCP headers, the TLS record and the session's block framing.
+//! A tunnel MTU the path cannot carry in one segment splits each full-size
+//! packet in two. [`probe`] asks the kernel for the path MTU to the server,
+//! sending don't-fragment datagrams so routers report smaller links, and
+//! [`tunnel_mtu`] takes the overhead off.
+//!
+//! Probing is implemented on Linux (`IP_MTU`); elsewhere the MTU the server
+//! pushed is kept.
+
+use crate::config::{MAX_TUNNEL_MTU, MIN_TUNNEL_MTU};
+use std::net::IpAddr;
+
+/// TCP header with the timestamp option
+const TCP_HEADER_LEN: u16 = 32;
+
+/// TLS record header plus AES-GCM explicit nonce and tag
+const TLS_RECORD_OVERHEAD: u16 = 29;
+
+/// SoftEther block framing: block count and block size
+const SESSION_FRAMING_LEN: u16 = 8;
+
+/// Probes sent before settling on what the kernel knows by then
+#[cfg(any(target_os = "linux", test))]
+const MAX_PROBES: usize = 5;
+
+/// Tunnel MTU whose packets fit one outer segment on a path of `path_mtu`
+pub fn tunnel_mtu(path_mtu: u16, ipv6: bool) -> u16 {
+    let ip_header_len = if ipv6 { 40 } else { 20 };
+    path_mtu
+        .saturating_sub(ip_header_len + TCP_HEADER_LEN + TLS_RECORD_OVERHEAD + SESSION_FRAMING_LEN)
+        .clamp(MIN_TUNNEL_MTU, MAX_TUNNEL_MTU)
+}
+
+/// The route to the server as far as the kernel knows it
+#[cfg(any(target_os = "linux", test))]
+trait Path {
+    /// Current path MTU estimate
+    fn known_mtu(&self) -> Option<u16>;
+
+    /// Send a don't-fragment datagram of `size` bytes and give routers time to object
+    fn send(&self, size: u16);
+}
+
+/// Lower the estimate until a datagram of its size goes through unchallenged
+#[cfg(any(target_os = "linux", test))]
+fn discover(path: &impl Path) -> Option<u16> {
+    let mut mtu = path.known_mtu()?;
+    for _ in 0..MAX_PROBES {
+        path.send(mtu);
+        let known = path.known_mtu()?;
+        if known >= mtu {
+            break;
+        }
+        mtu = known;
+    }
+    Some(mtu)
+}
+
+/// Path MTU to `server`, or `None` where it cannot be probed
+pub fn probe(server: IpAddr) -> Option<u16> {
+    #[cfg(target_os = "linux")]
+    {
+        let path = linux::UdpPath::connect(server)
+            .map_err(|e| log::warn!("Path MTU probe to {} failed: {}", server, e))
+            .ok()?;
+        discover(&path)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = server;
+        None
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::Path;
+    use std::io;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    /// Discard service; the datagram's content and any answer are irrelevant
+    const PROBE_PORT: u16 = 9;
+
+    /// How long a router's "fragmentation needed" has to arrive
+    const PROBE_WAIT: Duration = Duration::from_millis(200);
+
+    /// Connected UDP socket that never fragments, whose `IP_MTU` tracks the path
+    pub struct UdpPath {
+        socket: UdpSocket,
+        ipv6: bool,
+    }
+
+    impl UdpPath {
+        pub fn connect(server: IpAddr) -> io::Result<Self> {
+            let ipv6 = server.is_ipv6();
+            let local: IpAddr = if ipv6 { Ipv6Addr::UNSPECIFIED.into() } else { Ipv4Addr::UNSPECIFIED.into() };
+            let socket = UdpSocket::bind((local, 0))?;
+            socket.connect((server, PROBE_PORT))?;
+            let path = Self { socket, ipv6 };
+            let (level, option, value) = if ipv6 {
+                (libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO)
+            } else {
+                (libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO)
+            };
+            // SAFETY: `value` is a c_int that outlives the call, as the option expects
+            let set = unsafe {
+                libc::setsockopt(
+                    path.socket.as_raw_fd(),
+                    level,
+                    option,
+                    &value as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if set < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(path)
+        }
+    }
+
+    impl Path for UdpPath {
+        fn known_mtu(&self) -> Option<u16> {
+            let (level, option) = if self.ipv6 { (libc::IPPROTO_IPV6, libc::IPV6_MTU) } else { (libc::IPPROTO_IP, libc::IP_MTU) };
+            let mut mtu: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: `mtu` and `len` describe a c_int buffer that outlives the call
+            let got = unsafe {
+                libc::getsockopt(self.socket.as_raw_fd(), level, option, &mut mtu as *mut libc::c_int as *mut libc::c_void, &mut len)
+            };
+            if got < 0 {
+                return None;
+            }
+            u16::try_from(mtu).ok()
+        }
+
+        fn send(&self, size: u16) {
+            let headers = if self.ipv6 { 40 + 8 } else { 20 + 8 };
+            let payload = vec![0u8; usize::from(size.saturating_sub(headers))];
+            // EMSGSIZE just means the kernel already knows a smaller MTU
+            let _ = self.socket.send(&payload);
+            std::thread::sleep(PROBE_WAIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Links of decreasing MTU, each reported by its router once a datagram too big for it is sent
+    struct Links {
+        hops: Vec<u16>,
+        known: Cell<u16>,
+    }
+
+    impl Path for Links {
+        fn known_mtu(&self) -> Option<u16> {
+            Some(self.known.get())
+        }
+
+        fn send(&self, size: u16) {
+            if let Some(&hop) = self.hops.iter().find(|&&hop| hop < size) {
+                self.known.set(hop);
+            }
+        }
+    }
+
+    #[test]
+    fn test_discover_follows_smaller_links() {
+        let path = Links { hops: vec![1492, 1400, 1280], known: Cell::new(1500) };
+        assert_eq!(discover(&path), Some(1280));
+        let clear = Links { hops: Vec::new(), known: Cell::new(1500) };
+        assert_eq!(discover(&clear), Some(1500));
+    }
+
+    #[test]
+    fn test_tunnel_mtu_subtracts_overhead() {
+        assert_eq!(tunnel_mtu(1500, false), 1411);
+        assert_eq!(tunnel_mtu(1500, true), 1391);
+        assert_eq!(tunnel_mtu(9000, false), MAX_TUNNEL_MTU);
+        assert_eq!(tunnel_mtu(600, false), MIN_TUNNEL_MTU);
+    }
+}
@@ -0,0 +1,802 @@
+//! TUN ⇄ session packet pump
+//!
+//! Moves IP packets between the TUN device and the VPN session once the
+//! tunnel is up. Each direction is one task on the client's runtime, using
+//! the non-blocking halves from [`async_tun`](super::async_tun):
+//!
+//! ```text
+//! AsyncTunReader ─▶ outbound task ─▶ PacketSink (session)
+//! AsyncTunWriter ◀─ inbound task  ◀─ PacketSource (session)
+//! ```
+//!
+//! Frames that are not IPv4 or IPv6 packets are dropped in both directions.
+//!
+//! On bridged hubs the session carries Ethernet frames instead: a
+//! [`NeighborStack`] adds and strips the headers, and its ARP/NDP replies and
+//! retransmissions are sent by the outbound task alongside TUN traffic.
+//! So are DHCP renewals, when the stack holds a lease, and the gratuitous ARP
+//! announcing the client's address when forwarding starts.
+//!
+//! A TAP device exchanges Ethernet frames itself; they pass between the
+//! device and the session unchanged, and the host's own stack answers ARP.
+//!
+//! When the server ends the session the inbound task keeps its
+//! [`DisconnectNotice`] for [`PacketPump::disconnect_notice`] before stopping.
+//!
+//! Session keepalives requested with [`PacketPump::send_keepalive`] go out
+//! through the outbound task, between packets.
+//!
+//! Packets in both directions wait for the [`RateLimiter`] before they are
+//! forwarded; link-layer replies and keepalives do not.
+//!
+//! A TUN device with offloads holds written packets back to merge them into
+//! super-packets; the inbound task flushes it whenever the session has no
+//! further packet ready.
+
+use super::async_tun::{AsyncTunReader, AsyncTunWriter};
+use super::dhcp::DhcpLease;
+use super::l2::ETHERNET_HEADER_LEN;
+use super::neighbor::{NeighborStack, NeighborStats, RETRANSMIT_INTERVAL};
+use super::packet_framing::IpVersion;
+use super::shaper::{Direction, RateLimiter};
+use crate::client_optimized::PerformanceStats;
+use crate::error::{Result, VpnError};
+use crate::protocol::binary::{BinaryDataReceiver, BinaryDataSender, BinaryProtocolClient, DisconnectNotice};
+use crate::protocol::bonding::{BondedReceiver, BondedSender};
+use bytes::Bytes;
+use futures::{stream, FutureExt, StreamExt};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+
+/// Link-layer replies buffered between the inbound and outbound tasks
+const QUEUE_LEN: usize = 256;
+
+/// Session side that carries packets read from TUN to the server
+pub trait PacketSink: Send + 'static {
+    fn send_packet(&mut self, packet: Bytes) -> impl Future<Output = Result<()>> + Send;
+
+    /// Send a keepalive probe to the server; sinks without one have nothing to do
+    fn send_keepalive(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Write out packets held back to go together; sinks that hold none have nothing to do
+    fn flush(&mut self) -> impl Future<Output = Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// Session side that yields packets from the server to be written to TUN
+pub trait PacketSource: Send + 'static {
+    fn recv_packet(&mut self) -> impl Future<Output = Result<Bytes>> + Send;
+}
+
+impl PacketSink for BinaryDataSender {
+    fn send_packet(&mut self, packet: Bytes) -> impl Future<Output = Result<()>> + Send {
+        self.send_vpn_data(packet)
+    }
+
+    fn send_keepalive(&mut self) -> impl Future<Output = Result<()>> + Send {
+        BinaryDataSender::send_keepalive(self)
+    }
+
+    fn flush(&mut self) -> impl Future<Output = Result<()>> + Send {
+        BinaryDataSender::flush(self)
+    }
+}
+
+impl PacketSource for BinaryDataReceiver {
+    fn recv_packet(&mut self) -> impl Future<Output = Result<Bytes>> + Send {
+        self.receive_vpn_data()
+    }
+}
+
+impl PacketSink for BondedSender {
+    fn send_packet(&mut self, packet: Bytes) -> impl Future<Output = Result<()>> + Send {
+        self.send_vpn_data(packet)
+    }
+
+    fn send_keepalive(&mut self) -> impl Future<Output = Result<()>> + Send {
+        BondedSender::send_keepalive(self)
+    }
+}
+
+impl PacketSource for BondedReceiver {
+    fn recv_packet(&mut self) -> impl Future<Output = Result<Bytes>> + Send {
+        self.receive_vpn_data()
+    }
+}
+
+/// The unsplit session, for exchanges before the pump starts (DHCP)
+impl PacketSink for BinaryProtocolClient {
+    fn send_packet(&mut self, packet: Bytes) -> impl Future<Output = Result<()>> + Send {
+        self.send_vpn_data(packet)
+    }
+
+    fn send_keepalive(&mut self) -> impl Future<Output = Result<()>> + Send {
+        BinaryProtocolClient::send_keepalive(self)
+    }
+}
+
+impl PacketSource for BinaryProtocolClient {
+    fn recv_packet(&mut self) -> impl Future<Output = Result<Bytes>> + Send {
+        self.receive_vpn_data()
+    }
+}
+
+impl PacketSink for mpsc::Sender<Bytes> {
+    async fn send_packet(&mut self, packet: Bytes) -> Result<()> {
+        self.send(packet)
+            .await
+            .map_err(|_| VpnError::Connection("Packet channel closed".to_string()))
+    }
+}
+
+impl PacketSource for mpsc::Receiver<Bytes> {
+    async fn recv_packet(&mut self) -> Result<Bytes> {
+        self.recv()
+            .await
+            .ok_or_else(|| VpnError::Connection("Packet channel closed".to_string()))
+    }
+}
+
+/// What the session carries, and who frames it
+#[derive(Debug)]
+pub enum LinkLayer {
+    /// IP packets, as read from and written to a TUN device
+    Ip,
+    /// Ethernet frames, added and stripped by a [`NeighborStack`] around a TUN device
+    Emulated(Box<NeighborStack>),
+    /// Ethernet frames, passed through a TAP device unchanged
+    Tap,
+}
+
+/// [`LinkLayer`] as shared by the two tasks
+#[derive(Clone)]
+enum Framing {
+    Ip,
+    Emulated(Arc<Mutex<NeighborStack>>),
+    Tap,
+}
+
+impl Framing {
+    /// Whether `frame` can be forwarded at all
+    fn accepts(&self, frame: &[u8]) -> bool {
+        match self {
+            Framing::Tap => frame.len() >= ETHERNET_HEADER_LEN,
+            Framing::Ip | Framing::Emulated(_) => IpVersion::of(frame).is_some(),
+        }
+    }
+
+    fn stack(&self) -> Option<&Arc<Mutex<NeighborStack>>> {
+        match self {
+            Framing::Emulated(stack) => Some(stack),
+            Framing::Ip | Framing::Tap => None,
+        }
+    }
+}
+
+/// Running bidirectional packet pump
+///
+/// Traffic is counted in the [`PerformanceStats`] passed to [`spawn`](Self::spawn).
+/// Stopping (or dropping) the pump aborts both tasks, which releases the TUN
+/// device.
+#[derive(Debug)]
+pub struct PacketPump {
+    outbound: JoinHandle<()>,
+    inbound: JoinHandle<()>,
+    dropped: Arc<AtomicU64>,
+    link: Option<Arc<Mutex<NeighborStack>>>,
+    notice: Arc<Mutex<Option<DisconnectNotice>>>,
+    keepalive: Arc<Notify>,
+}
+
+impl PacketPump {
+    /// Start forwarding between the TUN halves and the session
+    ///
+    /// `mtu` sizes the device read buffer (plus the Ethernet header on a
+    /// TAP device). Both tasks run on `handle`. Unless `link` is
+    /// [`LinkLayer::Ip`], the session carries Ethernet frames. Traffic is
+    /// held to the limits of `shaper`, which may change while running.
+    #[allow(clippy::too_many_arguments)]
+    pub fn spawn<S: PacketSink, R: PacketSource>(
+        handle: &Handle,
+        (reader, writer): (AsyncTunReader, AsyncTunWriter),
+        sink: S,
+        source: R,
+        mtu: u16,
+        traffic: Arc<PerformanceStats>,
+        shaper: Arc<RateLimiter>,
+        link: LinkLayer,
+    ) -> Result<Self> {
+        let dropped = Arc::new(AtomicU64::new(0));
+        let notice = Arc::new(Mutex::new(None));
+        let keepalive = Arc::new(Notify::new());
+        let (replies_tx, replies_rx) = mpsc::channel(QUEUE_LEN);
+        let mut buffer_len = usize::from(mtu);
+        let framing = match link {
+            LinkLayer::Ip => Framing::Ip,
+            LinkLayer::Emulated(mut stack) => {
+                // Queued before either task runs, so it cannot fail
+                let _ = replies_tx.try_send(stack.announce());
+                Framing::Emulated(Arc::new(Mutex::new(*stack)))
+            }
+            LinkLayer::Tap => {
+                buffer_len += ETHERNET_HEADER_LEN;
+                Framing::Tap
+            }
+        };
+        let link = framing.stack().cloned();
+
+        let outbound = handle.spawn(send_outbound(
+            reader,
+            buffer_len,
+            replies_rx,
+            keepalive.clone(),
+            sink,
+            traffic.clone(),
+            shaper.clone(),
+            dropped.clone(),
+            framing.clone(),
+        ));
+        let inbound = handle.spawn(receive_inbound(
+            source,
+            writer,
+            replies_tx,
+            traffic,
+            shaper,
+            dropped.clone(),
+            framing,
+            notice.clone(),
+        ));
+
+        Ok(Self { outbound, inbound, dropped, link, notice, keepalive })
+    }
+
+    /// Whether both directions are still forwarding
+    pub fn is_running(&self) -> bool {
+        !self.outbound.is_finished() && !self.inbound.is_finished()
+    }
+
+    /// Frames discarded because they were not IP packets (or, on TAP, not Ethernet frames)
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// ARP/NDP counters, when forwarding Ethernet frames
+    pub fn neighbor_stats(&self) -> Option<NeighborStats> {
+        self.link.as_ref().map(|link| link.lock().unwrap().stats())
+    }
+
+    /// DHCP lease as last renewed, when the link runs a DHCP client
+    pub fn dhcp_lease(&self) -> Option<DhcpLease> {
+        self.link.as_ref().and_then(|link| link.lock().unwrap().dhcp_lease().cloned())
+    }
+
+    /// Why the server ended the session, once it has
+    pub fn disconnect_notice(&self) -> Option<DisconnectNotice> {
+        self.notice.lock().unwrap().clone()
+    }
+
+    /// Have the outbound task send a keepalive probe
+    ///
+    /// Requests made while one is still queued are merged into it.
+    pub fn send_keepalive(&self) {
+        self.keepalive.notify_one();
+    }
+
+    /// Stop forwarding in both directions
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for PacketPump {
+    fn drop(&mut self) {
+        self.outbound.abort();
+        self.inbound.abort();
+    }
+}
+
+/// TUN packets, link-layer replies and keepalives → session, until TUN closes or the session fails
+#[allow(clippy::too_many_arguments)]
+async fn send_outbound<S: PacketSink>(
+    mut reader: AsyncTunReader,
+    buffer_len: usize,
+    mut replies: mpsc::Receiver<Bytes>,
+    keepalive: Arc<Notify>,
+    mut sink: S,
+    traffic: Arc<PerformanceStats>,
+    shaper: Arc<RateLimiter>,
+    dropped: Arc<AtomicU64>,
+    framing: Framing,
+) {
+    let link = framing.stack();
+    let mut buf = vec![0u8; buffer_len];
+    let mut retransmit = tokio::time::interval(RETRANSMIT_INTERVAL);
+    let mut unflushed = false;
+    loop {
+        // TUN reads come late so a busy device cannot starve the rest, and
+        // batched packets are flushed once nothing else is ready
+        let (frames, shaped) = tokio::select! {
+            biased;
+            Some(reply) = replies.recv() => (vec![reply], false),
+            () = keepalive.notified() => {
+                if let Err(e) = sink.send_keepalive().await {
+                    log::warn!("Keepalive send failed, stopping outbound forwarding: {e}");
+                    return;
+                }
+                unflushed = false;
+                continue;
+            }
+            _ = retransmit.tick(), if link.is_some() => match link {
+                Some(link) => (link.lock().unwrap().poll(Instant::now()), false),
+                None => (Vec::new(), false),
+            },
+            read = reader.read_packet(&mut buf) => {
+                let packet = match read {
+                    Ok(0) => return,
+                    Ok(len) => &buf[..len],
+                    Err(e) => {
+                        log::warn!("TUN read failed, stopping outbound forwarding: {e}");
+                        return;
+                    }
+                };
+                if !framing.accepts(packet) {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                }
+                let packet = Bytes::copy_from_slice(packet);
+                let frames = match link {
+                    None => vec![packet],
+                    Some(link) => link.lock().unwrap().outbound(packet, Instant::now()),
+                };
+                (frames, true)
+            },
+            () = std::future::ready(()), if unflushed => {
+                if let Err(e) = sink.flush().await {
+                    log::warn!("Session send failed, stopping outbound forwarding: {e}");
+                    return;
+                }
+                unflushed = false;
+                continue;
+            }
+        };
+
+        for frame in frames {
+            let len = frame.len() as u64;
+            if shaped {
+                shaper.throttle(Direction::Upload, frame.len()).await;
+            }
+            if let Err(e) = sink.send_packet(frame).await {
+                log::warn!("Session send failed, stopping outbound forwarding: {e}");
+                return;
+            }
+            traffic.update_traffic(len, 0, 1, 0);
+            unflushed = true;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn receive_inbound<R: PacketSource>(
+    source: R,
+    mut writer: AsyncTunWriter,
+    replies: mpsc::Sender<Bytes>,
+    traffic: Arc<PerformanceStats>,
+    shaper: Arc<RateLimiter>,
+    dropped: Arc<AtomicU64>,
+    framing: Framing,
+    notice: Arc<Mutex<Option<DisconnectNotice>>>,
+) {
+    // A stream keeps a receive in progress across the check for a ready packet
+    let mut frames = stream::unfold(source, |mut source| async move {
+        let frame = source.recv_packet().await;
+        Some((frame, source))
+    })
+    .boxed();
+    loop {
+        let received = match frames.next().now_or_never() {
+            Some(received) => received,
+            None => {
+                if let Err(e) = writer.flush().await {
+                    log::warn!("TUN write failed, stopping inbound forwarding: {e}");
+                    return;
+                }
+                frames.next().await
+            }
+        };
+        let Some(received) = received else {
+            return;
+        };
+        let frame = match received {
+            Ok(frame) => frame,
+            Err(VpnError::ServerDisconnected { code, message }) => {
+                log::warn!("Server ended the session (error {code}): {message}");
+                *notice.lock().unwrap() = Some(DisconnectNotice { code, message });
+                return;
+            }
+            Err(e) => {
+                log::warn!("Session receive failed, stopping inbound forwarding: {e}");
+                return;
+            }
+        };
+        let packet = match framing.stack() {
+            None => frame,
+            Some(link) => {
+                let inbound = link.lock().unwrap().inbound(&frame, Instant::now());
+                for reply in inbound.frames {
+                    if replies.send(reply).await.is_err() {
+                        return;
+                    }
+                }
+                match inbound.packet {
+                    Some(packet) => packet,
+                    None => continue,
+                }
+            }
+        };
+        if !framing.accepts(&packet) {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        shaper.throttle(Direction::Download, packet.len()).await;
+        traffic.update_traffic(0, packet.len() as u64, 0, 1);
+        if let Err(e) = writer.write_packet(&packet).await {
+            log::warn!("TUN write failed, stopping inbound forwarding: {e}");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::async_tun;
+    use crate::tunnel::tun_io::{TunReader, TunWriter};
+    use std::io::{self, Read, Write};
+    use std::sync::{mpsc as std_mpsc, Mutex};
+    use std::time::Duration;
+
+    /// Yields one packet per read, blocking like a TUN device; end of file once the sender is gone
+    struct QueuedPackets(std_mpsc::Receiver<Vec<u8>>);
+
+    impl Read for QueuedPackets {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Ok(packet) = self.0.recv() else {
+                return Ok(0);
+            };
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+    }
+
+    /// Records every packet written
+    #[derive(Clone, Default)]
+    struct Written(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn ipv4_packet(last_octet: u8) -> Vec<u8> {
+        let mut packet = vec![0u8; 20];
+        packet[0] = 0x45;
+        packet[19] = last_octet;
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_pump_keeps_to_rate_limit() {
+        let (tun_in, tun_packets) = std_mpsc::channel();
+        let reader = TunReader::new(QueuedPackets(tun_packets));
+        let writer = TunWriter::new(Written::default());
+        let (sink, mut to_server) = mpsc::channel(8);
+        let (_from_server, source) = mpsc::channel::<Bytes>(8);
+        // 100 000 bytes/s, after a bucket of 10 000 bytes
+        let shaper = Arc::new(RateLimiter::new(800_000, 0));
+
+        let _pump = PacketPump::spawn(
+            &Handle::current(),
+            async_tun::split((reader, writer)).unwrap(),
+            sink,
+            source,
+            1500,
+            Arc::new(PerformanceStats::new()),
+            shaper.clone(),
+            LinkLayer::Ip,
+        )
+        .unwrap();
+
+        let start = std::time::Instant::now();
+        let mut packet = ipv4_packet(1);
+        packet.resize(1000, 0);
+        for _ in 0..20 {
+            tun_in.send(packet.clone()).unwrap();
+        }
+        for _ in 0..20 {
+            to_server.recv().await.unwrap();
+        }
+        // 20 000 bytes, half of them from the bucket: about 100ms
+        assert!(start.elapsed() >= Duration::from_millis(90));
+
+        // Lifting the limit lets the rest through at once
+        shaper.set(0, 0);
+        let start = std::time::Instant::now();
+        for _ in 0..8 {
+            tun_in.send(packet.clone()).unwrap();
+        }
+        for _ in 0..8 {
+            to_server.recv().await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_pump_forwards_both_directions() {
+        let (tun_in, tun_packets) = std_mpsc::channel();
+        let reader = TunReader::new(QueuedPackets(tun_packets));
+        let written = Written::default();
+        let writer = TunWriter::new(written.clone());
+
+        let (sink, mut to_server) = mpsc::channel(8);
+        let (from_server, source) = mpsc::channel(8);
+        let traffic = Arc::new(PerformanceStats::new());
+
+        let pump = PacketPump::spawn(
+            &Handle::current(),
+            async_tun::split((reader, writer)).unwrap(),
+            sink,
+            source,
+            1500,
+            traffic.clone(),
+            Arc::default(),
+            LinkLayer::Ip,
+        )
+        .unwrap();
+
+        // TUN → session, with the non-IP frame dropped
+        tun_in.send(ipv4_packet(1)).unwrap();
+        tun_in.send(b"not an ip packet".to_vec()).unwrap();
+        tun_in.send(ipv4_packet(2)).unwrap();
+        assert_eq!(to_server.recv().await.unwrap(), ipv4_packet(1));
+        assert_eq!(to_server.recv().await.unwrap(), ipv4_packet(2));
+
+        // Session → TUN
+        from_server.send(Bytes::from(ipv4_packet(3))).await.unwrap();
+        from_server.send(Bytes::from_static(b"junk")).await.unwrap();
+        from_server.send(Bytes::from(ipv4_packet(4))).await.unwrap();
+        for _ in 0..100 {
+            if written.0.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*written.0.lock().unwrap(), vec![ipv4_packet(3), ipv4_packet(4)]);
+
+        assert_eq!(pump.dropped(), 2);
+        assert_eq!(traffic.packets_sent.load(Ordering::Relaxed), 2);
+        assert_eq!(traffic.packets_received.load(Ordering::Relaxed), 2);
+        assert_eq!(traffic.bytes_received.load(Ordering::Relaxed), 40);
+        assert!(pump.is_running());
+
+        // Losing the session stops the pump
+        drop(from_server);
+        for _ in 0..100 {
+            if !pump.is_running() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!pump.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_pump_passes_tap_frames_unchanged() {
+        let (tun_in, tun_packets) = std_mpsc::channel();
+        let reader = TunReader::new(QueuedPackets(tun_packets));
+        let written = Written::default();
+        let writer = TunWriter::new(written.clone());
+        let (sink, mut to_server) = mpsc::channel(8);
+        let (from_server, source) = mpsc::channel(8);
+
+        let pump = PacketPump::spawn(
+            &Handle::current(),
+            async_tun::split((reader, writer)).unwrap(),
+            sink,
+            source,
+            1500,
+            Arc::new(PerformanceStats::new()),
+            Arc::default(),
+            LinkLayer::Tap,
+        )
+        .unwrap();
+
+        // A full-size frame fits the read buffer; ARP goes through like anything else
+        let mut frame = crate::tunnel::l2::ip_frame([0x02, 0, 0, 0, 0, 1], [0x02, 0, 0, 0, 0, 2], &ipv4_packet(1));
+        let arp = crate::tunnel::l2::gratuitous_arp([0x02, 0, 0, 0, 0, 2], std::net::Ipv4Addr::new(10, 0, 0, 2));
+        let jumbo = vec![0xab; 1500 + ETHERNET_HEADER_LEN];
+        tun_in.send(frame.to_vec()).unwrap();
+        tun_in.send(vec![0; 6]).unwrap();
+        tun_in.send(arp.to_vec()).unwrap();
+        tun_in.send(jumbo.clone()).unwrap();
+        assert_eq!(to_server.recv().await.unwrap(), frame);
+        assert_eq!(to_server.recv().await.unwrap(), arp);
+        assert_eq!(to_server.recv().await.unwrap(), jumbo);
+
+        frame = crate::tunnel::l2::ip_frame([0x02, 0, 0, 0, 0, 2], [0x02, 0, 0, 0, 0, 1], &ipv4_packet(3));
+        from_server.send(frame.clone()).await.unwrap();
+        for _ in 0..100 {
+            if !written.0.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*written.0.lock().unwrap(), vec![frame.to_vec()]);
+        assert_eq!(pump.dropped(), 1);
+        assert_eq!(pump.neighbor_stats(), None);
+    }
+
+    /// Session the server has just ended
+    struct Removed;
+
+    impl PacketSource for Removed {
+        async fn recv_packet(&mut self) -> Result<Bytes> {
+            Err(DisconnectNotice { code: 11, message: "Removed by administrator".to_string() }.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pump_keeps_disconnect_notice() {
+        let (_tun_in, tun_packets) = std_mpsc::channel();
+        let reader = TunReader::new(QueuedPackets(tun_packets));
+        let writer = TunWriter::new(Written::default());
+        let (sink, _to_server) = mpsc::channel(8);
+
+        let pump = PacketPump::spawn(
+            &Handle::current(),
+            async_tun::split((reader, writer)).unwrap(),
+            sink,
+            Removed,
+            1500,
+            Arc::new(PerformanceStats::new()),
+            Arc::default(),
+            LinkLayer::Ip,
+        )
+        .unwrap();
+        for _ in 0..100 {
+            if !pump.is_running() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!pump.is_running());
+        assert_eq!(pump.disconnect_notice().map(|notice| notice.code), Some(11));
+    }
+
+    #[tokio::test]
+    async fn test_pump_sends_keepalives_and_reads_echoes() {
+        let server = crate::protocol::mock::MockServer::start().await.unwrap();
+        let mut session = BinaryProtocolClient::new(server.addr());
+        session.connect().await.unwrap();
+        session.authenticate("user", "pass", "HUB").await.unwrap();
+        let heartbeat = session.heartbeat();
+        let (sink, source) = session.into_split().unwrap();
+
+        let (_tun_in, tun_packets) = std_mpsc::channel();
+        let reader = TunReader::new(QueuedPackets(tun_packets));
+        let writer = TunWriter::new(Written::default());
+        let pump = PacketPump::spawn(
+            &Handle::current(),
+            async_tun::split((reader, writer)).unwrap(),
+            sink,
+            source,
+            1500,
+            Arc::new(PerformanceStats::new()),
+            Arc::default(),
+            LinkLayer::Ip,
+        )
+        .unwrap();
+
+        pump.send_keepalive();
+        for _ in 0..100 {
+            if heartbeat.counts() == (1, 1) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(heartbeat.counts(), (1, 1));
+        assert!(heartbeat.take_rtt().is_some());
+        assert!(pump.is_running());
+    }
+
+    #[tokio::test]
+    async fn test_pump_bridges_ethernet_frames() {
+        use crate::tunnel::neighbor::NeighborConfig;
+        use std::net::Ipv4Addr;
+
+        let (tun_in, tun_packets) = std_mpsc::channel();
+        let reader = TunReader::new(QueuedPackets(tun_packets));
+        let written = Written::default();
+        let writer = TunWriter::new(written.clone());
+
+        let (sink, mut to_server) = mpsc::channel(8);
+        let (from_server, source) = mpsc::channel(8);
+        let mac = [0x02, 0, 0, 0, 0, 0x07];
+        let gateway_mac = [0x02, 0, 0, 0, 0, 0x01];
+        let link = NeighborStack::new(NeighborConfig {
+            mac,
+            ipv4: Ipv4Addr::new(10, 0, 0, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: Ipv4Addr::new(10, 0, 0, 1),
+            ipv6: None,
+        });
+
+        let pump = PacketPump::spawn(
+            &Handle::current(),
+            async_tun::split((reader, writer)).unwrap(),
+            sink,
+            source,
+            1500,
+            Arc::new(PerformanceStats::new()),
+            Arc::default(),
+            LinkLayer::Emulated(Box::new(link)),
+        )
+        .unwrap();
+
+        // Forwarding starts with a gratuitous ARP for our address
+        let announcement = to_server.recv().await.unwrap();
+        assert_eq!(announcement[..12], [[0xff; 6], mac].concat());
+        assert_eq!(announcement[28..32], announcement[38..42]);
+
+        // The gateway asks for us: answered without any TUN traffic
+        let mut request = vec![0xff; 6];
+        request.extend(gateway_mac);
+        request.extend([0x08, 0x06, 0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01]);
+        request.extend(gateway_mac);
+        request.extend([10, 0, 0, 1, 0, 0, 0, 0, 0, 0, 10, 0, 0, 2]);
+        from_server.send(Bytes::from(request)).await.unwrap();
+        let reply = to_server.recv().await.unwrap();
+        assert_eq!(reply[..6], gateway_mac);
+        assert_eq!(reply[20..22], [0x00, 0x02]);
+
+        // Resolved by the request, so TUN packets leave framed for the gateway
+        tun_in.send(ipv4_packet(1)).unwrap();
+        let frame = to_server.recv().await.unwrap();
+        assert_eq!(frame[..12], [gateway_mac, mac].concat());
+        assert_eq!(frame[14..], ipv4_packet(1));
+
+        // Frames for us reach TUN without their Ethernet header (or padding)
+        let mut packet = ipv4_packet(2);
+        packet[3] = 20;
+        let mut frame = mac.to_vec();
+        frame.extend(gateway_mac);
+        frame.extend([0x08, 0x00]);
+        frame.extend(&packet);
+        frame.resize(60, 0);
+        from_server.send(Bytes::from(frame)).await.unwrap();
+        for _ in 0..100 {
+            if !written.0.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(*written.0.lock().unwrap(), vec![packet]);
+
+        let stats = pump.neighbor_stats().unwrap();
+        assert_eq!(stats.advertisements_sent, 2);
+        assert_eq!(pump.dropped(), 0);
+    }
+}
@@ -0,0 +1,215 @@
+//! Server-pushed static routes
+//!
+//! SoftEther hubs can push static routes to clients, either through the
+//! SecureNAT virtual DHCP server (classless static route option 121 / the
+//! Microsoft variant 249) or as the hub's `ClasslessRoute` policy text
+//! (`192.168.5.0/24/192.168.4.254, 10.0.0.0/255.0.0.0/192.168.4.253`).
+//! Both forms are normalized into [`PushedRoute`]s, installed through the
+//! tunnel's change plan and removed again when the tunnel is torn down.
+
+use crate::net_util;
+use crate::protocol::pack::Pack;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// PACK elements that may carry the textual route list
+const ROUTE_TEXT_ELEMENTS: &[&str] = &["ClasslessRoute", "classless_route", "static_routes"];
+
+/// PACK elements that may carry raw DHCP classless route options
+const ROUTE_OPTION_ELEMENTS: &[&str] = &["dhcp_option_121", "dhcp_option_249"];
+
+/// A static route pushed by the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PushedRoute {
+    /// Network address (host bits cleared)
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+    /// Next hop inside the tunnel; `None` means the tunnel gateway
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl PushedRoute {
+    pub fn new(network: Ipv4Addr, prefix_len: u8, gateway: Option<Ipv4Addr>) -> Option<Self> {
+        let IpAddr::V4(network) = net_util::subnet(IpAddr::V4(network), prefix_len)?.network() else {
+            return None;
+        };
+        Some(Self {
+            network,
+            prefix_len,
+            gateway: gateway.filter(|gw| !gw.is_unspecified()),
+        })
+    }
+
+    /// Destination in CIDR notation
+    pub fn cidr(&self) -> String {
+        format!("{}/{}", self.network, self.prefix_len)
+    }
+
+    /// Whether this is a default route (0.0.0.0/0)
+    pub fn is_default(&self) -> bool {
+        self.prefix_len == 0
+    }
+}
+
+impl fmt::Display for PushedRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.gateway {
+            Some(gateway) => write!(f, "{} via {}", self.cidr(), gateway),
+            None => f.write_str(&self.cidr()),
+        }
+    }
+}
+
+/// Parse SoftEther's textual classless route list
+///
+/// Entries are `network/mask/gateway` where the mask is either a prefix
+/// length or a dotted netmask, separated by commas, semicolons or whitespace.
+/// Malformed entries are skipped.
+pub fn parse_route_list(text: &str) -> Vec<PushedRoute> {
+    text.split([',', ';', ' ', '\t', '\n', '\r'])
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| {
+            let route = parse_route_entry(entry);
+            if route.is_none() {
+                log::warn!("Ignoring malformed pushed route '{}'", entry);
+            }
+            route
+        })
+        .collect()
+}
+
+fn parse_route_entry(entry: &str) -> Option<PushedRoute> {
+    let mut parts = entry.split('/');
+    let network: Ipv4Addr = parts.next()?.parse().ok()?;
+    let mask = parts.next()?;
+    let gateway = match parts.next() {
+        Some(gateway) => Some(gateway.parse().ok()?),
+        None => None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+
+    let prefix_len = match mask.parse::<u8>() {
+        Ok(prefix_len) => prefix_len,
+        // Non-contiguous netmasks are rejected
+        Err(_) => net_util::prefix_len(mask.parse().ok()?)?,
+    };
+    PushedRoute::new(network, prefix_len, gateway)
+}
+
+/// Decode a DHCP classless static route option (RFC 3442, option 121/249)
+///
+/// Each entry is the prefix length, the significant octets of the
+/// destination and a 4-byte router. Decoding stops at the first truncated
+/// or invalid entry.
+pub fn decode_classless_option(data: &[u8]) -> Vec<PushedRoute> {
+    let mut routes = Vec::new();
+    let mut rest = data;
+
+    while let Some((&prefix_len, tail)) = rest.split_first() {
+        if prefix_len > 32 {
+            log::warn!("Invalid prefix length {} in classless route option", prefix_len);
+            break;
+        }
+        let significant = usize::from(prefix_len).div_ceil(8);
+        if tail.len() < significant + 4 {
+            log::warn!("Truncated classless route option");
+            break;
+        }
+
+        let mut network = [0u8; 4];
+        network[..significant].copy_from_slice(&tail[..significant]);
+        let router = Ipv4Addr::new(
+            tail[significant],
+            tail[significant + 1],
+            tail[significant + 2],
+            tail[significant + 3],
+        );
+        if let Some(route) = PushedRoute::new(Ipv4Addr::from(network), prefix_len, Some(router)) {
+            routes.push(route);
+        }
+        rest = &tail[significant + 4..];
+    }
+
+    routes
+}
+
+/// Collect all routes pushed in a server response, without duplicates
+pub fn from_pack(pack: &Pack) -> Vec<PushedRoute> {
+    let mut routes: Vec<PushedRoute> = Vec::new();
+
+    for name in ROUTE_TEXT_ELEMENTS {
+        match pack.get_str_array(name) {
+            Ok(entries) => {
+                for entry in entries {
+                    routes.extend(parse_route_list(&entry));
+                }
+            }
+            Err(e) => log::warn!("Ignoring malformed {} element: {}", name, e),
+        }
+    }
+
+    for name in ROUTE_OPTION_ELEMENTS {
+        if let Some(data) = pack.get_data(name) {
+            routes.extend(decode_classless_option(data));
+        }
+    }
+
+    let mut unique = Vec::with_capacity(routes.len());
+    for route in routes {
+        if !unique.contains(&route) {
+            unique.push(route);
+        }
+    }
+    unique
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_route_list() {
+        let routes = parse_route_list(
+            "192.168.5.0/24/192.168.4.254, 10.0.0.0/255.0.0.0/192.168.4.253;bogus 172.16.1.7/16",
+        );
+        assert_eq!(
+            routes,
+            vec![
+                PushedRoute::new(Ipv4Addr::new(192, 168, 5, 0), 24, Some(Ipv4Addr::new(192, 168, 4, 254))).unwrap(),
+                PushedRoute::new(Ipv4Addr::new(10, 0, 0, 0), 8, Some(Ipv4Addr::new(192, 168, 4, 253))).unwrap(),
+                PushedRoute::new(Ipv4Addr::new(172, 16, 0, 0), 16, None).unwrap(),
+            ]
+        );
+        assert_eq!(routes[2].cidr(), "172.16.0.0/16");
+        assert!(parse_route_list("10.0.0.0/255.0.255.0/10.0.0.1").is_empty());
+    }
+
+    #[test]
+    fn test_decode_classless_option() {
+        // RFC 3442 examples: 10.0.0.0/8 via 10.21.0.1, 0.0.0.0/0 via 10.21.0.1,
+        // 192.168.128.0/17 via 10.21.0.2
+        let data = [
+            8, 10, 10, 21, 0, 1, //
+            0, 10, 21, 0, 1, //
+            17, 192, 168, 128, 10, 21, 0, 2, //
+            24, 1, 2, // truncated
+        ];
+        let routes = decode_classless_option(&data);
+        assert_eq!(routes.len(), 3);
+        assert_eq!(routes[0].to_string(), "10.0.0.0/8 via 10.21.0.1");
+        assert!(routes[1].is_default());
+        assert_eq!(routes[2].cidr(), "192.168.128.0/17");
+    }
+
+    #[test]
+    fn test_from_pack_merges_sources() {
+        let mut pack = Pack::new();
+        pack.add_str("ClasslessRoute", "10.0.0.0/8/10.21.0.1");
+        pack.add_data("dhcp_option_121", vec![8, 10, 10, 21, 0, 1, 16, 172, 20, 10, 21, 0, 1]);
+        let routes = from_pack(&pack);
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[1].cidr(), "172.20.0.0/16");
+    }
+}
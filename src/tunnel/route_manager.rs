@@ -0,0 +1,590 @@
+//! Route planning and verification
+//!
+//! Which routes send traffic into the tunnel, and which keep the VPN server
+//! outside it, is decided by the [`RouteManager`] for the platform:
+//! [`plan`](RouteManager::plan) turns a [`RouteRequest`] into a [`RoutePlan`]
+//! of server bypass, subnet, default and split routes. `TunnelManager` applies
+//! those routes as part of its change plan, then asks
+//! [`verify`](RouteManager::verify) whether the host really sends traffic
+//! where the plan says. A change that fails or a check that does not hold
+//! rolls back everything applied so far, so the host is left either fully
+//! routed through the tunnel or as it was.
+
+use super::lease::Ipv6Lease;
+use super::plan::{ChangePlan, SystemChange};
+use super::platform::{Platform, PlatformOps};
+use crate::config::{RouteMode, RoutingConfig};
+use crate::error::{Result, VpnError};
+use crate::net_util;
+use crate::underlay::UnderlayBinding;
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+
+/// Address checked to reach the tunnel once IPv4 traffic is routed into it
+///
+/// From TEST-NET-1, which no configured or pushed route should cover.
+const PROBE_V4: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// IPv6 counterpart of [`PROBE_V4`], from the documentation prefix
+const PROBE_V6: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+
+/// Priority of the first policy routing rule, just ahead of the main table's (32766)
+const POLICY_RULE_PRIORITY: u32 = 32764;
+
+/// What a planned route is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteRole {
+    /// Keeps the VPN server (or the tunnel peer) on the uplink, so the tunnel does not carry itself
+    ServerBypass,
+    /// The tunnel's own subnet, when only that goes through the tunnel
+    Subnet,
+    /// Replaces the default route
+    Default,
+    /// Half of the address space, outranking the default route
+    Split,
+    /// Rule sending traffic to the table holding the tunnel's routes
+    Policy,
+}
+
+/// A route change and what it is for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedRoute {
+    pub role: RouteRole,
+    pub change: SystemChange,
+}
+
+impl PlannedRoute {
+    /// Destination and output interface, for changes that name both
+    fn target(&self) -> Option<(IpNet, &str)> {
+        match &self.change {
+            SystemChange::AddRoute { destination, interface: Some(interface), .. } => {
+                Some((net_util::parse_network(destination)?.trunc(), interface))
+            }
+            SystemChange::SetDefaultRoute { interface, .. } => {
+                Some((IpNet::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).ok()?, interface))
+            }
+            SystemChange::AddTableRoute { destination, interface, .. } if destination == "default" => {
+                Some((IpNet::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).ok()?, interface))
+            }
+            _ => None,
+        }
+    }
+
+    fn is_ipv6(&self) -> bool {
+        matches!(&self.change, SystemChange::AddRoute { destination, .. } if destination.contains(':'))
+    }
+}
+
+/// Routes the tunnel needs, in the order they are applied
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutePlan {
+    routes: Vec<PlannedRoute>,
+}
+
+impl RoutePlan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, role: RouteRole, change: SystemChange) {
+        self.routes.push(PlannedRoute { role, change });
+    }
+
+    pub fn routes(&self) -> &[PlannedRoute] {
+        &self.routes
+    }
+
+    /// IPv4 routes, or with `ipv6` the IPv6 ones
+    pub fn family(&self, ipv6: bool) -> impl Iterator<Item = &PlannedRoute> {
+        self.routes.iter().filter(move |route| route.is_ipv6() == ipv6)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// The routes a host review left of this plan, pushed into `plan` at `positions`
+    ///
+    /// Vetoed routes are dropped; replaced ones are taken as the host changed them.
+    pub fn reviewed(&self, plan: &ChangePlan, positions: &[usize]) -> Self {
+        let routes = self
+            .routes
+            .iter()
+            .zip(positions)
+            .filter_map(|(route, &index)| {
+                let item = plan.items().get(index).filter(|item| item.is_approved())?;
+                Some(PlannedRoute { role: route.role, change: item.change.clone() })
+            })
+            .collect();
+        Self { routes }
+    }
+}
+
+/// What routes are planned from
+#[derive(Debug, Clone, Copy)]
+pub struct RouteRequest<'a> {
+    /// Tunnel interface
+    pub interface: &'a str,
+    /// Own address and netmask on the tunnel
+    pub local_ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// Peer address, the next hop of IPv4 tunnel routes
+    pub gateway: Ipv4Addr,
+    pub ipv6: Option<&'a Ipv6Lease>,
+    pub vpn_server: Option<&'a str>,
+    /// Uplink chosen to carry the session
+    pub underlay: Option<&'a UnderlayBinding>,
+    /// Default gateway before the tunnel came up
+    pub original_gateway: Option<&'a str>,
+    /// Route only the tunnel's own subnets and leave the default route alone
+    pub split_only: bool,
+    pub metric: Option<u32>,
+}
+
+impl RouteRequest<'_> {
+    fn server_is_ipv6(&self) -> bool {
+        self.vpn_server.is_some_and(|ip| ip.contains(':'))
+    }
+
+    fn tunnel_route(&self, role: RouteRole, destination: &str, gateway: Option<String>, plan: &mut RoutePlan) {
+        plan.push(
+            role,
+            SystemChange::AddRoute {
+                destination: destination.to_string(),
+                gateway,
+                interface: Some(self.interface.to_string()),
+                metric: self.metric,
+            },
+        );
+    }
+}
+
+/// Route planning for one platform's routing model
+pub trait RouteManager: Send + Sync {
+    /// Routes sending all IPv4 traffic into the tunnel, and what keeps the server outside it
+    fn full_tunnel_v4(&self, request: &RouteRequest<'_>, ops: &dyn PlatformOps, plan: &mut RoutePlan);
+
+    /// The IPv6 counterpart of [`full_tunnel_v4`](Self::full_tunnel_v4)
+    fn full_tunnel_v6(&self, request: &RouteRequest<'_>, ipv6: &Ipv6Lease, ops: &dyn PlatformOps, plan: &mut RoutePlan) {
+        // An IPv6 server must keep using the IPv6 uplink, or the split routes below loop it
+        if let Some(vpn_server) = request.vpn_server.filter(|_| request.server_is_ipv6() && request.underlay.is_none()) {
+            match ops.underlay_route_v6() {
+                Some((gateway, interface)) => plan.push(
+                    RouteRole::ServerBypass,
+                    SystemChange::AddRoute {
+                        destination: net_util::host_cidr(vpn_server),
+                        gateway: Some(gateway),
+                        interface: Some(interface),
+                        metric: None,
+                    },
+                ),
+                None => log::warn!("No IPv6 default route to keep VPN server {} reachable", vpn_server),
+            }
+        }
+
+        // The IPv6 counterpart of the 0.0.0.0/1 + 128.0.0.0/1 pair
+        for destination in ["::/1", "8000::/1"] {
+            request.tunnel_route(RouteRole::Split, destination, ipv6.gateway.map(|gw| gw.to_string()), plan);
+        }
+    }
+
+    /// Routes for `request`, querying the host through `ops` where needed
+    ///
+    /// Fails if only the tunnel subnet is to be routed and the netmask is not a prefix.
+    fn plan(&self, request: &RouteRequest<'_>, ops: &dyn PlatformOps) -> Result<RoutePlan> {
+        let mut plan = RoutePlan::new();
+
+        // A chosen uplink carries the session whatever the routing mode
+        if let (Some(underlay), Some(vpn_server)) = (request.underlay, request.vpn_server) {
+            log::info!(interface = underlay.interface.as_str(); "Pinning VPN server to the chosen uplink");
+            plan.push(
+                RouteRole::ServerBypass,
+                SystemChange::AddRoute {
+                    destination: net_util::host_cidr(vpn_server),
+                    // The uplink's gateway is IPv4; an IPv6 server leaves via the interface
+                    gateway: underlay.gateway.filter(|_| !request.server_is_ipv6()).map(|gw| gw.to_string()),
+                    interface: Some(underlay.interface.clone()),
+                    metric: None,
+                },
+            );
+        }
+
+        if request.split_only {
+            // Leave the default route, the other VPN's routes and its NAT rules alone
+            let subnet = net_util::ipv4_subnet(request.local_ip, request.netmask).ok_or_else(|| {
+                VpnError::Routing(format!("Tunnel netmask {} is not a prefix", request.netmask))
+            })?;
+            request.tunnel_route(RouteRole::Subnet, &subnet.to_string(), None, &mut plan);
+        } else {
+            self.full_tunnel_v4(request, ops, &mut plan);
+        }
+
+        if let Some(ipv6) = request.ipv6 {
+            if request.split_only {
+                request.tunnel_route(RouteRole::Subnet, &ipv6.network_cidr(), None, &mut plan);
+            } else {
+                self.full_tunnel_v6(request, ipv6, ops, &mut plan);
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Check that the host routes as `plan` intends
+    ///
+    /// The VPN server has to leave through its bypass route's interface and a
+    /// probe address through the tunnel once all traffic is routed into it.
+    /// Where the host cannot say which interface it would use, nothing is
+    /// checked.
+    fn verify(&self, plan: &RoutePlan, ops: &dyn PlatformOps) -> Result<()> {
+        let mut checks: Vec<(IpAddr, &str)> = Vec::new();
+        for route in plan.routes() {
+            let Some((destination, interface)) = route.target() else {
+                continue;
+            };
+            let address = match route.role {
+                RouteRole::ServerBypass => destination.addr(),
+                RouteRole::Default | RouteRole::Split => {
+                    let probe = if destination.addr().is_ipv6() { IpAddr::V6(PROBE_V6) } else { IpAddr::V4(PROBE_V4) };
+                    if !destination.contains(&probe) {
+                        continue;
+                    }
+                    probe
+                }
+                RouteRole::Subnet | RouteRole::Policy => continue,
+            };
+            if !checks.contains(&(address, interface)) {
+                checks.push((address, interface));
+            }
+        }
+
+        for (address, expected) in checks {
+            match ops.route_interface(address) {
+                Some(actual) if actual != expected => {
+                    return Err(VpnError::Routing(format!(
+                        "Route check failed: traffic to {address} leaves through {actual} instead of {expected}"
+                    )));
+                }
+                Some(_) => log::debug!("Route check: {} leaves through {}", address, expected),
+                None => log::debug!("Route check skipped: no route lookup for {}", address),
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the routes of `plan` that do not go away with the tunnel interface
+    fn remove(&self, plan: &RoutePlan, ops: &dyn PlatformOps) {
+        let _ = (plan, ops);
+    }
+
+    /// Whether full-tunnel plans replace the default route, which teardown then restores
+    fn replaces_default_route(&self) -> bool {
+        true
+    }
+}
+
+/// Route manager for `platform`
+pub fn for_platform(platform: Platform) -> Arc<dyn RouteManager> {
+    match platform {
+        Platform::Linux => Arc::new(LinuxRoutes),
+        Platform::MacOs => Arc::new(MacOsRoutes),
+        Platform::Windows => Arc::new(WindowsRoutes),
+        Platform::Other => Arc::new(SplitOnlyRoutes),
+    }
+}
+
+/// Route manager for `platform` in the configured routing mode
+///
+/// Policy routing needs Linux; elsewhere the platform's usual routes are planned.
+pub fn for_routing(platform: Platform, routing: &RoutingConfig) -> Arc<dyn RouteManager> {
+    match (platform, routing.mode) {
+        (Platform::Linux, RouteMode::Policy) => Arc::new(PolicyRoutes::new(routing)),
+        (platform, RouteMode::Policy) => {
+            log::warn!("Policy routing is only available on Linux; replacing the default route instead");
+            for_platform(platform)
+        }
+        (platform, RouteMode::Replace) => for_platform(platform),
+    }
+}
+
+/// Keep the VPN server on the current IPv4 uplink, unless a chosen one already carries it
+fn bypass_server_v4(request: &RouteRequest<'_>, ops: &dyn PlatformOps, plan: &mut RoutePlan) {
+    if request.underlay.is_some() {
+        return;
+    }
+    let (default_gw, active_interface) = ops.underlay_route();
+    log::info!(
+        gateway = default_gw.as_str(),
+        interface = active_interface.as_str();
+        "Keeping the VPN server on the original gateway"
+    );
+    if let Some(vpn_server) = request.vpn_server.filter(|_| !request.server_is_ipv6()) {
+        plan.push(
+            RouteRole::ServerBypass,
+            SystemChange::AddRoute {
+                destination: net_util::host_cidr(vpn_server),
+                gateway: Some(default_gw),
+                interface: Some(active_interface),
+                metric: None,
+            },
+        );
+    }
+}
+
+/// Cover both halves of the IPv4 space - more specific than the default
+/// route, so they win even if something re-adds the original default
+fn split_pair_v4(request: &RouteRequest<'_>, plan: &mut RoutePlan) {
+    for destination in ["0.0.0.0/1", "128.0.0.0/1"] {
+        request.tunnel_route(RouteRole::Split, destination, Some(request.gateway.to_string()), plan);
+    }
+}
+
+/// Linux: the default route is replaced and backed by the /1 pair
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinuxRoutes;
+
+impl RouteManager for LinuxRoutes {
+    fn full_tunnel_v4(&self, request: &RouteRequest<'_>, ops: &dyn PlatformOps, plan: &mut RoutePlan) {
+        bypass_server_v4(request, ops, plan);
+        plan.push(
+            RouteRole::Default,
+            SystemChange::SetDefaultRoute {
+                gateway: Some(request.gateway.to_string()),
+                interface: request.interface.to_string(),
+                metric: request.metric,
+            },
+        );
+        split_pair_v4(request, plan);
+    }
+}
+
+/// Linux policy routing: the tunnel's default route lives in a table of its
+/// own, which rules consult for all traffic but the session's own
+///
+/// The session's sockets carry `fwmark` and keep following the main table,
+/// so the main default route stays as it is and the server needs no bypass
+/// route. The main table is still consulted first, minus its default route,
+/// so the host's own subnets stay reachable.
+#[derive(Debug, Clone, Copy)]
+pub struct PolicyRoutes {
+    pub table: u32,
+    pub fwmark: u32,
+}
+
+impl PolicyRoutes {
+    pub fn new(routing: &RoutingConfig) -> Self {
+        Self { table: routing.policy_table, fwmark: routing.socket_mark }
+    }
+}
+
+impl RouteManager for PolicyRoutes {
+    fn full_tunnel_v4(&self, request: &RouteRequest<'_>, _ops: &dyn PlatformOps, plan: &mut RoutePlan) {
+        plan.push(
+            RouteRole::Default,
+            SystemChange::AddTableRoute {
+                destination: "default".to_string(),
+                gateway: Some(request.gateway.to_string()),
+                interface: request.interface.to_string(),
+                table: self.table,
+            },
+        );
+        plan.push(
+            RouteRole::Policy,
+            SystemChange::AddPolicyRules { fwmark: self.fwmark, table: self.table, priority: POLICY_RULE_PRIORITY },
+        );
+    }
+
+    /// The table route leaves with the interface, the rules stay behind
+    fn remove(&self, plan: &RoutePlan, ops: &dyn PlatformOps) {
+        for route in plan.routes() {
+            if let SystemChange::AddPolicyRules { fwmark, table, priority } = route.change {
+                let _ = ops.delete_policy_rules(fwmark, table, priority);
+            }
+        }
+    }
+
+    fn replaces_default_route(&self) -> bool {
+        false
+    }
+}
+
+/// macOS: the default route moves to the utun interface, with the peer kept
+/// on the original gateway
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MacOsRoutes;
+
+impl RouteManager for MacOsRoutes {
+    fn full_tunnel_v4(&self, request: &RouteRequest<'_>, _ops: &dyn PlatformOps, plan: &mut RoutePlan) {
+        let Some(original_gateway) = request.original_gateway else {
+            return;
+        };
+        plan.push(
+            RouteRole::ServerBypass,
+            SystemChange::AddRoute {
+                destination: format!("{}/32", request.gateway),
+                gateway: Some(original_gateway.to_string()),
+                interface: None,
+                metric: None,
+            },
+        );
+        plan.push(
+            RouteRole::Default,
+            SystemChange::SetDefaultRoute {
+                gateway: None,
+                interface: request.interface.to_string(),
+                metric: request.metric,
+            },
+        );
+    }
+}
+
+/// Windows: the default route stays and the /1 pair outranks it
+///
+/// The adapter outlives the session, and so would every route through it,
+/// so teardown removes them one by one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WindowsRoutes;
+
+impl RouteManager for WindowsRoutes {
+    fn full_tunnel_v4(&self, request: &RouteRequest<'_>, ops: &dyn PlatformOps, plan: &mut RoutePlan) {
+        bypass_server_v4(request, ops, plan);
+        split_pair_v4(request, plan);
+    }
+
+    fn remove(&self, plan: &RoutePlan, ops: &dyn PlatformOps) {
+        for route in plan.routes() {
+            if let SystemChange::AddRoute { destination, interface, .. } = &route.change {
+                let _ = ops.delete_route(destination, interface.as_deref());
+            }
+        }
+    }
+}
+
+/// Platforms without a routing backend: only subnet routes and a pinned uplink
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SplitOnlyRoutes;
+
+impl RouteManager for SplitOnlyRoutes {
+    fn full_tunnel_v4(&self, _request: &RouteRequest<'_>, _ops: &dyn PlatformOps, _plan: &mut RoutePlan) {}
+
+    fn full_tunnel_v6(&self, _request: &RouteRequest<'_>, _ipv6: &Ipv6Lease, _ops: &dyn PlatformOps, _plan: &mut RoutePlan) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel::platform::{PlatformOp, RecordingOps};
+
+    fn request(vpn_server: Option<&str>) -> RouteRequest<'_> {
+        RouteRequest {
+            interface: "vpnse0",
+            local_ip: Ipv4Addr::new(10, 0, 0, 2),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: Ipv4Addr::new(10, 0, 0, 1),
+            ipv6: None,
+            vpn_server,
+            underlay: None,
+            original_gateway: Some("192.168.1.1"),
+            split_only: false,
+            metric: Some(50),
+        }
+    }
+
+    fn roles(plan: &RoutePlan) -> Vec<RouteRole> {
+        plan.routes().iter().map(|route| route.role).collect()
+    }
+
+    #[test]
+    fn test_platform_plans() {
+        use RouteRole::{Default, ServerBypass, Split, Subnet};
+        let ops = RecordingOps::new(Platform::Linux).with_underlay_route("192.168.1.1", "wlan0");
+        let request = request(Some("203.0.113.10"));
+
+        let linux = for_platform(Platform::Linux).plan(&request, &ops).unwrap();
+        assert_eq!(roles(&linux), [ServerBypass, Default, Split, Split]);
+        let windows = for_platform(Platform::Windows).plan(&request, &ops).unwrap();
+        assert_eq!(roles(&windows), [ServerBypass, Split, Split]);
+        let macos = for_platform(Platform::MacOs).plan(&request, &ops).unwrap();
+        assert_eq!(roles(&macos), [ServerBypass, Default]);
+        assert!(for_platform(Platform::Other).plan(&request, &ops).unwrap().is_empty());
+
+        let split = RouteRequest { split_only: true, ..request };
+        assert_eq!(roles(&for_platform(Platform::Linux).plan(&split, &ops).unwrap()), [Subnet]);
+        let bad_mask = RouteRequest { netmask: Ipv4Addr::new(255, 0, 255, 0), ..split };
+        assert!(matches!(for_platform(Platform::Linux).plan(&bad_mask, &ops), Err(VpnError::Routing(_))));
+    }
+
+    #[test]
+    fn test_policy_routing_plan() {
+        let routing = RoutingConfig { mode: RouteMode::Policy, ..RoutingConfig::default() };
+        let ops = RecordingOps::new(Platform::Linux).with_underlay_route("192.168.1.1", "wlan0");
+        let routes = for_routing(Platform::Linux, &routing);
+        assert!(!routes.replaces_default_route());
+
+        // No server bypass and the main default route left alone
+        let plan = routes.plan(&request(Some("203.0.113.10")), &ops).unwrap();
+        assert_eq!(roles(&plan), [RouteRole::Default, RouteRole::Policy]);
+        assert_eq!(plan.routes()[0].change.to_string(), "route add default via 10.0.0.1 dev vpnse0 table 51820");
+        let rules = SystemChange::AddPolicyRules { fwmark: 51820, table: 51820, priority: 32764 };
+        assert_eq!(plan.routes()[1].change, rules);
+
+        // The probe is checked through the tunnel table
+        let leaking = RecordingOps::new(Platform::Linux).with_route_lookup("192.0.2.1", "wlan0");
+        assert!(routes.verify(&plan, &leaking).is_err());
+
+        routes.remove(&plan, &ops);
+        assert_eq!(
+            ops.recorded(),
+            [PlatformOp::DeletePolicyRules { fwmark: 51820, table: 51820, priority: 32764 }]
+        );
+
+        // Other platforms keep replacing the default route
+        assert!(for_routing(Platform::Windows, &routing).replaces_default_route());
+    }
+
+    #[test]
+    fn test_verify_checks_server_and_tunnel_paths() {
+        let request = request(Some("203.0.113.10"));
+        let routes = for_platform(Platform::Linux);
+        let ops = RecordingOps::new(Platform::Linux).with_underlay_route("192.168.1.1", "wlan0");
+        let plan = routes.plan(&request, &ops).unwrap();
+
+        // Nothing to look routes up with: nothing is checked
+        assert!(routes.verify(&plan, &ops).is_ok());
+
+        let ops = ops.with_route_lookup("203.0.113.10", "wlan0").with_route_lookup("192.0.2.1", "vpnse0");
+        assert!(routes.verify(&plan, &ops).is_ok());
+
+        // The server looping into the tunnel fails the check
+        let looped = RecordingOps::new(Platform::Linux).with_route_lookup("203.0.113.10", "vpnse0");
+        let err = routes.verify(&plan, &looped).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Routing error: Route check failed: traffic to 203.0.113.10 leaves through vpnse0 instead of wlan0"
+        );
+        // As does traffic still leaving through the uplink
+        let leaking = RecordingOps::new(Platform::Linux).with_route_lookup("192.0.2.1", "wlan0");
+        assert!(routes.verify(&plan, &leaking).is_err());
+    }
+
+    #[test]
+    fn test_review_keeps_route_roles() {
+        let ops = RecordingOps::new(Platform::Windows).with_underlay_route("192.168.1.1", "Wi-Fi");
+        let routes = for_platform(Platform::Windows).plan(&request(Some("203.0.113.10")), &ops).unwrap();
+        let mut plan = ChangePlan::new();
+        plan.push(SystemChange::SetSysctl { key: "net.ipv4.ip_forward".to_string(), value: "1".to_string() });
+        let positions: Vec<usize> = routes
+            .routes()
+            .iter()
+            .map(|route| {
+                plan.push(route.change.clone());
+                plan.len() - 1
+            })
+            .collect();
+        plan.veto(2, "managed");
+
+        let reviewed = routes.reviewed(&plan, &positions);
+        assert_eq!(roles(&reviewed), [RouteRole::ServerBypass, RouteRole::Split]);
+        assert_eq!(reviewed.routes()[1], routes.routes()[2]);
+    }
+}
@@ -0,0 +1,709 @@
+//! rtnetlink routing backend for Linux
+//!
+//! Routes (IPv4 and IPv6), interface addresses and policy rules are installed with `RTM_*` requests on a
+//! `NETLINK_ROUTE` socket and kernel parameters are written straight to
+//! `/proc/sys`. Every request is acknowledged by the kernel, so a rejected
+//! change surfaces as an error carrying the kernel's errno rather than being
+//! lost in the output of a shell command.
+
+use crate::error::{Result, VpnError};
+use crate::net_util;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+
+/// Main routing table
+pub const RT_TABLE_MAIN: u32 = 254;
+
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_GETROUTE: u16 = 26;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_ACK: u16 = 0x04;
+const NLM_F_DUMP: u16 = 0x300;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_PRIORITY: u16 = 6;
+const RTA_TABLE: u16 = 15;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_F_NODAD: u8 = 0x02;
+
+const FRA_DST: u16 = 1;
+const FRA_SRC: u16 = 2;
+const FRA_PRIORITY: u16 = 6;
+const FRA_FWMARK: u16 = 10;
+const FRA_SUPPRESS_PREFIXLEN: u16 = 14;
+const FRA_TABLE: u16 = 15;
+
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RT_SCOPE_NOWHERE: u8 = 255;
+const RTN_UNICAST: u8 = 1;
+const FR_ACT_TO_TBL: u8 = 1;
+const FIB_RULE_INVERT: u32 = 0x2;
+
+const NLMSG_HDR_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+
+/// An IPv4 or IPv6 route; the family is that of `destination`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub destination: IpAddr,
+    pub prefix_len: u8,
+    pub gateway: Option<IpAddr>,
+    /// Output interface index
+    pub oif: Option<u32>,
+    pub metric: Option<u32>,
+    pub table: u32,
+}
+
+impl Route {
+    /// Route to `cidr` ("10.0.0.0/8", "1.2.3.4", "::/1" or "default") in the main table
+    pub fn to(cidr: &str) -> Result<Self> {
+        let (destination, prefix_len) = parse_cidr(cidr)?;
+        Ok(Self {
+            destination,
+            prefix_len,
+            gateway: None,
+            oif: None,
+            metric: None,
+            table: RT_TABLE_MAIN,
+        })
+    }
+
+    /// Whether this is a default route
+    pub fn is_default(&self) -> bool {
+        self.prefix_len == 0
+    }
+}
+
+/// A policy routing rule sending matching traffic to `table`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub priority: Option<u32>,
+    pub from: Option<(Ipv4Addr, u8)>,
+    pub to: Option<(Ipv4Addr, u8)>,
+    pub fwmark: Option<u32>,
+    /// Match what the selectors above do not (`ip rule add not ...`)
+    pub invert: bool,
+    /// Ignore routes in `table` with a prefix this short or shorter
+    pub suppress_prefixlength: Option<u32>,
+    pub table: u32,
+}
+
+/// Parse a destination in CIDR notation; a bare address is a host route
+///
+/// `default` is the IPv4 default route; the IPv6 one is written `::/0`.
+pub fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8)> {
+    if cidr == "default" {
+        return Ok((IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    }
+    let network = net_util::parse_network(cidr)
+        .ok_or_else(|| VpnError::Routing(format!("Invalid route destination: {cidr}")))?;
+    Ok((network.addr(), network.prefix_len()))
+}
+
+fn family(addr: IpAddr) -> u8 {
+    match addr {
+        IpAddr::V4(_) => libc::AF_INET as u8,
+        IpAddr::V6(_) => libc::AF_INET6 as u8,
+    }
+}
+
+fn octets(addr: IpAddr) -> Vec<u8> {
+    match addr {
+        IpAddr::V4(addr) => addr.octets().to_vec(),
+        IpAddr::V6(addr) => addr.octets().to_vec(),
+    }
+}
+
+/// Index of the interface called `name`
+pub fn interface_index(name: &str) -> Result<u32> {
+    let c_name = std::ffi::CString::new(name)
+        .map_err(|_| VpnError::Routing(format!("Invalid interface name: {name}")))?;
+    // SAFETY: c_name is a valid NUL-terminated string
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(VpnError::Routing(format!("Interface {name} not found"))),
+        index => Ok(index),
+    }
+}
+
+/// Name of the interface with index `index`
+pub fn interface_name(index: u32) -> Option<String> {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    // SAFETY: buf holds IF_NAMESIZE bytes as if_indextoname requires
+    let name = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if name.is_null() {
+        return None;
+    }
+    // SAFETY: on success buf holds a NUL-terminated name
+    let name = unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Write a kernel parameter, returning its previous value
+///
+/// `key` uses sysctl(8) notation, e.g. `net.ipv4.conf.all.rp_filter`.
+pub fn write_sysctl(key: &str, value: &str) -> Result<String> {
+    let path = sysctl_path(key);
+    let previous = std::fs::read_to_string(&path)
+        .map_err(|e| sysctl_error(key, e))?
+        .trim()
+        .to_string();
+    std::fs::write(&path, value).map_err(|e| sysctl_error(key, e))?;
+    Ok(previous)
+}
+
+fn sysctl_path(key: &str) -> PathBuf {
+    PathBuf::from("/proc/sys").join(key.replace('.', "/"))
+}
+
+fn sysctl_error(key: &str, error: io::Error) -> VpnError {
+    match error.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => {
+            VpnError::Permission(format!("Writing {key} requires root or CAP_NET_ADMIN"))
+        }
+        Some(libc::EROFS) => VpnError::CapabilityUnavailable(format!("{key}: /proc/sys is mounted read-only")),
+        _ => VpnError::Routing(format!("Failed to set {key}: {error}")),
+    }
+}
+
+/// A `NETLINK_ROUTE` socket
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    /// Open a routing socket
+    pub fn open() -> Result<Self> {
+        // SAFETY: plain socket(2) call; the result is checked before use
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_ROUTE)
+        };
+        if fd < 0 {
+            return Err(VpnError::Routing(format!(
+                "Failed to open netlink socket: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        // SAFETY: fd was just returned by socket(2) and is owned by nobody else
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: sockaddr_nl is plain data; all-zero binds to a kernel-assigned port
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        // SAFETY: addr is a valid sockaddr_nl of the length passed
+        let rc = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if rc < 0 {
+            return Err(VpnError::Routing(format!(
+                "Failed to bind netlink socket: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        Ok(Self { fd, seq: 0 })
+    }
+
+    /// Add `route`, replacing an existing route to the same destination
+    pub fn replace_route(&mut self, route: &Route) -> Result<()> {
+        let msg = route_message(route, RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE);
+        self.request(msg).map_err(|e| route_error("add", route, e))
+    }
+
+    /// Delete `route`; unset fields match any route
+    pub fn delete_route(&mut self, route: &Route) -> Result<()> {
+        let msg = route_message(route, RTM_DELROUTE, 0);
+        self.request(msg).map_err(|e| route_error("delete", route, e))
+    }
+
+    /// IPv4 default routes in the main table
+    pub fn default_routes(&mut self) -> Result<Vec<Route>> {
+        self.main_default_routes(libc::AF_INET as u8)
+    }
+
+    /// IPv6 default routes in the main table
+    pub fn default_routes_v6(&mut self) -> Result<Vec<Route>> {
+        self.main_default_routes(libc::AF_INET6 as u8)
+    }
+
+    fn main_default_routes(&mut self, family: u8) -> Result<Vec<Route>> {
+        let mut msg = Message::new(RTM_GETROUTE, NLM_F_DUMP);
+        msg.push_rtmsg(family, 0, 0, 0, 0, 0);
+        let routes = self
+            .dump(msg)
+            .map_err(|e| VpnError::Routing(format!("Failed to read routing table: {e}")))?
+            .iter()
+            .filter_map(|payload| parse_route(payload))
+            .filter(|route| route.is_default() && route.table == RT_TABLE_MAIN)
+            .collect();
+        Ok(routes)
+    }
+
+    /// The route the kernel would use for traffic to `destination`
+    pub fn route_get(&mut self, destination: IpAddr) -> Result<Route> {
+        let mut msg = Message::new(RTM_GETROUTE, 0);
+        let host_len = if destination.is_ipv4() { 32 } else { 128 };
+        msg.push_rtmsg(family(destination), host_len, 0, 0, 0, 0);
+        msg.push_attr(RTA_DST, &octets(destination));
+        let what = || format!("look up the route to {destination}");
+        let payload = self.query(msg).map_err(|e| netlink_error(&what(), e))?;
+        parse_route(&payload).ok_or_else(|| VpnError::Routing(format!("Cannot {}: no unicast route", what())))
+    }
+
+    /// Assign `address`/`prefix_len` to interface `oif`
+    ///
+    /// IPv6 addresses skip duplicate address detection: a tunnel has no
+    /// neighbours to collide with, and DAD would keep the address unusable
+    /// for the first second or so.
+    pub fn add_address(&mut self, oif: u32, address: IpAddr, prefix_len: u8) -> Result<()> {
+        let msg = address_message(oif, address, prefix_len, RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE);
+        self.request(msg)
+            .map_err(|e| netlink_error(&format!("add address {address}/{prefix_len}"), e))
+    }
+
+    /// Swap every default route for `route`
+    ///
+    /// The routes that were removed are put back if any step fails, so the
+    /// host is never left without a default route.
+    pub fn replace_default_route(&mut self, route: &Route) -> Result<()> {
+        let previous = self.default_routes()?;
+        let mut removed = Vec::new();
+        let result = previous
+            .iter()
+            .try_for_each(|old| {
+                self.delete_route(old)?;
+                removed.push(old.clone());
+                Ok(())
+            })
+            .and_then(|()| self.replace_route(route));
+
+        if result.is_err() {
+            for old in &removed {
+                if let Err(e) = self.replace_route(old) {
+                    log::error!("Failed to restore default route: {}", e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Delete the default routes leaving through interface `oif`
+    pub fn delete_default_routes_via(&mut self, oif: u32) -> Result<()> {
+        for route in self.default_routes()? {
+            if route.oif == Some(oif) {
+                self.delete_route(&route)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a policy routing rule; an identical existing rule is not an error
+    pub fn add_rule(&mut self, rule: &Rule) -> Result<()> {
+        match self.request(rule_message(rule, RTM_NEWRULE, NLM_F_CREATE | NLM_F_EXCL)) {
+            Err(e) if e.raw_os_error() == Some(libc::EEXIST) => Ok(()),
+            result => result.map_err(|e| rule_error("add", rule, e)),
+        }
+    }
+
+    /// Delete a policy routing rule
+    pub fn delete_rule(&mut self, rule: &Rule) -> Result<()> {
+        self.request(rule_message(rule, RTM_DELRULE, 0))
+            .map_err(|e| rule_error("delete", rule, e))
+    }
+
+    /// Send a request and wait for the kernel's acknowledgement
+    fn request(&mut self, msg: Message) -> io::Result<()> {
+        let seq = self.send(msg, NLM_F_ACK)?;
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let len = self.recv(&mut buf)?;
+            for (header, payload) in messages(&buf[..len]) {
+                if header.seq == seq && header.kind == NLMSG_ERROR {
+                    return ack_result(payload);
+                }
+            }
+        }
+    }
+
+    /// Send a request answered by a single message and return its payload
+    fn query(&mut self, msg: Message) -> io::Result<Vec<u8>> {
+        let seq = self.send(msg, 0)?;
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let len = self.recv(&mut buf)?;
+            for (header, payload) in messages(&buf[..len]) {
+                if header.seq != seq {
+                    continue;
+                }
+                if header.kind == NLMSG_ERROR {
+                    ack_result(payload)?;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "empty netlink reply"));
+                }
+                return Ok(payload.to_vec());
+            }
+        }
+    }
+
+    /// Send a dump request and collect the payloads of all replies
+    fn dump(&mut self, msg: Message) -> io::Result<Vec<Vec<u8>>> {
+        let seq = self.send(msg, 0)?;
+        let mut buf = vec![0u8; 32768];
+        let mut payloads = Vec::new();
+        loop {
+            let len = self.recv(&mut buf)?;
+            for (header, payload) in messages(&buf[..len]) {
+                if header.seq != seq {
+                    continue;
+                }
+                match header.kind {
+                    NLMSG_DONE => return Ok(payloads),
+                    NLMSG_ERROR => {
+                        ack_result(payload)?;
+                        return Ok(payloads);
+                    }
+                    _ => payloads.push(payload.to_vec()),
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, mut msg: Message, extra_flags: u16) -> io::Result<u32> {
+        self.seq = self.seq.wrapping_add(1);
+        let bytes = msg.finish(self.seq, extra_flags);
+        // SAFETY: bytes is a valid buffer of the given length
+        let sent = unsafe { libc::send(self.fd.as_raw_fd(), bytes.as_ptr().cast(), bytes.len(), 0) };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(self.seq)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // SAFETY: buf is a valid writable buffer of the given length
+            let len = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+            if len >= 0 {
+                return Ok(len as usize);
+            }
+            let error = io::Error::last_os_error();
+            if error.kind() != io::ErrorKind::Interrupted {
+                return Err(error);
+            }
+        }
+    }
+}
+
+fn route_error(action: &str, route: &Route, error: io::Error) -> VpnError {
+    let what = format!("{action} route {}/{}", route.destination, route.prefix_len);
+    netlink_error(&what, error)
+}
+
+fn rule_error(action: &str, rule: &Rule, error: io::Error) -> VpnError {
+    netlink_error(&format!("{action} rule to table {}", rule.table), error)
+}
+
+fn netlink_error(what: &str, error: io::Error) -> VpnError {
+    match error.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EACCES) => {
+            VpnError::Permission(format!("Cannot {what}: CAP_NET_ADMIN required"))
+        }
+        _ => VpnError::Routing(format!("Cannot {what}: {error}")),
+    }
+}
+
+/// Interpret the payload of an `NLMSG_ERROR` reply (errno 0 is an ACK)
+fn ack_result(payload: &[u8]) -> io::Result<()> {
+    let errno = payload
+        .get(..4)
+        .map(|b| i32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated netlink error"))?;
+    match errno {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(-errno)),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    kind: u16,
+    seq: u32,
+}
+
+/// Split a receive buffer into (header, payload) pairs
+fn messages(mut buf: &[u8]) -> Vec<(Header, &[u8])> {
+    let mut out = Vec::new();
+    while buf.len() >= NLMSG_HDR_LEN {
+        let len = u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        if len < NLMSG_HDR_LEN || len > buf.len() {
+            break;
+        }
+        let header = Header {
+            kind: u16::from_ne_bytes([buf[4], buf[5]]),
+            seq: u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        };
+        out.push((header, &buf[NLMSG_HDR_LEN..len]));
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    out
+}
+
+/// Iterate the (type, data) attributes following a fixed-size header
+fn attributes(mut buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut out = Vec::new();
+    while buf.len() >= 4 {
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        if len < 4 || len > buf.len() {
+            break;
+        }
+        out.push((u16::from_ne_bytes([buf[2], buf[3]]), &buf[4..len]));
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    out
+}
+
+/// Decode an `RTM_NEWROUTE` payload into an IPv4 or IPv6 unicast route
+fn parse_route(payload: &[u8]) -> Option<Route> {
+    if payload.len() < RTMSG_LEN || payload[7] != RTN_UNICAST {
+        return None;
+    }
+    let unspecified = match i32::from(payload[0]) {
+        libc::AF_INET => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        libc::AF_INET6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        _ => return None,
+    };
+    let parse_addr = |data: &[u8]| match unspecified {
+        IpAddr::V4(_) => <[u8; 4]>::try_from(data).ok().map(IpAddr::from),
+        IpAddr::V6(_) => <[u8; 16]>::try_from(data).ok().map(IpAddr::from),
+    };
+    let mut route = Route {
+        destination: unspecified,
+        prefix_len: payload[1],
+        gateway: None,
+        oif: None,
+        metric: None,
+        table: u32::from(payload[4]),
+    };
+    for (kind, data) in attributes(&payload[RTMSG_LEN..]) {
+        let word = <[u8; 4]>::try_from(data).ok();
+        match kind {
+            RTA_DST => route.destination = parse_addr(data)?,
+            RTA_GATEWAY => route.gateway = Some(parse_addr(data)?),
+            RTA_OIF => route.oif = Some(u32::from_ne_bytes(word?)),
+            RTA_PRIORITY => route.metric = Some(u32::from_ne_bytes(word?)),
+            RTA_TABLE => route.table = u32::from_ne_bytes(word?),
+            _ => {}
+        }
+    }
+    Some(route)
+}
+
+fn route_message(route: &Route, kind: u16, flags: u16) -> Message {
+    let mut msg = Message::new(kind, flags);
+    let (protocol, scope, route_type) = if kind == RTM_DELROUTE {
+        (0, RT_SCOPE_NOWHERE, 0)
+    } else if route.gateway.is_some() {
+        (RTPROT_BOOT, RT_SCOPE_UNIVERSE, RTN_UNICAST)
+    } else {
+        (RTPROT_BOOT, RT_SCOPE_LINK, RTN_UNICAST)
+    };
+    msg.push_rtmsg(family(route.destination), route.prefix_len, table_byte(route.table), protocol, scope, route_type);
+    if route.prefix_len > 0 {
+        msg.push_attr(RTA_DST, &octets(route.destination));
+    }
+    if let Some(gateway) = route.gateway {
+        msg.push_attr(RTA_GATEWAY, &octets(gateway));
+    }
+    if let Some(oif) = route.oif {
+        msg.push_attr(RTA_OIF, &oif.to_ne_bytes());
+    }
+    if let Some(metric) = route.metric {
+        msg.push_attr(RTA_PRIORITY, &metric.to_ne_bytes());
+    }
+    msg.push_attr(RTA_TABLE, &route.table.to_ne_bytes());
+    msg
+}
+
+fn address_message(oif: u32, address: IpAddr, prefix_len: u8, kind: u16, flags: u16) -> Message {
+    let mut msg = Message::new(kind, flags);
+    let ifa_flags = if address.is_ipv6() { IFA_F_NODAD } else { 0 };
+    // struct ifaddrmsg
+    msg.buf.extend_from_slice(&[family(address), prefix_len, ifa_flags, RT_SCOPE_UNIVERSE]);
+    msg.buf.extend_from_slice(&oif.to_ne_bytes());
+    msg.push_attr(IFA_LOCAL, &octets(address));
+    msg.push_attr(IFA_ADDRESS, &octets(address));
+    msg
+}
+
+fn rule_message(rule: &Rule, kind: u16, flags: u16) -> Message {
+    let mut msg = Message::new(kind, flags);
+    // struct fib_rule_hdr
+    msg.buf.extend_from_slice(&[
+        libc::AF_INET as u8,
+        rule.to.map_or(0, |(_, len)| len),
+        rule.from.map_or(0, |(_, len)| len),
+        0,
+        table_byte(rule.table),
+        0,
+        0,
+        FR_ACT_TO_TBL,
+    ]);
+    let flags = if rule.invert { FIB_RULE_INVERT } else { 0 };
+    msg.buf.extend_from_slice(&flags.to_ne_bytes());
+    if let Some((addr, _)) = rule.to {
+        msg.push_attr(FRA_DST, &addr.octets());
+    }
+    if let Some((addr, _)) = rule.from {
+        msg.push_attr(FRA_SRC, &addr.octets());
+    }
+    if let Some(priority) = rule.priority {
+        msg.push_attr(FRA_PRIORITY, &priority.to_ne_bytes());
+    }
+    if let Some(fwmark) = rule.fwmark {
+        msg.push_attr(FRA_FWMARK, &fwmark.to_ne_bytes());
+    }
+    if let Some(prefix_len) = rule.suppress_prefixlength {
+        msg.push_attr(FRA_SUPPRESS_PREFIXLEN, &prefix_len.to_ne_bytes());
+    }
+    msg.push_attr(FRA_TABLE, &rule.table.to_ne_bytes());
+    msg
+}
+
+/// Table id for the one-byte header field; larger ids travel in the TABLE attribute
+fn table_byte(table: u32) -> u8 {
+    u8::try_from(table).unwrap_or(0)
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A netlink request under construction
+struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    fn new(kind: u16, flags: u16) -> Self {
+        let mut buf = vec![0u8; NLMSG_HDR_LEN];
+        buf[4..6].copy_from_slice(&kind.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+        Self { buf }
+    }
+
+    /// Append a `struct rtmsg`
+    fn push_rtmsg(&mut self, family: u8, dst_len: u8, table: u8, protocol: u8, scope: u8, route_type: u8) {
+        self.buf.extend_from_slice(&[family, dst_len, 0, 0, table, protocol, scope, route_type]);
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+    }
+
+    fn push_attr(&mut self, kind: u16, data: &[u8]) {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&kind.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align(self.buf.len()), 0);
+    }
+
+    /// Fill in length, flags and sequence number
+    fn finish(&mut self, seq: u32, extra_flags: u16) -> &[u8] {
+        let len = self.buf.len() as u32;
+        let flags = u16::from_ne_bytes([self.buf[6], self.buf[7]]) | extra_flags;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[6..8].copy_from_slice(&flags.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        &self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cidr() {
+        assert_eq!(parse_cidr("default").unwrap(), (IpAddr::from([0, 0, 0, 0]), 0));
+        assert_eq!(parse_cidr("10.0.0.0/8").unwrap(), (IpAddr::from([10, 0, 0, 0]), 8));
+        assert_eq!(parse_cidr("1.2.3.4").unwrap(), (IpAddr::from([1, 2, 3, 4]), 32));
+        assert_eq!(parse_cidr("8000::/1").unwrap(), ("8000::".parse().unwrap(), 1));
+        assert_eq!(parse_cidr("2001:db8::1").unwrap(), ("2001:db8::1".parse().unwrap(), 128));
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("2001:db8::/129").is_err());
+        assert!(parse_cidr("vpn.example.com").is_err());
+        assert_eq!(sysctl_path("net.ipv4.conf.vpnse0.rp_filter"), PathBuf::from("/proc/sys/net/ipv4/conf/vpnse0/rp_filter"));
+    }
+
+    #[test]
+    fn test_route_message_round_trip() {
+        let route = Route {
+            gateway: Some(IpAddr::from([10, 0, 0, 1])),
+            oif: Some(7),
+            metric: Some(50),
+            ..Route::to("128.0.0.0/1").unwrap()
+        };
+        let mut msg = route_message(&route, RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE);
+        let bytes = msg.finish(42, NLM_F_ACK).to_vec();
+
+        let parsed = messages(&bytes);
+        assert_eq!(parsed.len(), 1);
+        let (header, payload) = parsed[0];
+        assert_eq!((header.kind, header.seq), (RTM_NEWROUTE, 42));
+        assert_eq!(u16::from_ne_bytes([bytes[6], bytes[7]]), NLM_F_REQUEST | NLM_F_CREATE | NLM_F_REPLACE | NLM_F_ACK);
+        assert_eq!(parse_route(payload), Some(route));
+
+        let route = Route {
+            gateway: Some("fd00:21::1".parse().unwrap()),
+            oif: Some(7),
+            ..Route::to("::/1").unwrap()
+        };
+        let mut msg = route_message(&route, RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE);
+        let bytes = msg.finish(43, NLM_F_ACK).to_vec();
+        let (_, payload) = messages(&bytes)[0];
+        assert_eq!(payload[0], libc::AF_INET6 as u8);
+        assert_eq!(parse_route(payload), Some(route));
+    }
+
+    #[test]
+    fn test_address_message() {
+        let address: IpAddr = "fd00:21::5".parse().unwrap();
+        let mut msg = address_message(7, address, 64, RTM_NEWADDR, NLM_F_CREATE | NLM_F_REPLACE);
+        let bytes = msg.finish(1, NLM_F_ACK).to_vec();
+        let (header, payload) = messages(&bytes)[0];
+        assert_eq!(header.kind, RTM_NEWADDR);
+        assert_eq!(&payload[..4], &[libc::AF_INET6 as u8, 64, IFA_F_NODAD, RT_SCOPE_UNIVERSE]);
+        assert_eq!(&payload[4..8], &7u32.to_ne_bytes());
+        let attrs = attributes(&payload[8..]);
+        assert_eq!(attrs[0], (IFA_LOCAL, &octets(address)[..]));
+        assert_eq!(attrs[1], (IFA_ADDRESS, &octets(address)[..]));
+    }
+
+    #[test]
+    fn test_ack_result() {
+        assert!(ack_result(&0i32.to_ne_bytes()).is_ok());
+        let err = ack_result(&(-libc::EPERM).to_ne_bytes()).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        assert!(matches!(netlink_error("add route", err), VpnError::Permission(_)));
+        assert!(ack_result(&[0, 0]).is_err());
+    }
+}
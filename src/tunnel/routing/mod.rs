@@ -0,0 +1,9 @@
+//! Native routing backends
+//!
+//! These program the kernel routing tables directly instead of running `ip`,
+//! `route` or `sysctl` through `sudo`, so they work wherever the process holds
+//! the right capabilities (e.g. a container granted CAP_NET_ADMIN) and report
+//! failures as errors instead of console warnings.
+
+#[cfg(target_os = "linux")]
+pub mod linux;
@@ -0,0 +1,181 @@
+//! Upload and download rate limits for tunnel traffic
+//!
+//! Each direction is a token bucket filled at the configured rate, holding at
+//! most [`BURST`] worth of traffic. A packet takes its size from the bucket
+//! and may leave it in debt; the next packet then waits until the debt is
+//! paid off. So packets are never split or dropped, and over time the rate
+//! holds whatever their sizes.
+//!
+//! The packet pump asks [`RateLimiter::throttle`] before it forwards each
+//! packet. Waiting on the download side also stops reading from the session,
+//! which lets TCP push back on the server. Limits can change at any time;
+//! 0 means unlimited.
+
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Traffic a bucket may save up while idle, as time at the full rate
+pub const BURST: Duration = Duration::from_millis(100);
+
+/// Smallest bucket, so low limits still pass a full-size frame without debt
+const MIN_BURST_BYTES: f64 = 2048.0;
+
+/// Direction of tunnel traffic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From this host to the server
+    Upload,
+    /// From the server to this host
+    Download,
+}
+
+/// Token bucket of one direction
+#[derive(Debug)]
+struct TokenBucket {
+    /// Fill rate in bytes per second, 0 when unlimited
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(bits_per_second: u64) -> Self {
+        let mut bucket = Self { rate: 0.0, tokens: 0.0, updated: Instant::now() };
+        bucket.set_rate(bits_per_second);
+        bucket
+    }
+
+    fn capacity(&self) -> f64 {
+        (self.rate * BURST.as_secs_f64()).max(MIN_BURST_BYTES)
+    }
+
+    fn bits_per_second(&self) -> u64 {
+        (self.rate * 8.0) as u64
+    }
+
+    /// Change the rate; a full bucket when the limit starts, existing debt is kept
+    fn set_rate(&mut self, bits_per_second: u64) {
+        let now = Instant::now();
+        let was_unlimited = self.rate == 0.0;
+        if !was_unlimited {
+            self.reserve(0, now);
+        }
+        self.rate = bits_per_second as f64 / 8.0;
+        self.updated = now;
+        self.tokens = if was_unlimited { self.capacity() } else { self.tokens.min(self.capacity()) };
+    }
+
+    /// Take `bytes` at `now`, returning how long to wait before sending them
+    fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        if self.rate == 0.0 {
+            return Duration::ZERO;
+        }
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.updated = now.max(self.updated);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity()) - bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Upload and download limits, shared by the packet pump and whoever sets them
+#[derive(Debug)]
+pub struct RateLimiter {
+    upload: Mutex<TokenBucket>,
+    download: Mutex<TokenBucket>,
+}
+
+impl RateLimiter {
+    /// Limits in bits per second, 0 for unlimited
+    pub fn new(upload_bps: u64, download_bps: u64) -> Self {
+        Self { upload: Mutex::new(TokenBucket::new(upload_bps)), download: Mutex::new(TokenBucket::new(download_bps)) }
+    }
+
+    /// Change both limits (bits per second, 0 for unlimited), effective from the next packet
+    pub fn set(&self, upload_bps: u64, download_bps: u64) {
+        self.upload.lock().unwrap().set_rate(upload_bps);
+        self.download.lock().unwrap().set_rate(download_bps);
+    }
+
+    /// Current `(upload, download)` limits in bits per second, 0 for unlimited
+    pub fn limits(&self) -> (u64, u64) {
+        (self.upload.lock().unwrap().bits_per_second(), self.download.lock().unwrap().bits_per_second())
+    }
+
+    /// Whether either direction is limited
+    pub fn is_limited(&self) -> bool {
+        self.limits() != (0, 0)
+    }
+
+    /// Wait until `bytes` may go out in `direction`
+    pub async fn throttle(&self, direction: Direction, bytes: usize) {
+        let bucket = match direction {
+            Direction::Upload => &self.upload,
+            Direction::Download => &self.download,
+        };
+        let wait = bucket.lock().unwrap().reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    /// No limits
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_holds_rate() {
+        // 80 kbit/s = 10 000 bytes/s, bucket of 2048 bytes
+        let mut bucket = TokenBucket::new(80_000);
+        let start = bucket.updated;
+        assert_eq!(bucket.reserve(2048, start), Duration::ZERO);
+        // The next 1000 bytes are all debt: 0.1s
+        assert_eq!(bucket.reserve(1000, start).as_millis(), 100);
+
+        // A second later the debt is paid and 9000 bytes saved, capped to the burst
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.reserve(2048, later), Duration::ZERO);
+        assert!(bucket.reserve(1, later) > Duration::ZERO);
+
+        // Lifting the limit stops all waiting; setting it again starts full
+        bucket.set_rate(0);
+        assert_eq!(bucket.reserve(1_000_000, later), Duration::ZERO);
+        bucket.set_rate(8_000_000);
+        assert_eq!(bucket.bits_per_second(), 8_000_000);
+        assert_eq!(bucket.reserve(100_000, Instant::now()), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn test_throttle_waits_per_direction() {
+        // 100 000 bytes/s up, bucket of 10 000 bytes; download unlimited
+        let limiter = RateLimiter::new(800_000, 0);
+        assert!(limiter.is_limited());
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.throttle(Direction::Download, 1_000_000).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(20));
+
+        // The bucket, then 5000 bytes of debt: 50ms before the next packet
+        limiter.throttle(Direction::Upload, 10_000).await;
+        limiter.throttle(Direction::Upload, 5_000).await;
+        limiter.throttle(Direction::Upload, 1).await;
+        assert!(start.elapsed() >= Duration::from_millis(45));
+
+        limiter.set(0, 0);
+        assert_eq!(limiter.limits(), (0, 0));
+        assert!(!limiter.is_limited());
+    }
+}
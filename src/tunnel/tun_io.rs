@@ -0,0 +1,296 @@
+//! Split TUN device I/O
+//!
+//! `tun::platform::Device` needs `&mut` for both `read` and `write`, so a
+//! single owner serializes the receive and transmit paths. [`split`] turns
+//! the device into a [`TunReader`] and a [`TunWriter`] that can live on
+//! different threads or tasks and never wait on each other.
+//!
+//! Halves of a device with offloads (see [`offload`](super::offload)) take
+//! care of its virtio-net headers: they read and write plain packets like
+//! any other.
+
+use super::offload::{Coalescer, Segmenter};
+use std::io::{self, Read, Write};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// Receive half of a TUN device
+pub struct TunReader {
+    inner: Box<dyn Read + Send>,
+    #[cfg(unix)]
+    fd: Option<RawFd>,
+    offload: Option<Box<Segmenter>>,
+}
+
+/// Transmit half of a TUN device
+pub struct TunWriter {
+    inner: Box<dyn Write + Send>,
+    #[cfg(unix)]
+    fd: Option<RawFd>,
+    offload: Option<Box<Coalescer>>,
+}
+
+impl TunReader {
+    /// Half without a pollable descriptor (Windows adapters, macOS BPF, host packet flows)
+    pub(crate) fn new(inner: impl Read + Send + 'static) -> Self {
+        Self {
+            inner: Box::new(inner),
+            #[cfg(unix)]
+            fd: None,
+            offload: None,
+        }
+    }
+
+    /// Half backed by a descriptor that [`async_tun`](super::async_tun) can poll
+    #[cfg(unix)]
+    pub(crate) fn pollable(inner: impl Read + AsRawFd + Send + 'static) -> Self {
+        let fd = Some(inner.as_raw_fd());
+        Self { inner: Box::new(inner), fd, offload: None }
+    }
+
+    /// Expect a virtio-net header on every frame, and cut super-packets into segments
+    #[cfg(unix)]
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn with_offload(mut self) -> Self {
+        self.offload = Some(Box::default());
+        self
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn raw_fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
+    /// Whether packets of a super-packet already read are waiting
+    #[cfg(unix)]
+    pub(crate) fn has_buffered(&self) -> bool {
+        self.offload.as_ref().is_some_and(|offload| offload.has_packets())
+    }
+
+    /// Read one packet into `buf`, blocking until one arrives
+    pub fn read_packet(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.offload {
+            Some(ref mut offload) => offload.read(&mut self.inner, buf),
+            None => self.inner.read(buf),
+        }
+    }
+}
+
+impl TunWriter {
+    /// Half without a pollable descriptor (Windows adapters, macOS BPF, host packet flows)
+    pub(crate) fn new(inner: impl Write + Send + 'static) -> Self {
+        Self {
+            inner: Box::new(inner),
+            #[cfg(unix)]
+            fd: None,
+            offload: None,
+        }
+    }
+
+    /// Half backed by a descriptor that [`async_tun`](super::async_tun) can poll
+    #[cfg(unix)]
+    pub(crate) fn pollable(inner: impl Write + AsRawFd + Send + 'static) -> Self {
+        let fd = Some(inner.as_raw_fd());
+        Self { inner: Box::new(inner), fd, offload: None }
+    }
+
+    /// Put a virtio-net header on every frame, merging TCP segments into super-packets
+    ///
+    /// Packets are held back for merging until the next one that does not
+    /// continue their stream, or [`flush`](Self::flush).
+    #[cfg(unix)]
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub(crate) fn with_offload(mut self) -> Self {
+        self.offload = Some(Box::default());
+        self
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn raw_fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
+    /// Write one complete packet
+    pub fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        if self.queue_packet(packet) {
+            return self.write_queued();
+        }
+        self.inner.write_all(packet)
+    }
+
+    /// Write the packets held back for merging
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.end_merge();
+        self.write_queued()
+    }
+
+    /// Queue the packets held back for merging, to go with the next [`write_queued`](Self::write_queued)
+    pub(crate) fn end_merge(&mut self) {
+        if let Some(ref mut offload) = self.offload {
+            offload.flush();
+        }
+    }
+
+    /// Hand `packet` to the coalescer without writing; false without offloads
+    pub(crate) fn queue_packet(&mut self, packet: &[u8]) -> bool {
+        match self.offload {
+            Some(ref mut offload) => {
+                offload.push(packet);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Write the frames the coalescer finished, stopping at the first error
+    ///
+    /// A frame that could not be written stays queued for the next call.
+    pub(crate) fn write_queued(&mut self) -> io::Result<()> {
+        let Some(ref mut offload) = self.offload else {
+            return Ok(());
+        };
+        while let Some(frame) = offload.next_frame() {
+            self.inner.write_all(frame)?;
+            offload.written();
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for TunReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TunReader")
+    }
+}
+
+impl std::fmt::Debug for TunWriter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TunWriter")
+    }
+}
+
+/// Split a device into independent receive and transmit halves
+///
+/// On Unix both halves share the file descriptor, which the kernel allows to
+/// be read and written concurrently; the interface goes away once both halves
+/// are dropped. The halves can be made non-blocking with
+/// [`async_tun::split`](super::async_tun::split).
+#[cfg(unix)]
+pub fn split(device: tun::platform::Device) -> (TunReader, TunWriter) {
+    let (reader, writer) = device.split();
+    (TunReader::pollable(reader), TunWriter::pollable(writer))
+}
+
+/// Split one queue of a device opened without the tun crate
+///
+/// With `offload` the queue was opened with `IFF_VNET_HDR`.
+#[cfg(target_os = "linux")]
+pub(crate) fn split_queue(queue: std::fs::File, offload: bool) -> (TunReader, TunWriter) {
+    let queue = std::sync::Arc::new(queue);
+    let (reader, writer) = (TunReader::pollable(Queue(queue.clone())), TunWriter::pollable(Queue(queue)));
+    if offload {
+        return (reader.with_offload(), writer.with_offload());
+    }
+    (reader, writer)
+}
+
+/// Queue descriptor shared by both halves
+#[cfg(target_os = "linux")]
+struct Queue(std::sync::Arc<std::fs::File>);
+
+#[cfg(target_os = "linux")]
+impl Read for Queue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Write for Queue {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AsRawFd for Queue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_write_proceeds_while_read_is_blocked() {
+        // A datagram socketpair behaves like a TUN fd: one packet per read/write
+        let (tun_side, peer) = UnixDatagram::pair().unwrap();
+        let mut reader = TunReader::new(DatagramIo(tun_side.try_clone().unwrap()));
+        let mut writer = TunWriter::new(DatagramIo(tun_side));
+
+        let (done_tx, done_rx) = mpsc::channel();
+        let rx_task = thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            let len = reader.read_packet(&mut buf).unwrap();
+            done_tx.send(buf[..len].to_vec()).unwrap();
+        });
+
+        // The reader is parked in read(); transmitting must not wait for it
+        thread::sleep(Duration::from_millis(50));
+        writer.write_packet(b"outbound").unwrap();
+        let mut buf = [0u8; 64];
+        let len = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"outbound");
+        assert!(done_rx.try_recv().is_err());
+
+        peer.send(b"inbound").unwrap();
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"inbound");
+        rx_task.join().unwrap();
+    }
+
+    #[test]
+    fn test_offload_halves_add_and_strip_headers() {
+        let (tun_side, peer) = UnixDatagram::pair().unwrap();
+        let mut reader = TunReader::new(DatagramIo(tun_side.try_clone().unwrap())).with_offload();
+        let mut writer = TunWriter::new(DatagramIo(tun_side)).with_offload();
+
+        // Not a TCP segment, so nothing to merge: out at once behind an empty header
+        writer.write_packet(b"outbound").unwrap();
+        let mut buf = [0u8; 64];
+        let len = peer.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\0\0\0\0\0\0\0\0\0\0outbound");
+
+        peer.send(b"\0\0\0\0\0\0\0\0\0\0inbound").unwrap();
+        let len = reader.read_packet(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"inbound");
+        assert!(!reader.has_buffered());
+    }
+
+    struct DatagramIo(UnixDatagram);
+
+    impl Read for DatagramIo {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf)
+        }
+    }
+
+    impl Write for DatagramIo {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
@@ -3,13 +3,15 @@
 //! This module provides Windows-specific implementations for tunnel management.
 
 use crate::error::{Result, VpnError};
+use crate::tunnel::killswitch::{KillSwitchPolicy, OutboundActions, WINDOWS_RULE_GROUP};
 use crate::tunnel::TunnelConfig;
+use std::net::{IpAddr, Ipv4Addr};
 use std::process::Command;
 
 /// Create a TUN interface on Windows
 #[allow(dead_code)]
 pub fn create_tun_interface(_config: &TunnelConfig) -> Result<String> {
-    println!("Creating TUN interface on Windows...");
+    log::debug!("Creating TUN interface on Windows");
 
     // Check if we have administrator privileges
     if !has_admin_privileges() {
@@ -22,24 +24,337 @@ pub fn create_tun_interface(_config: &TunnelConfig) -> Result<String> {
     // Try to create a TUN device using TAP-Windows adapter
     let interface_name = create_tap_device()?;
 
-    println!("TUN interface '{interface_name}' created on Windows");
+    log::info!("TUN interface '{interface_name}' created on Windows");
     Ok(interface_name)
 }
 
 /// Destroy a TUN interface on Windows
 #[allow(dead_code)]
 pub fn destroy_tun_interface(interface_name: &str) -> Result<()> {
-    println!("Destroying TUN interface '{interface_name}' on Windows");
+    log::debug!("Destroying TUN interface '{interface_name}' on Windows");
 
     // Disable the interface
     let _ = Command::new("netsh")
         .args(["interface", "set", "interface", interface_name, "disable"])
         .status();
 
-    println!("TUN interface '{interface_name}' destroyed on Windows");
+    log::info!("TUN interface '{interface_name}' destroyed on Windows");
     Ok(())
 }
 
+/// Set the connection-specific DNS suffix of an adapter
+pub fn set_dns_suffix(interface_name: &str, domain: &str) -> Result<()> {
+    let output = Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-Command",
+            &format!(
+                "Set-DnsClient -InterfaceAlias '{interface_name}' -ConnectionSpecificSuffix '{domain}'"
+            ),
+        ])
+        .output()
+        .map_err(|e| VpnError::Network(format!("Failed to run Set-DnsClient: {e}")))?;
+
+    if !output.status.success() {
+        return Err(VpnError::Network(format!(
+            "Failed to set DNS suffix on '{interface_name}': {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    log::info!("DNS suffix '{domain}' set on '{interface_name}'");
+    Ok(())
+}
+
+/// Configure the WINS servers of an adapter (first one primary)
+pub fn set_wins_servers(interface_name: &str, servers: &[Ipv4Addr]) -> Result<()> {
+    let name = format!("name={interface_name}");
+    for (index, server) in servers.iter().enumerate() {
+        let addr = format!("addr={server}");
+        let mut args = vec!["interface", "ip"];
+        if index == 0 {
+            args.extend(["set", "wins", name.as_str(), "source=static", addr.as_str()]);
+        } else {
+            args.extend(["add", "wins", name.as_str(), addr.as_str()]);
+        }
+
+        let status = Command::new("netsh")
+            .args(&args)
+            .status()
+            .map_err(|e| VpnError::Network(format!("Failed to run netsh: {e}")))?;
+        if !status.success() {
+            return Err(VpnError::Network(format!(
+                "Failed to set WINS server {server} on '{interface_name}'"
+            )));
+        }
+    }
+    log::info!("WINS servers configured on '{interface_name}'");
+    Ok(())
+}
+
+/// Assign an additional address (e.g. the IPv6 one) to an adapter
+pub fn add_address(interface_name: &str, address: IpAddr, prefix_len: u8) -> Result<()> {
+    let (family, address_arg) = match address {
+        IpAddr::V6(_) => ("ipv6", format!("address={address}/{prefix_len}")),
+        IpAddr::V4(_) => {
+            let mask = Ipv4Addr::from(u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0));
+            ("ipv4", format!("address={address} mask={mask}"))
+        }
+    };
+    let name = format!("interface={interface_name}");
+    let mut args = vec!["interface", family, "add", "address", name.as_str()];
+    args.extend(address_arg.split(' '));
+
+    let status = Command::new("netsh")
+        .args(&args)
+        .status()
+        .map_err(|e| VpnError::Network(format!("Failed to run netsh: {e}")))?;
+    if !status.success() {
+        return Err(VpnError::Network(format!(
+            "Failed to add address {address}/{prefix_len} on '{interface_name}'"
+        )));
+    }
+    log::info!("Address {address}/{prefix_len} added on '{interface_name}'");
+    Ok(())
+}
+
+/// Interface metric of the tunnel adapter while connected
+///
+/// Windows asks the resolvers of the lowest-metric interface first, so this
+/// keeps name resolution on the tunnel.
+const TUNNEL_INTERFACE_METRIC: u32 = 1;
+
+/// Next hop and interface alias of the preferred default route
+pub fn default_route(ipv6: bool) -> Option<(String, String)> {
+    let prefix = if ipv6 { "::/0" } else { "0.0.0.0/0" };
+    let output = Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-Command",
+            &format!(
+                "Get-NetRoute -DestinationPrefix '{prefix}' -ErrorAction SilentlyContinue | \
+                 Sort-Object {{ $_.RouteMetric + $_.InterfaceMetric }} | Select-Object -First 1 | \
+                 ForEach-Object {{ \"$($_.NextHop) $($_.InterfaceAlias)\" }}"
+            ),
+        ])
+        .output()
+        .ok()?;
+    let line = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    // Aliases may contain spaces, next hops never do
+    let (gateway, interface) = line.split_once(' ')?;
+    Some((gateway.to_string(), interface.to_string()))
+}
+
+/// Alias of the interface Windows would send traffic for `destination` through
+pub fn route_interface(destination: IpAddr) -> Option<String> {
+    let output = Command::new("powershell")
+        .args([
+            "-NoProfile",
+            "-Command",
+            &format!(
+                "Find-NetRoute -RemoteIPAddress '{destination}' -ErrorAction SilentlyContinue | \
+                 Select-Object -First 1 -ExpandProperty InterfaceAlias"
+            ),
+        ])
+        .output()
+        .ok()?;
+    let alias = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!alias.is_empty()).then_some(alias)
+}
+
+/// Add an active-store route (gone after a reboot)
+///
+/// Without an interface `route add` picks it from the gateway.
+pub fn add_route(destination: &str, gateway: Option<&str>, interface: Option<&str>, metric: Option<u32>) -> Result<()> {
+    let family = if destination.contains(':') { "ipv6" } else { "ipv4" };
+    match interface.filter(|interface| !interface.is_empty()) {
+        Some(interface) => {
+            let mut args = vec![
+                "interface".to_string(),
+                family.to_string(),
+                "add".to_string(),
+                "route".to_string(),
+                format!("prefix={destination}"),
+                format!("interface={interface}"),
+            ];
+            if let Some(gateway) = gateway {
+                args.push(format!("nexthop={gateway}"));
+            }
+            if let Some(metric) = metric {
+                args.push(format!("metric={metric}"));
+            }
+            args.push("store=active".to_string());
+            run_netsh(&args, &format!("add route {destination}"))?;
+        }
+        None => {
+            let gateway = gateway.ok_or_else(|| {
+                VpnError::Routing(format!("Route {destination} needs a gateway or an interface"))
+            })?;
+            let mut args = vec!["add", destination, gateway];
+            let metric = metric.map(|metric| metric.to_string());
+            if let Some(ref metric) = metric {
+                args.extend(["metric", metric.as_str()]);
+            }
+            let output = Command::new("route")
+                .args(&args)
+                .output()
+                .map_err(|e| VpnError::Routing(format!("Failed to run route: {e}")))?;
+            if !output.status.success() {
+                return Err(VpnError::Routing(format!(
+                    "Failed to add route {destination}: {}",
+                    String::from_utf8_lossy(&output.stdout).trim()
+                )));
+            }
+        }
+    }
+    log::info!("Added route {destination}");
+    Ok(())
+}
+
+/// Remove a route added by [`add_route`]
+pub fn delete_route(destination: &str, interface: Option<&str>) -> Result<()> {
+    match interface.filter(|interface| !interface.is_empty()) {
+        Some(interface) => {
+            let family = if destination.contains(':') { "ipv6" } else { "ipv4" };
+            run_netsh(
+                &[
+                    "interface".to_string(),
+                    family.to_string(),
+                    "delete".to_string(),
+                    "route".to_string(),
+                    format!("prefix={destination}"),
+                    format!("interface={interface}"),
+                    "store=active".to_string(),
+                ],
+                &format!("remove route {destination}"),
+            )?;
+        }
+        None => {
+            let _ = Command::new("route").args(["delete", destination]).output();
+        }
+    }
+    log::info!("Removed route {destination}");
+    Ok(())
+}
+
+/// Point the adapter's resolvers at `servers` and make it the preferred interface for DNS
+pub fn set_dns_servers(interface_name: &str, servers: &[IpAddr]) -> Result<()> {
+    for (family, ipv6) in [("ipv4", false), ("ipv6", true)] {
+        let family_servers = servers.iter().filter(|server| server.is_ipv6() == ipv6);
+        for (index, server) in family_servers.enumerate() {
+            let mut args = vec!["interface".to_string(), family.to_string()];
+            if index == 0 {
+                args.extend([
+                    "set".to_string(),
+                    "dnsservers".to_string(),
+                    format!("name={interface_name}"),
+                    "source=static".to_string(),
+                    format!("address={server}"),
+                    "register=none".to_string(),
+                ]);
+            } else {
+                args.extend([
+                    "add".to_string(),
+                    "dnsservers".to_string(),
+                    format!("name={interface_name}"),
+                    format!("address={server}"),
+                    format!("index={}", index + 1),
+                ]);
+            }
+            args.push("validate=no".to_string());
+            run_netsh(&args, &format!("set DNS server {server}"))?;
+        }
+    }
+    run_powershell(
+        &format!("Set-NetIPInterface -InterfaceAlias '{interface_name}' -InterfaceMetric {TUNNEL_INTERFACE_METRIC}"),
+        "lower the tunnel interface metric",
+    )?;
+    let _ = Command::new("ipconfig").arg("/flushdns").output();
+    log::info!("DNS servers set on '{interface_name}'");
+    Ok(())
+}
+
+/// Undo [`set_dns_servers`]: resolvers from DHCP and an automatic metric again
+pub fn restore_dns(interface_name: &str) -> Result<()> {
+    for family in ["ipv4", "ipv6"] {
+        let name = format!("name={interface_name}");
+        let _ = Command::new("netsh")
+            .args(["interface", family, "set", "dnsservers", name.as_str(), "source=dhcp"])
+            .output();
+    }
+    run_powershell(
+        &format!("Set-NetIPInterface -InterfaceAlias '{interface_name}' -AutomaticMetric Enabled"),
+        "restore the tunnel interface metric",
+    )?;
+    let _ = Command::new("ipconfig").arg("/flushdns").output();
+    log::info!("Original DNS restored on '{interface_name}'");
+    Ok(())
+}
+
+/// Block outbound traffic except through the tunnel and to the VPN server
+pub fn enable_kill_switch(policy: &KillSwitchPolicy) -> Result<()> {
+    run_powershell(&policy.windows_script(), "engage kill switch")?;
+    log::info!("Kill switch engaged on '{}'", policy.tunnel_interface);
+    Ok(())
+}
+
+/// Default outbound action of each firewall profile, to save before engaging
+pub fn outbound_actions() -> Result<OutboundActions> {
+    OutboundActions::parse(&powershell_output(OutboundActions::QUERY, "read the firewall profiles")?)
+}
+
+/// Remove the kill switch rules and put back the `previous` default outbound actions
+///
+/// Without them (the process that engaged the kill switch is gone) the
+/// profiles go back to `NotConfigured`.
+pub fn disable_kill_switch(previous: Option<&OutboundActions>) -> Result<()> {
+    let restore = match previous {
+        Some(previous) => previous.restore_script(),
+        None => {
+            log::warn!("Default outbound actions before the kill switch unknown, resetting them to NotConfigured");
+            "Set-NetFirewallProfile -All -DefaultOutboundAction NotConfigured".to_string()
+        }
+    };
+    run_powershell(
+        &format!("{restore}; Remove-NetFirewallRule -Group '{WINDOWS_RULE_GROUP}' -ErrorAction SilentlyContinue"),
+        "release kill switch",
+    )?;
+    log::info!("Kill switch released");
+    Ok(())
+}
+
+fn run_netsh(args: &[String], action: &str) -> Result<()> {
+    let output = Command::new("netsh")
+        .args(args)
+        .output()
+        .map_err(|e| VpnError::Platform(format!("Failed to run netsh: {e}")))?;
+    if !output.status.success() {
+        // netsh reports errors on stdout
+        return Err(VpnError::Platform(format!(
+            "Failed to {action}: {}",
+            String::from_utf8_lossy(&output.stdout).trim()
+        )));
+    }
+    Ok(())
+}
+
+fn run_powershell(script: &str, action: &str) -> Result<()> {
+    powershell_output(script, action).map(drop)
+}
+
+fn powershell_output(script: &str, action: &str) -> Result<String> {
+    let output = Command::new("powershell")
+        .args(["-NoProfile", "-Command", script])
+        .output()
+        .map_err(|e| VpnError::Platform(format!("Failed to run PowerShell: {e}")))?;
+    if !output.status.success() {
+        return Err(VpnError::Platform(format!(
+            "Failed to {action}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
 #[allow(dead_code)]
 fn has_admin_privileges() -> bool {
     // Check if running as administrator by trying to access a system registry key
@@ -96,6 +411,6 @@ fn create_tap_device() -> Result<String> {
 
     // Final fallback: return a default TAP interface name
     let tap_interface_name = "TAP-Windows Adapter V9";
-    println!("Using default TAP interface: {tap_interface_name}");
+    log::debug!("Using default TAP interface: {tap_interface_name}");
     Ok(tap_interface_name.to_string())
 }
@@ -1,16 +1,30 @@
-//! Windows TAP Interface Implementation
-//! 
-//! Provides Windows-specific TUN/TAP interface management using
-//! the OpenVPN TAP-Windows adapter
+//! Windows TUN backends
+//!
+//! Two drivers can carry the tunnel:
+//!
+//! - Wintun, whose sessions exchange packets with the driver through shared
+//!   ring buffers; the receive and transmit halves work without a lock and
+//!   without a syscall per packet while traffic flows. Needs `wintun.dll`
+//!   next to the executable or on the DLL search path.
+//! - OpenVPN's TAP-Windows adapter in TUN mode, with one overlapped
+//!   `ReadFile`/`WriteFile` per packet.
+//!
+//! [`open`] picks one according to [`WindowsDriver`]; `Auto` prefers
+//! Wintun and falls back to TAP. In [`TunnelMode::Tap`] only TAP-Windows
+//! will do, left in its native mode so it exchanges Ethernet frames.
 
+use super::tun_io::{TunReader, TunWriter};
+use super::TunnelConfig;
+use crate::config::{TunnelMode, WindowsDriver};
 use crate::error::{Result, VpnError};
+use crate::net_util;
 use std::ffi::OsString;
 use std::os::windows::ffi::OsStrExt;
 use std::ptr;
 use winapi::um::{
     fileapi::{CreateFileW, OPEN_EXISTING},
     handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
-    ioapiset::{DeviceIoControl},
+    ioapiset::{DeviceIoControl, GetOverlappedResult},
     winnt::{FILE_ATTRIBUTE_SYSTEM, GENERIC_READ, GENERIC_WRITE, HANDLE},
     synchapi::{CreateEventW},
     minwinbase::OVERLAPPED,
@@ -21,7 +35,133 @@ use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 use bytes::Bytes;
-use std::io;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use winapi::shared::winerror::ERROR_IO_PENDING;
+
+/// Tunnel type reported for adapters this crate creates
+const WINTUN_TUNNEL_TYPE: &str = "rVPNSE";
+
+/// Ring buffer size of a Wintun session (8 MiB, as WireGuard uses)
+const WINTUN_RING_CAPACITY: u32 = 0x80_0000;
+
+/// Open the tunnel adapter with the driver `config` asks for
+///
+/// Returns the driver actually used and the adapter's I/O halves. With
+/// [`WindowsDriver::Auto`] a missing or broken Wintun falls back to TAP.
+pub fn open(config: &TunnelConfig) -> Result<(WindowsDriver, TunReader, TunWriter)> {
+    let tap_mode = config.mode == TunnelMode::Tap;
+    if config.windows_driver != WindowsDriver::Tap && !tap_mode {
+        match WintunInterface::new(&config.interface_name) {
+            Ok(interface) => {
+                interface.configure(config.local_ip, config.netmask, config.mtu)?;
+                let (reader, writer) = interface.split();
+                return Ok((WindowsDriver::Wintun, reader, writer));
+            }
+            Err(e) if config.windows_driver == WindowsDriver::Wintun => return Err(e),
+            Err(e) => log::info!("Wintun unavailable ({}), falling back to TAP-Windows", e),
+        }
+    }
+
+    let mut interface = WindowsTapInterface::new()?;
+    if !tap_mode {
+        interface.configure_tun(&config.local_ip.to_string(), &config.remote_ip.to_string(), &config.netmask.to_string())?;
+    }
+    interface.set_media_status(true)?;
+    // TAP adapters keep their own name; the tunnel expects it to be `interface_name`
+    let prefix_len = net_util::prefix_len(config.netmask)
+        .ok_or_else(|| VpnError::Config(format!("Tunnel netmask {} is not a prefix", config.netmask)))?;
+    super::windows::add_address(&config.interface_name, IpAddr::V4(config.local_ip), prefix_len)?;
+    let (reader, writer) = interface.split();
+    Ok((WindowsDriver::Tap, reader, writer))
+}
+
+/// Whether `wintun.dll` can be loaded
+pub fn wintun_available() -> bool {
+    load_wintun().is_ok()
+}
+
+fn load_wintun() -> Result<wintun::Wintun> {
+    // SAFETY: loads wintun.dll from the DLL search path, like every Wintun user
+    unsafe { wintun::load() }.map_err(|e| VpnError::TunTap(format!("Failed to load wintun.dll: {e}")))
+}
+
+/// Wintun adapter with a running session
+pub struct WintunInterface {
+    adapter: Arc<wintun::Adapter>,
+    session: Arc<wintun::Session>,
+}
+
+impl WintunInterface {
+    /// Open the adapter called `name`, creating it if needed, and start a session
+    ///
+    /// An adapter created here is removed again once the session ends.
+    pub fn new(name: &str) -> Result<Self> {
+        let wintun = load_wintun()?;
+        let adapter = match wintun::Adapter::open(&wintun, name) {
+            Ok(adapter) => adapter,
+            Err(_) => wintun::Adapter::create(&wintun, name, WINTUN_TUNNEL_TYPE, None)
+                .map_err(|e| VpnError::TunTap(format!("Failed to create Wintun adapter '{name}': {e}")))?,
+        };
+        let session = adapter
+            .start_session(WINTUN_RING_CAPACITY)
+            .map_err(|e| VpnError::TunTap(format!("Failed to start Wintun session on '{name}': {e}")))?;
+        log::info!("Wintun session started on '{}'", name);
+        Ok(Self { adapter, session: Arc::new(session) })
+    }
+
+    /// Assign the tunnel address and MTU
+    pub fn configure(&self, local_ip: Ipv4Addr, netmask: Ipv4Addr, mtu: u16) -> Result<()> {
+        self.adapter
+            .set_network_addresses_tuple(IpAddr::V4(local_ip), IpAddr::V4(netmask), None)
+            .map_err(|e| VpnError::TunTap(format!("Failed to address Wintun adapter: {e}")))?;
+        self.adapter
+            .set_mtu(usize::from(mtu))
+            .map_err(|e| VpnError::TunTap(format!("Failed to set Wintun MTU: {e}")))
+    }
+
+    /// Receive and transmit halves, both working on the session's rings directly
+    pub fn split(self) -> (TunReader, TunWriter) {
+        (TunReader::new(WintunReader(self.session.clone())), TunWriter::new(WintunWriter(self.session)))
+    }
+}
+
+struct WintunReader(Arc<wintun::Session>);
+
+impl Read for WintunReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self.0.receive_blocking().map_err(io::Error::other)?;
+        let bytes = packet.bytes();
+        let len = bytes.len().min(buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        Ok(len)
+    }
+}
+
+struct WintunWriter(Arc<wintun::Session>);
+
+impl Write for WintunWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = u16::try_from(buf.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet larger than 64 KiB"))?;
+        let mut packet = self.0.allocate_send_packet(size).map_err(io::Error::other)?;
+        packet.bytes_mut().copy_from_slice(buf);
+        self.0.send_packet(packet);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for WintunWriter {
+    fn drop(&mut self) {
+        // Wake a reader blocked in receive_blocking so the session can end
+        let _ = self.0.shutdown();
+    }
+}
 
 /// TAP device IOCTL codes
 const TAP_IOCTL_GET_MAC: u32 = 0x170001;
@@ -39,6 +179,11 @@ pub struct WindowsTapInterface {
     mac_address: [u8; 6],
 }
 
+// SAFETY: the handle is opened for overlapped I/O and every read or write
+// waits on its own OVERLAPPED and event, so threads may share it
+unsafe impl Send for WindowsTapInterface {}
+unsafe impl Sync for WindowsTapInterface {}
+
 impl WindowsTapInterface {
     /// Create a new Windows TAP interface
     pub fn new() -> Result<Self> {
@@ -281,90 +426,78 @@ impl WindowsTapInterface {
     /// Read packet from TAP device
     pub async fn read_packet(&mut self) -> Result<Bytes> {
         let mut buffer = vec![0u8; self.mtu as usize + 14]; // +14 for Ethernet header
-        let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
-        
-        // Create event for overlapped I/O
-        unsafe {
-            overlapped.hEvent = CreateEventW(ptr::null_mut(), 1, 0, ptr::null());
-            if overlapped.hEvent.is_null() {
-                return Err(VpnError::TunTap("Failed to create event".to_string()));
-            }
-        }
-        
-        // For this demo, we'll use a simplified synchronous read
-        // In a real implementation, you'd use proper async I/O
-        let mut bytes_read = 0u32;
-        
-        unsafe {
-            let result = winapi::um::fileapi::ReadFile(
-                self.handle,
-                buffer.as_mut_ptr() as *mut _,
-                buffer.len() as u32,
-                &mut bytes_read,
-                &mut overlapped,
-            );
-            
-            if result == 0 {
-                let error_code = GetLastError();
-                if error_code != winapi::shared::winerror::ERROR_IO_PENDING {
-                    return Err(VpnError::TunTap(format!("Read failed: error code {}", error_code)));
-                }
-                
-                // Wait for completion (simplified)
-                winapi::um::synchapi::WaitForSingleObject(overlapped.hEvent, winapi::um::winbase::INFINITE);
-                winapi::um::ioapiset::GetOverlappedResult(self.handle, &mut overlapped, &mut bytes_read, 1);
-            }
-            
-            CloseHandle(overlapped.hEvent);
-        }
-        
-        buffer.truncate(bytes_read as usize);
+        let len = self
+            .read_frame(&mut buffer)
+            .map_err(|e| VpnError::TunTap(format!("Read failed: {e}")))?;
+        buffer.truncate(len);
         Ok(Bytes::from(buffer))
     }
 
     /// Write packet to TAP device
     pub async fn write_packet(&mut self, packet: Bytes) -> Result<()> {
+        let written = self
+            .write_frame(&packet)
+            .map_err(|e| VpnError::TunTap(format!("Write failed: {e}")))?;
+        if written != packet.len() {
+            return Err(VpnError::TunTap("Incomplete write".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Receive and transmit halves sharing the device handle
+    pub fn split(self) -> (TunReader, TunWriter) {
+        let device = Arc::new(self);
+        (TunReader::new(TapHalf(device.clone())), TunWriter::new(TapHalf(device)))
+    }
+
+    /// Block until the next packet arrives
+    fn read_frame(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = u32::try_from(buf.len()).unwrap_or(u32::MAX);
+        // SAFETY: `buf` outlives the operation, which completes before returning
+        self.overlapped_io(|overlapped, read| unsafe {
+            winapi::um::fileapi::ReadFile(self.handle, buf.as_mut_ptr() as *mut _, len, read, overlapped)
+        })
+    }
+
+    fn write_frame(&self, packet: &[u8]) -> io::Result<usize> {
+        let len = u32::try_from(packet.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "packet too large"))?;
+        // SAFETY: `packet` outlives the operation, which completes before returning
+        self.overlapped_io(|overlapped, written| unsafe {
+            winapi::um::fileapi::WriteFile(self.handle, packet.as_ptr() as *const _, len, written, overlapped)
+        })
+    }
+
+    /// Start one overlapped read or write and wait for it to complete
+    fn overlapped_io(&self, start: impl FnOnce(*mut OVERLAPPED, &mut u32) -> i32) -> io::Result<usize> {
+        // SAFETY: an all-zero OVERLAPPED is the documented initial state
         let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
-        
-        // Create event for overlapped I/O
-        unsafe {
-            overlapped.hEvent = CreateEventW(ptr::null_mut(), 1, 0, ptr::null());
-            if overlapped.hEvent.is_null() {
-                return Err(VpnError::TunTap("Failed to create event".to_string()));
-            }
+        // SAFETY: plain manual-reset event without a name or security descriptor
+        overlapped.hEvent = unsafe { CreateEventW(ptr::null_mut(), 1, 0, ptr::null()) };
+        if overlapped.hEvent.is_null() {
+            return Err(io::Error::last_os_error());
         }
-        
-        let mut bytes_written = 0u32;
-        
-        unsafe {
-            let result = winapi::um::fileapi::WriteFile(
-                self.handle,
-                packet.as_ptr() as *const _,
-                packet.len() as u32,
-                &mut bytes_written,
-                &mut overlapped,
-            );
-            
-            if result == 0 {
-                let error_code = GetLastError();
-                if error_code != winapi::shared::winerror::ERROR_IO_PENDING {
-                    CloseHandle(overlapped.hEvent);
-                    return Err(VpnError::TunTap(format!("Write failed: error code {}", error_code)));
+
+        let mut transferred = 0u32;
+        let result = if start(&mut overlapped as *mut OVERLAPPED, &mut transferred) != 0 {
+            Ok(transferred as usize)
+        } else {
+            // SAFETY: reads the calling thread's last error code
+            match unsafe { GetLastError() } {
+                ERROR_IO_PENDING => {
+                    // SAFETY: `overlapped` belongs to the pending operation; TRUE waits for it
+                    if unsafe { GetOverlappedResult(self.handle, &mut overlapped, &mut transferred, 1) } != 0 {
+                        Ok(transferred as usize)
+                    } else {
+                        Err(io::Error::last_os_error())
+                    }
                 }
-                
-                // Wait for completion (simplified)
-                winapi::um::synchapi::WaitForSingleObject(overlapped.hEvent, winapi::um::winbase::INFINITE);
-                winapi::um::ioapiset::GetOverlappedResult(self.handle, &mut overlapped, &mut bytes_written, 1);
+                code => Err(io::Error::from_raw_os_error(code as i32)),
             }
-            
-            CloseHandle(overlapped.hEvent);
-        }
-        
-        if bytes_written != packet.len() as u32 {
-            return Err(VpnError::TunTap("Incomplete write".to_string()));
-        }
-        
-        Ok(())
+        };
+        // SAFETY: the event was created above and the operation is complete
+        unsafe { CloseHandle(overlapped.hEvent) };
+        result
     }
 
     /// Get device information
@@ -389,6 +522,25 @@ impl Drop for WindowsTapInterface {
     }
 }
 
+/// One side of a TAP device split with [`WindowsTapInterface::split`]
+struct TapHalf(Arc<WindowsTapInterface>);
+
+impl Read for TapHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read_frame(buf)
+    }
+}
+
+impl Write for TapHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_frame(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 // Async I/O traits implementation (simplified for demo)
 impl AsyncRead for WindowsTapInterface {
     fn poll_read(
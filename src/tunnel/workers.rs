@@ -0,0 +1,310 @@
+//! Packet processing spread over worker tasks
+//!
+//! Compression and encryption are the CPU-heavy part of forwarding. With
+//! [`PerformanceConfig::packet_workers`](crate::client_optimized::PerformanceConfig::packet_workers)
+//! above 1, a [`WorkerPool`] does them on that many tasks, and the packet pump
+//! talks to the session through a [`ShardedSender`] and [`ShardedReceiver`]:
+//!
+//! ```text
+//!             ┌▶ worker 0 ─┐
+//! reserve ────┼▶ worker 1 ─┼───▶ in order ─▶ writer
+//!             └▶ worker N ─┘
+//! ```
+//!
+//! Outbound, packets from TUN are sealed and written to the session by one
+//! task. Inbound, packets from the session are opened and go on to the
+//! pump's single TUN writer.
+//!
+//! Nonces and sequence numbers are reserved as packets enter, and results
+//! leave in that order, since the stream authenticates its order. Outbound
+//! packets go to the worker their flow hashes to, keeping each flow on one
+//! worker. Inbound payloads cannot be hashed before they are opened, so
+//! they are dealt out in turn.
+//!
+//! Workers are Tokio tasks: the work spreads over cores on a multi-threaded
+//! runtime only.
+
+use super::pump::{PacketSink, PacketSource};
+use crate::error::{Result, VpnError};
+use crate::protocol::binary::{BinaryDataReceiver, BinaryDataSender, DataSlots, PayloadCodec, SoftEtherPacket};
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// Packets in flight between entering the workers and leaving in order
+const IN_FLIGHT: usize = 256;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Worker tasks, each with a queue of its own
+///
+/// The workers stop once the pool is dropped and their queues are done.
+pub struct WorkerPool {
+    shards: Vec<mpsc::UnboundedSender<Job>>,
+}
+
+impl WorkerPool {
+    /// Spawn `workers` tasks (at least one) on `handle`
+    pub fn spawn(handle: &Handle, workers: usize) -> Arc<Self> {
+        let shards = (0..workers.max(1))
+            .map(|_| {
+                let (tx, mut rx) = mpsc::unbounded_channel::<Job>();
+                handle.spawn(async move {
+                    while let Some(job) = rx.recv().await {
+                        job();
+                    }
+                });
+                tx
+            })
+            .collect();
+        Arc::new(Self { shards })
+    }
+
+    /// Number of worker tasks
+    pub fn workers(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Run `work` on the worker `shard` maps to; the result arrives on the returned channel
+    pub fn submit<T, F>(&self, shard: u64, work: F) -> oneshot::Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let index = (shard % self.shards.len() as u64) as usize;
+        // A worker only goes away with the pool; a dropped job closes `rx`
+        let _ = self.shards[index].send(Box::new(move || {
+            let _ = tx.send(work());
+        }));
+        rx
+    }
+}
+
+/// Hash of the flow a packet belongs to
+///
+/// Addresses, protocol and ports of IPv4 and IPv6 packets, also inside
+/// Ethernet frames; other frames all hash alike.
+pub fn flow_hash(frame: &[u8]) -> u64 {
+    const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+    const ETHERTYPE_IPV6: [u8; 2] = [0x86, 0xdd];
+    let packet = match frame.first().map(|byte| byte >> 4) {
+        Some(4 | 6) => frame,
+        _ => match frame.get(12..14) {
+            Some(ethertype) if ethertype == ETHERTYPE_IPV4 || ethertype == ETHERTYPE_IPV6 => &frame[14..],
+            _ => return 0,
+        },
+    };
+    let (addresses, protocol, payload) = match packet.first().map(|byte| byte >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            (&packet[12..20], packet[9], packet.get(header_len..))
+        }
+        Some(6) if packet.len() >= 40 => (&packet[8..40], packet[6], packet.get(40..)),
+        _ => return 0,
+    };
+    // TCP, UDP and SCTP start with the ports
+    let ports = match protocol {
+        6 | 17 | 132 => payload.and_then(|payload| payload.get(..4)).unwrap_or_default(),
+        _ => &[],
+    };
+
+    // FNV-1a
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &byte in addresses.iter().chain([protocol].iter()).chain(ports) {
+        hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// Session sink sealing packets on a [`WorkerPool`]
+///
+/// A writer task sends the sealed packets in the order they came in.
+pub struct ShardedSender {
+    slots: DataSlots,
+    codec: PayloadCodec,
+    pool: Arc<WorkerPool>,
+    queue: mpsc::Sender<oneshot::Receiver<Result<SoftEtherPacket>>>,
+    writer: Option<JoinHandle<Result<()>>>,
+}
+
+impl ShardedSender {
+    /// Take over `sender`, spawning its writer task on `handle`
+    pub fn new(handle: &Handle, sender: BinaryDataSender, pool: Arc<WorkerPool>) -> Self {
+        let (slots, mut writer) = sender.into_parts();
+        let (queue, mut sealed) = mpsc::channel::<oneshot::Receiver<Result<SoftEtherPacket>>>(IN_FLIGHT);
+        let writer = handle.spawn(async move {
+            while let Some(packet) = sealed.recv().await {
+                let packet = packet.await.map_err(|_| VpnError::Connection("Packet worker stopped".into()))??;
+                writer.send(packet).await?;
+                if sealed.is_empty() {
+                    writer.flush().await?;
+                }
+            }
+            Ok(())
+        });
+        let codec = slots.codec();
+        Self { slots, codec, pool, queue, writer: Some(writer) }
+    }
+
+    async fn enqueue(&mut self, packet: oneshot::Receiver<Result<SoftEtherPacket>>) -> Result<()> {
+        if self.queue.send(packet).await.is_ok() {
+            return Ok(());
+        }
+        // The writer gave up; report why
+        match self.writer.take() {
+            Some(writer) => match writer.await {
+                Ok(Err(e)) => Err(e),
+                _ => Err(VpnError::Connection("Session writer stopped".into())),
+            },
+            None => Err(VpnError::Connection("Session writer stopped".into())),
+        }
+    }
+}
+
+impl PacketSink for ShardedSender {
+    async fn send_packet(&mut self, packet: Bytes) -> Result<()> {
+        let slot = self.slots.reserve()?;
+        let codec = self.codec.clone();
+        let sealed = self.pool.submit(flow_hash(&packet), move || codec.seal(slot, packet));
+        self.enqueue(sealed).await
+    }
+
+    async fn send_keepalive(&mut self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        let _ = tx.send(Ok(self.slots.keepalive()));
+        self.enqueue(rx).await
+    }
+}
+
+/// Session source opening packets on a [`WorkerPool`]
+///
+/// A reader task takes packets off the session and hands them to the
+/// workers in turn; they come out in the order they arrived.
+pub struct ShardedReceiver {
+    opened: mpsc::Receiver<oneshot::Receiver<Result<Bytes>>>,
+    // Taken off `opened`, kept across a cancelled receive
+    next: Option<oneshot::Receiver<Result<Bytes>>>,
+    reader: JoinHandle<()>,
+}
+
+impl ShardedReceiver {
+    /// Take over `receiver`, spawning its reader task on `handle`
+    pub fn new(handle: &Handle, mut receiver: BinaryDataReceiver, pool: Arc<WorkerPool>) -> Self {
+        let (queue, opened) = mpsc::channel(IN_FLIGHT);
+        let reader = handle.spawn(async move {
+            let codec = receiver.codec();
+            for turn in 0u64.. {
+                let packet = match receiver.receive_sealed().await {
+                    Ok((slot, data)) => {
+                        let codec = codec.clone();
+                        pool.submit(turn, move || codec.open(slot, data))
+                    }
+                    Err(e) => {
+                        // Passed on in order, after the packets before it
+                        let (tx, rx) = oneshot::channel();
+                        let _ = tx.send(Err(e));
+                        let _ = queue.send(rx).await;
+                        return;
+                    }
+                };
+                if queue.send(packet).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Self { opened, next: None, reader }
+    }
+}
+
+impl PacketSource for ShardedReceiver {
+    async fn recv_packet(&mut self) -> Result<Bytes> {
+        let next = match self.next {
+            Some(ref mut next) => next,
+            None => {
+                let next = self.opened.recv().await.ok_or_else(|| VpnError::Connection("Session reader stopped".into()))?;
+                self.next.insert(next)
+            }
+        };
+        let opened = next.await;
+        self.next = None;
+        opened.unwrap_or_else(|_| Err(VpnError::Connection("Packet worker stopped".into())))
+    }
+}
+
+impl Drop for ShardedReceiver {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SessionKeys;
+    use crate::protocol::mock::MockServer;
+    use crate::protocol::BinaryProtocolClient;
+
+    fn udp_packet(source_port: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[9] = 17;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 2]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 1]);
+        packet[20..22].copy_from_slice(&source_port.to_be_bytes());
+        packet[22..24].copy_from_slice(&53u16.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_flow_hash() {
+        let packet = udp_packet(5000);
+        assert_eq!(flow_hash(&packet), flow_hash(&udp_packet(5000)));
+        assert_ne!(flow_hash(&packet), flow_hash(&udp_packet(5001)));
+
+        // The same packet inside an Ethernet frame is the same flow
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&packet);
+        assert_eq!(flow_hash(&frame), flow_hash(&packet));
+
+        assert_eq!(flow_hash(b""), 0);
+        assert_eq!(flow_hash(&[0x45; 10]), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_sharded_session_keeps_order() {
+        let keys = SessionKeys::derive(&[7; 20], &[b"password hash"]).unwrap();
+        let server = MockServer::start_encrypted(keys.clone()).await.unwrap();
+        let mut client = BinaryProtocolClient::new(server.addr()).with_session_keys(&keys).unwrap();
+        client.connect().await.unwrap();
+        client.authenticate("user", "pass", "HUB").await.unwrap();
+
+        let handle = Handle::current();
+        let pool = WorkerPool::spawn(&handle, 4);
+        assert_eq!(pool.workers(), 4);
+        let (sender, receiver) = client.into_split().unwrap();
+        let (mut sink, mut source) =
+            (ShardedSender::new(&handle, sender, pool.clone()), ShardedReceiver::new(&handle, receiver, pool));
+
+        // Flows spread over the workers, the echo comes back in sending order
+        let packets: Vec<Bytes> = (0..200u16)
+            .map(|i| {
+                let mut packet = udp_packet(i % 7);
+                packet.extend_from_slice(&i.to_be_bytes());
+                Bytes::from(packet)
+            })
+            .collect();
+        for (i, packet) in packets.iter().enumerate() {
+            sink.send_packet(packet.clone()).await.unwrap();
+            if i == 100 {
+                sink.send_keepalive().await.unwrap();
+            }
+        }
+        for packet in &packets {
+            assert_eq!(&source.recv_packet().await.unwrap(), packet);
+        }
+    }
+}
@@ -0,0 +1,499 @@
+//! Underlay Interface Selection
+//!
+//! On multi-homed hosts (Wi-Fi + Ethernet + LTE) the operating system picks
+//! the uplink that carries the VPN session by route metric. This module lists
+//! the host's interfaces so users can choose one, and resolves the choice into
+//! an [`UnderlayBinding`] that the HTTP/TLS clients, the UDP acceleration
+//! socket and the route planner all honour.
+//!
+//! With policy routing (Linux) the session's own sockets carry a firewall
+//! mark instead, which keeps them on the main routing table while everything
+//! else goes to the tunnel's: see [`connect_tcp`] and [`set_socket_mark`].
+
+use crate::error::{Result, VpnError};
+use serde::Serialize;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// What kind of link an interface is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InterfaceKind {
+    Ethernet,
+    Wireless,
+    Cellular,
+    Loopback,
+    /// Tunnel of this or another VPN
+    Tunnel,
+    /// Bridge, veth, container or hypervisor network
+    Virtual,
+    Other,
+}
+
+/// A network interface of the host
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UnderlayInterface {
+    pub name: String,
+    pub index: u32,
+    pub kind: InterfaceKind,
+    pub addresses: Vec<IpAddr>,
+    pub is_up: bool,
+    /// Gateway of the default route through this interface, if there is one
+    pub gateway: Option<Ipv4Addr>,
+    /// Metric of that default route (Windows: interface metric); lower wins
+    pub metric: Option<u32>,
+}
+
+impl UnderlayInterface {
+    /// First IPv4 address
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.addresses.iter().find_map(|addr| match addr {
+            IpAddr::V4(v4) => Some(*v4),
+            IpAddr::V6(_) => None,
+        })
+    }
+
+    /// Whether the interface can carry the VPN session
+    pub fn is_uplink(&self) -> bool {
+        self.is_up
+            && self.ipv4().is_some()
+            && !matches!(self.kind, InterfaceKind::Loopback | InterfaceKind::Tunnel)
+    }
+}
+
+/// The chosen uplink, resolved when connecting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnderlayBinding {
+    pub interface: String,
+    /// Source address for sockets bound to the uplink
+    pub address: Ipv4Addr,
+    /// Next hop towards the VPN server; `None` routes through the interface directly
+    pub gateway: Option<Ipv4Addr>,
+}
+
+impl UnderlayBinding {
+    /// Resolve interface `name` on this host
+    pub fn resolve(name: &str) -> Result<Self> {
+        Self::from_interfaces(name, &list_interfaces())
+    }
+
+    fn from_interfaces(name: &str, interfaces: &[UnderlayInterface]) -> Result<Self> {
+        let Some(interface) = interfaces.iter().find(|i| i.name == name) else {
+            let uplinks: Vec<&str> = interfaces
+                .iter()
+                .filter(|i| i.is_uplink())
+                .map(|i| i.name.as_str())
+                .collect();
+            return Err(VpnError::Network(format!(
+                "Underlay interface '{name}' not found (uplinks: {})",
+                uplinks.join(", ")
+            )));
+        };
+        if !interface.is_up {
+            return Err(VpnError::Network(format!("Underlay interface '{name}' is down")));
+        }
+        let address = interface
+            .ipv4()
+            .ok_or_else(|| VpnError::Network(format!("Underlay interface '{name}' has no IPv4 address")))?;
+        Ok(Self {
+            interface: interface.name.clone(),
+            address,
+            gateway: interface.gateway,
+        })
+    }
+
+    /// Make every connection of an HTTP client leave through this uplink
+    pub fn apply_to(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        let builder = builder.local_address(IpAddr::V4(self.address));
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        let builder = builder.interface(&self.interface);
+        builder
+    }
+}
+
+/// The uplink the OS would pick now: the first with a default route, by metric
+pub fn default_uplink() -> Option<UnderlayBinding> {
+    let uplink = list_interfaces().into_iter().find(UnderlayInterface::is_uplink)?;
+    UnderlayBinding::resolve(&uplink.name).ok()
+}
+
+/// Set the firewall mark (`SO_MARK`) policy routing tells the session's sockets apart by
+#[cfg(target_os = "linux")]
+pub fn set_socket_mark(socket: &impl std::os::fd::AsRawFd, mark: u32) -> io::Result<()> {
+    // SAFETY: `mark` is a u32 that outlives the call, as SO_MARK expects
+    let set = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if set < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Connect to `server` over TCP, with `mark` set before the first packet leaves
+///
+/// The mark is only set on Linux; elsewhere this is a plain connect.
+pub async fn connect_tcp(server: SocketAddr, mark: Option<u32>) -> io::Result<TcpStream> {
+    let Some(mark) = mark else {
+        return TcpStream::connect(server).await;
+    };
+    let socket = if server.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    #[cfg(target_os = "linux")]
+    set_socket_mark(&socket, mark)?;
+    #[cfg(not(target_os = "linux"))]
+    let _ = mark;
+    socket.connect(server).await
+}
+
+/// All interfaces of the host, uplinks with a default route first (by metric)
+pub fn list_interfaces() -> Vec<UnderlayInterface> {
+    let mut interfaces = platform_interfaces();
+    interfaces.sort_by_key(|i| {
+        (
+            !i.is_uplink(),
+            i.gateway.is_none(),
+            i.metric.unwrap_or(u32::MAX),
+            i.index,
+        )
+    });
+    interfaces
+}
+
+/// Tunnels of any software, not only the VPNs coexistence looks out for
+fn is_tunnel_name(name: &str) -> bool {
+    name.starts_with("tun") || name.starts_with("tap") || crate::tunnel::coexistence::is_vpn_interface(name)
+}
+
+/// Classify an interface by name, after platform-specific checks found nothing
+fn kind_from_name(name: &str) -> InterfaceKind {
+    const CELLULAR: &[&str] = &["wwan", "rmnet", "ccmni", "pdp_ip"];
+    const WIRELESS: &[&str] = &["wlan", "wlp", "wlx"];
+    const VIRTUAL: &[&str] = &[
+        "docker", "veth", "br-", "virbr", "vmnet", "vboxnet", "bridge", "lxc", "lxd", "cni", "flannel", "podman",
+    ];
+    const ETHERNET: &[&str] = &["eth", "enp", "eno", "ens", "enx", "em", "en"];
+
+    let starts = |prefixes: &[&str]| prefixes.iter().any(|prefix| name.starts_with(prefix));
+    if name == "lo" || name.starts_with("lo0") {
+        InterfaceKind::Loopback
+    } else if starts(CELLULAR) {
+        InterfaceKind::Cellular
+    } else if is_tunnel_name(name) {
+        InterfaceKind::Tunnel
+    } else if starts(WIRELESS) {
+        InterfaceKind::Wireless
+    } else if starts(VIRTUAL) {
+        InterfaceKind::Virtual
+    } else if starts(ETHERNET) {
+        InterfaceKind::Ethernet
+    } else {
+        InterfaceKind::Other
+    }
+}
+
+/// Name, flags and addresses of every interface, from getifaddrs(3)
+#[cfg(unix)]
+fn unix_interfaces() -> Vec<UnderlayInterface> {
+    let mut interfaces: Vec<UnderlayInterface> = Vec::new();
+    let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: getifaddrs fills `head` with a list we free below
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        return interfaces;
+    }
+
+    let mut cursor = head;
+    while !cursor.is_null() {
+        // SAFETY: cursor points into the list returned by getifaddrs
+        let entry = unsafe { &*cursor };
+        cursor = entry.ifa_next;
+        if entry.ifa_name.is_null() {
+            continue;
+        }
+        // SAFETY: ifa_name is a NUL-terminated string owned by the list
+        let name = unsafe { std::ffi::CStr::from_ptr(entry.ifa_name) }.to_string_lossy().into_owned();
+        // SAFETY: ifa_addr is null or points to a sockaddr of the family it declares
+        let address = unsafe { sockaddr_ip(entry.ifa_addr) };
+
+        let position = match interfaces.iter().position(|i| i.name == name) {
+            Some(position) => position,
+            None => {
+                let flags = entry.ifa_flags as libc::c_int;
+                interfaces.push(UnderlayInterface {
+                    index: unix_index(&name),
+                    kind: if flags & libc::IFF_LOOPBACK != 0 {
+                        InterfaceKind::Loopback
+                    } else {
+                        InterfaceKind::Other
+                    },
+                    is_up: flags & libc::IFF_UP != 0 && flags & libc::IFF_RUNNING != 0,
+                    name,
+                    addresses: Vec::new(),
+                    gateway: None,
+                    metric: None,
+                });
+                interfaces.len() - 1
+            }
+        };
+        if let Some(address) = address {
+            interfaces[position].addresses.push(address);
+        }
+    }
+    // SAFETY: head came from getifaddrs and is freed exactly once
+    unsafe { libc::freeifaddrs(head) };
+    interfaces
+}
+
+#[cfg(unix)]
+fn unix_index(name: &str) -> u32 {
+    std::ffi::CString::new(name)
+        // SAFETY: the CString is a valid NUL-terminated name
+        .map(|c_name| unsafe { libc::if_nametoindex(c_name.as_ptr()) })
+        .unwrap_or(0)
+}
+
+/// # Safety
+/// `addr` must be null or point to a valid sockaddr of the family it declares.
+#[cfg(unix)]
+unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    match i32::from((*addr).sa_family) {
+        libc::AF_INET => {
+            let v4 = &*(addr as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(v4.sin_addr.s_addr))))
+        }
+        libc::AF_INET6 => {
+            let v6 = &*(addr as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(v6.sin6_addr.s6_addr.into()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn platform_interfaces() -> Vec<UnderlayInterface> {
+    use crate::tunnel::routing::linux::Netlink;
+
+    let default_routes = Netlink::open()
+        .and_then(|mut netlink| netlink.default_routes())
+        .unwrap_or_default();
+    let mut interfaces = unix_interfaces();
+    for interface in &mut interfaces {
+        if interface.kind == InterfaceKind::Other {
+            interface.kind = linux_kind(&interface.name);
+        }
+        // Several default routes through one interface: the preferred one counts
+        if let Some(route) = default_routes
+            .iter()
+            .filter(|route| route.oif == Some(interface.index))
+            .min_by_key(|route| route.metric.unwrap_or(0))
+        {
+            interface.gateway = match route.gateway {
+                Some(IpAddr::V4(gateway)) => Some(gateway),
+                _ => None,
+            };
+            interface.metric = Some(route.metric.unwrap_or(0));
+        }
+    }
+    interfaces
+}
+
+#[cfg(target_os = "linux")]
+fn linux_kind(name: &str) -> InterfaceKind {
+    let sys = std::path::Path::new("/sys/class/net").join(name);
+    if sys.join("wireless").exists() || sys.join("phy80211").exists() {
+        return InterfaceKind::Wireless;
+    }
+    match kind_from_name(name) {
+        // Physical NICs have a backing device; bridges, veths and dummies do not
+        InterfaceKind::Other | InterfaceKind::Ethernet if sys.exists() && !sys.join("device").exists() => {
+            InterfaceKind::Virtual
+        }
+        kind => kind,
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn platform_interfaces() -> Vec<UnderlayInterface> {
+    use std::process::Command;
+
+    let ports = Command::new("networksetup")
+        .arg("-listallhardwareports")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default();
+    let default_route = Command::new("route")
+        .args(["-n", "get", "default"])
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).into_owned())
+        .unwrap_or_default();
+    let field = |key: &str| {
+        default_route
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(key))
+            .map(|value| value.trim().to_string())
+    };
+    let default_interface = field("interface:");
+    let default_gateway = field("gateway:").and_then(|gw| gw.parse().ok());
+
+    let mut interfaces = unix_interfaces();
+    for interface in &mut interfaces {
+        if interface.kind == InterfaceKind::Other {
+            interface.kind = macos_port_kind(&ports, &interface.name).unwrap_or_else(|| kind_from_name(&interface.name));
+        }
+        if default_interface.as_deref() == Some(interface.name.as_str()) {
+            interface.gateway = default_gateway;
+        }
+    }
+    interfaces
+}
+
+/// Kind of `device` from `networksetup -listallhardwareports` output
+#[cfg(target_os = "macos")]
+fn macos_port_kind(ports: &str, device: &str) -> Option<InterfaceKind> {
+    let mut port = None;
+    for line in ports.lines() {
+        if let Some(name) = line.strip_prefix("Hardware Port: ") {
+            port = Some(name);
+        } else if line.strip_prefix("Device: ") == Some(device) {
+            let port = port?;
+            return Some(if port.contains("Wi-Fi") || port.contains("AirPort") {
+                InterfaceKind::Wireless
+            } else if port.contains("iPhone") || port.contains("Cellular") {
+                InterfaceKind::Cellular
+            } else if port.contains("Bridge") {
+                InterfaceKind::Virtual
+            } else {
+                InterfaceKind::Ethernet
+            });
+        }
+    }
+    None
+}
+
+#[cfg(windows)]
+fn platform_interfaces() -> Vec<UnderlayInterface> {
+    // alias|index|address|metric|state|media type|default gateway, one line per IPv4 address
+    const SCRIPT: &str = "Get-NetIPAddress -AddressFamily IPv4 | ForEach-Object { \
+        $i = Get-NetIPInterface -InterfaceIndex $_.InterfaceIndex -AddressFamily IPv4; \
+        $a = Get-NetAdapter -InterfaceIndex $_.InterfaceIndex -ErrorAction SilentlyContinue; \
+        $r = Get-NetRoute -InterfaceIndex $_.InterfaceIndex -DestinationPrefix 0.0.0.0/0 -ErrorAction SilentlyContinue | Select-Object -First 1; \
+        '{0}|{1}|{2}|{3}|{4}|{5}|{6}' -f $_.InterfaceAlias,$_.InterfaceIndex,$_.IPAddress,$i.InterfaceMetric,$i.ConnectionState,$a.PhysicalMediaType,$r.NextHop }";
+
+    let output = match std::process::Command::new("powershell")
+        .args(["-NoProfile", "-Command", SCRIPT])
+        .output()
+    {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).into_owned(),
+        _ => return Vec::new(),
+    };
+
+    let mut interfaces: Vec<UnderlayInterface> = Vec::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.trim().split('|').collect();
+        let [name, index, address, metric, state, media, gateway] = fields[..] else {
+            continue;
+        };
+        let address = address.parse::<IpAddr>().ok();
+        if let Some(existing) = interfaces.iter_mut().find(|i| i.name == name) {
+            existing.addresses.extend(address);
+            continue;
+        }
+        let kind = match media {
+            "802.3" => InterfaceKind::Ethernet,
+            "Native 802.11" | "Wireless LAN" => InterfaceKind::Wireless,
+            "Wireless WAN" => InterfaceKind::Cellular,
+            _ if name.starts_with("Loopback") => InterfaceKind::Loopback,
+            _ if is_tunnel_name(&name.to_lowercase()) => InterfaceKind::Tunnel,
+            _ => InterfaceKind::Other,
+        };
+        interfaces.push(UnderlayInterface {
+            name: name.to_string(),
+            index: index.parse().unwrap_or(0),
+            kind,
+            addresses: address.into_iter().collect(),
+            is_up: state == "Connected",
+            gateway: gateway.parse().ok().filter(|gw: &Ipv4Addr| !gw.is_unspecified()),
+            metric: metric.parse().ok(),
+        });
+    }
+    interfaces
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn platform_interfaces() -> Vec<UnderlayInterface> {
+    let mut interfaces = unix_interfaces();
+    for interface in &mut interfaces {
+        if interface.kind == InterfaceKind::Other {
+            interface.kind = kind_from_name(&interface.name);
+        }
+    }
+    interfaces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interface(name: &str, kind: InterfaceKind, addresses: &[&str], is_up: bool) -> UnderlayInterface {
+        UnderlayInterface {
+            name: name.to_string(),
+            index: 0,
+            kind,
+            addresses: addresses.iter().map(|a| a.parse().unwrap()).collect(),
+            is_up,
+            gateway: None,
+            metric: None,
+        }
+    }
+
+    #[test]
+    fn test_kind_from_name() {
+        assert_eq!(kind_from_name("lo"), InterfaceKind::Loopback);
+        assert_eq!(kind_from_name("wwan0"), InterfaceKind::Cellular);
+        assert_eq!(kind_from_name("wg0"), InterfaceKind::Tunnel);
+        assert_eq!(kind_from_name("tun0"), InterfaceKind::Tunnel);
+        assert_eq!(kind_from_name("wlan0"), InterfaceKind::Wireless);
+        assert_eq!(kind_from_name("docker0"), InterfaceKind::Virtual);
+        assert_eq!(kind_from_name("enp3s0"), InterfaceKind::Ethernet);
+    }
+
+    #[test]
+    fn test_binding_resolution() {
+        let mut lte = interface("wwan0", InterfaceKind::Cellular, &["fe80::1", "100.64.3.7"], true);
+        lte.gateway = Some(Ipv4Addr::new(100, 64, 3, 1));
+        let interfaces = vec![
+            interface("lo", InterfaceKind::Loopback, &["127.0.0.1"], true),
+            interface("eth0", InterfaceKind::Ethernet, &["192.168.1.20"], false),
+            interface("wlan0", InterfaceKind::Wireless, &["fe80::2"], true),
+            lte,
+        ];
+
+        let binding = UnderlayBinding::from_interfaces("wwan0", &interfaces).unwrap();
+        assert_eq!(binding.address, Ipv4Addr::new(100, 64, 3, 7));
+        assert_eq!(binding.gateway, Some(Ipv4Addr::new(100, 64, 3, 1)));
+
+        let missing = UnderlayBinding::from_interfaces("eth1", &interfaces).unwrap_err();
+        assert!(missing.to_string().contains("uplinks: wwan0"));
+        assert!(UnderlayBinding::from_interfaces("eth0", &interfaces).is_err());
+        assert!(UnderlayBinding::from_interfaces("wlan0", &interfaces).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_list_includes_loopback() {
+        let interfaces = list_interfaces();
+        let lo = interfaces.iter().find(|i| i.name == "lo").expect("loopback listed");
+        assert_eq!(lo.kind, InterfaceKind::Loopback);
+        assert!(lo.addresses.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        assert!(!lo.is_uplink());
+    }
+}
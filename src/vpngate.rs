@@ -0,0 +1,280 @@
+//! VPN Gate Server List
+//!
+//! VPN Gate (<https://www.vpngate.net>) is a network of volunteer-run
+//! SoftEther servers. Its public API serves the current list as CSV:
+//!
+//! ```text
+//! *vpn_servers
+//! #HostName,IP,Score,Ping,Speed,CountryLong,CountryShort,NumVpnSessions,Uptime,TotalUsers,TotalTraffic,LogType,Operator,Message,OpenVPN_ConfigData_Base64
+//! public-vpn-123,219.100.37.10,1234567,12,98765432,Japan,JP,42,3600000,1000,5000000,2weeks,Daiyuu Nobori_,,ZGV2...
+//! *
+//! ```
+//!
+//! The list carries no SSL-VPN port of its own; SoftEther serves OpenVPN on
+//! the same TCP listener, so the port comes from the `remote` line of the
+//! OpenVPN profile when that profile is TCP, and is 443 otherwise. Every VPN
+//! Gate server offers the `VPNGATE` hub with the shared `vpn`/`vpn` account.
+
+use crate::config::{AuthMethod, Config};
+use crate::error::{Result, VpnError};
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Default location of the server list
+pub const VPNGATE_API_URL: &str = "http://www.vpngate.net/api/iphone/";
+
+/// Hub every VPN Gate server offers
+pub const VPNGATE_HUB: &str = "VPNGATE";
+
+/// Shared account of the VPN Gate hub, used as both username and password
+pub const VPNGATE_ACCOUNT: &str = "vpn";
+
+/// Port assumed when the OpenVPN profile does not name a TCP one
+const DEFAULT_PORT: u16 = 443;
+
+/// Ping assumed for servers that report none, for scoring
+const UNKNOWN_PING_MS: u32 = 500;
+
+/// Columns up to and including `Operator`; `Message` may contain commas
+const LEADING_COLUMNS: usize = 13;
+
+/// One server of the VPN Gate list
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VpnGateServer {
+    pub host_name: String,
+    pub ip: IpAddr,
+    /// SSL-VPN port, taken from the OpenVPN profile
+    pub port: u16,
+    /// VPN Gate's own ranking, higher is better
+    pub vpngate_score: u64,
+    /// Round-trip time from VPN Gate's monitor, if measured
+    pub ping_ms: Option<u32>,
+    /// Measured line speed in bits per second
+    pub speed_bps: u64,
+    pub country_long: String,
+    /// ISO 3166 country code, e.g. `JP`
+    pub country_short: String,
+    /// Sessions currently connected
+    pub sessions: u32,
+    pub uptime_ms: u64,
+    pub total_users: u64,
+    pub operator: String,
+    pub message: String,
+}
+
+impl VpnGateServer {
+    /// Rank of the server for [`select_servers`], higher is better
+    ///
+    /// Speed in Mbit/s, halved for every 50 ms of ping and discounted by load:
+    /// `mbps / (1 + ping / 50) / (1 + sessions / 100)`.
+    pub fn score(&self) -> f64 {
+        let mbps = self.speed_bps as f64 / 1_000_000.0;
+        let ping = f64::from(self.ping_ms.unwrap_or(UNKNOWN_PING_MS));
+        let load = f64::from(self.sessions);
+        mbps / (1.0 + ping / 50.0) / (1.0 + load / 100.0)
+    }
+
+    /// `base` pointed at this server's `VPNGATE` hub
+    ///
+    /// The server's address, port, hub and the shared account replace those
+    /// of `base`; everything else (tunnel, logging, reconnect...) is kept.
+    /// VPN Gate servers present self-signed certificates nobody can pin in
+    /// advance, so certificate verification is turned off.
+    pub fn to_config(&self, base: &Config) -> Result<Config> {
+        let mut config = base.clone();
+        config.server.address = self.ip.to_string();
+        config.server.hostname = None;
+        config.server.port = self.port;
+        config.server.hub = VPNGATE_HUB.to_string();
+        config.server.verify_certificate = false;
+        config.clustering.enabled = false;
+        config.auth.method = AuthMethod::Password;
+        config.auth.username = Some(VPNGATE_ACCOUNT.to_string());
+        config.auth.password = Some(VPNGATE_ACCOUNT.to_string());
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// Filters applied by [`select_servers`]; the default accepts every server
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SelectionCriteria {
+    /// Country codes to choose from (case-insensitive), any country if empty
+    pub countries: Vec<String>,
+    /// Skip servers whose ping is higher or unknown
+    pub max_ping_ms: Option<u32>,
+    /// Skip servers slower than this, in bits per second
+    pub min_speed_bps: u64,
+    /// Skip servers with more sessions connected
+    pub max_sessions: Option<u32>,
+}
+
+impl SelectionCriteria {
+    fn accepts(&self, server: &VpnGateServer) -> bool {
+        (self.countries.is_empty() || self.countries.iter().any(|c| c.eq_ignore_ascii_case(&server.country_short)))
+            && self.max_ping_ms.is_none_or(|max| server.ping_ms.is_some_and(|ping| ping <= max))
+            && server.speed_bps >= self.min_speed_bps
+            && self.max_sessions.is_none_or(|max| server.sessions <= max)
+    }
+}
+
+/// Parse the CSV served by the VPN Gate API
+///
+/// Rows that cannot be parsed are skipped; a list without a single usable
+/// row is an error, as VPN Gate answers with an HTML page when overloaded.
+pub fn parse_server_list(csv: &str) -> Result<Vec<VpnGateServer>> {
+    let servers: Vec<VpnGateServer> = csv
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('*') && !line.starts_with('#'))
+        .filter_map(|line| {
+            let server = parse_row(line);
+            if server.is_none() {
+                log::debug!("Skipping malformed VPN Gate row: {}", line.chars().take(80).collect::<String>());
+            }
+            server
+        })
+        .collect();
+    if servers.is_empty() {
+        return Err(VpnError::Protocol("VPN Gate list contains no servers".to_string()));
+    }
+    Ok(servers)
+}
+
+fn parse_row(line: &str) -> Option<VpnGateServer> {
+    let mut leading = line.splitn(LEADING_COLUMNS + 1, ',');
+    let columns: Vec<&str> = leading.by_ref().take(LEADING_COLUMNS).collect();
+    let (message, profile) = leading.next()?.rsplit_once(',')?;
+    if columns.len() < LEADING_COLUMNS {
+        return None;
+    }
+    Some(VpnGateServer {
+        host_name: columns[0].to_string(),
+        ip: columns[1].parse().ok()?,
+        port: profile_port(profile).unwrap_or(DEFAULT_PORT),
+        vpngate_score: columns[2].parse().unwrap_or(0),
+        // VPN Gate reports "-" for servers it could not ping
+        ping_ms: columns[3].parse().ok(),
+        speed_bps: columns[4].parse().unwrap_or(0),
+        country_long: columns[5].to_string(),
+        country_short: columns[6].to_string(),
+        sessions: columns[7].parse().unwrap_or(0),
+        uptime_ms: columns[8].parse().unwrap_or(0),
+        total_users: columns[9].parse().unwrap_or(0),
+        operator: columns[12].to_string(),
+        message: message.to_string(),
+    })
+}
+
+/// Port of a TCP OpenVPN profile, which SoftEther shares with SSL-VPN
+fn profile_port(profile_base64: &str) -> Option<u16> {
+    let profile = base64::engine::general_purpose::STANDARD.decode(profile_base64.trim()).ok()?;
+    let profile = String::from_utf8_lossy(&profile);
+    let directive = |name: &str| {
+        profile
+            .lines()
+            .map(str::split_whitespace)
+            .find_map(|mut words| (words.next() == Some(name)).then(|| words.collect::<Vec<_>>()))
+    };
+    if !directive("proto")?.first()?.starts_with("tcp") {
+        return None;
+    }
+    directive("remote")?.get(1)?.parse().ok()
+}
+
+/// Download and parse the server list from `url` ([`VPNGATE_API_URL`] for the public one)
+pub async fn fetch_server_list(url: &str, timeout: Duration) -> Result<Vec<VpnGateServer>> {
+    let client = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| VpnError::Network(format!("Failed to create HTTP client: {e}")))?;
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| VpnError::Network(format!("Failed to fetch VPN Gate list from {url}: {e}")))?;
+    let csv = response
+        .text()
+        .await
+        .map_err(|e| VpnError::Network(format!("Failed to read VPN Gate list from {url}: {e}")))?;
+    let servers = parse_server_list(&csv)?;
+    log::info!(url = url; "Fetched {} VPN Gate servers", servers.len());
+    Ok(servers)
+}
+
+/// The servers matching `criteria`, best [`score`](VpnGateServer::score) first
+pub fn select_servers<'a>(servers: &'a [VpnGateServer], criteria: &SelectionCriteria) -> Vec<&'a VpnGateServer> {
+    let mut selected: Vec<&VpnGateServer> = servers.iter().filter(|server| criteria.accepts(server)).collect();
+    selected.sort_by(|a, b| b.score().total_cmp(&a.score()));
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(proto: &str, port: u16) -> String {
+        let text = format!("client\r\ndev tun\r\nproto {proto}\r\nremote 203.0.113.7 {port}\r\ncipher AES-128-CBC\r\n");
+        base64::engine::general_purpose::STANDARD.encode(text)
+    }
+
+    fn list() -> String {
+        [
+            "*vpn_servers".to_string(),
+            "#HostName,IP,Score,Ping,Speed,CountryLong,CountryShort,NumVpnSessions,Uptime,TotalUsers,TotalTraffic,LogType,Operator,Message,OpenVPN_ConfigData_Base64".to_string(),
+            format!("fast-jp,203.0.113.7,900,10,200000000,Japan,JP,20,3600000,100,5000,2weeks,Op A,,{}", profile("tcp", 1194)),
+            format!("slow-jp,203.0.113.8,800,200,10000000,Japan,JP,5,3600000,100,5000,2weeks,Op B,Hi, all,{}", profile("udp", 1195)),
+            format!("far-us,203.0.113.9,700,-,500000000,United States,US,90,3600000,100,5000,2weeks,Op C,,{}", profile("tcp", 443)),
+            "broken,not-an-ip,1,1,1,X,XX,1,1,1,1,x,x,,".to_string(),
+            "*".to_string(),
+        ]
+        .join("\r\n")
+    }
+
+    #[test]
+    fn test_parse_server_list() {
+        let servers = parse_server_list(&list()).unwrap();
+        assert_eq!(servers.len(), 3);
+        assert_eq!(servers[0].port, 1194);
+        assert_eq!(servers[0].ping_ms, Some(10));
+        // UDP profiles say nothing about the TCP listener
+        assert_eq!(servers[1].port, DEFAULT_PORT);
+        assert_eq!(servers[1].message, "Hi, all");
+        assert_eq!(servers[1].operator, "Op B");
+        assert_eq!(servers[2].ping_ms, None);
+        assert_eq!(servers[2].country_short, "US");
+
+        assert!(parse_server_list("<html>busy</html>").is_err());
+    }
+
+    #[test]
+    fn test_select_servers() {
+        let servers = parse_server_list(&list()).unwrap();
+        let names = |criteria: &SelectionCriteria| {
+            select_servers(&servers, criteria).iter().map(|s| s.host_name.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(names(&SelectionCriteria::default()), ["fast-jp", "far-us", "slow-jp"]);
+        let japan = SelectionCriteria { countries: vec!["jp".to_string()], ..Default::default() };
+        assert_eq!(names(&japan), ["fast-jp", "slow-jp"]);
+        let near = SelectionCriteria { max_ping_ms: Some(100), ..Default::default() };
+        assert_eq!(names(&near), ["fast-jp"]);
+    }
+
+    #[test]
+    fn test_to_config() {
+        let servers = parse_server_list(&list()).unwrap();
+        let mut base = Config::default_test();
+        base.tunnel.mtu = Some(1400);
+        let config = servers[0].to_config(&base).unwrap();
+        assert_eq!(config.server.address, "203.0.113.7");
+        assert_eq!(config.server.port, 1194);
+        assert_eq!(config.server.hub, VPNGATE_HUB);
+        assert_eq!(config.auth.username.as_deref(), Some("vpn"));
+        assert!(!config.server.verify_certificate);
+        assert_eq!(config.tunnel.mtu, Some(1400));
+    }
+}
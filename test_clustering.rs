@@ -9,9 +9,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut config = Config::default_test();
     config.clustering.enabled = true;
     config.clustering.cluster_nodes = vec![
-        "vpn-node1.example.com:443".to_string(),
-        "vpn-node2.example.com:443".to_string(),
-        "vpn-node3.example.com:443".to_string(),
+        "vpn-node1.example.com:443".into(),
+        "vpn-node2.example.com:443".into(),
+        "vpn-node3.example.com:443".into(),
     ];
     config.clustering.load_balancing_strategy = LoadBalancingStrategy::RoundRobin;
     config.clustering.max_peers_per_cluster = 100;
@@ -63,8 +63,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     
     if let Some(cluster_status) = client.get_cluster_status() {
         println!("   Cluster status:");
-        for (address, healthy, connections) in cluster_status {
-            println!("     - {}: healthy={}, connections={}", address, healthy, connections);
+        for node in cluster_status {
+            println!(
+                "     - {}: healthy={}, connections={}, penalty={:.2}, blacklisted_for={:?}",
+                node.address, node.healthy, node.active_connections, node.penalty, node.blacklisted_for
+            );
         }
     }
 
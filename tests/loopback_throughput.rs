@@ -0,0 +1,169 @@
+//! Loopback throughput and latency regression test
+//!
+//! Runs `BinaryProtocolClient` against an in-process mock server over
+//! 127.0.0.1 and fails when the data path gets slower than the configured
+//! thresholds. Only meaningful in optimized builds, so it is compiled with
+//! `--release --features perf-tests` (the nightly perf workflow does this):
+//!
+//! ```text
+//! cargo test --release --features perf-tests --test loopback_throughput -- --nocapture
+//! ```
+//!
+//! Thresholds can be tuned per runner with `RVPNSE_PERF_MIN_MBPS` and
+//! `RVPNSE_PERF_MAX_P99_US`.
+
+#![cfg(all(feature = "perf-tests", not(debug_assertions)))]
+
+use bytes::Bytes;
+use rvpnse::protocol::binary::protocol_constants::*;
+use rvpnse::protocol::binary::{BinaryProtocolClient, SoftEtherPacket};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Payload per data packet (a full Ethernet frame through a 1500 MTU tunnel)
+const FRAME_LEN: usize = 1400;
+
+/// Payload of a latency probe (echoed by the mock server)
+const PROBE_LEN: usize = 64;
+
+/// Data pushed through the tunnel for the throughput measurement
+const THROUGHPUT_BYTES: usize = 256 * 1024 * 1024;
+
+/// Round trips sampled for the latency measurement
+const LATENCY_SAMPLES: usize = 2_000;
+
+const DEFAULT_MIN_MBPS: f64 = 500.0;
+const DEFAULT_MAX_P99_US: u64 = 2_000;
+
+fn threshold<T: std::str::FromStr>(var: &str, default: T) -> T {
+    std::env::var(var)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+async fn read_packet<R: AsyncReadExt + Unpin>(reader: &mut R) -> Option<SoftEtherPacket> {
+    let mut header = [0u8; PACKET_HEADER_SIZE];
+    reader.read_exact(&mut header).await.ok()?;
+    let len = u32::from_be_bytes([header[9], header[10], header[11], header[12]]) as usize;
+    let mut packet = vec![0u8; PACKET_HEADER_SIZE + len];
+    packet[..PACKET_HEADER_SIZE].copy_from_slice(&header);
+    reader.read_exact(&mut packet[PACKET_HEADER_SIZE..]).await.ok()?;
+    SoftEtherPacket::from_bytes(Bytes::from(packet)).ok()
+}
+
+async fn write_packet<W: AsyncWriteExt + Unpin>(writer: &mut W, packet: SoftEtherPacket) -> Option<()> {
+    writer.write_all(&packet.to_bytes().ok()?).await.ok()?;
+    writer.flush().await.ok()
+}
+
+/// Mock server: completes the hello/session exchange, echoes latency probes,
+/// counts all other data and answers a zero-length data packet with the
+/// number of payload bytes counted so far.
+async fn serve(stream: TcpStream) -> Option<()> {
+    stream.set_nodelay(true).ok()?;
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::with_capacity(256 * 1024, reader);
+    let mut writer = BufWriter::new(writer);
+    let mut received: u64 = 0;
+
+    while let Some(packet) = read_packet(&mut reader).await {
+        let reply = match packet.packet_type {
+            PACKET_TYPE_HELLO => SoftEtherPacket {
+                packet_type: PACKET_TYPE_HELLO_RESPONSE,
+                session_id: 0,
+                sequence: 0,
+                data: Bytes::new(),
+            },
+            PACKET_TYPE_SESSION_ESTABLISH => SoftEtherPacket {
+                packet_type: PACKET_TYPE_SESSION_RESPONSE,
+                session_id: packet.session_id,
+                sequence: 0,
+                data: Bytes::new(),
+            },
+            PACKET_TYPE_DATA if packet.data.is_empty() => SoftEtherPacket::create_data_packet(
+                packet.session_id,
+                packet.sequence,
+                Bytes::copy_from_slice(&received.to_be_bytes()),
+            ),
+            PACKET_TYPE_DATA if packet.data.len() == PROBE_LEN => {
+                SoftEtherPacket::create_data_packet(packet.session_id, packet.sequence, packet.data)
+            }
+            PACKET_TYPE_DATA => {
+                received += packet.data.len() as u64;
+                continue;
+            }
+            _ => continue,
+        };
+        write_packet(&mut writer, reply).await?;
+    }
+    Some(())
+}
+
+async fn connected_client() -> BinaryProtocolClient {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        if let Ok((stream, _)) = listener.accept().await {
+            serve(stream).await;
+        }
+    });
+
+    let mut client = BinaryProtocolClient::new(addr);
+    client.connect().await.unwrap();
+    client.authenticate("perf", "perf", "DEFAULT").await.unwrap();
+    client.establish_session().await.unwrap();
+    client
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn loopback_throughput_and_latency() {
+    let min_mbps: f64 = threshold("RVPNSE_PERF_MIN_MBPS", DEFAULT_MIN_MBPS);
+    let max_p99_us: u64 = threshold("RVPNSE_PERF_MAX_P99_US", DEFAULT_MAX_P99_US);
+    let mut client = connected_client().await;
+
+    let probe = Bytes::from(vec![0xa5u8; PROBE_LEN]);
+    let mut samples = Vec::with_capacity(LATENCY_SAMPLES);
+    for _ in 0..LATENCY_SAMPLES {
+        let started = Instant::now();
+        client.send_vpn_data(probe.clone()).await.unwrap();
+        let echoed = client.receive_vpn_data().await.unwrap();
+        samples.push(started.elapsed());
+        assert_eq!(echoed, probe);
+    }
+    samples.sort();
+    let p50 = samples[samples.len() / 2];
+    let p99 = samples[samples.len() * 99 / 100];
+
+    let frame = Bytes::from(vec![0x5au8; FRAME_LEN]);
+    let frames = THROUGHPUT_BYTES / FRAME_LEN;
+    let started = Instant::now();
+    for _ in 0..frames {
+        client.send_vpn_data(frame.clone()).await.unwrap();
+    }
+    client.send_vpn_data(Bytes::new()).await.unwrap();
+    let report = client.receive_vpn_data().await.unwrap();
+    let elapsed = started.elapsed();
+
+    let received = u64::from_be_bytes(report[..8].try_into().unwrap());
+    assert_eq!(received, (frames * FRAME_LEN) as u64, "mock server lost data");
+    let mbps = (frames * FRAME_LEN) as f64 * 8.0 / elapsed.as_secs_f64() / 1_000_000.0;
+
+    println!(
+        "loopback: {mbps:.0} Mbit/s ({} MiB in {:?}), latency p50 {:?} p99 {:?}",
+        THROUGHPUT_BYTES / (1024 * 1024),
+        elapsed,
+        p50,
+        p99
+    );
+
+    assert!(
+        mbps >= min_mbps,
+        "throughput regression: {mbps:.0} Mbit/s < {min_mbps:.0} Mbit/s"
+    );
+    assert!(
+        p99 <= Duration::from_micros(max_p99_us),
+        "latency regression: p99 {p99:?} > {max_p99_us} us"
+    );
+}